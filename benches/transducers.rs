@@ -0,0 +1,104 @@
+/*
+    Benchmarks of the canonical queries in bench.rs over synthetic
+    workloads. Run with `cargo bench`; criterion reports changes relative
+    to the previous run, so run it before and after a change to measure it.
+*/
+
+use criterion::{
+    black_box, criterion_group, criterion_main, BenchmarkId, Criterion,
+    Throughput,
+};
+use data_transducers::bench::{self, Workload};
+use data_transducers::interface::Transducer;
+use data_transducers::runtime::KeyedRuntime;
+
+const N_ITEMS: usize = 10_000;
+
+// eval_epsilons: the length of the epsilon chain after each update
+fn epsilons(c: &mut Criterion) {
+    let strm = Workload::new(N_ITEMS).rstream(0);
+    let mut group = c.benchmark_group("eval_epsilons");
+    group.throughput(Throughput::Elements(N_ITEMS as u64));
+    for n in [1, 10, 100] {
+        group.bench_with_input(BenchmarkId::from_parameter(n), &n, |b, &n| {
+            let mut m = bench::epsilon_chain(n);
+            b.iter(|| bench::run(&mut m, black_box(&strm)))
+        });
+    }
+    group.finish();
+}
+
+// Concat::update: the frequency of the tag restarting the concatenation
+fn concat(c: &mut Criterion) {
+    let mut group = c.benchmark_group("concat_update");
+    group.throughput(Throughput::Elements(N_ITEMS as u64));
+    for weight in [1, 10, 100] {
+        let w = Workload::new(N_ITEMS).mix(&[('a', 100), ('b', weight)]);
+        let strm = w.rstream(());
+        group.bench_with_input(
+            BenchmarkId::from_parameter(weight),
+            &strm,
+            |b, strm| {
+                let mut m = bench::sum_since('b');
+                b.iter(|| bench::run(&mut m, black_box(strm)))
+            },
+        );
+    }
+    group.finish();
+}
+
+// Restart density: restarts per 1000 items
+fn restarts(c: &mut Criterion) {
+    let mut group = c.benchmark_group("restarts");
+    group.throughput(Throughput::Elements(N_ITEMS as u64));
+    for density in [0, 10, 500] {
+        let strm = Workload::new(N_ITEMS).restarts(density).rstream(());
+        group.bench_with_input(
+            BenchmarkId::from_parameter(density),
+            &strm,
+            |b, strm| {
+                let mut m = bench::sum_since('b');
+                b.iter(|| bench::run(&mut m, black_box(strm)))
+            },
+        );
+    }
+    group.finish();
+}
+
+// Batched evaluation (see DataTransducer::update_chunk)
+fn batch(c: &mut Criterion) {
+    let events = Workload::new(N_ITEMS).events();
+    let mut group = c.benchmark_group("update_batch");
+    group.throughput(Throughput::Elements(N_ITEMS as u64));
+    group.bench_function("update", |b| {
+        let mut m = bench::epsilon_chain(10);
+        m.init_one(0);
+        b.iter(|| events.iter().map(|e| m.update(e)).count())
+    });
+    group.bench_function("update_batch", |b| {
+        let mut m = bench::epsilon_chain(10);
+        m.init_one(0);
+        b.iter(|| m.update_batch(black_box(&events)).len())
+    });
+    group.finish();
+}
+
+// Key cardinality, in the keyed runtime
+fn keys(c: &mut Criterion) {
+    let mut group = c.benchmark_group("keyed_runtime");
+    group.throughput(Throughput::Elements(N_ITEMS as u64));
+    for n_keys in [1, 100, 10_000] {
+        let events = Workload::new(N_ITEMS).keys(n_keys).events();
+        let rt = KeyedRuntime::new(bench::sum(), 0, |e: &bench::Event| e.key)
+            .workers(2);
+        group.bench_with_input(
+            BenchmarkId::from_parameter(n_keys),
+            &events,
+            |b, events| b.iter(|| rt.run(events.iter().copied()).len()),
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, epsilons, concat, restarts, batch, keys);
+criterion_main!(benches);
@@ -0,0 +1,60 @@
+/*
+    Example of min-plus and max-plus (tropical) QREs: optimal-cost matches.
+
+    The input is a stream of (tag, cost) events. A "route" is an 'a' event
+    immediately followed by either a 'b' event or a 'c' event, where a 'c'
+    event costs double. The queries report, each time a route completes,
+    the cheapest and the most expensive route seen so far.
+*/
+
+use data_transducers::ext_value::Ext;
+use data_transducers::interface::Transducer;
+use data_transducers::qre::{atom, concat, stream_iden};
+use data_transducers::semiring::{sr_sum, MaxPlus, MinPlus, Semiring};
+
+type Event = (char, i64);
+
+fn cost(&(tag, c): &Event) -> i64 {
+    if tag == 'c' {
+        2 * c
+    } else {
+        c
+    }
+}
+
+fn route<S, F>(weight: F) -> impl Transducer<S, Event, S>
+where
+    S: Semiring + Clone,
+    F: Fn(i64) -> S + Clone,
+{
+    let weight2 = weight.clone();
+    let start = atom(
+        |&(t, _c): &Event| t == 'a',
+        move |s: S, d: &Event| s.times(&weight(cost(d))),
+    );
+    let end = atom(
+        |&(t, _c): &Event| t == 'b' || t == 'c',
+        move |s: S, d: &Event| s.times(&weight2(cost(d))),
+    );
+    concat(start, end)
+}
+
+fn main() {
+    println!("=== Min-Plus Example ===");
+    let mut cheapest = sr_sum(concat(stream_iden(), route(MinPlus)));
+    let mut costliest = sr_sum(concat(stream_iden(), route(MaxPlus)));
+    let events =
+        vec![('a', 3), ('b', 4), ('a', 1), ('c', 2), ('a', 2), ('b', 9)];
+    cheapest.init_one(MinPlus::one());
+    costliest.init_one(MaxPlus::one());
+    for event in events {
+        let min = cheapest.update(&event);
+        let max = costliest.update(&event);
+        match (min, max) {
+            (Ext::One(MinPlus(min)), Ext::One(MaxPlus(max))) => {
+                println!("{:?}: routes so far cost {} to {}", event, min, max)
+            }
+            _ => println!("{:?}: no route completed", event),
+        }
+    }
+}
@@ -0,0 +1,82 @@
+/*
+    Fuzz target: the optimization passes on DataTransducers.
+
+    The input is decoded as the seed and shape of a random machine (see
+    random.rs) and a stream of items and restarts. Epsilon elimination
+    (when there are no epsilon cycles), dead state removal, and
+    minimization must not change the outputs of the machine on the stream.
+
+    Run with: cargo fuzz run optimize
+*/
+
+#![no_main]
+
+use arbitrary::Arbitrary;
+use data_transducers::ext_value::Ext;
+use data_transducers::interface::{RInput, Transducer};
+use data_transducers::random::{random_machine, MachineConfig, Rng, ALPHABET};
+use data_transducers::state_machine::DataTransducer;
+use libfuzzer_sys::fuzz_target;
+
+#[derive(Arbitrary, Debug)]
+struct Input {
+    seed: u64,
+    n_states: u8,
+    n_updates: u8,
+    n_epsilons: u8,
+    eps_cycles: bool,
+    steps: Vec<Step>,
+}
+
+#[derive(Arbitrary, Clone, Copy, Debug)]
+enum Step {
+    Restart(i8),
+    Item(u8),
+}
+
+fn run(
+    m: &mut DataTransducer<'_, char, i64>,
+    strm: &[RInput<i64, char>],
+) -> Vec<Ext<i64>> {
+    m.reset();
+    m.process_rstream_single(strm.iter().copied()).collect()
+}
+
+fuzz_target!(|input: Input| {
+    if input.steps.len() > 64 {
+        return;
+    }
+    let config = MachineConfig {
+        n_states: 2 + input.n_states as usize % 10,
+        n_updates: input.n_updates as usize % 16,
+        n_epsilons: input.n_epsilons as usize % 12,
+        eps_cycles: input.eps_cycles,
+        eps_nullary: false,
+    };
+    let mut m = random_machine(&mut Rng::new(input.seed), &config);
+    let strm: Vec<RInput<i64, char>> = input
+        .steps
+        .iter()
+        .map(|&step| match step {
+            Step::Restart(i) => RInput::Restart(i as i64),
+            Step::Item(ch) => {
+                RInput::Item(ALPHABET[ch as usize % ALPHABET.len()])
+            }
+        })
+        .collect();
+    let expected = run(&mut m, &strm);
+
+    let mut opt = m.clone();
+    opt.remove_dead_states();
+    assert_eq!(run(&mut opt, &strm), expected, "remove_dead_states {:?}", m);
+    let mut opt = m.clone();
+    opt.minimize(ALPHABET);
+    assert_eq!(run(&mut opt, &strm), expected, "minimize {:?}", m);
+    let mut opt = m.clone();
+    if opt.eliminate_epsilons().is_ok() {
+        assert_eq!(run(&mut opt, &strm), expected, "eliminate {:?}", m);
+        opt.remove_dead_states();
+        opt.minimize(ALPHABET);
+        assert_eq!(run(&mut opt, &strm), expected, "all passes {:?}", m);
+    }
+});
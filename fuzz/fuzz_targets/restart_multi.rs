@@ -0,0 +1,130 @@
+/*
+    Fuzz target: restartability of the QRE combinators.
+
+    The input is decoded as a QRE (over the chars of random::ALPHABET, with
+    i32 values) and a stream of items and restarts. If the QRE claims to be
+    restartable, processing the stream with one transducer (restarts being
+    .init()) must agree with spawning a fresh transducer at each restart and
+    summing their outputs. Its lowering to a DataTransducer must also agree
+    with it (see lower::conformance), which exercises the epsilon closure.
+    QREs which can't be constructed (see qre::try_concat) are skipped.
+
+    Run with: cargo fuzz run restart_multi
+*/
+
+#![no_main]
+
+use arbitrary::Arbitrary;
+use data_transducers::ext_value::Ext;
+use data_transducers::interface::{RInput, Transducer};
+use data_transducers::lower::{conformance, Lower};
+use data_transducers::qre::{atom, epsilon, try_concat, try_iterate, union};
+use data_transducers::random::ALPHABET;
+use libfuzzer_sys::fuzz_target;
+
+#[derive(Arbitrary, Clone, Debug)]
+enum Ast {
+    Eps(i8),
+    Atom(u8, i8),
+    Union(Box<Ast>, Box<Ast>),
+    Concat(Box<Ast>, Box<Ast>),
+    Iter(Box<Ast>),
+}
+
+#[derive(Arbitrary, Clone, Copy, Debug)]
+enum Step {
+    Restart(i8),
+    Item(u8),
+}
+
+fn letter(n: u8) -> char {
+    ALPHABET[n as usize % ALPHABET.len()]
+}
+
+trait Qre: Transducer<i32, char, i32> + Lower<'static, char, i32> {}
+impl<M> Qre for M where M: Transducer<i32, char, i32> + Lower<'static, char, i32>
+{}
+
+fn build(ast: &Ast) -> Option<Box<dyn Qre>> {
+    Some(match *ast {
+        Ast::Eps(k) => {
+            Box::new(epsilon(move |i: i32| i.wrapping_add(k as i32)))
+        }
+        Ast::Atom(ch, k) => {
+            let ch = letter(ch);
+            Box::new(atom(
+                move |&d: &char| d == ch,
+                move |i: i32, _d: &char| {
+                    i.wrapping_mul(2).wrapping_add(k as i32)
+                },
+            ))
+        }
+        Ast::Union(ref a1, ref a2) => Box::new(union(build(a1)?, build(a2)?)),
+        Ast::Concat(ref a1, ref a2) => {
+            Box::new(try_concat(build(a1)?, build(a2)?).ok()?)
+        }
+        Ast::Iter(ref a) => Box::new(try_iterate(build(a)?).ok()?),
+    })
+}
+
+fn depth(ast: &Ast) -> usize {
+    match ast {
+        Ast::Eps(_) | Ast::Atom(..) => 0,
+        Ast::Union(a1, a2) | Ast::Concat(a1, a2) => {
+            1 + depth(a1).max(depth(a2))
+        }
+        Ast::Iter(a) => 1 + depth(a),
+    }
+}
+
+// Run one transducer, or a fresh one (from spawn) for each restart
+fn single<M>(m: &mut M, strm: &[RInput<i32, char>]) -> Vec<Ext<i32>>
+where
+    M: Transducer<i32, char, i32>,
+{
+    m.process_rstream_single(strm.iter().copied()).collect()
+}
+fn multi<M, F>(spawn: F, strm: &[RInput<i32, char>]) -> Vec<Ext<i32>>
+where
+    M: Transducer<i32, char, i32>,
+    F: Fn() -> M,
+{
+    let mut ms: Vec<M> = Vec::new();
+    let mut result = Vec::new();
+    for item in strm {
+        result.push(match *item {
+            RInput::Restart(i) => {
+                ms.push(spawn());
+                ms.last_mut().unwrap().init_one(i)
+            }
+            RInput::Item(d) => {
+                ms.iter_mut().fold(Ext::None, |out, m| out + m.update(&d))
+            }
+        });
+    }
+    result
+}
+
+fuzz_target!(|input: (Ast, Vec<Step>)| {
+    let (ast, steps) = input;
+    if depth(&ast) > 8 || steps.len() > 64 {
+        return;
+    }
+    let mut m = match build(&ast) {
+        Some(m) if m.is_restartable() => m,
+        _ => return,
+    };
+    let strm: Vec<RInput<i32, char>> = steps
+        .iter()
+        .map(|&step| match step {
+            Step::Restart(i) => RInput::Restart(i as i32),
+            Step::Item(ch) => RInput::Item(letter(ch)),
+        })
+        .collect();
+
+    let expected = multi(|| build(&ast).unwrap(), &strm);
+    assert_eq!(single(&mut m, &strm), expected, "{:?} on {:?}", ast, strm);
+    if let Err(div) = conformance(&mut m, &[strm]) {
+        panic!("lowering disagrees: {:?}\n{}", ast, div);
+    }
+});
@@ -0,0 +1,500 @@
+/*
+    Aggregation functions with declared algebraic properties.
+
+    Some evaluation strategies for an aggregate are only correct if its
+    binary operation has the right properties: splitting the items into
+    chunks which are aggregated separately (e.g. in parallel) needs the
+    operation to be associative, and a sliding window which subtracts the
+    items it evicts needs an inverse (and commutativity, since the oldest
+    item is removed from the front). If the operation doesn't have the
+    property, these silently give wrong results (floating-point addition
+    is the classic case: it isn't associative).
+
+    An AggFn is an operation with a unit, and the properties it is
+    declared to have:
+        agg_fn(0, |x, y| x + y).associative().commutative()
+            .with_inverse(|x, y| x - y)
+    Declared properties enable the optimizations:
+    - .fold_chunks(items, n) aggregates n chunks of the items separately
+      and combines the results if associative (on the rayon pool, with the
+      "parallel" feature), and sequentially otherwise;
+    - .sliding(size) is a sliding window over the items, which keeps a
+      running aggregate and subtracts the evicted items if invertible, and
+      otherwise aggregates the whole window after each item.
+    Either way the output is the same as the sequential fold, provided the
+    declarations are true.
+
+    Declarations can't be checked exactly, since the operation is an
+    arbitrary function; instead, .check(samples) tests them on all
+    combinations of the sample values, and returns the first violation,
+    and probe(op, inverse, samples) reports the properties which
+    hold on the samples. This is intended for unit tests of user code.
+    Note: there are samples.len()^3 combinations for associativity.
+*/
+
+use super::ext_value::Ext;
+use super::interface::Transducer;
+use std::collections::VecDeque;
+use std::fmt::{self, Debug, Display};
+use std::mem;
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct AggProps {
+    pub associative: bool,
+    pub commutative: bool,
+    // There is an inverse: inverse(op(x, y), y) == x
+    pub invertible: bool,
+}
+
+/*
+    Property violations
+*/
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Violation<T> {
+    // op(unit, x) != x or op(x, unit) != x
+    Unit(T),
+    // op(op(x, y), z) != op(x, op(y, z))
+    Associative(T, T, T),
+    // op(x, y) != op(y, x)
+    Commutative(T, T),
+    // inverse(op(x, y), y) != x
+    Inverse(T, T),
+}
+impl<T: Debug> Display for Violation<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Violation::Unit(x) => write!(f, "not a unit for {:?}", x),
+            Violation::Associative(x, y, z) => {
+                write!(f, "not associative on {:?}, {:?}, {:?}", x, y, z)
+            }
+            Violation::Commutative(x, y) => {
+                write!(f, "not commutative on {:?}, {:?}", x, y)
+            }
+            Violation::Inverse(x, y) => {
+                write!(f, "inverse fails on {:?}, {:?}", x, y)
+            }
+        }
+    }
+}
+
+pub fn check_unit<T, F>(unit: &T, op: F, samples: &[T]) -> Result<(), T>
+where
+    T: Clone + Eq,
+    F: Fn(&T, &T) -> T,
+{
+    match samples.iter().find(|&x| op(unit, x) != *x || op(x, unit) != *x) {
+        Some(x) => Err(x.clone()),
+        None => Ok(()),
+    }
+}
+pub fn check_associative<T, F>(op: F, samples: &[T]) -> Result<(), (T, T, T)>
+where
+    T: Clone + Eq,
+    F: Fn(&T, &T) -> T,
+{
+    for x in samples {
+        for y in samples {
+            let xy = op(x, y);
+            for z in samples {
+                if op(&xy, z) != op(x, &op(y, z)) {
+                    return Err((x.clone(), y.clone(), z.clone()));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+pub fn check_commutative<T, F>(op: F, samples: &[T]) -> Result<(), (T, T)>
+where
+    T: Clone + Eq,
+    F: Fn(&T, &T) -> T,
+{
+    for (n, x) in samples.iter().enumerate() {
+        for y in &samples[n + 1..] {
+            if op(x, y) != op(y, x) {
+                return Err((x.clone(), y.clone()));
+            }
+        }
+    }
+    Ok(())
+}
+pub fn check_inverse<T, F, G>(
+    op: F,
+    inverse: G,
+    samples: &[T],
+) -> Result<(), (T, T)>
+where
+    T: Clone + Eq,
+    F: Fn(&T, &T) -> T,
+    G: Fn(&T, &T) -> T,
+{
+    for x in samples {
+        for y in samples {
+            if inverse(&op(x, y), y) != *x {
+                return Err((x.clone(), y.clone()));
+            }
+        }
+    }
+    Ok(())
+}
+
+// The properties which hold on the samples (invertible only if an inverse
+// is given)
+pub fn probe<T, F, G>(op: F, inverse: Option<G>, samples: &[T]) -> AggProps
+where
+    T: Clone + Eq,
+    F: Fn(&T, &T) -> T,
+    G: Fn(&T, &T) -> T,
+{
+    AggProps {
+        associative: check_associative(&op, samples).is_ok(),
+        commutative: check_commutative(&op, samples).is_ok(),
+        invertible: inverse
+            .is_some_and(|inv| check_inverse(&op, inv, samples).is_ok()),
+    }
+}
+
+/*
+    Aggregation functions
+*/
+
+type NoInverse<T> = fn(&T, &T) -> T;
+
+#[derive(Clone)]
+pub struct AggFn<T, F, G>
+where
+    F: Fn(&T, &T) -> T,
+    G: Fn(&T, &T) -> T,
+{
+    unit: T,
+    op: F,
+    inverse: Option<G>,
+    props: AggProps,
+}
+// An aggregation function with no declared properties
+pub fn agg_fn<T, F>(unit: T, op: F) -> AggFn<T, F, NoInverse<T>>
+where
+    F: Fn(&T, &T) -> T,
+{
+    AggFn { unit, op, inverse: None, props: AggProps::default() }
+}
+
+impl<T, F, G> AggFn<T, F, G>
+where
+    F: Fn(&T, &T) -> T,
+    G: Fn(&T, &T) -> T,
+{
+    /* Declarations */
+
+    pub fn associative(mut self) -> Self {
+        self.props.associative = true;
+        self
+    }
+    pub fn commutative(mut self) -> Self {
+        self.props.commutative = true;
+        self
+    }
+    pub fn with_inverse<G2>(self, inverse: G2) -> AggFn<T, F, G2>
+    where
+        G2: Fn(&T, &T) -> T,
+    {
+        let props = AggProps { invertible: true, ..self.props };
+        AggFn { unit: self.unit, op: self.op, inverse: Some(inverse), props }
+    }
+    pub fn props(&self) -> AggProps {
+        self.props
+    }
+
+    // Test the declared properties (and the unit) on the samples
+    pub fn check(&self, samples: &[T]) -> Result<(), Violation<T>>
+    where
+        T: Clone + Eq,
+    {
+        check_unit(&self.unit, &self.op, samples).map_err(Violation::Unit)?;
+        if self.props.associative {
+            check_associative(&self.op, samples)
+                .map_err(|(x, y, z)| Violation::Associative(x, y, z))?;
+        }
+        if self.props.commutative {
+            check_commutative(&self.op, samples)
+                .map_err(|(x, y)| Violation::Commutative(x, y))?;
+        }
+        if let Some(inverse) = &self.inverse {
+            check_inverse(&self.op, inverse, samples)
+                .map_err(|(x, y)| Violation::Inverse(x, y))?;
+        }
+        Ok(())
+    }
+
+    /* Evaluation */
+
+    pub fn fold(&self, items: &[T]) -> T
+    where
+        T: Clone,
+    {
+        items.iter().fold(self.unit.clone(), |acc, x| (self.op)(&acc, x))
+    }
+
+    // Fold n_chunks chunks of the items separately, then combine them in
+    // order; only if associative, otherwise the same as .fold()
+    #[cfg(not(feature = "parallel"))]
+    pub fn fold_chunks(&self, items: &[T], n_chunks: usize) -> T
+    where
+        T: Clone,
+    {
+        if !self.props.associative || n_chunks <= 1 || items.is_empty() {
+            return self.fold(items);
+        }
+        let chunk_len = items.len().div_ceil(n_chunks);
+        let partials: Vec<T> =
+            items.chunks(chunk_len).map(|c| self.fold(c)).collect();
+        self.fold(&partials)
+    }
+    #[cfg(feature = "parallel")]
+    pub fn fold_chunks(&self, items: &[T], n_chunks: usize) -> T
+    where
+        T: Clone + Send + Sync,
+        F: Sync,
+    {
+        use rayon::prelude::*;
+        if !self.props.associative || n_chunks <= 1 || items.is_empty() {
+            return self.fold(items);
+        }
+        let chunk_len = items.len().div_ceil(n_chunks);
+        let (unit, op) = (&self.unit, &self.op);
+        let fold = |c: &[T]| c.iter().fold(unit.clone(), |acc, x| op(&acc, x));
+        let partials: Vec<T> = items.par_chunks(chunk_len).map(fold).collect();
+        self.fold(&partials)
+    }
+
+    // A sliding window of the last size items; see SlidingAgg below
+    pub fn sliding(self, size: usize) -> SlidingAgg<T, F, G> {
+        assert!(size > 0, "window size must be positive");
+        SlidingAgg {
+            agg: self,
+            size,
+            started: false,
+            window: VecDeque::new(),
+            acc: None,
+        }
+    }
+}
+
+/*
+    Sliding windows
+
+    Same semantics as qre::sliding(size, m) where m is the fold of agg:
+    after each item from the size-th on, outputs the aggregate of the last
+    size items. If the aggregation function is declared associative,
+    commutative and invertible, each update costs one op and one inverse;
+    otherwise, it refolds the window, for size ops.
+*/
+
+#[derive(Clone)]
+pub struct SlidingAgg<T, F, G>
+where
+    F: Fn(&T, &T) -> T,
+    G: Fn(&T, &T) -> T,
+{
+    agg: AggFn<T, F, G>,
+    size: usize,
+    started: bool,
+    window: VecDeque<T>,
+    // Running aggregate of the window (subtract-on-evict only)
+    acc: Option<T>,
+}
+impl<T, F, G> SlidingAgg<T, F, G>
+where
+    F: Fn(&T, &T) -> T,
+    G: Fn(&T, &T) -> T,
+{
+    pub fn subtracts_on_evict(&self) -> bool {
+        let p = self.agg.props;
+        p.associative && p.commutative && p.invertible
+    }
+}
+impl<T, F, G> Transducer<(), T, T> for SlidingAgg<T, F, G>
+where
+    T: Clone,
+    F: Fn(&T, &T) -> T,
+    G: Fn(&T, &T) -> T,
+{
+    fn init(&mut self, i: Ext<()>) -> Ext<T> {
+        if i.is_none() {
+            return Ext::None;
+        }
+        self.started = true;
+        self.window.clear();
+        self.acc = None;
+        Ext::None
+    }
+    fn update(&mut self, item: &T) -> Ext<T> {
+        if !self.started {
+            return Ext::None;
+        }
+        self.window.push_back(item.clone());
+        let evicted = if self.window.len() > self.size {
+            self.window.pop_front()
+        } else {
+            None
+        };
+        let out = if self.subtracts_on_evict() {
+            let acc = self.acc.take().unwrap_or_else(|| self.agg.unit.clone());
+            let mut acc = (self.agg.op)(&acc, item);
+            if let (Some(old), Some(inverse)) = (&evicted, &self.agg.inverse) {
+                acc = inverse(&acc, old);
+            }
+            self.acc = Some(acc.clone());
+            acc
+        } else {
+            self.window
+                .iter()
+                .fold(self.agg.unit.clone(), |acc, x| (self.agg.op)(&acc, x))
+        };
+        if self.window.len() == self.size {
+            Ext::One(out)
+        } else {
+            Ext::None
+        }
+    }
+    fn reset(&mut self) {
+        self.started = false;
+        self.window.clear();
+        self.acc = None;
+    }
+
+    fn is_epsilon(&self) -> bool {
+        false
+    }
+    fn is_restartable(&self) -> bool {
+        false
+    }
+    fn is_nullable(&self) -> bool {
+        false
+    }
+    fn n_states(&self) -> usize {
+        2
+    }
+    fn n_transs(&self) -> usize {
+        if self.subtracts_on_evict() {
+            2
+        } else {
+            1
+        }
+    }
+    fn n_bytes(&self) -> usize {
+        mem::size_of::<Self>() + self.window.capacity() * mem::size_of::<T>()
+    }
+}
+
+/*
+    Unit Tests
+*/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::qre::{atom, concat, epsilon, iterate, sliding};
+
+    const SAMPLES: &[i64] = &[0, 1, -2, 3, 7, i64::MAX];
+
+    fn sum() -> AggFn<i64, impl Fn(&i64, &i64) -> i64 + Clone, NoInverse<i64>> {
+        agg_fn(0, |x: &i64, y: &i64| x.wrapping_add(*y))
+    }
+
+    #[test]
+    fn test_checks() {
+        let sum = sum().associative().commutative();
+        assert_eq!(sum.check(SAMPLES), Ok(()));
+        let sum = sum.with_inverse(|x: &i64, y: &i64| x.wrapping_sub(*y));
+        assert_eq!(sum.check(SAMPLES), Ok(()));
+        assert!(sum.props().invertible);
+
+        let diff = agg_fn(0, |x: &i64, y: &i64| x.wrapping_sub(*y));
+        assert_eq!(diff.check(SAMPLES), Err(Violation::Unit(1)));
+        let first = agg_fn(0, |x: &i64, y: &i64| if *x == 0 { *y } else { *x });
+        assert_eq!(first.clone().check(SAMPLES), Ok(()));
+        let err = first.commutative().check(SAMPLES).unwrap_err();
+        assert_eq!(err, Violation::Commutative(1, -2));
+        assert_eq!(err.to_string(), "not commutative on 1, -2");
+        let avg = agg_fn(0, |x: &i64, y: &i64| (x + y) / 2).associative();
+        assert!(matches!(avg.check(&[0, 4, 8]), Err(Violation::Unit(4))));
+        assert!(check_associative(|x, y| (x + y) / 2, &[0, 4, 8]).is_err());
+    }
+
+    #[test]
+    fn test_probe() {
+        let inv = |x: &i64, y: &i64| x.wrapping_sub(*y);
+        let props =
+            probe(|x: &i64, y: &i64| x.wrapping_add(*y), Some(inv), SAMPLES);
+        let all =
+            AggProps { associative: true, commutative: true, invertible: true };
+        assert_eq!(props, all);
+        // Saturating addition isn't associative, and can't be undone
+        let props =
+            probe(|x: &i64, y: &i64| x.saturating_add(*y), Some(inv), SAMPLES);
+        assert_eq!(props, AggProps { commutative: true, ..Default::default() });
+        let props = probe(
+            |x: &i64, y: &i64| *x.max(y),
+            None::<NoInverse<i64>>,
+            SAMPLES,
+        );
+        assert_eq!(props, AggProps { invertible: false, ..all });
+    }
+
+    // Bit patterns of f64s, as floats aren't Eq
+    #[test]
+    fn test_float_not_associative() {
+        let add = |x: &u64, y: &u64| {
+            (f64::from_bits(*x) + f64::from_bits(*y)).to_bits()
+        };
+        let samples: Vec<u64> =
+            [0.1, 0.2, 0.3].iter().map(|x: &f64| x.to_bits()).collect();
+        assert!(check_commutative(add, &samples).is_ok());
+        assert!(check_associative(add, &samples).is_err());
+        let err = agg_fn(0.0f64.to_bits(), add).associative().check(&samples);
+        assert!(matches!(err, Err(Violation::Associative(..))));
+    }
+
+    #[test]
+    fn test_fold_chunks() {
+        let items: Vec<i64> = (1..=100).collect();
+        let sum = sum().associative();
+        assert_eq!(sum.fold(&items), 5050);
+        for n in [0, 1, 3, 7, 100, 200] {
+            assert_eq!(sum.fold_chunks(&items, n), 5050);
+            assert_eq!(sum.fold_chunks(&[], n), 0);
+        }
+        // Not associative: sequential
+        let op = |x: &i64, y: &i64| x * 2 + y;
+        let expected = items[..10].iter().fold(0, |x, y| op(&x, y));
+        assert_eq!(agg_fn(0, op).fold_chunks(&items[..10], 3), expected);
+    }
+
+    #[test]
+    fn test_sliding() {
+        let items: Vec<i64> = vec![3, 1, 4, 1, 5, 9, 2, 6, 5, 3, 5];
+        let step = atom(|_: &i64| true, |x: i64, y: &i64| x + y);
+        let fold = concat(epsilon(|()| 0), iterate(step));
+        let mut reference = sliding(3, fold);
+        reference.init_one(());
+        let expected = reference.update_batch(&items);
+
+        let sum = sum().associative().commutative();
+        let mut refold = sum.clone().sliding(3);
+        let mut subtract =
+            sum.with_inverse(|x: &i64, y: &i64| x.wrapping_sub(*y)).sliding(3);
+        assert!(!refold.subtracts_on_evict());
+        assert!(subtract.subtracts_on_evict());
+        assert_eq!(refold.update(&1), Ext::None);
+        for m in
+            [&mut refold as &mut dyn Transducer<(), i64, i64>, &mut subtract]
+        {
+            assert_eq!(m.init_one(()), Ext::None);
+            assert_eq!(m.update_batch(&items), expected);
+            m.init_one(());
+            assert_eq!(m.update_batch(&items), expected);
+        }
+    }
+}
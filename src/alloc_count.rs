@@ -0,0 +1,179 @@
+/*
+    Allocation accounting: counting the heap allocations made by each step
+    of a transducer.
+
+    Latency-sensitive uses need steps which don't allocate. CountingAlloc
+    is a global allocator (wrapping the system allocator) which counts the
+    allocations made by each thread; install it in a test or benchmark
+    binary with
+        #[global_allocator]
+        static ALLOC: CountingAlloc = CountingAlloc;
+    and then count_allocs(f) gives the number of allocations made by f
+    (on the current thread), and allocs_per_update() the number made by
+    each .update() of a transducer on some items.
+
+    The core combinators of qre.rs (epsilon, atom, union, parcomp, concat,
+    iterate, aggregate, and the derived constructs) don't allocate in
+    .init() or .update(): they only move Ext values between fields, so a
+    query built from them allocates only if its actions or its output
+    values do (e.g. returning a String or a Vec). The tests below enforce
+    this. So does DataTransducer (state_machine.rs), whose steps reuse
+    scratch buffers kept on the machine: once these have grown to the size
+    of the machine (usually on the first .init()), its steps don't allocate
+    either, unless dispatch, profiling, recording, or outputs of transitions
+    are enabled.
+*/
+
+use super::ext_value::Ext;
+use super::interface::Transducer;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+
+thread_local! {
+    // Const-initialized, so that accessing it doesn't allocate
+    static N_ALLOCS: Cell<u64> = const { Cell::new(0) };
+}
+
+pub struct CountingAlloc;
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        // The thread-local may be gone if the thread is exiting
+        let _ = N_ALLOCS.try_with(|n| n.set(n.get() + 1));
+        System.alloc(layout)
+    }
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let _ = N_ALLOCS.try_with(|n| n.set(n.get() + 1));
+        System.alloc_zeroed(layout)
+    }
+    unsafe fn realloc(
+        &self,
+        ptr: *mut u8,
+        layout: Layout,
+        new_size: usize,
+    ) -> *mut u8 {
+        let _ = N_ALLOCS.try_with(|n| n.set(n.get() + 1));
+        System.realloc(ptr, layout, new_size)
+    }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+// Number of allocations made so far on this thread (including
+// reallocations); always 0 if CountingAlloc is not installed
+pub fn n_allocs() -> u64 {
+    N_ALLOCS.with(|n| n.get())
+}
+
+// Whether CountingAlloc is the global allocator
+pub fn is_installed() -> bool {
+    let before = n_allocs();
+    drop(std::hint::black_box(Box::new(0u8)));
+    n_allocs() > before
+}
+
+pub fn count_allocs<R, F: FnOnce() -> R>(f: F) -> (R, u64) {
+    let before = n_allocs();
+    let result = f();
+    (result, n_allocs() - before)
+}
+
+// The number of allocations made by each .update() of m on the items
+// (after initializing it with i). The outputs are dropped after counting,
+// so allocations they own are counted, but not their deallocation.
+pub fn allocs_per_update<I, D, O, M>(m: &mut M, i: I, items: &[D]) -> Vec<u64>
+where
+    M: Transducer<I, D, O>,
+{
+    assert!(is_installed(), "CountingAlloc is not the global allocator");
+    m.init(Ext::One(i));
+    items.iter().map(|item| count_allocs(|| m.update(item)).1).collect()
+}
+
+/*
+    Unit Tests
+*/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::qre::*;
+    use crate::state_machine::DataTransducer;
+
+    // Counting is per thread, so the other tests running in parallel
+    // don't interfere
+    #[global_allocator]
+    static ALLOC: CountingAlloc = CountingAlloc;
+
+    fn items() -> Vec<char> {
+        "ab0cb1aab2".chars().collect()
+    }
+    fn assert_no_allocs<O, M: Transducer<i32, char, O>>(mut m: M) {
+        let allocs = allocs_per_update(&mut m, 0, &items());
+        assert_eq!(allocs, vec![0; items().len()]);
+        let (_, n) = count_allocs(|| m.init_one(1));
+        assert_eq!(n, 0);
+    }
+
+    #[test]
+    fn test_count_allocs() {
+        assert!(is_installed());
+        let (v, n) = count_allocs(|| vec![1, 2, 3]);
+        assert_eq!((v.len(), n), (3, 1));
+        let (_, n) = count_allocs(|| {
+            let mut v = Vec::new();
+            for i in 0..100 {
+                v.push(i);
+            }
+        });
+        assert!(n > 1);
+        // Allocating actions are counted
+        let mut m = iterate(atom(
+            |_| true,
+            |s: String, &c: &char| s + "x" + &c.to_string(),
+        ));
+        m.init_one(String::new());
+        let (_, n) = count_allocs(|| m.update(&'a'));
+        assert!(n > 0);
+    }
+
+    #[test]
+    fn test_zero_alloc_combinators() {
+        let a = || atom(|&c: &char| c == 'a', |x: i32, _| x + 1);
+        let b = || atom(|&c: &char| c == 'b', |x: i32, _| x * 2);
+        let any = || atom(|_: &char| true, |x: i32, _| x);
+        assert_no_allocs(a());
+        assert_no_allocs(epsilon(|x: i32| x + 1));
+        assert_no_allocs(union(a(), b()));
+        assert_no_allocs(parcomp(a(), b()));
+        assert_no_allocs(union_op(a(), b(), |x, y| x + y));
+        assert_no_allocs(concat(iterate(any()), a()));
+        assert_no_allocs(iterate(union(a(), b())));
+        assert_no_allocs(concat(
+            epsilon(|x| (x, x)),
+            aggregate(iterate(a()), |s, y| s + y),
+        ));
+        assert_no_allocs(top(concat(
+            iterate(any()),
+            concat(a(), iterate(b())),
+        )));
+        // Boxed sub-transducers are allocated once, on construction
+        let boxed: Box<dyn Transducer<i32, char, i32>> = Box::new(a());
+        assert_no_allocs(concat(iterate(any()), boxed));
+    }
+
+    #[test]
+    fn test_zero_alloc_data_transducer() {
+        // x + 1 on each item, doubled at the next 'a'
+        let mut m: DataTransducer<char, i32> = DataTransducer::new();
+        m.set_nstates(4);
+        m.add_epsilon_iden(0, 2);
+        m.add_transition1(2, 2, |_| true, |_, x| x + 1);
+        m.add_transition1(2, 3, |&c| c == 'a', |_, x| x * 2);
+        m.add_epsilon1(3, 1, |x| x - 1);
+        assert_no_allocs(m.clone());
+        // With a cycle of epsilons, evaluated using the worklist
+        m.add_epsilon_iden(1, 3);
+        assert_no_allocs(m);
+    }
+}
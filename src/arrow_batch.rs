@@ -0,0 +1,219 @@
+/*
+    Arrow record batches as input (with the "arrow" feature).
+
+    Analytics data often comes as Arrow record batches: a table of rows
+    stored by column. The rows of the batches are the items of the stream,
+    as Row: a position in a batch, whose fields are read in place from the
+    column buffers (no row is materialized), so a guard such as
+        |r: &Row| r.i64("status") == Some(500)
+    only reads the one value. Fields are given by column index or name
+    (see ColumnRef); lookup by name scans the schema, so guards on hot
+    paths may prefer indices. A field is None if it is null, or if the
+    column is missing or of another type.
+
+    There are two ways to run a transducer on the batches:
+    - run_batches(m, init, batches) feeds the rows one at a time;
+    - run_batches_chunked(m, init, batches) feeds each batch to
+      .update_batch() as one slice of rows, which for a DataTransducer
+      evaluates the guards of each transition over a chunk of rows at a
+      time (see DataTransducer::update_chunk), i.e. column by column.
+    Both give the output after each row, as io::run_source does.
+*/
+
+use super::ext_value::Ext;
+use super::interface::Transducer;
+use arrow_array::cast::AsArray;
+use arrow_array::types::{Float64Type, Int64Type};
+use arrow_array::{Array, ArrayRef, ArrowPrimitiveType, RecordBatch};
+use std::rc::Rc;
+
+// A column of a batch, by index or by name
+pub trait ColumnRef {
+    fn column_index(&self, batch: &RecordBatch) -> Option<usize>;
+}
+impl ColumnRef for usize {
+    fn column_index(&self, batch: &RecordBatch) -> Option<usize> {
+        Some(*self).filter(|&i| i < batch.num_columns())
+    }
+}
+impl ColumnRef for &str {
+    fn column_index(&self, batch: &RecordBatch) -> Option<usize> {
+        batch.schema_ref().index_of(self).ok()
+    }
+}
+
+/*
+    Rows
+*/
+
+#[derive(Clone, Debug)]
+pub struct Row {
+    batch: Rc<RecordBatch>,
+    index: usize,
+}
+impl Row {
+    // Position in the batch
+    pub fn index(&self) -> usize {
+        self.index
+    }
+    pub fn batch(&self) -> &RecordBatch {
+        &self.batch
+    }
+
+    fn column(&self, col: impl ColumnRef) -> Option<&ArrayRef> {
+        let i = col.column_index(&self.batch)?;
+        Some(self.batch.column(i)).filter(|c| c.is_valid(self.index))
+    }
+    pub fn is_null(&self, col: impl ColumnRef) -> bool {
+        self.column(col).is_none()
+    }
+    pub fn value<T: ArrowPrimitiveType>(
+        &self,
+        col: impl ColumnRef,
+    ) -> Option<T::Native> {
+        let array = self.column(col)?.as_primitive_opt::<T>()?;
+        Some(array.value(self.index))
+    }
+    pub fn i64(&self, col: impl ColumnRef) -> Option<i64> {
+        self.value::<Int64Type>(col)
+    }
+    pub fn f64(&self, col: impl ColumnRef) -> Option<f64> {
+        self.value::<Float64Type>(col)
+    }
+    pub fn bool(&self, col: impl ColumnRef) -> Option<bool> {
+        Some(self.column(col)?.as_boolean_opt()?.value(self.index))
+    }
+    pub fn str(&self, col: impl ColumnRef) -> Option<&str> {
+        Some(self.column(col)?.as_string_opt::<i32>()?.value(self.index))
+    }
+}
+
+// The rows of a batch, in order
+pub fn rows(batch: RecordBatch) -> Vec<Row> {
+    let batch = Rc::new(batch);
+    (0..batch.num_rows())
+        .map(|index| Row { batch: Rc::clone(&batch), index })
+        .collect()
+}
+
+/*
+    Driving a transducer
+*/
+
+pub fn run_batches<I, O, M, It>(m: &mut M, init: I, batches: It) -> Vec<Ext<O>>
+where
+    M: Transducer<I, Row, O>,
+    It: IntoIterator<Item = RecordBatch>,
+{
+    m.init_one(init);
+    let mut result = Vec::new();
+    for batch in batches {
+        // One row, moved along the batch
+        let mut row = Row { batch: Rc::new(batch), index: 0 };
+        for index in 0..row.batch.num_rows() {
+            row.index = index;
+            result.push(m.update(&row));
+        }
+    }
+    result
+}
+
+pub fn run_batches_chunked<I, O, M, It>(
+    m: &mut M,
+    init: I,
+    batches: It,
+) -> Vec<Ext<O>>
+where
+    M: Transducer<I, Row, O>,
+    It: IntoIterator<Item = RecordBatch>,
+{
+    m.init_one(init);
+    let mut result = Vec::new();
+    for batch in batches {
+        result.extend(m.update_batch(&rows(batch)));
+    }
+    result
+}
+
+/*
+    Unit Tests
+*/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::qre::{atom, iterate, union};
+    use crate::state_machine::DataTransducer;
+    use arrow_array::{BooleanArray, Float64Array, Int64Array, StringArray};
+    use std::sync::Arc;
+
+    // Requests: (path, status, latency in ms)
+    fn batch(reqs: &[(&str, Option<i64>, f64)]) -> RecordBatch {
+        let paths: StringArray = reqs.iter().map(|r| Some(r.0)).collect();
+        let statuses: Int64Array = reqs.iter().map(|r| r.1).collect();
+        let latencies: Float64Array = reqs.iter().map(|r| Some(r.2)).collect();
+        let slow: BooleanArray = reqs.iter().map(|r| Some(r.2 > 1.0)).collect();
+        RecordBatch::try_from_iter(vec![
+            ("path", Arc::new(paths) as ArrayRef),
+            ("status", Arc::new(statuses) as ArrayRef),
+            ("latency", Arc::new(latencies) as ArrayRef),
+            ("slow", Arc::new(slow) as ArrayRef),
+        ])
+        .unwrap()
+    }
+    fn batches() -> Vec<RecordBatch> {
+        vec![
+            batch(&[("/a", Some(200), 0.5), ("/b", Some(500), 2.0)]),
+            batch(&[("/a", None, 1.5), ("/a", Some(500), 0.25)]),
+        ]
+    }
+
+    #[test]
+    fn test_rows() {
+        let rows = rows(batches().remove(1));
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0].str("path"), Some("/a"));
+        assert_eq!(rows[0].i64("status"), None);
+        assert!(rows[0].is_null(1));
+        assert_eq!(rows[1].i64(1), Some(500));
+        assert_eq!(rows[1].f64("latency"), Some(0.25));
+        assert_eq!(rows[0].bool("slow"), Some(true));
+        // Missing columns, or of another type
+        assert_eq!(rows[1].i64("path"), None);
+        assert_eq!(rows[1].str("nonexistent"), None);
+        assert_eq!(rows[1].str(7), None);
+    }
+
+    #[test]
+    fn test_run_batches() {
+        // Number of server errors, and of those slower than 1 ms
+        let error = |r: &Row| r.i64("status") == Some(500);
+        let mut m = iterate(union(
+            atom(error, |(n, k): (i64, i64), r: &Row| {
+                (n + 1, k + (r.f64("latency") > Some(1.0)) as i64)
+            }),
+            atom(move |r: &Row| !error(r), |nk, _| nk),
+        ));
+        let out = run_batches(&mut m, (0, 0), batches());
+        assert_eq!(out.len(), 4);
+        assert_eq!(out.last(), Some(&Ext::One((2, 1))));
+        m.reset();
+        assert_eq!(run_batches_chunked(&mut m, (0, 0), batches()), out);
+
+        // Count of the slow requests to /a, as a DataTransducer, whose
+        // .update_batch() is columnar
+        let mut m: DataTransducer<Row, i64> = DataTransducer::new();
+        m.add_epsilon_iden(0, 1);
+        m.add_transition1(
+            1,
+            1,
+            |r: &Row| r.str(0) == Some("/a") && r.bool(3) == Some(true),
+            |_, n| n + 1,
+        );
+        m.add_iden(1, 1, |r: &Row| {
+            r.str(0) != Some("/a") || r.bool(3) != Some(true)
+        });
+        let out = run_batches_chunked(&mut m, 0, batches());
+        assert_eq!(out, [Ext::One(0), Ext::One(0), Ext::One(1), Ext::One(1)]);
+    }
+}
@@ -0,0 +1,287 @@
+/*
+    Async pipeline driver (on tokio).
+
+    Deploying a transducer means wiring it between a source and a sink, with
+    some way to stop. Driver does this wiring as tokio tasks connected by
+    bounded channels, so that a slow stage applies backpressure upstream:
+    - .source(items) feeds the items of an iterator (e.g. an io.rs source)
+      into the pipeline, on a blocking thread;
+    - .input() gives a channel which async code can send items into;
+    - .stage(ch, m, init) runs a transducer on the items of a channel, and
+      forwards its outputs, so that stages can be chained;
+    - .sink(ch, sink) writes the items of a channel to an io::Sink, on a
+      blocking thread, and .collect(ch) gathers them instead.
+    A stage forwards the value of each Ext::One output; Ext::None outputs
+    are skipped, and an Ext::Many output is an error, which stops the stage.
+
+    Shutdown is graceful: .canceller().cancel() stops the sources, and each
+    stage then finishes the items already in its channel before closing its
+    output, so that nothing in flight is lost. (The pipeline also shuts down
+    when all sources are exhausted.) .join() waits for all the tasks and
+    reports the first error.
+
+    All methods which start tasks must be called in a tokio runtime.
+*/
+
+use super::ext_value::Ext;
+use super::interface::Transducer;
+use super::io::Sink;
+use std::fmt::{self, Display};
+use std::io;
+use std::sync::Arc;
+use tokio::sync::{mpsc, watch};
+use tokio::task::{self, JoinHandle};
+
+#[derive(Debug)]
+pub enum DriverError {
+    // A stage produced Ext::Many (on the given step, from 1)
+    Many { step: usize },
+    // A sink failed to write
+    Io(io::Error),
+    // A task panicked
+    Panicked(String),
+}
+impl Display for DriverError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DriverError::Many { step } => {
+                write!(f, "stage produced many outputs on step {}", step)
+            }
+            DriverError::Io(err) => write!(f, "sink failed: {}", err),
+            DriverError::Panicked(msg) => write!(f, "task panicked: {}", msg),
+        }
+    }
+}
+impl std::error::Error for DriverError {}
+
+// The receiving end of a channel in the pipeline
+pub struct Channel<D>(mpsc::Receiver<D>);
+impl<D> Channel<D> {
+    pub async fn recv(&mut self) -> Option<D> {
+        self.0.recv().await
+    }
+}
+
+#[derive(Clone)]
+pub struct Canceller(Arc<watch::Sender<bool>>);
+impl Canceller {
+    pub fn cancel(&self) {
+        // Fails only if no source is listening any more
+        let _ = self.0.send(true);
+    }
+}
+
+type Task = JoinHandle<Result<(), DriverError>>;
+
+pub struct Driver {
+    capacity: usize,
+    cancel: Canceller,
+    cancelled: watch::Receiver<bool>,
+    tasks: Vec<Task>,
+}
+impl Driver {
+    // capacity: the number of items buffered in each channel
+    pub fn new(capacity: usize) -> Self {
+        assert!(capacity > 0, "channels need a positive capacity");
+        let (tx, rx) = watch::channel(false);
+        Self {
+            capacity,
+            cancel: Canceller(Arc::new(tx)),
+            cancelled: rx,
+            tasks: Vec::new(),
+        }
+    }
+    pub fn canceller(&self) -> Canceller {
+        self.cancel.clone()
+    }
+
+    pub fn input<D>(&self) -> (mpsc::Sender<D>, Channel<D>) {
+        let (tx, rx) = mpsc::channel(self.capacity);
+        (tx, Channel(rx))
+    }
+
+    pub fn source<D, It>(&mut self, items: It) -> Channel<D>
+    where
+        D: Send + 'static,
+        It: IntoIterator<Item = D> + Send + 'static,
+    {
+        let (tx, rx) = self.input();
+        let cancelled = self.cancelled.clone();
+        self.tasks.push(task::spawn_blocking(move || {
+            for item in items {
+                if *cancelled.borrow() || tx.blocking_send(item).is_err() {
+                    break;
+                }
+            }
+            Ok(())
+        }));
+        rx
+    }
+
+    pub fn stage<I, D, O, M>(
+        &mut self,
+        mut input: Channel<D>,
+        mut m: M,
+        init: I,
+    ) -> Channel<O>
+    where
+        D: Send + 'static,
+        O: Send + 'static,
+        I: Send + 'static,
+        M: Transducer<I, D, O> + Send + 'static,
+    {
+        let (tx, rx) = self.input();
+        self.tasks.push(task::spawn(async move {
+            let mut outputs = vec![m.init_one(init)];
+            let mut step = 0;
+            loop {
+                for out in outputs.drain(..) {
+                    step += 1;
+                    match out {
+                        Ext::None => (),
+                        Ext::One(o) => {
+                            if tx.send(o).await.is_err() {
+                                // Nobody is listening any more
+                                return Ok(());
+                            }
+                        }
+                        Ext::Many => return Err(DriverError::Many { step }),
+                    }
+                }
+                match input.recv().await {
+                    Some(item) => outputs.push(m.update(&item)),
+                    None => return Ok(()),
+                }
+            }
+        }));
+        rx
+    }
+
+    pub fn sink<O, S>(&mut self, mut input: Channel<O>, mut sink: S)
+    where
+        O: Send + 'static,
+        S: Sink<O> + Send + 'static,
+    {
+        self.tasks.push(task::spawn_blocking(move || {
+            let mut step = 0;
+            while let Some(o) = input.0.blocking_recv() {
+                step += 1;
+                sink.write(step, &Ext::One(o)).map_err(DriverError::Io)?;
+            }
+            sink.flush().map_err(DriverError::Io)
+        }));
+    }
+
+    pub fn collect<O>(&mut self, mut input: Channel<O>) -> JoinHandle<Vec<O>>
+    where
+        O: Send + 'static,
+    {
+        task::spawn(async move {
+            let mut result = Vec::new();
+            while let Some(o) = input.recv().await {
+                result.push(o);
+            }
+            result
+        })
+    }
+
+    // Wait for all tasks to finish, returning the first error
+    pub async fn join(self) -> Result<(), DriverError> {
+        let mut result = Ok(());
+        for t in self.tasks {
+            let status = match t.await {
+                Ok(status) => status,
+                Err(err) => Err(DriverError::Panicked(err.to_string())),
+            };
+            if result.is_ok() {
+                result = status;
+            }
+        }
+        result
+    }
+}
+
+/*
+    Unit Tests
+*/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::sum;
+    use crate::io::CallbackSink;
+    use crate::qre::{atom, concat, stream_iden, union};
+    use std::sync::Mutex;
+
+    fn evens() -> impl Transducer<(), i64, i64> + Send {
+        concat(stream_iden(), atom(|&x: &i64| x % 2 == 0, |(), &x| x))
+    }
+
+    #[tokio::test]
+    async fn test_stages() {
+        let mut driver = Driver::new(4);
+        let items = driver.source(1..=100);
+        let sums = driver.stage(items, sum(), 0);
+        let out = driver.collect(sums);
+        driver.join().await.unwrap();
+        let out = out.await.unwrap();
+        assert_eq!(out.len(), 101);
+        assert_eq!(out[100], 5050);
+
+        // Chained, with None outputs skipped, into a sink
+        let mut driver = Driver::new(2);
+        let (tx, items) = driver.input();
+        let sums = driver.stage(items, sum(), 0);
+        let evens = driver.stage(sums, evens(), ());
+        let written = Arc::new(Mutex::new(Vec::new()));
+        let w = written.clone();
+        driver.sink(
+            evens,
+            CallbackSink(move |step, out: &Ext<i64>| {
+                w.lock().unwrap().push((step, *out));
+            }),
+        );
+        for x in 1..=4 {
+            tx.send(x).await.unwrap();
+        }
+        drop(tx);
+        driver.join().await.unwrap();
+        // The sums are 0, 1, 3, 6, 10, and the even ones are kept
+        let expected =
+            vec![(1, Ext::One(0)), (2, Ext::One(6)), (3, Ext::One(10))];
+        assert_eq!(*written.lock().unwrap(), expected);
+    }
+
+    #[tokio::test]
+    async fn test_cancel() {
+        let mut driver = Driver::new(1);
+        let items = driver.source(0..);
+        let mut sums = driver.stage(items, sum(), 0);
+        let canceller = driver.canceller();
+        let mut n = 0;
+        while sums.recv().await.is_some() {
+            n += 1;
+            if n == 10 {
+                canceller.cancel();
+            }
+        }
+        // Graceful: the items in flight are still processed
+        assert!(n >= 10);
+        driver.join().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_many() {
+        let mut driver = Driver::new(4);
+        let items = driver.source(vec![1, 2, 3]);
+        let m = union(
+            atom(|_| true, |(), &x: &i64| x),
+            atom(|_| true, |(), &x: &i64| x),
+        );
+        let out = driver.stage(items, m, ());
+        let out = driver.collect(out);
+        let err = driver.join().await.unwrap_err();
+        assert!(matches!(err, DriverError::Many { step: 2 }));
+        assert!(out.await.unwrap().is_empty());
+    }
+}
@@ -0,0 +1,217 @@
+/*
+    Synthetic workloads and canonical queries, for benchmarking (see
+    benches/transducers.rs).
+
+    A Workload describes a stream of Events, generated deterministically
+    from a seed (with the Rng of random.rs):
+    - the mix of event tags, as relative weights;
+    - the number of distinct keys (for keyed processing, see runtime.rs);
+    - the density of restarts, for streams with initial values (see
+      interface::RInput), as a number per 1000 items.
+    The canonical queries exercise the main evaluation paths:
+    - sum: iterate over an atom, the simplest aggregate;
+    - sum_since: the sum of the values since the last event with a tag,
+      which restarts a concatenation on each such event;
+    - epsilon_chain: a DataTransducer whose update feeds a chain of
+      epsilon transitions, so that most of its time is in eval_epsilons.
+*/
+
+use super::interface::{RInput, Transducer};
+use super::qre::{atom, concat, iterate, stream_iden};
+use super::random::Rng;
+use super::state_machine::DataTransducer;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Event {
+    pub key: u32,
+    pub tag: char,
+    pub value: i64,
+}
+
+#[derive(Clone, Debug)]
+pub struct Workload {
+    n_items: usize,
+    mix: Vec<(char, u32)>,
+    n_keys: u32,
+    restarts_per_1000: u64,
+    seed: u64,
+}
+impl Default for Workload {
+    fn default() -> Self {
+        Self {
+            n_items: 10_000,
+            mix: vec![('a', 8), ('b', 1), ('c', 1)],
+            n_keys: 1,
+            restarts_per_1000: 0,
+            seed: 1,
+        }
+    }
+}
+impl Workload {
+    pub fn new(n_items: usize) -> Self {
+        Self { n_items, ..Default::default() }
+    }
+    pub fn mix(mut self, mix: &[(char, u32)]) -> Self {
+        assert!(mix.iter().any(|&(_, w)| w > 0), "mix needs a positive weight");
+        self.mix = mix.to_vec();
+        self
+    }
+    pub fn keys(mut self, n_keys: u32) -> Self {
+        assert!(n_keys > 0, "need at least one key");
+        self.n_keys = n_keys;
+        self
+    }
+    pub fn restarts(mut self, per_1000: u64) -> Self {
+        assert!(per_1000 <= 1000, "at most one restart per item");
+        self.restarts_per_1000 = per_1000;
+        self
+    }
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    fn event(&self, rng: &mut Rng) -> Event {
+        let total: u64 = self.mix.iter().map(|&(_, w)| w as u64).sum();
+        let mut pick = rng.below(total);
+        let mut tag = self.mix[0].0;
+        for &(t, w) in &self.mix {
+            if pick < w as u64 {
+                tag = t;
+                break;
+            }
+            pick -= w as u64;
+        }
+        let key = rng.below(self.n_keys as u64) as u32;
+        let value = rng.below(201) as i64 - 100;
+        Event { key, tag, value }
+    }
+    pub fn events(&self) -> Vec<Event> {
+        let mut rng = Rng::new(self.seed);
+        (0..self.n_items).map(|_| self.event(&mut rng)).collect()
+    }
+    // The events, preceded by a restart with the initial value, and with
+    // further restarts (with the same value) at the chosen density; the
+    // events are the same as .events(), whatever the density
+    pub fn rstream<I: Clone>(&self, init: I) -> Vec<RInput<I, Event>> {
+        let mut rng = Rng::new(self.seed);
+        let mut restarts = Rng::new(!self.seed);
+        let mut result = vec![RInput::Restart(init.clone())];
+        for _ in 0..self.n_items {
+            if restarts.chance(self.restarts_per_1000, 1000) {
+                result.push(RInput::Restart(init.clone()));
+            }
+            result.push(RInput::Item(self.event(&mut rng)));
+        }
+        result
+    }
+}
+
+/*
+    Canonical queries
+*/
+
+pub fn sum() -> impl Transducer<i64, Event, i64> + Clone {
+    iterate(atom(|_| true, |s: i64, e: &Event| s.wrapping_add(e.value)))
+}
+
+pub fn sum_since(tag: char) -> impl Transducer<(), Event, i64> {
+    let start = atom(move |e: &Event| e.tag == tag, |(), _| 0);
+    let rest = iterate(atom(
+        move |e: &Event| e.tag != tag,
+        |s: i64, e: &Event| s.wrapping_add(e.value),
+    ));
+    concat(stream_iden(), concat(start, rest))
+}
+
+// Running sum, passed through a chain of n epsilons (each adding one)
+pub fn epsilon_chain<'a>(n: usize) -> DataTransducer<'a, Event, i64> {
+    let mut m = DataTransducer::new();
+    m.set_nstates(n + 3);
+    m.add_epsilon1(0, 2, |&q| q);
+    m.add_transition1(
+        2,
+        2,
+        |_| true,
+        |e: &Event, &q: &i64| q.wrapping_add(e.value),
+    );
+    for i in 2..n + 2 {
+        m.add_epsilon1(i, i + 1, |&q: &i64| q.wrapping_add(1));
+    }
+    m.add_epsilon1(n + 2, 1, |&q| q);
+    m
+}
+
+// Run a query over a stream with restarts, returning the number of outputs
+// (so that the work can't be optimized away)
+pub fn run<I, O, M>(m: &mut M, strm: &[RInput<I, Event>]) -> usize
+where
+    I: Clone,
+    M: Transducer<I, Event, O>,
+{
+    let mut n = 0;
+    for item in strm {
+        let out = match item {
+            RInput::Restart(i) => m.init_one(i.clone()),
+            RInput::Item(e) => m.update(e),
+        };
+        n += !out.is_none() as usize;
+    }
+    n
+}
+
+/*
+    Unit Tests
+*/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ext_value::Ext;
+
+    #[test]
+    fn test_workload() {
+        let w = Workload::new(1000).mix(&[('x', 3), ('y', 1)]).keys(5);
+        let events = w.events();
+        assert_eq!(events, w.events());
+        assert_ne!(events, w.clone().seed(2).events());
+        let n_x = events.iter().filter(|e| e.tag == 'x').count();
+        assert!(650 < n_x && n_x < 850);
+        assert!(events.iter().all(|e| e.key < 5 && e.value.abs() <= 100));
+
+        let strm = w.restarts(100).rstream(0);
+        let n_restarts =
+            strm.iter().filter(|i| matches!(i, RInput::Restart(_))).count();
+        assert!(50 < n_restarts && n_restarts < 150);
+        let items: Vec<Event> = strm
+            .into_iter()
+            .filter_map(|i| match i {
+                RInput::Item(e) => Some(e),
+                RInput::Restart(_) => None,
+            })
+            .collect();
+        assert_eq!(items, events);
+    }
+
+    #[test]
+    fn test_queries() {
+        let events = Workload::new(200).events();
+        let total: i64 = events.iter().map(|e| e.value).sum();
+        let mut m = sum();
+        m.init_one(0);
+        assert_eq!(*m.update_batch(&events).last().unwrap(), Ext::One(total));
+        let mut m = epsilon_chain(10);
+        m.init_one(0);
+        let last = *m.update_batch(&events).last().unwrap();
+        assert_eq!(last, Ext::One(total + 10));
+
+        let last_b = events.iter().rposition(|e| e.tag == 'b').unwrap();
+        let since: i64 = events[last_b + 1..].iter().map(|e| e.value).sum();
+        let mut m = sum_since('b');
+        m.init_one(());
+        assert_eq!(*m.update_batch(&events).last().unwrap(), Ext::One(since));
+
+        let strm = Workload::new(100).rstream(0);
+        assert_eq!(run(&mut sum(), &strm), 101);
+    }
+}
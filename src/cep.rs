@@ -0,0 +1,1073 @@
+/*
+    Complex event processing: a frontend for SASE-style event patterns.
+
+    A pattern is a sequence of components, each matching events of some
+    type (a guard on the items, see guard.rs) and binding them to a
+    variable:
+        SEQ(A a, B+ b[], !C c, OR(D d, E e), AND(F f, G g), H h)
+        WHERE [id] AND a.price < b[i].price AND b[i].price > b[i-1].price
+        WITHIN 100
+    - "A a" matches one event of type A;
+    - "B+ b[]" (Kleene plus) matches one or more events of type B;
+    - "!C c" (negation) matches if no event of type C occurs between the
+      events matched by the components before and after it;
+    - OR(..) matches one event of any of the types, AND(..) one event of
+      each of the types, in any order.
+    Events of other types in between are skipped (see Strategy).
+    Correlation predicates (WHERE) relate the attributes of the events:
+    "[id]" requires all events to have the same id, and comparisons
+    between attributes of variables (or constants) are checked as soon as
+    all their variables are bound. A Kleene variable refers to its latest
+    event, b[i] (the same), or the one before it, b[i-1]; a comparison
+    with b[i-1] holds on the first event. The events matched by a negation
+    must also satisfy the predicates mentioning it (e.g. "!Cancel c WHERE
+    c.id = a.id" only excludes cancellations of the same id). WITHIN bounds
+    the time from the first event to the last one, by default counted in
+    items (see Pattern::timed()).
+
+    Patterns are built with Pattern::new() and its methods, or parsed from
+    the syntax above with parse_pattern(). .compile() turns a pattern into
+    a Cep matcher: a transducer whose output on each item is the list of
+    matches completed by that item, as the events bound to each variable.
+
+    The matcher is not compiled into QRE combinators: correlation
+    predicates need the events bound so far, which QRE guards don't see,
+    and a single item can complete several overlapping matches, which
+    QREs (unambiguous, with at most one output per item) don't express.
+    Instead it is an automaton in the style of SASE's NFA with buffers:
+    it keeps a set of partial matches (runs), each at a component of the
+    pattern with its bindings so far, and advances each run on each item.
+    Each item matching the first component starts a new run, so a WITHIN
+    bound should be given to keep the number of runs bounded.
+*/
+
+use super::ext_value::Ext;
+use super::guard::Guard;
+use super::interface::Transducer;
+use super::scanner::{Scanner, SyntaxError};
+use super::timed::Time;
+use std::collections::HashSet;
+use std::error::Error;
+use std::fmt::{self, Display};
+use std::mem;
+use std::ops::Range;
+use std::rc::Rc;
+
+/*
+    Matches
+*/
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Match<D> {
+    names: Rc<Vec<String>>,
+    // The events bound to each variable, in order
+    events: Vec<Vec<D>>,
+}
+impl<D> Match<D> {
+    fn new(names: Rc<Vec<String>>) -> Self {
+        let events = names.iter().map(|_| Vec::new()).collect();
+        Self { names, events }
+    }
+    // The events bound to a variable (empty if it is unknown, a negation,
+    // or a branch of an OR which didn't match)
+    pub fn get(&self, var: &str) -> &[D] {
+        match self.names.iter().position(|name| name == var) {
+            Some(v) => &self.events[v],
+            None => &[],
+        }
+    }
+    // The latest event bound to a variable
+    pub fn last(&self, var: &str) -> Option<&D> {
+        self.get(var).last()
+    }
+    pub fn vars(&self) -> impl Iterator<Item = (&str, &[D])> {
+        self.names
+            .iter()
+            .zip(self.events.iter())
+            .filter(|(_, events)| !events.is_empty())
+            .map(|(name, events)| (name.as_str(), events.as_slice()))
+    }
+    pub fn len(&self) -> usize {
+        self.events.iter().map(Vec::len).sum()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/*
+    Patterns
+*/
+
+// Selection strategy: which events may be skipped between the events of
+// a match
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Strategy {
+    // No events in between
+    Contiguous,
+    // Events which can't be matched are skipped; each run takes the first
+    // matching event (the default)
+    SkipTillNextMatch,
+    // Any events are skipped: all combinations of matching events
+    // are found, which can be exponentially many
+    SkipTillAnyMatch,
+}
+
+enum Comp<'a, D> {
+    Event(usize, Guard<'a, D>),
+    Plus(usize, Guard<'a, D>),
+    Not(usize, Guard<'a, D>),
+    Or(Vec<(usize, Guard<'a, D>)>),
+    And(Vec<(usize, Guard<'a, D>)>),
+}
+impl<D> Comp<'_, D> {
+    fn vars(&self) -> Vec<usize> {
+        match self {
+            Comp::Event(v, _) | Comp::Plus(v, _) | Comp::Not(v, _) => vec![*v],
+            Comp::Or(branches) | Comp::And(branches) => {
+                branches.iter().map(|(v, _)| *v).collect()
+            }
+        }
+    }
+}
+
+type Test<'a, D> = Rc<dyn Fn(&Match<D>) -> bool + 'a>;
+struct Cond<'a, D> {
+    vars: Vec<usize>,
+    test: Test<'a, D>,
+}
+
+// An attribute of the events, for correlation predicates
+pub type Attr<'a, D> = Rc<dyn Fn(&D) -> f64 + 'a>;
+
+type TimeFn<'a, D> = Box<dyn Fn(&D) -> Time + 'a>;
+
+pub struct Pattern<'a, D> {
+    names: Vec<String>,
+    comps: Vec<Comp<'a, D>>,
+    conds: Vec<(Vec<String>, Test<'a, D>)>,
+    equivs: Vec<Attr<'a, D>>,
+    within: Option<Time>,
+    time_of: Option<TimeFn<'a, D>>,
+    strategy: Strategy,
+}
+impl<D> Default for Pattern<'_, D> {
+    fn default() -> Self {
+        Self {
+            names: Vec::new(),
+            comps: Vec::new(),
+            conds: Vec::new(),
+            equivs: Vec::new(),
+            within: None,
+            time_of: None,
+            strategy: Strategy::SkipTillNextMatch,
+        }
+    }
+}
+impl<'a, D: Clone + 'a> Pattern<'a, D> {
+    pub fn new() -> Self {
+        Default::default()
+    }
+    fn var(&mut self, name: &str) -> usize {
+        self.names.push(name.to_string());
+        self.names.len() - 1
+    }
+    fn vars(
+        &mut self,
+        branches: Vec<(&str, Guard<'a, D>)>,
+    ) -> Vec<(usize, Guard<'a, D>)> {
+        branches.into_iter().map(|(name, g)| (self.var(name), g)).collect()
+    }
+
+    pub fn event(mut self, var: &str, g: Guard<'a, D>) -> Self {
+        let v = self.var(var);
+        self.comps.push(Comp::Event(v, g));
+        self
+    }
+    pub fn plus(mut self, var: &str, g: Guard<'a, D>) -> Self {
+        let v = self.var(var);
+        self.comps.push(Comp::Plus(v, g));
+        self
+    }
+    pub fn absent(mut self, var: &str, g: Guard<'a, D>) -> Self {
+        let v = self.var(var);
+        self.comps.push(Comp::Not(v, g));
+        self
+    }
+    pub fn any_of(mut self, branches: Vec<(&str, Guard<'a, D>)>) -> Self {
+        let branches = self.vars(branches);
+        self.comps.push(Comp::Or(branches));
+        self
+    }
+    pub fn all_of(mut self, branches: Vec<(&str, Guard<'a, D>)>) -> Self {
+        let branches = self.vars(branches);
+        self.comps.push(Comp::And(branches));
+        self
+    }
+    // A correlation predicate on the given variables, checked each time
+    // one of them is bound, once all of them are
+    pub fn condition<F>(mut self, vars: &[&str], test: F) -> Self
+    where
+        F: Fn(&Match<D>) -> bool + 'a,
+    {
+        let vars = vars.iter().map(|v| v.to_string()).collect();
+        self.conds.push((vars, Rc::new(test)));
+        self
+    }
+    // All events (including those of negations) have the same attribute
+    pub fn equivalence<F>(mut self, attr: F) -> Self
+    where
+        F: Fn(&D) -> f64 + 'a,
+    {
+        self.equivs.push(Rc::new(attr));
+        self
+    }
+    pub fn within(mut self, limit: Time) -> Self {
+        self.within = Some(limit);
+        self
+    }
+    pub fn timed<F>(mut self, time_of: F) -> Self
+    where
+        F: Fn(&D) -> Time + 'a,
+    {
+        self.time_of = Some(Box::new(time_of));
+        self
+    }
+    pub fn strategy(mut self, strategy: Strategy) -> Self {
+        self.strategy = strategy;
+        self
+    }
+
+    pub fn compile(self) -> Result<Cep<'a, D>, CepError> {
+        let invalid = |msg: &str| Err(CepError::Invalid(msg.to_string()));
+        match (self.comps.first(), self.comps.last()) {
+            (None, _) => return invalid("empty pattern"),
+            (Some(Comp::Not(..)), _) | (_, Some(Comp::Not(..))) => {
+                return invalid("a pattern can't start or end with a negation")
+            }
+            (_, Some(Comp::Plus(..))) => {
+                return invalid("a pattern can't end with a Kleene plus")
+            }
+            _ => (),
+        }
+        let mut seen = HashSet::new();
+        if let Some(name) = self.names.iter().find(|&n| !seen.insert(n)) {
+            return Err(CepError::DuplicateVar(name.clone()));
+        }
+        if self.comps.iter().any(|c| match c {
+            Comp::Or(bs) | Comp::And(bs) => bs.is_empty() || bs.len() > 64,
+            _ => false,
+        }) {
+            return invalid("OR and AND need between 1 and 64 branches");
+        }
+
+        // The index of the component binding each variable
+        let mut comp_of = vec![0; self.names.len()];
+        for (i, comp) in self.comps.iter().enumerate() {
+            for v in comp.vars() {
+                comp_of[v] = i;
+            }
+        }
+        let comps = &self.comps;
+        let is_neg = |v: usize| matches!(comps[comp_of[v]], Comp::Not(..));
+        let mut conds = Vec::new();
+        for (names, test) in self.conds {
+            let mut vars = Vec::new();
+            for name in names {
+                match self.names.iter().position(|n| *n == name) {
+                    Some(v) => vars.push(v),
+                    None => return Err(CepError::UnknownVar(name)),
+                }
+            }
+            // A negation is checked when it is reached, so its predicates
+            // can only refer to the events before it
+            if let Some(&neg) = vars.iter().find(|&&v| is_neg(v)) {
+                if vars.iter().any(|&v| v != neg && comp_of[v] > comp_of[neg]) {
+                    return invalid(
+                        "a predicate on a negation can only refer to \
+                         earlier variables",
+                    );
+                }
+            }
+            conds.push(Cond { vars, test });
+        }
+        for attr in self.equivs {
+            for v in 0..self.names.len() {
+                let attr = attr.clone();
+                let test = move |m: &Match<D>| {
+                    let x = attr(m.events[v].last().unwrap());
+                    // Compare with any other event (they are all equal)
+                    let other = m
+                        .events
+                        .iter()
+                        .enumerate()
+                        .find_map(
+                            |(u, es)| if u == v { None } else { es.first() },
+                        )
+                        .or_else(|| m.events[v].first());
+                    other.is_none_or(|e| attr(e) == x)
+                };
+                conds.push(Cond { vars: vec![v], test: Rc::new(test) });
+            }
+        }
+
+        Ok(Cep {
+            names: Rc::new(self.names),
+            comps: self.comps,
+            conds,
+            within: self.within,
+            time_of: self.time_of,
+            strategy: self.strategy,
+            runs: Vec::new(),
+            started: false,
+            n_steps: 0,
+        })
+    }
+}
+
+/*
+    Matcher
+*/
+
+// A partial match, at component pos; taken is the number of events taken
+// by a Kleene plus there, or the bitmask of the branches of an AND, and
+// blocked is set when a Kleene plus can't go on to the next component
+// (because of a negation) until it takes another event
+#[derive(Clone)]
+struct Run<D> {
+    pos: usize,
+    taken: u64,
+    blocked: bool,
+    start: Time,
+    m: Match<D>,
+}
+
+// The result of trying to advance a run on an item: the runs following
+// it, if the item matched; or the run is unchanged; or it is replaced by
+// other runs (none if it is dead), whatever the strategy
+enum Outcome<D> {
+    Matched(Vec<Run<D>>),
+    Skipped,
+    Killed(Vec<Run<D>>),
+}
+
+pub struct Cep<'a, D> {
+    names: Rc<Vec<String>>,
+    comps: Vec<Comp<'a, D>>,
+    conds: Vec<Cond<'a, D>>,
+    within: Option<Time>,
+    time_of: Option<TimeFn<'a, D>>,
+    strategy: Strategy,
+    runs: Vec<Run<D>>,
+    started: bool,
+    n_steps: Time,
+}
+
+impl<'a, D: Clone> Cep<'a, D> {
+    pub fn n_runs(&self) -> usize {
+        self.runs.len()
+    }
+
+    // Bind var to the item, if it has the type and the predicates hold
+    fn bind(
+        &self,
+        m: &Match<D>,
+        v: usize,
+        g: &Guard<'a, D>,
+        item: &D,
+    ) -> Option<Match<D>> {
+        if !g.eval(item) {
+            return None;
+        }
+        let mut m = m.clone();
+        m.events[v].push(item.clone());
+        let holds = self.conds.iter().all(|c| {
+            !c.vars.contains(&v)
+                || c.vars.iter().any(|&u| m.events[u].is_empty())
+                || (c.test)(&m)
+        });
+        if holds {
+            Some(m)
+        } else {
+            None
+        }
+    }
+
+    // Advance a run which hasn't taken any events at pos (skipping over
+    // the negations before the next component, which kill the run if
+    // they match)
+    fn advance_fresh(&self, r: &Run<D>, pos: usize, item: &D) -> Outcome<D> {
+        let mut pos = pos;
+        while let Comp::Not(v, g) = &self.comps[pos] {
+            if self.bind(&r.m, *v, g, item).is_some() {
+                return Outcome::Killed(Vec::new());
+            }
+            pos += 1;
+        }
+        let run = |pos, taken, m| Run {
+            pos,
+            taken,
+            blocked: false,
+            start: r.start,
+            m,
+        };
+        let next: Vec<Run<D>> = match &self.comps[pos] {
+            Comp::Event(v, g) => self
+                .bind(&r.m, *v, g, item)
+                .map(|m| run(pos + 1, 0, m))
+                .into_iter()
+                .collect(),
+            Comp::Plus(v, g) => self
+                .bind(&r.m, *v, g, item)
+                .map(|m| run(pos, 1, m))
+                .into_iter()
+                .collect(),
+            Comp::Or(branches) => branches
+                .iter()
+                .filter_map(|(v, g)| self.bind(&r.m, *v, g, item))
+                .map(|m| run(pos + 1, 0, m))
+                .collect(),
+            Comp::And(_) => return self.advance_and(r, pos, 0, item),
+            Comp::Not(..) => unreachable!(),
+        };
+        if next.is_empty() {
+            Outcome::Skipped
+        } else {
+            Outcome::Matched(next)
+        }
+    }
+    fn advance_and(
+        &self,
+        r: &Run<D>,
+        pos: usize,
+        taken: u64,
+        item: &D,
+    ) -> Outcome<D> {
+        let branches = match &self.comps[pos] {
+            Comp::And(branches) => branches,
+            _ => unreachable!(),
+        };
+        let all = u64::MAX >> (64 - branches.len());
+        let next: Vec<Run<D>> = branches
+            .iter()
+            .enumerate()
+            .filter(|&(j, _)| taken & (1 << j) == 0)
+            .filter_map(|(j, (v, g))| {
+                let m = self.bind(&r.m, *v, g, item)?;
+                let taken = taken | (1 << j);
+                let (pos, taken) =
+                    if taken == all { (pos + 1, 0) } else { (pos, taken) };
+                Some(Run { pos, taken, blocked: false, start: r.start, m })
+            })
+            .collect();
+        if next.is_empty() {
+            Outcome::Skipped
+        } else {
+            Outcome::Matched(next)
+        }
+    }
+    fn advance(&self, r: &Run<D>, item: &D) -> Outcome<D> {
+        if r.taken == 0 {
+            return self.advance_fresh(r, r.pos, item);
+        }
+        match &self.comps[r.pos] {
+            Comp::Plus(v, g) => {
+                // Either take another event, or go on to the next component
+                let stay = self.bind(&r.m, *v, g, item).map(|m| Run {
+                    pos: r.pos,
+                    taken: r.taken + 1,
+                    blocked: false,
+                    start: r.start,
+                    m,
+                });
+                let leave = if r.blocked {
+                    Outcome::Skipped
+                } else {
+                    self.advance_fresh(r, r.pos + 1, item)
+                };
+                match (stay, leave) {
+                    (Some(stay), Outcome::Matched(mut next)) => {
+                        next.insert(0, stay);
+                        Outcome::Matched(next)
+                    }
+                    (None, Outcome::Matched(next)) => Outcome::Matched(next),
+                    (Some(stay), _) => Outcome::Matched(vec![stay]),
+                    (None, Outcome::Killed(_)) => Outcome::Killed(vec![Run {
+                        blocked: true,
+                        ..r.clone()
+                    }]),
+                    (None, Outcome::Skipped) => Outcome::Skipped,
+                }
+            }
+            Comp::And(_) => self.advance_and(r, r.pos, r.taken, item),
+            _ => unreachable!(),
+        }
+    }
+
+    fn step(&mut self, item: &D) -> Vec<Match<D>> {
+        let t = match &self.time_of {
+            Some(time_of) => time_of(item),
+            None => self.n_steps,
+        };
+        self.n_steps += 1;
+        if let Some(limit) = self.within {
+            self.runs.retain(|r| t.saturating_sub(r.start) <= limit);
+        }
+        let fresh = Run {
+            pos: 0,
+            taken: 0,
+            blocked: false,
+            start: t,
+            m: Match::new(self.names.clone()),
+        };
+        let mut runs = Vec::new();
+        for r in mem::take(&mut self.runs) {
+            match (self.advance(&r, item), self.strategy) {
+                (Outcome::Matched(next), Strategy::SkipTillAnyMatch) => {
+                    runs.extend(next);
+                    runs.push(r);
+                }
+                (Outcome::Matched(next), _) => runs.extend(next),
+                (Outcome::Skipped, Strategy::Contiguous) => (),
+                (Outcome::Skipped, _) => runs.push(r),
+                (Outcome::Killed(next), _) => runs.extend(next),
+            }
+        }
+        if let Outcome::Matched(next) = self.advance(&fresh, item) {
+            runs.extend(next);
+        }
+        let n = self.comps.len();
+        let mut matches = Vec::new();
+        for r in runs {
+            if r.pos == n {
+                matches.push(r.m);
+            } else {
+                self.runs.push(r);
+            }
+        }
+        matches
+    }
+}
+
+impl<D: Clone> Transducer<(), D, Vec<Match<D>>> for Cep<'_, D> {
+    fn init(&mut self, i: Ext<()>) -> Ext<Vec<Match<D>>> {
+        if !i.is_none() {
+            self.reset();
+            self.started = true;
+        }
+        Ext::None
+    }
+    fn update(&mut self, item: &D) -> Ext<Vec<Match<D>>> {
+        if !self.started {
+            return Ext::None;
+        }
+        let matches = self.step(item);
+        if matches.is_empty() {
+            Ext::None
+        } else {
+            Ext::One(matches)
+        }
+    }
+    fn reset(&mut self) {
+        self.runs.clear();
+        self.started = false;
+        self.n_steps = 0;
+    }
+
+    fn is_epsilon(&self) -> bool {
+        false
+    }
+    fn is_restartable(&self) -> bool {
+        false
+    }
+    fn is_nullable(&self) -> bool {
+        false
+    }
+    fn n_states(&self) -> usize {
+        self.comps.len()
+    }
+    fn n_transs(&self) -> usize {
+        self.comps.iter().map(|c| c.vars().len()).sum()
+    }
+}
+
+/*
+    Parsing
+*/
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum CepError {
+    // At the given byte offset in the text
+    Syntax { pos: usize, msg: String },
+    UnknownType(String),
+    UnknownAttr(String),
+    UnknownVar(String),
+    DuplicateVar(String),
+    Invalid(String),
+}
+impl Display for CepError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CepError::Syntax { pos, msg } => {
+                write!(f, "at offset {}: {}", pos, msg)
+            }
+            CepError::UnknownType(name) => {
+                write!(f, "unknown event type {:?}", name)
+            }
+            CepError::UnknownAttr(name) => {
+                write!(f, "unknown attribute {:?}", name)
+            }
+            CepError::UnknownVar(name) => {
+                write!(f, "unknown variable {:?}", name)
+            }
+            CepError::DuplicateVar(name) => {
+                write!(f, "variable {:?} is bound twice", name)
+            }
+            CepError::Invalid(msg) => write!(f, "invalid pattern: {}", msg),
+        }
+    }
+}
+impl Error for CepError {}
+impl SyntaxError for CepError {
+    fn at(span: Range<usize>, msg: String) -> Self {
+        CepError::Syntax { pos: span.start, msg }
+    }
+}
+
+// Parse a pattern (without the PATTERN keyword), looking up the event
+// types and the attributes by name
+pub fn parse_pattern<'a, D, T, A>(
+    text: &str,
+    types: T,
+    attrs: A,
+) -> Result<Pattern<'a, D>, CepError>
+where
+    D: Clone + 'a,
+    T: Fn(&str) -> Option<Guard<'a, D>>,
+    A: Fn(&str) -> Option<Attr<'a, D>>,
+{
+    let mut p = Parser::new(text);
+    p.keyword("SEQ")?;
+    p.expect("(")?;
+    let mut pattern = Pattern::new();
+    loop {
+        pattern = p.comp(pattern, &types)?;
+        if !p.eat(",") {
+            break;
+        }
+    }
+    p.expect(")")?;
+    if p.eat_keyword("WHERE") {
+        loop {
+            pattern = p.cond(pattern, &attrs)?;
+            if !p.eat_keyword("AND") {
+                break;
+            }
+        }
+    }
+    if p.eat_keyword("WITHIN") {
+        let limit = p.number()?;
+        if limit < 0.0 || limit.fract() != 0.0 {
+            return Err(p.error("expected a whole number"));
+        }
+        pattern = pattern.within(limit as Time);
+    }
+    p.expect_end("pattern")?;
+    Ok(pattern)
+}
+
+// A side of a comparison: a constant, or an attribute of the latest
+// (offset 0) or second latest (offset 1) event of a variable
+enum Term<'a, D> {
+    Const(f64),
+    Attr(String, usize, Attr<'a, D>),
+}
+
+type Parser<'t> = Scanner<'t, CepError>;
+
+impl<'t> Parser<'t> {
+    // A word which doesn't start with a digit
+    fn ident(&mut self) -> Option<&'t str> {
+        self.skip_space();
+        if self.text[self.pos..].starts_with(|c: char| c.is_ascii_digit()) {
+            return None;
+        }
+        self.word()
+    }
+    fn name(&mut self, what: &str) -> Result<&'t str, CepError> {
+        match self.ident() {
+            Some(word) => Ok(word),
+            None => Err(self.error(&format!("expected {}", what))),
+        }
+    }
+    fn eat_keyword(&mut self, keyword: &str) -> bool {
+        let start = self.pos;
+        if self.ident() == Some(keyword) {
+            true
+        } else {
+            self.pos = start;
+            false
+        }
+    }
+    fn keyword(&mut self, keyword: &str) -> Result<(), CepError> {
+        if self.eat_keyword(keyword) {
+            Ok(())
+        } else {
+            Err(self.error(&format!("expected {}", keyword)))
+        }
+    }
+    fn number(&mut self) -> Result<f64, CepError> {
+        self.skip_space();
+        let rest = &self.text[self.pos..];
+        let len = rest
+            .char_indices()
+            .find(|&(i, c)| {
+                !(c.is_ascii_digit() || c == '.' || (i == 0 && c == '-'))
+            })
+            .map_or(rest.len(), |(i, _)| i);
+        match rest[..len].parse() {
+            Ok(x) => {
+                self.pos += len;
+                Ok(x)
+            }
+            Err(_) => Err(self.error("expected a number")),
+        }
+    }
+
+    // A type and a variable, e.g. "A a"
+    fn binding<'a, D, T>(
+        &mut self,
+        types: &T,
+    ) -> Result<(Guard<'a, D>, bool, &'t str), CepError>
+    where
+        T: Fn(&str) -> Option<Guard<'a, D>>,
+    {
+        let ty = self.name("an event type")?;
+        let g =
+            types(ty).ok_or_else(|| CepError::UnknownType(ty.to_string()))?;
+        let plus = self.eat("+");
+        let var = self.name("a variable")?;
+        if plus && self.eat("[") {
+            self.expect("]")?;
+        }
+        Ok((g, plus, var))
+    }
+    fn branches<'a, D, T>(
+        &mut self,
+        types: &T,
+    ) -> Result<Vec<(&'t str, Guard<'a, D>)>, CepError>
+    where
+        T: Fn(&str) -> Option<Guard<'a, D>>,
+    {
+        self.expect("(")?;
+        let mut branches = Vec::new();
+        loop {
+            let (g, plus, var) = self.binding(types)?;
+            if plus {
+                return Err(
+                    self.error("Kleene plus is not allowed in OR and AND")
+                );
+            }
+            branches.push((var, g));
+            if !self.eat(",") {
+                break;
+            }
+        }
+        self.expect(")")?;
+        Ok(branches)
+    }
+    fn comp<'a, D, T>(
+        &mut self,
+        pattern: Pattern<'a, D>,
+        types: &T,
+    ) -> Result<Pattern<'a, D>, CepError>
+    where
+        D: Clone + 'a,
+        T: Fn(&str) -> Option<Guard<'a, D>>,
+    {
+        if self.eat("!") || self.eat("~") {
+            let parens = self.eat("(");
+            let (g, plus, var) = self.binding(types)?;
+            if plus {
+                return Err(
+                    self.error("Kleene plus is not allowed in a negation")
+                );
+            }
+            if parens {
+                self.expect(")")?;
+            }
+            return Ok(pattern.absent(var, g));
+        }
+        if self.eat_keyword("OR") {
+            return Ok(pattern.any_of(self.branches(types)?));
+        }
+        if self.eat_keyword("AND") {
+            return Ok(pattern.all_of(self.branches(types)?));
+        }
+        let (g, plus, var) = self.binding(types)?;
+        Ok(if plus { pattern.plus(var, g) } else { pattern.event(var, g) })
+    }
+
+    fn term<'a, D, A>(&mut self, attrs: &A) -> Result<Term<'a, D>, CepError>
+    where
+        A: Fn(&str) -> Option<Attr<'a, D>>,
+    {
+        let var = match self.ident() {
+            Some(var) => var,
+            None => return Ok(Term::Const(self.number()?)),
+        };
+        let mut offset = 0;
+        if self.eat("[") {
+            self.keyword("i")?;
+            if self.eat("-") {
+                self.expect("1")?;
+                offset = 1;
+            }
+            self.expect("]")?;
+        }
+        self.expect(".")?;
+        let name = self.name("an attribute")?;
+        let attr = attrs(name)
+            .ok_or_else(|| CepError::UnknownAttr(name.to_string()))?;
+        Ok(Term::Attr(var.to_string(), offset, attr))
+    }
+    fn cond<'a, D, A>(
+        &mut self,
+        pattern: Pattern<'a, D>,
+        attrs: &A,
+    ) -> Result<Pattern<'a, D>, CepError>
+    where
+        D: Clone + 'a,
+        A: Fn(&str) -> Option<Attr<'a, D>>,
+    {
+        if self.eat("[") {
+            let name = self.name("an attribute")?;
+            let attr = attrs(name)
+                .ok_or_else(|| CepError::UnknownAttr(name.to_string()))?;
+            self.expect("]")?;
+            return Ok(pattern.equivalence(move |d| attr(d)));
+        }
+        let lhs = self.term(attrs)?;
+        self.skip_space();
+        let ops = ["<=", ">=", "!=", "=", "<", ">"];
+        let op = match ops
+            .iter()
+            .find(|op| self.text[self.pos..].starts_with(*op))
+        {
+            Some(op) => *op,
+            None => return Err(self.error("expected a comparison")),
+        };
+        self.pos += op.len();
+        let rhs = self.term(attrs)?;
+        let vars: Vec<String> = [&lhs, &rhs]
+            .iter()
+            .filter_map(|t| match t {
+                Term::Attr(var, _, _) => Some(var.clone()),
+                Term::Const(_) => None,
+            })
+            .collect();
+        let eval = |t: &Term<'a, D>, m: &Match<D>| match t {
+            Term::Const(x) => Some(*x),
+            Term::Attr(var, offset, attr) => {
+                let events = m.get(var);
+                events.len().checked_sub(offset + 1).map(|i| attr(&events[i]))
+            }
+        };
+        let test = move |m: &Match<D>| match (eval(&lhs, m), eval(&rhs, m)) {
+            (Some(x), Some(y)) => match op {
+                "<=" => x <= y,
+                ">=" => x >= y,
+                "!=" => x != y,
+                "=" => x == y,
+                "<" => x < y,
+                _ => x > y,
+            },
+            // Comparisons with b[i-1] hold on the first event of b
+            _ => true,
+        };
+        let vars: Vec<&str> = vars.iter().map(String::as_str).collect();
+        Ok(pattern.condition(&vars, test))
+    }
+}
+
+/*
+    Unit Tests
+*/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::guard::pred;
+
+    // Items are (type, id, price)
+    type Item = (char, i64, i64);
+
+    fn types<'a>(name: &str) -> Option<Guard<'a, Item>> {
+        let ch = name.chars().next()?;
+        if name.len() == 1 && ch.is_ascii_uppercase() {
+            Some(pred(name, move |&(c, _, _): &Item| c == ch))
+        } else {
+            None
+        }
+    }
+    fn attrs<'a>(name: &str) -> Option<Attr<'a, Item>> {
+        match name {
+            "id" => Some(Rc::new(|&(_, id, _): &Item| id as f64)),
+            "price" => Some(Rc::new(|&(_, _, p): &Item| p as f64)),
+            _ => None,
+        }
+    }
+    // The matches completed by each item, as the types of their events
+    fn matches(text: &str, items: &[Item]) -> Vec<Vec<String>> {
+        let pattern = parse_pattern(text, types, attrs).unwrap();
+        let mut m = pattern.compile().unwrap();
+        m.init_one(());
+        items
+            .iter()
+            .filter_map(|item| match m.update(item) {
+                Ext::One(ms) => Some(ms.iter().map(show).collect()),
+                _ => None,
+            })
+            .collect()
+    }
+    fn show(m: &Match<Item>) -> String {
+        m.vars()
+            .map(|(_, es)| {
+                es.iter().map(|e| format!("{}{}", e.0, e.2)).collect::<String>()
+            })
+            .collect()
+    }
+    fn items(s: &str) -> Vec<Item> {
+        // Each item is a type and a price digit, all with id 0
+        let cs: Vec<char> = s.chars().collect();
+        cs.chunks(2)
+            .map(|c| (c[0], 0, c[1].to_digit(10).unwrap() as i64))
+            .collect()
+    }
+
+    #[test]
+    fn test_seq() {
+        let strm = items("A1X0B2A3B4");
+        assert_eq!(
+            matches("SEQ(A a, B b)", &strm),
+            vec![vec!["A1B2"], vec!["A3B4"]]
+        );
+        let any = parse_pattern("SEQ(A a, B b)", types, attrs)
+            .unwrap()
+            .strategy(Strategy::SkipTillAnyMatch);
+        let mut m = any.compile().unwrap();
+        m.init_one(());
+        let n: Vec<usize> = strm
+            .iter()
+            .map(|i| m.update(i).into_inner().map_or(0, |ms| ms.len()))
+            .collect();
+        assert_eq!(n, vec![0, 0, 1, 0, 2]);
+        let strict = Pattern::new()
+            .event("a", types("A").unwrap())
+            .event("b", types("B").unwrap())
+            .strategy(Strategy::Contiguous);
+        let mut m = strict.compile().unwrap();
+        m.init_one(());
+        let out: Vec<bool> =
+            strm.iter().map(|i| !m.update(i).is_none()).collect();
+        assert_eq!(out, vec![false, false, false, false, true]);
+    }
+
+    #[test]
+    fn test_kleene_negation() {
+        let strm = items("A1B2B3C0B4D5");
+        assert_eq!(
+            matches("SEQ(A a, B+ b[], D d)", &strm),
+            vec![vec!["A1B2B3B4D5"]]
+        );
+        // No C between the last B and D
+        let strm2 = items("A1B2C0D5B3D6");
+        assert_eq!(
+            matches("SEQ(A a, B+ b[], !C c, D d)", &strm2),
+            vec![vec!["A1B2B3D6"]]
+        );
+        assert!(matches("SEQ(A a, ~(C c), D d)", &strm2).is_empty());
+        // Rising prices
+        let text = "SEQ(A a, B+ b[], D d) WHERE b[i].price > b[i-1].price";
+        assert_eq!(matches(text, &items("A1B2B3B1D0")), vec![vec!["A1B2B3D0"]]);
+    }
+
+    #[test]
+    fn test_or_and() {
+        let strm = items("A1C2B3D4");
+        assert_eq!(
+            matches("SEQ(OR(A a, B b), D d)", &strm),
+            vec![vec!["A1D4", "B3D4"]]
+        );
+        assert_eq!(
+            matches("SEQ(AND(B b, C c), D d)", &strm),
+            vec![vec!["B3C2D4"]]
+        );
+    }
+
+    #[test]
+    fn test_correlation_within() {
+        let strm = vec![
+            ('A', 1, 10),
+            ('A', 2, 20),
+            ('C', 1, 0),
+            ('B', 2, 30),
+            ('B', 1, 5),
+        ];
+        // B of the same id, with no C of that id in between
+        let text = "SEQ(A a, !C c, B b) WHERE [id]";
+        assert_eq!(matches(text, &strm), vec![vec!["A20B30"]]);
+        let text = "SEQ(A a, B b) WHERE a.id = b.id AND b.price < a.price";
+        assert_eq!(matches(text, &strm), vec![vec!["A10B5"]]);
+        let text = "SEQ(A a, B b) WHERE [id] WITHIN 2";
+        assert_eq!(matches(text, &strm), vec![vec!["A20B30"]]);
+        // With timestamps (the price)
+        let p = parse_pattern("SEQ(A a, B b) WITHIN 20", types, attrs)
+            .unwrap()
+            .timed(|&(_, _, t)| t as Time);
+        let mut m = p.compile().unwrap();
+        m.init_one(());
+        let strm = items("A0A5B9");
+        let strm: Vec<Item> =
+            strm.into_iter().map(|(c, id, p)| (c, id, p * 4)).collect();
+        let out: Vec<usize> = strm
+            .iter()
+            .map(|i| m.update(i).into_inner().map_or(0, |ms| ms.len()))
+            .collect();
+        assert_eq!(out, vec![0, 0, 1]);
+    }
+
+    #[test]
+    fn test_errors() {
+        let err = |text| {
+            parse_pattern(text, types, attrs)
+                .and_then(Pattern::compile)
+                .err()
+                .unwrap()
+        };
+        assert_eq!(
+            err("SEQ(A a, x b)"),
+            CepError::UnknownType("x".to_string())
+        );
+        assert_eq!(
+            err("SEQ(A a) WHERE [size]"),
+            CepError::UnknownAttr("size".to_string())
+        );
+        assert_eq!(
+            err("SEQ(A a) WHERE b.id = 1"),
+            CepError::UnknownVar("b".to_string())
+        );
+        assert_eq!(
+            err("SEQ(A a, B a)"),
+            CepError::DuplicateVar("a".to_string())
+        );
+        assert!(matches!(err("SEQ(A a, !C c)"), CepError::Invalid(_)));
+        assert!(matches!(err("SEQ(A a, B+ b)"), CepError::Invalid(_)));
+        assert!(matches!(
+            err("SEQ(A a, !C c, B b) WHERE c.id = b.id"),
+            CepError::Invalid(_)
+        ));
+        assert_eq!(err("SEQ(A a").to_string(), "at offset 7: expected \")\"");
+        assert!(matches!(err("SEQ(A a) WITHIN x"), CepError::Syntax { .. }));
+    }
+}
@@ -0,0 +1,466 @@
+/*
+    Restartability certificates for QREs.
+
+    The restartability of a QRE is derived syntactically, following the
+    constructions of qre.rs: epsilons and atoms are restartable, a union or
+    concatenation is restartable if both operands are, an iteration always
+    is (its body must be), and parcomp and aggregate are not. (For parcomp,
+    restartability would need the rates of the operands to agree, which is
+    not analyzed.) The same rules give which subexpressions are epsilons,
+    and the requirement of concat that its second operand be restartable
+    or its first one an epsilon.
+
+    certify(&m) returns the derivation as a Certificate: for each
+    subexpression, the construct and the two properties derived for it.
+    A certificate can be stored as text (Display and FromStr), e.g.
+        concat/r(epsilon/er, iterate/r(union/r(atom/r, atom/r)))
+    where the flags are e for an epsilon and r for restartable, and then
+    re-validated cheaply (in time linear in the size of the QRE):
+    - parsing checks that each step of the derivation follows the rules;
+    - cert.validate(&m) checks that it is the derivation of m, i.e. that
+      m still has the same structure.
+    Constructs without a derivation (e.g. windows) can't be certified;
+    wrapping one in a box doesn't hide it.
+*/
+
+use super::interface::Transducer;
+use super::qre::{
+    Aggregate, Atom, Concat, Epsilon, Iterate, ParComp, TopWrapper, Union,
+};
+use std::error::Error;
+use std::fmt::{self, Display};
+use std::str::FromStr;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Construct {
+    Epsilon,
+    Atom,
+    Union,
+    Concat,
+    Iterate,
+    ParComp,
+    Aggregate,
+}
+const CONSTRUCTS: &[(Construct, &str, usize)] = &[
+    (Construct::Epsilon, "epsilon", 0),
+    (Construct::Atom, "atom", 0),
+    (Construct::Union, "union", 2),
+    (Construct::Concat, "concat", 2),
+    (Construct::Iterate, "iterate", 1),
+    (Construct::ParComp, "parcomp", 2),
+    (Construct::Aggregate, "aggregate", 1),
+];
+impl Construct {
+    fn name(self) -> &'static str {
+        CONSTRUCTS.iter().find(|c| c.0 == self).unwrap().1
+    }
+    fn arity(self) -> usize {
+        CONSTRUCTS.iter().find(|c| c.0 == self).unwrap().2
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Derivation {
+    pub construct: Construct,
+    pub epsilon: bool,
+    pub restartable: bool,
+    pub children: Vec<Derivation>,
+}
+
+impl Derivation {
+    // The derivation of a construct, given those of its operands
+    pub fn new(construct: Construct, children: Vec<Derivation>) -> Self {
+        let (epsilon, restartable) = derive(construct, &children);
+        Derivation { construct, epsilon, restartable, children }
+    }
+    // Check each step against the rules, returning the path (indices of
+    // the operands from the root) of the first wrong one
+    fn check(&self, path: &mut Vec<usize>) -> Result<(), CertError> {
+        let error = |path: &[usize], msg: &str| {
+            Err(CertError::Invalid { path: path.to_vec(), msg: msg.into() })
+        };
+        if self.children.len() != self.construct.arity() {
+            return error(path, "wrong number of operands");
+        }
+        for (i, child) in self.children.iter().enumerate() {
+            path.push(i);
+            child.check(path)?;
+            path.pop();
+        }
+        let c = &self.children;
+        match self.construct {
+            Construct::Concat if !c[1].restartable && !c[0].epsilon => {
+                return error(path, "concat of a non-restartable operand");
+            }
+            Construct::Iterate if !c[0].restartable => {
+                return error(path, "iterate of a non-restartable operand");
+            }
+            _ => (),
+        }
+        if derive(self.construct, c) != (self.epsilon, self.restartable) {
+            return error(path, "flags don't follow from the operands");
+        }
+        Ok(())
+    }
+    // The first difference with another derivation
+    fn diff(&self, other: &Self, path: &mut Vec<usize>) -> Option<Vec<usize>> {
+        if self.construct != other.construct
+            || self.children.len() != other.children.len()
+        {
+            return Some(path.clone());
+        }
+        for (i, (c1, c2)) in
+            self.children.iter().zip(&other.children).enumerate()
+        {
+            path.push(i);
+            if let Some(p) = c1.diff(c2, path) {
+                return Some(p);
+            }
+            path.pop();
+        }
+        None
+    }
+}
+
+// The rules: (epsilon, restartable) from those of the operands
+fn derive(construct: Construct, children: &[Derivation]) -> (bool, bool) {
+    let all = |f: fn(&Derivation) -> bool| children.iter().all(f);
+    let epsilon = all(|c| c.epsilon);
+    match construct {
+        Construct::Epsilon => (true, true),
+        Construct::Atom => (false, true),
+        Construct::Union | Construct::Concat => {
+            (epsilon, all(|c| c.restartable))
+        }
+        Construct::Iterate => (epsilon, true),
+        Construct::ParComp | Construct::Aggregate => (epsilon, false),
+    }
+}
+
+impl Display for Derivation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/", self.construct.name())?;
+        if self.epsilon {
+            write!(f, "e")?;
+        }
+        if self.restartable {
+            write!(f, "r")?;
+        }
+        if !self.children.is_empty() {
+            write!(f, "(")?;
+            for (i, child) in self.children.iter().enumerate() {
+                if i > 0 {
+                    write!(f, ", ")?;
+                }
+                write!(f, "{}", child)?;
+            }
+            write!(f, ")")?;
+        }
+        Ok(())
+    }
+}
+
+/*
+    Derivations of the QRE constructs
+*/
+
+pub trait Certify {
+    fn derivation(&self) -> Derivation;
+}
+
+impl<C: Certify + ?Sized> Certify for Box<C> {
+    fn derivation(&self) -> Derivation {
+        (**self).derivation()
+    }
+}
+impl<I, D, O, F> Certify for Epsilon<I, D, O, F>
+where
+    F: Fn(I) -> O,
+{
+    fn derivation(&self) -> Derivation {
+        Derivation::new(Construct::Epsilon, vec![])
+    }
+}
+impl<I, D, O, G, F> Certify for Atom<I, D, O, G, F>
+where
+    G: Fn(&D) -> bool,
+    F: Fn(I, &D) -> O,
+{
+    fn derivation(&self) -> Derivation {
+        Derivation::new(Construct::Atom, vec![])
+    }
+}
+impl<I, D, O, M1, M2> Certify for Union<I, D, O, M1, M2>
+where
+    M1: Transducer<I, D, O> + Certify,
+    M2: Transducer<I, D, O> + Certify,
+{
+    fn derivation(&self) -> Derivation {
+        let children = vec![self.m1.derivation(), self.m2.derivation()];
+        Derivation::new(Construct::Union, children)
+    }
+}
+impl<D, X, Y, Z, M1, M2> Certify for Concat<D, X, Y, Z, M1, M2>
+where
+    M1: Transducer<X, D, Y> + Certify,
+    M2: Transducer<Y, D, Z> + Certify,
+{
+    fn derivation(&self) -> Derivation {
+        let children = vec![self.m1.derivation(), self.m2.derivation()];
+        Derivation::new(Construct::Concat, children)
+    }
+}
+impl<X, D, M> Certify for Iterate<X, D, M>
+where
+    M: Transducer<X, D, X> + Certify,
+{
+    fn derivation(&self) -> Derivation {
+        Derivation::new(Construct::Iterate, vec![self.m.derivation()])
+    }
+}
+impl<I, D, O1, O2, M1, M2> Certify for ParComp<I, D, O1, O2, M1, M2>
+where
+    M1: Transducer<I, D, O1> + Certify,
+    M2: Transducer<I, D, O2> + Certify,
+{
+    fn derivation(&self) -> Derivation {
+        let children = vec![self.m1.derivation(), self.m2.derivation()];
+        Derivation::new(Construct::ParComp, children)
+    }
+}
+impl<D, X, Y, Z, M, F> Certify for Aggregate<D, X, Y, Z, M, F>
+where
+    M: Transducer<X, D, Y> + Certify,
+    F: Fn(Z, Y) -> Z,
+{
+    fn derivation(&self) -> Derivation {
+        Derivation::new(Construct::Aggregate, vec![self.m.derivation()])
+    }
+}
+impl<I, D, O, M> Certify for TopWrapper<I, D, O, M>
+where
+    M: Transducer<I, D, O> + Certify,
+{
+    fn derivation(&self) -> Derivation {
+        self.m.derivation()
+    }
+}
+
+/*
+    Certificates
+*/
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum CertError {
+    // The QRE (or the subexpression at the path) isn't restartable
+    NotRestartable { path: Vec<usize> },
+    // A step of the derivation doesn't follow the rules
+    Invalid { path: Vec<usize>, msg: String },
+    // The certificate is for a QRE of a different structure
+    Mismatch { path: Vec<usize> },
+    // The text is not a derivation (at the byte offset)
+    Syntax { pos: usize },
+}
+fn fmt_path(path: &[usize]) -> String {
+    let steps: Vec<String> = path.iter().map(|i| i.to_string()).collect();
+    format!("[{}]", steps.join("."))
+}
+impl Display for CertError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CertError::NotRestartable { path } => {
+                write!(f, "not restartable at {}", fmt_path(path))
+            }
+            CertError::Invalid { path, msg } => {
+                write!(f, "invalid derivation at {}: {}", fmt_path(path), msg)
+            }
+            CertError::Mismatch { path } => write!(
+                f,
+                "the certificate doesn't match the QRE at {}",
+                fmt_path(path)
+            ),
+            CertError::Syntax { pos } => {
+                write!(f, "malformed certificate at offset {}", pos)
+            }
+        }
+    }
+}
+impl Error for CertError {}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Certificate {
+    derivation: Derivation,
+}
+
+// Certify that m is restartable
+pub fn certify<M: Certify + ?Sized>(m: &M) -> Result<Certificate, CertError> {
+    Certificate::new(m.derivation())
+}
+
+impl Certificate {
+    fn new(derivation: Derivation) -> Result<Self, CertError> {
+        derivation.check(&mut Vec::new())?;
+        if !derivation.restartable {
+            return Err(CertError::NotRestartable { path: vec![] });
+        }
+        Ok(Certificate { derivation })
+    }
+    pub fn derivation(&self) -> &Derivation {
+        &self.derivation
+    }
+    // Check that this certifies m
+    pub fn validate<M: Certify + ?Sized>(
+        &self,
+        m: &M,
+    ) -> Result<(), CertError> {
+        match self.derivation.diff(&m.derivation(), &mut Vec::new()) {
+            None => Ok(()),
+            Some(path) => Err(CertError::Mismatch { path }),
+        }
+    }
+}
+impl Display for Certificate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.derivation)
+    }
+}
+impl FromStr for Certificate {
+    type Err = CertError;
+    fn from_str(text: &str) -> Result<Self, CertError> {
+        let mut parser = Parser { text, pos: 0 };
+        let derivation = parser.derivation()?;
+        parser.skip_space();
+        if parser.pos < text.len() {
+            return Err(CertError::Syntax { pos: parser.pos });
+        }
+        Certificate::new(derivation)
+    }
+}
+
+struct Parser<'t> {
+    text: &'t str,
+    pos: usize,
+}
+impl Parser<'_> {
+    fn skip_space(&mut self) {
+        let rest = &self.text[self.pos..];
+        self.pos += rest.len() - rest.trim_start().len();
+    }
+    fn eat(&mut self, token: &str) -> bool {
+        self.skip_space();
+        let found = self.text[self.pos..].starts_with(token);
+        if found {
+            self.pos += token.len();
+        }
+        found
+    }
+    fn derivation(&mut self) -> Result<Derivation, CertError> {
+        self.skip_space();
+        let syntax = CertError::Syntax { pos: self.pos };
+        let rest = &self.text[self.pos..];
+        let (name, flags) = rest
+            .find(|c: char| !c.is_ascii_alphanumeric() && c != '/')
+            .map_or(rest, |len| &rest[..len])
+            .split_once('/')
+            .ok_or_else(|| syntax.clone())?;
+        let construct = CONSTRUCTS
+            .iter()
+            .find(|c| c.1 == name)
+            .ok_or_else(|| syntax.clone())?
+            .0;
+        let (epsilon, restartable) = match flags {
+            "" => (false, false),
+            "e" => (true, false),
+            "r" => (false, true),
+            "er" => (true, true),
+            _ => return Err(syntax),
+        };
+        self.pos += name.len() + 1 + flags.len();
+        let mut children = Vec::new();
+        if self.eat("(") {
+            children.push(self.derivation()?);
+            while self.eat(",") {
+                children.push(self.derivation()?);
+            }
+            if !self.eat(")") {
+                return Err(CertError::Syntax { pos: self.pos });
+            }
+        }
+        Ok(Derivation { construct, epsilon, restartable, children })
+    }
+}
+
+/*
+    Unit Tests
+*/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::qre::{aggregate, atom, concat, epsilon, iterate, top, union};
+
+    fn sum() -> impl Transducer<(), char, i32> + Certify {
+        let digit = atom(
+            |c: &char| c.is_ascii_digit(),
+            |x: i32, c: &char| x + c.to_digit(10).unwrap() as i32,
+        );
+        let other = atom(|c: &char| !c.is_ascii_digit(), |x: i32, _| x);
+        concat(epsilon(|()| 0), iterate(union(digit, other)))
+    }
+
+    #[test]
+    fn test_certify() {
+        let cert = certify(&sum()).unwrap();
+        let text = "concat/r(epsilon/er, iterate/r(union/r(atom/r, atom/r)))";
+        assert_eq!(cert.to_string(), text);
+        assert_eq!(text.parse::<Certificate>(), Ok(cert.clone()));
+        assert_eq!(cert.validate(&sum()), Ok(()));
+        assert_eq!(cert.validate(&top(sum())), Ok(()));
+        let boxed: Box<dyn Certify> = Box::new(sum());
+        assert_eq!(certify(&*boxed), Ok(cert.clone()));
+        // A QRE of another structure
+        let m =
+            concat(epsilon(|()| 0), iterate(atom(|_: &char| true, |x, _| x)));
+        assert_eq!(
+            cert.validate(&m),
+            Err(CertError::Mismatch { path: vec![1, 0] })
+        );
+        // Not restartable
+        let m = aggregate(atom(|_: &char| true, |(), _| 1), |x: i32, y| x + y);
+        assert_eq!(
+            certify(&m).unwrap_err().to_string(),
+            "not restartable at []"
+        );
+    }
+
+    #[test]
+    fn test_invalid() {
+        let parse = |text: &str| text.parse::<Certificate>().unwrap_err();
+        // Flags not following from the rules
+        assert_eq!(
+            parse("union/r(atom/r, aggregate/r(atom/r))"),
+            CertError::Invalid {
+                path: vec![1],
+                msg: "flags don't follow from the operands".into()
+            }
+        );
+        assert_eq!(
+            parse("iterate/r(aggregate/(atom/r))").to_string(),
+            "invalid derivation at []: iterate of a non-restartable operand"
+        );
+        assert!(matches!(
+            parse("concat/(atom/r, parcomp/(atom/r, atom/r))"),
+            CertError::Invalid { .. }
+        ));
+        assert!(matches!(parse("atom/r(atom/r)"), CertError::Invalid { .. }));
+        // Valid, but not restartable
+        assert_eq!(
+            parse("aggregate/(atom/r)"),
+            CertError::NotRestartable { path: vec![] }
+        );
+        // Malformed
+        assert_eq!(parse("atom/x"), CertError::Syntax { pos: 0 });
+        assert_eq!(parse("iterate/r(atom/r"), CertError::Syntax { pos: 16 });
+        assert_eq!(parse("star/r"), CertError::Syntax { pos: 0 });
+    }
+}
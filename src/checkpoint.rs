@@ -0,0 +1,386 @@
+/*
+    Checkpointing, for recovery after a crash (enabled by the "json"
+    feature).
+
+    A long-running computation periodically persists a Checkpoint: the
+    position reached in the source (its offset), and a snapshot of the
+    transducer's state there. On restart, the transducer is restored from
+    the latest checkpoint, and the source seeks back to its offset, so that
+    processing resumes exactly where the checkpoint was taken. The pieces:
+    - SourceOffset: a source which can report and seek to its offset
+      (Counted wraps any iterator, with the number of items consumed as
+      the offset);
+    - Snapshot: a transducer whose state can be saved and restored (e.g.
+      DataTransducer, by .get_states() and .set_states());
+    - CheckpointStore: where checkpoints are persisted, as JSON (FileStore
+      replaces a file atomically, MemoryStore is for tests);
+    - Checkpointer: the driver, which resumes from the store (or starts
+      afresh with .init()), then saves a checkpoint every n items, and at
+      the end of the source.
+
+    The outputs are numbered by step (0 for .init(), then the position of
+    the item in the source, from 1), and the numbering resumes with the
+    checkpoint. The outputs produced between the latest checkpoint and a
+    crash are produced again after recovery, so delivery is at-least-once;
+    a sink which ignores the steps it has already seen makes it
+    exactly-once.
+*/
+
+use super::errors::CheckpointError;
+use super::ext_value::Ext;
+use super::interface::Transducer;
+use super::state_machine::{DataTransducer, Transition};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::PathBuf;
+
+/*
+    Sources and transducers which can be checkpointed
+*/
+
+pub trait SourceOffset: Iterator {
+    type Offset: Clone + Serialize + DeserializeOwned;
+    // The offset of the next item
+    fn offset(&self) -> Self::Offset;
+    // Continue from an offset previously returned by .offset()
+    fn seek(&mut self, offset: &Self::Offset) -> io::Result<()>;
+}
+
+// Any iterator, with the number of items consumed as the offset. Seeking
+// skips items, so the iterator must produce the same items again after a
+// restart (e.g. the lines of a file), and can only seek forward.
+pub struct Counted<It> {
+    items: It,
+    offset: u64,
+}
+impl<It> Counted<It> {
+    pub fn new(items: It) -> Self {
+        Self { items, offset: 0 }
+    }
+}
+impl<It: Iterator> Iterator for Counted<It> {
+    type Item = It::Item;
+    fn next(&mut self) -> Option<It::Item> {
+        let item = self.items.next()?;
+        self.offset += 1;
+        Some(item)
+    }
+}
+impl<It: Iterator> SourceOffset for Counted<It> {
+    type Offset = u64;
+    fn offset(&self) -> u64 {
+        self.offset
+    }
+    fn seek(&mut self, &offset: &u64) -> io::Result<()> {
+        if offset < self.offset {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("can't seek back from {} to {}", self.offset, offset),
+            ));
+        }
+        while self.offset < offset {
+            if self.next().is_none() {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    format!("source ended before offset {}", offset),
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+pub trait Snapshot {
+    type State: Serialize + DeserializeOwned;
+    fn snapshot(&self) -> Self::State;
+    fn restore(&mut self, state: Self::State) -> Result<(), Box<dyn Error>>;
+}
+impl<'a, D, Q, U> Snapshot for DataTransducer<'a, D, Q, U>
+where
+    Q: Clone + Serialize + DeserializeOwned,
+    U: 'a + ?Sized + Transition<D, Q>,
+{
+    type State = Vec<Ext<Q>>;
+    fn snapshot(&self) -> Vec<Ext<Q>> {
+        self.get_states()
+    }
+    fn restore(&mut self, state: Vec<Ext<Q>>) -> Result<(), Box<dyn Error>> {
+        Ok(self.set_states(state)?)
+    }
+}
+
+/*
+    Persistence
+*/
+
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Checkpoint<Off, St> {
+    // The last step processed
+    pub step: usize,
+    // The offset of the next item in the source
+    pub offset: Off,
+    pub state: St,
+}
+
+pub trait CheckpointStore {
+    // Replace the latest checkpoint
+    fn save(&mut self, data: &str) -> io::Result<()>;
+    // The latest checkpoint, if any
+    fn load(&mut self) -> io::Result<Option<String>>;
+}
+
+// Stores the checkpoint in a file. Saving writes a temporary file next to
+// it, syncs it to disk, and renames it over the old one (then syncs the
+// directory, on unix), so that a crash or power loss while saving leaves
+// either the previous checkpoint or the new one, complete.
+pub struct FileStore {
+    path: PathBuf,
+}
+impl FileStore {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+}
+impl CheckpointStore for FileStore {
+    fn save(&mut self, data: &str) -> io::Result<()> {
+        let mut tmp = self.path.clone().into_os_string();
+        tmp.push(".tmp");
+        let mut file = File::create(&tmp)?;
+        file.write_all(data.as_bytes())?;
+        file.sync_all()?;
+        drop(file);
+        fs::rename(&tmp, &self.path)?;
+        // The rename itself is only durable once the directory is synced
+        // (directories can't be opened as files on Windows)
+        #[cfg(unix)]
+        {
+            let dir = match self.path.parent() {
+                Some(dir) if !dir.as_os_str().is_empty() => dir,
+                _ => std::path::Path::new("."),
+            };
+            File::open(dir)?.sync_all()?;
+        }
+        Ok(())
+    }
+    fn load(&mut self) -> io::Result<Option<String>> {
+        match fs::read_to_string(&self.path) {
+            Ok(data) => Ok(Some(data)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct MemoryStore {
+    pub data: Option<String>,
+    // Number of checkpoints saved
+    pub n_saves: usize,
+}
+impl CheckpointStore for MemoryStore {
+    fn save(&mut self, data: &str) -> io::Result<()> {
+        self.data = Some(data.to_string());
+        self.n_saves += 1;
+        Ok(())
+    }
+    fn load(&mut self) -> io::Result<Option<String>> {
+        Ok(self.data.clone())
+    }
+}
+
+/*
+    Driver
+*/
+
+pub struct Checkpointer<S: CheckpointStore> {
+    store: S,
+    // Number of items between checkpoints
+    every: usize,
+}
+impl<S: CheckpointStore> Checkpointer<S> {
+    pub fn new(store: S, every: usize) -> Self {
+        assert!(every > 0, "checkpoints need a positive interval");
+        Self { store, every }
+    }
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+    pub fn into_store(self) -> S {
+        self.store
+    }
+
+    fn save<Src, M>(
+        &mut self,
+        step: usize,
+        source: &Src,
+        m: &M,
+    ) -> Result<(), CheckpointError>
+    where
+        Src: SourceOffset,
+        M: Snapshot,
+    {
+        let checkpoint =
+            Checkpoint { step, offset: source.offset(), state: m.snapshot() };
+        self.store.save(&serde_json::to_string(&checkpoint)?)?;
+        Ok(())
+    }
+
+    // Run m on the source, resuming from the latest checkpoint if there is
+    // one, and passing each output to the callback with its step. An error
+    // from the callback stops the run without a final checkpoint (as a
+    // crash would). Returns the number of the last step.
+    pub fn run<I, D, O, M, Src, F>(
+        &mut self,
+        m: &mut M,
+        init: I,
+        source: &mut Src,
+        mut callback: F,
+    ) -> Result<usize, CheckpointError>
+    where
+        M: Transducer<I, D, O> + Snapshot,
+        Src: SourceOffset<Item = D>,
+        F: FnMut(usize, Ext<O>) -> io::Result<()>,
+    {
+        let mut step = match self.store.load()? {
+            Some(data) => {
+                let checkpoint: Checkpoint<Src::Offset, M::State> =
+                    serde_json::from_str(&data)?;
+                m.restore(checkpoint.state)
+                    .map_err(CheckpointError::Restore)?;
+                source.seek(&checkpoint.offset)?;
+                checkpoint.step
+            }
+            None => {
+                callback(0, m.init_one(init))?;
+                0
+            }
+        };
+        while let Some(item) = source.next() {
+            step += 1;
+            callback(step, m.update(&item))?;
+            if step % self.every == 0 {
+                self.save(step, source, m)?;
+            }
+        }
+        self.save(step, source, m)?;
+        Ok(step)
+    }
+}
+
+/*
+    Unit Tests
+*/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    // Running sum of the items
+    fn sum<'a>() -> DataTransducer<'a, i64, i64> {
+        let mut m = DataTransducer::new();
+        m.set_nstates(3);
+        m.add_epsilon1(0, 2, |&q| q);
+        m.add_transition1(2, 2, |_| true, |&d, &q| q + d);
+        m.add_epsilon1(2, 1, |&q| q);
+        m
+    }
+
+    #[test]
+    fn test_counted() {
+        let mut src = Counted::new(10..20);
+        assert_eq!(src.next(), Some(10));
+        assert_eq!(src.offset(), 1);
+        src.seek(&5).unwrap();
+        assert_eq!(src.next(), Some(15));
+        assert!(src.seek(&2).is_err());
+        assert!(src.seek(&100).is_err());
+    }
+
+    #[test]
+    fn test_resume() {
+        let items: Vec<i64> = (1..=10).collect();
+        // Crash while producing the output of step 7
+        let mut cp = Checkpointer::new(MemoryStore::default(), 3);
+        let mut seen = BTreeMap::new();
+        let err = cp.run(
+            &mut sum(),
+            0,
+            &mut Counted::new(items.iter().copied()),
+            |step, out| {
+                if step == 7 {
+                    return Err(io::ErrorKind::Interrupted.into());
+                }
+                seen.insert(step, out);
+                Ok(())
+            },
+        );
+        assert!(matches!(err, Err(CheckpointError::Io(_))));
+        let store = cp.into_store();
+        assert_eq!(store.n_saves, 2);
+        let checkpoint: Checkpoint<u64, Vec<Ext<i64>>> =
+            serde_json::from_str(store.data.as_ref().unwrap()).unwrap();
+        assert_eq!(checkpoint.step, 6);
+        assert_eq!(checkpoint.offset, 6);
+        assert_eq!(checkpoint.state[1], Ext::One(21));
+
+        // Recover with a new machine and source: processing resumes at step
+        // 7, and steps 1 to 6 are not repeated
+        let mut cp = Checkpointer::new(store, 3);
+        let last = cp
+            .run(
+                &mut sum(),
+                0,
+                &mut Counted::new(items.iter().copied()),
+                |step, out| {
+                    assert!(seen.insert(step, out).is_none());
+                    Ok(())
+                },
+            )
+            .unwrap();
+        assert_eq!(last, 10);
+        let expected: BTreeMap<usize, Ext<i64>> =
+            (0..=10).map(|n| (n, Ext::One((n * (n + 1) / 2) as i64))).collect();
+        assert_eq!(seen, expected);
+        // Nothing left to do
+        let last = cp
+            .run(&mut sum(), 0, &mut Counted::new(items.into_iter()), |_, _| {
+                panic!("no output expected")
+            })
+            .unwrap();
+        assert_eq!(last, 10);
+    }
+
+    #[test]
+    fn test_file_store() {
+        let path = std::env::temp_dir()
+            .join(format!("checkpoint-test-{}.json", std::process::id()));
+        let mut store = FileStore::new(&path);
+        assert_eq!(store.load().unwrap(), None);
+        store.save("first").unwrap();
+        store.save("second").unwrap();
+        assert_eq!(store.load().unwrap().as_deref(), Some("second"));
+        // The temporary file was renamed over the checkpoint
+        assert!(!path.with_extension("json.tmp").exists());
+        fs::remove_file(&path).unwrap();
+
+        // A machine with different states rejects the checkpoint
+        let mut cp = Checkpointer::new(MemoryStore::default(), 1);
+        let mut items = Counted::new(vec![1, 2].into_iter());
+        cp.run(&mut sum(), 0, &mut items, |_, _| Ok(())).unwrap();
+        let mut other = sum();
+        other.set_nstates(4);
+        let err = cp
+            .run(
+                &mut other,
+                0,
+                &mut Counted::new(vec![1, 2].into_iter()),
+                |_, _| Ok(()),
+            )
+            .unwrap_err();
+        assert!(matches!(err, CheckpointError::Restore(_)));
+    }
+}
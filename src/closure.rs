@@ -0,0 +1,286 @@
+/*
+    Transducers as closures, and closures as transducers.
+
+    into_fn(m, i) initializes m with i once, and returns a closure which
+    feeds it each item and returns the output: an FnMut(&D) -> Ext<O>, for
+    APIs which expect a callback (e.g. Iterator::map, or a registry of
+    event handlers). The output of the initialization itself is dropped;
+    to keep it, initialize the transducer and use as_fn(&mut m) instead.
+
+    from_fn(make) goes the other way: make builds a stateful closure from
+    the initial value, and the transducer feeds each item to the latest
+    closure built. Since the state is hidden in the closure, a restart
+    replaces it rather than running alongside it, so the transducer is not
+    restartable; and an initial value of Ext::Many can't build a closure,
+    so the transducer then outputs Many on every item.
+
+    Tiny stages can also be written as closures directly, without from_fn:
+    - a function fn(&D) -> Ext<O> (e.g. a closure capturing nothing, cast
+      with `as fn(&_) -> _`) is a stateless transducer;
+    - a pair (state, step) where step: FnMut(&mut S, &D) -> Ext<O> is a
+      transducer with state S, which .reset() sets to S::default().
+    (Closures can't implement Transducer themselves, as a boxed closure is
+    also a closure, and Box<M> is a transducer whenever M is.) These are
+    always running: they ignore initial values, and output on every item
+    (even before .init()), so they aren't restartable.
+*/
+
+use super::ext_value::Ext;
+use super::interface::Transducer;
+use std::marker::PhantomData;
+
+/*
+    Transducer to closure
+*/
+
+pub fn into_fn<I, D, O, M>(mut m: M, i: I) -> impl FnMut(&D) -> Ext<O>
+where
+    M: Transducer<I, D, O>,
+{
+    m.init_one(i);
+    move |item| m.update(item)
+}
+
+pub fn as_fn<'a, I, D, O, M>(m: &'a mut M) -> impl FnMut(&D) -> Ext<O> + 'a
+where
+    M: Transducer<I, D, O>,
+{
+    move |item| m.update(item)
+}
+
+/*
+    Closure to transducer
+*/
+
+enum Run<F> {
+    Stopped,
+    One(F),
+    Many,
+}
+
+pub struct FromFn<I, D, O, G, F>
+where
+    G: Fn(I) -> F,
+    F: FnMut(&D) -> Ext<O>,
+{
+    make: G,
+    run: Run<F>,
+    ph_i: PhantomData<I>,
+    ph_d: PhantomData<D>,
+    ph_o: PhantomData<O>,
+}
+pub fn from_fn<I, D, O, G, F>(make: G) -> FromFn<I, D, O, G, F>
+where
+    G: Fn(I) -> F,
+    F: FnMut(&D) -> Ext<O>,
+{
+    FromFn {
+        make,
+        run: Run::Stopped,
+        ph_i: PhantomData,
+        ph_d: PhantomData,
+        ph_o: PhantomData,
+    }
+}
+
+impl<I, D, O, G, F> Clone for FromFn<I, D, O, G, F>
+where
+    G: Fn(I) -> F + Clone,
+    F: FnMut(&D) -> Ext<O>,
+{
+    // The state of a closure can't be copied, so clones start stopped
+    fn clone(&self) -> Self {
+        from_fn(self.make.clone())
+    }
+}
+
+impl<I, D, O, G, F> Transducer<I, D, O> for FromFn<I, D, O, G, F>
+where
+    G: Fn(I) -> F,
+    F: FnMut(&D) -> Ext<O>,
+{
+    fn init(&mut self, i: Ext<I>) -> Ext<O> {
+        match i {
+            Ext::None => (),
+            Ext::One(x) => self.run = Run::One((self.make)(x)),
+            Ext::Many => self.run = Run::Many,
+        }
+        Ext::None
+    }
+    fn update(&mut self, item: &D) -> Ext<O> {
+        match &mut self.run {
+            Run::Stopped => Ext::None,
+            Run::One(f) => f(item),
+            Run::Many => Ext::Many,
+        }
+    }
+    fn reset(&mut self) {
+        self.run = Run::Stopped;
+    }
+
+    fn is_epsilon(&self) -> bool {
+        false
+    }
+    fn is_restartable(&self) -> bool {
+        false
+    }
+    fn is_nullable(&self) -> bool {
+        false
+    }
+    fn n_states(&self) -> usize {
+        1
+    }
+    fn n_transs(&self) -> usize {
+        1
+    }
+}
+
+/*
+    Closures as transducers
+*/
+
+impl<I, D, O> Transducer<I, D, O> for fn(&D) -> Ext<O> {
+    fn init(&mut self, _i: Ext<I>) -> Ext<O> {
+        Ext::None
+    }
+    fn update(&mut self, item: &D) -> Ext<O> {
+        self(item)
+    }
+    fn reset(&mut self) {}
+
+    fn is_epsilon(&self) -> bool {
+        false
+    }
+    fn is_restartable(&self) -> bool {
+        false
+    }
+    fn is_nullable(&self) -> bool {
+        false
+    }
+    fn n_states(&self) -> usize {
+        0
+    }
+    fn n_transs(&self) -> usize {
+        1
+    }
+}
+
+impl<I, D, O, S, F> Transducer<I, D, O> for (S, F)
+where
+    S: Default,
+    F: FnMut(&mut S, &D) -> Ext<O>,
+{
+    fn init(&mut self, _i: Ext<I>) -> Ext<O> {
+        Ext::None
+    }
+    fn update(&mut self, item: &D) -> Ext<O> {
+        (self.1)(&mut self.0, item)
+    }
+    fn reset(&mut self) {
+        self.0 = S::default();
+    }
+
+    fn is_epsilon(&self) -> bool {
+        false
+    }
+    fn is_restartable(&self) -> bool {
+        false
+    }
+    fn is_nullable(&self) -> bool {
+        false
+    }
+    fn n_states(&self) -> usize {
+        1
+    }
+    fn n_transs(&self) -> usize {
+        1
+    }
+}
+
+/*
+    Unit Tests
+*/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::sum;
+
+    #[test]
+    fn test_into_fn() {
+        let items = [1, 2, 3];
+        let outs: Vec<Ext<i64>> =
+            items.iter().map(into_fn(sum(), 10)).collect();
+        assert_eq!(outs, [Ext::One(11), Ext::One(13), Ext::One(16)]);
+
+        let mut m = sum();
+        assert_eq!(m.init_one(0), Ext::One(0));
+        type Handler<'a> = Box<dyn FnMut(&i64) -> Ext<i64> + 'a>;
+        let mut handlers: Vec<Handler> = vec![Box::new(as_fn(&mut m))];
+        assert_eq!(handlers[0](&5), Ext::One(5));
+        drop(handlers);
+        assert_eq!(m.update(&1), Ext::One(6));
+    }
+
+    #[test]
+    fn test_from_fn() {
+        let mut m = from_fn(|start: i64| {
+            let mut total = start;
+            move |&x: &i64| {
+                total += x;
+                if x > 0 {
+                    Ext::One(total)
+                } else {
+                    Ext::None
+                }
+            }
+        });
+        assert_eq!(m.update(&1), Ext::None);
+        assert_eq!(m.init_one(100), Ext::None);
+        assert_eq!(
+            m.update_batch(&[1, -2, 3]),
+            [Ext::One(101), Ext::None, Ext::One(102)]
+        );
+        // A restart replaces the closure
+        m.init_one(0);
+        assert_eq!(m.update(&1), Ext::One(1));
+        m.init(Ext::Many);
+        assert_eq!(m.update(&1), Ext::Many);
+        m.reset();
+        assert_eq!(m.update(&1), Ext::None);
+        assert!(!m.is_restartable());
+    }
+
+    #[test]
+    fn test_closures() {
+        use crate::qre::{concat, epsilon};
+
+        let vowel = |&c: &char| -> Ext<char> {
+            if "aeiou".contains(c) {
+                Ext::One(c.to_ascii_uppercase())
+            } else {
+                Ext::None
+            }
+        };
+        let mut m = vowel as fn(&_) -> _;
+        assert_eq!(Transducer::<(), _, _>::init_one(&mut m, ()), Ext::None);
+        let outs = Transducer::<(), _, _>::update_batch(&mut m, &['b', 'e']);
+        assert_eq!(outs, [Ext::None, Ext::One('E')]);
+
+        let count = |n: &mut u32, &c: &char| {
+            *n += 1;
+            if c == '.' {
+                Ext::One(*n)
+            } else {
+                Ext::None
+            }
+        };
+        // Counting from 10, after a stage which ignores its initial value
+        let mut m = concat(epsilon(|()| ()), (10, count));
+        m.init_one(());
+        assert_eq!(m.update_batch(&['a', '.']), [Ext::None, Ext::One(12)]);
+        m.reset();
+        m.init_one(());
+        assert_eq!(m.update(&'.'), Ext::One(1));
+    }
+}
@@ -0,0 +1,606 @@
+/*
+    Code generation: compile a DataTransducer to Rust source.
+
+    The generated module defines a struct implementing Transducer with the
+    same behavior as the machine, but with the states in a fixed-size array
+    and the guards and actions called directly, with no dynamic dispatch or
+    Rc. This is the fastest way to deploy a fixed query: generate the code
+    once (e.g. in a build script) and include it.
+
+    As for the export formats (see json_format.rs), every update transition
+    must have a symbolic guard, and every transition a named action. The
+    generated code calls these by name, from a user-provided module (by
+    default "fns") with submodules:
+        guards: fn(&D) -> bool, for each primitive guard
+        fields: fn(&D) -> i64, for each field used in a range constraint
+        updates: fn(&D) -> Q, fn(&D, &Q) -> Q, or fn(&D, &Q, &Q) -> Q,
+            for each update action with 0, 1, or 2 sources
+        epsilons: fn() -> Q, fn(&Q) -> Q, or fn(&Q, &Q) -> Q,
+            for each epsilon action with 0, 1, or 2 sources
+    The guard "true" and the one-source action "iden" are built in (as in
+    text_format.rs), so they need not be provided.
+
+    The machine must not have epsilon cycles (the epsilon transitions are
+    evaluated in a single pass, in topological order), and must use the
+    default ConflictPolicy::Union. Output actions, state guards, custom
+    final combiners, and subscriptions are not supported.
+*/
+
+use super::guard::Guard;
+use super::interface::Transducer;
+use super::state_machine::{
+    ConflictPolicy, DataTransducer, TransInfo, TransRef,
+};
+use std::error::Error;
+use std::fmt::{self, Write};
+
+// Names and paths used in the generated code
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CodegenOptions {
+    // Name of the generated struct
+    pub name: String,
+    // The types D and Q of the machine, as written in the generated module
+    pub item_type: String,
+    pub state_type: String,
+    // Path to the module with the guards and actions (see above)
+    pub fns: String,
+    // Path to this crate
+    pub crate_path: String,
+}
+impl CodegenOptions {
+    pub fn new(name: &str, item_type: &str, state_type: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            item_type: item_type.to_string(),
+            state_type: state_type.to_string(),
+            fns: "fns".to_string(),
+            crate_path: "data_transducers".to_string(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum CodegenError {
+    // Epsilon cycle through the given state
+    EpsilonCycle { state: usize },
+    // An update transition whose guard is a closure
+    NoSymbolicGuard { trans: TransRef },
+    // A transition whose action has no name
+    NoActionName { trans: TransRef },
+    // A guard, field, or action name which is not a Rust identifier
+    InvalidName { name: String },
+    // A feature of the machine that is not supported
+    Unsupported { feature: &'static str },
+}
+impl fmt::Display for CodegenError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CodegenError::EpsilonCycle { state } => write!(
+                f,
+                "can't generate code: state {} is on an epsilon cycle",
+                state
+            ),
+            CodegenError::NoSymbolicGuard { trans } => write!(
+                f,
+                "can't generate code for {:?}: guard is not symbolic",
+                trans
+            ),
+            CodegenError::NoActionName { trans } => write!(
+                f,
+                "can't generate code for {:?}: action has no name",
+                trans
+            ),
+            CodegenError::InvalidName { name } => {
+                write!(f, "{:?} is not a valid Rust identifier", name)
+            }
+            CodegenError::Unsupported { feature } => {
+                write!(f, "can't generate code for machines with {}", feature)
+            }
+        }
+    }
+}
+impl Error for CodegenError {}
+
+fn check_ident(name: &str) -> Result<&str, CodegenError> {
+    let mut chars = name.chars();
+    let valid = chars.next().is_some_and(|c| c.is_alphabetic() || c == '_')
+        && chars.all(|c| c.is_alphanumeric() || c == '_');
+    if valid {
+        Ok(name)
+    } else {
+        Err(CodegenError::InvalidName { name: name.to_string() })
+    }
+}
+
+// The guard as a Rust expression (of the variable item)
+fn guard_expr<D>(g: &Guard<'_, D>, fns: &str) -> Result<String, CodegenError> {
+    Ok(match g {
+        Guard::True => "true".to_string(),
+        Guard::False => "false".to_string(),
+        Guard::Pred(p) if p.name() == "true" => "true".to_string(),
+        Guard::Pred(p) => {
+            format!("{}::guards::{}(item)", fns, check_ident(p.name())?)
+        }
+        Guard::Range(x, lo, hi) => {
+            let x =
+                format!("{}::fields::{}(item)", fns, check_ident(x.name())?);
+            match (*lo, *hi) {
+                (lo, hi) if lo == hi => format!("{} == {}", x, lo),
+                (i64::MIN, i64::MAX) => "true".to_string(),
+                (lo, i64::MAX) => format!("{} >= {}", x, lo),
+                (i64::MIN, hi) => format!("{} <= {}", x, hi),
+                (lo, hi) => format!("({}..={}).contains(&{})", lo, hi, x),
+            }
+        }
+        Guard::Not(g) => format!("!({})", guard_expr(g, fns)?),
+        Guard::And(..) | Guard::Or(..) => format!("({})", guard_cond(g, fns)?),
+    })
+}
+
+// The same, without parentheses around a top-level && or ||
+fn guard_cond<D>(g: &Guard<'_, D>, fns: &str) -> Result<String, CodegenError> {
+    match g {
+        Guard::And(g1, g2) => {
+            Ok(format!("{} && {}", guard_expr(g1, fns)?, guard_expr(g2, fns)?))
+        }
+        Guard::Or(g1, g2) => {
+            Ok(format!("{} || {}", guard_expr(g1, fns)?, guard_expr(g2, fns)?))
+        }
+        _ => guard_expr(g, fns),
+    }
+}
+
+// The value an action produces, as a Rust expression; states is the
+// expression for the array of source values
+fn action_expr(
+    tr: &TransInfo,
+    name: &str,
+    states: &str,
+    fns: &str,
+) -> Result<String, CodegenError> {
+    if let ([s], "iden") = (tr.sources.as_slice(), name) {
+        return Ok(format!("{}[{}].clone()", states, s));
+    }
+    let name = check_ident(name)?;
+    let args: Vec<String> = tr
+        .sources
+        .iter()
+        .map(|s| format!("{}[{}].as_ref()", states, s))
+        .collect();
+    let vars = ["q1", "q2"][..args.len()].join(", ");
+    let op = match (tr.is_epsilon(), args.len()) {
+        (true, 0) => {
+            return Ok(format!("Ext::One({}::epsilons::{}())", fns, name))
+        }
+        (false, 0) => {
+            return Ok(format!("Ext::One({}::updates::{}(item))", fns, name))
+        }
+        (true, _) => format!("{}::epsilons::{}", fns, name),
+        (false, _) => {
+            format!("|{}| {}::updates::{}(item, {})", vars, fns, name, vars)
+        }
+    };
+    let mut expr = format!("ext_value::apply{}(\n", args.len());
+    for arg in std::iter::once(op).chain(args) {
+        writeln!(expr, "                {},", arg).unwrap();
+    }
+    Ok(expr + "            )")
+}
+
+// The epsilon transitions in an order where each comes after all those
+// into its sources
+fn epsilon_order(
+    epsilons: &[TransInfo],
+    n_states: usize,
+) -> Option<Vec<usize>> {
+    let mut n_into = vec![0; n_states];
+    for e in epsilons {
+        n_into[e.target] += 1;
+    }
+    let mut done = vec![false; epsilons.len()];
+    let mut order = vec![];
+    while order.len() < epsilons.len() {
+        let next = (0..epsilons.len()).find(|&i| {
+            !done[i] && epsilons[i].sources.iter().all(|&s| n_into[s] == 0)
+        })?;
+        done[next] = true;
+        n_into[epsilons[next].target] -= 1;
+        order.push(next);
+    }
+    Some(order)
+}
+
+// Generate the Rust source for a machine
+pub fn generate<D, Q: Clone>(
+    m: &DataTransducer<'_, D, Q>,
+    opts: &CodegenOptions,
+) -> Result<String, CodegenError> {
+    if let Some(&feature) = m.extensions_used().first() {
+        return Err(CodegenError::Unsupported { feature });
+    }
+    if m.conflict_policy() != ConflictPolicy::Union {
+        let feature = "priority-based conflict resolution";
+        return Err(CodegenError::Unsupported { feature });
+    }
+    let n = m.n_states();
+    let (updates, epsilons): (Vec<TransInfo>, Vec<TransInfo>) =
+        m.transitions().partition(|tr| !tr.is_epsilon());
+    let order = match epsilon_order(&epsilons, n) {
+        Some(order) => order,
+        None => {
+            let state = m.analyze(&[]).epsilon_cycles[0][0];
+            return Err(CodegenError::EpsilonCycle { state });
+        }
+    };
+    let action_name = |tr: &TransInfo| {
+        m.action_name(tr.trans)
+            .ok_or(CodegenError::NoActionName { trans: tr.trans })
+    };
+    let (fns, name) = (&opts.fns, &opts.name);
+    let (d, q) = (&opts.item_type, &opts.state_type);
+    let nones = |k: usize| vec!["Ext::None"; k].join(", ");
+
+    // Update transitions
+    let mut upd = String::new();
+    for tr in &updates {
+        let guard = m
+            .symbolic_guard(tr.trans)
+            .ok_or(CodegenError::NoSymbolicGuard { trans: tr.trans })?;
+        let action = action_expr(tr, action_name(tr)?, "old", fns)?;
+        writeln!(
+            upd,
+            "        // {:?}: {:?} -> {}",
+            tr.trans, tr.sources, tr.target
+        )
+        .unwrap();
+        writeln!(upd, "        if {} {{", guard_cond(&guard, fns)?).unwrap();
+        writeln!(upd, "            new[{}] += {};", tr.target, action).unwrap();
+        writeln!(upd, "        }}").unwrap();
+    }
+    // Epsilon transitions
+    let mut eps = String::new();
+    for &i in &order {
+        let tr = &epsilons[i];
+        let action = action_expr(tr, action_name(tr)?, "self.states", fns)?;
+        writeln!(
+            eps,
+            "        // {:?}: {:?} -> {}",
+            tr.trans, tr.sources, tr.target
+        )
+        .unwrap();
+        writeln!(eps, "        if self.eps_live({}, {}) {{", i, tr.target)
+            .unwrap();
+        writeln!(eps, "            let new = {};", action).unwrap();
+        writeln!(eps, "            self.eps_add({}, {}, new);", i, tr.target)
+            .unwrap();
+        writeln!(eps, "        }}").unwrap();
+    }
+    let finals = m.final_states();
+    let output = match finals.as_slice() {
+        [f] => format!("self.states[{}].clone()", f),
+        _ => {
+            let mut out = "let mut out = Ext::None;\n".to_string();
+            for f in &finals {
+                writeln!(out, "        out += self.states[{}].clone();", f)
+                    .unwrap();
+            }
+            out + "        out"
+        }
+    };
+    let uses_ext_value =
+        upd.contains("ext_value::") || eps.contains("ext_value::");
+
+    let mut code = String::new();
+    let c = &mut code;
+    writeln!(c, "// Generated by data_transducers::codegen; do not edit.")
+        .unwrap();
+    writeln!(c, "// Guards and actions are in {}::{{guards, fields, updates, epsilons}}.", fns)
+        .unwrap();
+    writeln!(c).unwrap();
+    if uses_ext_value {
+        writeln!(c, "use {}::ext_value::{{self, Ext}};", opts.crate_path)
+            .unwrap();
+    } else {
+        writeln!(c, "use {}::ext_value::Ext;", opts.crate_path).unwrap();
+    }
+    writeln!(c, "use {}::interface::Transducer;", opts.crate_path).unwrap();
+    writeln!(c).unwrap();
+    writeln!(c, "#[derive(Clone, Debug)]").unwrap();
+    writeln!(c, "pub struct {} {{", name).unwrap();
+    writeln!(c, "    states: [Ext<{}>; {}],", q, n).unwrap();
+    writeln!(c, "    // Contribution of each epsilon transition in this step")
+        .unwrap();
+    writeln!(c, "    eps_vals: [Ext<()>; {}],", epsilons.len()).unwrap();
+    writeln!(c, "}}").unwrap();
+    writeln!(c).unwrap();
+    writeln!(c, "impl Default for {} {{", name).unwrap();
+    writeln!(c, "    fn default() -> Self {{").unwrap();
+    writeln!(c, "        Self::new()").unwrap();
+    writeln!(c, "    }}").unwrap();
+    writeln!(c, "}}").unwrap();
+    writeln!(c).unwrap();
+    // The state type may or may not be Copy
+    writeln!(c, "#[allow(clippy::clone_on_copy)]").unwrap();
+    writeln!(c, "impl {} {{", name).unwrap();
+    writeln!(c, "    pub fn new() -> Self {{").unwrap();
+    writeln!(c, "        Self {{").unwrap();
+    writeln!(c, "            states: [{}],", nones(n)).unwrap();
+    writeln!(c, "            eps_vals: [Ext::None; {}],", epsilons.len())
+        .unwrap();
+    writeln!(c, "        }}").unwrap();
+    writeln!(c, "    }}").unwrap();
+    writeln!(c, "    fn eval_epsilons(&mut self) {{").unwrap();
+    c.push_str(&eps);
+    writeln!(c, "    }}").unwrap();
+    if !epsilons.is_empty() {
+        writeln!(
+            c,
+            "    // Whether an epsilon transition may still change its target"
+        )
+        .unwrap();
+        writeln!(
+            c,
+            "    fn eps_live(&self, e: usize, target: usize) -> bool {{"
+        )
+        .unwrap();
+        writeln!(c, "        !self.eps_vals[e].is_many() && !self.states[target].is_many()")
+            .unwrap();
+        writeln!(c, "    }}").unwrap();
+        writeln!(c, "    fn eps_add(&mut self, e: usize, target: usize, new: Ext<{}>) {{", q)
+            .unwrap();
+        writeln!(c, "        if new.is_none() || new.is_one() && self.eps_vals[e].is_one() {{")
+            .unwrap();
+        writeln!(c, "            return;").unwrap();
+        writeln!(c, "        }}").unwrap();
+        writeln!(c, "        self.eps_vals[e] = new.to_unit();").unwrap();
+        writeln!(c, "        self.states[target] += new;").unwrap();
+        writeln!(c, "    }}").unwrap();
+    }
+    writeln!(c, "    fn output(&self) -> Ext<{}> {{", q).unwrap();
+    writeln!(c, "        {}", output).unwrap();
+    writeln!(c, "    }}").unwrap();
+    writeln!(c, "}}").unwrap();
+    writeln!(c).unwrap();
+    writeln!(c, "#[allow(clippy::clone_on_copy)]").unwrap();
+    writeln!(c, "impl Transducer<{q}, {d}, {q}> for {} {{", name, q = q, d = d)
+        .unwrap();
+    writeln!(c, "    fn init(&mut self, i: Ext<{}>) -> Ext<{}> {{", q, q)
+        .unwrap();
+    writeln!(c, "        self.states[0] += i;").unwrap();
+    writeln!(c, "        self.eval_epsilons();").unwrap();
+    writeln!(c, "        self.output()").unwrap();
+    writeln!(c, "    }}").unwrap();
+    if updates.is_empty() {
+        writeln!(c, "    fn update(&mut self, _item: &{}) -> Ext<{}> {{", d, q)
+            .unwrap();
+        writeln!(c, "        self.states = [{}];", nones(n)).unwrap();
+    } else {
+        writeln!(c, "    fn update(&mut self, item: &{}) -> Ext<{}> {{", d, q)
+            .unwrap();
+        writeln!(c, "        let old = &self.states;").unwrap();
+        writeln!(
+            c,
+            "        let mut new: [Ext<{}>; {}] = [{}];",
+            q,
+            n,
+            nones(n)
+        )
+        .unwrap();
+        c.push_str(&upd);
+        writeln!(c, "        self.states = new;").unwrap();
+    }
+    writeln!(c, "        self.eps_vals = [Ext::None; {}];", epsilons.len())
+        .unwrap();
+    writeln!(c, "        self.eval_epsilons();").unwrap();
+    writeln!(c, "        self.output()").unwrap();
+    writeln!(c, "    }}").unwrap();
+    writeln!(c, "    fn reset(&mut self) {{").unwrap();
+    writeln!(c, "        *self = Self::new();").unwrap();
+    writeln!(c, "    }}").unwrap();
+    writeln!(c).unwrap();
+    writeln!(c, "    fn is_epsilon(&self) -> bool {{").unwrap();
+    writeln!(c, "        {}", updates.is_empty()).unwrap();
+    writeln!(c, "    }}").unwrap();
+    writeln!(c, "    fn is_restartable(&self) -> bool {{").unwrap();
+    writeln!(c, "        unimplemented!()").unwrap();
+    writeln!(c, "    }}").unwrap();
+    writeln!(c, "    fn is_nullable(&self) -> bool {{").unwrap();
+    writeln!(c, "        {}", m.is_nullable()).unwrap();
+    writeln!(c, "    }}").unwrap();
+    writeln!(c, "    fn n_states(&self) -> usize {{").unwrap();
+    writeln!(c, "        {}", n).unwrap();
+    writeln!(c, "    }}").unwrap();
+    writeln!(c, "    fn n_transs(&self) -> usize {{").unwrap();
+    writeln!(c, "        {}", m.n_transs()).unwrap();
+    writeln!(c, "    }}").unwrap();
+    writeln!(c, "}}").unwrap();
+    Ok(code)
+}
+
+/*
+    Unit Tests
+*/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ext_value::Ext;
+    use crate::guard;
+    use crate::interface::RInput;
+    use crate::text_format::{parse_machine, FnTable};
+
+    type ExD = (char, isize);
+    type ExQ = isize;
+
+    // Guards and actions for the generated code
+    mod fns {
+        use super::{ExD, ExQ};
+        pub mod guards {
+            use super::ExD;
+            pub fn is_a(d: &ExD) -> bool {
+                d.0 == 'a'
+            }
+            pub fn is_b(d: &ExD) -> bool {
+                d.0 == 'b'
+            }
+            pub fn is_hash(d: &ExD) -> bool {
+                d.0 == '#'
+            }
+        }
+        pub mod fields {
+            use super::ExD;
+            pub fn value(d: &ExD) -> i64 {
+                d.1 as i64
+            }
+        }
+        pub mod updates {
+            use super::{ExD, ExQ};
+            pub fn value(d: &ExD, _: &ExQ) -> ExQ {
+                d.1
+            }
+            pub fn one(_: &ExD, _: &ExQ) -> ExQ {
+                1
+            }
+            pub fn add_value(d: &ExD, q: &ExQ) -> ExQ {
+                q + d.1
+            }
+            pub fn add_one(_: &ExD, q: &ExQ) -> ExQ {
+                q + 1
+            }
+            pub fn divide(_: &ExD, q1: &ExQ, q2: &ExQ) -> ExQ {
+                q1 / q2
+            }
+        }
+        pub mod epsilons {
+            use super::ExQ;
+            pub fn max(q1: &ExQ, q2: &ExQ) -> ExQ {
+                *q1.max(q2)
+            }
+        }
+    }
+
+    mod golden {
+        include!("codegen_golden.rs");
+    }
+
+    // The average example from text_format.rs, with a range guard and an
+    // epsilon transition which must be evaluated first
+    fn example_machine() -> DataTransducer<'static, ExD, ExQ> {
+        let mut table = FnTable::new();
+        table
+            .guard("is_a", fns::guards::is_a)
+            .guard("is_b", fns::guards::is_b)
+            .guard("is_hash", fns::guards::is_hash)
+            .action1("value", fns::updates::value)
+            .action1("one", fns::updates::one)
+            .action1("add_value", fns::updates::add_value)
+            .action1("add_one", fns::updates::add_one)
+            .action2("divide", fns::updates::divide);
+        let text = "
+            states 4
+            label 2 sum
+            label 3 count
+            update 0 -> 0 when is_b do iden
+            update sum -> sum when is_b do iden
+            update count -> count when is_b do iden
+            update 0 -> sum when is_a do value
+            update 0 -> count when is_a do one
+            update sum -> sum when is_a do add_value
+            update count -> count when is_a do add_one
+            update sum count -> 1 when is_hash do divide
+            update 0 -> 1 when is_hash do iden
+            epsilon 1 -> 0 do iden
+        ";
+        let mut m = parse_machine(text, &table).unwrap();
+        let value = guard::field("value", fns::fields::value);
+        let large =
+            guard::pred("is_b", fns::guards::is_b) & value.at_least(100);
+        let tr = m.add_sym_transition1(2, 1, large, fns::updates::add_value);
+        m.set_action_name(tr, "add_value");
+        let tr = m.add_epsilon2(2, 3, 1, fns::epsilons::max);
+        m.set_action_name(tr, "max");
+        m
+    }
+
+    fn options() -> CodegenOptions {
+        CodegenOptions {
+            fns: "super::fns".to_string(),
+            crate_path: "crate".to_string(),
+            ..CodegenOptions::new("Average", "(char, isize)", "isize")
+        }
+    }
+
+    #[test]
+    fn test_golden() {
+        let code = generate(&example_machine(), &options()).unwrap();
+        assert_eq!(code, include_str!("codegen_golden.rs"));
+    }
+
+    #[test]
+    fn test_generated_behavior() {
+        let mut m1 = example_machine();
+        let mut m2 = golden::Average::new();
+        assert_eq!(m2.n_states(), m1.n_states());
+        assert_eq!(m2.n_transs(), m1.n_transs());
+        assert_eq!(m2.is_nullable(), m1.is_nullable());
+        let strm = [
+            RInput::Restart(0),
+            RInput::Item(('a', 6)),
+            RInput::Item(('b', 2)),
+            RInput::Item(('a', 8)),
+            RInput::Item(('#', 0)),
+            RInput::Item(('a', 300)),
+            RInput::Item(('b', 100)),
+            RInput::Restart(1),
+            RInput::Item(('#', 0)),
+            RInput::Item(('a', 2)),
+            RInput::Item(('b', 100)),
+            RInput::Item(('#', 0)),
+        ];
+        let out1: Vec<Ext<ExQ>> =
+            m1.process_rstream_single(strm.iter().copied()).collect();
+        let out2: Vec<Ext<ExQ>> =
+            m2.process_rstream_single(strm.iter().copied()).collect();
+        assert_eq!(out1, out2);
+        assert!(out1.iter().any(|out| out.is_many()));
+        m2.reset();
+        assert_eq!(m2.init_one(3), Ext::None);
+        assert_eq!(m2.update_val(('#', 0)), Ext::One(3));
+    }
+
+    #[test]
+    fn test_errors() {
+        let opts = options();
+        let mut m = example_machine();
+        m.add_transition0(1, |_| true, |&d: &ExD| d.1);
+        let err = generate(&m, &opts).unwrap_err();
+        let trans = TransRef::Update(10);
+        assert_eq!(err, CodegenError::NoSymbolicGuard { trans });
+
+        let mut m = example_machine();
+        let tr = m.add_sym_transition0(1, guard::Guard::True, |&d: &ExD| d.1);
+        assert_eq!(
+            generate(&m, &opts),
+            Err(CodegenError::NoActionName { trans: tr })
+        );
+        m.set_action_name(tr, "not valid");
+        assert_eq!(
+            generate(&m, &opts).unwrap_err().to_string(),
+            "\"not valid\" is not a valid Rust identifier"
+        );
+
+        let mut m = example_machine();
+        m.add_epsilon_iden(0, 2);
+        assert_eq!(
+            generate(&m, &opts),
+            Err(CodegenError::EpsilonCycle { state: 0 })
+        );
+
+        let mut m = example_machine();
+        m.set_final_combiner(|_| Ext::None);
+        assert!(matches!(
+            generate(&m, &opts),
+            Err(CodegenError::Unsupported { feature: "a final combiner" })
+        ));
+    }
+}
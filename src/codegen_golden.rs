@@ -0,0 +1,153 @@
+// Generated by data_transducers::codegen; do not edit.
+// Guards and actions are in super::fns::{guards, fields, updates, epsilons}.
+
+use crate::ext_value::{self, Ext};
+use crate::interface::Transducer;
+
+#[derive(Clone, Debug)]
+pub struct Average {
+    states: [Ext<isize>; 4],
+    // Contribution of each epsilon transition in this step
+    eps_vals: [Ext<()>; 2],
+}
+
+impl Default for Average {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[allow(clippy::clone_on_copy)]
+impl Average {
+    pub fn new() -> Self {
+        Self {
+            states: [Ext::None, Ext::None, Ext::None, Ext::None],
+            eps_vals: [Ext::None; 2],
+        }
+    }
+    fn eval_epsilons(&mut self) {
+        // Epsilon(1): [2, 3] -> 1
+        if self.eps_live(1, 1) {
+            let new = ext_value::apply2(
+                super::fns::epsilons::max,
+                self.states[2].as_ref(),
+                self.states[3].as_ref(),
+            );
+            self.eps_add(1, 1, new);
+        }
+        // Epsilon(0): [1] -> 0
+        if self.eps_live(0, 0) {
+            let new = self.states[1].clone();
+            self.eps_add(0, 0, new);
+        }
+    }
+    // Whether an epsilon transition may still change its target
+    fn eps_live(&self, e: usize, target: usize) -> bool {
+        !self.eps_vals[e].is_many() && !self.states[target].is_many()
+    }
+    fn eps_add(&mut self, e: usize, target: usize, new: Ext<isize>) {
+        if new.is_none() || new.is_one() && self.eps_vals[e].is_one() {
+            return;
+        }
+        self.eps_vals[e] = new.to_unit();
+        self.states[target] += new;
+    }
+    fn output(&self) -> Ext<isize> {
+        self.states[1].clone()
+    }
+}
+
+#[allow(clippy::clone_on_copy)]
+impl Transducer<isize, (char, isize), isize> for Average {
+    fn init(&mut self, i: Ext<isize>) -> Ext<isize> {
+        self.states[0] += i;
+        self.eval_epsilons();
+        self.output()
+    }
+    fn update(&mut self, item: &(char, isize)) -> Ext<isize> {
+        let old = &self.states;
+        let mut new: [Ext<isize>; 4] = [Ext::None, Ext::None, Ext::None, Ext::None];
+        // Update(0): [0] -> 0
+        if super::fns::guards::is_b(item) {
+            new[0] += old[0].clone();
+        }
+        // Update(1): [2] -> 2
+        if super::fns::guards::is_b(item) {
+            new[2] += old[2].clone();
+        }
+        // Update(2): [3] -> 3
+        if super::fns::guards::is_b(item) {
+            new[3] += old[3].clone();
+        }
+        // Update(3): [0] -> 2
+        if super::fns::guards::is_a(item) {
+            new[2] += ext_value::apply1(
+                |q1| super::fns::updates::value(item, q1),
+                old[0].as_ref(),
+            );
+        }
+        // Update(4): [0] -> 3
+        if super::fns::guards::is_a(item) {
+            new[3] += ext_value::apply1(
+                |q1| super::fns::updates::one(item, q1),
+                old[0].as_ref(),
+            );
+        }
+        // Update(5): [2] -> 2
+        if super::fns::guards::is_a(item) {
+            new[2] += ext_value::apply1(
+                |q1| super::fns::updates::add_value(item, q1),
+                old[2].as_ref(),
+            );
+        }
+        // Update(6): [3] -> 3
+        if super::fns::guards::is_a(item) {
+            new[3] += ext_value::apply1(
+                |q1| super::fns::updates::add_one(item, q1),
+                old[3].as_ref(),
+            );
+        }
+        // Update(7): [2, 3] -> 1
+        if super::fns::guards::is_hash(item) {
+            new[1] += ext_value::apply2(
+                |q1, q2| super::fns::updates::divide(item, q1, q2),
+                old[2].as_ref(),
+                old[3].as_ref(),
+            );
+        }
+        // Update(8): [0] -> 1
+        if super::fns::guards::is_hash(item) {
+            new[1] += old[0].clone();
+        }
+        // Update(9): [2] -> 1
+        if super::fns::guards::is_b(item) && super::fns::fields::value(item) >= 100 {
+            new[1] += ext_value::apply1(
+                |q1| super::fns::updates::add_value(item, q1),
+                old[2].as_ref(),
+            );
+        }
+        self.states = new;
+        self.eps_vals = [Ext::None; 2];
+        self.eval_epsilons();
+        self.output()
+    }
+    fn reset(&mut self) {
+        *self = Self::new();
+    }
+
+    fn is_epsilon(&self) -> bool {
+        false
+    }
+    fn is_restartable(&self) -> bool {
+        unimplemented!()
+    }
+    fn is_nullable(&self) -> bool {
+        false
+    }
+    fn n_states(&self) -> usize {
+        4
+    }
+    fn n_transs(&self) -> usize {
+        12
+    }
+}
@@ -0,0 +1,755 @@
+/*
+    Regexes over symbolic guards, compiled to machines with derivatives.
+
+    A Regex is built from guards (see guard.rs), each matching a single
+    item, with concatenation, union (|), Kleene star, and also
+    intersection (&) and complement (!). Derivatives compile a regex to a
+    machine incrementally: the derivative of a regex r by an item is a
+    regex matching the rest of the words of r which start with that item,
+    so the states of the machine are regexes, starting from r, and each
+    item moves to the derivative (Brzozowski). Derivatives are simplified
+    (e.g. unions are kept as sets), so that there are finitely many of
+    them up to this simplification, and the construction terminates.
+
+    The items are only seen through the guards of the regex, so the
+    derivative by an item only depends on which of the guards hold on it;
+    the machine's transitions are labeled with the satisfiable boolean
+    combinations of the guards ("minterms"), which may be few even when
+    the item type is large. The guards are identified by their string
+    representation (as in guard.rs, predicates with the same name are
+    assumed to be the same). Also as in guard.rs, different predicates are
+    assumed independent: if they are in fact exclusive, some minterms
+    can't hold on any item, and the full DFA has states which are never
+    reached (the lazy DFA doesn't, as it only sees actual items).
+
+    Derivatives gives three machines for a regex, each of which outputs
+    the initial value on each prefix of the stream (since .init()) which
+    is in the language:
+    - .lazy(): a DFA whose states are built on the fly, as the items are
+      seen (and cached), so that only the reachable part of the DFA is
+      ever built; this works even when the full DFA would be too large;
+    - .to_dfa(): the full DFA, as a DataTransducer, with a state for each
+      derivative which is not empty;
+    - .to_nfa(): an NFA, as a DataTransducer, built with Antimirov's
+      partial derivatives, which split derivatives by unions and so give
+      fewer states (at most one more than the number of guards). Regexes
+      with intersection or complement are not supported. As for the QRE
+      constructs, a prefix matched in several ways gives Ext::Many.
+    These are independent of the QRE constructs and their lowering
+    (lower.rs), so can be used to check them (see the unit tests).
+*/
+
+use super::ext_value::Ext;
+use super::guard::Guard;
+use super::interface::Transducer;
+use super::state_machine::DataTransducer;
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::ops;
+
+/*
+    Regexes
+*/
+
+pub enum Regex<'a, D> {
+    Empty,
+    Eps,
+    Sym(Guard<'a, D>),
+    Cat(Box<Regex<'a, D>>, Box<Regex<'a, D>>),
+    Alt(Box<Regex<'a, D>>, Box<Regex<'a, D>>),
+    Star(Box<Regex<'a, D>>),
+    And(Box<Regex<'a, D>>, Box<Regex<'a, D>>),
+    Not(Box<Regex<'a, D>>),
+}
+impl<D> Clone for Regex<'_, D> {
+    fn clone(&self) -> Self {
+        match self {
+            Regex::Empty => Regex::Empty,
+            Regex::Eps => Regex::Eps,
+            Regex::Sym(g) => Regex::Sym(g.clone()),
+            Regex::Cat(r1, r2) => Regex::Cat(r1.clone(), r2.clone()),
+            Regex::Alt(r1, r2) => Regex::Alt(r1.clone(), r2.clone()),
+            Regex::Star(r) => Regex::Star(r.clone()),
+            Regex::And(r1, r2) => Regex::And(r1.clone(), r2.clone()),
+            Regex::Not(r) => Regex::Not(r.clone()),
+        }
+    }
+}
+
+pub fn sym<D>(g: Guard<'_, D>) -> Regex<'_, D> {
+    Regex::Sym(g)
+}
+pub fn cat<'a, D>(r1: Regex<'a, D>, r2: Regex<'a, D>) -> Regex<'a, D> {
+    Regex::Cat(Box::new(r1), Box::new(r2))
+}
+pub fn star<D>(r: Regex<'_, D>) -> Regex<'_, D> {
+    Regex::Star(Box::new(r))
+}
+pub fn plus<D>(r: Regex<'_, D>) -> Regex<'_, D> {
+    cat(r.clone(), star(r))
+}
+pub fn opt<D>(r: Regex<'_, D>) -> Regex<'_, D> {
+    Regex::Eps | r
+}
+// Concatenation of any number of regexes
+pub fn seq<D>(rs: Vec<Regex<'_, D>>) -> Regex<'_, D> {
+    rs.into_iter().rev().fold(Regex::Eps, |acc, r| cat(r, acc))
+}
+
+impl<'a, D> ops::BitOr for Regex<'a, D> {
+    type Output = Regex<'a, D>;
+    fn bitor(self, other: Self) -> Self {
+        Regex::Alt(Box::new(self), Box::new(other))
+    }
+}
+impl<'a, D> ops::BitAnd for Regex<'a, D> {
+    type Output = Regex<'a, D>;
+    fn bitand(self, other: Self) -> Self {
+        Regex::And(Box::new(self), Box::new(other))
+    }
+}
+impl<'a, D> ops::Not for Regex<'a, D> {
+    type Output = Regex<'a, D>;
+    fn not(self) -> Self {
+        Regex::Not(Box::new(self))
+    }
+}
+
+/*
+    Derivatives
+
+    Regexes are hash-consed into terms, which are indices into a table of
+    nodes, and are kept in a normal form by the constructors below: so
+    two derivatives which are equal up to the simplifications are the same
+    term, and terms can be compared and used as states directly.
+*/
+
+type Term = usize;
+const EMPTY: Term = 0;
+const EPS: Term = 1;
+
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+enum Node {
+    Empty,
+    Eps,
+    // Index of the guard
+    Sym(usize),
+    Cat(Term, Term),
+    // Sorted, without duplicates, and at least two
+    Alt(Vec<Term>),
+    Star(Term),
+    And(Vec<Term>),
+    Not(Term),
+}
+
+pub struct Derivatives<'a, D> {
+    guards: Vec<Guard<'a, D>>,
+    nodes: Vec<Node>,
+    nullable: Vec<bool>,
+    terms: HashMap<Node, Term>,
+    start: Term,
+    // Whether intersection or complement is used
+    extended: bool,
+    // The sets of guards holding on the items seen, as classes of items
+    classes: HashMap<Vec<bool>, usize>,
+    class_bits: Vec<Vec<bool>>,
+    // Derivatives by term and class
+    derivs: HashMap<(Term, usize), Term>,
+}
+
+impl<'a, D> Derivatives<'a, D> {
+    pub fn new(r: &Regex<'a, D>) -> Self {
+        let mut result = Self {
+            guards: Vec::new(),
+            nodes: Vec::new(),
+            nullable: Vec::new(),
+            terms: HashMap::new(),
+            start: EMPTY,
+            extended: false,
+            classes: HashMap::new(),
+            class_bits: Vec::new(),
+            derivs: HashMap::new(),
+        };
+        result.intern(Node::Empty);
+        result.intern(Node::Eps);
+        result.start = result.term_of(r);
+        result
+    }
+    pub fn n_guards(&self) -> usize {
+        self.guards.len()
+    }
+    // Number of distinct terms (derivatives and their subterms) built so far
+    pub fn n_terms(&self) -> usize {
+        self.nodes.len()
+    }
+    pub fn is_extended(&self) -> bool {
+        self.extended
+    }
+
+    fn term_of(&mut self, r: &Regex<'a, D>) -> Term {
+        match r {
+            Regex::Empty => EMPTY,
+            Regex::Eps => EPS,
+            Regex::Sym(g) => {
+                let name = g.to_string();
+                let i = match self
+                    .guards
+                    .iter()
+                    .position(|h| h.to_string() == name)
+                {
+                    Some(i) => i,
+                    None => {
+                        self.guards.push(g.clone());
+                        self.guards.len() - 1
+                    }
+                };
+                self.intern(Node::Sym(i))
+            }
+            Regex::Cat(r1, r2) => {
+                let (t1, t2) = (self.term_of(r1), self.term_of(r2));
+                self.cat(t1, t2)
+            }
+            Regex::Alt(r1, r2) => {
+                let (t1, t2) = (self.term_of(r1), self.term_of(r2));
+                self.alt(vec![t1, t2])
+            }
+            Regex::Star(r) => {
+                let t = self.term_of(r);
+                self.star(t)
+            }
+            Regex::And(r1, r2) => {
+                self.extended = true;
+                let (t1, t2) = (self.term_of(r1), self.term_of(r2));
+                self.and(vec![t1, t2])
+            }
+            Regex::Not(r) => {
+                self.extended = true;
+                let t = self.term_of(r);
+                self.not(t)
+            }
+        }
+    }
+
+    /* Smart constructors */
+
+    fn intern(&mut self, node: Node) -> Term {
+        if let Some(&t) = self.terms.get(&node) {
+            return t;
+        }
+        let nullable = match &node {
+            Node::Empty | Node::Sym(_) => false,
+            Node::Eps | Node::Star(_) => true,
+            Node::Cat(t1, t2) => self.nullable[*t1] && self.nullable[*t2],
+            Node::Alt(ts) => ts.iter().any(|&t| self.nullable[t]),
+            Node::And(ts) => ts.iter().all(|&t| self.nullable[t]),
+            Node::Not(t) => !self.nullable[*t],
+        };
+        self.nodes.push(node.clone());
+        self.nullable.push(nullable);
+        self.terms.insert(node, self.nodes.len() - 1);
+        self.nodes.len() - 1
+    }
+    // The regex matching everything
+    fn all(&mut self) -> Term {
+        self.intern(Node::Not(EMPTY))
+    }
+    fn cat(&mut self, t1: Term, t2: Term) -> Term {
+        match (&self.nodes[t1], t2) {
+            (Node::Empty, _) | (_, EMPTY) => EMPTY,
+            (Node::Eps, _) => t2,
+            (_, EPS) => t1,
+            // Associate to the right
+            (&Node::Cat(t11, t12), _) => {
+                let rest = self.cat(t12, t2);
+                self.cat(t11, rest)
+            }
+            _ => self.intern(Node::Cat(t1, t2)),
+        }
+    }
+    fn alt(&mut self, ts: Vec<Term>) -> Term {
+        let all = self.all();
+        let mut set = BTreeSet::new();
+        for t in ts {
+            match &self.nodes[t] {
+                Node::Alt(ts1) => set.extend(ts1.iter().copied()),
+                Node::Empty => (),
+                _ => {
+                    set.insert(t);
+                }
+            }
+        }
+        if set.contains(&all) {
+            return all;
+        }
+        match set.len() {
+            0 => EMPTY,
+            1 => *set.iter().next().unwrap(),
+            _ => self.intern(Node::Alt(set.into_iter().collect())),
+        }
+    }
+    fn and(&mut self, ts: Vec<Term>) -> Term {
+        let all = self.all();
+        let mut set = BTreeSet::new();
+        for t in ts {
+            match &self.nodes[t] {
+                Node::And(ts1) => set.extend(ts1.iter().copied()),
+                Node::Empty => return EMPTY,
+                _ if t == all => (),
+                _ => {
+                    set.insert(t);
+                }
+            }
+        }
+        match set.len() {
+            0 => all,
+            1 => *set.iter().next().unwrap(),
+            _ => self.intern(Node::And(set.into_iter().collect())),
+        }
+    }
+    fn star(&mut self, t: Term) -> Term {
+        match self.nodes[t] {
+            Node::Empty | Node::Eps => EPS,
+            Node::Star(_) => t,
+            _ => self.intern(Node::Star(t)),
+        }
+    }
+    fn not(&mut self, t: Term) -> Term {
+        match self.nodes[t] {
+            Node::Not(t1) => t1,
+            _ => self.intern(Node::Not(t)),
+        }
+    }
+
+    /* Derivatives */
+
+    // The class of items on which exactly the given guards hold
+    fn class(&mut self, bits: Vec<bool>) -> usize {
+        if let Some(&c) = self.classes.get(&bits) {
+            return c;
+        }
+        self.class_bits.push(bits.clone());
+        self.classes.insert(bits, self.class_bits.len() - 1);
+        self.class_bits.len() - 1
+    }
+    fn class_of(&mut self, item: &D) -> usize {
+        let bits = self.guards.iter().map(|g| g.eval(item)).collect();
+        self.class(bits)
+    }
+
+    // Brzozowski derivative
+    fn deriv(&mut self, t: Term, c: usize) -> Term {
+        if let Some(&d) = self.derivs.get(&(t, c)) {
+            return d;
+        }
+        let d = match self.nodes[t].clone() {
+            Node::Empty | Node::Eps => EMPTY,
+            Node::Sym(g) => {
+                if self.class_bits[c][g] {
+                    EPS
+                } else {
+                    EMPTY
+                }
+            }
+            Node::Cat(t1, t2) => {
+                let d1 = self.deriv(t1, c);
+                let first = self.cat(d1, t2);
+                if self.nullable[t1] {
+                    let d2 = self.deriv(t2, c);
+                    self.alt(vec![first, d2])
+                } else {
+                    first
+                }
+            }
+            Node::Alt(ts) => {
+                let ds = ts.iter().map(|&t1| self.deriv(t1, c)).collect();
+                self.alt(ds)
+            }
+            Node::Star(t1) => {
+                let d1 = self.deriv(t1, c);
+                self.cat(d1, t)
+            }
+            Node::And(ts) => {
+                let ds = ts.iter().map(|&t1| self.deriv(t1, c)).collect();
+                self.and(ds)
+            }
+            Node::Not(t1) => {
+                let d1 = self.deriv(t1, c);
+                self.not(d1)
+            }
+        };
+        self.derivs.insert((t, c), d);
+        d
+    }
+    // Antimirov partial derivatives (without intersection or complement)
+    fn partial_derivs(&mut self, t: Term, c: usize) -> BTreeSet<Term> {
+        match self.nodes[t].clone() {
+            Node::Empty | Node::Eps => BTreeSet::new(),
+            Node::Sym(g) => {
+                if self.class_bits[c][g] {
+                    std::iter::once(EPS).collect()
+                } else {
+                    BTreeSet::new()
+                }
+            }
+            Node::Cat(t1, t2) => {
+                let mut result: BTreeSet<Term> = self
+                    .partial_derivs(t1, c)
+                    .into_iter()
+                    .map(|d| self.cat(d, t2))
+                    .collect();
+                if self.nullable[t1] {
+                    result.extend(self.partial_derivs(t2, c));
+                }
+                result
+            }
+            Node::Alt(ts) => {
+                ts.iter().flat_map(|&t1| self.partial_derivs(t1, c)).collect()
+            }
+            Node::Star(t1) => self
+                .partial_derivs(t1, c)
+                .into_iter()
+                .map(|d| self.cat(d, t))
+                .collect(),
+            Node::And(_) | Node::Not(_) => {
+                unreachable!("partial derivatives of an extended regex")
+            }
+        }
+    }
+
+    // The satisfiable combinations of the guards, as classes, with the
+    // guard defining each one
+    fn minterms(&mut self) -> Vec<(usize, Guard<'a, D>)> {
+        let mut minterms = vec![(Vec::new(), Guard::True)];
+        for g in &self.guards {
+            let mut next = Vec::new();
+            for (bits, m) in minterms {
+                for holds in [true, false] {
+                    let g = if holds { g.clone() } else { !g.clone() };
+                    let m = m.clone() & g;
+                    if m.is_satisfiable() {
+                        let mut bits: Vec<bool> = bits.clone();
+                        bits.push(holds);
+                        next.push((bits, m));
+                    }
+                }
+            }
+            minterms = next;
+        }
+        minterms.into_iter().map(|(bits, m)| (self.class(bits), m)).collect()
+    }
+
+    /* Machines */
+
+    pub fn lazy<I>(self) -> LazyDfa<'a, D, I> {
+        LazyDfa {
+            state: self.start,
+            value: Ext::None,
+            visited: HashSet::new(),
+            d: self,
+        }
+    }
+
+    // Build a machine with the given states (terms), where successors
+    // gives the targets of each state on each minterm
+    fn build_machine<Q, F>(
+        &mut self,
+        starts: Vec<Term>,
+        successors: F,
+    ) -> DataTransducer<'a, D, Q>
+    where
+        Q: Clone,
+        F: Fn(&mut Self, Term, usize) -> Vec<Term>,
+    {
+        let minterms = self.minterms();
+        let mut m = DataTransducer::new();
+        let mut ids: HashMap<Term, usize> = HashMap::new();
+        let mut queue = VecDeque::new();
+        let mut state_of =
+            |m: &mut DataTransducer<'a, D, Q>,
+             t: Term,
+             queue: &mut VecDeque<Term>| {
+                *ids.entry(t).or_insert_with(|| {
+                    queue.push_back(t);
+                    m.add_state()
+                })
+            };
+        for t in starts {
+            let s = state_of(&mut m, t, &mut queue);
+            m.add_epsilon_iden(0, s);
+        }
+        while let Some(t) = queue.pop_front() {
+            let s = state_of(&mut m, t, &mut queue);
+            if self.nullable[t] {
+                m.add_epsilon_iden(s, 1);
+            }
+            // The guard of the transitions to each target, as the union of
+            // the minterms leading to it
+            let mut guards: Vec<(Term, Guard<'a, D>)> = Vec::new();
+            for (c, g) in &minterms {
+                for d in successors(self, t, *c) {
+                    match guards.iter_mut().find(|(t1, _)| *t1 == d) {
+                        Some((_, guard)) => *guard = guard.clone() | g.clone(),
+                        None => guards.push((d, g.clone())),
+                    }
+                }
+            }
+            for (d, guard) in guards {
+                let target = state_of(&mut m, d, &mut queue);
+                let guard = if guard.is_valid() { Guard::True } else { guard };
+                m.add_sym_iden(s, target, guard);
+            }
+        }
+        m
+    }
+    // The full DFA; its states are the derivatives of the regex which are
+    // not empty (on items for which there is no transition, it stops)
+    pub fn to_dfa<Q: Clone>(&mut self) -> DataTransducer<'a, D, Q> {
+        let start = self.start;
+        let starts = if start == EMPTY { vec![] } else { vec![start] };
+        self.build_machine(starts, |d, t, c| {
+            let next = d.deriv(t, c);
+            if next == EMPTY {
+                vec![]
+            } else {
+                vec![next]
+            }
+        })
+    }
+    // The NFA of partial derivatives (None if the regex is extended)
+    pub fn to_nfa<Q: Clone>(&mut self) -> Option<DataTransducer<'a, D, Q>> {
+        if self.extended {
+            return None;
+        }
+        let start = self.start;
+        let starts = if start == EMPTY { vec![] } else { vec![start] };
+        Some(self.build_machine(starts, |d, t, c| {
+            d.partial_derivs(t, c).into_iter().collect()
+        }))
+    }
+}
+
+/*
+    DFA built on the fly
+*/
+
+pub struct LazyDfa<'a, D, I> {
+    d: Derivatives<'a, D>,
+    state: Term,
+    // The initial value, while the state is not empty
+    value: Ext<I>,
+    visited: HashSet<Term>,
+}
+
+impl<D, I> LazyDfa<'_, D, I> {
+    // Number of distinct DFA states reached so far
+    pub fn n_visited(&self) -> usize {
+        self.visited.len()
+    }
+    // Whether no continuation of the items so far can match (in
+    // particular, before .init())
+    pub fn is_dead(&self) -> bool {
+        self.value.is_none()
+    }
+}
+
+impl<D, I: Clone> Transducer<I, D, I> for LazyDfa<'_, D, I> {
+    fn init(&mut self, i: Ext<I>) -> Ext<I> {
+        if i.is_none() {
+            return Ext::None;
+        }
+        self.state = self.d.start;
+        self.value = i;
+        self.visited.insert(self.state);
+        if self.d.nullable[self.state] {
+            self.value.clone()
+        } else {
+            Ext::None
+        }
+    }
+    fn update(&mut self, item: &D) -> Ext<I> {
+        if self.value.is_none() {
+            return Ext::None;
+        }
+        let c = self.d.class_of(item);
+        self.state = self.d.deriv(self.state, c);
+        self.visited.insert(self.state);
+        if self.state == EMPTY {
+            self.value = Ext::None;
+        }
+        if self.d.nullable[self.state] {
+            self.value.clone()
+        } else {
+            Ext::None
+        }
+    }
+    fn reset(&mut self) {
+        self.value = Ext::None;
+    }
+
+    fn is_epsilon(&self) -> bool {
+        false
+    }
+    fn is_restartable(&self) -> bool {
+        false
+    }
+    fn is_nullable(&self) -> bool {
+        self.d.nullable[self.d.start]
+    }
+    fn n_states(&self) -> usize {
+        self.d.n_terms()
+    }
+    fn n_transs(&self) -> usize {
+        self.d.derivs.len()
+    }
+}
+
+/*
+    Unit Tests
+*/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::guard::field;
+
+    // A field rather than predicates, so that the guards are known to be
+    // exclusive (see guard.rs)
+    fn is(c: char) -> Regex<'static, char> {
+        sym(field("char", |&d: &char| d as i64).equals(c as i64))
+    }
+    fn any() -> Regex<'static, char> {
+        sym(Guard::True)
+    }
+    // Reference semantics, by trying all ways to split the word
+    fn matches(r: &Regex<char>, w: &[char]) -> bool {
+        match r {
+            Regex::Empty => false,
+            Regex::Eps => w.is_empty(),
+            Regex::Sym(g) => w.len() == 1 && g.eval(&w[0]),
+            Regex::Cat(r1, r2) => (0..=w.len())
+                .any(|i| matches(r1, &w[..i]) && matches(r2, &w[i..])),
+            Regex::Alt(r1, r2) => matches(r1, w) || matches(r2, w),
+            Regex::Star(r1) => {
+                w.is_empty()
+                    || (1..=w.len())
+                        .any(|i| matches(r1, &w[..i]) && matches(r, &w[i..]))
+            }
+            Regex::And(r1, r2) => matches(r1, w) && matches(r2, w),
+            Regex::Not(r1) => !matches(r1, w),
+        }
+    }
+    fn words(n: usize) -> Vec<Vec<char>> {
+        let mut result = vec![vec![]];
+        let mut last = vec![vec![]];
+        for _ in 0..n {
+            last = last
+                .iter()
+                .flat_map(|w: &Vec<char>| {
+                    "abc".chars().map(move |c| {
+                        let mut w = w.clone();
+                        w.push(c);
+                        w
+                    })
+                })
+                .collect();
+            result.extend(last.iter().cloned());
+        }
+        result
+    }
+    // Check a machine against the reference on all words up to length 5,
+    // at each prefix
+    fn check<M: Transducer<(), char, ()>>(r: &Regex<char>, m: &mut M) {
+        for w in words(5) {
+            m.reset();
+            let mut out = m.init_one(());
+            for (i, c) in w.iter().enumerate() {
+                assert_eq!(out.is_one(), matches(r, &w[..i]), "{:?}", &w[..i]);
+                out = m.update(c);
+            }
+            assert_eq!(out.is_one(), matches(r, &w), "{:?}", w);
+        }
+    }
+    fn regexes() -> Vec<Regex<'static, char>> {
+        vec![
+            seq(vec![star(is('a') | is('b')), is('a'), is('b'), is('b')]),
+            plus(cat(is('a'), opt(is('c')))),
+            cat(star(any()), cat(is('c'), any())),
+            star(is('a') | cat(is('b'), is('c'))) | Regex::Eps,
+            Regex::Empty,
+            Regex::Eps,
+        ]
+    }
+
+    #[test]
+    fn test_machines() {
+        for r in regexes() {
+            let mut d = Derivatives::new(&r);
+            check(&r, &mut d.to_dfa());
+            check(&r, &mut d.to_nfa().unwrap());
+            check(&r, &mut Derivatives::new(&r).lazy());
+        }
+    }
+
+    #[test]
+    fn test_extended() {
+        // Words with an a, but not ending in b, and not containing cc
+        let contains = |r| seq(vec![star(any()), r, star(any())]);
+        let r = contains(is('a'))
+            & !cat(star(any()), is('b'))
+            & !contains(cat(is('c'), is('c')));
+        let mut d = Derivatives::new(&r);
+        assert!(d.is_extended());
+        assert!(d.to_nfa::<()>().is_none());
+        check(&r, &mut d.to_dfa());
+        check(&r, &mut Derivatives::new(&r).lazy());
+    }
+
+    #[test]
+    fn test_sizes() {
+        // (a|b)*abb: the classic 4-state DFA (plus the initial and final
+        // states of the DataTransducer)
+        let r = regexes().remove(0);
+        let mut d = Derivatives::new(&r);
+        assert_eq!(d.n_guards(), 2);
+        let dfa: DataTransducer<char, ()> = d.to_dfa();
+        assert_eq!(dfa.n_states(), 4 + 2);
+        // The NFA has a state per position
+        let nfa: DataTransducer<char, ()> = d.to_nfa().unwrap();
+        assert_eq!(nfa.n_states(), 4 + 2);
+        // (any)* c (any) (any): the DFA needs 8 states, built lazily only
+        // as far as the stream goes
+        let r = seq(vec![star(any()), is('c'), any(), any()]);
+        let mut lazy = Derivatives::new(&r).lazy();
+        lazy.init_one(());
+        for c in "aaaa".chars() {
+            lazy.update(&c);
+        }
+        assert_eq!(lazy.n_visited(), 1);
+        lazy.update(&'c');
+        assert_eq!(lazy.n_visited(), 2);
+        let dfa: DataTransducer<char, ()> = Derivatives::new(&r).to_dfa();
+        assert_eq!(dfa.n_states(), 8 + 2);
+        let nfa: DataTransducer<char, ()> =
+            Derivatives::new(&r).to_nfa().unwrap();
+        assert_eq!(nfa.n_states(), 4 + 2);
+    }
+
+    #[test]
+    fn test_values() {
+        // The initial value is output on matching prefixes
+        let mut m = Derivatives::new(&plus(is('a'))).lazy();
+        assert_eq!(m.init_one(3), Ext::None);
+        assert_eq!(m.update(&'a'), Ext::One(3));
+        assert_eq!(m.update(&'b'), Ext::None);
+        assert_eq!(m.update(&'a'), Ext::None);
+        assert_eq!(m.init_one(4), Ext::None);
+        assert_eq!(m.update(&'a'), Ext::One(4));
+        // Ambiguous matches in the NFA
+        let r = cat(star(is('a')), star(is('a')));
+        let mut nfa = Derivatives::new(&r).to_nfa().unwrap();
+        nfa.init_one(1);
+        assert!(nfa.update(&'a').is_many());
+        let mut dfa = Derivatives::new(&r).to_dfa();
+        dfa.init_one(1);
+        assert_eq!(dfa.update(&'a'), Ext::One(1));
+    }
+}
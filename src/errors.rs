@@ -0,0 +1,265 @@
+/*
+    Errors of the crate.
+
+    Each subsystem reports its own error type, with the details of what
+    went wrong (e.g. the line of a text-format machine, or the byte offset
+    in a query). These are grouped by what the caller was doing:
+    - BuildError: building a transducer, with DataTransducerBuilder or the
+      try_ constructors of qre.rs;
+    - ParseError: reading a transducer, pattern, formula or query from text;
+    - EvalError: running transducers on streams, with the IO sources and
+      sinks, pipelines, and the overflow-checked aggregates;
+    - CheckpointError: saving and restoring the state of a computation
+      (see checkpoint.rs), or re-validating a stored restartability
+      certificate (see certificate.rs).
+    Error is any of these (or a failure to export or generate code for a
+    machine, or to import a regex). Each subsystem error converts into its
+    group and into Error, so that ? works across subsystems in code which
+    embeds the crate.
+*/
+
+use super::cep::CepError;
+use super::certificate::CertError;
+use super::codegen::CodegenError;
+use super::io::{CsvError, SinkError};
+use super::lexer::LexError;
+use super::overflow::OverflowError;
+use super::pipeline::PipelineError;
+use super::qre::ConstructionError;
+use super::qre_text::Diagnostic;
+use super::query::QueryError;
+use super::state_machine::{TransRef, MAX_ARITY};
+use super::temporal::FormulaError;
+use super::text_format::TextError;
+use std::io;
+use thiserror::Error;
+
+/*
+    Building
+*/
+
+// The methods on DataTransducer panic with the machine errors;
+// DataTransducerBuilder returns them instead.
+#[derive(Clone, Debug, Eq, Error, PartialEq)]
+pub enum BuildError {
+    // A transition refers to a state that has not been added
+    #[error(
+        "transition refers to state {id}, but there are only {n_states} states"
+    )]
+    NoSuchState { id: usize, n_states: usize },
+    // The number of states can't be decreased
+    #[error("can't set number of states to {requested}: already have {current} states")]
+    ShrinkStates { current: usize, requested: usize },
+    // A transition to remove does not exist
+    #[error("no transition {trans:?}: there are only {n_transs} of this kind")]
+    NoSuchTransition { trans: TransRef, n_transs: usize },
+    // The initial and final states (0, 1, and any others set with
+    // .set_final_states()) and states with subscriptions can't be removed
+    #[error("can't remove state {id}: it is initial, final, or subscribed to")]
+    RemoveReserved { id: usize },
+    // No state has the given label
+    #[error("no state is named {name:?}")]
+    NoSuchName { name: String },
+    // The label is already used for another state
+    #[error("a state is already named {name:?}")]
+    DuplicateName { name: String },
+    // Restoring a snapshot with the wrong number of states
+    #[error("expected values for {expected} states, but found {found}")]
+    StateCount { expected: usize, found: usize },
+    // Epsilon elimination is impossible due to a cycle of epsilon
+    // transitions through the given state
+    #[error("can't eliminate epsilons: state {state} is on an epsilon cycle")]
+    EpsilonCycle { state: usize },
+    // A transition was given the wrong number of source states
+    // (or takes more than are supported)
+    #[error(
+        "transition of arity {arity} given {n_sources} source states (at most {})",
+        MAX_ARITY
+    )]
+    Arity { arity: usize, n_sources: usize },
+    // Output actions can only be attached to update transitions
+    #[error("can't add an output to {trans:?}: not an update transition")]
+    EpsilonOutput { trans: TransRef },
+    // Priorities can only be given to update transitions
+    #[error("can't set the priority of {trans:?}: not an update transition")]
+    EpsilonPriority { trans: TransRef },
+    // Epsilon transitions can't have guards
+    #[error("can't add a guard to {trans:?}: not an update transition")]
+    EpsilonStateGuard { trans: TransRef },
+    // Epsilon elimination is not possible with ConflictPolicy::HighestPriority
+    // (composed transitions would compete with those at a different state)
+    #[error("can't eliminate epsilons when resolving conflicts by priority")]
+    PriorityEpsilons,
+    // A timer must count at least one item
+    #[error("a timer must count at least one item")]
+    ZeroTimer,
+    // A QRE operand doesn't meet the requirements of its construct
+    #[error(transparent)]
+    Construction(#[from] ConstructionError),
+}
+
+/*
+    Parsing
+*/
+
+#[derive(Clone, Debug, Error, PartialEq)]
+pub enum ParseError {
+    #[error(transparent)]
+    Text(#[from] TextError),
+    #[cfg(feature = "json")]
+    #[error(transparent)]
+    Json(#[from] super::json_format::ImportError),
+    #[error(transparent)]
+    Qre(#[from] Diagnostic),
+    #[error(transparent)]
+    Query(#[from] QueryError),
+    #[error(transparent)]
+    Pattern(#[from] CepError),
+    #[error(transparent)]
+    Formula(#[from] FormulaError),
+    #[error(transparent)]
+    Lex(#[from] LexError),
+}
+
+/*
+    Evaluation
+*/
+
+#[derive(Debug, Error)]
+pub enum EvalError {
+    #[error(transparent)]
+    Csv(#[from] CsvError),
+    #[cfg(feature = "json")]
+    #[error(transparent)]
+    Json(#[from] super::io::JsonError),
+    #[cfg(feature = "protobuf")]
+    #[error(transparent)]
+    Protobuf(#[from] super::io::ProtobufError),
+    #[error(transparent)]
+    Sink(#[from] SinkError),
+    #[error(transparent)]
+    Pipeline(#[from] PipelineError),
+    #[cfg(feature = "async")]
+    #[error(transparent)]
+    Driver(#[from] super::async_driver::DriverError),
+    #[error(transparent)]
+    Overflow(#[from] OverflowError),
+}
+
+/*
+    Checkpointing
+*/
+
+#[derive(Debug, Error)]
+pub enum CheckpointError {
+    // The store, source, or output callback failed
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+    // A checkpoint couldn't be encoded or decoded
+    #[cfg(feature = "json")]
+    #[error("invalid checkpoint: {0}")]
+    Format(#[from] serde_json::Error),
+    // The transducer rejected the saved state
+    #[error("couldn't restore state: {0}")]
+    Restore(Box<dyn std::error::Error>),
+    // A stored certificate doesn't hold (anymore)
+    #[error("invalid certificate: {0}")]
+    Certificate(#[from] CertError),
+}
+
+/*
+    All errors
+*/
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error(transparent)]
+    Build(#[from] BuildError),
+    #[error(transparent)]
+    Parse(#[from] ParseError),
+    #[error(transparent)]
+    Eval(#[from] EvalError),
+    #[error(transparent)]
+    Checkpoint(#[from] CheckpointError),
+    #[cfg(feature = "json")]
+    #[error(transparent)]
+    Export(#[from] super::json_format::ExportError),
+    #[error(transparent)]
+    Codegen(#[from] CodegenError),
+    #[cfg(feature = "regex")]
+    #[error(transparent)]
+    Regex(#[from] super::regex_import::RegexError),
+}
+
+// Subsystem errors convert into Error through their group
+macro_rules! into_error {
+    ($group:ident: $($(#[$attr:meta])* $t:ty),* $(,)?) => {$(
+        $(#[$attr])*
+        impl From<$t> for Error {
+            fn from(err: $t) -> Self {
+                Error::$group(err.into())
+            }
+        }
+    )*};
+}
+into_error!(Build: ConstructionError);
+into_error!(
+    Parse: TextError,
+    #[cfg(feature = "json")]
+    super::json_format::ImportError,
+    Diagnostic,
+    QueryError,
+    CepError,
+    FormulaError,
+    LexError,
+);
+into_error!(
+    Eval: CsvError,
+    #[cfg(feature = "json")]
+    super::io::JsonError,
+    #[cfg(feature = "protobuf")]
+    super::io::ProtobufError,
+    SinkError,
+    PipelineError,
+    #[cfg(feature = "async")]
+    super::async_driver::DriverError,
+    OverflowError,
+);
+into_error!(Checkpoint: CertError);
+
+/*
+    Unit Tests
+*/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::qre::{aggregate, atom, concat, epsilon, try_iterate};
+    use crate::query::parse_query;
+
+    #[test]
+    fn test_groups() {
+        let err: Error = BuildError::ZeroTimer.into();
+        assert!(matches!(err, Error::Build(BuildError::ZeroTimer)));
+        assert_eq!(err.to_string(), "a timer must count at least one item");
+
+        let sum = aggregate(atom(|_: &char| true, |x: i32, _| x), |z, y| z + y);
+        let m = concat(epsilon(|i: i32| (i, 0)), sum);
+        let err: Error = try_iterate(m).err().unwrap().into();
+        assert!(matches!(err, Error::Build(BuildError::Construction(_))));
+        assert_eq!(err.to_string(), "iterate: operand 1 must be restartable");
+
+        let query = || -> Result<(), Error> {
+            parse_query::<(i64,)>("SELECT median(x)")?;
+            Ok(())
+        };
+        let err = query().unwrap_err();
+        assert!(matches!(err, Error::Parse(ParseError::Query(_))));
+        assert_eq!(err.to_string(), "at offset 7: expected an aggregate");
+
+        let err: Error = OverflowError.into();
+        assert!(matches!(err, Error::Eval(EvalError::Overflow(_))));
+        let err: Error = CheckpointError::from(io::Error::other("gone")).into();
+        assert_eq!(err.to_string(), "I/O error: gone");
+    }
+}
@@ -4,14 +4,21 @@
 
     Ext<T> can be thought variant of Option<T>, where Many
     represents a multiset of two or more values.
+
+    Iterators of Ext<T> can be flattened to iterators of T with the
+    ExtIterator adapters (see below).
 */
 
 use derive_more::{Display, From};
+use std::error::Error;
+use std::fmt;
 use std::iter::FromIterator;
 use std::ops;
 
-#[derive(Clone, Copy, Debug, Display, Eq, From, PartialEq)]
+#[derive(Clone, Copy, Debug, Default, Display, Eq, From, PartialEq)]
+#[cfg_attr(feature = "json", derive(serde::Serialize, serde::Deserialize))]
 pub enum Ext<T> {
+    #[default]
     None,
     One(T),
     Many,
@@ -77,19 +84,13 @@ impl<T> Ext<T> {
     pub fn as_ref(&self) -> Ext<&T> {
         match self {
             Ext::None => Ext::None,
-            Ext::One(x) => Ext::One(&x),
+            Ext::One(x) => Ext::One(x),
             Ext::Many => Ext::Many,
         }
     }
 }
 
-/* Default value and from/to relationships */
-
-impl<T> Default for Ext<T> {
-    fn default() -> Self {
-        Ext::None
-    }
-}
+/* From/to relationships */
 
 impl<T> From<Option<T>> for Ext<T> {
     fn from(opt_t: Option<T>) -> Self {
@@ -231,12 +232,104 @@ where
     apply1(|(((x, y), z), t)| op(x, y, z, t), v1 * v2 * v3 * v4)
 }
 
+/* Flattening a stream of outputs */
+
+// Streams of outputs (e.g. from .process_stream()) can be flattened to the
+// plain values: Ext::None is dropped, Ext::One(x) is x, and Ext::Many
+// (which has no values to yield) is handled by a ManyPolicy.
+// For example:
+//     let outs = m.process_stream(0, items);
+//     let sums: Vec<i32> = outs.flatten_outputs().collect();
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ManyPolicy {
+    // Drop it, like None
+    Skip,
+    // End the stream
+    Stop,
+    // Panic, reporting the step (numbered from 1)
+    Panic,
+}
+
+// A Many output, at the given step (numbered from 1)
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ManyError {
+    pub step: usize,
+}
+impl fmt::Display for ManyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "more than one output at step {}", self.step)
+    }
+}
+impl Error for ManyError {}
+
+pub trait ExtIterator<T>: Iterator<Item = Ext<T>> + Sized {
+    // Flatten the outputs, panicking on Many
+    fn flatten_outputs(self) -> FlattenOutputs<Self> {
+        self.flatten_outputs_with(ManyPolicy::Panic)
+    }
+    fn flatten_outputs_with(self, many: ManyPolicy) -> FlattenOutputs<Self> {
+        FlattenOutputs { iter: self, many, step: 0, done: false }
+    }
+    // Flatten the outputs, yielding Err on Many
+    fn try_flatten_outputs(self) -> TryFlattenOutputs<Self> {
+        TryFlattenOutputs { iter: self, step: 0 }
+    }
+}
+impl<T, It: Iterator<Item = Ext<T>>> ExtIterator<T> for It {}
+
+#[derive(Clone, Debug)]
+pub struct FlattenOutputs<It> {
+    iter: It,
+    many: ManyPolicy,
+    step: usize,
+    done: bool,
+}
+impl<T, It: Iterator<Item = Ext<T>>> Iterator for FlattenOutputs<It> {
+    type Item = T;
+    fn next(&mut self) -> Option<T> {
+        while !self.done {
+            self.step += 1;
+            match self.iter.next()? {
+                Ext::None => (),
+                Ext::One(x) => return Some(x),
+                Ext::Many => match self.many {
+                    ManyPolicy::Skip => (),
+                    ManyPolicy::Stop => self.done = true,
+                    ManyPolicy::Panic => {
+                        panic!("{}", ManyError { step: self.step })
+                    }
+                },
+            }
+        }
+        None
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct TryFlattenOutputs<It> {
+    iter: It,
+    step: usize,
+}
+impl<T, It: Iterator<Item = Ext<T>>> Iterator for TryFlattenOutputs<It> {
+    type Item = Result<T, ManyError>;
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            self.step += 1;
+            match self.iter.next()? {
+                Ext::None => (),
+                Ext::One(x) => return Some(Ok(x)),
+                Ext::Many => return Some(Err(ManyError { step: self.step })),
+            }
+        }
+    }
+}
+
 /* ========== TESTS ========== */
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::i32;
 
     #[test]
     fn test_union() {
@@ -360,4 +453,45 @@ mod tests {
         assert_eq!(apply4(vec_4, x1, x0, x3, x1), Ext::None);
         assert_eq!(apply4(vec_4, x1, x3, x1, x1), Ext::Many);
     }
+
+    #[test]
+    fn test_flatten_outputs() {
+        use crate::interface::Transducer;
+        use crate::qre::{atom, iterate, union};
+
+        let outs = vec![Ext::One(1), Ext::None, Ext::Many, Ext::One(4)];
+        let flat: Vec<i32> = outs
+            .iter()
+            .copied()
+            .flatten_outputs_with(ManyPolicy::Skip)
+            .collect();
+        assert_eq!(flat, [1, 4]);
+        let flat: Vec<i32> = outs
+            .iter()
+            .copied()
+            .flatten_outputs_with(ManyPolicy::Stop)
+            .collect();
+        assert_eq!(flat, [1]);
+        let flat: Vec<Result<i32, ManyError>> =
+            outs.into_iter().try_flatten_outputs().collect();
+        assert_eq!(flat, [Ok(1), Err(ManyError { step: 3 }), Ok(4)]);
+
+        // Sum of the even numbers, output after each one
+        let mut m = iterate(atom(|&x: &i32| x % 2 == 0, |s, &x| s + x));
+        let flat: Vec<i32> = m
+            .process_stream(0, vec![2, 4, 6].into_iter())
+            .flatten_outputs()
+            .collect();
+        assert_eq!(flat, [0, 2, 6, 12]);
+        let mut m = union(
+            iterate(atom(|_: &i32| true, |s, _| s)),
+            iterate(atom(|&x: &i32| x > 0, |s, _| s)),
+        );
+        let result = std::panic::catch_unwind(move || {
+            m.process_stream(0, vec![-1, 1].into_iter())
+                .flatten_outputs()
+                .count()
+        });
+        assert!(result.is_err());
+    }
 }
@@ -0,0 +1,18 @@
+/*
+    Transducers shared by the unit tests of several modules.
+*/
+
+use super::interface::Transducer;
+use super::qre::{atom, iterate};
+
+// Running sum of the items
+pub fn sum() -> impl Transducer<i64, i64, i64> + Clone + Send {
+    sum_by(|&x| x)
+}
+
+// Running sum of a part of each item, e.g. the value of a (key, value) pair
+pub fn sum_by<D: Send>(
+    part: fn(&D) -> i64,
+) -> impl Transducer<i64, D, i64> + Clone + Send {
+    iterate(atom(|_| true, move |s, d: &D| s + part(d)))
+}
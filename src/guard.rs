@@ -0,0 +1,366 @@
+/*
+    Symbolic guards: boolean combinations of named primitive predicates and
+    of interval constraints on named integer fields of the item.
+
+    Unlike closures, these can be inspected: in particular, we can check
+    whether a guard is satisfiable, or whether two guards overlap (can both
+    hold on the same item). They can be used as the guards of update
+    transitions (see DataTransducer::add_sym_transition1() etc.).
+
+    Primitive predicates and fields are identified by name: two predicates
+    (or fields) with the same name are assumed to be the same function.
+    Different predicates are assumed to be independent, i.e. any
+    combination of them may hold on some item. So if predicates are
+    mutually exclusive (e.g. is_a and is_b), the satisfiability checks are
+    conservative: they may report overlaps which can't happen. Exclusive
+    cases are better expressed as ranges of a field (e.g. a tag field).
+
+    Example:
+        let is_a = pred("is_a", |d: &(char, i64)| d.0 == 'a');
+        let value = field("value", |d: &(char, i64)| d.1);
+        let g = is_a & value.at_least(0);
+*/
+
+use std::collections::HashMap;
+use std::fmt::{self, Debug};
+use std::ops;
+use std::rc::Rc;
+
+/*
+    Primitive predicates and fields
+*/
+
+pub struct Predicate<'a, D> {
+    name: String,
+    f: Rc<dyn Fn(&D) -> bool + 'a>,
+}
+impl<D> Clone for Predicate<'_, D> {
+    fn clone(&self) -> Self {
+        Self { name: self.name.clone(), f: self.f.clone() }
+    }
+}
+
+impl<D> Predicate<'_, D> {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+pub struct Field<'a, D> {
+    name: String,
+    f: Rc<dyn Fn(&D) -> i64 + 'a>,
+}
+impl<D> Clone for Field<'_, D> {
+    fn clone(&self) -> Self {
+        Self { name: self.name.clone(), f: self.f.clone() }
+    }
+}
+
+pub fn pred<'a, D, F>(name: &str, f: F) -> Guard<'a, D>
+where
+    F: 'a + Fn(&D) -> bool,
+{
+    Guard::Pred(Predicate { name: name.to_string(), f: Rc::new(f) })
+}
+
+pub fn field<'a, D, F>(name: &str, f: F) -> Field<'a, D>
+where
+    F: 'a + Fn(&D) -> i64,
+{
+    Field { name: name.to_string(), f: Rc::new(f) }
+}
+
+impl<'a, D> Field<'a, D> {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+    // The field is between lo and hi (inclusive)
+    pub fn in_range(&self, lo: i64, hi: i64) -> Guard<'a, D> {
+        Guard::Range(self.clone(), lo, hi)
+    }
+    pub fn equals(&self, v: i64) -> Guard<'a, D> {
+        self.in_range(v, v)
+    }
+    pub fn at_least(&self, lo: i64) -> Guard<'a, D> {
+        self.in_range(lo, i64::MAX)
+    }
+    pub fn at_most(&self, hi: i64) -> Guard<'a, D> {
+        self.in_range(i64::MIN, hi)
+    }
+}
+
+/*
+    Guards
+*/
+
+pub enum Guard<'a, D> {
+    True,
+    False,
+    Pred(Predicate<'a, D>),
+    Range(Field<'a, D>, i64, i64),
+    Not(Box<Guard<'a, D>>),
+    And(Box<Guard<'a, D>>, Box<Guard<'a, D>>),
+    Or(Box<Guard<'a, D>>, Box<Guard<'a, D>>),
+}
+
+impl<D> Clone for Guard<'_, D> {
+    fn clone(&self) -> Self {
+        match self {
+            Guard::True => Guard::True,
+            Guard::False => Guard::False,
+            Guard::Pred(p) => Guard::Pred(p.clone()),
+            Guard::Range(x, lo, hi) => Guard::Range(x.clone(), *lo, *hi),
+            Guard::Not(g) => Guard::Not(g.clone()),
+            Guard::And(g1, g2) => Guard::And(g1.clone(), g2.clone()),
+            Guard::Or(g1, g2) => Guard::Or(g1.clone(), g2.clone()),
+        }
+    }
+}
+
+impl<'a, D> Guard<'a, D> {
+    pub fn eval(&self, item: &D) -> bool {
+        match self {
+            Guard::True => true,
+            Guard::False => false,
+            Guard::Pred(p) => (p.f)(item),
+            Guard::Range(x, lo, hi) => (lo..=hi).contains(&&(x.f)(item)),
+            Guard::Not(g) => !g.eval(item),
+            Guard::And(g1, g2) => g1.eval(item) && g2.eval(item),
+            Guard::Or(g1, g2) => g1.eval(item) || g2.eval(item),
+        }
+    }
+    // The guard as a closure (e.g. for DataTransducer::add_transition1())
+    pub fn into_fn(self) -> impl Fn(&D) -> bool + 'a
+    where
+        D: 'a,
+    {
+        move |item| self.eval(item)
+    }
+
+    /* Satisfiability checks (see above for the assumptions made) */
+    pub fn is_satisfiable(&self) -> bool {
+        self.dnf(true).iter().any(|conj| conj.is_satisfiable())
+    }
+    // Whether the guard holds on every item
+    pub fn is_valid(&self) -> bool {
+        !self.dnf(false).iter().any(|conj| conj.is_satisfiable())
+    }
+    // Whether both guards can hold on the same item
+    pub fn overlaps(&self, other: &Self) -> bool {
+        (self.clone() & other.clone()).is_satisfiable()
+    }
+    // Whether other holds on every item on which self holds
+    pub fn implies(&self, other: &Self) -> bool {
+        !(self.clone() & !other.clone()).is_satisfiable()
+    }
+
+    // Disjunctive normal form of the guard (if positive) or its negation.
+    // Note: this may be exponentially larger than the guard.
+    fn dnf(&self, positive: bool) -> Vec<Conj> {
+        match (self, positive) {
+            (Guard::True, true) | (Guard::False, false) => {
+                vec![Conj::default()]
+            }
+            (Guard::True, false) | (Guard::False, true) => vec![],
+            (Guard::Pred(p), _) => {
+                let mut conj = Conj::default();
+                conj.preds.push((p.name.clone(), positive));
+                vec![conj]
+            }
+            (Guard::Range(x, lo, hi), _) => {
+                let mut conj = Conj::default();
+                conj.ranges.push((x.name.clone(), *lo, *hi, positive));
+                vec![conj]
+            }
+            (Guard::Not(g), _) => g.dnf(!positive),
+            (Guard::And(g1, g2), true) | (Guard::Or(g1, g2), false) => {
+                let (d1, d2) = (g1.dnf(positive), g2.dnf(positive));
+                let mut result = Vec::with_capacity(d1.len() * d2.len());
+                for c1 in &d1 {
+                    for c2 in &d2 {
+                        result.push(c1.and(c2));
+                    }
+                }
+                result
+            }
+            (Guard::Or(g1, g2), true) | (Guard::And(g1, g2), false) => {
+                let mut result = g1.dnf(positive);
+                result.extend(g2.dnf(positive));
+                result
+            }
+        }
+    }
+}
+
+// Inclusive bounds
+type Interval = (i64, i64);
+
+// A conjunction of (possibly negated) predicates and ranges
+#[derive(Clone, Debug, Default)]
+struct Conj {
+    preds: Vec<(String, bool)>,
+    ranges: Vec<(String, i64, i64, bool)>,
+}
+impl Conj {
+    fn and(&self, other: &Conj) -> Conj {
+        let mut result = self.clone();
+        result.preds.extend(other.preds.iter().cloned());
+        result.ranges.extend(other.ranges.iter().cloned());
+        result
+    }
+    fn is_satisfiable(&self) -> bool {
+        let mut preds: HashMap<&str, bool> = HashMap::new();
+        for (name, pos) in &self.preds {
+            if *preds.entry(name).or_insert(*pos) != *pos {
+                return false;
+            }
+        }
+        // For each field: the intersection of the positive ranges, and the
+        // negative ranges, which must not cover it
+        let mut fields: HashMap<&str, (Interval, Vec<Interval>)> =
+            HashMap::new();
+        for (name, lo, hi, pos) in &self.ranges {
+            let (bounds, holes) =
+                fields.entry(name).or_insert(((i64::MIN, i64::MAX), vec![]));
+            if *pos {
+                *bounds = (bounds.0.max(*lo), bounds.1.min(*hi));
+            } else if lo <= hi {
+                holes.push((*lo, *hi));
+            }
+        }
+        fields.into_iter().all(|(_, ((lo, hi), mut holes))| {
+            if lo > hi {
+                return false;
+            }
+            holes.sort_unstable();
+            // Sweep from lo, skipping over the holes
+            let mut cur = lo;
+            for (hole_lo, hole_hi) in holes {
+                if hole_lo > cur {
+                    break;
+                }
+                if hole_hi >= hi {
+                    return false;
+                }
+                cur = cur.max(hole_hi + 1);
+            }
+            cur <= hi
+        })
+    }
+}
+
+/*
+    Operators: g1 & g2, g1 | g2, !g
+*/
+
+impl<'a, D> ops::BitAnd for Guard<'a, D> {
+    type Output = Guard<'a, D>;
+    fn bitand(self, other: Self) -> Self {
+        Guard::And(Box::new(self), Box::new(other))
+    }
+}
+impl<'a, D> ops::BitOr for Guard<'a, D> {
+    type Output = Guard<'a, D>;
+    fn bitor(self, other: Self) -> Self {
+        Guard::Or(Box::new(self), Box::new(other))
+    }
+}
+impl<'a, D> ops::Not for Guard<'a, D> {
+    type Output = Guard<'a, D>;
+    fn not(self) -> Self {
+        Guard::Not(Box::new(self))
+    }
+}
+
+impl<D> fmt::Display for Guard<'_, D> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Guard::True => write!(f, "true"),
+            Guard::False => write!(f, "false"),
+            Guard::Pred(p) => write!(f, "{}", p.name),
+            Guard::Range(x, lo, hi) if lo == hi => {
+                write!(f, "{} == {}", x.name, lo)
+            }
+            Guard::Range(x, lo, i64::MAX) => write!(f, "{} >= {}", x.name, lo),
+            Guard::Range(x, i64::MIN, hi) => write!(f, "{} <= {}", x.name, hi),
+            Guard::Range(x, lo, hi) => {
+                write!(f, "{} <= {} <= {}", lo, x.name, hi)
+            }
+            Guard::Not(g) => write!(f, "!({})", g),
+            Guard::And(g1, g2) => write!(f, "({} & {})", g1, g2),
+            Guard::Or(g1, g2) => write!(f, "({} | {})", g1, g2),
+        }
+    }
+}
+impl<D> Debug for Guard<'_, D> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+/*
+    Unit Tests
+*/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type Item = (char, i64);
+
+    fn is_a<'a>() -> Guard<'a, Item> {
+        pred("is_a", |d: &Item| d.0 == 'a')
+    }
+    fn value<'a>() -> Field<'a, Item> {
+        field("value", |d: &Item| d.1)
+    }
+
+    #[test]
+    fn test_eval() {
+        let g = is_a() & value().at_least(0) | value().equals(-5);
+        assert!(g.eval(&('a', 3)));
+        assert!(!g.eval(&('a', -1)));
+        assert!(!g.eval(&('b', 3)));
+        assert!(g.eval(&('b', -5)));
+        let f = (!is_a()).into_fn();
+        assert!(f(&('b', 0)));
+        assert_eq!(
+            g.to_string(),
+            "((is_a & value >= 0) | value == -5)".to_string()
+        );
+        assert_eq!(
+            (!value().in_range(1, 2) & value().at_most(3)).to_string(),
+            "(!(1 <= value <= 2) & value <= 3)"
+        );
+    }
+
+    #[test]
+    fn test_satisfiable() {
+        let (a, x) = (is_a(), value());
+        assert!(a.is_satisfiable());
+        assert!(!(a.clone() & !a.clone()).is_satisfiable());
+        assert!((a.clone() | !a.clone()).is_valid());
+        assert!(!a.is_valid());
+        assert!(!Guard::<Item>::False.is_satisfiable());
+        assert!(Guard::<Item>::True.is_valid());
+        // Ranges
+        assert!(x.at_least(3).overlaps(&x.at_most(3)));
+        assert!(!x.at_least(4).overlaps(&x.at_most(3)));
+        assert!(x.in_range(0, 10).implies(&x.at_least(0)));
+        assert!(!x.at_least(0).implies(&x.in_range(0, 10)));
+        assert!(!x.in_range(5, 4).is_satisfiable());
+        // Holes
+        let holes = x.in_range(0, 10)
+            & !x.in_range(0, 3)
+            & !x.in_range(4, 7)
+            & !x.in_range(9, 10);
+        assert!(holes.is_satisfiable());
+        assert!(holes.implies(&x.equals(8)));
+        assert!(!(holes & !x.equals(8)).is_satisfiable());
+        assert!((x.at_most(0) | x.at_least(1)).is_valid());
+        assert!(!(x.at_most(0) | x.at_least(2)).is_valid());
+        assert!((x.at_most(i64::MAX) | a.clone()).is_valid());
+        // Predicates and fields are independent
+        assert!((a.clone() & x.equals(1)).overlaps(&(a & x.at_least(0))));
+    }
+}
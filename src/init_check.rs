@@ -0,0 +1,283 @@
+/*
+    Checking the INIT property of transducers (see interface.rs) at runtime:
+    .init(Ext::None) has no effect and returns Ext::None, and
+    .init(Ext::Many) is the union of two or more .init(Ext::One(x)).
+
+    Several of the combinators depend on it for their children (e.g. concat
+    feeds Ext::None to its second transducer on most items, which
+    Concat::is_epsilon assumes has no effect), so a user-defined transducer
+    breaking it silently breaks the QREs built on it. Two checks are
+    provided:
+
+    - init_child(m, i) calls m.init(i); with the feature "check-init", it
+      panics if .init(Ext::None) produces output. The combinators of qre.rs
+      call their children through it, so with the feature, every child of
+      a union, concat, iterate, parcomp or aggregate is checked on every
+      .init(). Without the feature it is just m.init(i).
+      (The output of .init(Ext::Many) isn't checked here: a DataTransducer
+      reports the output accumulated over the current step, so after an
+      .update() it can return the single value produced by the update.)
+
+    - init_checked(m) wraps a (cloneable) transducer to check the property
+      fully on the next item: after .init(Ext::None), it must behave as
+      if the init never happened, and after .init(Ext::Many), as if it was
+      given twice the last value it was initialized with. (A copy of m
+      runs alongside for one item, so this is for testing.)
+*/
+
+use super::ext_value::Ext;
+use super::interface::Transducer;
+use std::any;
+use std::fmt::Debug;
+use std::marker::PhantomData;
+
+// Call m.init(i), checking the output with the feature "check-init"
+#[inline]
+pub fn init_child<I, D, O, M>(m: &mut M, i: Ext<I>) -> Ext<O>
+where
+    M: Transducer<I, D, O> + ?Sized,
+{
+    if cfg!(feature = "check-init") && i.is_none() {
+        let out = m.init(i);
+        if !out.is_none() {
+            violation::<M>("init(Ext::None) produced output");
+        }
+        out
+    } else {
+        m.init(i)
+    }
+}
+
+fn violation<M: ?Sized>(msg: &str) -> ! {
+    panic!("INIT property violated by {}: {}", any::type_name::<M>(), msg)
+}
+
+/*
+    Full check on the next item
+*/
+
+pub struct InitChecked<I, D, O, M>
+where
+    M: Transducer<I, D, O>,
+{
+    m: M,
+    // The last value m was initialized with
+    last: Option<I>,
+    // Copy of m which is given the equivalent inits instead, until the
+    // next item
+    shadow: Option<M>,
+    ph_d: PhantomData<D>,
+    ph_o: PhantomData<O>,
+}
+pub fn init_checked<I, D, O, M>(m: M) -> InitChecked<I, D, O, M>
+where
+    M: Transducer<I, D, O>,
+{
+    InitChecked {
+        m,
+        last: None,
+        shadow: None,
+        ph_d: PhantomData,
+        ph_o: PhantomData,
+    }
+}
+
+impl<I, D, O, M> Clone for InitChecked<I, D, O, M>
+where
+    I: Clone,
+    M: Transducer<I, D, O> + Clone,
+{
+    fn clone(&self) -> Self {
+        InitChecked {
+            m: self.m.clone(),
+            last: self.last.clone(),
+            shadow: self.shadow.clone(),
+            ph_d: PhantomData,
+            ph_o: PhantomData,
+        }
+    }
+}
+
+impl<I, D, O, M> Transducer<I, D, O> for InitChecked<I, D, O, M>
+where
+    I: Clone,
+    O: Debug + PartialEq,
+    M: Transducer<I, D, O> + Clone,
+{
+    fn init(&mut self, i: Ext<I>) -> Ext<O> {
+        // The inits given to the shadow instead (if it can be checked)
+        let equiv = match (&i, &self.last) {
+            (Ext::None, _) => Some(vec![]),
+            (Ext::One(x), _) => Some(vec![x.clone()]),
+            (Ext::Many, Some(x)) => Some(vec![x.clone(), x.clone()]),
+            (Ext::Many, None) => None,
+        };
+        if let Ext::One(x) = &i {
+            self.last = Some(x.clone());
+        }
+        match equiv {
+            Some(xs) => {
+                if self.shadow.is_none() {
+                    self.shadow = Some(self.m.clone());
+                }
+                let shadow = self.shadow.as_mut().unwrap();
+                let expected = xs
+                    .into_iter()
+                    .fold(Ext::None, |acc, x| acc + shadow.init_one(x));
+                let out = self.m.init(i);
+                if out != expected {
+                    violation::<M>(&format!(
+                        "init gave {:?} rather than {:?}",
+                        out, expected
+                    ));
+                }
+                out
+            }
+            None => {
+                // Nothing to compare with: check the output only
+                self.shadow = None;
+                init_child(&mut self.m, Ext::Many)
+            }
+        }
+    }
+    fn update(&mut self, item: &D) -> Ext<O> {
+        let out = self.m.update(item);
+        if let Some(mut shadow) = self.shadow.take() {
+            let expected = shadow.update(item);
+            if out != expected {
+                violation::<M>(&format!(
+                    "after init, the next item gave {:?} rather than {:?}",
+                    out, expected
+                ));
+            }
+        }
+        out
+    }
+    fn reset(&mut self) {
+        self.m.reset();
+        self.shadow = None;
+    }
+
+    fn is_epsilon(&self) -> bool {
+        self.m.is_epsilon()
+    }
+    fn is_restartable(&self) -> bool {
+        self.m.is_restartable()
+    }
+    fn is_nullable(&self) -> bool {
+        self.m.is_nullable()
+    }
+    fn n_states(&self) -> usize {
+        self.m.n_states()
+    }
+    fn n_transs(&self) -> usize {
+        self.m.n_transs()
+    }
+}
+
+/*
+    Unit Tests
+*/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::qre::{atom, concat, epsilon, iterate, union};
+
+    // Counts the inits it is given, including Ext::None, and outputs the
+    // count on the next item
+    #[derive(Clone)]
+    struct CountsInits {
+        n: usize,
+    }
+    impl Transducer<usize, char, usize> for CountsInits {
+        fn init(&mut self, _: Ext<usize>) -> Ext<usize> {
+            self.n += 1;
+            Ext::None
+        }
+        fn update(&mut self, _: &char) -> Ext<usize> {
+            Ext::One(std::mem::take(&mut self.n))
+        }
+        fn reset(&mut self) {
+            self.n = 0;
+        }
+        fn is_epsilon(&self) -> bool {
+            false
+        }
+        fn is_restartable(&self) -> bool {
+            false
+        }
+        fn is_nullable(&self) -> bool {
+            false
+        }
+        fn n_states(&self) -> usize {
+            1
+        }
+        fn n_transs(&self) -> usize {
+            1
+        }
+    }
+
+    #[test]
+    fn test_qre_ok() {
+        let sum = iterate(union(
+            atom(|&c: &char| c == 'a', |s: usize, _| s + 1),
+            atom(|&c: &char| c != 'a', |s: usize, _| s),
+        ));
+        let mut m = init_checked(concat(epsilon(|x: usize| x * 10), sum));
+        assert_eq!(m.init_one(1), Ext::One(10));
+        assert_eq!(m.update(&'a'), Ext::One(11));
+        assert_eq!(m.init(Ext::None), Ext::None);
+        assert_eq!(m.update(&'a'), Ext::One(12));
+        // Restarted twice with 1: Many from then on
+        assert_eq!(m.init(Ext::Many), Ext::Many);
+        assert_eq!(m.update(&'b'), Ext::Many);
+        assert_eq!(init_child(&mut m, Ext::None), Ext::None);
+    }
+
+    #[test]
+    #[should_panic(expected = "INIT property violated")]
+    fn test_init_none_effect() {
+        let mut m = init_checked(CountsInits { n: 0 });
+        m.init_one(3);
+        m.update(&'a');
+        m.init(Ext::None);
+        m.update(&'a');
+    }
+
+    #[cfg(feature = "check-init")]
+    #[test]
+    #[should_panic(expected = "init(Ext::None) produced output")]
+    fn test_combinator_children() {
+        // A transducer which always outputs on init, as the second
+        // operand of a concat
+        struct Always;
+        impl Transducer<(), char, ()> for Always {
+            fn init(&mut self, _: Ext<()>) -> Ext<()> {
+                Ext::One(())
+            }
+            fn update(&mut self, _: &char) -> Ext<()> {
+                Ext::None
+            }
+            fn reset(&mut self) {}
+            fn is_epsilon(&self) -> bool {
+                true
+            }
+            fn is_restartable(&self) -> bool {
+                true
+            }
+            fn is_nullable(&self) -> bool {
+                true
+            }
+            fn n_states(&self) -> usize {
+                0
+            }
+            fn n_transs(&self) -> usize {
+                0
+            }
+        }
+        let mut m = concat(atom(|_: &char| true, |(), _| ()), Always);
+        m.init_one(());
+        m.update(&'a');
+    }
+}
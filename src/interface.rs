@@ -7,12 +7,17 @@
     - O: The type of output data for the transducer produced after each update
     Also:
     - RInput<I, D>: An input item which could also be a "restart event"
+    - PItem<D>: A data item which could also be "punctuation"
+    - Change<D>: A data item which could also retract an earlier one
     - Strm: an iterator over D items or RInput<I, D> items
+    - DataItem: items which may carry a key and a timestamp
 */
 
 use super::ext_value::Ext;
+use super::timed::Time;
 use std::fmt::Debug;
 use std::iter;
+use std::mem;
 
 /*
     Input to the transducer is given as an initial value,
@@ -25,12 +30,53 @@ use std::iter;
     is relevant in the context of "restartable" transducers which are more
     composable.
 */
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum RInput<I, D> {
     Restart(I),
     Item(D),
 }
 
+/*
+    Streams may also carry punctuation (heartbeats): control items which
+    carry no data, but mark the end of a window. PInput extends RInput with
+    punctuation; see qre::punctuated for the transducer which handles it.
+*/
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum PItem<D> {
+    Item(D),
+    Punct,
+}
+pub type PInput<I, D> = RInput<I, PItem<D>>;
+
+/*
+    Upstream systems may also amend items: a Change either inserts an item,
+    or retracts an item seen earlier. Only invertible computations can
+    process retractions; see retract.rs.
+*/
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Change<D> {
+    Insert(D),
+    Retract(D),
+}
+
+/*
+    Items may carry a key (e.g. a user or sensor ID) and a timestamp. The
+    operators which need them (the keyed runtime, time bounds and time
+    windows) read them through the DataItem trait, rather than each taking
+    its own extractor closures. Both are optional: an operator's docs say
+    what it does with items which lack them. timed::Timed<D> is the item
+    type with a timestamp and no key.
+*/
+pub trait DataItem {
+    type Key;
+    fn key(&self) -> Option<Self::Key> {
+        None
+    }
+    fn timestamp(&self) -> Option<Time> {
+        None
+    }
+}
+
 pub trait Transducer<I, D, O> {
     /* FUNCTIONALITY TO IMPLEMENT */
 
@@ -62,13 +108,26 @@ pub trait Transducer<I, D, O> {
     //     is_restartable_for below (should hold for all input streams)
     //     It should hold that if is_epsilon() is true then is_restartable()
     //     is true also.
+    // is_nullable: should return true if .init() may immediately produce
+    //     output, i.e. the rate of the transducer contains the empty stream.
+    //     (For the QRE constructs this is exact.)
     // n_states: # of internal values kept (of type I, O, D, or something else)
     //     (in this development, does not input/output if they are not stored)
     // n_transs: # of transforming functions kept.
+    // n_bytes: estimate of the memory footprint in bytes, including the
+    //     transducer itself and any heap storage it owns (e.g. Vec capacities),
+    //     but not heap storage owned by the values of type I, D, or O.
+    //     Unlike the above, this may vary with the values stored, but for
+    //     the constructs in this crate it is constant.
     fn is_epsilon(&self) -> bool;
     fn is_restartable(&self) -> bool;
+    fn is_nullable(&self) -> bool;
     fn n_states(&self) -> usize;
     fn n_transs(&self) -> usize;
+    fn n_bytes(&self) -> usize {
+        // Default: no heap storage
+        mem::size_of_val(self)
+    }
 
     /* DERIVED FUNCTIONALITY */
 
@@ -82,6 +141,12 @@ pub trait Transducer<I, D, O> {
         self.update(&d)
     }
 
+    // Process a batch of input items, returning the output after each one
+    // (implementations may override this with a faster version)
+    fn update_batch(&mut self, items: &[D]) -> Vec<Ext<O>> {
+        items.iter().map(|item| self.update(item)).collect()
+    }
+
     // Spawn an empty copy of the transducer: one that is in the initial
     // state and prior to any .init() updates
     // Note: this implementation is most efficient if self has not been modified;
@@ -196,4 +261,163 @@ pub trait Transducer<I, D, O> {
         let multi_out = self.process_rstream_multi(strm);
         single_out.eq(multi_out)
     }
+
+    // Bounded check of restartability: test the property on every stream of
+    // length max_len over the given alphabet (of items and restarts), and
+    // return the first counterexample found, cut off after the first
+    // differing output. (Checking streams of length exactly max_len suffices,
+    // since outputs on a prefix don't depend on later items.)
+    // Unlike restartability_holds_for, this does not print debug output.
+    // Note: there are alphabet.len()^max_len streams.
+    fn is_restartable_bounded(
+        &self,
+        alphabet: &[RInput<I, D>],
+        max_len: usize,
+    ) -> Result<(), Vec<RInput<I, D>>>
+    where
+        Self: Clone + Sized,
+        I: Clone,
+        D: Clone,
+        O: Eq,
+    {
+        if alphabet.is_empty() {
+            return Ok(());
+        }
+        // Enumerate streams as numbers in base alphabet.len()
+        let mut digits = vec![0; max_len];
+        loop {
+            let strm: Vec<RInput<I, D>> =
+                digits.iter().map(|&i| alphabet[i].clone()).collect();
+            let mut single = self.spawn_empty();
+            let mut multi: Vec<Self> = Vec::new();
+            for (n, item) in strm.iter().enumerate() {
+                let (out_single, out_multi) = match item {
+                    RInput::Restart(i) => {
+                        let mut m = self.spawn_empty();
+                        let out_multi = m.init_one(i.clone());
+                        multi.push(m);
+                        (single.init_one(i.clone()), out_multi)
+                    }
+                    RInput::Item(d) => {
+                        let mut out_multi = Ext::None;
+                        for m in multi.iter_mut() {
+                            out_multi += m.update(d);
+                        }
+                        (single.update(d), out_multi)
+                    }
+                };
+                if out_single != out_multi {
+                    return Err(strm[..=n].to_vec());
+                }
+            }
+            // Next stream
+            match digits.iter().rposition(|&i| i + 1 < alphabet.len()) {
+                Some(pos) => {
+                    digits[pos] += 1;
+                    for i in digits[pos + 1..].iter_mut() {
+                        *i = 0;
+                    }
+                }
+                None => return Ok(()),
+            }
+        }
+    }
+}
+
+/*
+    Boxed transducers are transducers.
+    This allows building up transducers whose shape is only known at runtime
+    (e.g. Box<dyn Transducer<I, D, O>>).
+*/
+impl<I, D, O, M> Transducer<I, D, O> for Box<M>
+where
+    M: Transducer<I, D, O> + ?Sized,
+{
+    fn init(&mut self, i: Ext<I>) -> Ext<O> {
+        (**self).init(i)
+    }
+    fn update(&mut self, item: &D) -> Ext<O> {
+        (**self).update(item)
+    }
+    fn reset(&mut self) {
+        (**self).reset()
+    }
+    fn update_batch(&mut self, items: &[D]) -> Vec<Ext<O>> {
+        (**self).update_batch(items)
+    }
+
+    fn is_epsilon(&self) -> bool {
+        (**self).is_epsilon()
+    }
+    fn is_restartable(&self) -> bool {
+        (**self).is_restartable()
+    }
+    fn is_nullable(&self) -> bool {
+        (**self).is_nullable()
+    }
+    fn n_states(&self) -> usize {
+        (**self).n_states()
+    }
+    fn n_transs(&self) -> usize {
+        (**self).n_transs()
+    }
+    fn n_bytes(&self) -> usize {
+        mem::size_of::<Self>() + (**self).n_bytes()
+    }
+}
+
+/*
+    Bounded equivalence checking: compare the outputs of two transducers on
+    every stream of length at most max_len over the given alphabet (of
+    items and restarts), starting from their empty state. Returns the
+    shortest counterexample found, i.e. a stream on which the last outputs
+    differ.
+
+    This is useful for validating transformations of a machine (e.g. the
+    optimization passes in state_machine.rs). An exact check is not possible
+    in general, since guards and actions are arbitrary functions; for a
+    randomized check with longer streams, see random::equiv_random.
+    Note: there are up to alphabet.len()^max_len streams. The transducers
+    are cloned at each step so that common prefixes are only processed once;
+    this keeps alphabet.len()^(max_len - 1) pairs of clones in memory.
+*/
+pub fn equiv_bounded<I, D, O, M1, M2>(
+    m1: &M1,
+    m2: &M2,
+    alphabet: &[RInput<I, D>],
+    max_len: usize,
+) -> Result<(), Vec<RInput<I, D>>>
+where
+    M1: Transducer<I, D, O> + Clone,
+    M2: Transducer<I, D, O> + Clone,
+    I: Clone,
+    D: Clone,
+    O: Eq,
+{
+    // Breadth-first, so that the counterexample is shortest
+    let mut level = vec![(m1.spawn_empty(), m2.spawn_empty(), vec![])];
+    for len in 1..=max_len {
+        let mut next_level = Vec::new();
+        for (m1, m2, strm) in level {
+            for item in alphabet {
+                let (mut m1, mut m2) = (m1.clone(), m2.clone());
+                let (out1, out2) = match item {
+                    RInput::Restart(i) => {
+                        (m1.init_one(i.clone()), m2.init_one(i.clone()))
+                    }
+                    RInput::Item(d) => (m1.update(d), m2.update(d)),
+                };
+                let mut strm = strm.clone();
+                strm.push(item.clone());
+                if out1 != out2 {
+                    return Err(strm);
+                }
+                if len < max_len {
+                    next_level.push((m1, m2, strm));
+                }
+            }
+        }
+        level = next_level;
+    }
+    Ok(())
 }
@@ -0,0 +1,1053 @@
+/*
+    Input sources and output sinks: reading a stream of items from a file
+    or other reader, driving a transducer over it, and writing out the
+    outputs.
+
+    A source is any iterator of Result<D, E>: the items, or an error on a
+    malformed one. run_source() feeds the items to a transducer, stopping
+    at the first error.
+
+    The CSV source splits the text into records (following RFC 4180:
+    fields may be quoted with ", and a quoted field may contain the
+    delimiter, newlines, and "" for a quote), and converts each record
+    into an item with the FromRecord trait. This is implemented for
+    Vec<Field>, a generic row of numbers and strings; for a record type of
+    your own, implement FromRecord, typically by looking up the fields by
+    column name with Record::parse().
+
+    The JSON-lines source (with the "json" feature) reads one JSON value
+    per line, deserialized to any type D (e.g. serde_json::Value). A field
+    can be designated as the restart field: lines with an object containing
+    it are restarts, with the value of the field as the initial value.
+    This source yields RInput items; run them with run_rsource().
+
+    The protobuf source (with the "protobuf" feature) reads binary records,
+    each a message of any prost type D prefixed by its length as a varint
+    (the framing of prost's encode_length_delimited, and of Java's
+    writeDelimitedTo). A length above the maximum record size (64 MiB by
+    default, see .max_record_size()) is an error, so that a corrupt or
+    malicious prefix can't make it allocate an arbitrary amount of memory.
+
+    An output sink consumes the outputs of a transducer, one per step
+    (numbered from 1): see the Sink trait, implemented for writers
+    (WriteSink, including stdout and files), JSON lines (JsonLinesSink,
+    with the "json" feature), callbacks, and channels. drain() feeds a
+    stream of outputs to a sink, handling the steps with no output
+    (Ext::None) or several (Ext::Many) as set by an OutputPolicy: by
+    default, None is skipped and Many is written.
+*/
+
+use super::ext_value::Ext;
+use super::interface::{RInput, Transducer};
+#[cfg(feature = "protobuf")]
+use prost::Message;
+#[cfg(feature = "json")]
+use serde::de::DeserializeOwned;
+#[cfg(feature = "json")]
+use serde::Serialize;
+use std::error::Error;
+use std::fmt::{self, Display};
+use std::fs::File;
+#[cfg(feature = "protobuf")]
+use std::io::Read;
+use std::io::{self, BufRead, BufWriter, Write};
+use std::marker::PhantomData;
+use std::mem;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::mpsc::Sender;
+
+/*
+    Driving a transducer
+*/
+
+// Run the transducer on the items of the source, after initializing it
+// with init; returns the output after each item, or the first error
+pub fn run_source<I, D, O, E, M, S>(
+    m: &mut M,
+    init: I,
+    source: S,
+) -> Result<Vec<Ext<O>>, E>
+where
+    M: Transducer<I, D, O>,
+    S: IntoIterator<Item = Result<D, E>>,
+{
+    m.init_one(init);
+    source.into_iter().map(|item| Ok(m.update(&item?))).collect()
+}
+
+// The same for a source of items and restarts
+pub fn run_rsource<I, D, O, E, M, S>(
+    m: &mut M,
+    source: S,
+) -> Result<Vec<Ext<O>>, E>
+where
+    M: Transducer<I, D, O>,
+    S: IntoIterator<Item = Result<RInput<I, D>, E>>,
+{
+    let mut step = |input| match input {
+        RInput::Restart(i) => m.init_one(i),
+        RInput::Item(d) => m.update(&d),
+    };
+    source.into_iter().map(|input| Ok(step(input?))).collect()
+}
+
+/*
+    CSV records
+*/
+
+// A generic field of a CSV record: an integer, a float, or otherwise text
+#[derive(Clone, Debug, PartialEq)]
+pub enum Field {
+    Int(i64),
+    Float(f64),
+    Text(String),
+}
+impl Field {
+    pub fn parse(s: &str) -> Self {
+        if let Ok(x) = s.parse() {
+            Field::Int(x)
+        } else if let Ok(x) = s.parse() {
+            Field::Float(x)
+        } else {
+            Field::Text(s.to_string())
+        }
+    }
+    pub fn as_int(&self) -> Option<i64> {
+        match self {
+            Field::Int(x) => Some(*x),
+            _ => None,
+        }
+    }
+    pub fn as_float(&self) -> Option<f64> {
+        match self {
+            Field::Int(x) => Some(*x as f64),
+            Field::Float(x) => Some(*x),
+            Field::Text(_) => None,
+        }
+    }
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Field::Text(s) => Some(s),
+            _ => None,
+        }
+    }
+}
+impl fmt::Display for Field {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Field::Int(x) => write!(f, "{}", x),
+            Field::Float(x) => write!(f, "{}", x),
+            Field::Text(s) => write!(f, "{}", s),
+        }
+    }
+}
+
+// A record as read from the file, with the column names if the file has
+// a header
+#[derive(Clone, Copy, Debug)]
+pub struct Record<'r> {
+    pub fields: &'r [String],
+    pub header: Option<&'r [String]>,
+}
+impl<'r> Record<'r> {
+    pub fn len(&self) -> usize {
+        self.fields.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+    pub fn get(&self, i: usize) -> Option<&'r str> {
+        self.fields.get(i).map(String::as_str)
+    }
+    // The field in the named column
+    pub fn column(&self, name: &str) -> Option<&'r str> {
+        let i = self.header?.iter().position(|col| col == name)?;
+        self.get(i)
+    }
+    // Parse the field in the named column
+    pub fn parse<T: FromStr>(&self, name: &str) -> Result<T, String> {
+        let s = self
+            .column(name)
+            .ok_or_else(|| format!("no column named {:?}", name))?;
+        s.parse().map_err(|_| format!("bad value {:?} for {}", s, name))
+    }
+}
+
+// Conversion of a CSV record into an item; returns an error message if
+// the record is malformed
+pub trait FromRecord: Sized {
+    fn from_record(record: Record<'_>) -> Result<Self, String>;
+}
+impl FromRecord for Vec<Field> {
+    fn from_record(record: Record<'_>) -> Result<Self, String> {
+        Ok(record.fields.iter().map(|s| Field::parse(s)).collect())
+    }
+}
+impl FromRecord for Vec<String> {
+    fn from_record(record: Record<'_>) -> Result<Self, String> {
+        Ok(record.fields.to_vec())
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum CsvErrorKind {
+    // Reading the underlying file failed
+    Io(String),
+    // The file ended inside a quoted field
+    UnterminatedQuote,
+    // A record has a different number of fields than the header
+    FieldCount { expected: usize, found: usize },
+    // The record could not be converted to an item (see FromRecord)
+    Record(String),
+}
+
+// An error in the record starting at the given line (numbered from 1)
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CsvError {
+    pub line: usize,
+    pub kind: CsvErrorKind,
+}
+impl fmt::Display for CsvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: ", self.line)?;
+        match &self.kind {
+            CsvErrorKind::Io(err) => write!(f, "{}", err),
+            CsvErrorKind::UnterminatedQuote => {
+                write!(f, "unterminated quoted field")
+            }
+            CsvErrorKind::FieldCount { expected, found } => write!(
+                f,
+                "expected {} fields as in the header, found {}",
+                expected, found
+            ),
+            CsvErrorKind::Record(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+impl Error for CsvError {}
+
+/*
+    CSV source
+*/
+
+pub struct CsvSource<R: BufRead> {
+    reader: R,
+    delimiter: char,
+    header: Option<Vec<String>>,
+    // Number of lines read so far
+    line: usize,
+}
+
+impl<R: BufRead> CsvSource<R> {
+    pub fn new(reader: R) -> Self {
+        Self { reader, delimiter: ',', header: None, line: 0 }
+    }
+    pub fn delimiter(mut self, delimiter: char) -> Self {
+        self.delimiter = delimiter;
+        self
+    }
+    // Read the first record as the names of the columns. Records are then
+    // required to have the same number of fields.
+    pub fn with_header(mut self) -> Result<Self, CsvError> {
+        self.header = self.next_fields()?.map(|(_, fields)| fields);
+        Ok(self)
+    }
+    pub fn header(&self) -> Option<&[String]> {
+        self.header.as_deref()
+    }
+    // The records converted to items of type D
+    pub fn items<D: FromRecord>(self) -> CsvItems<R, D> {
+        CsvItems { source: self, ph_d: PhantomData }
+    }
+
+    fn read_line(&mut self, buf: &mut String) -> Result<bool, CsvError> {
+        match self.reader.read_line(buf) {
+            Ok(0) => Ok(false),
+            Ok(_) => {
+                self.line += 1;
+                Ok(true)
+            }
+            Err(err) => Err(CsvError {
+                line: self.line + 1,
+                kind: CsvErrorKind::Io(err.to_string()),
+            }),
+        }
+    }
+    // The fields of the next record (skipping blank lines), with the line
+    // it starts on, or None at the end of the file
+    fn next_fields(
+        &mut self,
+    ) -> Result<Option<(usize, Vec<String>)>, CsvError> {
+        let mut text = String::new();
+        loop {
+            if !self.read_line(&mut text)? {
+                return Ok(None);
+            }
+            if !text.trim().is_empty() {
+                break;
+            }
+            text.clear();
+        }
+        let start = self.line;
+        let mut fields = vec![];
+        let mut field = String::new();
+        let mut quoted = false;
+        let mut pos = 0;
+        loop {
+            let mut chars = text[pos..].chars().peekable();
+            while let Some(c) = chars.next() {
+                match (quoted, c) {
+                    (true, '"') if chars.peek() == Some(&'"') => {
+                        chars.next();
+                        field.push('"');
+                    }
+                    (true, '"') => quoted = false,
+                    (true, c) => field.push(c),
+                    (false, '"') if field.is_empty() => quoted = true,
+                    (false, '\n') | (false, '\r') => {}
+                    (false, c) if c == self.delimiter => {
+                        fields.push(mem::take(&mut field));
+                    }
+                    (false, c) => field.push(c),
+                }
+            }
+            if !quoted {
+                break;
+            }
+            // A quoted field continues on the next line
+            pos = text.len();
+            if !self.read_line(&mut text)? {
+                return Err(CsvError {
+                    line: start,
+                    kind: CsvErrorKind::UnterminatedQuote,
+                });
+            }
+        }
+        fields.push(field);
+        Ok(Some((start, fields)))
+    }
+    fn next_item<D: FromRecord>(&mut self) -> Option<Result<D, CsvError>> {
+        let (line, fields) = match self.next_fields() {
+            Ok(record) => record?,
+            Err(err) => return Some(Err(err)),
+        };
+        if let Some(header) = &self.header {
+            if header.len() != fields.len() {
+                let (expected, found) = (header.len(), fields.len());
+                let kind = CsvErrorKind::FieldCount { expected, found };
+                return Some(Err(CsvError { line, kind }));
+            }
+        }
+        let record = Record { fields: &fields, header: self.header() };
+        Some(
+            D::from_record(record).map_err(|msg| CsvError {
+                line,
+                kind: CsvErrorKind::Record(msg),
+            }),
+        )
+    }
+}
+
+impl<R: BufRead> Iterator for CsvSource<R> {
+    type Item = Result<Vec<String>, CsvError>;
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_item()
+    }
+}
+
+pub struct CsvItems<R: BufRead, D> {
+    source: CsvSource<R>,
+    ph_d: PhantomData<D>,
+}
+
+impl<R: BufRead, D: FromRecord> Iterator for CsvItems<R, D> {
+    type Item = Result<D, CsvError>;
+    fn next(&mut self) -> Option<Self::Item> {
+        self.source.next_item()
+    }
+}
+
+/*
+    JSON-lines source
+*/
+
+// An error in the given line (numbered from 1): reading it failed, or it
+// is not valid JSON of the expected type
+#[cfg(feature = "json")]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct JsonError {
+    pub line: usize,
+    pub message: String,
+}
+#[cfg(feature = "json")]
+impl fmt::Display for JsonError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+#[cfg(feature = "json")]
+impl Error for JsonError {}
+
+#[cfg(feature = "json")]
+pub struct JsonLinesSource<R: BufRead, I, D> {
+    reader: R,
+    restart_field: Option<String>,
+    line: usize,
+    ph_i: PhantomData<I>,
+    ph_d: PhantomData<D>,
+}
+
+#[cfg(feature = "json")]
+impl<R, I, D> JsonLinesSource<R, I, D>
+where
+    R: BufRead,
+    I: DeserializeOwned,
+    D: DeserializeOwned,
+{
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader,
+            restart_field: None,
+            line: 0,
+            ph_i: PhantomData,
+            ph_d: PhantomData,
+        }
+    }
+    pub fn restart_field(mut self, field: &str) -> Self {
+        self.restart_field = Some(field.to_string());
+        self
+    }
+
+    fn parse(&self, text: &str) -> Result<RInput<I, D>, serde_json::Error> {
+        let mut value: serde_json::Value = serde_json::from_str(text)?;
+        if let Some(field) = &self.restart_field {
+            if let Some(init) =
+                value.as_object_mut().and_then(|o| o.remove(field))
+            {
+                return Ok(RInput::Restart(serde_json::from_value(init)?));
+            }
+        }
+        Ok(RInput::Item(serde_json::from_value(value)?))
+    }
+}
+
+#[cfg(feature = "json")]
+impl<R, I, D> Iterator for JsonLinesSource<R, I, D>
+where
+    R: BufRead,
+    I: DeserializeOwned,
+    D: DeserializeOwned,
+{
+    type Item = Result<RInput<I, D>, JsonError>;
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut text = String::new();
+        loop {
+            text.clear();
+            let read = self.reader.read_line(&mut text);
+            self.line += 1;
+            let error =
+                |message: String| JsonError { line: self.line, message };
+            match read {
+                Ok(0) => return None,
+                Ok(_) if text.trim().is_empty() => continue,
+                Ok(_) => {
+                    let result = self.parse(&text);
+                    return Some(result.map_err(|e| error(e.to_string())));
+                }
+                Err(err) => return Some(Err(error(err.to_string()))),
+            }
+        }
+    }
+}
+
+/*
+    Protobuf source
+*/
+
+// An error in the given record (numbered from 1): reading it failed (e.g.
+// the input ends in the middle of it), or it is not a valid message
+#[cfg(feature = "protobuf")]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ProtobufError {
+    pub record: usize,
+    pub message: String,
+}
+#[cfg(feature = "protobuf")]
+impl fmt::Display for ProtobufError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "record {}: {}", self.record, self.message)
+    }
+}
+#[cfg(feature = "protobuf")]
+impl Error for ProtobufError {}
+
+#[cfg(feature = "protobuf")]
+const MAX_RECORD_SIZE: usize = 64 << 20;
+
+#[cfg(feature = "protobuf")]
+pub struct ProtobufSource<R: Read, D> {
+    reader: R,
+    record: usize,
+    max_len: usize,
+    // The buffer for the current record, reused across records
+    buf: Vec<u8>,
+    ph_d: PhantomData<D>,
+}
+
+#[cfg(feature = "protobuf")]
+impl<R: Read, D: Message + Default> ProtobufSource<R, D> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader,
+            record: 0,
+            max_len: MAX_RECORD_SIZE,
+            buf: Vec::new(),
+            ph_d: PhantomData,
+        }
+    }
+    // Records longer than this (in bytes) are errors
+    pub fn max_record_size(mut self, max_len: usize) -> Self {
+        self.max_len = max_len;
+        self
+    }
+
+    // The length prefix of the next record (None at the end of the input)
+    fn read_len(&mut self) -> io::Result<Option<u64>> {
+        let mut len: u64 = 0;
+        for i in 0..10 {
+            let mut byte = [0];
+            if self.reader.read(&mut byte)? == 0 {
+                if i == 0 {
+                    return Ok(None);
+                }
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            len |= u64::from(byte[0] & 0x7F) << (7 * i);
+            if byte[0] & 0x80 == 0 {
+                return Ok(Some(len));
+            }
+        }
+        Err(io::Error::new(io::ErrorKind::InvalidData, "invalid length"))
+    }
+    fn read_record(&mut self) -> io::Result<Option<&[u8]>> {
+        match self.read_len()? {
+            None => Ok(None),
+            Some(len) if len > self.max_len as u64 => {
+                let msg = format!(
+                    "record of {} bytes exceeds the maximum of {}",
+                    len, self.max_len
+                );
+                Err(io::Error::new(io::ErrorKind::InvalidData, msg))
+            }
+            Some(len) => {
+                // Read through take() rather than into a buffer of the given
+                // length, so that a truncated input only uses the memory for
+                // the bytes actually there
+                self.buf.clear();
+                (&mut self.reader).take(len).read_to_end(&mut self.buf)?;
+                if (self.buf.len() as u64) < len {
+                    return Err(io::ErrorKind::UnexpectedEof.into());
+                }
+                Ok(Some(&self.buf))
+            }
+        }
+    }
+}
+
+#[cfg(feature = "protobuf")]
+impl<R: Read, D: Message + Default> Iterator for ProtobufSource<R, D> {
+    type Item = Result<D, ProtobufError>;
+    fn next(&mut self) -> Option<Self::Item> {
+        self.record += 1;
+        let record = self.record;
+        let error = |message: String| ProtobufError { record, message };
+        match self.read_record() {
+            Ok(None) => None,
+            Ok(Some(bytes)) => {
+                Some(D::decode(bytes).map_err(|e| error(e.to_string())))
+            }
+            Err(err) => Some(Err(error(err.to_string()))),
+        }
+    }
+}
+
+/*
+    Output sinks
+*/
+
+// What to do with a step whose output is None or Many
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ExtPolicy {
+    Skip,
+    Write,
+    Error,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct OutputPolicy {
+    pub none: ExtPolicy,
+    pub many: ExtPolicy,
+}
+impl Default for OutputPolicy {
+    fn default() -> Self {
+        Self { none: ExtPolicy::Skip, many: ExtPolicy::Write }
+    }
+}
+
+pub trait Sink<O> {
+    // Consume the output of a step (numbered from 1); this is only called
+    // with None and Many if the policy says to write them
+    fn write(&mut self, step: usize, out: &Ext<O>) -> io::Result<()>;
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+impl<O, S: Sink<O> + ?Sized> Sink<O> for &mut S {
+    fn write(&mut self, step: usize, out: &Ext<O>) -> io::Result<()> {
+        (**self).write(step, out)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        (**self).flush()
+    }
+}
+
+#[derive(Debug)]
+pub enum SinkErrorKind {
+    // The sink failed to write the output
+    Io(io::Error),
+    // The output was None or Many, and the policy is ExtPolicy::Error
+    Unexpected,
+}
+
+// An error on the output of the given step
+#[derive(Debug)]
+pub struct SinkError {
+    pub step: usize,
+    pub kind: SinkErrorKind,
+}
+impl fmt::Display for SinkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.kind {
+            SinkErrorKind::Io(err) => {
+                write!(f, "writing the output of step {}: {}", self.step, err)
+            }
+            SinkErrorKind::Unexpected => {
+                write!(f, "unexpected output at step {}", self.step)
+            }
+        }
+    }
+}
+impl Error for SinkError {}
+
+// Write the outputs to the sink, according to the policy, and flush it;
+// returns the number of outputs written
+pub fn drain<O, S, It>(
+    outputs: It,
+    sink: &mut S,
+    policy: OutputPolicy,
+) -> Result<usize, SinkError>
+where
+    S: Sink<O> + ?Sized,
+    It: IntoIterator<Item = Ext<O>>,
+{
+    let mut n_written = 0;
+    let mut step = 0;
+    for out in outputs {
+        step += 1;
+        let action = match out {
+            Ext::None => policy.none,
+            Ext::One(_) => ExtPolicy::Write,
+            Ext::Many => policy.many,
+        };
+        match action {
+            ExtPolicy::Skip => continue,
+            ExtPolicy::Write => {}
+            ExtPolicy::Error => {
+                let kind = SinkErrorKind::Unexpected;
+                return Err(SinkError { step, kind });
+            }
+        }
+        sink.write(step, &out)
+            .map_err(|err| SinkError { step, kind: SinkErrorKind::Io(err) })?;
+        n_written += 1;
+    }
+    sink.flush()
+        .map_err(|err| SinkError { step, kind: SinkErrorKind::Io(err) })?;
+    Ok(n_written)
+}
+
+// Writes each output on a line (using Display), optionally prefixed with
+// the step number as "<step>: ". None is written as an empty line.
+pub struct WriteSink<W: Write> {
+    writer: W,
+    numbered: bool,
+}
+impl<W: Write> WriteSink<W> {
+    pub fn new(writer: W) -> Self {
+        Self { writer, numbered: false }
+    }
+    pub fn numbered(mut self) -> Self {
+        self.numbered = true;
+        self
+    }
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+impl WriteSink<io::Stdout> {
+    pub fn stdout() -> Self {
+        Self::new(io::stdout())
+    }
+}
+impl WriteSink<BufWriter<File>> {
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        Ok(Self::new(BufWriter::new(File::create(path)?)))
+    }
+}
+impl<O: Display, W: Write> Sink<O> for WriteSink<W> {
+    fn write(&mut self, step: usize, out: &Ext<O>) -> io::Result<()> {
+        if self.numbered {
+            write!(self.writer, "{}: ", step)?;
+        }
+        match out {
+            Ext::None => writeln!(self.writer),
+            _ => writeln!(self.writer, "{}", out),
+        }
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+// Writes each output as a JSON object {"step": <step>, "output": <output>},
+// where the output is null for None and "many" for Many
+#[cfg(feature = "json")]
+pub struct JsonLinesSink<W: Write> {
+    writer: W,
+}
+#[cfg(feature = "json")]
+impl<W: Write> JsonLinesSink<W> {
+    pub fn new(writer: W) -> Self {
+        Self { writer }
+    }
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+#[cfg(feature = "json")]
+impl<O: Serialize, W: Write> Sink<O> for JsonLinesSink<W> {
+    fn write(&mut self, step: usize, out: &Ext<O>) -> io::Result<()> {
+        let output = match out {
+            Ext::None => serde_json::Value::Null,
+            Ext::One(x) => serde_json::to_value(x)?,
+            Ext::Many => serde_json::Value::from("many"),
+        };
+        let line = serde_json::json!({ "step": step, "output": output });
+        writeln!(self.writer, "{}", line)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+// Calls a function on each output
+pub struct CallbackSink<F>(pub F);
+impl<O, F: FnMut(usize, &Ext<O>)> Sink<O> for CallbackSink<F> {
+    fn write(&mut self, step: usize, out: &Ext<O>) -> io::Result<()> {
+        (self.0)(step, out);
+        Ok(())
+    }
+}
+
+// Sends each output, with its step, on a channel; fails if the receiver
+// has hung up
+pub struct ChannelSink<O>(pub Sender<(usize, Ext<O>)>);
+impl<O: Clone> Sink<O> for ChannelSink<O> {
+    fn write(&mut self, step: usize, out: &Ext<O>) -> io::Result<()> {
+        self.0.send((step, out.clone())).map_err(|_| {
+            io::Error::new(io::ErrorKind::BrokenPipe, "receiver hung up")
+        })
+    }
+}
+
+/*
+    Unit Tests
+*/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::qre::{atom, atom_univ, iterate};
+
+    const TRADES: &str = "\
+time,symbol,price
+1,ABC,10
+2,\"X,Y\",12.5
+
+3,ABC,\"1\"\"1\"
+4,ABC,\"multi
+line\"
+";
+
+    fn fields(s: &[&str]) -> Vec<String> {
+        s.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_records() {
+        let source = CsvSource::new(TRADES.as_bytes()).with_header().unwrap();
+        assert_eq!(
+            source.header(),
+            Some(&fields(&["time", "symbol", "price"])[..])
+        );
+        let records: Vec<_> = source.collect();
+        assert_eq!(
+            records,
+            vec![
+                Ok(fields(&["1", "ABC", "10"])),
+                Ok(fields(&["2", "X,Y", "12.5"])),
+                Ok(fields(&["3", "ABC", "1\"1"])),
+                Ok(fields(&["4", "ABC", "multi\nline"])),
+            ]
+        );
+        let rows: Vec<Vec<Field>> = CsvSource::new("1;x;2.5\n".as_bytes())
+            .delimiter(';')
+            .items()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(
+            rows,
+            vec![vec![
+                Field::Int(1),
+                Field::Text("x".to_string()),
+                Field::Float(2.5)
+            ]]
+        );
+        assert_eq!(rows[0][2].as_float(), Some(2.5));
+        assert_eq!(rows[0][1].to_string(), "x");
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut source = CsvSource::new("a,b\n1,2\n3\n\"4,5\n".as_bytes())
+            .with_header()
+            .unwrap();
+        assert!(source.next().unwrap().is_ok());
+        let err = source.next().unwrap().unwrap_err();
+        assert_eq!(err.line, 3);
+        assert_eq!(
+            err.kind,
+            CsvErrorKind::FieldCount { expected: 2, found: 1 }
+        );
+        let err = source.next().unwrap().unwrap_err();
+        assert_eq!(err.to_string(), "line 4: unterminated quoted field");
+        assert_eq!(source.next(), None);
+    }
+
+    #[derive(Debug, PartialEq)]
+    struct Trade {
+        symbol: String,
+        price: f64,
+    }
+    impl FromRecord for Trade {
+        fn from_record(record: Record<'_>) -> Result<Self, String> {
+            Ok(Trade {
+                symbol: record.parse("symbol")?,
+                price: record.parse("price")?,
+            })
+        }
+    }
+
+    #[test]
+    fn test_run_csv() {
+        // Number of trades of ABC so far
+        let mut m = iterate(atom_univ(|n: usize, t: &Trade| {
+            n + (t.symbol == "ABC") as usize
+        }));
+        let text = "symbol,price\nABC,3\nXYZ,4\nABC,5\n";
+        let source = CsvSource::new(text.as_bytes()).with_header().unwrap();
+        assert_eq!(
+            run_source(&mut m, 0, source.items()),
+            Ok(vec![Ext::One(1), Ext::One(1), Ext::One(2)])
+        );
+        // Errors in the conversion to Trade
+        let text = "symbol,price\nABC,3\nABC,high\n";
+        let source = CsvSource::new(text.as_bytes()).with_header().unwrap();
+        let err = run_source(&mut m, 0, source.items()).unwrap_err();
+        assert_eq!(err.to_string(), "line 3: bad value \"high\" for price");
+        // Generic rows: the price in each row with more than 2 fields
+        let mut m = atom(
+            |r: &Vec<Field>| r.len() > 2,
+            |(), r: &Vec<Field>| r[2].as_float().unwrap(),
+        );
+        let source = CsvSource::new(TRADES.as_bytes()).with_header().unwrap();
+        let out = run_source(&mut m, (), source.items()).unwrap();
+        assert_eq!(out[0], Ext::One(10.0));
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn test_json_lines() {
+        use serde::Deserialize;
+        use serde_json::{json, Value};
+
+        #[derive(Debug, Deserialize, PartialEq)]
+        struct Event {
+            kind: String,
+            value: i64,
+        }
+        let text = r#"
+            {"kind": "a", "value": 3}
+            {"restart": 10}
+
+            {"kind": "b", "value": 4}
+        "#;
+        let source = JsonLinesSource::<_, i64, Event>::new(text.as_bytes())
+            .restart_field("restart");
+        let inputs: Vec<_> = source.collect::<Result<_, _>>().unwrap();
+        assert_eq!(inputs[1], RInput::Restart(10));
+        assert_eq!(
+            inputs[2],
+            RInput::Item(Event { kind: "b".to_string(), value: 4 })
+        );
+        // Sum of the values, for each restart
+        let mut m = iterate(atom_univ(|s: i64, e: &Event| s + e.value));
+        let source =
+            JsonLinesSource::new(text.as_bytes()).restart_field("restart");
+        assert_eq!(
+            run_rsource(&mut m, source),
+            Ok(vec![Ext::None, Ext::One(10), Ext::One(14)])
+        );
+        // Untyped items, and errors
+        let text = "{\"kind\": \"a\"}\n[1, 2]\n{\"restart\": \"x\"}\n{";
+        let mut source = JsonLinesSource::<_, i64, Value>::new(text.as_bytes())
+            .restart_field("restart");
+        assert_eq!(source.next(), Some(Ok(RInput::Item(json!({"kind": "a"})))));
+        assert_eq!(source.next(), Some(Ok(RInput::Item(json!([1, 2])))));
+        assert_eq!(source.next().unwrap().unwrap_err().line, 3);
+        assert_eq!(source.next().unwrap().unwrap_err().line, 4);
+        assert_eq!(source.next(), None);
+        let mut source = JsonLinesSource::<_, i64, Event>::new(&b"[1, 2]"[..]);
+        assert!(source
+            .next()
+            .unwrap()
+            .unwrap_err()
+            .message
+            .contains("invalid type"));
+    }
+
+    #[cfg(feature = "protobuf")]
+    #[test]
+    fn test_protobuf() {
+        #[derive(Clone, PartialEq, prost::Message)]
+        struct Reading {
+            #[prost(uint32, tag = "1")]
+            sensor: u32,
+            #[prost(sint64, tag = "2")]
+            value: i64,
+        }
+        let readings = [(1, 10), (2, -300), (1, 5)]
+            .map(|(sensor, value)| Reading { sensor, value });
+        let mut bytes = Vec::new();
+        for r in &readings {
+            r.encode_length_delimited(&mut bytes).unwrap();
+        }
+        let items: Vec<Reading> =
+            ProtobufSource::new(&bytes[..]).collect::<Result<_, _>>().unwrap();
+        assert_eq!(items, readings);
+
+        // Sum of the readings of sensor 1
+        let mut m = iterate(atom(
+            |_| true,
+            |s, r: &Reading| {
+                if r.sensor == 1 {
+                    s + r.value
+                } else {
+                    s
+                }
+            },
+        ));
+        let source = ProtobufSource::new(&bytes[..]);
+        let out = run_source(&mut m, 0, source).unwrap();
+        assert_eq!(out.last(), Some(&Ext::One(15)));
+
+        // The input ends in the middle of the last record
+        let mut source =
+            ProtobufSource::<_, Reading>::new(&bytes[..bytes.len() - 1]);
+        assert!(source.nth(1).unwrap().is_ok());
+        let err = source.next().unwrap().unwrap_err();
+        assert_eq!(err.record, 3);
+        assert!(source.next().is_none());
+
+        // A bogus length prefix (2^56 - 1) is an error, not an allocation
+        let bogus = [0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x7F];
+        let mut source = ProtobufSource::<_, Reading>::new(&bogus[..]);
+        let err = source.next().unwrap().unwrap_err();
+        assert_eq!(err.record, 1);
+        assert!(err.message.contains("exceeds the maximum"));
+        let mut source =
+            ProtobufSource::<_, Reading>::new(&bytes[..]).max_record_size(4);
+        assert!(source.next().unwrap().is_ok());
+        assert!(source.next().unwrap().is_err());
+    }
+
+    #[test]
+    fn test_sinks() {
+        let outputs = vec![Ext::One(1), Ext::None, Ext::Many, Ext::One(4)];
+        let mut sink = WriteSink::new(Vec::new());
+        let policy = OutputPolicy::default();
+        assert_eq!(drain(outputs.clone(), &mut sink, policy).unwrap(), 3);
+        assert_eq!(sink.into_inner(), b"1\nMany\n4\n");
+        let mut sink = WriteSink::new(Vec::new()).numbered();
+        let policy =
+            OutputPolicy { none: ExtPolicy::Write, many: ExtPolicy::Skip };
+        assert_eq!(drain(outputs.clone(), &mut sink, policy).unwrap(), 3);
+        assert_eq!(sink.into_inner(), b"1: 1\n2: \n4: 4\n");
+
+        let mut seen = vec![];
+        let mut sink =
+            CallbackSink(|step, out: &Ext<i32>| seen.push((step, *out)));
+        let policy =
+            OutputPolicy { none: ExtPolicy::Skip, many: ExtPolicy::Error };
+        let err = drain(outputs.clone(), &mut sink, policy).unwrap_err();
+        assert_eq!(err.to_string(), "unexpected output at step 3");
+        assert_eq!(seen, vec![(1, Ext::One(1))]);
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        let mut sink = ChannelSink(tx);
+        drain(outputs.clone(), &mut sink, OutputPolicy::default()).unwrap();
+        let received: Vec<_> = rx.try_iter().collect();
+        assert_eq!(
+            received,
+            vec![(1, Ext::One(1)), (3, Ext::Many), (4, Ext::One(4))]
+        );
+        drop(rx);
+        let err =
+            drain(outputs, &mut sink, OutputPolicy::default()).unwrap_err();
+        assert_eq!(err.step, 1);
+        assert!(matches!(err.kind, SinkErrorKind::Io(_)));
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn test_json_lines_sink() {
+        // Run a source through a transducer to a sink
+        let mut m = iterate(atom_univ(|s: i64, v: &i64| s + v));
+        let text = "1\n{\"restart\": 0}\n2\n3\n";
+        let source =
+            JsonLinesSource::new(text.as_bytes()).restart_field("restart");
+        let outputs = run_rsource(&mut m, source).unwrap();
+        let mut sink = JsonLinesSink::new(Vec::new());
+        let policy =
+            OutputPolicy { none: ExtPolicy::Write, many: ExtPolicy::Write };
+        drain(outputs, &mut sink, policy).unwrap();
+        assert_eq!(
+            String::from_utf8(sink.into_inner()).unwrap(),
+            "{\"output\":null,\"step\":1}\n\
+             {\"output\":0,\"step\":2}\n\
+             {\"output\":2,\"step\":3}\n\
+             {\"output\":5,\"step\":4}\n"
+        );
+    }
+}
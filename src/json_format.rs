@@ -0,0 +1,666 @@
+/*
+    JSON interchange format for DataTransducer state machines (enabled by
+    the "json" feature).
+
+    As in text_format.rs, guards and actions are closures, so the JSON
+    refers to them by the names they are registered under in a FnTable.
+    Guards are symbolic (see guard.rs): boolean combinations of named
+    primitive guards and of ranges of named integer fields. This allows
+    exchanging machines with external tools (editors, visualizers, other
+    QRE implementations), which can inspect the guards.
+
+    A machine is an object with the following fields (all but "states"
+    optional):
+        "states": the number of states (at least 2)
+        "labels": object mapping labels to state indices
+        "final_states": list of the final states (default [1])
+        "conflict_policy": "union" (default) or "highest_priority"
+        "updates": list of update transitions, each an object with
+            "sources" (0 to 2 state indices), "target", "guard",
+            "action", and optionally "priority" (default 0)
+        "epsilons": list of epsilon transitions, each an object with
+            "sources", "target", and "action"
+    where a guard is one of
+        "true", "false",
+        {"pred": <name>},
+        {"range": {"field": <name>, "lo": <int>, "hi": <int>}}
+            (inclusive; either bound may be omitted),
+        {"not": <guard>}, {"and": [<guards>]}, {"or": [<guards>]}
+    and the actions must be registered with as many arguments as there
+    are sources.
+
+    Example (the text_format.rs example, abbreviated):
+        {
+          "states": 4,
+          "labels": {"sum": 2, "count": 3},
+          "updates": [
+            {"sources": [0], "target": 2, "guard": {"pred": "is_a"},
+             "action": "value"},
+            {"sources": [2, 3], "target": 1, "guard": {"pred": "is_hash"},
+             "action": "divide"},
+            ...
+          ],
+          "epsilons": [{"sources": [1], "target": 0, "action": "iden"}]
+        }
+
+    Exporting requires every update transition to have a symbolic guard and
+    every transition to have a named action (see
+    DataTransducer::set_action_name()), as is the case for machines parsed
+    from either format. Output actions, state guards, custom final
+    combiners, and subscriptions are not represented.
+*/
+
+use super::errors::BuildError;
+use super::guard::{self, Guard};
+use super::state_machine::{
+    ConflictPolicy, DataTransducer, DataTransducerBuilder, TransRef,
+};
+use super::text_format::FnTable;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+
+/*
+    The schema
+*/
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct MachineSpec {
+    pub states: usize,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub labels: BTreeMap<String, usize>,
+    #[serde(
+        default = "default_finals",
+        skip_serializing_if = "is_default_finals"
+    )]
+    pub final_states: Vec<usize>,
+    #[serde(default, skip_serializing_if = "is_default_policy")]
+    pub conflict_policy: PolicySpec,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub updates: Vec<UpdateSpec>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub epsilons: Vec<EpsilonSpec>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct UpdateSpec {
+    pub sources: Vec<usize>,
+    pub target: usize,
+    pub guard: GuardSpec,
+    pub action: String,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub priority: i32,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct EpsilonSpec {
+    pub sources: Vec<usize>,
+    pub target: usize,
+    pub action: String,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum GuardSpec {
+    True,
+    False,
+    Pred(String),
+    Range {
+        field: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        lo: Option<i64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        hi: Option<i64>,
+    },
+    Not(Box<GuardSpec>),
+    And(Vec<GuardSpec>),
+    Or(Vec<GuardSpec>),
+}
+
+// Mirrors ConflictPolicy
+#[derive(
+    Clone, Copy, Debug, Default, Eq, PartialEq, Serialize, Deserialize,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum PolicySpec {
+    #[default]
+    Union,
+    HighestPriority,
+}
+
+fn default_finals() -> Vec<usize> {
+    vec![1]
+}
+fn is_default_finals(finals: &[usize]) -> bool {
+    finals == [1]
+}
+fn is_default_policy(policy: &PolicySpec) -> bool {
+    *policy == PolicySpec::Union
+}
+fn is_zero(x: &i32) -> bool {
+    *x == 0
+}
+
+/*
+    Errors
+*/
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ImportErrorKind {
+    // Not valid JSON, or not of the form above
+    Json(String),
+    // Guard, field, or action not registered in the table
+    // (for actions, with the given number of arguments)
+    UnknownGuard(String),
+    UnknownField(String),
+    UnknownAction { name: String, arity: usize },
+    // Well-formed, but can't be added to the machine
+    Build(BuildError),
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ImportError {
+    // The transition at fault, if any
+    pub trans: Option<TransRef>,
+    pub kind: ImportErrorKind,
+}
+impl fmt::Display for ImportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(trans) = self.trans {
+            write!(f, "{:?}: ", trans)?;
+        }
+        match &self.kind {
+            ImportErrorKind::Json(msg) => write!(f, "invalid JSON: {}", msg),
+            ImportErrorKind::UnknownGuard(name) => {
+                write!(f, "unknown guard {:?}", name)
+            }
+            ImportErrorKind::UnknownField(name) => {
+                write!(f, "unknown field {:?}", name)
+            }
+            ImportErrorKind::UnknownAction { name, arity } => write!(
+                f,
+                "unknown action {:?} with {} source state(s)",
+                name, arity
+            ),
+            ImportErrorKind::Build(err) => write!(f, "{}", err),
+        }
+    }
+}
+impl Error for ImportError {}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ExportError {
+    // An update transition whose guard is a closure
+    NoSymbolicGuard { trans: TransRef },
+    // A transition whose action has no name
+    NoActionName { trans: TransRef },
+}
+impl fmt::Display for ExportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExportError::NoSymbolicGuard { trans } => {
+                write!(f, "can't export {:?}: guard is not symbolic", trans)
+            }
+            ExportError::NoActionName { trans } => {
+                write!(f, "can't export {:?}: action has no name", trans)
+            }
+        }
+    }
+}
+impl Error for ExportError {}
+
+/*
+    Export
+*/
+
+fn export_guard<D>(g: &Guard<'_, D>) -> GuardSpec {
+    // Flatten nested conjunctions and disjunctions
+    fn flatten<D>(g: &Guard<'_, D>, and: bool, out: &mut Vec<GuardSpec>) {
+        match g {
+            Guard::And(g1, g2) if and => {
+                flatten(g1, and, out);
+                flatten(g2, and, out);
+            }
+            Guard::Or(g1, g2) if !and => {
+                flatten(g1, and, out);
+                flatten(g2, and, out);
+            }
+            _ => out.push(export_guard(g)),
+        }
+    }
+    match g {
+        Guard::True => GuardSpec::True,
+        Guard::False => GuardSpec::False,
+        Guard::Pred(p) => GuardSpec::Pred(p.name().to_string()),
+        Guard::Range(x, lo, hi) => GuardSpec::Range {
+            field: x.name().to_string(),
+            lo: Some(*lo).filter(|&lo| lo != i64::MIN),
+            hi: Some(*hi).filter(|&hi| hi != i64::MAX),
+        },
+        Guard::Not(g) => GuardSpec::Not(Box::new(export_guard(g))),
+        Guard::And(..) | Guard::Or(..) => {
+            let and = matches!(g, Guard::And(..));
+            let mut gs = vec![];
+            flatten(g, and, &mut gs);
+            if and {
+                GuardSpec::And(gs)
+            } else {
+                GuardSpec::Or(gs)
+            }
+        }
+    }
+}
+
+pub fn export_machine<D, Q: Clone>(
+    m: &DataTransducer<'_, D, Q>,
+) -> Result<MachineSpec, ExportError> {
+    let mut spec = MachineSpec {
+        states: m.states().count(),
+        labels: m
+            .states()
+            .filter_map(|st| Some((st.name?.to_string(), st.id)))
+            .collect(),
+        final_states: m.final_states(),
+        conflict_policy: match m.conflict_policy() {
+            ConflictPolicy::Union => PolicySpec::Union,
+            ConflictPolicy::HighestPriority => PolicySpec::HighestPriority,
+        },
+        updates: vec![],
+        epsilons: vec![],
+    };
+    for tr in m.transitions() {
+        let trans = tr.trans;
+        let action = m
+            .action_name(trans)
+            .ok_or(ExportError::NoActionName { trans })?
+            .to_string();
+        if tr.is_epsilon() {
+            let (sources, target) = (tr.sources, tr.target);
+            spec.epsilons.push(EpsilonSpec { sources, target, action });
+            continue;
+        }
+        let guard = m
+            .symbolic_guard(trans)
+            .ok_or(ExportError::NoSymbolicGuard { trans })?;
+        spec.updates.push(UpdateSpec {
+            sources: tr.sources,
+            target: tr.target,
+            guard: export_guard(&guard),
+            action,
+            priority: m.priority(trans).unwrap_or(0),
+        });
+    }
+    Ok(spec)
+}
+
+// Export as (pretty-printed) JSON
+pub fn to_json<D, Q: Clone>(
+    m: &DataTransducer<'_, D, Q>,
+) -> Result<String, ExportError> {
+    let spec = export_machine(m)?;
+    Ok(serde_json::to_string_pretty(&spec).expect("MachineSpec is valid JSON"))
+}
+
+/*
+    Import
+*/
+
+fn import_guard<'a, D, Q>(
+    spec: &GuardSpec,
+    table: &FnTable<'a, D, Q>,
+) -> Result<Guard<'a, D>, ImportErrorKind>
+where
+    D: 'a,
+    Q: 'a + Clone,
+{
+    let all = |gs: &[GuardSpec], and: bool| {
+        let gs = gs.iter().map(|g| import_guard(g, table));
+        let unit = if and { Guard::True } else { Guard::False };
+        gs.collect::<Result<Vec<_>, _>>().map(|gs| {
+            // (nested the same way as the operators)
+            gs.into_iter()
+                .reduce(|g1, g2| if and { g1 & g2 } else { g1 | g2 })
+                .unwrap_or(unit)
+        })
+    };
+    Ok(match spec {
+        GuardSpec::True => Guard::True,
+        GuardSpec::False => Guard::False,
+        GuardSpec::Pred(name) => {
+            let g = table
+                .guards
+                .get(name)
+                .ok_or_else(|| ImportErrorKind::UnknownGuard(name.clone()))?
+                .clone();
+            guard::pred(name, move |d: &D| g(d))
+        }
+        GuardSpec::Range { field, lo, hi } => {
+            let f = table
+                .fields
+                .get(field)
+                .ok_or_else(|| ImportErrorKind::UnknownField(field.clone()))?
+                .clone();
+            guard::field(field, move |d: &D| f(d))
+                .in_range(lo.unwrap_or(i64::MIN), hi.unwrap_or(i64::MAX))
+        }
+        GuardSpec::Not(g) => !import_guard(g, table)?,
+        GuardSpec::And(gs) => all(gs, true)?,
+        GuardSpec::Or(gs) => all(gs, false)?,
+    })
+}
+
+fn import_update<'a, D, Q>(
+    u: &UpdateSpec,
+    table: &FnTable<'a, D, Q>,
+    b: &mut DataTransducerBuilder<'a, D, Q>,
+) -> Result<(), ImportErrorKind>
+where
+    D: 'a,
+    Q: 'a + Clone,
+{
+    let guard = import_guard(&u.guard, table)?;
+    let unknown = || ImportErrorKind::UnknownAction {
+        name: u.action.clone(),
+        arity: u.sources.len(),
+    };
+    let tr = match *u.sources.as_slice() {
+        [] => {
+            let f = table.actions0.get(&u.action).ok_or_else(unknown)?.clone();
+            b.add_sym_transition0(u.target, guard, move |d| f(d))
+        }
+        [s] => {
+            let f = table.actions1.get(&u.action).ok_or_else(unknown)?.clone();
+            b.add_sym_transition1(s, u.target, guard, move |d, q| f(d, q))
+        }
+        [s1, s2] => {
+            let f = table.actions2.get(&u.action).ok_or_else(unknown)?.clone();
+            b.add_sym_transition2(s1, s2, u.target, guard, move |d, q1, q2| {
+                f(d, q1, q2)
+            })
+        }
+        _ => Err(BuildError::Arity {
+            arity: u.sources.len(),
+            n_sources: u.sources.len(),
+        }),
+    }
+    .map_err(ImportErrorKind::Build)?;
+    b.set_action_name(tr, &u.action).map_err(ImportErrorKind::Build)?;
+    if u.priority != 0 {
+        b.set_priority(tr, u.priority).map_err(ImportErrorKind::Build)?;
+    }
+    Ok(())
+}
+
+fn import_epsilon<'a, D, Q>(
+    e: &EpsilonSpec,
+    table: &FnTable<'a, D, Q>,
+    b: &mut DataTransducerBuilder<'a, D, Q>,
+) -> Result<(), ImportErrorKind>
+where
+    D: 'a,
+    Q: 'a + Clone,
+{
+    let unknown = || ImportErrorKind::UnknownAction {
+        name: e.action.clone(),
+        arity: e.sources.len(),
+    };
+    let tr = match *e.sources.as_slice() {
+        [] => {
+            let f =
+                table.eps_actions0.get(&e.action).ok_or_else(unknown)?.clone();
+            b.add_epsilon0(e.target, move || f())
+        }
+        [s] => {
+            let f =
+                table.eps_actions1.get(&e.action).ok_or_else(unknown)?.clone();
+            b.add_epsilon1(s, e.target, move |q| f(q))
+        }
+        [s1, s2] => {
+            let f =
+                table.eps_actions2.get(&e.action).ok_or_else(unknown)?.clone();
+            b.add_epsilon2(s1, s2, e.target, move |q1, q2| f(q1, q2))
+        }
+        _ => Err(BuildError::Arity {
+            arity: e.sources.len(),
+            n_sources: e.sources.len(),
+        }),
+    }
+    .map_err(ImportErrorKind::Build)?;
+    b.set_action_name(tr, &e.action).map_err(ImportErrorKind::Build)
+}
+
+// Build a machine from its description, using the guards, fields, and
+// actions in table
+pub fn import_machine<'a, D, Q>(
+    spec: &MachineSpec,
+    table: &FnTable<'a, D, Q>,
+) -> Result<DataTransducer<'a, D, Q>, ImportError>
+where
+    D: 'a,
+    Q: 'a + Clone,
+{
+    let err = |trans| move |kind| ImportError { trans, kind };
+    let build =
+        |e| ImportError { trans: None, kind: ImportErrorKind::Build(e) };
+    let mut b = DataTransducerBuilder::new();
+    if spec.states != b.n_states() {
+        b.set_nstates(spec.states).map_err(build)?;
+    }
+    for (label, &id) in &spec.labels {
+        b.name_state(id, label).map_err(build)?;
+    }
+    for (i, u) in spec.updates.iter().enumerate() {
+        import_update(u, table, &mut b)
+            .map_err(err(Some(TransRef::Update(i))))?;
+    }
+    for (i, e) in spec.epsilons.iter().enumerate() {
+        import_epsilon(e, table, &mut b)
+            .map_err(err(Some(TransRef::Epsilon(i))))?;
+    }
+    if !is_default_finals(&spec.final_states) {
+        b.set_final_states(&spec.final_states).map_err(build)?;
+    }
+    b.set_conflict_policy(match spec.conflict_policy {
+        PolicySpec::Union => ConflictPolicy::Union,
+        PolicySpec::HighestPriority => ConflictPolicy::HighestPriority,
+    });
+    Ok(b.build())
+}
+
+// Import from JSON text
+pub fn from_json<'a, D, Q>(
+    text: &str,
+    table: &FnTable<'a, D, Q>,
+) -> Result<DataTransducer<'a, D, Q>, ImportError>
+where
+    D: 'a,
+    Q: 'a + Clone,
+{
+    let spec: MachineSpec = serde_json::from_str(text).map_err(|e| {
+        ImportError { trans: None, kind: ImportErrorKind::Json(e.to_string()) }
+    })?;
+    import_machine(&spec, table)
+}
+
+/* Unit tests */
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ext_value::Ext;
+    use crate::interface::Transducer;
+    use crate::text_format::parse_machine;
+
+    type ExD = (char, isize);
+    type ExQ = isize;
+
+    fn example_table() -> FnTable<'static, ExD, ExQ> {
+        let mut table = FnTable::new();
+        table
+            .guard("is_a", |&d: &ExD| d.0 == 'a')
+            .guard("is_b", |&d: &ExD| d.0 == 'b')
+            .guard("is_hash", |&d: &ExD| d.0 == '#')
+            .field("value", |&d: &ExD| d.1 as i64)
+            .action1("value", |&d: &ExD, _| d.1)
+            .action1("one", |_, _| 1)
+            .action1("add_value", |&d: &ExD, &q| q + d.1)
+            .action1("add_one", |_, &q| q + 1)
+            .action2("divide", |_, &q1, &q2| q1 / q2)
+            .eps_action0("zero", || 0);
+        table
+    }
+
+    // Average of the 'a' values in each window (see text_format.rs)
+    const EXAMPLE: &str = "
+        states 4
+        label 2 sum
+        label 3 count
+        update 0 -> 0 when is_b do iden
+        update sum -> sum when is_b do iden
+        update count -> count when is_b do iden
+        update 0 -> sum when is_a do value
+        update 0 -> count when is_a do one
+        update sum -> sum when is_a do add_value
+        update count -> count when is_a do add_one
+        update sum count -> 1 when is_hash do divide
+        update 0 -> 1 when is_hash do iden
+        epsilon 1 -> 0 do iden
+    ";
+
+    fn run(m: &mut DataTransducer<'_, ExD, ExQ>) -> Vec<Ext<ExQ>> {
+        let items =
+            [('a', 6), ('b', 2), ('a', 8), ('#', 0), ('a', 2), ('#', 0)];
+        m.reset();
+        let mut result = vec![m.init_one(0)];
+        result.extend(items.iter().map(|d| m.update(d)));
+        result
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let table = example_table();
+        let mut m1 = parse_machine(EXAMPLE, &table).unwrap();
+        let json = to_json(&m1).unwrap();
+        let mut m2 = from_json(&json, &table).unwrap();
+        assert_eq!(run(&mut m1), run(&mut m2));
+        assert_eq!(run(&mut m2)[4], Ext::One(7));
+        assert_eq!(m2.state_id("count"), Some(3));
+        assert_eq!(to_json(&m2).unwrap(), json);
+        let spec = export_machine(&m1).unwrap();
+        assert_eq!(spec.states, 4);
+        assert_eq!(
+            spec.updates[7],
+            UpdateSpec {
+                sources: vec![2, 3],
+                target: 1,
+                guard: GuardSpec::Pred("is_hash".to_string()),
+                action: "divide".to_string(),
+                priority: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn test_json_schema() {
+        // Sum of the 'a' values between 0 and 9, restarting from 0 on '#'
+        let json = r#"{
+            "states": 3,
+            "labels": {"sum": 2},
+            "final_states": [2],
+            "conflict_policy": "highest_priority",
+            "updates": [
+                {"sources": [2], "target": 2, "action": "add_value",
+                 "guard": {"and": [
+                    {"pred": "is_a"},
+                    {"range": {"field": "value", "lo": 0, "hi": 9}}
+                 ]}},
+                {"sources": [2], "target": 2, "action": "iden",
+                 "guard": {"not": {"pred": "is_hash"}}, "priority": -1},
+                {"sources": [], "target": 2, "action": "zero",
+                 "guard": {"pred": "is_hash"}}
+            ],
+            "epsilons": [{"sources": [0], "target": 2, "action": "iden"}]
+        }"#;
+        let mut table = example_table();
+        table.action0("zero", |_| 0);
+        let mut m = from_json(json, &table).unwrap();
+        assert_eq!(m.init_one(1), Ext::One(1));
+        assert_eq!(m.update_val(('a', 5)), Ext::One(6));
+        assert_eq!(m.update_val(('a', 10)), Ext::One(6));
+        assert_eq!(m.update_val(('b', 1)), Ext::One(6));
+        assert_eq!(m.update_val(('#', 0)), Ext::One(0));
+        assert_eq!(m.update_val(('a', 3)), Ext::One(3));
+        let spec = export_machine(&m).unwrap();
+        assert_eq!(spec.final_states, vec![2]);
+        assert_eq!(spec.conflict_policy, PolicySpec::HighestPriority);
+        assert_eq!(
+            spec.updates[0].guard,
+            GuardSpec::And(vec![
+                GuardSpec::Pred("is_a".to_string()),
+                GuardSpec::Range {
+                    field: "value".to_string(),
+                    lo: Some(0),
+                    hi: Some(9)
+                },
+            ])
+        );
+        assert!(to_json(&m).unwrap().contains(r#""priority": -1"#));
+    }
+
+    #[test]
+    fn test_json_errors() {
+        let table = example_table();
+        let err = |json: &str| from_json(json, &table).unwrap_err();
+        assert!(matches!(err("{}").kind, ImportErrorKind::Json(_)));
+        assert!(matches!(
+            err(r#"{"states": 2, "updates": [{"sources": []}]}"#).kind,
+            ImportErrorKind::Json(_)
+        ));
+        let update = |guard: &str, action: &str| {
+            format!(
+                r#"{{"states": 2, "updates": [{{"sources": [0], "target": 1,
+                    "guard": {}, "action": "{}"}}]}}"#,
+                guard, action
+            )
+        };
+        assert_eq!(
+            err(&update(r#"{"pred": "is_c"}"#, "iden")),
+            ImportError {
+                trans: Some(TransRef::Update(0)),
+                kind: ImportErrorKind::UnknownGuard("is_c".to_string())
+            }
+        );
+        assert_eq!(
+            err(&update(r#"{"range": {"field": "x"}}"#, "iden")).kind,
+            ImportErrorKind::UnknownField("x".to_string())
+        );
+        assert_eq!(
+            err(&update(r#""true""#, "divide")).to_string(),
+            "Update(0): unknown action \"divide\" with 1 source state(s)"
+        );
+        assert_eq!(
+            err(r#"{"states": 2, "epsilons": [
+                {"sources": [0], "target": 5, "action": "iden"}]}"#)
+            .to_string(),
+            "Epsilon(0): transition refers to state 5, but there are only 2 \
+             states"
+        );
+        assert!(matches!(
+            err(r#"{"states": 1}"#).kind,
+            ImportErrorKind::Build(BuildError::ShrinkStates { .. })
+        ));
+        // Machines built from closures can't be exported
+        let mut m = DataTransducer::<ExD, ExQ>::new();
+        m.add_iden(0, 1, |_| true);
+        assert_eq!(
+            to_json(&m),
+            Err(ExportError::NoActionName { trans: TransRef::Update(0) })
+        );
+        m.set_action_name(TransRef::Update(0), "iden");
+        assert_eq!(
+            to_json(&m).unwrap_err().to_string(),
+            "can't export Update(0): guard is not symbolic"
+        );
+    }
+}
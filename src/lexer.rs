@@ -0,0 +1,562 @@
+/*
+    Tokenization: turning a stream of characters into a stream of tokens,
+    so that QREs can work at the granularity of words, numbers, fields of
+    a log line, etc. rather than of single characters.
+
+    A Tokenizer consumes characters one at a time and produces the tokens
+    as they are completed; .finish() completes the last one at the end of
+    the input. Two are provided:
+    - Lexer: tokens described by regexes over characters (see
+      derivative.rs), with the usual rules: each token is the longest
+      prefix of the rest of the input matching some rule, the first
+      such rule (in the order they were added) giving its kind; skip
+      rules match text which is dropped (e.g. whitespace);
+    - SplitOn: the text between separator characters.
+    Other tokenizers can be plugged in by implementing the trait.
+
+    There are two ways to run a QRE on the tokens:
+    - tokens(tk, chars) is an iterator of the tokens (or lexing errors),
+      which can be used as a source (see io::run_source);
+    - tokenized(tk, m) is a transducer on characters, which runs m on the
+      tokens: the output on a character is the output of m on the tokens
+      completed by it (there is usually at most one).
+    For a stream of bytes rather than characters (e.g. ASCII logs), use
+    bytes.map(char::from).
+
+    A Lexer keeps the characters of the current token, and the ones read
+    beyond the longest match so far (when a longer match fails, they are
+    lexed again); each rule runs as a lazy DFA, so only the reachable
+    states of the rules are built.
+*/
+
+use super::derivative::{self, Derivatives, LazyDfa, Regex};
+use super::ext_value::Ext;
+use super::guard::{self, Field, Guard};
+use super::interface::Transducer;
+use std::collections::VecDeque;
+use std::error::Error;
+use std::fmt::{self, Display};
+use std::marker::PhantomData;
+use std::mem;
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Token<K> {
+    pub kind: K,
+    pub text: String,
+    // Offset of the first character (in characters)
+    pub pos: usize,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct LexError {
+    // Offset of the character (in characters), which is skipped
+    pub pos: usize,
+    pub ch: char,
+}
+impl Display for LexError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "unexpected character {:?} at offset {}", self.ch, self.pos)
+    }
+}
+impl Error for LexError {}
+
+pub trait Tokenizer {
+    type Token;
+    // Consume a character, pushing the tokens it completes to out
+    fn push(
+        &mut self,
+        ch: char,
+        out: &mut Vec<Self::Token>,
+    ) -> Result<(), LexError>;
+    // End of the input: push the remaining tokens to out. After an error,
+    // this can be called again to continue.
+    fn finish(&mut self, out: &mut Vec<Self::Token>) -> Result<(), LexError>;
+    // Start over, forgetting the characters read so far
+    fn reset(&mut self);
+}
+
+/*
+    Regexes over characters
+*/
+
+fn code<'a>() -> Field<'a, char> {
+    guard::field("char", |&c: &char| c as i64)
+}
+pub fn chr<'a>(c: char) -> Regex<'a, char> {
+    derivative::sym(code().equals(c as i64))
+}
+// A character between lo and hi (inclusive)
+pub fn range<'a>(lo: char, hi: char) -> Regex<'a, char> {
+    derivative::sym(code().in_range(lo as i64, hi as i64))
+}
+pub fn one_of<'a>(chars: &str) -> Regex<'a, char> {
+    chars.chars().map(chr).fold(Regex::Empty, |acc, r| acc | r)
+}
+pub fn any_char<'a>() -> Regex<'a, char> {
+    derivative::sym(Guard::True)
+}
+// The string s
+pub fn lit<'a>(s: &str) -> Regex<'a, char> {
+    derivative::seq(s.chars().map(chr).collect())
+}
+// A character class given by a named predicate (e.g. char::is_whitespace)
+pub fn class<'a, F>(name: &str, f: F) -> Regex<'a, char>
+where
+    F: Fn(char) -> bool + 'a,
+{
+    derivative::sym(guard::pred(name, move |&c: &char| f(c)))
+}
+
+/*
+    Lexer from regexes
+*/
+
+struct Rule<'a, K> {
+    // None for skip rules
+    kind: Option<K>,
+    dfa: LazyDfa<'a, char, ()>,
+}
+
+pub struct Lexer<'a, K> {
+    rules: Vec<Rule<'a, K>>,
+    // Characters of the current token (and beyond the longest match)
+    buf: Vec<char>,
+    // Offset of the start of the current token
+    start: usize,
+    // Rule and length of the longest match so far
+    best: Option<(usize, usize)>,
+    // Characters to lex again (after a longest match)
+    queue: VecDeque<char>,
+}
+
+impl<'a, K: Clone> Lexer<'a, K> {
+    pub fn new() -> Self {
+        Lexer {
+            rules: Vec::new(),
+            buf: Vec::new(),
+            start: 0,
+            best: None,
+            queue: VecDeque::new(),
+        }
+    }
+    fn rule(mut self, kind: Option<K>, r: &Regex<'a, char>) -> Self {
+        let dfa = Derivatives::new(r).lazy();
+        assert!(!dfa.is_nullable(), "a token can't be empty");
+        self.rules.push(Rule { kind, dfa });
+        self
+    }
+    pub fn token(self, kind: K, r: &Regex<'a, char>) -> Self {
+        self.rule(Some(kind), r)
+    }
+    pub fn skip(self, r: &Regex<'a, char>) -> Self {
+        self.rule(None, r)
+    }
+
+    fn step(
+        &mut self,
+        ch: char,
+        out: &mut Vec<Token<K>>,
+    ) -> Result<(), LexError> {
+        if self.buf.is_empty() {
+            for rule in &mut self.rules {
+                rule.dfa.reset();
+                rule.dfa.init_one(());
+            }
+        }
+        self.buf.push(ch);
+        let len = self.buf.len();
+        let mut live = false;
+        for (i, rule) in self.rules.iter_mut().enumerate() {
+            let accepts = rule.dfa.update(&ch).is_one();
+            if accepts && self.best.is_none_or(|(_, n)| n < len) {
+                self.best = Some((i, len));
+            }
+            live |= !rule.dfa.is_dead();
+        }
+        if live {
+            Ok(())
+        } else {
+            self.emit(out)
+        }
+    }
+    // End the current token at the longest match, and queue the rest of
+    // the characters to lex again; with no match, skip the first one
+    fn emit(&mut self, out: &mut Vec<Token<K>>) -> Result<(), LexError> {
+        let buf = mem::take(&mut self.buf);
+        let start = self.start;
+        let (result, len) = match self.best.take() {
+            Some((i, len)) => {
+                if let Some(kind) = &self.rules[i].kind {
+                    let text = buf[..len].iter().collect();
+                    out.push(Token { kind: kind.clone(), text, pos: start });
+                }
+                (Ok(()), len)
+            }
+            None => (Err(LexError { pos: start, ch: buf[0] }), 1),
+        };
+        self.start += len;
+        for &ch in buf[len..].iter().rev() {
+            self.queue.push_front(ch);
+        }
+        result
+    }
+    fn run_queue(&mut self, out: &mut Vec<Token<K>>) -> Result<(), LexError> {
+        while let Some(ch) = self.queue.pop_front() {
+            self.step(ch, out)?;
+        }
+        Ok(())
+    }
+}
+impl<K: Clone> Default for Lexer<'_, K> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: Clone> Tokenizer for Lexer<'_, K> {
+    type Token = Token<K>;
+    fn push(
+        &mut self,
+        ch: char,
+        out: &mut Vec<Token<K>>,
+    ) -> Result<(), LexError> {
+        self.queue.push_back(ch);
+        self.run_queue(out)
+    }
+    fn finish(&mut self, out: &mut Vec<Token<K>>) -> Result<(), LexError> {
+        loop {
+            self.run_queue(out)?;
+            if self.buf.is_empty() {
+                return Ok(());
+            }
+            self.emit(out)?;
+        }
+    }
+    fn reset(&mut self) {
+        self.buf.clear();
+        self.start = 0;
+        self.best = None;
+        self.queue.clear();
+    }
+}
+
+/*
+    Splitting on separators
+*/
+
+pub struct SplitOn<F: Fn(char) -> bool> {
+    is_sep: F,
+    // Current token, and the offset of its start
+    text: String,
+    start: usize,
+    pos: usize,
+}
+pub fn split_on<F: Fn(char) -> bool>(is_sep: F) -> SplitOn<F> {
+    SplitOn { is_sep, text: String::new(), start: 0, pos: 0 }
+}
+
+impl<F: Fn(char) -> bool> SplitOn<F> {
+    fn flush(&mut self, out: &mut Vec<Token<()>>) {
+        if !self.text.is_empty() {
+            let text = mem::take(&mut self.text);
+            out.push(Token { kind: (), text, pos: self.start });
+        }
+    }
+}
+impl<F: Fn(char) -> bool> Tokenizer for SplitOn<F> {
+    type Token = Token<()>;
+    fn push(
+        &mut self,
+        ch: char,
+        out: &mut Vec<Token<()>>,
+    ) -> Result<(), LexError> {
+        if (self.is_sep)(ch) {
+            self.flush(out);
+        } else {
+            if self.text.is_empty() {
+                self.start = self.pos;
+            }
+            self.text.push(ch);
+        }
+        self.pos += 1;
+        Ok(())
+    }
+    fn finish(&mut self, out: &mut Vec<Token<()>>) -> Result<(), LexError> {
+        self.flush(out);
+        Ok(())
+    }
+    fn reset(&mut self) {
+        self.text.clear();
+        self.pos = 0;
+    }
+}
+
+/*
+    Running QREs on tokens
+*/
+
+// Iterator of the tokens of the characters
+pub struct Tokens<Tk: Tokenizer, It> {
+    tk: Tk,
+    chars: It,
+    ready: VecDeque<Result<Tk::Token, LexError>>,
+    done: bool,
+}
+pub fn tokens<Tk, It>(tk: Tk, chars: It) -> Tokens<Tk, It::IntoIter>
+where
+    Tk: Tokenizer,
+    It: IntoIterator<Item = char>,
+{
+    let chars = chars.into_iter();
+    Tokens { tk, chars, ready: VecDeque::new(), done: false }
+}
+
+impl<Tk: Tokenizer, It: Iterator<Item = char>> Iterator for Tokens<Tk, It> {
+    type Item = Result<Tk::Token, LexError>;
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut out = Vec::new();
+        while self.ready.is_empty() && !self.done {
+            let result = match self.chars.next() {
+                Some(ch) => self.tk.push(ch, &mut out),
+                None => {
+                    let result = self.tk.finish(&mut out);
+                    self.done = result.is_ok();
+                    result
+                }
+            };
+            self.ready.extend(out.drain(..).map(Ok));
+            if let Err(e) = result {
+                self.ready.push_back(Err(e));
+            }
+        }
+        self.ready.pop_front()
+    }
+}
+
+// Transducer on characters running m on the tokens
+pub struct Tokenized<Tk, I, O, M>
+where
+    Tk: Tokenizer,
+    M: Transducer<I, Tk::Token, O>,
+{
+    tk: Tk,
+    m: M,
+    // The tokens completed by the last character
+    out: Vec<Tk::Token>,
+    n_errors: usize,
+    ph_i: PhantomData<I>,
+    ph_o: PhantomData<O>,
+}
+pub fn tokenized<Tk, I, O, M>(tk: Tk, m: M) -> Tokenized<Tk, I, O, M>
+where
+    Tk: Tokenizer,
+    M: Transducer<I, Tk::Token, O>,
+{
+    Tokenized {
+        tk,
+        m,
+        out: Vec::new(),
+        n_errors: 0,
+        ph_i: PhantomData,
+        ph_o: PhantomData,
+    }
+}
+
+impl<Tk, I, O, M> Tokenized<Tk, I, O, M>
+where
+    Tk: Tokenizer,
+    M: Transducer<I, Tk::Token, O>,
+{
+    // Number of lexing errors so far (the characters are skipped)
+    pub fn n_errors(&self) -> usize {
+        self.n_errors
+    }
+    fn run_tokens(&mut self) -> Ext<O> {
+        let mut result = Ext::None;
+        for token in self.out.drain(..) {
+            result += self.m.update(&token);
+        }
+        result
+    }
+    // End of the input: the output of m on the last token
+    pub fn finish(&mut self) -> Ext<O> {
+        while self.tk.finish(&mut self.out).is_err() {
+            self.n_errors += 1;
+        }
+        self.run_tokens()
+    }
+}
+impl<Tk, I, O, M> Transducer<I, char, O> for Tokenized<Tk, I, O, M>
+where
+    Tk: Tokenizer,
+    M: Transducer<I, Tk::Token, O>,
+{
+    fn init(&mut self, i: Ext<I>) -> Ext<O> {
+        self.m.init(i)
+    }
+    fn update(&mut self, ch: &char) -> Ext<O> {
+        if self.tk.push(*ch, &mut self.out).is_err() {
+            self.n_errors += 1;
+        }
+        self.run_tokens()
+    }
+    fn reset(&mut self) {
+        self.tk.reset();
+        self.m.reset();
+        self.n_errors = 0;
+    }
+
+    fn is_epsilon(&self) -> bool {
+        self.m.is_epsilon()
+    }
+    fn is_restartable(&self) -> bool {
+        // The token boundaries don't depend on where m was initialized
+        self.m.is_restartable()
+    }
+    fn is_nullable(&self) -> bool {
+        self.m.is_nullable()
+    }
+    fn n_states(&self) -> usize {
+        self.m.n_states()
+    }
+    fn n_transs(&self) -> usize {
+        self.m.n_transs()
+    }
+}
+
+/*
+    Unit Tests
+*/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::derivative::plus;
+    use crate::qre::{atom, concat, epsilon, iterate};
+
+    #[derive(Clone, Copy, Debug, Eq, PartialEq)]
+    enum Kind {
+        If,
+        Ident,
+        Num,
+        Eq,
+        EqEq,
+        Arrow,
+    }
+    use Kind::*;
+
+    fn lexer() -> Lexer<'static, Kind> {
+        let alpha = range('a', 'z') | range('A', 'Z');
+        Lexer::new()
+            .token(If, &lit("if"))
+            .token(Ident, &plus(alpha))
+            .token(Num, &plus(range('0', '9')))
+            .token(Eq, &chr('='))
+            .token(EqEq, &lit("=="))
+            .token(Arrow, &lit("=>"))
+            .skip(&plus(one_of(" \t\n")))
+    }
+    fn lex<Tk: Tokenizer>(
+        tk: Tk,
+        text: &str,
+    ) -> Vec<Result<Tk::Token, LexError>> {
+        tokens(tk, text.chars()).collect()
+    }
+    fn kinds(text: &str) -> Vec<(Kind, String)> {
+        lex(lexer(), text)
+            .into_iter()
+            .map(|t| t.map(|t| (t.kind, t.text)).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_longest_match() {
+        let expected = vec![
+            (If, "if"),
+            (Ident, "iff"),
+            (EqEq, "=="),
+            (Eq, "="),
+            (Arrow, "=>"),
+            (Ident, "x"),
+            (Num, "42"),
+        ];
+        let expected: Vec<(Kind, String)> =
+            expected.into_iter().map(|(k, s)| (k, s.to_string())).collect();
+        assert_eq!(kinds("if iff == = =>x 42"), expected);
+        assert_eq!(kinds("  "), vec![]);
+        let toks = lex(lexer(), "ab 12");
+        assert_eq!(toks[1], Ok(Token { kind: Num, text: "12".into(), pos: 3 }));
+    }
+
+    #[test]
+    fn test_backtrack_errors() {
+        // On "abcx", the longest match is "a": "bcx" is lexed again
+        let lexer = Lexer::new().token(1, &lit("a")).token(2, &lit("abcd"));
+        let toks = lex(lexer, "abcdabcx");
+        assert_eq!(toks.len(), 5);
+        assert_eq!(toks[0].as_ref().unwrap().kind, 2);
+        assert_eq!(toks[1].as_ref().unwrap().pos, 4);
+        assert_eq!(toks[2], Err(LexError { pos: 5, ch: 'b' }));
+        assert_eq!(toks[3], Err(LexError { pos: 6, ch: 'c' }));
+        assert_eq!(
+            toks[4].clone().unwrap_err().to_string(),
+            "unexpected character 'x' at offset 7"
+        );
+        // An incomplete token at the end
+        let lexer = Lexer::new().token(1, &lit("a")).token(2, &lit("abcd"));
+        let toks = lex(lexer, "aab");
+        assert_eq!(toks[1].as_ref().unwrap().pos, 1);
+        assert_eq!(toks[2], Err(LexError { pos: 2, ch: 'b' }));
+    }
+
+    #[test]
+    fn test_split_on() {
+        let toks: Vec<String> = lex(split_on(|c| c == ','), "a,,bc,d")
+            .into_iter()
+            .map(|t| t.unwrap().text)
+            .collect();
+        assert_eq!(toks, ["a", "bc", "d"]);
+        let mut tk = split_on(char::is_whitespace);
+        let mut out = Vec::new();
+        for ch in " x yz".chars() {
+            tk.push(ch, &mut out).unwrap();
+        }
+        assert_eq!(out.len(), 1);
+        tk.finish(&mut out).unwrap();
+        assert_eq!(out[1], Token { kind: (), text: "yz".into(), pos: 3 });
+    }
+
+    #[test]
+    fn test_tokenized() {
+        // Sum of the numbers assigned to identifiers ("x = 3")
+        let assign = concat(
+            concat(
+                atom(|t: &Token<Kind>| t.kind == Ident, |s: i64, _| s),
+                atom(|t: &Token<Kind>| t.kind == Eq, |s, _| s),
+            ),
+            atom(
+                |t: &Token<Kind>| t.kind == Num,
+                |s, t: &Token<Kind>| s + t.text.parse::<i64>().unwrap(),
+            ),
+        );
+        let sum = concat(epsilon(|()| 0), iterate(assign));
+        let mut m = tokenized(lexer(), sum);
+        m.init_one(());
+        let out: Vec<Ext<i64>> =
+            "x = 3 y=40 ?".chars().map(|c| m.update(&c)).collect();
+        assert_eq!(out[5], Ext::One(3));
+        assert!(out[..5].iter().all(|o| o.is_none()));
+        assert_eq!(out[10], Ext::One(43));
+        assert_eq!(out.iter().filter(|o| o.is_one()).count(), 2);
+        // The '?' is skipped
+        assert_eq!(m.n_errors(), 1);
+        assert_eq!(m.finish(), Ext::None);
+        // The last number is only complete at the end
+        m.reset();
+        m.init_one(());
+        for c in "z=5".chars() {
+            assert_eq!(m.update(&c), Ext::None);
+        }
+        assert_eq!(m.finish(), Ext::One(5));
+    }
+}
@@ -4,7 +4,53 @@
     2020-12-09
 */
 
+pub mod agg_props;
+pub mod alloc_count;
+#[cfg(feature = "arrow")]
+pub mod arrow_batch;
+#[cfg(feature = "async")]
+pub mod async_driver;
+pub mod bench;
+pub mod cep;
+pub mod certificate;
+#[cfg(feature = "json")]
+pub mod checkpoint;
+pub mod closure;
+pub mod codegen;
+pub mod derivative;
+pub mod errors;
 pub mod ext_value;
+#[cfg(test)]
+mod fixtures;
+pub mod guard;
+pub mod init_check;
 pub mod interface;
+pub mod io;
+#[cfg(feature = "json")]
+pub mod json_format;
+pub mod lexer;
+pub mod lower;
+pub mod model_check;
+pub mod overflow;
+#[cfg(feature = "parallel")]
+pub mod parallel;
+pub mod pipeline;
 pub mod qre;
+pub mod qre_text;
+pub mod query;
+pub mod random;
+pub mod rate;
+pub mod record;
+#[cfg(feature = "regex")]
+pub mod regex_import;
+pub mod retract;
+pub mod runtime;
+pub mod sample;
+mod scanner;
+pub mod semiring;
+pub mod shared;
 pub mod state_machine;
+pub mod temporal;
+pub mod text_format;
+pub mod timed;
+pub mod weighted;
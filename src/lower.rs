@@ -0,0 +1,607 @@
+/*
+    Lowering of QREs to explicit state machines.
+
+    A QRE whose initial, intermediate, and output values all have the same
+    type Q can be compiled ("lowered") to a single DataTransducer<D, Q>,
+    following the construction in the POPL paper. This gives a second,
+    independent implementation of the QRE semantics, which is mainly useful
+    for testing (the two should always agree) and as an entry point for
+    analyses on explicit machines.
+
+    Each construct is lowered to a "fragment" of the machine which reads its
+    input from a given source state and writes its output to a given target
+    state. The invariant maintained is that a fragment only ever *reads* its
+    source and only ever *writes* its target; any other states it needs
+    are freshly added. This makes it safe for fragments to share their source
+    and target states (as in union).
+
+    Constructs whose types vary (ParComp, Aggregate) are not supported.
+    Lowering takes the QRE by reference, so the guard and action closures
+    must be Clone.
+
+    The conformance harness at the bottom (conformance() and friends)
+    checks that a QRE and its lowering agree on given or generated input
+    streams, and reports the first divergence.
+*/
+
+use super::ext_value::Ext;
+use super::interface::{RInput, Transducer};
+use super::qre::{Atom, Concat, Epsilon, Iterate, TopWrapper, Union};
+use super::random::Rng;
+use super::state_machine::DataTransducer;
+use std::fmt::{self, Debug};
+
+pub trait Lower<'a, D, Q>
+where
+    Q: Clone,
+{
+    // Add states and transitions to m implementing this QRE, reading from
+    // the state with index source and writing to the state with index target
+    fn lower_into(
+        &self,
+        m: &mut DataTransducer<'a, D, Q>,
+        source: usize,
+        target: usize,
+    );
+
+    /* Derived functionality */
+
+    // Lower to a fresh machine, from its initial to its final state
+    fn lower(&self) -> DataTransducer<'a, D, Q> {
+        let mut m = DataTransducer::new();
+        self.lower_into(&mut m, 0, 1);
+        m
+    }
+}
+
+impl<'a, D, Q, L> Lower<'a, D, Q> for Box<L>
+where
+    Q: Clone,
+    L: Lower<'a, D, Q> + ?Sized,
+{
+    fn lower_into(
+        &self,
+        m: &mut DataTransducer<'a, D, Q>,
+        source: usize,
+        target: usize,
+    ) {
+        (**self).lower_into(m, source, target)
+    }
+}
+
+impl<'a, D, Q, F> Lower<'a, D, Q> for Epsilon<Q, D, Q, F>
+where
+    Q: Clone,
+    F: 'a + Fn(Q) -> Q + Clone,
+{
+    fn lower_into(
+        &self,
+        m: &mut DataTransducer<'a, D, Q>,
+        source: usize,
+        target: usize,
+    ) {
+        let action = self.action.clone();
+        m.add_epsilon1(source, target, move |q| action(q.clone()));
+    }
+}
+
+impl<'a, D, Q, G, F> Lower<'a, D, Q> for Atom<Q, D, Q, G, F>
+where
+    Q: Clone,
+    G: 'a + Fn(&D) -> bool + Clone,
+    F: 'a + Fn(Q, &D) -> Q + Clone,
+{
+    fn lower_into(
+        &self,
+        m: &mut DataTransducer<'a, D, Q>,
+        source: usize,
+        target: usize,
+    ) {
+        let guard = self.guard.clone();
+        let action = self.action.clone();
+        m.add_transition1(source, target, guard, move |d, q| {
+            action(q.clone(), d)
+        });
+    }
+}
+
+impl<'a, D, Q, M1, M2> Lower<'a, D, Q> for Union<Q, D, Q, M1, M2>
+where
+    Q: Clone,
+    M1: Transducer<Q, D, Q> + Lower<'a, D, Q>,
+    M2: Transducer<Q, D, Q> + Lower<'a, D, Q>,
+{
+    fn lower_into(
+        &self,
+        m: &mut DataTransducer<'a, D, Q>,
+        source: usize,
+        target: usize,
+    ) {
+        // Both fragments read source and write target
+        self.m1.lower_into(m, source, target);
+        self.m2.lower_into(m, source, target);
+    }
+}
+
+impl<'a, D, Q, M1, M2> Lower<'a, D, Q> for Concat<D, Q, Q, Q, M1, M2>
+where
+    Q: Clone,
+    M1: Transducer<Q, D, Q> + Lower<'a, D, Q>,
+    M2: Transducer<Q, D, Q> + Lower<'a, D, Q>,
+{
+    fn lower_into(
+        &self,
+        m: &mut DataTransducer<'a, D, Q>,
+        source: usize,
+        target: usize,
+    ) {
+        let mid = m.add_state();
+        self.m1.lower_into(m, source, mid);
+        self.m2.lower_into(m, mid, target);
+    }
+}
+
+impl<'a, D, Q, M> Lower<'a, D, Q> for Iterate<Q, D, M>
+where
+    Q: Clone + Debug + Eq,
+    M: Transducer<Q, D, Q> + Lower<'a, D, Q>,
+{
+    fn lower_into(
+        &self,
+        m: &mut DataTransducer<'a, D, Q>,
+        source: usize,
+        target: usize,
+    ) {
+        // loop_st accumulates the input and the output of each iteration,
+        // and feeds back into the sub-machine. If the sub-machine is
+        // nullable this is an epsilon cycle, which evaluates to Many,
+        // matching the loopy case of Iterate.
+        let loop_st = m.add_state();
+        let sub_out = m.add_state();
+        m.add_epsilon_iden(source, loop_st);
+        self.m.lower_into(m, loop_st, sub_out);
+        m.add_epsilon_iden(sub_out, loop_st);
+        m.add_epsilon_iden(loop_st, target);
+    }
+}
+
+impl<'a, D, Q, M> Lower<'a, D, Q> for TopWrapper<Q, D, Q, M>
+where
+    Q: Clone,
+    M: Transducer<Q, D, Q> + Lower<'a, D, Q>,
+{
+    fn lower_into(
+        &self,
+        m: &mut DataTransducer<'a, D, Q>,
+        source: usize,
+        target: usize,
+    ) {
+        self.m.lower_into(m, source, target)
+    }
+}
+
+/*
+    Conformance harness: run a QRE and its lowering side by side.
+
+    A DataTransducer reports the final state accumulated over the whole
+    step on .init(), so on a restart it reports the union of the outputs
+    since the last item, rather than the contribution of the restart alone.
+    The QRE outputs are converted to this form before comparing.
+*/
+
+// The first point where a QRE and its lowering disagree
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Divergence<D, Q> {
+    // The stream, cut off after the differing output
+    pub stream: Vec<RInput<Q, D>>,
+    // Outputs on the stream (the last ones differ)
+    pub qre_outputs: Vec<Ext<Q>>,
+    pub machine_outputs: Vec<Ext<Q>>,
+    // Debug output of the lowered machine
+    pub machine: String,
+}
+
+impl<D: Debug, Q: Debug> fmt::Display for Divergence<D, Q> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let i = self.stream.len() - 1;
+        writeln!(f, "lowered machine diverges from the QRE at input {}", i)?;
+        writeln!(f, "  stream: {:?}", self.stream)?;
+        writeln!(f, "  QRE outputs: {:?}", self.qre_outputs)?;
+        writeln!(f, "  machine outputs: {:?}", self.machine_outputs)?;
+        writeln!(
+            f,
+            "  at {:?}: QRE gives {:?}, machine gives {:?}",
+            self.stream[i], self.qre_outputs[i], self.machine_outputs[i]
+        )?;
+        writeln!(f, "  machine: {:?}", self.machine)
+    }
+}
+
+// Compare on one stream, with the QRE already lowered to machine. Both
+// are reset first.
+fn conformance_on<'a, D, Q, M>(
+    qre: &mut M,
+    machine: &mut DataTransducer<'a, D, Q>,
+    rstrm: &[RInput<Q, D>],
+) -> Result<(), Divergence<D, Q>>
+where
+    D: Clone + Debug,
+    Q: Clone + Debug + Eq,
+    M: Transducer<Q, D, Q>,
+{
+    qre.reset();
+    machine.reset();
+    let mut step = Ext::None;
+    let mut qre_outputs = Vec::with_capacity(rstrm.len());
+    let mut machine_outputs = Vec::with_capacity(rstrm.len());
+    for (i, item) in rstrm.iter().enumerate() {
+        let (out, m_out) = match item {
+            RInput::Restart(q) => {
+                step += qre.init_one(q.clone());
+                (step.clone(), machine.init_one(q.clone()))
+            }
+            RInput::Item(d) => {
+                step = qre.update(d);
+                (step.clone(), machine.update(d))
+            }
+        };
+        let differ = out != m_out;
+        qre_outputs.push(out);
+        machine_outputs.push(m_out);
+        if differ {
+            return Err(Divergence {
+                stream: rstrm[..=i].to_vec(),
+                qre_outputs,
+                machine_outputs,
+                machine: format!("{:?}", machine),
+            });
+        }
+    }
+    Ok(())
+}
+
+// Check that the QRE and its lowering agree on each of the given streams.
+// The QRE is reset before each stream.
+pub fn conformance<'a, D, Q, M>(
+    qre: &mut M,
+    streams: &[Vec<RInput<Q, D>>],
+) -> Result<(), Divergence<D, Q>>
+where
+    D: 'a + Clone + Debug,
+    Q: 'a + Clone + Debug + Eq,
+    M: Transducer<Q, D, Q> + Lower<'a, D, Q>,
+{
+    let mut machine = qre.lower();
+    for rstrm in streams {
+        conformance_on(qre, &mut machine, rstrm)?;
+    }
+    Ok(())
+}
+
+// Same, on n_streams random streams of inputs from the alphabet, of length
+// at most max_len
+pub fn conformance_random<'a, D, Q, M>(
+    qre: &mut M,
+    alphabet: &[RInput<Q, D>],
+    n_streams: usize,
+    max_len: usize,
+    rng: &mut Rng,
+) -> Result<(), Divergence<D, Q>>
+where
+    D: 'a + Clone + Debug,
+    Q: 'a + Clone + Debug + Eq,
+    M: Transducer<Q, D, Q> + Lower<'a, D, Q>,
+{
+    if alphabet.is_empty() {
+        return Ok(());
+    }
+    let mut machine = qre.lower();
+    for _ in 0..n_streams {
+        let len = rng.below(max_len as u64 + 1) as usize;
+        let rstrm: Vec<RInput<Q, D>> = (0..len)
+            .map(|_| {
+                alphabet[rng.below(alphabet.len() as u64) as usize].clone()
+            })
+            .collect();
+        conformance_on(qre, &mut machine, &rstrm)?;
+    }
+    Ok(())
+}
+
+// Both of the above, panicking with the divergence if there is one (for
+// use in tests). The random streams are generated from a fixed seed.
+pub fn assert_conforms<'a, D, Q, M>(
+    qre: &mut M,
+    streams: &[Vec<RInput<Q, D>>],
+    alphabet: &[RInput<Q, D>],
+) where
+    D: 'a + Clone + Debug,
+    Q: 'a + Clone + Debug + Eq,
+    M: Transducer<Q, D, Q> + Lower<'a, D, Q>,
+{
+    const N_STREAMS: usize = 100;
+    const MAX_LEN: usize = 10;
+    let mut rng = Rng::new(1);
+    if let Err(div) = conformance(qre, streams).and_then(|()| {
+        conformance_random(qre, alphabet, N_STREAMS, MAX_LEN, &mut rng)
+    }) {
+        panic!("{}", div);
+    }
+}
+
+/*
+    Unit tests and differential testing harness
+
+    The harness generates random small QREs (over i32 values and char items)
+    and random RInput streams, lowers each QRE to a DataTransducer, and checks
+    that the two produce identical outputs. It also checks that the QRE
+    satisfies restartability (single-transducer semantics agrees with
+    spawning a fresh transducer for each restart).
+*/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ext_value::Ext;
+    use crate::interface::RInput;
+    use crate::qre::{atom, concat, epsilon, iterate, top, union};
+
+    // (Deterministic pseudorandom generator, so that failures are
+    // reproducible from the seed)
+    use crate::random::{Rng, ALPHABET};
+
+    // Random QREs are generated as ASTs, so that the same QRE can be built
+    // several times (once per restart for the multi semantics)
+    #[derive(Clone, Debug)]
+    enum Ast {
+        Eps(i32),
+        Atom(char, i32),
+        Union(Box<Ast>, Box<Ast>),
+        Concat(Box<Ast>, Box<Ast>),
+        Iter(Box<Ast>),
+        Top(Box<Ast>),
+    }
+
+    fn gen_ast(rng: &mut Rng, depth: usize) -> Ast {
+        let choice = if depth == 0 { rng.below(2) } else { rng.below(6) };
+        let sub = |rng: &mut Rng| Box::new(gen_ast(rng, depth - 1));
+        match choice {
+            0 => Ast::Eps(rng.below(3) as i32),
+            1 => {
+                let ch = ALPHABET[rng.below(ALPHABET.len() as u64) as usize];
+                Ast::Atom(ch, rng.below(10) as i32)
+            }
+            2 => Ast::Union(sub(rng), sub(rng)),
+            3 | 4 => Ast::Concat(sub(rng), sub(rng)),
+            _ if rng.below(3) == 0 => Ast::Top(sub(rng)),
+            _ => Ast::Iter(sub(rng)),
+        }
+    }
+
+    fn gen_rstream(rng: &mut Rng, max_len: u64) -> Vec<RInput<i32, char>> {
+        let len = rng.below(max_len + 1);
+        (0..len)
+            .map(|_| {
+                if rng.below(4) == 0 {
+                    RInput::Restart(rng.below(100) as i32)
+                } else {
+                    let i = rng.below(ALPHABET.len() as u64) as usize;
+                    RInput::Item(ALPHABET[i])
+                }
+            })
+            .collect()
+    }
+
+    trait Qre: Transducer<i32, char, i32> + Lower<'static, char, i32> {}
+    impl<M> Qre for M where M: Transducer<i32, char, i32> + Lower<'static, char, i32>
+    {}
+
+    fn build(ast: &Ast) -> Box<dyn Qre> {
+        match ast {
+            &Ast::Eps(k) => Box::new(epsilon(move |i: i32| i.wrapping_add(k))),
+            &Ast::Atom(ch, k) => Box::new(atom(
+                move |&d: &char| d == ch,
+                move |i: i32, _d: &char| i.wrapping_mul(2).wrapping_add(k),
+            )),
+            Ast::Union(a1, a2) => Box::new(union(build(a1), build(a2))),
+            Ast::Concat(a1, a2) => Box::new(concat(build(a1), build(a2))),
+            Ast::Iter(a) => Box::new(iterate(build(a))),
+            Ast::Top(a) => Box::new(top(build(a))),
+        }
+    }
+
+    // Multi-restart semantics: a fresh QRE for each restart
+    fn process_multi(ast: &Ast, rstrm: &[RInput<i32, char>]) -> Vec<Ext<i32>> {
+        let mut qres: Vec<Box<dyn Qre>> = Vec::new();
+        let mut result = Vec::new();
+        for item in rstrm {
+            result.push(match item {
+                &RInput::Restart(i) => {
+                    qres.push(build(ast));
+                    qres.last_mut().unwrap().init_one(i)
+                }
+                RInput::Item(d) => qres
+                    .iter_mut()
+                    .map(|m| m.update(d))
+                    .fold(Ext::None, |x, y| x + y),
+            });
+        }
+        result
+    }
+
+    fn process_single<M>(
+        m: &mut M,
+        rstrm: &[RInput<i32, char>],
+    ) -> Vec<Ext<i32>>
+    where
+        M: Transducer<i32, char, i32>,
+    {
+        m.process_rstream_single(rstrm.iter().cloned()).collect()
+    }
+
+    fn check_differential(ast: &Ast, rstrm: &[RInput<i32, char>]) {
+        let mut qre = build(ast);
+        let single = process_single(&mut qre, rstrm);
+        let multi = process_multi(ast, rstrm);
+        assert_eq!(
+            single, multi,
+            "restartability failed: {:?} on {:?}",
+            ast, rstrm
+        );
+        if let Err(div) = conformance(&mut build(ast), &[rstrm.to_vec()]) {
+            panic!("lowering disagrees: {:?}\n{}", ast, div);
+        }
+        let mut machine = build(ast).lower();
+        let lowered = process_single(&mut machine, rstrm);
+        // Epsilon elimination, dead state removal, and minimization preserve
+        // the semantics (when they apply)
+        let mut machine = build(ast).lower();
+        if machine.eliminate_epsilons().is_ok() {
+            machine.remove_dead_states();
+            machine.minimize(ALPHABET);
+            let eliminated = process_single(&mut machine, rstrm);
+            assert_eq!(
+                lowered, eliminated,
+                "epsilon elimination disagrees: {:?} on {:?}\nmachine: {:?}",
+                ast, rstrm, machine
+            );
+        }
+    }
+
+    #[test]
+    fn test_lower_atom_concat() {
+        let m1 = atom(|&ch: &char| ch == 'a', |i: i32, _ch| i + 1);
+        let m2 = atom(|&ch: &char| ch == 'b', |i: i32, _ch| i * 10);
+        let q = concat(m1, m2);
+        let mut m = q.lower();
+        assert_eq!(m.n_states(), 3);
+        assert_eq!(m.n_transs(), 2);
+        assert!(!m.is_nullable());
+        assert_eq!(m.init_one(1), Ext::None);
+        assert_eq!(m.update_val('a'), Ext::None);
+        assert_eq!(m.update_val('b'), Ext::One(20));
+        assert_eq!(m.update_val('b'), Ext::None);
+    }
+
+    #[test]
+    fn test_lower_iterate() {
+        let m1 = atom(|&ch: &char| ch == 'a', |i: i32, _ch| i + 1);
+        let q = iterate(m1);
+        let mut m = q.lower();
+        assert!(m.is_nullable());
+        assert_eq!(m.init_one(1), Ext::One(1));
+        assert_eq!(m.update_val('a'), Ext::One(2));
+        assert_eq!(m.update_val('a'), Ext::One(3));
+        assert_eq!(m.update_val('b'), Ext::None);
+        // Loopy case
+        let q = iterate(union(epsilon(|i: i32| i), atom_a()));
+        let mut m = q.lower();
+        assert_eq!(m.init_one(1), Ext::Many);
+        assert_eq!(m.update_val('a'), Ext::Many);
+    }
+    fn atom_a() -> impl Qre + Clone {
+        atom(|&ch: &char| ch == 'a', |i: i32, _ch| i + 1)
+    }
+
+    #[test]
+    fn test_differential_fixed() {
+        use Ast::*;
+        let ast = Iter(Box::new(Concat(
+            Box::new(Atom('a', 1)),
+            Box::new(Union(Box::new(Atom('b', 2)), Box::new(Eps(1)))),
+        )));
+        let rstrm = [
+            RInput::Restart(3),
+            RInput::Item('a'),
+            RInput::Item('b'),
+            RInput::Restart(4),
+            RInput::Item('a'),
+            RInput::Item('a'),
+            RInput::Item('c'),
+        ];
+        check_differential(&ast, &rstrm);
+    }
+
+    // A QRE with a deliberately wrong lowering, for testing the harness
+    #[derive(Clone)]
+    struct Mislowered<M>(M);
+    impl<M: Transducer<i32, char, i32>> Transducer<i32, char, i32>
+        for Mislowered<M>
+    {
+        fn init(&mut self, i: Ext<i32>) -> Ext<i32> {
+            self.0.init(i)
+        }
+        fn update(&mut self, item: &char) -> Ext<i32> {
+            self.0.update(item)
+        }
+        fn reset(&mut self) {
+            self.0.reset()
+        }
+        fn is_epsilon(&self) -> bool {
+            self.0.is_epsilon()
+        }
+        fn is_restartable(&self) -> bool {
+            self.0.is_restartable()
+        }
+        fn is_nullable(&self) -> bool {
+            self.0.is_nullable()
+        }
+        fn n_states(&self) -> usize {
+            self.0.n_states()
+        }
+        fn n_transs(&self) -> usize {
+            self.0.n_transs()
+        }
+    }
+    impl<M> Lower<'static, char, i32> for Mislowered<M> {
+        fn lower_into(
+            &self,
+            m: &mut DataTransducer<'static, char, i32>,
+            source: usize,
+            target: usize,
+        ) {
+            // Wrong on 'b'
+            m.add_transition1(source, target, |_| true, |_, &q| q + 1);
+        }
+    }
+
+    #[test]
+    fn test_conformance() {
+        let alphabet =
+            [RInput::Restart(0), RInput::Item('a'), RInput::Item('b')];
+        let streams = vec![vec![RInput::Restart(1), RInput::Item('a')]];
+        assert_conforms(&mut iterate(atom_a()), &streams, &alphabet);
+        let mut bad = Mislowered(atom_a());
+        assert_eq!(conformance(&mut bad, &streams), Ok(()));
+        let streams = vec![
+            vec![RInput::Restart(1), RInput::Item('a')],
+            vec![RInput::Restart(1), RInput::Item('b'), RInput::Item('a')],
+        ];
+        let div = conformance(&mut bad, &streams).unwrap_err();
+        assert_eq!(div.stream, vec![RInput::Restart(1), RInput::Item('b')]);
+        assert_eq!(div.qre_outputs, vec![Ext::None, Ext::None]);
+        assert_eq!(div.machine_outputs, vec![Ext::None, Ext::One(2)]);
+        let msg = div.to_string();
+        assert!(msg
+            .starts_with("lowered machine diverges from the QRE at input 1\n"));
+        assert!(
+            msg.contains("at Item('b'): QRE gives None, machine gives One(2)")
+        );
+        let mut rng = Rng::new(1);
+        let div = conformance_random(&mut bad, &alphabet, 100, 5, &mut rng)
+            .unwrap_err();
+        assert_eq!(div.stream.last(), Some(&RInput::Item('b')));
+    }
+
+    #[test]
+    fn test_differential_random() {
+        let mut rng = Rng::new(0x2545_f491_4f6c_dd1d);
+        for _ in 0..500 {
+            let ast = gen_ast(&mut rng, 3);
+            for _ in 0..10 {
+                let rstrm = gen_rstream(&mut rng, 8);
+                check_differential(&ast, &rstrm);
+            }
+        }
+    }
+}
@@ -0,0 +1,761 @@
+/*
+    Command-line interface: run a query over a stream of items.
+
+    Usage: data-transducers [options] [<machine file>]
+        -m, --machine <file>   state machine in the text format
+                               (see text_format.rs)
+        -p, --pattern <name>   built-in query (see PATTERNS below)
+        -i, --input <file>     read items from a file instead of stdin
+        --init <n>             initial value (default 0)
+        --restart <tag>        items with this tag are restarts, with their
+                               value as the new initial value
+        --format <format>      how to print outputs:
+                                 lines: one line per item, empty if there
+                                   is no output (the default)
+                                 changes: "<item number>: <output>", only
+                                   for items with an output
+                                 json: one JSON object per item,
+                                   {"output": <output>, "step": <item
+                                   number>} (with the "json" feature)
+        --repl                 interactive mode (see below), starting
+                               from the query if one is given
+        --list                 list the built-in queries
+        -h, --help             print this message
+
+    Items are read one per line, each a tag and an optional integer value
+    (e.g. "a 5"); blank lines and lines starting with # are skipped.
+    Machines may use the guards "is_<tag>" (for any tag), "pos", "neg", and
+    "zero" on the item, and the actions in builtin_table() below.
+
+    In interactive mode, each line is one of:
+        a line of the text format ("state", "update", etc.), which is added
+            to the machine (the machine then starts over, as after :reset)
+        an item, which is fed to the query, printing the output
+        :init <n>      restart with initial value n
+        :item <item>   feed an item whose tag is a keyword of the text format
+        :states        print the values of the states of the machine
+        :trace         toggle printing the transitions fired on each step
+        :reset         discard the state values, and initialize with 0
+        :show          print the machine
+        :load <file>   replace the machine with one from a file
+        :pattern <name>  switch to a built-in query
+        :help, :quit
+    The state values and traces are only available for machines, not the
+    built-in queries.
+*/
+
+use data_transducers::ext_value::Ext;
+use data_transducers::interface::Transducer;
+#[cfg(feature = "json")]
+use data_transducers::io::JsonLinesSink;
+use data_transducers::io::{drain, ExtPolicy, OutputPolicy, Sink, WriteSink};
+use data_transducers::qre::{atom_univ, iterate};
+use data_transducers::state_machine::DataTransducer;
+use data_transducers::text_format::{parse_machine, FnTable};
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Write};
+use std::process;
+
+const USAGE: &str = "\
+usage: data-transducers [options] [<machine file>]
+    -m, --machine <file>   state machine in the text format
+    -p, --pattern <name>   built-in query (see --list)
+    -i, --input <file>     read items from a file instead of stdin
+    --init <n>             initial value (default 0)
+    --restart <tag>        items with this tag restart the query
+    --format <format>      lines (default), changes, or json (with the
+                           json feature)
+    --repl                 interactive mode (type :help)
+    --list                 list the built-in queries
+    -h, --help             print this message";
+
+/*
+    Items and queries
+*/
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+struct Item {
+    tag: String,
+    value: i64,
+}
+
+fn parse_item(line: &str) -> Result<Item, String> {
+    let mut words = line.split_whitespace();
+    let tag = words.next().ok_or("empty item")?.to_string();
+    let value = match words.next() {
+        Some(v) => v.parse().map_err(|_| format!("bad value {:?}", v))?,
+        None => 0,
+    };
+    if words.next().is_some() {
+        return Err(format!("too many fields in item {:?}", line));
+    }
+    Ok(Item { tag, value })
+}
+
+type Query = Box<dyn Transducer<i64, Item, i64>>;
+type Machine = DataTransducer<'static, Item, i64>;
+
+// The functions available to machines. The guards on the tag are
+// registered for each "is_<tag>" used in the machine text.
+fn builtin_table(text: &str) -> FnTable<'static, Item, i64> {
+    let mut table = FnTable::new();
+    for word in text.split_whitespace() {
+        if let Some(tag) = word.strip_prefix("is_") {
+            let tag = tag.to_string();
+            table.guard(word, move |d: &Item| d.tag == tag);
+        }
+    }
+    table
+        .guard("pos", |d: &Item| d.value > 0)
+        .guard("neg", |d: &Item| d.value < 0)
+        .guard("zero", |d: &Item| d.value == 0)
+        .field("value", |d: &Item| d.value)
+        .action0("value", |d: &Item| d.value)
+        .action0("zero", |_| 0)
+        .action0("one", |_| 1)
+        .action1("value", |d: &Item, _| d.value)
+        .action1("one", |_, _| 1)
+        .action1("add_value", |d: &Item, &q| q.wrapping_add(d.value))
+        .action1("add_one", |_, &q| q.wrapping_add(1))
+        .action1("max_value", |d: &Item, &q| q.max(d.value))
+        .action1("min_value", |d: &Item, &q| q.min(d.value))
+        .action2("add", |_, &q1, &q2| q1.wrapping_add(q2))
+        .action2("max", |_, &q1, &q2| q1.max(q2))
+        .action2("min", |_, &q1, &q2| q1.min(q2))
+        .action2("divide", |_, &q1, &q2| q1.checked_div(q2).unwrap_or(0))
+        .eps_action0("zero", || 0)
+        .eps_action1("add_one", |&q| q.wrapping_add(1))
+        .eps_action2("add", |&q1, &q2| q1.wrapping_add(q2))
+        .eps_action2("max", |&q1, &q2| q1.max(q2))
+        .eps_action2("min", |&q1, &q2| q1.min(q2))
+        .eps_action2("divide", |&q1, &q2| q1.checked_div(q2).unwrap_or(0));
+    table
+}
+
+// Built-in queries (QREs), by name, with a description
+type Pattern = (&'static str, &'static str, fn() -> Query);
+
+const PATTERNS: &[Pattern] = &[
+    ("count", "number of items, plus the initial value", || {
+        Box::new(iterate(atom_univ(|n: i64, _: &Item| n.wrapping_add(1))))
+    }),
+    ("sum", "sum of the values, plus the initial value", || {
+        Box::new(iterate(atom_univ(|s: i64, d: &Item| s.wrapping_add(d.value))))
+    }),
+    ("max", "maximum of the values and the initial value", || {
+        Box::new(iterate(atom_univ(|m: i64, d: &Item| m.max(d.value))))
+    }),
+    ("min", "minimum of the values and the initial value", || {
+        Box::new(iterate(atom_univ(|m: i64, d: &Item| m.min(d.value))))
+    }),
+    ("last", "value of the last item", || {
+        Box::new(iterate(atom_univ(|_: i64, d: &Item| d.value)))
+    }),
+];
+
+fn pattern(name: &str) -> Result<Query, String> {
+    match PATTERNS.iter().find(|p| p.0 == name) {
+        Some(p) => Ok((p.2)()),
+        None => Err(format!("no built-in query {:?} (see --list)", name)),
+    }
+}
+
+fn read_file(path: &str) -> Result<String, String> {
+    fs::read_to_string(path).map_err(|e| format!("can't read {}: {}", path, e))
+}
+
+fn machine_from_text(text: &str) -> Result<Machine, String> {
+    parse_machine(text, &builtin_table(text)).map_err(|e| e.to_string())
+}
+
+fn load_machine(path: &str) -> Result<Query, String> {
+    let m = machine_from_text(&read_file(path)?)
+        .map_err(|e| format!("in {}: {}", path, e))?;
+    Ok(Box::new(m))
+}
+
+/*
+    Options
+*/
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Format {
+    Lines,
+    Changes,
+    #[cfg(feature = "json")]
+    Json,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum QuerySource {
+    Machine(String),
+    Pattern(String),
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+struct Options {
+    query: QuerySource,
+    input: Option<String>,
+    init: i64,
+    restart: Option<String>,
+    format: Format,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum Command {
+    Run(Options),
+    Repl(Option<QuerySource>),
+    List,
+    Help,
+}
+
+fn parse_args(args: &[String]) -> Result<Command, String> {
+    let mut query = None;
+    let mut input = None;
+    let mut init = 0;
+    let mut restart = None;
+    let mut format = Format::Lines;
+    let mut repl = false;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next().cloned().ok_or(format!("{} expects an argument", arg))
+        };
+        let new_query = match arg.as_str() {
+            "-h" | "--help" => return Ok(Command::Help),
+            "--list" => return Ok(Command::List),
+            "--repl" => {
+                repl = true;
+                None
+            }
+            "-m" | "--machine" => Some(QuerySource::Machine(value()?)),
+            "-p" | "--pattern" => Some(QuerySource::Pattern(value()?)),
+            "-i" | "--input" => {
+                input = Some(value()?);
+                None
+            }
+            "--init" => {
+                let v = value()?;
+                init = v.parse().map_err(|_| format!("bad --init {:?}", v))?;
+                None
+            }
+            "--restart" => {
+                restart = Some(value()?);
+                None
+            }
+            "--format" => {
+                format = match value()?.as_str() {
+                    "lines" => Format::Lines,
+                    "changes" => Format::Changes,
+                    #[cfg(feature = "json")]
+                    "json" => Format::Json,
+                    #[cfg(not(feature = "json"))]
+                    "json" => {
+                        return Err(
+                            "--format json needs the json feature".to_string()
+                        )
+                    }
+                    f => return Err(format!("unknown format {:?}", f)),
+                };
+                None
+            }
+            a if a.starts_with('-') => {
+                return Err(format!("unknown option {:?}", a))
+            }
+            path => Some(QuerySource::Machine(path.to_string())),
+        };
+        if let Some(q) = new_query {
+            if query.replace(q).is_some() {
+                return Err("more than one query given".to_string());
+            }
+        }
+    }
+    if repl {
+        return Ok(Command::Repl(query));
+    }
+    let query = query.ok_or("no query given (use --machine or --pattern)")?;
+    Ok(Command::Run(Options { query, input, init, restart, format }))
+}
+
+/*
+    Running a query
+*/
+
+// The sink for the format, and how it treats items with no output or
+// several outputs
+fn sink<'w>(
+    format: Format,
+    out: &'w mut dyn Write,
+) -> (Box<dyn Sink<i64> + 'w>, OutputPolicy) {
+    let all = OutputPolicy { none: ExtPolicy::Write, many: ExtPolicy::Write };
+    match format {
+        Format::Lines => (Box::new(WriteSink::new(out)), all),
+        Format::Changes => {
+            (Box::new(WriteSink::new(out).numbered()), OutputPolicy::default())
+        }
+        #[cfg(feature = "json")]
+        Format::Json => (Box::new(JsonLinesSink::new(out)), all),
+    }
+}
+
+// Run the query on the items, numbered from 1 (the output on the initial
+// value is not printed), stopping at the first malformed item
+fn run(
+    query: &mut Query,
+    opts: &Options,
+    input: &mut dyn BufRead,
+    out: &mut dyn Write,
+) -> Result<(), String> {
+    query.init_one(opts.init);
+    let items = input.lines().enumerate().filter_map(|(line_no, line)| {
+        let line = match line {
+            Ok(line) => line,
+            Err(e) => return Some(Err(e.to_string())),
+        };
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            return None;
+        }
+        Some(
+            parse_item(line)
+                .map_err(|e| format!("line {}: {}", line_no + 1, e)),
+        )
+    });
+    let mut error = None;
+    let outputs = items.map_while(|item| match item {
+        Ok(item) if opts.restart.as_ref() == Some(&item.tag) => {
+            Some(query.init_one(item.value))
+        }
+        Ok(item) => Some(query.update(&item)),
+        Err(e) => {
+            error = Some(e);
+            None
+        }
+    });
+    let (mut sink, policy) = sink(opts.format, out);
+    drain(outputs, &mut *sink, policy).map_err(|e| e.to_string())?;
+    error.map_or(Ok(()), Err)
+}
+
+/*
+    Interactive mode
+*/
+
+const KEYWORDS: &[&str] = &["states", "state", "label", "update", "epsilon"];
+
+enum Session {
+    // A machine, and its text so far
+    Machine(Box<Machine>, String),
+    Pattern(Query),
+}
+
+struct Repl {
+    session: Session,
+    trace: bool,
+}
+
+impl Repl {
+    fn new() -> Self {
+        Self {
+            session: Session::Machine(Box::new(Machine::new()), String::new()),
+            trace: false,
+        }
+    }
+    fn load(&mut self, query: &QuerySource) -> Result<(), String> {
+        self.session = match query {
+            QuerySource::Machine(path) => {
+                let text = read_file(path)?;
+                Session::Machine(Box::new(machine_from_text(&text)?), text)
+            }
+            QuerySource::Pattern(name) => Session::Pattern(pattern(name)?),
+        };
+        self.restart(0);
+        Ok(())
+    }
+    fn query(&mut self) -> &mut dyn Transducer<i64, Item, i64> {
+        match &mut self.session {
+            Session::Machine(m, _) => m,
+            Session::Pattern(q) => q,
+        }
+    }
+    fn restart(&mut self, init: i64) -> String {
+        self.step(|q| q.init_one(init))
+    }
+    // Run one step, returning the output, preceded by the step as recorded
+    // if tracing
+    fn step<F>(&mut self, f: F) -> String
+    where
+        F: FnOnce(&mut dyn Transducer<i64, Item, i64>) -> Ext<i64>,
+    {
+        match (&mut self.session, self.trace) {
+            (Session::Machine(m, _), true) => {
+                m.start_recording();
+                let out = f(m);
+                let mut result = String::new();
+                for step in m.stop_recording() {
+                    result += &format!("  {}\n", step);
+                }
+                result + &out.to_string()
+            }
+            _ => f(self.query()).to_string(),
+        }
+    }
+    fn machine(&mut self) -> Result<(&mut Machine, &mut String), String> {
+        match &mut self.session {
+            Session::Machine(m, text) => Ok((&mut **m, text)),
+            Session::Pattern(_) => {
+                Err("not available for built-in queries".to_string())
+            }
+        }
+    }
+
+    // Handle a line of input, returning what to print (or None to quit)
+    fn handle(&mut self, line: &str) -> Result<Option<String>, String> {
+        let line = line.trim();
+        let (cmd, arg) = match line.find(char::is_whitespace) {
+            Some(i) => (&line[..i], line[i..].trim()),
+            None => (line, ""),
+        };
+        let out = match cmd {
+            "" => String::new(),
+            _ if cmd.starts_with('#') => String::new(),
+            ":quit" | ":q" => return Ok(None),
+            ":help" => REPL_HELP.to_string(),
+            ":init" => {
+                let init =
+                    arg.parse().map_err(|_| format!("bad value {:?}", arg))?;
+                self.restart(init)
+            }
+            ":item" => {
+                let item = parse_item(arg)?;
+                self.step(|q| q.update(&item))
+            }
+            ":reset" => {
+                self.query().reset();
+                self.restart(0)
+            }
+            ":states" => {
+                let (m, _) = self.machine()?;
+                let mut out = String::new();
+                for state in m.states() {
+                    let name = state.name.map(|n| format!(" ({})", n));
+                    out += &format!(
+                        "{}{}: {}\n",
+                        state.id,
+                        name.unwrap_or_default(),
+                        state.value
+                    );
+                }
+                out
+            }
+            ":trace" => {
+                self.trace = !self.trace;
+                format!("tracing {}", if self.trace { "on" } else { "off" })
+            }
+            ":show" => self.machine()?.1.clone(),
+            ":load" => {
+                self.load(&QuerySource::Machine(arg.to_string()))?;
+                format!("loaded {}", arg)
+            }
+            ":pattern" => {
+                self.load(&QuerySource::Pattern(arg.to_string()))?;
+                format!("using {}", arg)
+            }
+            _ if cmd.starts_with(':') => {
+                return Err(format!("unknown command {} (see :help)", cmd))
+            }
+            _ if KEYWORDS.contains(&cmd) => {
+                let (_, text) = self.machine()?;
+                let new_text = format!("{}{}\n", text, line);
+                let m = machine_from_text(&new_text)?;
+                self.session = Session::Machine(Box::new(m), new_text);
+                self.restart(0);
+                String::new()
+            }
+            _ => {
+                let item = parse_item(line)?;
+                self.step(|q| q.update(&item))
+            }
+        };
+        Ok(Some(out))
+    }
+
+    fn run(
+        &mut self,
+        input: &mut dyn BufRead,
+        out: &mut dyn Write,
+    ) -> Result<(), String> {
+        let io_err = |e: io::Error| e.to_string();
+        loop {
+            write!(out, "> ").and_then(|()| out.flush()).map_err(io_err)?;
+            let mut line = String::new();
+            if input.read_line(&mut line).map_err(io_err)? == 0 {
+                return Ok(());
+            }
+            match self.handle(&line) {
+                Ok(None) => return Ok(()),
+                Ok(Some(s)) if s.is_empty() => {}
+                Ok(Some(s)) => {
+                    writeln!(out, "{}", s.trim_end()).map_err(io_err)?
+                }
+                Err(err) => writeln!(out, "error: {}", err).map_err(io_err)?,
+            }
+        }
+    }
+}
+
+const REPL_HELP: &str = "\
+<text format line>  add to the machine (e.g. update 0 -> 1 when is_a do value)
+<tag> [<value>]     feed an item
+:init <n>           restart with initial value n
+:item <item>        feed an item whose tag is a keyword of the text format
+:states             print the state values
+:trace              toggle printing the transitions fired on each step
+:reset              discard the state values, and initialize with 0
+:show               print the machine
+:load <file>        replace the machine with one from a file
+:pattern <name>     switch to a built-in query (see --list)
+:quit";
+
+fn main_inner(args: &[String]) -> Result<(), String> {
+    let opts = match parse_args(args)? {
+        Command::Help => {
+            println!("{}", USAGE);
+            return Ok(());
+        }
+        Command::List => {
+            for (name, description, _) in PATTERNS {
+                println!("{:8} {}", name, description);
+            }
+            return Ok(());
+        }
+        Command::Repl(query) => {
+            let mut repl = Repl::new();
+            if let Some(query) = query {
+                repl.load(&query)?;
+            }
+            let stdout = io::stdout();
+            return repl.run(&mut io::stdin().lock(), &mut stdout.lock());
+        }
+        Command::Run(opts) => opts,
+    };
+    let mut query = match &opts.query {
+        QuerySource::Machine(path) => load_machine(path)?,
+        QuerySource::Pattern(name) => pattern(name)?,
+    };
+    let mut input: Box<dyn BufRead> = match &opts.input {
+        Some(path) => Box::new(BufReader::new(
+            File::open(path)
+                .map_err(|e| format!("can't read {}: {}", path, e))?,
+        )),
+        None => Box::new(BufReader::new(io::stdin())),
+    };
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    run(&mut query, &opts, &mut input, &mut out)
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.is_empty() {
+        eprintln!("{}", USAGE);
+        process::exit(2);
+    }
+    if let Err(err) = main_inner(&args) {
+        eprintln!("error: {}", err);
+        process::exit(1);
+    }
+}
+
+/*
+    Unit Tests
+*/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(s: &str) -> Vec<String> {
+        s.split_whitespace().map(String::from).collect()
+    }
+
+    fn run_str(query: &mut Query, opts: &Options, input: &str) -> String {
+        let mut out = Vec::new();
+        run(query, opts, &mut input.as_bytes(), &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    fn options(query: QuerySource) -> Options {
+        Options {
+            query,
+            input: None,
+            init: 0,
+            restart: None,
+            format: Format::Lines,
+        }
+    }
+
+    #[test]
+    fn test_parse_args() {
+        let pattern = QuerySource::Pattern("sum".to_string());
+        assert_eq!(
+            parse_args(&args("-p sum")),
+            Ok(Command::Run(options(pattern.clone())))
+        );
+        assert_eq!(
+            parse_args(&args("--init 5 --restart r -p sum --format changes")),
+            Ok(Command::Run(Options {
+                init: 5,
+                restart: Some("r".to_string()),
+                format: Format::Changes,
+                ..options(pattern)
+            }))
+        );
+        let machine = QuerySource::Machine("m.txt".to_string());
+        assert_eq!(
+            parse_args(&args("m.txt -i items.txt")),
+            Ok(Command::Run(Options {
+                input: Some("items.txt".to_string()),
+                ..options(machine)
+            }))
+        );
+        assert_eq!(parse_args(&args("-p sum --list")), Ok(Command::List));
+        assert_eq!(parse_args(&args("--help")), Ok(Command::Help));
+        assert_eq!(parse_args(&args("--repl")), Ok(Command::Repl(None)));
+        assert_eq!(
+            parse_args(&args("--repl -p sum")),
+            Ok(Command::Repl(Some(QuerySource::Pattern("sum".to_string()))))
+        );
+        assert!(parse_args(&args("-p sum -m m.txt")).is_err());
+        assert!(parse_args(&args("--format xml -p sum")).is_err());
+        assert_eq!(
+            parse_args(&args("--format json -p sum")).is_ok(),
+            cfg!(feature = "json")
+        );
+        assert!(parse_args(&args("--init x -p sum")).is_err());
+        assert!(parse_args(&args("-p")).is_err());
+        assert!(parse_args(&args("--bogus")).is_err());
+        assert!(parse_args(&args("-i items.txt")).is_err());
+    }
+
+    #[test]
+    fn test_patterns() {
+        let opts = options(QuerySource::Pattern("sum".to_string()));
+        let input = "a 3\n\n# comment\nb -1\nc 5\n";
+        let mut sum = pattern("sum").unwrap();
+        assert_eq!(run_str(&mut sum, &opts, input), "3\n2\n7\n");
+        let mut count = pattern("count").unwrap();
+        assert_eq!(run_str(&mut count, &opts, input), "1\n2\n3\n");
+        let mut max = pattern("max").unwrap();
+        assert_eq!(run_str(&mut max, &opts, input), "3\n3\n5\n");
+        let mut min = pattern("min").unwrap();
+        assert_eq!(run_str(&mut min, &opts, input), "0\n-1\n-1\n");
+        let mut last = pattern("last").unwrap();
+        assert_eq!(run_str(&mut last, &opts, input), "3\n-1\n5\n");
+        assert!(pattern("median").is_err());
+    }
+
+    #[test]
+    fn test_restart_and_format() {
+        let opts = Options {
+            init: 10,
+            restart: Some("r".to_string()),
+            format: Format::Changes,
+            ..options(QuerySource::Pattern("sum".to_string()))
+        };
+        let mut sum = pattern("sum").unwrap();
+        let input = "a 1\nr 100\na 2\n";
+        assert_eq!(run_str(&mut sum, &opts, input), "1: 11\n2: 100\n3: Many\n");
+        let mut sum = pattern("sum").unwrap();
+        let mut out = Vec::new();
+        let err = run(&mut sum, &opts, &mut "a 1\na 1 2".as_bytes(), &mut out);
+        assert_eq!(
+            err,
+            Err("line 2: too many fields in item \"a 1 2\"".into())
+        );
+        assert_eq!(String::from_utf8(out).unwrap(), "1: 11\n");
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn test_json_format() {
+        let opts = Options {
+            restart: Some("r".to_string()),
+            format: Format::Json,
+            ..options(QuerySource::Pattern("last".to_string()))
+        };
+        let mut last = pattern("last").unwrap();
+        assert_eq!(
+            run_str(&mut last, &opts, "r 1\na 2\n"),
+            "{\"output\":1,\"step\":1}\n{\"output\":\"many\",\"step\":2}\n"
+        );
+    }
+
+    #[test]
+    fn test_machine() {
+        let text = "
+            # Sum of the values of 'a' items, output on each 'b' item
+            state sum
+            epsilon 0 -> sum do iden
+            update sum -> sum when is_a do add_value
+            update sum -> sum when is_b do iden
+            update sum -> 1 when is_b do iden
+        ";
+        let table = builtin_table(text);
+        let mut m: Query = Box::new(parse_machine(text, &table).unwrap());
+        let opts = options(QuerySource::Machine("-".to_string()));
+        assert_eq!(
+            run_str(&mut m, &opts, "a 3\na 4\nb\nc\nb\n"),
+            "\n\n7\n\n\n"
+        );
+    }
+
+    #[test]
+    fn test_repl() {
+        let mut repl = Repl::new();
+        let mut run = |line: &str| repl.handle(line).map(Option::unwrap);
+        assert_eq!(run("state sum"), Ok(String::new()));
+        assert_eq!(run("epsilon 0 -> sum do iden"), Ok(String::new()));
+        assert_eq!(
+            run("update sum -> sum when is_a do add_value"),
+            Ok("".into())
+        );
+        assert_eq!(run("update sum -> 1 when is_b do iden"), Ok("".into()));
+        assert!(run("update sum -> 1 when is_c do nothing").is_err());
+        assert_eq!(run("a 3"), Ok("None".to_string()));
+        assert_eq!(run("a 4"), Ok("None".to_string()));
+        assert_eq!(run(":states"), Ok("0: None\n1: None\n2 (sum): 7\n".into()));
+        assert_eq!(run("b"), Ok("7".to_string()));
+        assert_eq!(run(":init 5"), Ok("7".to_string()));
+        assert_eq!(run(":reset"), Ok("None".to_string()));
+        assert_eq!(run("a 1"), Ok("None".to_string()));
+        assert_eq!(run(":item state 0"), Ok("None".to_string()));
+        assert_eq!(run(":show").unwrap().lines().count(), 4);
+        assert_eq!(run(":trace"), Ok("tracing on".to_string()));
+        assert_eq!(
+            run(":init 1"),
+            Ok("  init One(1): fired [Epsilon(0)], changed {0: One(1), \
+                2: One(1)}, output None\nNone"
+                .to_string())
+        );
+        assert_eq!(
+            run("b"),
+            Ok("  update Item { tag: \"b\", value: 0 }: fired [Update(1)], \
+                changed {0: None, 1: One(1), 2: None}, output One(1)\n1"
+                .to_string())
+        );
+        assert!(run(":bogus").is_err());
+        assert!(run("a b c").is_err());
+        assert_eq!(run(":pattern count"), Ok("using count".to_string()));
+        assert_eq!(run("x"), Ok("1".to_string()));
+        assert!(run(":states").is_err());
+        assert!(run(":pattern nope").is_err());
+        assert_eq!(repl.handle(":quit"), Ok(None));
+
+        let mut out = Vec::new();
+        let input = "a 2\n:quit\na 3\n";
+        Repl::new().run(&mut input.as_bytes(), &mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "> None\n> ");
+    }
+}
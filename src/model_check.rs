@@ -0,0 +1,307 @@
+/*
+    Exhaustive small-model checking of transducers.
+
+    Given a finite alphabet of items and initial values, and a bound on the
+    length, these check a property on every stream of restarts and items
+    up to that length (so every placement of the restarts), rather than on
+    a few hand-written streams:
+    - check_restartable(m, ..): running m on the stream (restarts being
+      .init()) gives the same outputs as running a fresh copy of m from
+      each restart and summing (see interface.rs);
+    - check_equivalent(m1, m2, ..): m1 and m2 give the same outputs;
+    - check_unambiguous(m, ..): m never outputs Ext::Many;
+    - check_streams(state, ..): any property, given as a step function
+      on a state which is cloned at each branch.
+    The result is the shortest counterexample, if any.
+
+    The search is depth-first on the tree of streams, cloning the state at
+    each node, so a stream shares the work on its prefixes; still, there
+    are alphabet.len()^max_len streams, so the bound should be small (for
+    an alphabet of 5, length 6 is already 15625 streams).
+    See also Transducer::is_restartable_bounded, which checks streams of a
+    given length only.
+*/
+
+use super::ext_value::Ext;
+use super::interface::{RInput, Transducer};
+use std::fmt::{self, Debug, Display};
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Counterexample<I, D> {
+    // The stream, up to the first step where the property fails
+    pub stream: Vec<RInput<I, D>>,
+    pub msg: String,
+}
+impl<I: Debug, D: Debug> Display for Counterexample<I, D> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "on {:?}: {}", self.stream, self.msg)
+    }
+}
+
+// The alphabet of the restarts with the given values and the items
+pub fn alphabet<I: Clone, D: Clone>(
+    inits: &[I],
+    items: &[D],
+) -> Vec<RInput<I, D>> {
+    let restarts = inits.iter().cloned().map(RInput::Restart);
+    restarts.chain(items.iter().cloned().map(RInput::Item)).collect()
+}
+
+/*
+    Generic search
+*/
+
+// Check step(state, a) on every stream of up to max_len letters from
+// state, where step advances the state by one letter and returns an error
+// message if the property fails there
+pub fn check_streams<S, I, D, F>(
+    state: S,
+    alphabet: &[RInput<I, D>],
+    max_len: usize,
+    mut step: F,
+) -> Result<(), Counterexample<I, D>>
+where
+    S: Clone,
+    I: Clone,
+    D: Clone,
+    F: FnMut(&mut S, &RInput<I, D>) -> Result<(), String>,
+{
+    let mut search = Search { alphabet, step: &mut step, best: None };
+    let mut stream = Vec::new();
+    search.dfs(&state, &mut stream, max_len);
+    match search.best {
+        None => Ok(()),
+        Some((stream, msg)) => Err(Counterexample { stream, msg }),
+    }
+}
+
+struct Search<'s, I, D, F> {
+    alphabet: &'s [RInput<I, D>],
+    step: &'s mut F,
+    // Shortest counterexample so far
+    best: Option<(Vec<RInput<I, D>>, String)>,
+}
+impl<I, D, F> Search<'_, I, D, F>
+where
+    I: Clone,
+    D: Clone,
+{
+    fn dfs<S>(&mut self, state: &S, stream: &mut Vec<RInput<I, D>>, len: usize)
+    where
+        S: Clone,
+        F: FnMut(&mut S, &RInput<I, D>) -> Result<(), String>,
+    {
+        // Only look for counterexamples shorter than the best one
+        let bound = match &self.best {
+            Some((best, _)) => len.min(best.len().saturating_sub(1)),
+            None => len,
+        };
+        if stream.len() >= bound {
+            return;
+        }
+        for a in self.alphabet {
+            let mut next = state.clone();
+            stream.push(a.clone());
+            match (self.step)(&mut next, a) {
+                Ok(()) => self.dfs(&next, stream, len),
+                Err(msg) => self.best = Some((stream.clone(), msg)),
+            }
+            stream.pop();
+            if self
+                .best
+                .as_ref()
+                .is_some_and(|(b, _)| b.len() <= stream.len() + 1)
+            {
+                // No shorter counterexample below this node
+                return;
+            }
+        }
+    }
+}
+
+fn run<I, D, O, M>(m: &mut M, a: &RInput<I, D>) -> Ext<O>
+where
+    I: Clone,
+    M: Transducer<I, D, O>,
+{
+    match a {
+        RInput::Restart(i) => m.init_one(i.clone()),
+        RInput::Item(d) => m.update(d),
+    }
+}
+
+/*
+    Properties
+*/
+
+pub fn check_restartable<I, D, O, M>(
+    m: &M,
+    alphabet: &[RInput<I, D>],
+    max_len: usize,
+) -> Result<(), Counterexample<I, D>>
+where
+    I: Clone,
+    D: Clone,
+    O: Debug + PartialEq,
+    M: Transducer<I, D, O> + Clone,
+{
+    // The single run, and one run per restart
+    let state = (m.spawn_empty(), Vec::new());
+    check_streams(state, alphabet, max_len, |(single, multi), a| {
+        let out_single = run(single, a);
+        let out_multi = match a {
+            RInput::Restart(_) => {
+                let mut fresh = m.spawn_empty();
+                let out = run(&mut fresh, a);
+                multi.push(fresh);
+                out
+            }
+            RInput::Item(_) => {
+                multi.iter_mut().fold(Ext::None, |acc, m| acc + run(m, a))
+            }
+        };
+        if out_single == out_multi {
+            Ok(())
+        } else {
+            Err(format!(
+                "the single run gave {:?}, the runs from each restart {:?}",
+                out_single, out_multi
+            ))
+        }
+    })
+}
+
+pub fn check_equivalent<I, D, O, M1, M2>(
+    m1: &M1,
+    m2: &M2,
+    alphabet: &[RInput<I, D>],
+    max_len: usize,
+) -> Result<(), Counterexample<I, D>>
+where
+    I: Clone,
+    D: Clone,
+    O: Debug + PartialEq,
+    M1: Transducer<I, D, O> + Clone,
+    M2: Transducer<I, D, O> + Clone,
+{
+    let state = (m1.spawn_empty(), m2.spawn_empty());
+    check_streams(state, alphabet, max_len, |(m1, m2), a| {
+        let (out1, out2) = (run(m1, a), run(m2, a));
+        if out1 == out2 {
+            Ok(())
+        } else {
+            Err(format!("the first gave {:?}, the second {:?}", out1, out2))
+        }
+    })
+}
+
+pub fn check_unambiguous<I, D, O, M>(
+    m: &M,
+    alphabet: &[RInput<I, D>],
+    max_len: usize,
+) -> Result<(), Counterexample<I, D>>
+where
+    I: Clone,
+    D: Clone,
+    M: Transducer<I, D, O> + Clone,
+{
+    check_streams(m.spawn_empty(), alphabet, max_len, |m, a| {
+        if run(m, a).is_many() {
+            Err("the output is Many".to_string())
+        } else {
+            Ok(())
+        }
+    })
+}
+
+/*
+    Unit Tests
+*/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::qre::{atom, concat, epsilon, iterate, union, within};
+
+    fn letters() -> Vec<RInput<i32, char>> {
+        alphabet(&[0, 5], &['a', 'b'])
+    }
+    fn count(c: char) -> impl Transducer<i32, char, i32> + Clone {
+        iterate(union(
+            atom(move |&d: &char| d == c, |x: i32, _| x + 1),
+            atom(move |&d: &char| d != c, |x: i32, _| x),
+        ))
+    }
+
+    #[test]
+    fn test_restartable() {
+        assert_eq!(check_restartable(&count('a'), &letters(), 5), Ok(()));
+        let m = concat(epsilon(|x: i32| x * 2), count('b'));
+        assert_eq!(check_restartable(&m, &letters(), 5), Ok(()));
+        // A window isn't: a restart moves the start of the window for the
+        // single run
+        let m = within(count('a'), 1);
+        let cex = check_restartable(&m, &letters(), 5).unwrap_err();
+        assert_eq!(cex.stream.len(), 3);
+        assert!(cex.to_string().starts_with("on [Restart("));
+    }
+
+    #[test]
+    fn test_equivalent() {
+        // Union is commutative, concat of epsilons composes the functions
+        let a = atom(|&d: &char| d == 'a', |x: i32, _| x + 1);
+        let b = atom(|&d: &char| d == 'b', |x: i32, _| x + 2);
+        let m1 = iterate(union(a.clone(), b.clone()));
+        let m2 = iterate(union(b, a));
+        assert_eq!(check_equivalent(&m1, &m2, &letters(), 4), Ok(()));
+        let m1 = concat(epsilon(|x: i32| x + 1), epsilon(|x: i32| x * 2));
+        let m2 = epsilon(|x: i32| (x + 1) * 2);
+        assert_eq!(check_equivalent(&m1, &m2, &letters(), 4), Ok(()));
+        // The shortest difference
+        let cex = check_equivalent(&count('a'), &count('b'), &letters(), 4)
+            .unwrap_err();
+        assert_eq!(cex.stream, vec![RInput::Restart(0), RInput::Item('a')]);
+        assert_eq!(cex.msg, "the first gave One(1), the second One(0)");
+    }
+
+    #[test]
+    fn test_unambiguous() {
+        // Two ways of matching an 'a'
+        let m = iterate(union(
+            atom(|&d: &char| d == 'a', |x: i32, _| x),
+            atom(|&d: &char| d != 'b', |x: i32, _| x),
+        ));
+        let cex = check_unambiguous(&m, &letters(), 4).unwrap_err();
+        assert_eq!(cex.stream, vec![RInput::Restart(0), RInput::Item('a')]);
+        // Restarting twice, the runs overlap from the next item
+        let cex = check_unambiguous(&count('a'), &letters(), 4).unwrap_err();
+        let expected = alphabet(&[0, 0], &['a']);
+        assert_eq!(cex.stream, expected);
+    }
+
+    #[test]
+    fn test_check_streams() {
+        // Count the streams
+        let mut n = 0;
+        let res = check_streams((), &letters(), 3, |(), _| {
+            n += 1;
+            Ok(())
+        });
+        assert_eq!(res, Ok(()));
+        assert_eq!(n, 4 + 16 + 64);
+        // A property failing on streams with two items
+        let res = check_streams(0, &letters(), 4, |k, a| {
+            if let RInput::Item(_) = a {
+                *k += 1;
+            }
+            if *k < 2 {
+                Ok(())
+            } else {
+                Err(format!("{} items", k))
+            }
+        });
+        let cex = res.unwrap_err();
+        assert_eq!(cex.stream, vec![RInput::Item('a'), RInput::Item('a')]);
+        assert_eq!(cex.msg, "2 items");
+    }
+}
@@ -0,0 +1,184 @@
+/*
+    Integer sums and counts with an overflow policy.
+
+    A running sum or count written as a closure (s + x) panics on overflow
+    in debug builds and silently wraps in release builds, which for a
+    long-running counter is a latent bug either way. These constructors make
+    the policy explicit:
+    - sum_checked(value) and count_checked() output Err(OverflowError) from
+      the first overflow on;
+    - sum_saturating(value) and count_saturating() stop at the bounds of
+      the type;
+    - sum_wrapping(value) and count_wrapping() wrap around.
+    value extracts the integer to add from each item. Like the running sum
+    in bench.rs, each takes the starting value as its initial value and
+    outputs the sum so far after .init() and each item; they are iterations
+    of an atom, so are restartable.
+
+    They are generic over the primitive integer types (see the Int trait).
+*/
+
+use super::interface::Transducer;
+use super::qre::{atom, concat, epsilon, iterate};
+use std::error::Error;
+use std::fmt::{self, Debug, Display};
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct OverflowError;
+impl Display for OverflowError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "integer overflow")
+    }
+}
+impl Error for OverflowError {}
+
+pub trait Int: Copy + Debug + Eq {
+    const ONE: Self;
+    fn checked_add(self, other: Self) -> Option<Self>;
+    fn saturating_add(self, other: Self) -> Self;
+    fn wrapping_add(self, other: Self) -> Self;
+}
+macro_rules! impl_int {
+    ($($t:ty),*) => {$(
+        impl Int for $t {
+            const ONE: Self = 1;
+            fn checked_add(self, other: Self) -> Option<Self> {
+                <$t>::checked_add(self, other)
+            }
+            fn saturating_add(self, other: Self) -> Self {
+                <$t>::saturating_add(self, other)
+            }
+            fn wrapping_add(self, other: Self) -> Self {
+                <$t>::wrapping_add(self, other)
+            }
+        }
+    )*};
+}
+impl_int!(i8, i16, i32, i64, i128, isize, u8, u16, u32, u64, u128, usize);
+
+/*
+    Sums
+*/
+
+pub fn sum_checked<D, N, F>(
+    value: F,
+) -> impl Transducer<N, D, Result<N, OverflowError>> + Clone
+where
+    N: Int,
+    F: Fn(&D) -> N + Clone,
+{
+    let step = move |s: Result<N, OverflowError>, d: &D| {
+        s.and_then(|s| s.checked_add(value(d)).ok_or(OverflowError))
+    };
+    concat(epsilon(Ok), iterate(atom(|_| true, step)))
+}
+
+pub fn sum_saturating<D, N, F>(value: F) -> impl Transducer<N, D, N> + Clone
+where
+    N: Int,
+    F: Fn(&D) -> N + Clone,
+{
+    iterate(atom(|_| true, move |s: N, d: &D| s.saturating_add(value(d))))
+}
+
+pub fn sum_wrapping<D, N, F>(value: F) -> impl Transducer<N, D, N> + Clone
+where
+    N: Int,
+    F: Fn(&D) -> N + Clone,
+{
+    iterate(atom(|_| true, move |s: N, d: &D| s.wrapping_add(value(d))))
+}
+
+/*
+    Counts
+*/
+
+pub fn count_checked<D, N>(
+) -> impl Transducer<N, D, Result<N, OverflowError>> + Clone
+where
+    N: Int,
+{
+    sum_checked(|_: &D| N::ONE)
+}
+
+pub fn count_saturating<D, N>() -> impl Transducer<N, D, N> + Clone
+where
+    N: Int,
+{
+    sum_saturating(|_: &D| N::ONE)
+}
+
+pub fn count_wrapping<D, N>() -> impl Transducer<N, D, N> + Clone
+where
+    N: Int,
+{
+    sum_wrapping(|_: &D| N::ONE)
+}
+
+/*
+    Unit Tests
+*/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ext_value::Ext;
+    use crate::interface::RInput;
+
+    #[test]
+    fn test_sums() {
+        let items = [100, 20, 7, 1, -50];
+        let mut m = sum_checked(|&x: &i8| x);
+        assert_eq!(m.init_one(0), Ext::One(Ok(0)));
+        let out: Vec<Ext<Result<i8, OverflowError>>> = m.update_batch(&items);
+        assert_eq!(out[2], Ext::One(Ok(127)));
+        // The error persists
+        assert_eq!(out[3], Ext::One(Err(OverflowError)));
+        assert_eq!(out[4], Ext::One(Err(OverflowError)));
+        m.reset();
+        assert_eq!(m.init_one(-10), Ext::One(Ok(-10)));
+        assert_eq!(m.update(&100), Ext::One(Ok(90)));
+
+        let mut m = sum_saturating(|&x: &i8| x);
+        m.init_one(0);
+        let out = m.update_batch(&items);
+        assert_eq!(out[3..], [Ext::One(127), Ext::One(77)]);
+        let mut m = sum_wrapping(|&x: &i8| x);
+        m.init_one(0);
+        let out = m.update_batch(&items);
+        assert_eq!(out[3..], [Ext::One(-128), Ext::One(78)]);
+    }
+
+    #[test]
+    fn test_counts() {
+        let mut m = count_checked::<char, u8>();
+        m.init_one(250);
+        let out = m.update_batch(&['a'; 6]);
+        assert_eq!(out[4], Ext::One(Ok(255)));
+        assert_eq!(out[5], Ext::One(Err(OverflowError)));
+        assert_eq!(OverflowError.to_string(), "integer overflow");
+        let mut m = count_saturating::<char, u8>();
+        m.init_one(254);
+        assert_eq!(m.update_batch(&['a'; 3]), [Ext::One(255); 3]);
+        let mut m = count_wrapping::<char, u8>();
+        m.init_one(254);
+        let out = m.update_batch(&['a'; 3]);
+        assert_eq!(out, [Ext::One(255), Ext::One(0), Ext::One(1)]);
+    }
+
+    #[test]
+    fn test_restartable() {
+        let m = sum_checked(|&x: &i8| x);
+        assert!(m.is_restartable());
+        let alphabet = [
+            RInput::Restart(0),
+            RInput::Restart(120),
+            RInput::Item(5),
+            RInput::Item(-3),
+        ];
+        assert_eq!(m.is_restartable_bounded(&alphabet, 5), Ok(()));
+        let m = count_saturating::<char, u8>();
+        let alphabet = [RInput::Restart(254), RInput::Item('a')];
+        assert_eq!(m.is_restartable_bounded(&alphabet, 6), Ok(()));
+    }
+}
@@ -0,0 +1,329 @@
+/*
+    Parallel evaluation of independent branches (enabled by the "parallel"
+    feature).
+
+    The branches of a union or a parallel composition all process the same
+    items independently, so when they are expensive (e.g. a wide
+    alternation over costly sub-queries), they can be evaluated in parallel
+    on each item, and their outputs merged, with the same results as the
+    sequential constructs in qre.rs:
+    - par_union(m1, m2) and par_parcomp(m1, m2) evaluate the two branches
+      with rayon::join;
+    - par_union_vec(ms) is the union of any number of transducers of the
+      same type, which are divided among the threads of the pool.
+    The work is scheduled on rayon's global thread pool (or the pool the
+    caller is running in, see rayon::ThreadPool::install). Splitting each
+    item across threads has a cost, so this only pays off when the work
+    per item of each branch is large; for cheap branches, the sequential
+    constructs are faster.
+*/
+
+use super::ext_value::Ext;
+use super::interface::Transducer;
+use rayon::prelude::*;
+use std::marker::PhantomData;
+use std::mem;
+
+/*
+    Union
+*/
+
+pub struct ParUnion<I, D, O, M1, M2>
+where
+    M1: Transducer<I, D, O>,
+    M2: Transducer<I, D, O>,
+{
+    m1: M1,
+    m2: M2,
+    ph_i: PhantomData<I>,
+    ph_d: PhantomData<D>,
+    ph_o: PhantomData<O>,
+}
+pub fn par_union<I, D, O, M1, M2>(m1: M1, m2: M2) -> ParUnion<I, D, O, M1, M2>
+where
+    M1: Transducer<I, D, O>,
+    M2: Transducer<I, D, O>,
+{
+    ParUnion { m1, m2, ph_i: PhantomData, ph_d: PhantomData, ph_o: PhantomData }
+}
+
+impl<I, D, O, M1, M2> Clone for ParUnion<I, D, O, M1, M2>
+where
+    M1: Transducer<I, D, O> + Clone,
+    M2: Transducer<I, D, O> + Clone,
+{
+    fn clone(&self) -> Self {
+        par_union(self.m1.clone(), self.m2.clone())
+    }
+}
+impl<I, D, O, M1, M2> Transducer<I, D, O> for ParUnion<I, D, O, M1, M2>
+where
+    I: Clone + Send,
+    D: Sync,
+    O: Send,
+    M1: Transducer<I, D, O> + Send,
+    M2: Transducer<I, D, O> + Send,
+{
+    fn init(&mut self, i: Ext<I>) -> Ext<O> {
+        let i2 = i.clone();
+        let (m1, m2) = (&mut self.m1, &mut self.m2);
+        let (o1, o2) = rayon::join(|| m1.init(i), || m2.init(i2));
+        o1 + o2
+    }
+    fn update(&mut self, item: &D) -> Ext<O> {
+        let (m1, m2) = (&mut self.m1, &mut self.m2);
+        let (o1, o2) = rayon::join(|| m1.update(item), || m2.update(item));
+        o1 + o2
+    }
+    fn reset(&mut self) {
+        self.m1.reset();
+        self.m2.reset();
+    }
+
+    fn is_epsilon(&self) -> bool {
+        self.m1.is_epsilon() && self.m2.is_epsilon()
+    }
+    fn is_restartable(&self) -> bool {
+        self.m1.is_restartable() && self.m2.is_restartable()
+    }
+    fn is_nullable(&self) -> bool {
+        self.m1.is_nullable() || self.m2.is_nullable()
+    }
+    fn n_states(&self) -> usize {
+        self.m1.n_states() + self.m2.n_states()
+    }
+    fn n_transs(&self) -> usize {
+        self.m1.n_transs() + self.m2.n_transs()
+    }
+    fn n_bytes(&self) -> usize {
+        mem::size_of::<Self>() - mem::size_of::<M1>() - mem::size_of::<M2>()
+            + self.m1.n_bytes()
+            + self.m2.n_bytes()
+    }
+}
+
+/*
+    Parallel composition
+*/
+
+pub struct ParParComp<I, D, O1, O2, M1, M2>
+where
+    M1: Transducer<I, D, O1>,
+    M2: Transducer<I, D, O2>,
+{
+    m1: M1,
+    m2: M2,
+    ph_i: PhantomData<I>,
+    ph_d: PhantomData<D>,
+    ph_o1: PhantomData<O1>,
+    ph_o2: PhantomData<O2>,
+}
+pub fn par_parcomp<I, D, O1, O2, M1, M2>(
+    m1: M1,
+    m2: M2,
+) -> ParParComp<I, D, O1, O2, M1, M2>
+where
+    M1: Transducer<I, D, O1>,
+    M2: Transducer<I, D, O2>,
+{
+    ParParComp {
+        m1,
+        m2,
+        ph_i: PhantomData,
+        ph_d: PhantomData,
+        ph_o1: PhantomData,
+        ph_o2: PhantomData,
+    }
+}
+
+impl<I, D, O1, O2, M1, M2> Clone for ParParComp<I, D, O1, O2, M1, M2>
+where
+    M1: Transducer<I, D, O1> + Clone,
+    M2: Transducer<I, D, O2> + Clone,
+{
+    fn clone(&self) -> Self {
+        par_parcomp(self.m1.clone(), self.m2.clone())
+    }
+}
+impl<I, D, O1, O2, M1, M2> Transducer<I, D, (O1, O2)>
+    for ParParComp<I, D, O1, O2, M1, M2>
+where
+    I: Clone + Send,
+    D: Sync,
+    O1: Send,
+    O2: Send,
+    M1: Transducer<I, D, O1> + Send,
+    M2: Transducer<I, D, O2> + Send,
+{
+    fn init(&mut self, i: Ext<I>) -> Ext<(O1, O2)> {
+        let i2 = i.clone();
+        let (m1, m2) = (&mut self.m1, &mut self.m2);
+        let (o1, o2) = rayon::join(|| m1.init(i), || m2.init(i2));
+        o1 * o2
+    }
+    fn update(&mut self, item: &D) -> Ext<(O1, O2)> {
+        let (m1, m2) = (&mut self.m1, &mut self.m2);
+        let (o1, o2) = rayon::join(|| m1.update(item), || m2.update(item));
+        o1 * o2
+    }
+    fn reset(&mut self) {
+        self.m1.reset();
+        self.m2.reset();
+    }
+
+    fn is_epsilon(&self) -> bool {
+        self.m1.is_epsilon() && self.m2.is_epsilon()
+    }
+    fn is_restartable(&self) -> bool {
+        // As for parcomp in qre.rs
+        unimplemented!()
+    }
+    fn is_nullable(&self) -> bool {
+        self.m1.is_nullable() && self.m2.is_nullable()
+    }
+    fn n_states(&self) -> usize {
+        self.m1.n_states() + self.m2.n_states()
+    }
+    fn n_transs(&self) -> usize {
+        self.m1.n_transs() + self.m2.n_transs()
+    }
+    fn n_bytes(&self) -> usize {
+        mem::size_of::<Self>() - mem::size_of::<M1>() - mem::size_of::<M2>()
+            + self.m1.n_bytes()
+            + self.m2.n_bytes()
+    }
+}
+
+/*
+    Union of many transducers
+*/
+
+pub struct ParUnionVec<I, D, O, M>
+where
+    M: Transducer<I, D, O>,
+{
+    ms: Vec<M>,
+    ph_i: PhantomData<I>,
+    ph_d: PhantomData<D>,
+    ph_o: PhantomData<O>,
+}
+pub fn par_union_vec<I, D, O, M>(ms: Vec<M>) -> ParUnionVec<I, D, O, M>
+where
+    M: Transducer<I, D, O>,
+{
+    ParUnionVec { ms, ph_i: PhantomData, ph_d: PhantomData, ph_o: PhantomData }
+}
+
+impl<I, D, O, M> Clone for ParUnionVec<I, D, O, M>
+where
+    M: Transducer<I, D, O> + Clone,
+{
+    fn clone(&self) -> Self {
+        par_union_vec(self.ms.clone())
+    }
+}
+impl<I, D, O, M> Transducer<I, D, O> for ParUnionVec<I, D, O, M>
+where
+    I: Clone + Send + Sync,
+    D: Sync,
+    O: Send,
+    M: Transducer<I, D, O> + Send,
+{
+    fn init(&mut self, i: Ext<I>) -> Ext<O> {
+        self.ms
+            .par_iter_mut()
+            .map(|m| m.init(i.clone()))
+            .reduce(|| Ext::None, |o1, o2| o1 + o2)
+    }
+    fn update(&mut self, item: &D) -> Ext<O> {
+        self.ms
+            .par_iter_mut()
+            .map(|m| m.update(item))
+            .reduce(|| Ext::None, |o1, o2| o1 + o2)
+    }
+    fn reset(&mut self) {
+        for m in self.ms.iter_mut() {
+            m.reset();
+        }
+    }
+
+    fn is_epsilon(&self) -> bool {
+        self.ms.iter().all(|m| m.is_epsilon())
+    }
+    fn is_restartable(&self) -> bool {
+        self.ms.iter().all(|m| m.is_restartable())
+    }
+    fn is_nullable(&self) -> bool {
+        self.ms.iter().any(|m| m.is_nullable())
+    }
+    fn n_states(&self) -> usize {
+        self.ms.iter().map(|m| m.n_states()).sum()
+    }
+    fn n_transs(&self) -> usize {
+        self.ms.iter().map(|m| m.n_transs()).sum()
+    }
+    fn n_bytes(&self) -> usize {
+        mem::size_of::<Self>()
+            + (self.ms.capacity() - self.ms.len()) * mem::size_of::<M>()
+            + self.ms.iter().map(|m| m.n_bytes()).sum::<usize>()
+    }
+}
+
+/*
+    Unit Tests
+*/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::qre::{atom, concat, iterate, parcomp, stream_iden, union};
+
+    // Running count of the items divisible by k
+    fn multiples(k: u64) -> impl Transducer<u64, u64, u64> + Send {
+        iterate(atom(move |_| true, move |n, &x: &u64| n + (x % k == 0) as u64))
+    }
+    // Matches the items divisible by k
+    fn ends_with(k: u64) -> impl Transducer<(), u64, u64> + Send {
+        concat(stream_iden(), atom(move |&x: &u64| x % k == 0, |(), &x| x))
+    }
+
+    #[test]
+    fn test_par_union() {
+        let items: Vec<u64> = (1..=100).collect();
+        let mut seq = union(ends_with(2), ends_with(3));
+        let mut par = par_union(ends_with(2), ends_with(3));
+        assert_eq!(par.init_one(()), seq.init_one(()));
+        assert_eq!(par.update_batch(&items), seq.update_batch(&items));
+        assert!(par.is_restartable());
+        assert_eq!(par.n_states(), seq.n_states());
+
+        let mut seq = parcomp(multiples(2), multiples(5));
+        let mut par = par_parcomp(multiples(2), multiples(5));
+        assert_eq!(par.init_one(0), seq.init_one(0));
+        assert_eq!(par.update_batch(&items), seq.update_batch(&items));
+        assert_eq!(par.update(&10), Ext::One((51, 21)));
+    }
+
+    #[test]
+    fn test_par_union_vec() {
+        let items: Vec<u64> = (1..=200).collect();
+        let mut par = par_union_vec((2..20).map(ends_with).collect());
+        assert!(par.is_restartable() && !par.is_nullable());
+        par.init_one(());
+        let out = par.update_batch(&items);
+        let expected: Vec<Ext<u64>> = items
+            .iter()
+            .map(|&x| (2..20).filter(|k| x % k == 0).map(|_| x).collect())
+            .collect();
+        assert_eq!(out, expected);
+        // Agrees with nested sequential unions
+        let mut seq = union(ends_with(2), union(ends_with(3), ends_with(4)));
+        let mut par =
+            par_union_vec(vec![ends_with(2), ends_with(3), ends_with(4)]);
+        seq.init_one(());
+        par.init_one(());
+        assert_eq!(par.update_batch(&items), seq.update_batch(&items));
+        par.reset();
+        assert_eq!(par.update(&12), Ext::None);
+    }
+}
@@ -0,0 +1,242 @@
+/*
+    Threaded pipelines of transducer stages.
+
+    For users who don't want the async machinery of async_driver.rs, a
+    Pipeline runs each stage on its own thread, connected by bounded
+    std::sync::mpsc channels (so a slow stage applies backpressure to the
+    earlier ones). The topology is declared with a builder:
+
+        let sums = Pipeline::new(items).stage(m1, i1).stage(m2, i2).collect();
+
+    Pipeline::new starts a thread feeding the items of an iterator;
+    .stage() starts a thread running a transducer on the items so far, and
+    forwards its outputs as the items of the next stage; .collect() or
+    .sink() consume the final items on the calling thread, and wait for the
+    threads to finish. As in async_driver.rs, a stage forwards the value of
+    each Ext::One output, skips Ext::None outputs, and stops with an error
+    on Ext::Many; the stages before it then stop as their outputs have no
+    receiver.
+*/
+
+use super::ext_value::Ext;
+use super::interface::Transducer;
+use super::io::Sink;
+use std::fmt::{self, Display};
+use std::io;
+use std::sync::mpsc::{self, Receiver};
+use std::thread::{self, JoinHandle};
+
+// Default capacity of the channels between stages
+const DEFAULT_CAPACITY: usize = 256;
+
+#[derive(Debug)]
+pub enum PipelineError {
+    // A stage (numbered from 1) produced Ext::Many on a step (from 1)
+    Many { stage: usize, step: usize },
+    // The sink failed to write
+    Io(io::Error),
+    // A stage panicked
+    Panicked { stage: usize },
+}
+impl Display for PipelineError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PipelineError::Many { stage, step } => write!(
+                f,
+                "stage {} produced many outputs on step {}",
+                stage, step
+            ),
+            PipelineError::Io(err) => write!(f, "sink failed: {}", err),
+            PipelineError::Panicked { stage } => {
+                write!(f, "stage {} panicked", stage)
+            }
+        }
+    }
+}
+impl std::error::Error for PipelineError {}
+
+type Stage = JoinHandle<Result<(), PipelineError>>;
+
+pub struct Pipeline<D> {
+    items: Receiver<D>,
+    capacity: usize,
+    // The source is stage 0
+    stages: Vec<Stage>,
+}
+impl<D: Send + 'static> Pipeline<D> {
+    pub fn new<It>(items: It) -> Self
+    where
+        It: IntoIterator<Item = D> + Send + 'static,
+    {
+        Self::with_capacity(items, DEFAULT_CAPACITY)
+    }
+    pub fn with_capacity<It>(items: It, capacity: usize) -> Self
+    where
+        It: IntoIterator<Item = D> + Send + 'static,
+    {
+        let (tx, rx) = mpsc::sync_channel(capacity);
+        let source = thread::spawn(move || {
+            for item in items {
+                if tx.send(item).is_err() {
+                    break;
+                }
+            }
+            Ok(())
+        });
+        Self { items: rx, capacity, stages: vec![source] }
+    }
+
+    pub fn stage<I, O, M>(mut self, mut m: M, init: I) -> Pipeline<O>
+    where
+        I: Send + 'static,
+        O: Send + 'static,
+        M: Transducer<I, D, O> + Send + 'static,
+    {
+        let stage = self.stages.len();
+        let (tx, rx) = mpsc::sync_channel(self.capacity);
+        let items = self.items;
+        self.stages.push(thread::spawn(move || {
+            let outputs = std::iter::once(m.init_one(init))
+                .chain(items.into_iter().map(|item| m.update(&item)));
+            for (step, out) in outputs.enumerate() {
+                match out {
+                    Ext::None => (),
+                    Ext::One(o) => {
+                        if tx.send(o).is_err() {
+                            break;
+                        }
+                    }
+                    Ext::Many => {
+                        return Err(PipelineError::Many {
+                            stage,
+                            step: step + 1,
+                        })
+                    }
+                }
+            }
+            Ok(())
+        }));
+        Pipeline { items: rx, capacity: self.capacity, stages: self.stages }
+    }
+
+    // Consume the items, passing each to the callback
+    pub fn for_each<F>(self, mut callback: F) -> Result<(), PipelineError>
+    where
+        F: FnMut(D) -> Result<(), PipelineError>,
+    {
+        let mut result = Ok(());
+        for item in self.items.iter() {
+            result = callback(item);
+            if result.is_err() {
+                break;
+            }
+        }
+        // Stop the stages if the callback failed
+        drop(self.items);
+        for (stage, handle) in self.stages.into_iter().enumerate() {
+            let status =
+                handle.join().unwrap_or(Err(PipelineError::Panicked { stage }));
+            if result.is_ok() {
+                result = status;
+            }
+        }
+        result
+    }
+    pub fn collect(self) -> Result<Vec<D>, PipelineError> {
+        let mut result = Vec::new();
+        self.for_each(|item| {
+            result.push(item);
+            Ok(())
+        })?;
+        Ok(result)
+    }
+    pub fn sink<S: Sink<D>>(self, mut sink: S) -> Result<(), PipelineError> {
+        let mut step = 0;
+        self.for_each(|item| {
+            step += 1;
+            sink.write(step, &Ext::One(item)).map_err(PipelineError::Io)
+        })?;
+        sink.flush().map_err(PipelineError::Io)
+    }
+}
+
+/*
+    Unit Tests
+*/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::WriteSink;
+    use crate::qre::{atom, concat, iterate, stream_iden};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    // Forwards each item
+    fn iden() -> impl Transducer<(), i64, i64> + Send {
+        concat(stream_iden(), atom(|_| true, |(), &x: &i64| x))
+    }
+
+    #[test]
+    fn test_backpressure() {
+        // With channels of capacity 1, while the sink holds the first item
+        // the source can only get a few items ahead: one in each channel,
+        // one in each thread waiting to send it, and the one being sent
+        let produced = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&produced);
+        let items = (0..100).inspect(move |_| {
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+        let mut out = Vec::new();
+        Pipeline::with_capacity(items, 1)
+            .stage(iden(), ())
+            .for_each(|x| {
+                if x == 0 {
+                    thread::sleep(Duration::from_millis(50));
+                    assert!(produced.load(Ordering::SeqCst) <= 5);
+                }
+                out.push(x);
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(out, (0..100).collect::<Vec<_>>());
+        assert_eq!(produced.load(Ordering::SeqCst), 100);
+    }
+
+    #[test]
+    fn test_panicked() {
+        let failing = iterate(atom(
+            |_| true,
+            |s, &x: &i64| if x == 3 { panic!() } else { s + x },
+        ));
+        let err = Pipeline::new(0..)
+            .stage(failing, 0)
+            .stage(iden(), ())
+            .collect()
+            .unwrap_err();
+        assert!(matches!(err, PipelineError::Panicked { stage: 1 }));
+        assert_eq!(err.to_string(), "stage 1 panicked");
+    }
+
+    #[test]
+    fn test_sink_and_callback() {
+        let mut sink = WriteSink::new(Vec::new()).numbered();
+        Pipeline::new(vec![1, 2]).stage(iden(), ()).sink(&mut sink).unwrap();
+        assert_eq!(
+            String::from_utf8(sink.into_inner()).unwrap(),
+            "1: 1\n2: 2\n"
+        );
+        // The callback stopping also stops the (infinite) source
+        let mut n = 0;
+        let err = Pipeline::new(0..).for_each(|_| {
+            n += 1;
+            if n == 5 {
+                Err(PipelineError::Io(io::ErrorKind::Other.into()))
+            } else {
+                Ok(())
+            }
+        });
+        assert!(matches!(err, Err(PipelineError::Io(_))));
+    }
+}
@@ -11,11 +11,50 @@
 */
 
 use super::ext_value::{self, Ext};
-use super::interface::Transducer;
-use std::fmt::Debug;
+use super::init_check::init_child;
+use super::interface::{PItem, Transducer};
+use std::collections::{BTreeMap, VecDeque};
+use std::error::Error;
+use std::fmt::{self, Debug, Display};
 use std::marker::PhantomData;
 use std::mem;
 
+/*
+    Construction errors
+
+    concat and iterate require some of their operands to be restartable,
+    which is only known at runtime (see Transducer::is_restartable), and
+    panic if not. The try_ versions of these constructors return the
+    requirement which failed instead, for transducers built from runtime
+    data (e.g. a DSL). Operands are numbered from 1.
+*/
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Requirement {
+    Restartable,
+    // Restartable, unless the previous operand is an epsilon
+    RestartableOrAfterEpsilon,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ConstructionError {
+    pub construct: &'static str,
+    pub operand: usize,
+    pub requirement: Requirement,
+}
+impl Display for ConstructionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let req = match self.requirement {
+            Requirement::Restartable => "must be restartable",
+            Requirement::RestartableOrAfterEpsilon => {
+                "must be restartable (or follow an epsilon)"
+            }
+        };
+        write!(f, "{}: operand {} {}", self.construct, self.operand, req)
+    }
+}
+impl Error for ConstructionError {}
+
 /*
     QRE epsilon
 
@@ -35,7 +74,7 @@ pub struct Epsilon<I, D, O, F>
 where
     F: Fn(I) -> O,
 {
-    action: F,
+    pub(crate) action: F,
     ph_i: PhantomData<I>,
     ph_d: PhantomData<D>,
     ph_o: PhantomData<O>,
@@ -82,6 +121,9 @@ where
     fn is_restartable(&self) -> bool {
         true
     }
+    fn is_nullable(&self) -> bool {
+        true
+    }
     fn n_states(&self) -> usize {
         // This would be 2 following the POPL definition, including 1 initial
         // and 1 final state. But we exclude the initial and final states here
@@ -127,8 +169,8 @@ where
     G: Fn(&D) -> bool,
     F: Fn(I, &D) -> O,
 {
-    guard: G,
-    action: F,
+    pub(crate) guard: G,
+    pub(crate) action: F,
     istate: Ext<I>,
     ph_d: PhantomData<D>,
     ph_o: PhantomData<O>,
@@ -187,8 +229,8 @@ where
     fn update(&mut self, item: &D) -> Ext<O> {
         let mut istate = Ext::None;
         mem::swap(&mut self.istate, &mut istate);
-        if (self.guard)(&item) {
-            ext_value::apply1(move |x| (self.action)(x, &item), istate)
+        if (self.guard)(item) {
+            ext_value::apply1(move |x| (self.action)(x, item), istate)
         } else {
             Ext::None
         }
@@ -203,6 +245,9 @@ where
     fn is_restartable(&self) -> bool {
         true
     }
+    fn is_nullable(&self) -> bool {
+        false
+    }
     fn n_states(&self) -> usize {
         1
     }
@@ -223,8 +268,8 @@ where
     M1: Transducer<I, D, O>,
     M2: Transducer<I, D, O>,
 {
-    m1: M1,
-    m2: M2,
+    pub(crate) m1: M1,
+    pub(crate) m2: M2,
     ph_i: PhantomData<I>,
     ph_d: PhantomData<D>,
     ph_o: PhantomData<O>,
@@ -254,7 +299,7 @@ where
 {
     fn init(&mut self, i: Ext<I>) -> Ext<O> {
         let i2 = i.clone();
-        self.m1.init(i) + self.m2.init(i2)
+        init_child(&mut self.m1, i) + init_child(&mut self.m2, i2)
     }
     fn update(&mut self, item: &D) -> Ext<O> {
         self.m1.update(item) + self.m2.update(item)
@@ -270,12 +315,135 @@ where
     fn is_restartable(&self) -> bool {
         self.m1.is_restartable() && self.m2.is_restartable()
     }
+    fn is_nullable(&self) -> bool {
+        self.m1.is_nullable() || self.m2.is_nullable()
+    }
     fn n_states(&self) -> usize {
         self.m1.n_states() + self.m2.n_states()
     }
     fn n_transs(&self) -> usize {
         self.m1.n_transs() + self.m2.n_transs()
     }
+    fn n_bytes(&self) -> usize {
+        // Replace the inline size of m1 and m2 by their full footprint
+        mem::size_of::<Self>() - mem::size_of::<M1>() - mem::size_of::<M2>()
+            + self.m1.n_bytes()
+            + self.m2.n_bytes()
+    }
+}
+
+/*
+    QRE union with a combining operation
+
+    Like union, but when both transducers produce a single value, combines
+    them with an operation instead of collapsing the result to Ext::Many.
+    This is the variant of union needed for outputs in an algebra such as
+    min-plus, where alternatives should be resolved (e.g. cheapest match)
+    rather than treated as ambiguous. (See also the semiring module.)
+
+    As with parcomp, this is not restartable in general: values coming from
+    different restarts would be combined with op rather than with +.
+*/
+
+pub struct UnionOp<I, D, O, M1, M2, F>
+where
+    M1: Transducer<I, D, O>,
+    M2: Transducer<I, D, O>,
+    F: Fn(O, O) -> O,
+{
+    m1: M1,
+    m2: M2,
+    op: F,
+    ph_i: PhantomData<I>,
+    ph_d: PhantomData<D>,
+    ph_o: PhantomData<O>,
+}
+pub fn union_op<I, D, O, M1, M2, F>(
+    m1: M1,
+    m2: M2,
+    op: F,
+) -> UnionOp<I, D, O, M1, M2, F>
+where
+    M1: Transducer<I, D, O>,
+    M2: Transducer<I, D, O>,
+    F: Fn(O, O) -> O,
+{
+    UnionOp {
+        m1,
+        m2,
+        op,
+        ph_i: PhantomData,
+        ph_d: PhantomData,
+        ph_o: PhantomData,
+    }
+}
+
+impl<I, D, O, M1, M2, F> UnionOp<I, D, O, M1, M2, F>
+where
+    M1: Transducer<I, D, O>,
+    M2: Transducer<I, D, O>,
+    F: Fn(O, O) -> O,
+{
+    fn combine(&self, o1: Ext<O>, o2: Ext<O>) -> Ext<O> {
+        match (o1, o2) {
+            (Ext::One(x1), Ext::One(x2)) => Ext::One((self.op)(x1, x2)),
+            (o1, o2) => o1 + o2,
+        }
+    }
+}
+impl<I, D, O, M1, M2, F> Clone for UnionOp<I, D, O, M1, M2, F>
+where
+    M1: Transducer<I, D, O> + Clone,
+    M2: Transducer<I, D, O> + Clone,
+    F: Fn(O, O) -> O + Clone,
+{
+    fn clone(&self) -> Self {
+        union_op(self.m1.clone(), self.m2.clone(), self.op.clone())
+    }
+}
+impl<I, D, O, M1, M2, F> Transducer<I, D, O> for UnionOp<I, D, O, M1, M2, F>
+where
+    I: Clone,
+    M1: Transducer<I, D, O>,
+    M2: Transducer<I, D, O>,
+    F: Fn(O, O) -> O,
+{
+    fn init(&mut self, i: Ext<I>) -> Ext<O> {
+        let i2 = i.clone();
+        let o1 = init_child(&mut self.m1, i);
+        let o2 = init_child(&mut self.m2, i2);
+        self.combine(o1, o2)
+    }
+    fn update(&mut self, item: &D) -> Ext<O> {
+        let o1 = self.m1.update(item);
+        let o2 = self.m2.update(item);
+        self.combine(o1, o2)
+    }
+    fn reset(&mut self) {
+        self.m1.reset();
+        self.m2.reset();
+    }
+
+    fn is_epsilon(&self) -> bool {
+        self.m1.is_epsilon() && self.m2.is_epsilon()
+    }
+    fn is_restartable(&self) -> bool {
+        false
+    }
+    fn is_nullable(&self) -> bool {
+        self.m1.is_nullable() || self.m2.is_nullable()
+    }
+    fn n_states(&self) -> usize {
+        self.m1.n_states() + self.m2.n_states()
+    }
+    fn n_transs(&self) -> usize {
+        self.m1.n_transs() + self.m2.n_transs() + 1
+    }
+    fn n_bytes(&self) -> usize {
+        mem::size_of::<Self>() - mem::size_of::<M1>() - mem::size_of::<M2>()
+            + self.m1.n_bytes()
+            + self.m2.n_bytes()
+    }
 }
 
 /*
@@ -290,8 +458,8 @@ where
     M1: Transducer<I, D, O1>,
     M2: Transducer<I, D, O2>,
 {
-    m1: M1,
-    m2: M2,
+    pub(crate) m1: M1,
+    pub(crate) m2: M2,
     ph_i: PhantomData<I>,
     ph_d: PhantomData<D>,
     ph_o1: PhantomData<O1>,
@@ -333,7 +501,7 @@ where
 {
     fn init(&mut self, i: Ext<I>) -> Ext<(O1, O2)> {
         let i2 = i.clone();
-        self.m1.init(i) * self.m2.init(i2)
+        init_child(&mut self.m1, i) * init_child(&mut self.m2, i2)
     }
     fn update(&mut self, item: &D) -> Ext<(O1, O2)> {
         self.m1.update(item) * self.m2.update(item)
@@ -352,12 +520,21 @@ where
         // languages.
         unimplemented!()
     }
+    fn is_nullable(&self) -> bool {
+        self.m1.is_nullable() && self.m2.is_nullable()
+    }
     fn n_states(&self) -> usize {
         self.m1.n_states() + self.m2.n_states()
     }
     fn n_transs(&self) -> usize {
         self.m1.n_transs() + self.m2.n_transs()
     }
+    fn n_bytes(&self) -> usize {
+        // Replace the inline size of m1 and m2 by their full footprint
+        mem::size_of::<Self>() - mem::size_of::<M1>() - mem::size_of::<M2>()
+            + self.m1.n_bytes()
+            + self.m2.n_bytes()
+    }
 }
 
 /*
@@ -382,28 +559,44 @@ where
     M1: Transducer<X, D, Y>,
     M2: Transducer<Y, D, Z>,
 {
-    m1: M1,
-    m2: M2,
+    pub(crate) m1: M1,
+    pub(crate) m2: M2,
     ph_d: PhantomData<D>,
     ph_x: PhantomData<X>,
     ph_y: PhantomData<Y>,
     ph_z: PhantomData<Z>,
 }
 pub fn concat<D, X, Y, Z, M1, M2>(m1: M1, m2: M2) -> Concat<D, X, Y, Z, M1, M2>
+where
+    M1: Transducer<X, D, Y>,
+    M2: Transducer<Y, D, Z>,
+{
+    try_concat(m1, m2).unwrap_or_else(|err| panic!("{}", err))
+}
+pub fn try_concat<D, X, Y, Z, M1, M2>(
+    m1: M1,
+    m2: M2,
+) -> Result<Concat<D, X, Y, Z, M1, M2>, ConstructionError>
 where
     M1: Transducer<X, D, Y>,
     M2: Transducer<Y, D, Z>,
 {
     // REQUIREMENT: m2 must be restartable OR m1 must be an epsilon
-    assert!(m2.is_restartable() || m1.is_epsilon());
-    Concat {
+    if !(m2.is_restartable() || m1.is_epsilon()) {
+        return Err(ConstructionError {
+            construct: "concat",
+            operand: 2,
+            requirement: Requirement::RestartableOrAfterEpsilon,
+        });
+    }
+    Ok(Concat {
         m1,
         m2,
         ph_d: PhantomData,
         ph_x: PhantomData,
         ph_y: PhantomData,
         ph_z: PhantomData,
-    }
+    })
 }
 
 impl<D, X, Y, Z, M1, M2> Clone for Concat<D, X, Y, Z, M1, M2>
@@ -421,12 +614,13 @@ where
     M2: Transducer<Y, D, Z>,
 {
     fn init(&mut self, i: Ext<X>) -> Ext<Z> {
-        self.m2.init(self.m1.init(i))
+        let y = init_child(&mut self.m1, i);
+        init_child(&mut self.m2, y)
     }
     fn update(&mut self, item: &D) -> Ext<Z> {
         let y = self.m1.update(item);
         let z1 = self.m2.update(item);
-        let z2 = self.m2.init(y);
+        let z2 = init_child(&mut self.m2, y);
         z1 + z2
     }
     fn reset(&mut self) {
@@ -450,12 +644,21 @@ where
         // implies .is_restartable().
         self.m1.is_restartable() && self.m2.is_restartable()
     }
+    fn is_nullable(&self) -> bool {
+        self.m1.is_nullable() && self.m2.is_nullable()
+    }
     fn n_states(&self) -> usize {
         self.m1.n_states() + self.m2.n_states()
     }
     fn n_transs(&self) -> usize {
         self.m1.n_transs() + self.m2.n_transs()
     }
+    fn n_bytes(&self) -> usize {
+        // Replace the inline size of m1 and m2 by their full footprint
+        mem::size_of::<Self>() - mem::size_of::<M1>() - mem::size_of::<M2>()
+            + self.m1.n_bytes()
+            + self.m2.n_bytes()
+    }
 }
 
 /*
@@ -474,29 +677,38 @@ pub struct Iterate<X, D, M>
 where
     M: Transducer<X, D, X>,
 {
-    m: M,
+    pub(crate) m: M,
     // Tracks the accumulation of values we have .init() into m
     istate: Ext<()>,
-    // True if m produced output in response to an .init() (degenerate case),
-    // false if it does not produce such output, None if this is not known
-    // yet.
-    // Once we determine true or false, self.loopy never changes;
-    // this is sound because the behavior of .init() is independent of the
-    // context, which is not true in general but holds due to the requirement
-    // that M is restartable.
-    loopy: Option<bool>,
+    // True if m produces output in response to an .init() (degenerate case).
+    // This is determined statically on construction: it holds exactly when
+    // the rate of m is nullable. Since m is restartable, the behavior of
+    // .init() is independent of the context, so this never changes.
+    loopy: bool,
     ph_x: PhantomData<X>,
     ph_d: PhantomData<D>,
 }
 pub fn iterate<X, D, M>(m: M) -> Iterate<X, D, M>
+where
+    M: Transducer<X, D, X>,
+{
+    try_iterate(m).unwrap_or_else(|err| panic!("{}", err))
+}
+pub fn try_iterate<X, D, M>(m: M) -> Result<Iterate<X, D, M>, ConstructionError>
 where
     M: Transducer<X, D, X>,
 {
     // REQUIREMENT: m must be restartable
-    assert!(m.is_restartable());
+    if !m.is_restartable() {
+        return Err(ConstructionError {
+            construct: "iterate",
+            operand: 1,
+            requirement: Requirement::Restartable,
+        });
+    }
     let istate = Ext::None;
-    let loopy = None;
-    Iterate { m, istate, loopy, ph_x: PhantomData, ph_d: PhantomData }
+    let loopy = m.is_nullable();
+    Ok(Iterate { m, istate, loopy, ph_x: PhantomData, ph_d: PhantomData })
 }
 
 impl<X, D, M> Clone for Iterate<X, D, M>
@@ -504,10 +716,9 @@ where
     M: Transducer<X, D, X> + Clone,
 {
     fn clone(&self) -> Self {
-        let m = self.m.clone();
-        let istate = self.istate;
-        let loopy = self.loopy;
-        Iterate { m, istate, loopy, ph_x: PhantomData, ph_d: PhantomData }
+        let mut result = iterate(self.m.clone());
+        result.istate = self.istate;
+        result
     }
 }
 impl<X, D, M> Transducer<X, D, X> for Iterate<X, D, M>
@@ -519,45 +730,27 @@ where
         if i.is_none() {
             return Ext::None;
         }
-        match self.loopy {
-            Some(true) => {
-                if cfg!(debug_assertions) {
-                    self.istate = Ext::Many;
-                    assert_eq!(self.m.init(Ext::Many), Ext::Many);
-                } else if !self.istate.is_many() {
-                    self.istate = Ext::Many;
-                    self.m.init(Ext::Many);
-                }
-                Ext::Many
-            }
-            Some(false) => {
-                if cfg!(debug_assertions) {
-                    self.istate += i.to_unit();
-                    assert_eq!(self.m.init(i.clone()), Ext::None);
-                } else if !self.istate.is_many() {
-                    self.istate += i.to_unit();
-                    self.m.init(i.clone());
-                }
-                // Return the input (epsilon/identity case)
-                i
+        if self.loopy {
+            // Any input circulates through m and back, so the result is
+            // always Many
+            if cfg!(debug_assertions) {
+                self.istate = Ext::Many;
+                assert_eq!(init_child(&mut self.m, Ext::Many), Ext::Many);
+            } else if !self.istate.is_many() {
+                self.istate = Ext::Many;
+                init_child(&mut self.m, Ext::Many);
             }
-            None => {
-                // This is where we find out if m is loopy
-                debug_assert!(self.istate.is_none());
-                self.istate = i.to_unit();
-                let out = self.m.init(i.clone());
-                if out.is_none() {
-                    // Not loopy
-                    self.loopy = Some(false);
-                    // Return the input (epsilon/identity case)
-                    i
-                } else {
-                    // Loopy; set this knowledge and rerun function
-                    // with new output
-                    self.loopy = Some(true);
-                    self.init(out)
-                }
+            Ext::Many
+        } else {
+            if cfg!(debug_assertions) {
+                self.istate += i.to_unit();
+                assert_eq!(init_child(&mut self.m, i.clone()), Ext::None);
+            } else if !self.istate.is_many() {
+                self.istate += i.to_unit();
+                init_child(&mut self.m, i.clone());
             }
+            // Return the input (epsilon/identity case)
+            i
         }
     }
     fn update(&mut self, item: &D) -> Ext<X> {
@@ -568,7 +761,6 @@ where
     fn reset(&mut self) {
         self.m.reset();
         self.istate = Ext::None;
-        // Don't need to reset self.loopy; this information will remain valid
     }
 
     fn is_epsilon(&self) -> bool {
@@ -579,12 +771,19 @@ where
         debug_assert!(self.m.is_restartable());
         true
     }
+    fn is_nullable(&self) -> bool {
+        // The empty stream is always matched by zero iterations
+        true
+    }
     fn n_states(&self) -> usize {
         self.m.n_states() + 1
     }
     fn n_transs(&self) -> usize {
         self.m.n_transs()
     }
+    fn n_bytes(&self) -> usize {
+        mem::size_of::<Self>() - mem::size_of::<M>() + self.m.n_bytes()
+    }
 }
 
 /*
@@ -604,7 +803,8 @@ where
     anything reasonable.
     It is possible to be restartable in some special cases, in particular
     if the sub-transducer matches on all input streams, but we do not
-    currently detect these cases.
+    currently detect these cases here (this can be checked on a given
+    alphabet with rate::matches_everything).
 */
 
 pub struct Aggregate<D, X, Y, Z, M, F>
@@ -612,7 +812,7 @@ where
     M: Transducer<X, D, Y>,
     F: Fn(Z, Y) -> Z,
 {
-    m: M,
+    pub(crate) m: M,
     agg_fun: F,
     // The most recently produced aggregate
     agg: Ext<Z>,
@@ -640,22 +840,35 @@ where
 
 impl<D, X, Y, Z, M, F> Aggregate<D, X, Y, Z, M, F>
 where
-    Z: Clone,
     M: Transducer<X, D, Y>,
     F: Fn(Z, Y) -> Z,
 {
     // Auxiliary function used by both .init and .update
-    // Update the aggregate and return the new result (if any)
-    fn update_agg(&mut self, y: Ext<Y>) -> Ext<Z> {
+    // Update the aggregate and return the new result (if any), by reference
+    fn update_agg(&mut self, y: Ext<Y>) -> Ext<&Z> {
         if y.is_none() {
             Ext::None
         } else {
             let mut tmp = Ext::None;
             mem::swap(&mut tmp, &mut self.agg);
             self.agg = ext_value::apply2(&self.agg_fun, tmp, y);
-            self.agg.clone()
+            self.agg.as_ref()
         }
     }
+
+    // Versions of .init() and .update() which return the aggregate by
+    // reference, rather than cloning it on every step (for large
+    // aggregates, such as sketches or vectors)
+    pub fn init_ref(&mut self, i: Ext<(X, Z)>) -> Ext<&Z> {
+        let (x, z) = i.split(|(x, z)| (x, z));
+        let y = init_child(&mut self.m, x);
+        self.agg += z;
+        self.update_agg(y)
+    }
+    pub fn update_ref(&mut self, item: &D) -> Ext<&Z> {
+        let y = self.m.update(item);
+        self.update_agg(y)
+    }
 }
 impl<D, X, Y, Z, M, F> Clone for Aggregate<D, X, Y, Z, M, F>
 where
@@ -676,14 +889,10 @@ where
     F: Fn(Z, Y) -> Z,
 {
     fn init(&mut self, i: Ext<(X, Z)>) -> Ext<Z> {
-        let (x, z) = i.split(|(x, z)| (x, z));
-        let y = self.m.init(x);
-        self.agg += z;
-        self.update_agg(y)
+        ext_value::apply1(Z::clone, self.init_ref(i))
     }
     fn update(&mut self, item: &D) -> Ext<Z> {
-        let y = self.m.update(item);
-        self.update_agg(y)
+        ext_value::apply1(Z::clone, self.update_ref(item))
     }
     fn reset(&mut self) {
         self.m.reset();
@@ -696,12 +905,18 @@ where
     fn is_restartable(&self) -> bool {
         false
     }
+    fn is_nullable(&self) -> bool {
+        self.m.is_nullable()
+    }
     fn n_states(&self) -> usize {
         self.m.n_states() + 1
     }
     fn n_transs(&self) -> usize {
         self.m.n_transs() + 1
     }
+    fn n_bytes(&self) -> usize {
+        mem::size_of::<Self>() - mem::size_of::<M>() + self.m.n_bytes()
+    }
 }
 
 /*
@@ -761,107 +976,904 @@ where
 }
 
 /*
-    QRE transducer top-level wrapper
+    QRE punctuated windows
+
+    Runs m on windows of the stream delimited by punctuation items
+    (PItem::Punct). On punctuation, the output of m on the window so far is
+    emitted, and m is reset and initialized again with the initial value,
+    for the next window. Item outputs are not emitted; so this replaces the
+    hand-rolled pattern of guarding the end of a window on a marker item
+    (like '#' in the POPL'19 examples).
+
+    As with aggregate, the initial value is stored, so this is not
+    restartable: a second .init() is added to the initial value for the
+    following windows.
+*/
 
-    For now, all this does is save the number of states, number of transitions,
-    epsilon-ness, and restartability as this is more efficient than recomputing
-    them all the time.
+pub struct Punctuated<I, D, O, M>
+where
+    M: Transducer<I, D, O>,
+{
+    m: M,
+    init: Ext<I>,
+    // Output of m on the current window
+    last: Ext<O>,
+    ph_d: PhantomData<D>,
+}
+pub fn punctuated<I, D, O, M>(m: M) -> Punctuated<I, D, O, M>
+where
+    M: Transducer<I, D, O>,
+{
+    Punctuated { m, init: Ext::None, last: Ext::None, ph_d: PhantomData }
+}
+
+impl<I, D, O, M> Clone for Punctuated<I, D, O, M>
+where
+    I: Clone,
+    O: Clone,
+    M: Transducer<I, D, O> + Clone,
+{
+    fn clone(&self) -> Self {
+        let mut result = punctuated(self.m.clone());
+        result.init = self.init.clone();
+        result.last = self.last.clone();
+        result
+    }
+}
+impl<I, D, O, M> Transducer<I, PItem<D>, O> for Punctuated<I, D, O, M>
+where
+    I: Clone,
+    M: Transducer<I, D, O>,
+{
+    fn init(&mut self, i: Ext<I>) -> Ext<O> {
+        self.init += i.clone();
+        self.last += self.m.init(i);
+        Ext::None
+    }
+    fn update(&mut self, item: &PItem<D>) -> Ext<O> {
+        match item {
+            PItem::Item(d) => {
+                self.last = self.m.update(d);
+                Ext::None
+            }
+            PItem::Punct => {
+                self.m.reset();
+                let next = self.m.init(self.init.clone());
+                mem::replace(&mut self.last, next)
+            }
+        }
+    }
+    fn reset(&mut self) {
+        self.m.reset();
+        self.init = Ext::None;
+        self.last = Ext::None;
+    }
+
+    fn is_epsilon(&self) -> bool {
+        false
+    }
+    fn is_restartable(&self) -> bool {
+        false
+    }
+    fn is_nullable(&self) -> bool {
+        false
+    }
+    fn n_states(&self) -> usize {
+        self.m.n_states() + 2
+    }
+    fn n_transs(&self) -> usize {
+        self.m.n_transs() + 1
+    }
+    fn n_bytes(&self) -> usize {
+        mem::size_of::<Self>() - mem::size_of::<M>() + self.m.n_bytes()
+    }
+}
+
+/*
+    QRE timeout
+
+    within(m, n) keeps the outputs of m only on matches of at most n items,
+    counted from .init(). Once more than n items have been seen, the partial
+    matches are discarded (m is reset), so that e.g. "a response within 3
+    items of a request" doesn't need counter states. For a bound on time
+    rather than the number of items, see timed::within.
+
+    Like aggregate, this is not restartable: the items are counted from the
+    last .init(), which restarts m.
 */
 
-pub struct TopWrapper<I, D, O, M>
+pub struct Within<I, D, O, M>
 where
     M: Transducer<I, D, O>,
 {
     m: M,
+    limit: usize,
+    // Number of items since .init()
+    count: usize,
     ph_i: PhantomData<I>,
     ph_d: PhantomData<D>,
     ph_o: PhantomData<O>,
-    epsilon: bool,
-    restartable: bool,
-    n_states: usize,
-    n_transs: usize,
 }
-pub fn top<I, D, O, M>(m: M) -> TopWrapper<I, D, O, M>
+pub fn within<I, D, O, M>(m: M, limit: usize) -> Within<I, D, O, M>
 where
     M: Transducer<I, D, O>,
 {
-    let epsilon = m.is_epsilon();
-    let restartable = m.is_restartable();
-    let n_states = m.n_states();
-    let n_transs = m.n_transs();
-    TopWrapper {
+    Within {
         m,
+        limit,
+        count: 0,
         ph_i: PhantomData,
         ph_d: PhantomData,
         ph_o: PhantomData,
-        epsilon,
-        restartable,
-        n_states,
-        n_transs,
     }
 }
 
-impl<I, D, O, M> Clone for TopWrapper<I, D, O, M>
+impl<I, D, O, M> Clone for Within<I, D, O, M>
 where
     M: Transducer<I, D, O> + Clone,
 {
     fn clone(&self) -> Self {
-        top(self.m.clone())
+        let mut result = within(self.m.clone(), self.limit);
+        result.count = self.count;
+        result
     }
 }
-impl<I, D, O, M> Transducer<I, D, O> for TopWrapper<I, D, O, M>
+impl<I, D, O, M> Transducer<I, D, O> for Within<I, D, O, M>
 where
     M: Transducer<I, D, O>,
 {
     fn init(&mut self, i: Ext<I>) -> Ext<O> {
+        if i.is_none() {
+            return Ext::None;
+        }
+        self.m.reset();
+        self.count = 0;
         self.m.init(i)
     }
     fn update(&mut self, item: &D) -> Ext<O> {
+        if self.count >= self.limit {
+            if self.count == self.limit {
+                // Timed out: discard the partial matches
+                self.m.reset();
+                self.count += 1;
+            }
+            return Ext::None;
+        }
+        self.count += 1;
         self.m.update(item)
     }
     fn reset(&mut self) {
         self.m.reset();
+        self.count = 0;
     }
 
     fn is_epsilon(&self) -> bool {
-        self.epsilon
+        self.m.is_epsilon()
     }
     fn is_restartable(&self) -> bool {
-        self.restartable
+        false
+    }
+    fn is_nullable(&self) -> bool {
+        self.m.is_nullable()
     }
     fn n_states(&self) -> usize {
-        self.n_states
+        self.m.n_states() + 1
     }
     fn n_transs(&self) -> usize {
-        self.n_transs
+        self.m.n_transs()
+    }
+    fn n_bytes(&self) -> usize {
+        mem::size_of::<Self>() - mem::size_of::<M>() + self.m.n_bytes()
     }
 }
 
 /*
-    Unit Tests
-*/
+    StreamQRE operators
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::interface::RInput;
+    The operator set of StreamQRE (Mamouras et al., PLDI'17). A StreamQRE
+    query doesn't take an initial value: it is a function of the stream
+    alone, and the results of subqueries are combined by explicit
+    operations. Here such queries are transducers with input ().
 
-    // Constants (examples)
+    - apply(m, op)
+      Apply op to the output of m. (This is concat followed by an epsilon.)
 
-    const EX_RSTRM_1: &[RInput<i32, char>] = &[
-        RInput::Item('a'),
-        RInput::Restart(3),
-        RInput::Item('b'),
-        RInput::Restart(4),
-        RInput::Restart(6),
-        RInput::Item('c'),
-    ];
+    - combine(m1, m2, op)
+      Run m1 and m2 on the same stream and combine their outputs with op.
+      (StreamQRE's name for apply_op.)
 
-    const EX_RSTRM_2: &[RInput<i32, char>] = &[RInput::Item('b')];
+    - iter(m, init, op)
+      Split the stream into chunks w1 w2 ... wk, each matched by m, and fold
+      op over the outputs of m on the chunks, starting from init. Outputs
+      init on the empty stream, and the new fold at the end of each chunk.
+      m must be restartable, and should be unambiguous and not nullable;
+      otherwise the output is Ext::Many.
 
-    const EX_RSTRM_3: &[RInput<i32, char>] = &[RInput::Restart(3)];
+    - split(m1, m2, op), map_collect(key, m), sliding(n, m), tumbling(n, m)
+      See below.
+*/
 
-    const EX_RSTRM_4: &[RInput<i32, char>] = &[];
+pub fn apply<I, D, Y, Z, M, F>(m: M, op: F) -> impl Transducer<I, D, Z>
+where
+    M: Transducer<I, D, Y>,
+    F: Fn(Y) -> Z,
+{
+    concat(m, epsilon(op))
+}
+
+pub fn combine<I, D, O1, O2, O, M1, M2, F>(
+    m1: M1,
+    m2: M2,
+    op: F,
+) -> impl Transducer<I, D, O>
+where
+    I: Clone,
+    M1: Transducer<I, D, O1>,
+    M2: Transducer<I, D, O2>,
+    F: Fn(O1, O2) -> O,
+{
+    apply_op(m1, m2, op)
+}
+
+pub fn iter<D, Y, Z, M, F>(m: M, init: Z, op: F) -> impl Transducer<(), D, Z>
+where
+    Z: Clone,
+    M: Transducer<(), D, Y> + Clone,
+    F: Fn(Z, Y) -> Z,
+{
+    // Outputs m on the last chunk, whenever the stream so far is a
+    // sequence of chunks
+    let chunks = concat(iterate(apply(m.clone(), |_| ())), m);
+    let folded = union(epsilon(|((), z)| z), aggregate(chunks, op));
+    concat(epsilon(move |()| ((), init.clone())), folded)
+}
+
+/*
+    QRE split (StreamQRE)
+
+    split(m1, m2, op) splits the stream into w = uv, where m1 matches u and
+    m2 matches v, and outputs op(x, y) for the outputs x of m1 on u and y of
+    m2 on v. Unlike concat, m2 doesn't receive x (it is initialized with
+    ()), so the two outputs have to be paired up: each split point (each
+    output of m1) starts its own copy of m2, kept together with x.
+
+    The copies are only dropped by .reset(), so the cost of an update grows
+    with the number of split points so far; split is meant for an m1 which
+    matches a bounded number of times (such as a prefix up to a marker).
+    Where m2 can be written to take x as its initial value, concat needs a
+    single copy. Like aggregate, this is not restartable.
+*/
+
+pub struct Split<I, D, X, Y, Z, M1, M2, F>
+where
+    M1: Transducer<I, D, X>,
+    M2: Transducer<(), D, Y>,
+    F: Fn(X, Y) -> Z,
+{
+    m1: M1,
+    // Never run: cloned to start each copy
+    m2: M2,
+    // The output of m1 at each split point, with its copy of m2
+    runs: Vec<(Ext<X>, M2)>,
+    op: F,
+    ph_i: PhantomData<I>,
+    ph_d: PhantomData<D>,
+    ph_y: PhantomData<Y>,
+    ph_z: PhantomData<Z>,
+}
+pub fn split<I, D, X, Y, Z, M1, M2, F>(
+    m1: M1,
+    m2: M2,
+    op: F,
+) -> Split<I, D, X, Y, Z, M1, M2, F>
+where
+    M1: Transducer<I, D, X>,
+    M2: Transducer<(), D, Y>,
+    F: Fn(X, Y) -> Z,
+{
+    Split {
+        m1,
+        m2,
+        runs: Vec::new(),
+        op,
+        ph_i: PhantomData,
+        ph_d: PhantomData,
+        ph_y: PhantomData,
+        ph_z: PhantomData,
+    }
+}
+
+impl<I, D, X, Y, Z, M1, M2, F> Split<I, D, X, Y, Z, M1, M2, F>
+where
+    X: Clone,
+    M1: Transducer<I, D, X>,
+    M2: Transducer<(), D, Y> + Clone,
+    F: Fn(X, Y) -> Z,
+{
+    // Start a copy of m2 at a split point (if any)
+    fn start(&mut self, x: Ext<X>) -> Ext<Z> {
+        if x.is_none() {
+            return Ext::None;
+        }
+        let mut m2 = self.m2.clone();
+        let y = m2.init_one(());
+        let z = ext_value::apply2(&self.op, x.clone(), y);
+        self.runs.push((x, m2));
+        z
+    }
+    // Number of copies of m2
+    pub fn n_runs(&self) -> usize {
+        self.runs.len()
+    }
+}
+impl<I, D, X, Y, Z, M1, M2, F> Clone for Split<I, D, X, Y, Z, M1, M2, F>
+where
+    X: Clone,
+    M1: Transducer<I, D, X> + Clone,
+    M2: Transducer<(), D, Y> + Clone,
+    F: Fn(X, Y) -> Z + Clone,
+{
+    fn clone(&self) -> Self {
+        let mut result =
+            split(self.m1.clone(), self.m2.clone(), self.op.clone());
+        result.runs = self.runs.clone();
+        result
+    }
+}
+impl<I, D, X, Y, Z, M1, M2, F> Transducer<I, D, Z>
+    for Split<I, D, X, Y, Z, M1, M2, F>
+where
+    X: Clone,
+    M1: Transducer<I, D, X>,
+    M2: Transducer<(), D, Y> + Clone,
+    F: Fn(X, Y) -> Z,
+{
+    fn init(&mut self, i: Ext<I>) -> Ext<Z> {
+        let x = self.m1.init(i);
+        self.start(x)
+    }
+    fn update(&mut self, item: &D) -> Ext<Z> {
+        let mut out = Ext::None;
+        for (x, m2) in self.runs.iter_mut() {
+            let y = m2.update(item);
+            if !y.is_none() {
+                out += ext_value::apply2(&self.op, x.clone(), y);
+            }
+        }
+        let x = self.m1.update(item);
+        out + self.start(x)
+    }
+    fn reset(&mut self) {
+        self.m1.reset();
+        self.runs.clear();
+    }
+
+    fn is_epsilon(&self) -> bool {
+        self.m1.is_epsilon() && self.m2.is_epsilon()
+    }
+    fn is_restartable(&self) -> bool {
+        false
+    }
+    fn is_nullable(&self) -> bool {
+        self.m1.is_nullable() && self.m2.is_nullable()
+    }
+    fn n_states(&self) -> usize {
+        self.m1.n_states() + self.m2.n_states()
+    }
+    fn n_transs(&self) -> usize {
+        self.m1.n_transs() + self.m2.n_transs()
+    }
+    fn n_bytes(&self) -> usize {
+        let runs: usize = self
+            .runs
+            .iter()
+            .map(|(_, m2)| mem::size_of::<Ext<X>>() + m2.n_bytes())
+            .sum();
+        mem::size_of::<Self>() - mem::size_of::<M1>() - mem::size_of::<M2>()
+            + self.m1.n_bytes()
+            + self.m2.n_bytes()
+            + runs
+    }
+}
+
+/*
+    QRE map-collect (StreamQRE)
+
+    map_collect(key, m) partitions the stream by key, runs a separate copy
+    of m on the items of each key, and outputs the map from each key seen
+    so far to the latest output of its copy (keys whose copy has no output
+    are left out). If a copy outputs Ext::Many, so does the map.
+
+    The copies are initialized with the initial value when their key first
+    appears, so it is stored; .init() starts over with no keys, and this is
+    not restartable. Each output clones the map, so this is meant for a
+    moderate number of keys; .update_key() only returns the output of the
+    item's copy, which takes time independent of the number of keys (see
+    also runtime::KeyedRuntime).
+*/
+
+pub struct MapCollect<I, D, K, O, M, G>
+where
+    M: Transducer<I, D, O>,
+    G: Fn(&D) -> K,
+{
+    key: G,
+    // Never run: cloned to start each copy
+    m: M,
+    init: Ext<I>,
+    runs: BTreeMap<K, M>,
+    // The latest output of each copy (if not Ext::None)
+    outs: BTreeMap<K, Ext<O>>,
+    ph_d: PhantomData<D>,
+}
+pub fn map_collect<I, D, K, O, M, G>(
+    key: G,
+    m: M,
+) -> MapCollect<I, D, K, O, M, G>
+where
+    M: Transducer<I, D, O>,
+    G: Fn(&D) -> K,
+{
+    MapCollect {
+        key,
+        m,
+        init: Ext::None,
+        runs: BTreeMap::new(),
+        outs: BTreeMap::new(),
+        ph_d: PhantomData,
+    }
+}
+
+impl<I, D, K, O, M, G> MapCollect<I, D, K, O, M, G>
+where
+    K: Ord + Clone,
+    O: Clone,
+    M: Transducer<I, D, O>,
+    G: Fn(&D) -> K,
+{
+    fn output(&self) -> Ext<BTreeMap<K, O>> {
+        let mut result = BTreeMap::new();
+        for (k, o) in &self.outs {
+            match o {
+                Ext::One(o) => {
+                    result.insert(k.clone(), o.clone());
+                }
+                Ext::Many => return Ext::Many,
+                Ext::None => unreachable!(),
+            }
+        }
+        Ext::One(result)
+    }
+    // Number of keys seen so far
+    pub fn n_keys(&self) -> usize {
+        self.runs.len()
+    }
+    // Update with the item, returning the output of the copy for its key
+    // rather than the whole map
+    pub fn update_key(&mut self, item: &D) -> Ext<O>
+    where
+        I: Clone,
+        M: Clone,
+    {
+        if self.init.is_none() {
+            return Ext::None;
+        }
+        let k = (self.key)(item);
+        let m = match self.runs.get_mut(&k) {
+            Some(m) => m,
+            None => {
+                let mut m = self.m.clone();
+                m.init(self.init.clone());
+                self.runs.entry(k.clone()).or_insert(m)
+            }
+        };
+        let o = m.update(item);
+        if o.is_none() {
+            self.outs.remove(&k);
+        } else {
+            self.outs.insert(k, o.clone());
+        }
+        o
+    }
+}
+impl<I, D, K, O, M, G> Clone for MapCollect<I, D, K, O, M, G>
+where
+    I: Clone,
+    K: Clone,
+    O: Clone,
+    M: Transducer<I, D, O> + Clone,
+    G: Fn(&D) -> K + Clone,
+{
+    fn clone(&self) -> Self {
+        let mut result = map_collect(self.key.clone(), self.m.clone());
+        result.init = self.init.clone();
+        result.runs = self.runs.clone();
+        result.outs = self.outs.clone();
+        result
+    }
+}
+impl<I, D, K, O, M, G> Transducer<I, D, BTreeMap<K, O>>
+    for MapCollect<I, D, K, O, M, G>
+where
+    I: Clone,
+    K: Ord + Clone,
+    O: Clone,
+    M: Transducer<I, D, O> + Clone,
+    G: Fn(&D) -> K,
+{
+    fn init(&mut self, i: Ext<I>) -> Ext<BTreeMap<K, O>> {
+        if i.is_none() {
+            return Ext::None;
+        }
+        self.runs.clear();
+        self.outs.clear();
+        self.init = i;
+        self.output()
+    }
+    fn update(&mut self, item: &D) -> Ext<BTreeMap<K, O>> {
+        if self.init.is_none() {
+            return Ext::None;
+        }
+        self.update_key(item);
+        self.output()
+    }
+    fn reset(&mut self) {
+        self.init = Ext::None;
+        self.runs.clear();
+        self.outs.clear();
+    }
+
+    fn is_epsilon(&self) -> bool {
+        false
+    }
+    fn is_restartable(&self) -> bool {
+        false
+    }
+    fn is_nullable(&self) -> bool {
+        true
+    }
+    fn n_states(&self) -> usize {
+        self.m.n_states() + 1
+    }
+    fn n_transs(&self) -> usize {
+        self.m.n_transs() + 1
+    }
+    fn n_bytes(&self) -> usize {
+        let runs: usize = self
+            .runs
+            .values()
+            .map(|m| {
+                mem::size_of::<K>() + mem::size_of::<Ext<O>>() + m.n_bytes()
+            })
+            .sum();
+        mem::size_of::<Self>() - mem::size_of::<M>() + self.m.n_bytes() + runs
+    }
+}
+
+/*
+    QRE count-based windows (StreamQRE)
+
+    sliding(n, m) outputs, after each item from the n-th on, the output of m
+    on the last n items; tumbling(n, m) outputs the output of m on each
+    block of n consecutive items, at the end of the block. m is initialized
+    with the initial value at the start of each window, so it is stored, and
+    .init() starts over; these are not restartable.
+
+    A sliding window keeps a copy of m for each of the n overlapping
+    windows, so each update costs n updates of m. For an aggregate with an
+    inverse (such as a sum), a cheaper sliding window is the difference of
+    two running aggregates.
+*/
+
+pub struct Sliding<I, D, O, M>
+where
+    M: Transducer<I, D, O>,
+{
+    // Never run: cloned to start each window
+    m: M,
+    size: usize,
+    init: Ext<I>,
+    // The windows started so far and not yet full, oldest first, with the
+    // number of items each has seen
+    runs: VecDeque<(usize, M)>,
+    ph_d: PhantomData<D>,
+    ph_o: PhantomData<O>,
+}
+pub fn sliding<I, D, O, M>(size: usize, m: M) -> Sliding<I, D, O, M>
+where
+    M: Transducer<I, D, O>,
+{
+    assert!(size > 0, "window size must be positive");
+    Sliding {
+        m,
+        size,
+        init: Ext::None,
+        runs: VecDeque::new(),
+        ph_d: PhantomData,
+        ph_o: PhantomData,
+    }
+}
+
+impl<I, D, O, M> Clone for Sliding<I, D, O, M>
+where
+    I: Clone,
+    M: Transducer<I, D, O> + Clone,
+{
+    fn clone(&self) -> Self {
+        let mut result = sliding(self.size, self.m.clone());
+        result.init = self.init.clone();
+        result.runs = self.runs.clone();
+        result
+    }
+}
+impl<I, D, O, M> Transducer<I, D, O> for Sliding<I, D, O, M>
+where
+    I: Clone,
+    M: Transducer<I, D, O> + Clone,
+{
+    fn init(&mut self, i: Ext<I>) -> Ext<O> {
+        if i.is_none() {
+            return Ext::None;
+        }
+        self.runs.clear();
+        self.init = i;
+        Ext::None
+    }
+    fn update(&mut self, item: &D) -> Ext<O> {
+        if self.init.is_none() {
+            return Ext::None;
+        }
+        let mut m = self.m.clone();
+        m.init(self.init.clone());
+        self.runs.push_back((0, m));
+        let mut out = Ext::None;
+        for (count, m) in self.runs.iter_mut() {
+            let o = m.update(item);
+            *count += 1;
+            if *count == self.size {
+                out = o;
+            }
+        }
+        if self.runs[0].0 == self.size {
+            self.runs.pop_front();
+        }
+        out
+    }
+    fn reset(&mut self) {
+        self.init = Ext::None;
+        self.runs.clear();
+    }
+
+    fn is_epsilon(&self) -> bool {
+        false
+    }
+    fn is_restartable(&self) -> bool {
+        false
+    }
+    fn is_nullable(&self) -> bool {
+        false
+    }
+    fn n_states(&self) -> usize {
+        self.m.n_states() + 1
+    }
+    fn n_transs(&self) -> usize {
+        self.m.n_transs() + 1
+    }
+    fn n_bytes(&self) -> usize {
+        let runs: usize = self
+            .runs
+            .iter()
+            .map(|(_, m)| mem::size_of::<usize>() + m.n_bytes())
+            .sum();
+        mem::size_of::<Self>() - mem::size_of::<M>() + self.m.n_bytes() + runs
+    }
+}
+
+pub struct Tumbling<I, D, O, M>
+where
+    M: Transducer<I, D, O>,
+{
+    m: M,
+    size: usize,
+    init: Ext<I>,
+    // Number of items in the current window
+    count: usize,
+    ph_d: PhantomData<D>,
+    ph_o: PhantomData<O>,
+}
+pub fn tumbling<I, D, O, M>(size: usize, m: M) -> Tumbling<I, D, O, M>
+where
+    M: Transducer<I, D, O>,
+{
+    assert!(size > 0, "window size must be positive");
+    Tumbling {
+        m,
+        size,
+        init: Ext::None,
+        count: 0,
+        ph_d: PhantomData,
+        ph_o: PhantomData,
+    }
+}
+
+impl<I, D, O, M> Clone for Tumbling<I, D, O, M>
+where
+    I: Clone,
+    M: Transducer<I, D, O> + Clone,
+{
+    fn clone(&self) -> Self {
+        let mut result = tumbling(self.size, self.m.clone());
+        result.init = self.init.clone();
+        result.count = self.count;
+        result
+    }
+}
+impl<I, D, O, M> Transducer<I, D, O> for Tumbling<I, D, O, M>
+where
+    I: Clone,
+    M: Transducer<I, D, O>,
+{
+    fn init(&mut self, i: Ext<I>) -> Ext<O> {
+        if i.is_none() {
+            return Ext::None;
+        }
+        self.m.reset();
+        self.count = 0;
+        self.init = i.clone();
+        self.m.init(i);
+        Ext::None
+    }
+    fn update(&mut self, item: &D) -> Ext<O> {
+        if self.init.is_none() {
+            return Ext::None;
+        }
+        let out = self.m.update(item);
+        self.count += 1;
+        if self.count < self.size {
+            return Ext::None;
+        }
+        self.m.reset();
+        self.count = 0;
+        self.m.init(self.init.clone());
+        out
+    }
+    fn reset(&mut self) {
+        self.m.reset();
+        self.init = Ext::None;
+        self.count = 0;
+    }
+
+    fn is_epsilon(&self) -> bool {
+        false
+    }
+    fn is_restartable(&self) -> bool {
+        false
+    }
+    fn is_nullable(&self) -> bool {
+        false
+    }
+    fn n_states(&self) -> usize {
+        self.m.n_states() + 1
+    }
+    fn n_transs(&self) -> usize {
+        self.m.n_transs() + 1
+    }
+    fn n_bytes(&self) -> usize {
+        mem::size_of::<Self>() - mem::size_of::<M>() + self.m.n_bytes()
+    }
+}
+
+/*
+    QRE transducer top-level wrapper
+
+    For now, all this does is save the number of states, number of transitions,
+    epsilon-ness, restartability, and nullability as this is more efficient than recomputing
+    them all the time.
+*/
+
+pub struct TopWrapper<I, D, O, M>
+where
+    M: Transducer<I, D, O>,
+{
+    pub(crate) m: M,
+    ph_i: PhantomData<I>,
+    ph_d: PhantomData<D>,
+    ph_o: PhantomData<O>,
+    epsilon: bool,
+    restartable: bool,
+    nullable: bool,
+    n_states: usize,
+    n_transs: usize,
+}
+pub fn top<I, D, O, M>(m: M) -> TopWrapper<I, D, O, M>
+where
+    M: Transducer<I, D, O>,
+{
+    let epsilon = m.is_epsilon();
+    let restartable = m.is_restartable();
+    let nullable = m.is_nullable();
+    let n_states = m.n_states();
+    let n_transs = m.n_transs();
+    TopWrapper {
+        m,
+        ph_i: PhantomData,
+        ph_d: PhantomData,
+        ph_o: PhantomData,
+        epsilon,
+        restartable,
+        nullable,
+        n_states,
+        n_transs,
+    }
+}
+
+impl<I, D, O, M> Clone for TopWrapper<I, D, O, M>
+where
+    M: Transducer<I, D, O> + Clone,
+{
+    fn clone(&self) -> Self {
+        top(self.m.clone())
+    }
+}
+impl<I, D, O, M> Transducer<I, D, O> for TopWrapper<I, D, O, M>
+where
+    M: Transducer<I, D, O>,
+{
+    fn init(&mut self, i: Ext<I>) -> Ext<O> {
+        self.m.init(i)
+    }
+    fn update(&mut self, item: &D) -> Ext<O> {
+        self.m.update(item)
+    }
+    fn reset(&mut self) {
+        self.m.reset();
+    }
+
+    fn is_epsilon(&self) -> bool {
+        self.epsilon
+    }
+    fn is_restartable(&self) -> bool {
+        self.restartable
+    }
+    fn is_nullable(&self) -> bool {
+        self.nullable
+    }
+    fn n_states(&self) -> usize {
+        self.n_states
+    }
+    fn n_transs(&self) -> usize {
+        self.n_transs
+    }
+    fn n_bytes(&self) -> usize {
+        mem::size_of::<Self>() - mem::size_of::<M>() + self.m.n_bytes()
+    }
+}
+
+/*
+    Unit Tests
+*/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interface::{PInput, RInput};
+
+    // Constants (examples)
+
+    const EX_RSTRM_1: &[RInput<i32, char>] = &[
+        RInput::Item('a'),
+        RInput::Restart(3),
+        RInput::Item('b'),
+        RInput::Restart(4),
+        RInput::Restart(6),
+        RInput::Item('c'),
+    ];
+
+    const EX_RSTRM_2: &[RInput<i32, char>] = &[RInput::Item('b')];
+
+    const EX_RSTRM_3: &[RInput<i32, char>] = &[RInput::Restart(3)];
+
+    const EX_RSTRM_4: &[RInput<i32, char>] = &[];
 
     const EX_RSTRM_5: &[RInput<i32, char>] = &[
         RInput::Restart(3),
@@ -918,6 +1930,7 @@ mod tests {
         for rstrm in EX_RSTRMS {
             assert!(m.restartability_holds_for(rstrm.iter().cloned()));
         }
+        assert_eq!(m.is_restartable_bounded(BOUNDED_ALPHABET, 4), Ok(()));
     }
 
     fn test_not_restartable<O, M>(m: &M)
@@ -930,12 +1943,24 @@ mod tests {
         // assert!(!m.is_restartable());
         for rstrm in EX_RSTRMS {
             if !(m.restartability_holds_for(rstrm.iter().cloned())) {
+                let cex = m.is_restartable_bounded(BOUNDED_ALPHABET, 4);
+                let cex = cex.expect_err("no counterexample in bounded check");
+                assert!(!m.restartability_holds_for(cex.iter().cloned()));
                 return;
             }
         }
         panic!("Not-restartable test failed: no counterexample stream found");
     }
 
+    // Alphabet for bounded restartability checks
+    const BOUNDED_ALPHABET: &[RInput<i32, char>] = &[
+        RInput::Restart(0),
+        RInput::Restart(3),
+        RInput::Item('1'),
+        RInput::Item('a'),
+        RInput::Item('b'),
+    ];
+
     // The tests
 
     #[test]
@@ -1034,6 +2059,21 @@ mod tests {
         test_restartable(&m);
     }
 
+    #[test]
+    fn test_union_op() {
+        let m1 = atom(|&ch: &char| ch == 'a' || ch == 'b', |i: i32, _ch| i + 1);
+        let m2 = atom(|&ch: &char| ch == 'a' || ch == 'c', |i: i32, _ch| i + 5);
+        let mut m = union_op(m1, m2, i32::min);
+        assert_eq!(m.init_one(10), Ext::None);
+        assert_eq!(m.update_val('a'), Ext::One(11));
+        assert_eq!(m.init_one(10), Ext::None);
+        assert_eq!(m.update_val('c'), Ext::One(15));
+        assert_eq!(m.init_one(10), Ext::None);
+        assert_eq!(m.init_one(20), Ext::None);
+        assert_eq!(m.update_val('a'), Ext::Many);
+        assert!(!m.is_restartable());
+    }
+
     #[test]
     fn test_parcomp() {
         let m1 = atom(
@@ -1131,6 +2171,56 @@ mod tests {
         test_restartable(&m);
     }
 
+    #[test]
+    fn test_iterate_loopy() {
+        // Iterating a nullable sub-transducer is loopy: every init circulates
+        // back through m, so the output is Many regardless of call history
+        let m1 = union(epsilon(|i: i32| i + 1), atom(|_ch| true, |i, _ch| i));
+        assert!(m1.is_nullable());
+        let mut m = iterate(m1);
+        assert!(m.loopy);
+        assert_eq!(m.update_val('a'), Ext::None);
+        assert_eq!(m.init_one(1), Ext::Many);
+        assert_eq!(m.update_val('a'), Ext::Many);
+        let mut m2 = m.clone();
+        m2.reset();
+        assert_eq!(m2.init_one(2), Ext::Many);
+
+        // Non-nullable sub-transducer: not loopy
+        let m3 = iterate(atom(|&ch: &char| ch == 'a', |i: i32, _ch| i + 1));
+        assert!(!m3.loopy);
+        assert!(m3.is_nullable());
+        test_restartable(&m);
+        test_restartable(&m3);
+    }
+
+    #[test]
+    fn test_try_constructors() {
+        let digit = || atom(|ch: &char| ch.is_ascii_digit(), |i: i32, _ch| i);
+        let sum = || {
+            let agg = aggregate(digit(), |z: i32, y| z + y);
+            concat(epsilon(|i: i32| (i, 0)), agg)
+        };
+        assert!(!sum().is_restartable());
+
+        let err = try_concat(digit(), sum()).err().unwrap();
+        assert_eq!(err.construct, "concat");
+        assert_eq!(err.operand, 2);
+        assert_eq!(err.requirement, Requirement::RestartableOrAfterEpsilon);
+        assert_eq!(
+            err.to_string(),
+            "concat: operand 2 must be restartable (or follow an epsilon)"
+        );
+        assert!(try_concat(epsilon(|i: i32| i), sum()).is_ok());
+        assert!(try_concat(sum(), digit()).is_ok());
+
+        let err = try_iterate(sum()).err().unwrap();
+        assert_eq!(err.to_string(), "iterate: operand 1 must be restartable");
+        let mut m = try_iterate(digit()).unwrap();
+        assert_eq!(m.init_one(3), Ext::One(3));
+        assert_eq!(m.update_val('1'), Ext::One(3));
+    }
+
     #[test]
     fn test_aggregate() {
         let m1 = atom(|ch: &char| ch.is_ascii_digit(), |i, _ch| i + 1);
@@ -1156,6 +2246,38 @@ mod tests {
         test_not_restartable(&m);
     }
 
+    #[test]
+    fn test_aggregate_ref() {
+        // The aggregate is returned by reference, so needn't be Clone
+        #[derive(Debug, PartialEq)]
+        struct Items(Vec<char>);
+        let m1 = iterate(atom(|ch: &char| ch.is_ascii_digit(), |(), _ch| ()));
+        let mut m = aggregate(m1, |mut items: Items, ()| {
+            items.0.push('.');
+            items
+        });
+        assert_eq!(m.init_ref(Ext::One(((), Items(vec![])))).unwrap().0, ['.']);
+        assert_eq!(m.update_ref(&'0').unwrap().0, ['.', '.']);
+        assert_eq!(m.update_ref(&'a'), Ext::None);
+        assert_eq!(m.update_ref(&'0'), Ext::None);
+    }
+
+    #[test]
+    fn test_n_bytes() {
+        let m1 = epsilon(|i: i32| i + 2);
+        let m2 = atom(|&ch: &char| ch == 'a', |i: i32, _ch| i + 3);
+        assert_eq!(m1.n_bytes(), mem::size_of_val(&m1));
+        assert_eq!(m2.n_bytes(), mem::size_of_val(&m2));
+        let m3 = union(m1.clone(), m2.clone());
+        assert_eq!(m3.n_bytes(), mem::size_of_val(&m3));
+        // Boxed sub-transducers are counted on top of the box itself
+        let b1: Box<dyn Transducer<i32, char, i32>> = Box::new(m2.clone());
+        let m4 = concat(m1, b1);
+        assert_eq!(m4.n_bytes(), mem::size_of_val(&m4) + mem::size_of_val(&m2));
+        let t4 = top(m4);
+        assert_eq!(t4.n_bytes(), mem::size_of_val(&t4) + mem::size_of_val(&m2));
+    }
+
     #[test]
     fn test_top_wrapper() {
         let m1 = epsilon(|i: i32| i + 2);
@@ -1171,4 +2293,190 @@ mod tests {
         test_equiv(m3, t3);
         test_equiv(m4, t4);
     }
+
+    #[test]
+    fn test_punctuated() {
+        // Sum of the 'a' items in each window
+        let sum = iterate(union(
+            atom(|&(ch, _)| ch == 'a', |s: i32, &(_, x): &(char, i32)| s + x),
+            atom(|&(ch, _)| ch == 'b', |s, _| s),
+        ));
+        let mut m = punctuated(sum);
+        assert!(!m.is_restartable());
+        let strm: Vec<PInput<i32, (char, i32)>> = vec![
+            RInput::Restart(0),
+            RInput::Item(PItem::Item(('a', 6))),
+            RInput::Item(PItem::Item(('b', 2))),
+            RInput::Item(PItem::Item(('a', 8))),
+            RInput::Item(PItem::Punct),
+            RInput::Item(PItem::Punct),
+            RInput::Item(PItem::Item(('a', 1))),
+            RInput::Item(PItem::Item(('c', 1))),
+            RInput::Item(PItem::Punct),
+        ];
+        let out: Vec<Ext<i32>> =
+            m.process_rstream_single(strm.into_iter()).collect();
+        let expected = vec![
+            Ext::None,
+            Ext::None,
+            Ext::None,
+            Ext::None,
+            Ext::One(14),
+            Ext::One(0),
+            Ext::None,
+            Ext::None,
+            Ext::None,
+        ];
+        assert_eq!(out, expected);
+        m.reset();
+        m.init_one(10);
+        assert_eq!(m.update(&PItem::Punct), Ext::One(10));
+    }
+
+    #[test]
+    fn test_within() {
+        // A request 'q' followed by a response 'r' within 3 items
+        let request = atom(|&ch: &char| ch == 'q', |(), _| ());
+        let other = iterate(atom(|&ch: &char| ch == '.', |(), _| ()));
+        let response = atom(|&ch: &char| ch == 'r', |(), _| 1);
+        let m = within(concat(concat(request, other), response), 3);
+        assert!(!m.is_restartable());
+        let mut m1 = m.clone();
+        m1.init_one(());
+        let out = m1.update_batch(&['q', '.', 'r', 'r']);
+        assert_eq!(out, vec![Ext::None, Ext::None, Ext::One(1), Ext::None]);
+        let mut m2 = m.clone();
+        m2.init_one(());
+        let out = m2.update_batch(&['q', '.', '.', 'r']);
+        assert_eq!(out, vec![Ext::None; 4]);
+        // .init() restarts the count
+        assert_eq!(m2.init_one(()), Ext::None);
+        assert_eq!(m2.update_batch(&['q', 'r']), vec![Ext::None, Ext::One(1)]);
+    }
+
+    // StreamQRE examples, on streams of (key, value) measurements
+
+    fn count<D>() -> impl Transducer<(), D, i32> + Clone {
+        concat(epsilon(|()| 0), iterate(atom(|_| true, |n: i32, _| n + 1)))
+    }
+    fn sum<K>() -> impl Transducer<(), (K, i32), i32> + Clone {
+        concat(
+            epsilon(|()| 0),
+            iterate(atom(|_| true, |s: i32, &(_, x): &(K, i32)| s + x)),
+        )
+    }
+
+    #[test]
+    fn test_apply_combine() {
+        let avg = combine(sum(), count(), |s, n| (s, n));
+        let mut m = apply(avg, |(s, n)| if n == 0 { 0 } else { s / n });
+        m.init_one(());
+        let out = m.update_batch(&[('a', 4), ('b', 8), ('a', 6)]);
+        assert_eq!(out, vec![Ext::One(4), Ext::One(6), Ext::One(6)]);
+    }
+
+    #[test]
+    fn test_iter() {
+        // Length of the longest chunk of the form x*# so far
+        let chunk = concat(
+            epsilon(|()| 0),
+            concat(
+                iterate(atom(|&ch: &char| ch == 'x', |n: i32, _| n + 1)),
+                atom(|&ch: &char| ch == '#', |n, _| n),
+            ),
+        );
+        let mut m = iter(chunk, 0, |best: i32, n| best.max(n));
+        assert_eq!(m.init_one(()), Ext::One(0));
+        let out = m.update_batch(&"xx#x#xxx#x".chars().collect::<Vec<_>>());
+        let n = Ext::None;
+        let expected =
+            vec![n, n, Ext::One(2), n, Ext::One(2), n, n, n, Ext::One(3), n];
+        assert_eq!(out, expected);
+    }
+
+    #[test]
+    fn test_split() {
+        // Number of items up to the first '#', and after it
+        let upto_marker =
+            concat(count(), atom(|&ch: &char| ch == '#', |n: i32, _| n + 1));
+        let mut m = split(upto_marker, count(), |x, y| (x, y));
+        assert!(!m.is_restartable());
+        m.init_one(());
+        let out = m.update_batch(&['a', 'b', '#', 'c', '#']);
+        assert_eq!(out[..2], [Ext::None, Ext::None]);
+        assert_eq!(out[2..], [Ext::One((3, 0)), Ext::One((3, 1)), Ext::Many]);
+        assert_eq!(m.n_runs(), 2);
+        // A copy of m2 for each split point: the count since the last '#'
+        let no_marker = concat(
+            epsilon(|()| 0),
+            iterate(atom(|&ch: &char| ch != '#', |n: i32, _| n + 1)),
+        );
+        let anything = concat(count(), atom(|_| true, |n: i32, _| n + 1));
+        let mut m = split(
+            concat(anything, atom(|&ch: &char| ch == '#', |n: i32, _| n + 1)),
+            no_marker,
+            |x, y| (x, y),
+        );
+        m.init_one(());
+        let out = m.update_batch(&['a', '#', 'b', '#', 'c']);
+        let expected = vec![
+            Ext::None,
+            Ext::One((2, 0)),
+            Ext::One((2, 1)),
+            Ext::One((4, 0)),
+            Ext::One((4, 1)),
+        ];
+        assert_eq!(out, expected);
+        m.reset();
+        assert_eq!(m.n_runs(), 0);
+    }
+
+    #[test]
+    fn test_map_collect() {
+        // Total of each key
+        let mut m = map_collect(|&(k, _): &(char, i32)| k, sum());
+        assert_eq!(m.init_one(()), Ext::One(BTreeMap::new()));
+        let out = m.update_batch(&[('a', 1), ('b', 2), ('a', 3)]);
+        let last = out[2].clone().unwrap();
+        assert_eq!(last.into_iter().collect::<Vec<_>>(), [('a', 4), ('b', 2)]);
+        assert_eq!(m.n_keys(), 2);
+        // Per-key sums of the last two values
+        let mut m = map_collect(|&(k, _): &(char, i32)| k, sliding(2, sum()));
+        m.init_one(());
+        let out = m.update_batch(&[('a', 1), ('b', 2), ('a', 3), ('a', 5)]);
+        let maps: Vec<Vec<(char, i32)>> =
+            out.into_iter().map(|o| o.unwrap().into_iter().collect()).collect();
+        assert_eq!(maps, vec![vec![], vec![], vec![('a', 4)], vec![('a', 8)]]);
+        // .init() starts over
+        m.init_one(());
+        assert_eq!(m.n_keys(), 0);
+        // Only the output of the item's key, which is kept in the map
+        assert_eq!(m.update_key(&('a', 1)), Ext::None);
+        assert_eq!(m.update_key(&('b', 2)), Ext::None);
+        assert_eq!(m.update_key(&('a', 3)), Ext::One(4));
+        let map = m.update(&('b', 5)).unwrap();
+        assert_eq!(map.into_iter().collect::<Vec<_>>(), [('a', 4), ('b', 7)]);
+        m.reset();
+        assert_eq!(m.update_key(&('a', 1)), Ext::None);
+    }
+
+    #[test]
+    fn test_windows() {
+        let items: Vec<((), i32)> = (1..=6).map(|x| ((), x)).collect();
+        let mut m = sliding(3, sum());
+        assert_eq!(m.init_one(()), Ext::None);
+        let out = m.update_batch(&items);
+        let n = Ext::None;
+        let expected =
+            vec![n, n, Ext::One(6), Ext::One(9), Ext::One(12), Ext::One(15)];
+        assert_eq!(out, expected);
+        let mut m = tumbling(2, sum());
+        m.init_one(());
+        let out = m.update_batch(&items);
+        let expected = vec![n, Ext::One(3), n, Ext::One(7), n, Ext::One(11)];
+        assert_eq!(out, expected);
+        let mut m = m.clone();
+        m.reset();
+        assert_eq!(m.update(&((), 1)), Ext::None);
+    }
 }
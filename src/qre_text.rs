@@ -0,0 +1,921 @@
+/*
+    A textual syntax for QREs, with a typechecker.
+
+    The combinators of qre.rs are generic Rust functions, so their
+    restrictions are only checked when a QRE is built: ill-typed terms are
+    rejected by rustc, and a concat or iterate on an operand that isn't
+    restartable panics. For a QRE written as text (e.g. read from a
+    configuration file), neither is acceptable, so the text is parsed to an
+    AST and typechecked first; only a well-typed term is instantiated, and
+    the errors are reported with their location in the text.
+
+    Syntax (one expression, whitespace is ignored):
+        epsilon(f)            f: I -> O
+        atom(g, f)            g: guard on items, f: (I, item) -> O
+        union(e1, e2, ...)    same signature for each ei
+        concat(e1, e2, ...)   output of each ei is the input of the next
+        iterate(e)            e: X -> X
+        apply(e, f)           e: I -> Y, f: Y -> Z
+        combine(e1, e2, op)   e1: I -> O1, e2: I -> O2, op: (O1, O2) -> O
+    where g, f and op are names registered in a Table, with their types.
+    union and concat with more than two operands associate to the left.
+
+    The typechecker infers the signature I -> O of each subexpression, as
+    well as whether it is an epsilon and whether it is restartable (by the
+    same rules as the transducers of qre.rs), and checks:
+    - the types of the operands and functions agree;
+    - the second operand of concat is restartable, or the first one is an
+      epsilon;
+    - the body of iterate is restartable.
+    combine (parcomp followed by an operation) is not restartable; a
+    restartability error points to the combine responsible.
+
+    Values are dynamically typed (Val), so the QRE has type
+    Transducer<Val, D, Val>; well-typed terms never see a value of the
+    wrong type at runtime.
+
+    Example:
+        concat(epsilon(zero), iterate(union(atom(pos, add), atom(neg, skip))))
+*/
+
+use super::ext_value::Ext;
+use super::interface::Transducer;
+use super::qre;
+use super::scanner::{Scanner, SyntaxError};
+use std::any::{self, Any, TypeId};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::{self, Debug, Display};
+use std::ops::Range;
+use std::rc::Rc;
+
+/*
+    Dynamically typed values
+*/
+
+trait AnyVal: Debug {
+    fn as_any(&self) -> &dyn Any;
+    fn eq_val(&self, other: &dyn AnyVal) -> bool;
+}
+impl<T: Any + Debug + Eq> AnyVal for T {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+    fn eq_val(&self, other: &dyn AnyVal) -> bool {
+        other.as_any().downcast_ref::<T>() == Some(self)
+    }
+}
+
+#[derive(Clone)]
+pub struct Val(Rc<dyn AnyVal>);
+impl Val {
+    pub fn new<T: Any + Debug + Eq>(x: T) -> Self {
+        Val(Rc::new(x))
+    }
+    // The value, if it has type T
+    pub fn get<T: Any>(&self) -> Option<&T> {
+        self.0.as_any().downcast_ref()
+    }
+    fn take<T: Any + Clone>(&self) -> T {
+        self.get::<T>().expect("value of the wrong type").clone()
+    }
+}
+impl Debug for Val {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+impl PartialEq for Val {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.eq_val(&*other.0)
+    }
+}
+impl Eq for Val {}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Type {
+    id: TypeId,
+    name: &'static str,
+}
+impl Type {
+    pub fn of<T: Any>() -> Self {
+        Type { id: TypeId::of::<T>(), name: any::type_name::<T>() }
+    }
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+}
+impl PartialEq for Type {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id
+    }
+}
+impl Eq for Type {}
+impl Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name)
+    }
+}
+
+/*
+    Table of named guards and functions, with their types
+*/
+
+type GuardFn<'a, D> = Rc<dyn Fn(&D) -> bool + 'a>;
+type EpsFn<'a> = Rc<dyn Fn(Val) -> Val + 'a>;
+type AtomFn<'a, D> = Rc<dyn Fn(Val, &D) -> Val + 'a>;
+type OpFn<'a> = Rc<dyn Fn(Val, Val) -> Val + 'a>;
+
+struct Entry<F> {
+    args: Vec<Type>,
+    out: Type,
+    f: F,
+}
+
+pub struct Table<'a, D> {
+    guards: HashMap<String, GuardFn<'a, D>>,
+    epsilons: HashMap<String, Entry<EpsFn<'a>>>,
+    atoms: HashMap<String, Entry<AtomFn<'a, D>>>,
+    ops: HashMap<String, Entry<OpFn<'a>>>,
+}
+
+impl<'a, D: 'a> Default for Table<'a, D> {
+    fn default() -> Self {
+        Table {
+            guards: HashMap::new(),
+            epsilons: HashMap::new(),
+            atoms: HashMap::new(),
+            ops: HashMap::new(),
+        }
+    }
+}
+
+impl<'a, D: 'a> Table<'a, D> {
+    pub fn new() -> Self {
+        Default::default()
+    }
+    // Register functions under a name (replacing any previous function of
+    // the same kind with that name)
+    pub fn guard<G>(&mut self, name: &str, g: G) -> &mut Self
+    where
+        G: Fn(&D) -> bool + 'a,
+    {
+        self.guards.insert(name.to_string(), Rc::new(g));
+        self
+    }
+    // Action of an epsilon (or of apply)
+    pub fn epsilon<I, O, F>(&mut self, name: &str, f: F) -> &mut Self
+    where
+        I: Any + Clone,
+        O: Any + Debug + Eq,
+        F: Fn(I) -> O + 'a,
+    {
+        let entry = Entry {
+            args: vec![Type::of::<I>()],
+            out: Type::of::<O>(),
+            f: Rc::new(move |v: Val| Val::new(f(v.take()))) as EpsFn<'a>,
+        };
+        self.epsilons.insert(name.to_string(), entry);
+        self
+    }
+    // Action of an atom
+    pub fn atom<I, O, F>(&mut self, name: &str, f: F) -> &mut Self
+    where
+        I: Any + Clone,
+        O: Any + Debug + Eq,
+        F: Fn(I, &D) -> O + 'a,
+    {
+        let entry = Entry {
+            args: vec![Type::of::<I>()],
+            out: Type::of::<O>(),
+            f: Rc::new(move |v: Val, d: &D| Val::new(f(v.take(), d)))
+                as AtomFn<'a, D>,
+        };
+        self.atoms.insert(name.to_string(), entry);
+        self
+    }
+    // Operation of combine
+    pub fn op<A, B, O, F>(&mut self, name: &str, f: F) -> &mut Self
+    where
+        A: Any + Clone,
+        B: Any + Clone,
+        O: Any + Debug + Eq,
+        F: Fn(A, B) -> O + 'a,
+    {
+        let entry = Entry {
+            args: vec![Type::of::<A>(), Type::of::<B>()],
+            out: Type::of::<O>(),
+            f: Rc::new(move |a: Val, b: Val| Val::new(f(a.take(), b.take())))
+                as OpFn<'a>,
+        };
+        self.ops.insert(name.to_string(), entry);
+        self
+    }
+}
+
+/*
+    Diagnostics
+*/
+
+// Byte offsets in the text
+pub type Span = Range<usize>;
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Diagnostic {
+    pub span: Span,
+    pub msg: String,
+    // Related locations (e.g. the subexpression which isn't restartable)
+    pub notes: Vec<(Span, String)>,
+}
+impl Diagnostic {
+    fn new(span: &Span, msg: String) -> Self {
+        Diagnostic { span: span.clone(), msg, notes: Vec::new() }
+    }
+    // The message with the line and column, followed by the line of text
+    // with the span underlined (and similarly for the notes)
+    pub fn render(&self, text: &str) -> String {
+        let mut result = String::new();
+        let mut show = |span: &Span, prefix: &str, msg: &str| {
+            let line_start =
+                text[..span.start].rfind('\n').map_or(0, |i| i + 1);
+            let line_end = text[span.start..]
+                .find('\n')
+                .map_or(text.len(), |i| span.start + i);
+            let line = text[..span.start].matches('\n').count() + 1;
+            let col = text[line_start..span.start].chars().count() + 1;
+            let width =
+                text[span.start..span.end.min(line_end)].chars().count().max(1);
+            result += &format!("{}:{}: {}{}\n", line, col, prefix, msg);
+            result += &format!("    {}\n", &text[line_start..line_end]);
+            result +=
+                &format!("    {}{}\n", " ".repeat(col - 1), "^".repeat(width));
+        };
+        show(&self.span, "error: ", &self.msg);
+        for (span, msg) in &self.notes {
+            show(span, "note: ", msg);
+        }
+        result
+    }
+}
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "at offset {}: {}", self.span.start, self.msg)
+    }
+}
+impl Error for Diagnostic {}
+impl SyntaxError for Diagnostic {
+    fn at(span: Span, msg: String) -> Self {
+        Diagnostic::new(&span, msg)
+    }
+}
+
+/*
+    AST and parser
+*/
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Name {
+    pub text: String,
+    pub span: Span,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ExprKind {
+    Epsilon(Name),
+    Atom(Name, Name),
+    Union(Box<Expr>, Box<Expr>),
+    Concat(Box<Expr>, Box<Expr>),
+    Iterate(Box<Expr>),
+    Apply(Box<Expr>, Name),
+    Combine(Box<Expr>, Box<Expr>, Name),
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Expr {
+    pub kind: ExprKind,
+    pub span: Span,
+}
+
+// Argument of a call before it is resolved: a name or a call
+enum Arg {
+    Name(Name),
+    Call(Expr),
+}
+
+pub fn parse(text: &str) -> Result<Expr, Diagnostic> {
+    let mut parser = Parser::new(text);
+    let e = parser.expr()?;
+    parser.expect_end("input")?;
+    Ok(e)
+}
+
+type Parser<'t> = Scanner<'t, Diagnostic>;
+
+impl Parser<'_> {
+    // Unlike Scanner::word(), names may contain any letters or digits
+    fn name(&mut self) -> Result<Name, Diagnostic> {
+        self.skip_space();
+        let rest = &self.text[self.pos..];
+        let len = rest
+            .find(|c: char| !c.is_alphanumeric() && c != '_')
+            .unwrap_or(rest.len());
+        if len == 0 {
+            return Err(self.error("expected a name"));
+        }
+        let span = self.pos..self.pos + len;
+        self.pos += len;
+        Ok(Name { text: rest[..len].to_string(), span })
+    }
+    fn arg(&mut self) -> Result<Arg, Diagnostic> {
+        let name = self.name()?;
+        if self.eat("(") {
+            self.call(name).map(Arg::Call)
+        } else {
+            Ok(Arg::Name(name))
+        }
+    }
+    fn expr(&mut self) -> Result<Expr, Diagnostic> {
+        match self.arg()? {
+            Arg::Call(e) => Ok(e),
+            Arg::Name(name) => {
+                let msg = format!("expected a QRE, found `{}`", name.text);
+                Err(Diagnostic::new(&name.span, msg))
+            }
+        }
+    }
+    // The rest of a call, after the opening parenthesis
+    fn call(&mut self, head: Name) -> Result<Expr, Diagnostic> {
+        let mut args = vec![self.arg()?];
+        while self.eat(",") {
+            args.push(self.arg()?);
+        }
+        if !self.eat(")") {
+            return Err(self.error("expected `,` or `)`"));
+        }
+        let span = head.span.start..self.pos;
+        let arity_error = |n: &str| {
+            let msg = format!("{} takes {} argument(s)", head.text, n);
+            Err(Diagnostic::new(&span, msg))
+        };
+        let mut args = args.into_iter();
+        let kind = match (head.text.as_str(), args.len()) {
+            ("epsilon", 1) => ExprKind::Epsilon(fn_arg(args.next())?),
+            ("atom", 2) => {
+                ExprKind::Atom(fn_arg(args.next())?, fn_arg(args.next())?)
+            }
+            ("union", n) | ("concat", n) if n >= 2 => {
+                let mut e = qre_arg(args.next())?;
+                for arg in args {
+                    let (e1, e2) = (Box::new(e), Box::new(qre_arg(Some(arg))?));
+                    let span = e1.span.start..e2.span.end;
+                    let kind = if head.text == "union" {
+                        ExprKind::Union(e1, e2)
+                    } else {
+                        ExprKind::Concat(e1, e2)
+                    };
+                    e = Expr { kind, span };
+                }
+                // The outermost node spans the whole call
+                return Ok(Expr { kind: e.kind, span });
+            }
+            ("iterate", 1) => {
+                ExprKind::Iterate(Box::new(qre_arg(args.next())?))
+            }
+            ("apply", 2) => ExprKind::Apply(
+                Box::new(qre_arg(args.next())?),
+                fn_arg(args.next())?,
+            ),
+            ("combine", 3) => ExprKind::Combine(
+                Box::new(qre_arg(args.next())?),
+                Box::new(qre_arg(args.next())?),
+                fn_arg(args.next())?,
+            ),
+            ("epsilon", _) | ("iterate", _) => return arity_error("1"),
+            ("atom", _) | ("apply", _) => return arity_error("2"),
+            ("union", _) | ("concat", _) => return arity_error("2 or more"),
+            ("combine", _) => return arity_error("3"),
+            (other, _) => {
+                let msg = format!("unknown combinator `{}`", other);
+                return Err(Diagnostic::new(&head.span, msg));
+            }
+        };
+        Ok(Expr { kind, span })
+    }
+}
+
+fn qre_arg(arg: Option<Arg>) -> Result<Expr, Diagnostic> {
+    match arg.unwrap() {
+        Arg::Call(e) => Ok(e),
+        Arg::Name(name) => {
+            let msg = format!("expected a QRE, found `{}`", name.text);
+            Err(Diagnostic::new(&name.span, msg))
+        }
+    }
+}
+fn fn_arg(arg: Option<Arg>) -> Result<Name, Diagnostic> {
+    match arg.unwrap() {
+        Arg::Name(name) => Ok(name),
+        Arg::Call(e) => {
+            Err(Diagnostic::new(&e.span, "expected a name".to_string()))
+        }
+    }
+}
+
+/*
+    The typechecker
+*/
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Sig {
+    pub input: Type,
+    pub output: Type,
+    pub epsilon: bool,
+    pub restartable: bool,
+}
+impl Display for Sig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} -> {}", self.input, self.output)
+    }
+}
+
+// Sig of a subexpression, with the combine making it not restartable
+struct Info {
+    sig: Sig,
+    blame: Option<Span>,
+}
+
+pub fn check<D>(
+    e: &Expr,
+    table: &Table<'_, D>,
+) -> Result<Sig, Vec<Diagnostic>> {
+    let mut checker = Checker { table, errors: Vec::new() };
+    match checker.expr(e) {
+        Some(info) if checker.errors.is_empty() => Ok(info.sig),
+        _ => Err(checker.errors),
+    }
+}
+
+struct Checker<'t, 'a, D> {
+    table: &'t Table<'a, D>,
+    errors: Vec<Diagnostic>,
+}
+impl<D> Checker<'_, '_, D> {
+    fn error(&mut self, span: &Span, msg: String) {
+        self.errors.push(Diagnostic::new(span, msg));
+    }
+    fn lookup<'e, F>(
+        &mut self,
+        map: &'e HashMap<String, Entry<F>>,
+        kind: &str,
+        name: &Name,
+    ) -> Option<&'e Entry<F>> {
+        let result = map.get(&name.text);
+        if result.is_none() {
+            self.error(&name.span, format!("unknown {} `{}`", kind, name.text));
+        }
+        result
+    }
+    // Report that info (the operand of e) must be restartable
+    fn not_restartable(&mut self, info: &Info, span: &Span, msg: String) {
+        let mut diag = Diagnostic::new(span, msg);
+        if let Some(blame) = &info.blame {
+            let note = "not restartable because of this combine".to_string();
+            diag.notes.push((blame.clone(), note));
+        }
+        self.errors.push(diag);
+    }
+
+    // Check the subexpressions even after an error, to report all errors
+    fn expr(&mut self, e: &Expr) -> Option<Info> {
+        match &e.kind {
+            ExprKind::Epsilon(f) => {
+                let f =
+                    self.lookup(&self.table.epsilons, "epsilon action", f)?;
+                let sig = Sig {
+                    input: f.args[0],
+                    output: f.out,
+                    epsilon: true,
+                    restartable: true,
+                };
+                Some(Info { sig, blame: None })
+            }
+            ExprKind::Atom(g, f) => {
+                if !self.table.guards.contains_key(&g.text) {
+                    self.error(&g.span, format!("unknown guard `{}`", g.text));
+                }
+                let f = self.lookup(&self.table.atoms, "atom action", f)?;
+                let sig = Sig {
+                    input: f.args[0],
+                    output: f.out,
+                    epsilon: false,
+                    restartable: true,
+                };
+                Some(Info { sig, blame: None })
+            }
+            ExprKind::Union(e1, e2) => {
+                let (i1, i2) = (self.expr(e1), self.expr(e2));
+                let (i1, i2) = (i1?, i2?);
+                let (s1, s2) = (i1.sig, i2.sig);
+                if (s1.input, s1.output) != (s2.input, s2.output) {
+                    let msg =
+                        format!("union of different types: {} and {}", s1, s2);
+                    self.error(&e2.span, msg);
+                    return None;
+                }
+                let sig = Sig {
+                    epsilon: s1.epsilon && s2.epsilon,
+                    restartable: s1.restartable && s2.restartable,
+                    ..s1
+                };
+                Some(Info { sig, blame: i1.blame.or(i2.blame) })
+            }
+            ExprKind::Concat(e1, e2) => {
+                let (i1, i2) = (self.expr(e1), self.expr(e2));
+                let (i1, i2) = (i1?, i2?);
+                let (s1, s2) = (i1.sig, i2.sig);
+                let mut ok = true;
+                if s1.output != s2.input {
+                    let msg = format!(
+                        "concat: the first operand outputs {}, but the \
+                         second one takes {}",
+                        s1.output, s2.input
+                    );
+                    self.error(&e2.span, msg);
+                    ok = false;
+                }
+                if !s2.restartable && !s1.epsilon {
+                    let msg = "concat: the second operand must be \
+                               restartable (or the first one an epsilon)"
+                        .to_string();
+                    self.not_restartable(&i2, &e2.span, msg);
+                    ok = false;
+                }
+                if !ok {
+                    return None;
+                }
+                let sig = Sig {
+                    input: s1.input,
+                    output: s2.output,
+                    epsilon: s1.epsilon && s2.epsilon,
+                    restartable: s1.restartable && s2.restartable,
+                };
+                Some(Info { sig, blame: i1.blame.or(i2.blame) })
+            }
+            ExprKind::Iterate(body) => {
+                let info = self.expr(body)?;
+                let s = info.sig;
+                let mut ok = true;
+                if s.input != s.output {
+                    let msg = format!(
+                        "iterate: the body must output its input type, \
+                         but it has type {}",
+                        s
+                    );
+                    self.error(&body.span, msg);
+                    ok = false;
+                }
+                if !s.restartable {
+                    let msg = "iterate: the body must be restartable";
+                    self.not_restartable(&info, &body.span, msg.to_string());
+                    ok = false;
+                }
+                if !ok {
+                    return None;
+                }
+                let sig = Sig { restartable: true, ..s };
+                Some(Info { sig, blame: None })
+            }
+            ExprKind::Apply(e1, name) => {
+                let info = self.expr(e1);
+                let f =
+                    self.lookup(&self.table.epsilons, "epsilon action", name);
+                let (info, f) = (info?, f?);
+                if info.sig.output != f.args[0] {
+                    let msg = format!(
+                        "apply: the QRE outputs {}, but `{}` takes {}",
+                        info.sig.output, name.text, f.args[0]
+                    );
+                    self.error(&e.span, msg);
+                    return None;
+                }
+                let sig = Sig { output: f.out, ..info.sig };
+                Some(Info { sig, blame: info.blame })
+            }
+            ExprKind::Combine(e1, e2, op) => {
+                let (i1, i2) = (self.expr(e1), self.expr(e2));
+                let op_entry = self.lookup(&self.table.ops, "operation", op);
+                let (i1, i2, op_entry) = (i1?, i2?, op_entry?);
+                let (s1, s2) = (i1.sig, i2.sig);
+                let mut ok = true;
+                if s1.input != s2.input {
+                    let msg = format!(
+                        "combine: the operands take different inputs, \
+                         {} and {}",
+                        s1.input, s2.input
+                    );
+                    self.error(&e2.span, msg);
+                    ok = false;
+                }
+                if op_entry.args != [s1.output, s2.output] {
+                    let msg = format!(
+                        "combine: `{}` takes ({}, {}), but the operands \
+                         output ({}, {})",
+                        op.text,
+                        op_entry.args[0],
+                        op_entry.args[1],
+                        s1.output,
+                        s2.output
+                    );
+                    self.error(&op.span, msg);
+                    ok = false;
+                }
+                if !ok {
+                    return None;
+                }
+                let sig = Sig {
+                    input: s1.input,
+                    output: op_entry.out,
+                    epsilon: s1.epsilon && s2.epsilon,
+                    restartable: false,
+                };
+                Some(Info { sig, blame: Some(e.span.clone()) })
+            }
+        }
+    }
+}
+
+/*
+    Instantiation
+*/
+
+// A QRE built from a well-typed term. It reports the restartability
+// computed by the typechecker, rather than asking its parts (parcomp, in
+// combine, can't decide it).
+pub struct Qre<'a, D> {
+    m: Box<dyn Transducer<Val, D, Val> + 'a>,
+    sig: Sig,
+}
+
+impl<'a, D: 'a> Qre<'a, D> {
+    pub fn sig(&self) -> Sig {
+        self.sig
+    }
+    fn new<M>(m: M, sig: Sig) -> Self
+    where
+        M: Transducer<Val, D, Val> + 'a,
+    {
+        Qre { m: Box::new(m), sig }
+    }
+}
+
+impl<D> Transducer<Val, D, Val> for Qre<'_, D> {
+    fn init(&mut self, i: Ext<Val>) -> Ext<Val> {
+        self.m.init(i)
+    }
+    fn update(&mut self, item: &D) -> Ext<Val> {
+        self.m.update(item)
+    }
+    fn reset(&mut self) {
+        self.m.reset()
+    }
+    fn is_epsilon(&self) -> bool {
+        self.sig.epsilon
+    }
+    fn is_restartable(&self) -> bool {
+        self.sig.restartable
+    }
+    fn is_nullable(&self) -> bool {
+        self.m.is_nullable()
+    }
+    fn n_states(&self) -> usize {
+        self.m.n_states()
+    }
+    fn n_transs(&self) -> usize {
+        self.m.n_transs()
+    }
+}
+
+// Parse, typecheck and instantiate a QRE
+pub fn compile<'a, D: 'a>(
+    text: &str,
+    table: &Table<'a, D>,
+) -> Result<Qre<'a, D>, Vec<Diagnostic>> {
+    let e = parse(text).map_err(|err| vec![err])?;
+    check(&e, table)?;
+    Ok(instantiate(&e, table))
+}
+
+// e must be well-typed
+fn instantiate<'a, D: 'a>(e: &Expr, table: &Table<'a, D>) -> Qre<'a, D> {
+    let sig = |m: &Qre<'a, D>| m.sig;
+    match &e.kind {
+        ExprKind::Epsilon(f) => {
+            let f = &table.epsilons[&f.text];
+            let sig = Sig {
+                input: f.args[0],
+                output: f.out,
+                epsilon: true,
+                restartable: true,
+            };
+            let f = f.f.clone();
+            Qre::new(qre::epsilon(move |v| f(v)), sig)
+        }
+        ExprKind::Atom(g, f) => {
+            let g = table.guards[&g.text].clone();
+            let f = &table.atoms[&f.text];
+            let sig = Sig {
+                input: f.args[0],
+                output: f.out,
+                epsilon: false,
+                restartable: true,
+            };
+            let f = f.f.clone();
+            Qre::new(qre::atom(move |d| g(d), move |v, d| f(v, d)), sig)
+        }
+        ExprKind::Union(e1, e2) => {
+            let (m1, m2) = (instantiate(e1, table), instantiate(e2, table));
+            let (s1, s2) = (sig(&m1), sig(&m2));
+            let sig = Sig {
+                epsilon: s1.epsilon && s2.epsilon,
+                restartable: s1.restartable && s2.restartable,
+                ..s1
+            };
+            Qre::new(qre::union(m1, m2), sig)
+        }
+        ExprKind::Concat(e1, e2) => {
+            let (m1, m2) = (instantiate(e1, table), instantiate(e2, table));
+            let (s1, s2) = (sig(&m1), sig(&m2));
+            let sig = Sig {
+                input: s1.input,
+                output: s2.output,
+                epsilon: s1.epsilon && s2.epsilon,
+                restartable: s1.restartable && s2.restartable,
+            };
+            Qre::new(qre::concat(m1, m2), sig)
+        }
+        ExprKind::Iterate(body) => {
+            let m = instantiate(body, table);
+            let sig = sig(&m);
+            Qre::new(qre::iterate(m), sig)
+        }
+        ExprKind::Apply(e1, f) => {
+            let m = instantiate(e1, table);
+            let f = &table.epsilons[&f.text];
+            let sig = Sig { output: f.out, ..sig(&m) };
+            let f = f.f.clone();
+            Qre::new(qre::apply(m, move |v| f(v)), sig)
+        }
+        ExprKind::Combine(e1, e2, op) => {
+            let (m1, m2) = (instantiate(e1, table), instantiate(e2, table));
+            let op = &table.ops[&op.text];
+            let sig = Sig {
+                input: sig(&m1).input,
+                output: op.out,
+                epsilon: sig(&m1).epsilon && sig(&m2).epsilon,
+                restartable: false,
+            };
+            let op = op.f.clone();
+            Qre::new(qre::combine(m1, m2, move |a, b| op(a, b)), sig)
+        }
+    }
+}
+
+/*
+    Unit Tests
+*/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table() -> Table<'static, i64> {
+        let mut t = Table::new();
+        t.guard("any", |_| true)
+            .guard("pos", |&d| d > 0)
+            .guard("neg", |&d| d <= 0)
+            .epsilon("zero", |()| 0i64)
+            .epsilon("unit", |_: i64| ())
+            .epsilon("is_big", |x: i64| x > 100)
+            .atom("add", |s: i64, &d| s + d)
+            .atom("skip", |s: i64, _| s)
+            .atom("start", |(), &d| d)
+            .op("plus", |a: i64, b: i64| a + b);
+        t
+    }
+    fn run(m: &mut Qre<i64>, i: Val, items: &[i64]) -> Vec<Ext<Val>> {
+        let mut out = vec![m.init_one(i)];
+        out.extend(items.iter().map(|d| m.update(d)));
+        out
+    }
+    fn errors(text: &str) -> Vec<String> {
+        match compile(text, &table()) {
+            Ok(_) => vec![],
+            Err(errs) => errs.iter().map(|e| e.render(text)).collect(),
+        }
+    }
+
+    #[test]
+    fn test_compile_run() {
+        let text = "concat(epsilon(zero), \
+                    iterate(union(atom(pos, add), atom(neg, skip))))";
+        let mut m = compile(text, &table()).unwrap();
+        assert_eq!(m.sig().to_string(), "() -> i64");
+        assert!(m.is_restartable());
+        let out = run(&mut m, Val::new(()), &[3, -1, 4]);
+        let out: Vec<Option<i64>> = out
+            .iter()
+            .map(|o| o.clone().into_inner().and_then(|v| v.get().copied()))
+            .collect();
+        assert_eq!(out, [Some(0), Some(3), Some(3), Some(7)]);
+
+        // combine isn't restartable, but can follow an epsilon
+        let text = "apply(concat(epsilon(zero), \
+                    combine(atom(any, add), atom(any, skip), plus)), is_big)";
+        let mut m = compile(text, &table()).unwrap();
+        assert!(!m.is_restartable());
+        let out = run(&mut m, Val::new(()), &[150]);
+        assert_eq!(out[1], Ext::One(Val::new(true)));
+        assert_ne!(Val::new(1i64), Val::new(1i32));
+    }
+
+    #[test]
+    fn test_type_errors() {
+        // Concat of mismatched types, and iterate changing the type
+        let text = "concat(epsilon(zero), apply(atom(any, add), is_big))";
+        assert!(compile(text, &table()).is_ok());
+        let errs = errors("concat(epsilon(unit), atom(any, add))");
+        assert_eq!(
+            errs,
+            ["1:23: error: concat: the first operand outputs (), but the \
+              second one takes i64\n    \
+              concat(epsilon(unit), atom(any, add))\n                          \
+              ^^^^^^^^^^^^^^\n"]
+        );
+        let errs = errors("iterate(apply(atom(any, add), is_big))");
+        assert_eq!(errs.len(), 1);
+        assert!(errs[0].contains("type i64 -> bool"));
+        let e = parse("union(epsilon(zero), atom(any, add))").unwrap();
+        let errs = check(&e, &table()).unwrap_err();
+        assert_eq!(
+            errs[0].msg,
+            "union of different types: () -> i64 and i64 -> i64"
+        );
+        let errs = errors("combine(atom(any, add), epsilon(zero), plus)");
+        assert_eq!(errs.len(), 1);
+        assert_eq!(
+            errs[0].lines().next(),
+            Some(
+                "1:25: error: combine: the operands take different inputs, \
+                 i64 and ()"
+            )
+        );
+        // All unknown names are reported
+        let errs = errors("union(atom(foo, add), epsilon(bar))");
+        assert_eq!(errs.len(), 2);
+        assert!(errs[0].starts_with("1:12: error: unknown guard `foo`"));
+        assert!(errs[1].starts_with("1:31: error: unknown epsilon action"));
+    }
+
+    #[test]
+    fn test_restartability_errors() {
+        let text = "iterate(\n  concat(atom(any, add),\n    \
+                    combine(atom(any, add), atom(any, skip), plus)))";
+        let errs = compile(text, &table()).err().unwrap();
+        assert_eq!(errs.len(), 1);
+        assert!(errs[0].msg.contains("must be restartable"));
+        assert_eq!(errs[0].notes.len(), 1);
+        let rendered = errs[0].render(text);
+        assert!(rendered.starts_with("3:5: error: concat: the second operand"));
+        assert!(rendered.contains("3:5: note: not restartable because"));
+        // The body of iterate
+        let text = "iterate(combine(atom(any, add), atom(any, add), plus))";
+        let errs = errors(text);
+        assert_eq!(errs.len(), 1);
+        assert!(errs[0].starts_with("1:9: error: iterate: the body must be"));
+    }
+
+    #[test]
+    fn test_syntax_errors() {
+        let err = |text: &str| parse(text).unwrap_err().render(text);
+        assert!(err("concat(epsilon(zero),")
+            .starts_with("1:22: error: expected a name"));
+        assert!(err("epsilon(zero) x").contains("expected end of input"));
+        assert!(err("iterate(zero)").contains("expected a QRE, found `zero`"));
+        assert!(err("atom(any, epsilon(x))").contains("expected a name"));
+        let msg = err("concat(epsilon(zero))");
+        assert!(msg.contains("concat takes 2 or more"));
+        assert!(
+            err("star(epsilon(zero))").contains("unknown combinator `star`")
+        );
+        // Spans end on character boundaries
+        assert_eq!(err("€"), "1:1: error: expected a name\n    €\n    ^\n");
+        assert_eq!(parse("atom(p, f) é").unwrap_err().span, 11..13);
+        assert!(err("atom(p, f) é").starts_with("1:12: error: expected end"));
+        let e = parse("concat(epsilon(a), atom(b, c), epsilon(d))").unwrap();
+        assert_eq!(e.span, 0..42);
+        match e.kind {
+            ExprKind::Concat(e1, _) => assert_eq!(e1.span, 7..29),
+            _ => panic!(),
+        }
+    }
+}
@@ -0,0 +1,642 @@
+/*
+    A small SQL-like language for windowed aggregation queries over record
+    streams (see record.rs), compiled to the combinators of qre.rs.
+
+    Syntax (keywords are case-insensitive):
+        SELECT agg(x), ... [OVER window] [WHERE cond] [GROUP BY key]
+    where
+    - agg is count, sum, avg, min or max, over an integer field x
+      (count(*) counts the rows);
+    - window is tumbling(n) or sliding(n), a window of n rows;
+    - cond combines comparisons of a field with a literal
+      (x < 10, kind = 'a', flag != true, with =, !=, <>, <, <=, >, >=)
+      with AND, OR, NOT and parentheses;
+    - key is a field: the query is evaluated separately on the rows of
+      each value of the field.
+    Example:
+        SELECT avg(x) OVER tumbling(100) WHERE kind = 'a' GROUP BY key
+
+    As in SQL, WHERE selects the rows before they are windowed and grouped,
+    and a row without the field (or with a field of another type) doesn't
+    satisfy a comparison and isn't counted by the aggregates of the field.
+    Without a window, the query outputs the aggregates of all the rows so
+    far after each row; with a window, it outputs the aggregates of each
+    window (at its end, for tumbling windows, or after each row, for full
+    sliding windows). Each output is a Row of the aggregates, as f64 (NaN
+    for the avg, min or max of no values), tagged with the key of the group
+    if grouped.
+
+    The query compiles to
+        map_collect(key, window(aggregates))
+    where aggregates is an iteration folding the rows into accumulators
+    (see qre::tumbling, qre::sliding and qre::map_collect).
+*/
+
+use super::ext_value::{self, Ext};
+use super::guard::Guard;
+use super::interface::Transducer;
+use super::qre::{
+    apply, atom, concat, epsilon, iterate, map_collect, sliding, tumbling,
+    MapCollect,
+};
+use super::record::{field, Record, Value};
+use super::scanner::{Scanner, SyntaxError};
+use std::error::Error;
+use std::fmt::{self, Display};
+use std::marker::PhantomData;
+use std::ops::Range;
+use std::rc::Rc;
+
+/*
+    Query results
+*/
+
+// The value of a grouping field
+#[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum Key {
+    Missing,
+    Int(i64),
+    Bool(bool),
+    Str(String),
+}
+impl Key {
+    fn of(v: Option<Value<'_>>) -> Self {
+        match v {
+            None => Key::Missing,
+            Some(Value::Int(x)) => Key::Int(x),
+            Some(Value::Bool(b)) => Key::Bool(b),
+            Some(Value::Str(s)) => Key::Str(s.to_string()),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Row {
+    // The group (for queries with GROUP BY)
+    pub key: Option<Key>,
+    // The aggregates, in the order of the SELECT clause
+    pub values: Vec<f64>,
+}
+
+/*
+    Aggregates
+*/
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Agg {
+    Count,
+    Sum,
+    Avg,
+    Min,
+    Max,
+}
+
+#[derive(Clone, Debug)]
+struct SelectItem {
+    agg: Agg,
+    // None for count(*)
+    attr: Option<String>,
+}
+
+// Accumulator for one aggregate
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+struct Acc {
+    n: u64,
+    sum: i64,
+    min: i64,
+    max: i64,
+}
+impl Acc {
+    fn new() -> Self {
+        Acc { n: 0, sum: 0, min: i64::MAX, max: i64::MIN }
+    }
+    fn add<D: Record>(&mut self, item: &SelectItem, d: &D) {
+        let x = match &item.attr {
+            None => 0,
+            Some(attr) => match d.int(attr) {
+                Some(x) => x,
+                None => return,
+            },
+        };
+        self.n += 1;
+        self.sum = self.sum.wrapping_add(x);
+        self.min = self.min.min(x);
+        self.max = self.max.max(x);
+    }
+    fn result(&self, agg: Agg) -> f64 {
+        match agg {
+            Agg::Count => self.n as f64,
+            Agg::Sum => self.sum as f64,
+            _ if self.n == 0 => f64::NAN,
+            Agg::Avg => self.sum as f64 / self.n as f64,
+            Agg::Min => self.min as f64,
+            Agg::Max => self.max as f64,
+        }
+    }
+}
+
+// The aggregates of the rows, as a QRE
+fn aggregates<'a, D>(
+    items: Rc<Vec<SelectItem>>,
+) -> impl Transducer<(), D, Vec<f64>> + Clone + 'a
+where
+    D: Record + 'a,
+{
+    let n = items.len();
+    let start = epsilon(move |()| vec![Acc::new(); n]);
+    let fold_items = items.clone();
+    let step = atom(
+        |_: &D| true,
+        move |mut accs: Vec<Acc>, d: &D| {
+            for (acc, item) in accs.iter_mut().zip(fold_items.iter()) {
+                acc.add(item, d);
+            }
+            accs
+        },
+    );
+    let finish = epsilon(move |accs: Vec<Acc>| {
+        accs.iter()
+            .zip(items.iter())
+            .map(|(acc, i)| acc.result(i.agg))
+            .collect()
+    });
+    concat(concat(start, iterate(step)), finish)
+}
+
+/*
+    Grouping
+
+    map_collect outputs the latest results of all the groups; GroupBy only
+    outputs the row of the group of each item, if its window produced one
+    (with MapCollect::update_key(), so without building the map of all the
+    groups on each row).
+*/
+
+struct GroupBy<D, M, G>
+where
+    M: Transducer<(), D, Vec<f64>>,
+    G: Fn(&D) -> Key,
+{
+    m: MapCollect<(), D, Key, Vec<f64>, M, G>,
+    attr: String,
+    ph_d: PhantomData<D>,
+}
+impl<D, M, G> Transducer<(), D, Row> for GroupBy<D, M, G>
+where
+    D: Record,
+    M: Transducer<(), D, Vec<f64>> + Clone,
+    G: Fn(&D) -> Key,
+{
+    fn init(&mut self, i: Ext<()>) -> Ext<Row> {
+        self.m.init(i);
+        Ext::None
+    }
+    fn update(&mut self, item: &D) -> Ext<Row> {
+        let key = Key::of(item.get(&self.attr));
+        let out = self.m.update_key(item);
+        ext_value::apply1(|values| Row { key: Some(key), values }, out)
+    }
+    fn reset(&mut self) {
+        self.m.reset();
+    }
+
+    fn is_epsilon(&self) -> bool {
+        false
+    }
+    fn is_restartable(&self) -> bool {
+        false
+    }
+    fn is_nullable(&self) -> bool {
+        false
+    }
+    fn n_states(&self) -> usize {
+        self.m.n_states()
+    }
+    fn n_transs(&self) -> usize {
+        self.m.n_transs()
+    }
+}
+
+fn build<'a, D, M>(
+    m: M,
+    group: Option<String>,
+) -> Box<dyn Transducer<(), D, Row> + 'a>
+where
+    D: Record + 'a,
+    M: Transducer<(), D, Vec<f64>> + Clone + 'a,
+{
+    match group {
+        None => Box::new(apply(m, |values| Row { key: None, values })),
+        Some(attr) => {
+            let key_attr = attr.clone();
+            let key = move |d: &D| Key::of(d.get(&key_attr));
+            Box::new(GroupBy {
+                m: map_collect(key, m),
+                attr,
+                ph_d: PhantomData,
+            })
+        }
+    }
+}
+
+/*
+    Compiled queries
+*/
+
+pub struct Query<'a, D> {
+    columns: Vec<String>,
+    filter: Guard<'a, D>,
+    m: Box<dyn Transducer<(), D, Row> + 'a>,
+}
+impl<'a, D> Query<'a, D> {
+    // Names of the aggregates, e.g. "avg(x)"
+    pub fn columns(&self) -> &[String] {
+        &self.columns
+    }
+    // The WHERE clause, as a guard (true if none)
+    pub fn filter(&self) -> &Guard<'a, D> {
+        &self.filter
+    }
+}
+impl<D> Transducer<(), D, Row> for Query<'_, D> {
+    fn init(&mut self, i: Ext<()>) -> Ext<Row> {
+        self.m.init(i)
+    }
+    fn update(&mut self, item: &D) -> Ext<Row> {
+        if self.filter.eval(item) {
+            self.m.update(item)
+        } else {
+            Ext::None
+        }
+    }
+    fn reset(&mut self) {
+        self.m.reset();
+    }
+
+    fn is_epsilon(&self) -> bool {
+        self.m.is_epsilon()
+    }
+    fn is_restartable(&self) -> bool {
+        self.m.is_restartable()
+    }
+    fn is_nullable(&self) -> bool {
+        self.m.is_nullable()
+    }
+    fn n_states(&self) -> usize {
+        self.m.n_states()
+    }
+    fn n_transs(&self) -> usize {
+        self.m.n_transs()
+    }
+}
+
+/*
+    Parsing
+*/
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct QueryError {
+    // Byte offset in the text
+    pub pos: usize,
+    pub msg: String,
+}
+impl Display for QueryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "at offset {}: {}", self.pos, self.msg)
+    }
+}
+impl Error for QueryError {}
+impl SyntaxError for QueryError {
+    fn at(span: Range<usize>, msg: String) -> Self {
+        QueryError { pos: span.start, msg }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Window {
+    Tumbling(usize),
+    Sliding(usize),
+}
+
+pub fn parse_query<'a, D>(text: &str) -> Result<Query<'a, D>, QueryError>
+where
+    D: Record + 'a,
+{
+    let mut parser = Parser::new(text);
+    parser.expect_keyword("SELECT")?;
+    let mut items = vec![parser.select_item()?];
+    while parser.eat(",") {
+        items.push(parser.select_item()?);
+    }
+    let window =
+        if parser.keyword("OVER") { Some(parser.window()?) } else { None };
+    let filter =
+        if parser.keyword("WHERE") { parser.or()? } else { Guard::True };
+    let group = if parser.keyword("GROUP") {
+        parser.expect_keyword("BY")?;
+        Some(parser.name()?)
+    } else {
+        None
+    };
+    parser.expect_end("query")?;
+
+    let columns = items
+        .iter()
+        .map(|item| {
+            let agg = format!("{:?}", item.agg).to_lowercase();
+            format!("{}({})", agg, item.attr.as_deref().unwrap_or("*"))
+        })
+        .collect();
+    let aggs = aggregates(Rc::new(items));
+    let m = match window {
+        None => build(aggs, group),
+        Some(Window::Tumbling(n)) => build(tumbling(n, aggs), group),
+        Some(Window::Sliding(n)) => build(sliding(n, aggs), group),
+    };
+    Ok(Query { columns, filter, m })
+}
+
+type Parser<'t> = Scanner<'t, QueryError>;
+
+impl Parser<'_> {
+    // Consume the keyword (in any case) if it comes next
+    fn keyword(&mut self, kw: &str) -> bool {
+        let start = self.pos;
+        match self.word() {
+            Some(w) if w.eq_ignore_ascii_case(kw) => true,
+            _ => {
+                self.pos = start;
+                false
+            }
+        }
+    }
+    fn expect_keyword(&mut self, kw: &str) -> Result<(), QueryError> {
+        if self.keyword(kw) {
+            Ok(())
+        } else {
+            self.skip_space();
+            Err(self.error(&format!("expected {}", kw)))
+        }
+    }
+    fn name(&mut self) -> Result<String, QueryError> {
+        match self.word() {
+            Some(w) => Ok(w.to_string()),
+            None => Err(self.error("expected a field name")),
+        }
+    }
+    fn integer(&mut self) -> Result<i64, QueryError> {
+        self.skip_space();
+        let start = self.pos;
+        let neg = self.eat("-");
+        match self.parse_word::<i64>() {
+            Some(n) => Ok(if neg { -n } else { n }),
+            None => {
+                self.pos = start;
+                Err(self.error("expected an integer"))
+            }
+        }
+    }
+
+    fn select_item(&mut self) -> Result<SelectItem, QueryError> {
+        self.skip_space();
+        let start = self.pos;
+        let agg = match self.word().map(str::to_lowercase).as_deref() {
+            Some("count") => Agg::Count,
+            Some("sum") => Agg::Sum,
+            Some("avg") => Agg::Avg,
+            Some("min") => Agg::Min,
+            Some("max") => Agg::Max,
+            _ => {
+                self.pos = start;
+                return Err(self.error("expected an aggregate"));
+            }
+        };
+        self.expect("(")?;
+        let attr = if self.eat("*") {
+            if agg != Agg::Count {
+                return Err(self.error("only count applies to *"));
+            }
+            None
+        } else {
+            Some(self.name()?)
+        };
+        self.expect(")")?;
+        Ok(SelectItem { agg, attr })
+    }
+    fn window(&mut self) -> Result<Window, QueryError> {
+        self.skip_space();
+        let tumbling = if self.keyword("tumbling") {
+            true
+        } else if self.keyword("sliding") {
+            false
+        } else {
+            return Err(self.error("expected tumbling or sliding"));
+        };
+        self.expect("(")?;
+        let size_pos = self.pos;
+        let size = self.integer()?;
+        if size <= 0 {
+            self.pos = size_pos;
+            self.skip_space();
+            return Err(self.error("window size must be positive"));
+        }
+        self.expect(")")?;
+        let size = size as usize;
+        Ok(if tumbling {
+            Window::Tumbling(size)
+        } else {
+            Window::Sliding(size)
+        })
+    }
+
+    /* Conditions, by precedence: OR < AND < NOT < comparisons */
+
+    fn or<'a, D: Record + 'a>(&mut self) -> Result<Guard<'a, D>, QueryError> {
+        let mut g = self.and()?;
+        while self.keyword("OR") {
+            g = g | self.and()?;
+        }
+        Ok(g)
+    }
+    fn and<'a, D: Record + 'a>(&mut self) -> Result<Guard<'a, D>, QueryError> {
+        let mut g = self.unary()?;
+        while self.keyword("AND") {
+            g = g & self.unary()?;
+        }
+        Ok(g)
+    }
+    fn unary<'a, D: Record + 'a>(
+        &mut self,
+    ) -> Result<Guard<'a, D>, QueryError> {
+        if self.keyword("NOT") {
+            Ok(!self.unary()?)
+        } else if self.eat("(") {
+            let g = self.or()?;
+            self.expect(")")?;
+            Ok(g)
+        } else {
+            self.comparison()
+        }
+    }
+    fn comparison<'a, D: Record + 'a>(
+        &mut self,
+    ) -> Result<Guard<'a, D>, QueryError> {
+        let x = field(&self.name()?);
+        self.skip_space();
+        let op_pos = self.pos;
+        let ops = ["<=", ">=", "!=", "<>", "=", "<", ">"];
+        let op = match ops.iter().find(|op| self.eat(op)) {
+            Some(&op) => op,
+            None => return Err(self.error("expected a comparison")),
+        };
+        self.skip_space();
+        if self.eat("'") {
+            let rest = &self.text[self.pos..];
+            let len = match rest.find('\'') {
+                Some(len) => len,
+                None => return Err(self.error("unterminated string")),
+            };
+            let s = &rest[..len];
+            self.pos += len + 1;
+            return match op {
+                "=" => Ok(x.is(s)),
+                "!=" | "<>" => Ok(x.is_str() & !x.is(s)),
+                _ => Err(QueryError {
+                    pos: op_pos,
+                    msg: "strings can only be compared with = or !=".into(),
+                }),
+            };
+        }
+        for (word, b) in [("true", true), ("false", false)] {
+            if self.keyword(word) {
+                return match (op, b) {
+                    ("=", true) | ("!=", false) | ("<>", false) => {
+                        Ok(x.is_true())
+                    }
+                    ("=", false) | ("!=", true) | ("<>", true) => {
+                        Ok(x.is_false())
+                    }
+                    _ => Err(QueryError {
+                        pos: op_pos,
+                        msg: "booleans can only be compared with = or !="
+                            .into(),
+                    }),
+                };
+            }
+        }
+        let v = self.integer()?;
+        Ok(match op {
+            "=" => x.equals(v),
+            "!=" | "<>" => x.is_int() & !x.equals(v),
+            "<" => x.lt(v),
+            "<=" => x.le(v),
+            ">" => x.gt(v),
+            _ => x.ge(v),
+        })
+    }
+}
+
+/*
+    Unit Tests
+*/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Event {
+        key: u32,
+        kind: &'static str,
+        x: i64,
+    }
+    crate::impl_record!(Event { key, kind, x });
+
+    fn events() -> Vec<Event> {
+        let data = [
+            (1, "a", 10),
+            (2, "a", 5),
+            (1, "b", 100),
+            (1, "a", 20),
+            (2, "a", 7),
+            (1, "a", 3),
+            (2, "b", 1),
+            (1, "a", 4),
+        ];
+        data.iter().map(|&(key, kind, x)| Event { key, kind, x }).collect()
+    }
+    fn run(text: &str) -> Vec<Ext<Row>> {
+        let mut q = parse_query(text).unwrap();
+        q.init_one(());
+        q.update_batch(&events())
+    }
+    fn row(key: Option<u32>, values: &[f64]) -> Ext<Row> {
+        let key = key.map(|k| Key::Int(k as i64));
+        Ext::One(Row { key, values: values.to_vec() })
+    }
+
+    #[test]
+    fn test_running() {
+        let mut q =
+            parse_query::<Event>("SELECT count(*), sum(x), max(x)").unwrap();
+        assert_eq!(q.columns(), ["count(*)", "sum(x)", "max(x)"]);
+        let out = q.init_one(()).unwrap();
+        assert_eq!(out.values[..2], [0.0, 0.0]);
+        assert!(out.values[2].is_nan());
+        let out = q.update_batch(&events());
+        assert_eq!(out[2], row(None, &[3.0, 115.0, 100.0]));
+        assert_eq!(out[7], row(None, &[8.0, 150.0, 100.0]));
+        // WHERE applies before aggregation
+        let out = run("select sum(x) where kind = 'a' and not x >= 20");
+        assert_eq!(out[2], Ext::None);
+        assert_eq!(out[3], Ext::None);
+        assert_eq!(out[7], row(None, &[29.0]));
+    }
+
+    #[test]
+    fn test_windows_groups() {
+        let out =
+            run("SELECT avg(x) OVER tumbling(2) WHERE kind='a' GROUP BY key");
+        let expected = vec![
+            Ext::None,
+            Ext::None,
+            Ext::None,
+            row(Some(1), &[15.0]),
+            row(Some(2), &[6.0]),
+            Ext::None,
+            Ext::None,
+            row(Some(1), &[3.5]),
+        ];
+        assert_eq!(out, expected);
+        let out = run("SELECT min(x), count(*) OVER sliding(3)");
+        assert_eq!(out[..2], [Ext::None, Ext::None]);
+        assert_eq!(out[2], row(None, &[5.0, 3.0]));
+        assert_eq!(out[7], row(None, &[1.0, 3.0]));
+        let out = run("SELECT sum(x) WHERE (x < 5 OR x > 50) GROUP BY kind");
+        let b =
+            |v| Ext::One(Row { key: Some(Key::Str("b".into())), values: v });
+        assert_eq!(out[2], b(vec![100.0]));
+        assert_eq!(out[6], b(vec![101.0]));
+        assert_eq!(out[0], Ext::None);
+    }
+
+    #[test]
+    fn test_errors() {
+        let err = |text| parse_query::<Event>(text).err().unwrap();
+        assert_eq!(err("avg(x)").pos, 0);
+        assert_eq!(err("SELECT median(x)").msg, "expected an aggregate");
+        assert_eq!(err("SELECT sum(*)").pos, 12);
+        assert_eq!(err("SELECT sum(x) OVER hopping(2)").pos, 19);
+        assert_eq!(err("SELECT sum(x) OVER tumbling(0)").pos, 28);
+        assert_eq!(err("SELECT sum(x) WHERE kind < 'a'").pos, 25);
+        assert_eq!(
+            err("SELECT sum(x) WHERE kind = 'a").msg,
+            "unterminated string"
+        );
+        assert_eq!(err("SELECT sum(x) GROUP key").msg, "expected BY");
+        assert_eq!(err("SELECT sum(x) LIMIT 3").pos, 14);
+        assert_eq!(
+            err("SELECT sum(x) WHERE x").to_string(),
+            "at offset 21: expected a comparison"
+        );
+    }
+}
@@ -0,0 +1,361 @@
+/*
+    Random data transducers and input streams, for property testing.
+
+    Machines are generated over a small alphabet of items (chars), with
+    values i64 and actions using wrapping arithmetic (so that they never
+    panic). Generation is deterministic given the seed of the Rng, so that
+    failures are reproducible.
+
+    This is intended for checking that different ways of evaluating the
+    same machine agree: e.g. the epsilon fixed point with and without
+    cycles, and the optimization passes in state_machine.rs (epsilon
+    elimination, minimization, dead state removal).
+*/
+
+use super::interface::{RInput, Transducer};
+use super::state_machine::DataTransducer;
+
+// Deterministic pseudorandom generator (xorshift)
+#[derive(Clone, Debug)]
+pub struct Rng(u64);
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        // xorshift gets stuck at 0
+        Rng(if seed == 0 { 0x9e37_79b9_7f4a_7c15 } else { seed })
+    }
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+    // Uniform (up to negligible bias) in 0..n; n must be positive
+    pub fn below(&mut self, n: u64) -> u64 {
+        self.next_u64() % n
+    }
+    pub fn chance(&mut self, num: u64, denom: u64) -> bool {
+        self.below(denom) < num
+    }
+}
+
+pub const ALPHABET: &[char] = &['a', 'b', 'c'];
+
+// Shape of the generated machines
+#[derive(Clone, Debug)]
+pub struct MachineConfig {
+    // Number of states, including the initial and final states (at least 2)
+    pub n_states: usize,
+    pub n_updates: usize,
+    pub n_epsilons: usize,
+    // Whether the epsilon transitions may form cycles (otherwise they
+    // follow a random order of the states)
+    pub eps_cycles: bool,
+    // Whether to generate epsilon transitions with no source states (these
+    // break the INIT property; see interface.rs)
+    pub eps_nullary: bool,
+}
+impl Default for MachineConfig {
+    fn default() -> Self {
+        Self {
+            n_states: 6,
+            n_updates: 8,
+            n_epsilons: 4,
+            eps_cycles: false,
+            eps_nullary: false,
+        }
+    }
+}
+
+fn gen_guard(rng: &mut Rng) -> impl Fn(&char) -> bool {
+    // A random nonempty subset of the alphabet
+    let n = ALPHABET.len() as u64;
+    let mask = 1 + rng.below((1 << n) - 1);
+    move |&ch| {
+        let i = ALPHABET.iter().position(|&a| a == ch).unwrap_or(0);
+        mask & (1 << i) != 0
+    }
+}
+
+fn item_value(ch: char) -> i64 {
+    ch as i64 - 'a' as i64
+}
+
+// Generate a random machine. All states are used as sources and targets
+// of transitions, so some are typically unreachable or dead.
+pub fn random_machine(
+    rng: &mut Rng,
+    config: &MachineConfig,
+) -> DataTransducer<'static, char, i64> {
+    assert!(config.n_states >= 2);
+    let n = config.n_states;
+    let mut m = DataTransducer::new();
+    m.set_nstates(n);
+    let state = |rng: &mut Rng| rng.below(n as u64) as usize;
+    for _ in 0..config.n_updates {
+        let target = state(rng);
+        let guard = gen_guard(rng);
+        let k = rng.below(5) as i64;
+        match rng.below(4) {
+            0 => {
+                m.add_transition0(target, guard, move |&d| item_value(d) + k);
+            }
+            1 => {
+                m.add_iden(state(rng), target, guard);
+            }
+            2 => {
+                m.add_transition1(state(rng), target, guard, move |&d, &q| {
+                    q.wrapping_mul(k).wrapping_add(item_value(d))
+                });
+            }
+            _ => {
+                let (s1, s2) = (state(rng), state(rng));
+                m.add_transition2(s1, s2, target, guard, move |_, &q1, &q2| {
+                    q1.wrapping_add(q2).wrapping_add(k)
+                });
+            }
+        }
+    }
+    // Without cycles, epsilons go forward in a random order of the states
+    let mut order: Vec<usize> = (0..n).collect();
+    for i in (1..n).rev() {
+        order.swap(i, rng.below(i as u64 + 1) as usize);
+    }
+    let mut rank = vec![0; n];
+    for (r, &s) in order.iter().enumerate() {
+        rank[s] = r;
+    }
+    let mut n_eps = 0;
+    while n_eps < config.n_epsilons {
+        let k = rng.below(5) as i64;
+        let target = state(rng);
+        let sources: Vec<usize> = match rng.below(4) {
+            0 if config.eps_nullary => vec![],
+            0..=2 => vec![state(rng)],
+            _ => vec![state(rng), state(rng)],
+        };
+        if !config.eps_cycles
+            && sources.iter().any(|&s| rank[s] >= rank[target])
+        {
+            continue;
+        }
+        match *sources.as_slice() {
+            [] => {
+                m.add_epsilon0(target, move || k);
+            }
+            [s] if k == 0 => {
+                m.add_epsilon_iden(s, target);
+            }
+            [s] => {
+                m.add_epsilon1(s, target, move |&q| q.wrapping_add(k));
+            }
+            [s1, s2] => {
+                m.add_epsilon2(s1, s2, target, move |&q1, &q2| {
+                    q1.wrapping_mul(q2).wrapping_add(k)
+                });
+            }
+            _ => unreachable!(),
+        }
+        n_eps += 1;
+    }
+    m
+}
+
+// Generate a random stream of items and restarts, of length at most max_len
+pub fn random_rstream(rng: &mut Rng, max_len: usize) -> Vec<RInput<i64, char>> {
+    let len = rng.below(max_len as u64 + 1);
+    (0..len)
+        .map(|_| {
+            if rng.chance(1, 4) {
+                RInput::Restart(rng.below(10) as i64)
+            } else {
+                let i = rng.below(ALPHABET.len() as u64) as usize;
+                RInput::Item(ALPHABET[i])
+            }
+        })
+        .collect()
+}
+
+// Randomized version of interface::equiv_bounded: compare the outputs of
+// two transducers on n_streams random streams over the alphabet, of length
+// at most max_len, returning a counterexample (cut off after the first
+// differing output)
+pub fn equiv_random<I, D, O, M1, M2>(
+    m1: &M1,
+    m2: &M2,
+    alphabet: &[RInput<I, D>],
+    n_streams: usize,
+    max_len: usize,
+    rng: &mut Rng,
+) -> Result<(), Vec<RInput<I, D>>>
+where
+    M1: Transducer<I, D, O> + Clone,
+    M2: Transducer<I, D, O> + Clone,
+    I: Clone,
+    D: Clone,
+    O: Eq,
+{
+    if alphabet.is_empty() {
+        return Ok(());
+    }
+    for _ in 0..n_streams {
+        let (mut m1, mut m2) = (m1.spawn_empty(), m2.spawn_empty());
+        let len = rng.below(max_len as u64 + 1) as usize;
+        let mut strm = Vec::with_capacity(len);
+        for _ in 0..len {
+            let item = &alphabet[rng.below(alphabet.len() as u64) as usize];
+            strm.push(item.clone());
+            let (out1, out2) = match item {
+                RInput::Restart(i) => {
+                    (m1.init_one(i.clone()), m2.init_one(i.clone()))
+                }
+                RInput::Item(d) => (m1.update(d), m2.update(d)),
+            };
+            if out1 != out2 {
+                return Err(strm);
+            }
+        }
+    }
+    Ok(())
+}
+
+/*
+    Unit Tests
+*/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ext_value::Ext;
+    use crate::interface::Transducer;
+
+    const N_MACHINES: u64 = 300;
+    const N_STREAMS: usize = 5;
+    const MAX_LEN: usize = 12;
+
+    fn run(
+        m: &mut DataTransducer<'_, char, i64>,
+        strm: &[RInput<i64, char>],
+    ) -> Vec<Ext<i64>> {
+        m.reset();
+        m.process_rstream_single(strm.iter().copied()).collect()
+    }
+
+    // Check that transforming each random machine (of the given shape)
+    // does not change its outputs on random streams
+    fn check_preserved<F>(config: &MachineConfig, transform: F)
+    where
+        F: Fn(&mut DataTransducer<'static, char, i64>),
+    {
+        for seed in 1..=N_MACHINES {
+            let mut rng = Rng::new(seed);
+            let mut m1 = random_machine(&mut rng, config);
+            let mut m2 = m1.clone();
+            transform(&mut m2);
+            for _ in 0..N_STREAMS {
+                let strm = random_rstream(&mut rng, MAX_LEN);
+                assert_eq!(
+                    run(&mut m1, &strm),
+                    run(&mut m2, &strm),
+                    "seed {}, stream {:?}, machine {:?}",
+                    seed,
+                    strm,
+                    m1
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_deterministic() {
+        let config = MachineConfig::default();
+        let m1 = random_machine(&mut Rng::new(7), &config);
+        let m2 = random_machine(&mut Rng::new(7), &config);
+        assert_eq!(format!("{:?}", m1), format!("{:?}", m2));
+        assert_eq!(m1.n_states(), config.n_states);
+        assert_eq!(m1.n_transs(), config.n_updates + config.n_epsilons);
+        assert_eq!(
+            random_rstream(&mut Rng::new(3), MAX_LEN),
+            random_rstream(&mut Rng::new(3), MAX_LEN)
+        );
+        assert_eq!(Rng::new(0).next_u64(), Rng::new(0).next_u64());
+        assert_ne!(Rng::new(0).next_u64(), 0);
+    }
+
+    #[test]
+    fn test_equiv() {
+        use crate::interface::equiv_bounded;
+        let alphabet = [
+            RInput::Restart(0),
+            RInput::Restart(1),
+            RInput::Item('a'),
+            RInput::Item('b'),
+            RInput::Item('c'),
+        ];
+        let mut rng = Rng::new(1);
+        let mut n_different = 0;
+        for seed in 1..=50 {
+            let config = MachineConfig::default();
+            let m1 = random_machine(&mut Rng::new(seed), &config);
+            let mut m2 = m1.clone();
+            m2.eliminate_epsilons().unwrap();
+            m2.minimize(ALPHABET);
+            assert_eq!(equiv_bounded(&m1, &m2, &alphabet, 4), Ok(()));
+            assert_eq!(
+                equiv_random(&m1, &m2, &alphabet, 20, 20, &mut rng),
+                Ok(())
+            );
+            // Compare with a different machine
+            let m3 = random_machine(&mut Rng::new(seed + 1000), &config);
+            if let Err(cex) = equiv_bounded(&m1, &m3, &alphabet, 4) {
+                n_different += 1;
+                // The counterexample is a shortest one
+                let mut m1 = m1.clone();
+                let mut m3 = m3.clone();
+                let out1 = m1.process_rstream_single(cex.iter().copied());
+                let out3 = m3.process_rstream_single(cex.iter().copied());
+                let diffs: Vec<bool> =
+                    out1.zip(out3).map(|(o1, o3)| o1 != o3).collect();
+                assert_eq!(diffs.iter().filter(|&&d| d).count(), 1);
+                assert!(diffs.last().unwrap());
+                assert!(equiv_random(&m1, &m3, &alphabet, 200, 20, &mut rng)
+                    .is_err());
+            }
+        }
+        assert!(n_different > 10);
+    }
+
+    #[test]
+    fn test_random_eliminate_epsilons() {
+        check_preserved(&MachineConfig::default(), |m| {
+            m.eliminate_epsilons().unwrap();
+        });
+    }
+
+    #[test]
+    fn test_random_remove_dead_states() {
+        let config = MachineConfig { eps_cycles: true, ..Default::default() };
+        check_preserved(&config, |m| {
+            m.remove_dead_states();
+        });
+    }
+
+    #[test]
+    fn test_random_minimize() {
+        let config = MachineConfig { eps_cycles: true, ..Default::default() };
+        check_preserved(&config, |m| {
+            m.minimize(ALPHABET);
+        });
+    }
+
+    #[test]
+    fn test_random_dispatch() {
+        let config = MachineConfig {
+            eps_cycles: true,
+            eps_nullary: true,
+            ..Default::default()
+        };
+        check_preserved(&config, |m| {
+            m.set_dispatch(|&d| item_value(d) as usize);
+        });
+    }
+}
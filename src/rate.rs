@@ -0,0 +1,456 @@
+/*
+    Rate analyses: emptiness and universality.
+
+    The rate of a transducer is the set of streams on which it produces
+    output: the streams of items w such that, after .init() with a value
+    and then w, the output is not Ext::None. The outputs themselves are
+    ignored, so the rate is a regular language, recognized by an NFA (the
+    "rate NFA") over the items. Items are only seen through the guards,
+    so as in DataTransducer::analyze(), the NFA is over a finite alphabet
+    of items given by the user, which should contain every item the
+    transducer will be run on (or at least, one item of each combination
+    of the guards which may hold). Letters are indices in the alphabet.
+
+    The rate NFA of a QRE is built from the constructs: an epsilon matches
+    the empty stream, an atom one item satisfying its guard, and union,
+    concat and iterate give the union, concatenation and Kleene star of
+    the rates; parcomp outputs when both operands do (the intersection),
+    and aggregate when its operand does. For a DataTransducer, which states
+    have a value after a stream only depends on the stream (not on the
+    values), so its rate NFA is the DFA on these sets of states; the state
+    guards (see .add_state_guard()) and final combiners are not taken
+    into account.
+
+    The analyses on it are:
+    - matches_nothing(&m, alphabet): the rate is empty. This is almost
+      always an authoring bug (e.g. the guards of a concat contradict each
+      other).
+    - matches_everything(&m, alphabet): the rate contains every stream,
+      including the empty one. This is the special case in which
+      aggregate(m) is restartable (see qre.rs), which can't be detected
+      without an alphabet.
+    matches_everything() determinizes the NFA, so is exponential in the
+    worst case.
+*/
+
+use super::interface::Transducer;
+use super::qre::{
+    Aggregate, Atom, Concat, Epsilon, Iterate, ParComp, TopWrapper, Union,
+};
+use std::collections::{BTreeSet, HashMap};
+
+/*
+    Rate NFAs
+*/
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RateNfa {
+    n_letters: usize,
+    // Transitions out of each state: on a letter, or epsilon (None)
+    trans: Vec<Vec<(Option<usize>, usize)>>,
+    initial: Vec<usize>,
+    finals: Vec<bool>,
+}
+
+impl RateNfa {
+    // The NFA with no states, over an alphabet of n_letters
+    pub fn new(n_letters: usize) -> Self {
+        RateNfa { n_letters, trans: vec![], initial: vec![], finals: vec![] }
+    }
+    pub fn add_state(&mut self) -> usize {
+        self.trans.push(vec![]);
+        self.finals.push(false);
+        self.trans.len() - 1
+    }
+    pub fn add_transition(
+        &mut self,
+        source: usize,
+        letter: usize,
+        target: usize,
+    ) {
+        assert!(letter < self.n_letters, "letter not in the alphabet");
+        self.trans[source].push((Some(letter), target));
+    }
+    pub fn add_epsilon(&mut self, source: usize, target: usize) {
+        self.trans[source].push((None, target));
+    }
+    pub fn set_initial(&mut self, state: usize) {
+        self.initial.push(state);
+    }
+    pub fn set_final(&mut self, state: usize) {
+        self.finals[state] = true;
+    }
+    pub fn n_letters(&self) -> usize {
+        self.n_letters
+    }
+    pub fn n_states(&self) -> usize {
+        self.trans.len()
+    }
+
+    /* Constructions on rates */
+    // The empty stream only
+    pub fn epsilon(n_letters: usize) -> Self {
+        let mut nfa = Self::new(n_letters);
+        let s = nfa.add_state();
+        nfa.set_initial(s);
+        nfa.set_final(s);
+        nfa
+    }
+    // The streams of one of the letters
+    pub fn letters(n_letters: usize, letters: &[usize]) -> Self {
+        let mut nfa = Self::new(n_letters);
+        let (s, t) = (nfa.add_state(), nfa.add_state());
+        for &a in letters {
+            nfa.add_transition(s, a, t);
+        }
+        nfa.set_initial(s);
+        nfa.set_final(t);
+        nfa
+    }
+    pub fn union(mut self, other: Self) -> Self {
+        let offset = self.embed(&other);
+        self.initial.extend(other.initial.iter().map(|&s| s + offset));
+        self
+    }
+    pub fn concat(mut self, other: Self) -> Self {
+        let offset = self.embed(&other);
+        for s in 0..offset {
+            if self.finals[s] {
+                self.finals[s] = false;
+                for &t in &other.initial {
+                    self.add_epsilon(s, t + offset);
+                }
+            }
+        }
+        self
+    }
+    pub fn star(mut self) -> Self {
+        let s = self.add_state();
+        for &t in &self.initial.clone() {
+            self.add_epsilon(s, t);
+        }
+        for t in 0..s {
+            if self.finals[t] {
+                self.add_epsilon(t, s);
+            }
+        }
+        self.initial = vec![s];
+        self.finals = vec![false; s + 1];
+        self.finals[s] = true;
+        self
+    }
+    // Product construction, on the reachable pairs of states
+    pub fn intersect(&self, other: &Self) -> Self {
+        assert_eq!(self.n_letters, other.n_letters, "different alphabets");
+        let mut nfa = Self::new(self.n_letters);
+        let mut ids = HashMap::new();
+        let mut todo = vec![];
+        let mut id = |nfa: &mut Self, todo: &mut Vec<_>, pair| {
+            *ids.entry(pair).or_insert_with(|| {
+                todo.push(pair);
+                nfa.add_state()
+            })
+        };
+        for &s1 in &self.initial {
+            for &s2 in &other.initial {
+                let s = id(&mut nfa, &mut todo, (s1, s2));
+                nfa.set_initial(s);
+            }
+        }
+        while let Some((s1, s2)) = todo.pop() {
+            let s = id(&mut nfa, &mut todo, (s1, s2));
+            if self.finals[s1] && other.finals[s2] {
+                nfa.set_final(s);
+            }
+            for &(a1, t1) in &self.trans[s1] {
+                if a1.is_none() {
+                    let t = id(&mut nfa, &mut todo, (t1, s2));
+                    nfa.add_epsilon(s, t);
+                    continue;
+                }
+                for &(a2, t2) in &other.trans[s2] {
+                    if a1 == a2 {
+                        let t = id(&mut nfa, &mut todo, (t1, t2));
+                        nfa.trans[s].push((a1, t));
+                    }
+                }
+            }
+            for &(a2, t2) in &other.trans[s2] {
+                if a2.is_none() {
+                    let t = id(&mut nfa, &mut todo, (s1, t2));
+                    nfa.add_epsilon(s, t);
+                }
+            }
+        }
+        nfa
+    }
+    // Copy the states of other into self, returning the index of other's
+    // state 0 (the initial states are not copied)
+    fn embed(&mut self, other: &Self) -> usize {
+        assert_eq!(self.n_letters, other.n_letters, "different alphabets");
+        let offset = self.n_states();
+        for tr in &other.trans {
+            self.trans.push(tr.iter().map(|&(a, t)| (a, t + offset)).collect());
+        }
+        self.finals.extend_from_slice(&other.finals);
+        offset
+    }
+
+    /* Analyses */
+    // The states reachable from the given ones by epsilon transitions
+    fn closure(
+        &self,
+        states: impl IntoIterator<Item = usize>,
+    ) -> BTreeSet<usize> {
+        let mut set = BTreeSet::new();
+        let mut stack: Vec<usize> = states.into_iter().collect();
+        while let Some(s) = stack.pop() {
+            if set.insert(s) {
+                stack.extend(
+                    self.trans[s]
+                        .iter()
+                        .filter(|tr| tr.0.is_none())
+                        .map(|tr| tr.1),
+                );
+            }
+        }
+        set
+    }
+    pub fn matches_nothing(&self) -> bool {
+        // No final state is reachable (whatever the letters)
+        let mut seen: BTreeSet<usize> = self.initial.iter().copied().collect();
+        let mut stack: Vec<usize> = seen.iter().copied().collect();
+        while let Some(s) = stack.pop() {
+            for &(_, t) in &self.trans[s] {
+                if seen.insert(t) {
+                    stack.push(t);
+                }
+            }
+        }
+        !seen.iter().any(|&s| self.finals[s])
+    }
+    pub fn matches_everything(&self) -> bool {
+        // Every reachable set of states of the determinized NFA has a
+        // final state
+        let start = self.closure(self.initial.iter().copied());
+        let mut seen = BTreeSet::new();
+        let mut stack = vec![start];
+        while let Some(set) = stack.pop() {
+            if !set.iter().any(|&s| self.finals[s]) {
+                return false;
+            }
+            for a in 0..self.n_letters {
+                let next = self.closure(set.iter().flat_map(|&s| {
+                    self.trans[s]
+                        .iter()
+                        .filter(move |tr| tr.0 == Some(a))
+                        .map(|tr| tr.1)
+                }));
+                if !seen.contains(&next) {
+                    seen.insert(next.clone());
+                    stack.push(next);
+                }
+            }
+        }
+        true
+    }
+}
+
+/*
+    Rates of the QRE constructs
+*/
+
+pub trait Rate<D> {
+    fn rate_nfa(&self, alphabet: &[D]) -> RateNfa;
+}
+
+pub fn matches_nothing<D, M: Rate<D> + ?Sized>(m: &M, alphabet: &[D]) -> bool {
+    m.rate_nfa(alphabet).matches_nothing()
+}
+pub fn matches_everything<D, M>(m: &M, alphabet: &[D]) -> bool
+where
+    M: Rate<D> + ?Sized,
+{
+    m.rate_nfa(alphabet).matches_everything()
+}
+
+impl<D, R: Rate<D> + ?Sized> Rate<D> for Box<R> {
+    fn rate_nfa(&self, alphabet: &[D]) -> RateNfa {
+        (**self).rate_nfa(alphabet)
+    }
+}
+impl<I, D, O, F> Rate<D> for Epsilon<I, D, O, F>
+where
+    F: Fn(I) -> O,
+{
+    fn rate_nfa(&self, alphabet: &[D]) -> RateNfa {
+        RateNfa::epsilon(alphabet.len())
+    }
+}
+impl<I, D, O, G, F> Rate<D> for Atom<I, D, O, G, F>
+where
+    G: Fn(&D) -> bool,
+    F: Fn(I, &D) -> O,
+{
+    fn rate_nfa(&self, alphabet: &[D]) -> RateNfa {
+        let letters: Vec<usize> = (0..alphabet.len())
+            .filter(|&a| (self.guard)(&alphabet[a]))
+            .collect();
+        RateNfa::letters(alphabet.len(), &letters)
+    }
+}
+impl<I, D, O, M1, M2> Rate<D> for Union<I, D, O, M1, M2>
+where
+    M1: Transducer<I, D, O> + Rate<D>,
+    M2: Transducer<I, D, O> + Rate<D>,
+{
+    fn rate_nfa(&self, alphabet: &[D]) -> RateNfa {
+        self.m1.rate_nfa(alphabet).union(self.m2.rate_nfa(alphabet))
+    }
+}
+impl<D, X, Y, Z, M1, M2> Rate<D> for Concat<D, X, Y, Z, M1, M2>
+where
+    M1: Transducer<X, D, Y> + Rate<D>,
+    M2: Transducer<Y, D, Z> + Rate<D>,
+{
+    fn rate_nfa(&self, alphabet: &[D]) -> RateNfa {
+        self.m1.rate_nfa(alphabet).concat(self.m2.rate_nfa(alphabet))
+    }
+}
+impl<X, D, M> Rate<D> for Iterate<X, D, M>
+where
+    M: Transducer<X, D, X> + Rate<D>,
+{
+    fn rate_nfa(&self, alphabet: &[D]) -> RateNfa {
+        self.m.rate_nfa(alphabet).star()
+    }
+}
+impl<I, D, O1, O2, M1, M2> Rate<D> for ParComp<I, D, O1, O2, M1, M2>
+where
+    M1: Transducer<I, D, O1> + Rate<D>,
+    M2: Transducer<I, D, O2> + Rate<D>,
+{
+    fn rate_nfa(&self, alphabet: &[D]) -> RateNfa {
+        self.m1.rate_nfa(alphabet).intersect(&self.m2.rate_nfa(alphabet))
+    }
+}
+impl<D, X, Y, Z, M, F> Rate<D> for Aggregate<D, X, Y, Z, M, F>
+where
+    M: Transducer<X, D, Y> + Rate<D>,
+    F: Fn(Z, Y) -> Z,
+{
+    fn rate_nfa(&self, alphabet: &[D]) -> RateNfa {
+        self.m.rate_nfa(alphabet)
+    }
+}
+impl<I, D, O, M> Rate<D> for TopWrapper<I, D, O, M>
+where
+    M: Transducer<I, D, O> + Rate<D>,
+{
+    fn rate_nfa(&self, alphabet: &[D]) -> RateNfa {
+        self.m.rate_nfa(alphabet)
+    }
+}
+
+/*
+    Unit Tests
+*/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::qre::{
+        aggregate, atom, concat, epsilon, iterate, parcomp, union,
+    };
+    use crate::state_machine::DataTransducer;
+
+    const ALPHABET: &[char] = &['a', 'b', 'c'];
+
+    fn is(c: char) -> impl Transducer<i32, char, i32> + Rate<char> {
+        atom(move |&d: &char| d == c, |x: i32, _| x + 1)
+    }
+    fn any() -> impl Transducer<i32, char, i32> + Rate<char> {
+        atom(|_: &char| true, |x: i32, _| x)
+    }
+
+    #[test]
+    fn test_qre_nothing() {
+        assert!(!matches_nothing(&is('a'), ALPHABET));
+        assert!(matches_nothing(&is('d'), ALPHABET));
+        // Contradictory guards only match nothing together
+        let both = parcomp(is('a'), is('b'));
+        assert!(matches_nothing(&both, ALPHABET));
+        let both = parcomp(iterate(is('a')), iterate(is('b')));
+        assert!(!matches_nothing(&both, ALPHABET));
+        assert!(matches_nothing(&concat(any(), is('d')), ALPHABET));
+        assert!(!matches_nothing(&iterate(is('d')), ALPHABET));
+        assert!(!matches_nothing(&union(is('d'), epsilon(|x| x)), ALPHABET));
+    }
+
+    #[test]
+    fn test_qre_everything() {
+        assert!(matches_everything(&iterate(any()), ALPHABET));
+        let m = iterate(union(is('a'), atom(|&d: &char| d != 'a', |x, _| x)));
+        assert!(matches_everything(&m, ALPHABET));
+        assert!(!matches_everything(&iterate(is('a')), ALPHABET));
+        // The empty stream isn't matched
+        assert!(!matches_everything(&concat(any(), iterate(any())), ALPHABET));
+        // Any stream ending in a, or not
+        let m = union(
+            concat(iterate(any()), is('a')),
+            union(epsilon(|x| x), concat(iterate(any()), is('b'))),
+        );
+        assert!(!matches_everything(&m, ALPHABET));
+        assert!(matches_everything(&m, &['a', 'b']));
+        // As for aggregate
+        let m = aggregate(iterate(any()), |z: i32, y| z + y);
+        assert!(matches_everything(&m, ALPHABET));
+        let boxed: Box<dyn Rate<char>> = Box::new(iterate(is('a')));
+        assert!(matches_everything(&boxed, &['a']));
+    }
+
+    #[test]
+    fn test_machine() {
+        // Outputs after the items a b, with anything in between
+        let mut m: DataTransducer<char, i32> = DataTransducer::new();
+        let s = m.add_state();
+        m.add_transition1(0, s, |&d| d == 'a', |_, &x| x);
+        m.add_iden(s, s, |&d| d != 'b');
+        m.add_transition1(s, 1, |&d| d == 'b', |_, &x| x);
+        assert!(!matches_nothing(&m, ALPHABET));
+        assert!(matches_nothing(&m, &['b', 'c']));
+        assert!(!matches_everything(&m, ALPHABET));
+        let nfa = m.rate_nfa(ALPHABET);
+        assert_eq!(nfa.n_letters(), 3);
+        // {0}, {s}, {1}, {}
+        assert_eq!(nfa.n_states(), 4);
+        // A transition with two sources needs both
+        let mut m: DataTransducer<char, i32> = DataTransducer::new();
+        let (s1, s2) = (m.add_state(), m.add_state());
+        m.add_epsilon1(0, s1, |&x| x);
+        m.add_transition1(0, s2, |&d| d == 'a', |_, &x| x);
+        m.add_transition2(s1, s2, 1, |_| true, |_, &x, &y| x + y);
+        assert!(matches_nothing(&m, ALPHABET));
+        // Only the empty stream, until the initial state keeps its value
+        let mut m: DataTransducer<char, i32> = DataTransducer::new();
+        m.add_epsilon_iden(0, 1);
+        assert!(!matches_everything(&m, ALPHABET));
+        m.add_iden(0, 0, |_| true);
+        assert!(matches_everything(&m, ALPHABET));
+    }
+
+    #[test]
+    fn test_intersect() {
+        // a*b and (a|b)b: only ab is in both
+        let n = 2;
+        let a_star_b =
+            RateNfa::letters(n, &[0]).star().concat(RateNfa::letters(n, &[1]));
+        let ab_b =
+            RateNfa::letters(n, &[0, 1]).concat(RateNfa::letters(n, &[1]));
+        let both = a_star_b.intersect(&ab_b);
+        assert!(!both.matches_nothing());
+        assert!(both.intersect(&RateNfa::epsilon(n)).matches_nothing());
+        assert!(RateNfa::new(n).matches_nothing());
+        assert!(RateNfa::epsilon(0).matches_everything());
+    }
+}
@@ -0,0 +1,340 @@
+/*
+    Record streams: named, typed fields of data items, and symbolic guards
+    on them.
+
+    A Record is a data item whose fields can be looked up by name, each
+    field being an integer, a boolean or a string. Tuples are records whose
+    fields are named by position ("0", "1", ...); structs can be made
+    records with impl_record!, and (with the json feature) JSON objects are
+    records too.
+
+    field(name) then builds guards (see guard.rs) on a field, without a
+    closure for each: e.g. for network packets
+        field("latency").gt(100) & field("proto").is("tcp")
+    Integer comparisons are interval constraints on the field, so the
+    satisfiability checks of guard.rs are exact for them; string and
+    boolean tests are named predicates (so two tests for different strings
+    are treated as independent). A test on an integer field is false if the
+    field is missing or not an integer, and similarly for the other types.
+
+    Example:
+        struct Packet { src: u32, port: u16, latency: i64 }
+        impl_record!(Packet { src, port, latency });
+        let slow_http = field("port").equals(80) & field("latency").gt(100);
+*/
+
+use super::guard::{self, Guard};
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Value<'r> {
+    Int(i64),
+    Bool(bool),
+    Str(&'r str),
+}
+impl Value<'_> {
+    pub fn as_int(&self) -> Option<i64> {
+        match self {
+            Value::Int(x) => Some(*x),
+            _ => None,
+        }
+    }
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Value::Bool(b) => Some(*b),
+            _ => None,
+        }
+    }
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::Str(s) => Some(s),
+            _ => None,
+        }
+    }
+}
+
+pub trait ToValue {
+    fn to_value(&self) -> Value<'_>;
+}
+macro_rules! int_to_value {
+    ($($t:ty),*) => {
+        $(
+            impl ToValue for $t {
+                fn to_value(&self) -> Value<'_> {
+                    Value::Int(*self as i64)
+                }
+            }
+        )*
+    };
+}
+int_to_value!(i8, i16, i32, i64, u8, u16, u32, u64, usize);
+impl ToValue for bool {
+    fn to_value(&self) -> Value<'_> {
+        Value::Bool(*self)
+    }
+}
+impl ToValue for str {
+    fn to_value(&self) -> Value<'_> {
+        Value::Str(self)
+    }
+}
+impl ToValue for String {
+    fn to_value(&self) -> Value<'_> {
+        Value::Str(self)
+    }
+}
+impl<T: ToValue + ?Sized> ToValue for &T {
+    fn to_value(&self) -> Value<'_> {
+        (**self).to_value()
+    }
+}
+
+/*
+    Records and typed field accessors
+*/
+
+pub trait Record {
+    // The field with the given name, if any
+    fn get(&self, name: &str) -> Option<Value<'_>>;
+
+    fn int(&self, name: &str) -> Option<i64> {
+        self.get(name).and_then(|v| v.as_int())
+    }
+    fn bool(&self, name: &str) -> Option<bool> {
+        self.get(name).and_then(|v| v.as_bool())
+    }
+    fn str(&self, name: &str) -> Option<&str> {
+        match self.get(name) {
+            Some(Value::Str(s)) => Some(s),
+            _ => None,
+        }
+    }
+}
+
+macro_rules! tuple_record {
+    ($($t:ident $i:tt),*) => {
+        impl<$($t: ToValue),*> Record for ($($t,)*) {
+            fn get(&self, name: &str) -> Option<Value<'_>> {
+                match name {
+                    $(stringify!($i) => Some(self.$i.to_value()),)*
+                    _ => None,
+                }
+            }
+        }
+    };
+}
+tuple_record!(A 0);
+tuple_record!(A 0, B 1);
+tuple_record!(A 0, B 1, C 2);
+tuple_record!(A 0, B 1, C 2, E 3);
+tuple_record!(A 0, B 1, C 2, E 3, F 4);
+tuple_record!(A 0, B 1, C 2, E 3, F 4, G 5);
+
+// Implement Record for a struct, with the listed fields (each of a type
+// implementing ToValue)
+#[macro_export]
+macro_rules! impl_record {
+    ($t:ty { $($f:ident),* $(,)? }) => {
+        impl $crate::record::Record for $t {
+            fn get(
+                &self,
+                name: &str,
+            ) -> Option<$crate::record::Value<'_>> {
+                match name {
+                    $(stringify!($f) => Some(
+                        $crate::record::ToValue::to_value(&self.$f),
+                    ),)*
+                    _ => None,
+                }
+            }
+        }
+    };
+}
+
+#[cfg(feature = "json")]
+impl Record for serde_json::Value {
+    fn get(&self, name: &str) -> Option<Value<'_>> {
+        match self.get(name)? {
+            serde_json::Value::Bool(b) => Some(Value::Bool(*b)),
+            serde_json::Value::Number(x) => x.as_i64().map(Value::Int),
+            serde_json::Value::String(s) => Some(Value::Str(s)),
+            _ => None,
+        }
+    }
+}
+
+/*
+    Guards on fields
+*/
+
+pub struct RecordField {
+    name: String,
+}
+pub fn field(name: &str) -> RecordField {
+    RecordField { name: name.to_string() }
+}
+
+impl RecordField {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    // The field is an integer
+    pub fn is_int<'a, D: Record + 'a>(&self) -> Guard<'a, D> {
+        let name = self.name.clone();
+        guard::pred(&format!("{}: int", self.name), move |d: &D| {
+            d.int(&name).is_some()
+        })
+    }
+    // The field is an integer between lo and hi (inclusive)
+    pub fn between<'a, D: Record + 'a>(
+        &self,
+        lo: i64,
+        hi: i64,
+    ) -> Guard<'a, D> {
+        let name = self.name.clone();
+        let x =
+            guard::field(&self.name, move |d: &D| d.int(&name).unwrap_or(0));
+        self.is_int() & x.in_range(lo, hi)
+    }
+    pub fn equals<'a, D: Record + 'a>(&self, v: i64) -> Guard<'a, D> {
+        self.between(v, v)
+    }
+    pub fn gt<'a, D: Record + 'a>(&self, v: i64) -> Guard<'a, D> {
+        match v.checked_add(1) {
+            Some(lo) => self.between(lo, i64::MAX),
+            None => Guard::False,
+        }
+    }
+    pub fn ge<'a, D: Record + 'a>(&self, v: i64) -> Guard<'a, D> {
+        self.between(v, i64::MAX)
+    }
+    pub fn lt<'a, D: Record + 'a>(&self, v: i64) -> Guard<'a, D> {
+        match v.checked_sub(1) {
+            Some(hi) => self.between(i64::MIN, hi),
+            None => Guard::False,
+        }
+    }
+    pub fn le<'a, D: Record + 'a>(&self, v: i64) -> Guard<'a, D> {
+        self.between(i64::MIN, v)
+    }
+
+    // The field is a string (resp. the string s)
+    pub fn is_str<'a, D: Record + 'a>(&self) -> Guard<'a, D> {
+        let name = self.name.clone();
+        guard::pred(&format!("{}: str", self.name), move |d: &D| {
+            d.str(&name).is_some()
+        })
+    }
+    pub fn is<'a, D: Record + 'a>(&self, s: &str) -> Guard<'a, D> {
+        let (name, s_owned) = (self.name.clone(), s.to_string());
+        guard::pred(&format!("{} == {:?}", self.name, s), move |d: &D| {
+            d.str(&name) == Some(&s_owned)
+        })
+    }
+    // The field is the boolean true (resp. false)
+    pub fn is_true<'a, D: Record + 'a>(&self) -> Guard<'a, D> {
+        let name = self.name.clone();
+        guard::pred(&self.name, move |d: &D| d.bool(&name) == Some(true))
+    }
+    pub fn is_false<'a, D: Record + 'a>(&self) -> Guard<'a, D> {
+        let name = self.name.clone();
+        guard::pred(&format!("!{}", self.name), move |d: &D| {
+            d.bool(&name) == Some(false)
+        })
+    }
+}
+
+/*
+    Unit Tests
+*/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Packet {
+        src: u32,
+        proto: String,
+        port: u16,
+        latency: i64,
+        syn: bool,
+    }
+    impl_record!(Packet { src, proto, port, latency, syn });
+
+    fn packet(proto: &str, port: u16, latency: i64) -> Packet {
+        let proto = proto.to_string();
+        Packet { src: 1, proto, port, latency, syn: port == 443 }
+    }
+
+    #[test]
+    fn test_accessors() {
+        let p = packet("tcp", 80, 120);
+        assert_eq!(p.int("latency"), Some(120));
+        assert_eq!(p.int("src"), Some(1));
+        assert_eq!(p.str("proto"), Some("tcp"));
+        assert_eq!(p.bool("syn"), Some(false));
+        assert_eq!(p.int("proto"), None);
+        assert_eq!(p.get("ttl"), None);
+        let t = ('a' as u32, "x", true);
+        assert_eq!(t.int("0"), Some(97));
+        assert_eq!(t.get("1"), Some(Value::Str("x")));
+        assert_eq!(t.bool("2"), Some(true));
+        assert_eq!(t.get("3"), None);
+    }
+
+    #[test]
+    fn test_guards() {
+        let slow_http = field("port").equals(80) & field("latency").gt(100);
+        assert!(slow_http.eval(&packet("tcp", 80, 120)));
+        assert!(!slow_http.eval(&packet("tcp", 80, 100)));
+        assert!(!slow_http.eval(&packet("tcp", 443, 120)));
+        let tcp = field("proto").is("tcp");
+        assert!(tcp.eval(&packet("tcp", 1, 1)));
+        assert!(!tcp.eval(&packet("udp", 1, 1)));
+        let syn = field("syn").is_true::<Packet>();
+        assert!(syn.eval(&packet("tcp", 443, 1)));
+        assert!(field("syn").is_false().eval(&packet("tcp", 80, 1)));
+        // Missing fields and fields of the wrong type
+        let g = field("ttl").le(5);
+        assert!(!g.eval(&packet("tcp", 80, 1)));
+        assert!((!g).eval(&packet("tcp", 80, 1)));
+        assert!(!field("proto").ge(0).eval(&packet("tcp", 80, 1)));
+        assert!(!field("port").is("80").eval(&packet("tcp", 80, 1)));
+        // Tuples
+        let g = field("1").between(1, 5) & field("0").is("a");
+        assert!(g.eval(&("a", 3)));
+        assert!(!g.eval(&("a", 6)));
+    }
+
+    #[test]
+    fn test_symbolic() {
+        // Integer comparisons are exact for the guard algebra
+        let fast: Guard<Packet> = field("latency").lt(50);
+        let slow = field("latency").gt(100);
+        assert!(!fast.overlaps(&slow));
+        assert!(slow.implies(&field("latency").ge(0)));
+        assert!(!(fast.clone() & slow.clone()).is_satisfiable());
+        let g: Guard<Packet> = fast | field("latency").between(50, 100) | slow;
+        assert!(g.implies(&field("latency").is_int()));
+        assert!(!g.is_valid());
+        assert!((g | !field("latency").is_int()).is_valid());
+        let g: Guard<Packet> = field("port").gt(i64::MAX);
+        assert!(!g.is_satisfiable());
+        let g: Guard<Packet> = field("latency").gt(100);
+        assert_eq!(g.to_string(), "(latency: int & latency >= 101)");
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn test_json() {
+        let v: serde_json::Value = serde_json::from_str(
+            r#"{"latency": 150, "proto": "tcp", "syn": true, "tags": []}"#,
+        )
+        .unwrap();
+        assert_eq!(Record::int(&v, "latency"), Some(150));
+        assert_eq!(Record::get(&v, "tags"), None);
+        let g = field("latency").gt(100) & field("proto").is("tcp");
+        assert!(g.eval(&v));
+        assert!(field("syn").is_true().eval(&v));
+    }
+}
@@ -0,0 +1,471 @@
+/*
+    Importing regexes compiled by regex-automata (with the "regex" feature).
+
+    regex-automata compiles regexes, in the syntax of the regex crate
+    (including Unicode classes), to DFAs and to Thompson NFAs over bytes.
+    These are converted to DataTransducers, so that it can be used as a
+    frontend for match-triggered aggregation:
+    - from_dfa(&dfa, action): a machine over bytes, with a state for each
+      DFA state reachable from the anchored start state;
+    - from_dfa_chars(dfa, action): the same over chars, each char moving
+      the DFA by its UTF-8 encoding;
+    - from_nfa(&nfa, action): a machine over bytes, with a state for each
+      NFA state which reads a byte (the epsilon transitions of the NFA are
+      followed here, so the machine has none, apart from those out of its
+      initial state).
+    As for the machines of derivative.rs, the initial value is carried
+    through the automaton, and there is an output on each prefix of the
+    stream (since .init()) which matches, taken as a whole: on a match of
+    pattern p, the output is action(p, &value). A prefix matched by several
+    patterns, or (for an NFA) along several paths, gives Ext::Many.
+
+    dfa(patterns) and nfa(patterns) compile the patterns as needed: the
+    DFA must have an anchored start state, and should report all matches
+    (MatchKind::All); with the default leftmost-first semantics, it stops
+    after the first match of an alternation. Look-around assertions (^, $,
+    \b, ...) are evaluated by a DFA at the end of each prefix, but can't be
+    imported from an NFA.
+*/
+
+use super::state_machine::DataTransducer;
+use regex_automata::dfa::{dense, Automaton, StartError, StartKind};
+use regex_automata::nfa::thompson::{self, State, NFA};
+use regex_automata::util::look::Look;
+use regex_automata::util::primitives::StateID;
+use regex_automata::util::start;
+use regex_automata::{Anchored, MatchKind};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::error::Error;
+use std::fmt::{self, Display};
+use std::rc::Rc;
+
+#[derive(Clone, Debug)]
+pub enum RegexError {
+    Dfa(Box<dense::BuildError>),
+    Nfa(Box<thompson::BuildError>),
+    // The DFA has no anchored start state
+    Start(StartError),
+    // An NFA state is a look-around assertion
+    Look(Look),
+}
+impl Display for RegexError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RegexError::Dfa(err) => write!(f, "building the DFA: {}", err),
+            RegexError::Nfa(err) => write!(f, "building the NFA: {}", err),
+            RegexError::Start(err) => write!(f, "no start state: {}", err),
+            RegexError::Look(look) => {
+                write!(f, "can't import assertion {:?} from an NFA", look)
+            }
+        }
+    }
+}
+impl Error for RegexError {}
+
+pub fn dfa<P: AsRef<str>>(
+    patterns: &[P],
+) -> Result<dense::DFA<Vec<u32>>, RegexError> {
+    let config = dense::Config::new()
+        .match_kind(MatchKind::All)
+        .start_kind(StartKind::Anchored);
+    dense::Builder::new()
+        .configure(config)
+        .build_many(patterns)
+        .map_err(|err| RegexError::Dfa(Box::new(err)))
+}
+
+pub fn nfa<P: AsRef<str>>(patterns: &[P]) -> Result<NFA, RegexError> {
+    let config =
+        thompson::Config::new().which_captures(thompson::WhichCaptures::None);
+    thompson::Compiler::new()
+        .configure(config)
+        .build_many(patterns)
+        .map_err(|err| RegexError::Nfa(Box::new(err)))
+}
+
+/*
+    Helpers
+*/
+
+// The machine states of the automaton states, added as they are reached
+struct States<S> {
+    ids: HashMap<S, usize>,
+    queue: VecDeque<S>,
+}
+impl<S: Copy + Eq + std::hash::Hash> States<S> {
+    fn new() -> Self {
+        Self { ids: HashMap::new(), queue: VecDeque::new() }
+    }
+    fn get<D, Q: Clone>(
+        &mut self,
+        m: &mut DataTransducer<'_, D, Q>,
+        s: S,
+    ) -> usize {
+        let queue = &mut self.queue;
+        *self.ids.entry(s).or_insert_with(|| {
+            queue.push_back(s);
+            m.add_state()
+        })
+    }
+}
+
+// Add a transition from source (or the initial state) to the final state
+// for each pattern
+fn add_matches<'a, D, Q, F>(
+    m: &mut DataTransducer<'a, D, Q>,
+    source: usize,
+    patterns: impl Iterator<Item = usize>,
+    action: &F,
+) where
+    Q: Clone,
+    F: 'a + Fn(usize, &Q) -> Q + Clone,
+{
+    for p in patterns {
+        let action = action.clone();
+        m.add_epsilon1(source, 1, move |q| action(p, q));
+    }
+}
+
+// Add b to the set of bytes for key
+fn add_byte<K: Eq>(sets: &mut Vec<(K, [bool; 256])>, key: K, b: u8) {
+    match sets.iter_mut().find(|(k, _)| *k == key) {
+        Some((_, bytes)) => bytes[b as usize] = true,
+        None => {
+            let mut bytes = [false; 256];
+            bytes[b as usize] = true;
+            sets.push((key, bytes));
+        }
+    }
+}
+
+fn start_state<A: Automaton>(dfa: &A) -> Result<StateID, RegexError> {
+    let config = start::Config::new().anchored(Anchored::Yes);
+    dfa.start_state(&config).map_err(RegexError::Start)
+}
+
+fn is_stopped<A: Automaton>(dfa: &A, s: StateID) -> bool {
+    dfa.is_dead_state(s) || dfa.is_quit_state(s)
+}
+
+// The patterns matching the input read so far, if it ends here
+fn dfa_matches<A: Automaton>(dfa: &A, s: StateID) -> Vec<usize> {
+    let eoi = dfa.next_eoi_state(s);
+    if !dfa.is_match_state(eoi) {
+        return vec![];
+    }
+    (0..dfa.match_len(eoi))
+        .map(|i| dfa.match_pattern(eoi, i).as_usize())
+        .collect()
+}
+
+/*
+    DFAs
+*/
+
+pub fn from_dfa<'a, A, Q, F>(
+    dfa: &A,
+    action: F,
+) -> Result<DataTransducer<'a, u8, Q>, RegexError>
+where
+    A: Automaton,
+    Q: Clone,
+    F: 'a + Fn(usize, &Q) -> Q + Clone,
+{
+    let mut m = DataTransducer::new();
+    let mut states = States::new();
+    let start = start_state(dfa)?;
+    if !is_stopped(dfa, start) {
+        let s = states.get(&mut m, start);
+        m.add_epsilon_iden(0, s);
+    }
+    while let Some(t) = states.queue.pop_front() {
+        let s = states.get(&mut m, t);
+        add_matches(&mut m, s, dfa_matches(dfa, t).into_iter(), &action);
+        // The bytes leading to each target
+        let mut targets: Vec<(StateID, [bool; 256])> = Vec::new();
+        for b in 0..=255 {
+            let next = dfa.next_state(t, b);
+            if !is_stopped(dfa, next) {
+                add_byte(&mut targets, next, b);
+            }
+        }
+        for (next, bytes) in targets {
+            let target = states.get(&mut m, next);
+            m.add_iden(s, target, move |&b: &u8| bytes[b as usize]);
+        }
+    }
+    Ok(m)
+}
+
+// The state after the UTF-8 encoding of ch (None if the DFA stops)
+fn next_char<A: Automaton>(dfa: &A, s: StateID, ch: char) -> Option<StateID> {
+    let mut buf = [0; 4];
+    let mut s = s;
+    for &b in ch.encode_utf8(&mut buf).as_bytes() {
+        s = dfa.next_state(s, b);
+        if is_stopped(dfa, s) {
+            return None;
+        }
+    }
+    Some(s)
+}
+
+// The states reachable by the UTF-8 encoding of some char (a superset:
+// overlong encodings and surrogates are not excluded)
+fn char_targets<A: Automaton>(dfa: &A, s: StateID) -> Vec<StateID> {
+    // After a lead byte, follow n continuation bytes
+    fn follow<A: Automaton>(
+        dfa: &A,
+        s: StateID,
+        n: usize,
+        seen: &mut HashSet<(StateID, usize)>,
+        result: &mut Vec<StateID>,
+    ) {
+        if is_stopped(dfa, s) || !seen.insert((s, n)) {
+            return;
+        }
+        if n == 0 {
+            result.push(s);
+            return;
+        }
+        for b in 0x80..=0xBF {
+            follow(dfa, dfa.next_state(s, b), n - 1, seen, result);
+        }
+    }
+    let mut seen = HashSet::new();
+    let mut result = Vec::new();
+    for b in 0..=0xF4 {
+        let n = match b {
+            0x00..=0x7F => 0,
+            0xC2..=0xDF => 1,
+            0xE0..=0xEF => 2,
+            0xF0..=0xF4 => 3,
+            _ => continue,
+        };
+        follow(dfa, dfa.next_state(s, b), n, &mut seen, &mut result);
+    }
+    result
+}
+
+pub fn from_dfa_chars<'a, A, Q, F>(
+    dfa: A,
+    action: F,
+) -> Result<DataTransducer<'a, char, Q>, RegexError>
+where
+    A: 'a + Automaton,
+    Q: Clone,
+    F: 'a + Fn(usize, &Q) -> Q + Clone,
+{
+    let dfa = Rc::new(dfa);
+    let mut m = DataTransducer::new();
+    let mut states = States::new();
+    let start = start_state(&*dfa)?;
+    if !is_stopped(&*dfa, start) {
+        let s = states.get(&mut m, start);
+        m.add_epsilon_iden(0, s);
+    }
+    while let Some(t) = states.queue.pop_front() {
+        let s = states.get(&mut m, t);
+        add_matches(&mut m, s, dfa_matches(&*dfa, t).into_iter(), &action);
+        for next in char_targets(&*dfa, t) {
+            let target = states.get(&mut m, next);
+            let dfa = Rc::clone(&dfa);
+            m.add_iden(s, target, move |&ch: &char| {
+                next_char(&*dfa, t, ch) == Some(next)
+            });
+        }
+    }
+    Ok(m)
+}
+
+/*
+    NFAs
+*/
+
+// The states reading a byte, and the patterns matched, which are
+// reachable from s by epsilon transitions
+fn closure(
+    nfa: &NFA,
+    s: StateID,
+) -> Result<(Vec<StateID>, Vec<usize>), RegexError> {
+    let mut seen = HashSet::new();
+    let mut stack = vec![s];
+    let mut reads = Vec::new();
+    let mut patterns = Vec::new();
+    while let Some(s) = stack.pop() {
+        if !seen.insert(s) {
+            continue;
+        }
+        match nfa.state(s) {
+            State::ByteRange { .. } | State::Sparse(_) | State::Dense(_) => {
+                reads.push(s)
+            }
+            State::Union { alternates } => {
+                stack.extend(alternates.iter().rev())
+            }
+            State::BinaryUnion { alt1, alt2 } => {
+                stack.push(*alt2);
+                stack.push(*alt1);
+            }
+            State::Capture { next, .. } => stack.push(*next),
+            State::Look { look, .. } => return Err(RegexError::Look(*look)),
+            State::Fail => (),
+            State::Match { pattern_id } => patterns.push(pattern_id.as_usize()),
+        }
+    }
+    Ok((reads, patterns))
+}
+
+// The transitions of a state reading a byte, as (first, last, next)
+fn byte_transitions(nfa: &NFA, s: StateID) -> Vec<(u8, u8, StateID)> {
+    match nfa.state(s) {
+        State::ByteRange { trans } => {
+            vec![(trans.start, trans.end, trans.next)]
+        }
+        State::Sparse(sparse) => sparse
+            .transitions
+            .iter()
+            .map(|tr| (tr.start, tr.end, tr.next))
+            .collect(),
+        State::Dense(dense) => (0..=255)
+            .zip(dense.transitions.iter())
+            .filter(|(_, next)| **next != StateID::ZERO)
+            .map(|(b, next)| (b, b, *next))
+            .collect(),
+        _ => vec![],
+    }
+}
+
+pub fn from_nfa<'a, Q, F>(
+    nfa: &NFA,
+    action: F,
+) -> Result<DataTransducer<'a, u8, Q>, RegexError>
+where
+    Q: Clone,
+    F: 'a + Fn(usize, &Q) -> Q + Clone,
+{
+    let mut m = DataTransducer::new();
+    let mut states = States::new();
+    let (reads, patterns) = closure(nfa, nfa.start_anchored())?;
+    for t in reads {
+        let s = states.get(&mut m, t);
+        m.add_epsilon_iden(0, s);
+    }
+    add_matches(&mut m, 0, patterns.into_iter(), &action);
+    while let Some(t) = states.queue.pop_front() {
+        let s = states.get(&mut m, t);
+        // The bytes leading to each target, and to a match of each pattern
+        let mut targets: Vec<(StateID, [bool; 256])> = Vec::new();
+        let mut matches: Vec<(usize, [bool; 256])> = Vec::new();
+        for (first, last, next) in byte_transitions(nfa, t) {
+            let (reads, patterns) = closure(nfa, next)?;
+            for b in first..=last {
+                for &r in &reads {
+                    add_byte(&mut targets, r, b);
+                }
+                for &p in &patterns {
+                    add_byte(&mut matches, p, b);
+                }
+            }
+        }
+        for (next, bytes) in targets {
+            let target = states.get(&mut m, next);
+            m.add_iden(s, target, move |&b: &u8| bytes[b as usize]);
+        }
+        for (p, bytes) in matches {
+            let action = action.clone();
+            m.add_transition1(
+                s,
+                1,
+                move |&b: &u8| bytes[b as usize],
+                move |_, q| action(p, q),
+            );
+        }
+    }
+    Ok(m)
+}
+
+/*
+    Unit Tests
+*/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ext_value::Ext;
+    use crate::interface::Transducer;
+
+    fn run<D, M>(m: &mut M, items: &[D]) -> Vec<usize>
+    where
+        M: Transducer<i64, D, i64>,
+    {
+        // The positions (in items) after which there is an output
+        m.reset();
+        let mut result = Vec::new();
+        let mut outs = vec![m.init_one(0)];
+        outs.extend(m.update_batch(items));
+        for (i, out) in outs.into_iter().enumerate() {
+            if !out.is_none() {
+                result.push(i);
+            }
+        }
+        result
+    }
+
+    #[test]
+    fn test_single_pattern() {
+        let count = |_: usize, q: &i64| q + 1;
+        let mut m1 = from_dfa(&dfa(&["ab+c?"]).unwrap(), count).unwrap();
+        let mut m2 = from_nfa(&nfa(&["ab+c?"]).unwrap(), count).unwrap();
+        let cases: [(&str, &[usize]); 4] = [
+            ("abbbc", &[2, 3, 4, 5]),
+            ("abcb", &[2, 3]),
+            ("ac", &[]),
+            ("", &[]),
+        ];
+        for (text, expected) in cases {
+            assert_eq!(run(&mut m1, text.as_bytes()), expected, "{}", text);
+            assert_eq!(run(&mut m2, text.as_bytes()), expected, "{}", text);
+        }
+        m1.reset();
+        m1.init_one(10);
+        assert_eq!(m1.update_batch(b"ab"), [Ext::None, Ext::One(11)]);
+    }
+
+    #[test]
+    fn test_patterns() {
+        // Each pattern adds a different amount
+        let action = |p: usize, q: &i64| q + 10_i64.pow(p as u32);
+        let patterns = ["[0-9]+", "[a-z]+[0-9]", "x[0-9]"];
+        let mut m1 = from_dfa(&dfa(&patterns).unwrap(), action).unwrap();
+        let mut m2 = from_nfa(&nfa(&patterns).unwrap(), action).unwrap();
+        for m in [&mut m1, &mut m2] {
+            m.init_one(0);
+            let out = m.update_batch(b"x12");
+            // x1 matches the last two patterns; x12 none
+            assert_eq!(out, [Ext::None, Ext::Many, Ext::None]);
+            m.reset();
+            m.init_one(0);
+            let out = m.update_batch(b"ab1");
+            assert_eq!(out, [Ext::None, Ext::None, Ext::One(10)]);
+            m.reset();
+            m.init_one(0);
+            assert_eq!(m.update_batch(b"42"), [Ext::One(1), Ext::One(1)]);
+        }
+    }
+
+    #[test]
+    fn test_chars() {
+        let d = dfa(&[r"\p{Greek}+!?"]).unwrap();
+        let mut m = from_dfa_chars(&d, |_, q: &i64| *q).unwrap();
+        let text: Vec<char> = "αβ!γ".chars().collect();
+        assert_eq!(run(&mut m, &text), [1, 2, 3]);
+        let text: Vec<char> = "αaβ".chars().collect();
+        assert_eq!(run(&mut m, &text), [1]);
+
+        let err = from_nfa(&nfa(&[r"a\b"]).unwrap(), |_, q: &i64| *q);
+        assert!(matches!(err, Err(RegexError::Look(_))));
+        let err = dfa(&["("]).unwrap_err();
+        assert!(err.to_string().starts_with("building the DFA"));
+    }
+}
@@ -0,0 +1,293 @@
+/*
+    Retractions: processing streams of Changes.
+
+    When an upstream system amends an item, it sends a retraction of the old
+    item (Change::Retract) and then the new one. Aggregates which are
+    invertible folds (sum, count, ...) can process the retraction by
+    undoing the item's contribution, rather than reprocessing the stream:
+    - invertible_fold(insert, retract) is the running fold of the items
+      inserted and not retracted, where retract undoes insert;
+    - count() and sum() are the usual instances.
+
+    Other transducers can't undo an item in general (e.g. a running max,
+    or a pattern). non_invertible(m) runs such a transducer on a stream of
+    changes, and flags the retractions: once an item is retracted, the
+    output is Ext::Many (undefined) until the next .init().
+    .is_invertible() tells the two apart.
+
+    Like aggregate in qre.rs, these keep a single accumulator, so they are
+    not restartable; .init() restarts the fold.
+*/
+
+use super::ext_value::{self, Ext};
+use super::interface::{Change, Transducer};
+use std::marker::PhantomData;
+use std::mem;
+use std::ops::{Add, Sub};
+
+/*
+    Invertible folds
+*/
+
+pub struct InvertibleFold<D, O, F, G>
+where
+    F: Fn(O, &D) -> O,
+    G: Fn(O, &D) -> O,
+{
+    insert: F,
+    retract: G,
+    acc: Ext<O>,
+    ph_d: PhantomData<D>,
+}
+pub fn invertible_fold<D, O, F, G>(
+    insert: F,
+    retract: G,
+) -> InvertibleFold<D, O, F, G>
+where
+    F: Fn(O, &D) -> O,
+    G: Fn(O, &D) -> O,
+{
+    InvertibleFold { insert, retract, acc: Ext::None, ph_d: PhantomData }
+}
+type Step<D, O> = fn(O, &D) -> O;
+pub type Count<D> = InvertibleFold<D, i64, Step<D, i64>, Step<D, i64>>;
+pub type Sum<D> = InvertibleFold<D, D, Step<D, D>, Step<D, D>>;
+
+pub fn count<D>() -> Count<D> {
+    invertible_fold(|n, _| n + 1, |n, _| n - 1)
+}
+pub fn sum<D>() -> Sum<D>
+where
+    D: Clone + Add<Output = D> + Sub<Output = D>,
+{
+    invertible_fold(|s, x| s + x.clone(), |s, x| s - x.clone())
+}
+
+impl<D, O, F, G> InvertibleFold<D, O, F, G>
+where
+    F: Fn(O, &D) -> O,
+    G: Fn(O, &D) -> O,
+{
+    pub fn is_invertible(&self) -> bool {
+        true
+    }
+}
+impl<D, O, F, G> Clone for InvertibleFold<D, O, F, G>
+where
+    O: Clone,
+    F: Fn(O, &D) -> O + Clone,
+    G: Fn(O, &D) -> O + Clone,
+{
+    fn clone(&self) -> Self {
+        let mut result =
+            invertible_fold(self.insert.clone(), self.retract.clone());
+        result.acc = self.acc.clone();
+        result
+    }
+}
+impl<D, O, F, G> Transducer<O, Change<D>, O> for InvertibleFold<D, O, F, G>
+where
+    O: Clone,
+    F: Fn(O, &D) -> O,
+    G: Fn(O, &D) -> O,
+{
+    fn init(&mut self, i: Ext<O>) -> Ext<O> {
+        if i.is_none() {
+            return Ext::None;
+        }
+        self.acc = i;
+        self.acc.clone()
+    }
+    fn update(&mut self, item: &Change<D>) -> Ext<O> {
+        let acc = mem::replace(&mut self.acc, Ext::None);
+        self.acc = match item {
+            Change::Insert(d) => {
+                ext_value::apply1(|x| (self.insert)(x, d), acc)
+            }
+            Change::Retract(d) => {
+                ext_value::apply1(|x| (self.retract)(x, d), acc)
+            }
+        };
+        self.acc.clone()
+    }
+    fn reset(&mut self) {
+        self.acc = Ext::None;
+    }
+
+    fn is_epsilon(&self) -> bool {
+        false
+    }
+    fn is_restartable(&self) -> bool {
+        false
+    }
+    fn is_nullable(&self) -> bool {
+        true
+    }
+    fn n_states(&self) -> usize {
+        1
+    }
+    fn n_transs(&self) -> usize {
+        2
+    }
+}
+
+/*
+    Non-invertible transducers
+*/
+
+pub struct NonInvertible<I, D, O, M>
+where
+    M: Transducer<I, D, O>,
+{
+    m: M,
+    // Whether an item was retracted since .init()
+    retracted: bool,
+    ph_i: PhantomData<I>,
+    ph_d: PhantomData<D>,
+    ph_o: PhantomData<O>,
+}
+pub fn non_invertible<I, D, O, M>(m: M) -> NonInvertible<I, D, O, M>
+where
+    M: Transducer<I, D, O>,
+{
+    NonInvertible {
+        m,
+        retracted: false,
+        ph_i: PhantomData,
+        ph_d: PhantomData,
+        ph_o: PhantomData,
+    }
+}
+
+impl<I, D, O, M> NonInvertible<I, D, O, M>
+where
+    M: Transducer<I, D, O>,
+{
+    pub fn is_invertible(&self) -> bool {
+        false
+    }
+}
+impl<I, D, O, M> Clone for NonInvertible<I, D, O, M>
+where
+    M: Transducer<I, D, O> + Clone,
+{
+    fn clone(&self) -> Self {
+        let mut result = non_invertible(self.m.clone());
+        result.retracted = self.retracted;
+        result
+    }
+}
+impl<I, D, O, M> Transducer<I, Change<D>, O> for NonInvertible<I, D, O, M>
+where
+    M: Transducer<I, D, O>,
+{
+    fn init(&mut self, i: Ext<I>) -> Ext<O> {
+        if i.is_none() {
+            return Ext::None;
+        }
+        self.m.reset();
+        self.retracted = false;
+        self.m.init(i)
+    }
+    fn update(&mut self, item: &Change<D>) -> Ext<O> {
+        match item {
+            Change::Insert(d) if !self.retracted => self.m.update(d),
+            Change::Insert(_) => Ext::Many,
+            Change::Retract(_) => {
+                self.retracted = true;
+                Ext::Many
+            }
+        }
+    }
+    fn reset(&mut self) {
+        self.m.reset();
+        self.retracted = false;
+    }
+
+    fn is_epsilon(&self) -> bool {
+        self.m.is_epsilon()
+    }
+    fn is_restartable(&self) -> bool {
+        false
+    }
+    fn is_nullable(&self) -> bool {
+        self.m.is_nullable()
+    }
+    fn n_states(&self) -> usize {
+        self.m.n_states() + 1
+    }
+    fn n_transs(&self) -> usize {
+        self.m.n_transs()
+    }
+    fn n_bytes(&self) -> usize {
+        mem::size_of::<Self>() - mem::size_of::<M>() + self.m.n_bytes()
+    }
+}
+
+/*
+    Unit Tests
+*/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::qre::{atom, iterate};
+
+    fn changes(items: &[i64]) -> Vec<Change<i64>> {
+        // Negative numbers retract their absolute value
+        items
+            .iter()
+            .map(|&x| match x {
+                x if x < 0 => Change::Retract(-x),
+                x => Change::Insert(x),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_count_sum() {
+        let items = changes(&[3, 4, -3, 5, -4]);
+        let mut m = count();
+        assert_eq!(m.init_one(0), Ext::One(0));
+        let out = m.update_batch(&items);
+        let expected: Vec<Ext<i64>> =
+            [1, 2, 1, 2, 1].iter().map(|&n| Ext::One(n)).collect();
+        assert_eq!(out, expected);
+        let mut m = sum();
+        assert!(m.is_invertible() && !m.is_restartable());
+        m.init_one(0);
+        let out = m.update_batch(&items);
+        let expected: Vec<Ext<i64>> =
+            [3, 7, 4, 9, 5].iter().map(|&n| Ext::One(n)).collect();
+        assert_eq!(out, expected);
+        // .init() restarts the fold
+        assert_eq!(m.init_one(10), Ext::One(10));
+        assert_eq!(m.update(&Change::Insert(1)), Ext::One(11));
+        m.reset();
+        assert_eq!(m.update(&Change::Insert(1)), Ext::None);
+    }
+
+    #[test]
+    fn test_invertible_fold() {
+        // Product of nonzero rationals, as (numerator, denominator)
+        let mut m = invertible_fold(
+            |(n, d): (i64, i64), &x: &i64| (n * x, d),
+            |(n, d): (i64, i64), &x: &i64| (n, d * x),
+        );
+        m.init_one((1, 1));
+        let out = m.update_batch(&changes(&[2, 3, -2]));
+        assert_eq!(out[2], Ext::One((6, 2)));
+    }
+
+    #[test]
+    fn test_non_invertible() {
+        let max = iterate(atom(|_| true, |m: i64, &x: &i64| m.max(x)));
+        let mut m = non_invertible(max);
+        assert!(!m.is_invertible());
+        m.init_one(0);
+        let out = m.update_batch(&changes(&[3, 5, -5, 2]));
+        assert_eq!(out, vec![Ext::One(3), Ext::One(5), Ext::Many, Ext::Many]);
+        m.init_one(0);
+        assert_eq!(m.update(&Change::Insert(2)), Ext::One(2));
+    }
+}
@@ -0,0 +1,289 @@
+/*
+    Keyed parallel runtime.
+
+    Many streams are keyed (by user, sensor, ...), with an independent
+    computation per key. KeyedRuntime shards such a stream across worker
+    threads by the hash of the key; each worker runs one instance of the
+    transducer per key (a copy of the given transducer, initialized with the
+    given initial value on the first item of the key), and the outputs of
+    the workers are merged back into one stream.
+
+    Each item produces one KeyedOutput: the output of its key's instance on
+    the item (possibly Ext::None), tagged with the key and the position of
+    the item in the input. (The outputs of .init() on new instances are not
+    reported, as they don't correspond to any item.) The outputs for a key
+    are always in input order; MergeOrder chooses the order overall:
+    - Input: the input order, as if the stream were processed sequentially
+      (outputs are buffered until all earlier ones are done);
+    - Arrival: the order in which the workers finish them, which keeps
+      latency and memory down.
+
+    The workers are std threads, fed over bounded channels so that a slow
+    worker applies backpressure to the input.
+
+    The key is given by a function on the items, or for items which carry
+    their key, by DataItem::key (with KeyedRuntime::by_key); items without
+    a key then all go to one instance, under the key None.
+*/
+
+use super::ext_value::Ext;
+use super::interface::{DataItem, Transducer};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{Hash, Hasher};
+use std::marker::PhantomData;
+use std::sync::mpsc;
+use std::thread;
+
+// Capacity of the channel to each worker
+const WORKER_QUEUE: usize = 1024;
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum MergeOrder {
+    #[default]
+    Input,
+    Arrival,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct KeyedOutput<K, O> {
+    // Position of the item in the input
+    pub seq: usize,
+    pub key: K,
+    pub output: Ext<O>,
+}
+
+pub struct KeyedRuntime<I, D, O, K, M, F>
+where
+    M: Transducer<I, D, O>,
+    F: Fn(&D) -> K,
+{
+    m: M,
+    init: I,
+    key_of: F,
+    n_workers: usize,
+    order: MergeOrder,
+    ph_d: PhantomData<D>,
+    ph_o: PhantomData<O>,
+}
+impl<I, D, O, K, M, F> KeyedRuntime<I, D, O, K, M, F>
+where
+    I: Clone + Send,
+    D: Send,
+    O: Send,
+    K: Clone + Eq + Hash + Send,
+    M: Transducer<I, D, O> + Clone + Send,
+    F: Fn(&D) -> K,
+{
+    pub fn new(m: M, init: I, key_of: F) -> Self {
+        let n_workers = thread::available_parallelism().map_or(1, |n| n.get());
+        Self {
+            m,
+            init,
+            key_of,
+            n_workers,
+            order: MergeOrder::default(),
+            ph_d: PhantomData,
+            ph_o: PhantomData,
+        }
+    }
+    pub fn workers(mut self, n_workers: usize) -> Self {
+        assert!(n_workers > 0, "need at least one worker");
+        self.n_workers = n_workers;
+        self
+    }
+    pub fn order(mut self, order: MergeOrder) -> Self {
+        self.order = order;
+        self
+    }
+
+    fn shard(&self, key: &K) -> usize {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        (hasher.finish() % self.n_workers as u64) as usize
+    }
+
+    // Run on the items, passing each output to the callback (on the
+    // calling thread) in the chosen order
+    pub fn run_with<It, G>(&self, items: It, mut callback: G)
+    where
+        It: IntoIterator<Item = D>,
+        G: FnMut(KeyedOutput<K, O>),
+    {
+        thread::scope(|scope| {
+            let (out_tx, out_rx) = mpsc::channel();
+            let mut inputs = Vec::new();
+            for _ in 0..self.n_workers {
+                let (tx, rx) =
+                    mpsc::sync_channel::<(usize, K, D)>(WORKER_QUEUE);
+                let out_tx = out_tx.clone();
+                let (m, init) = (self.m.clone(), self.init.clone());
+                scope.spawn(move || {
+                    let mut instances: HashMap<K, M> = HashMap::new();
+                    for (seq, key, item) in rx {
+                        let inst =
+                            instances.entry(key.clone()).or_insert_with(|| {
+                                let mut inst = m.spawn_empty();
+                                inst.init_one(init.clone());
+                                inst
+                            });
+                        let output = inst.update(&item);
+                        // The receiver outlives the workers
+                        out_tx.send(KeyedOutput { seq, key, output }).unwrap();
+                    }
+                });
+                inputs.push(tx);
+            }
+            drop(out_tx);
+
+            let mut merge = Merge::new(self.order);
+            for (seq, item) in items.into_iter().enumerate() {
+                let key = (self.key_of)(&item);
+                let shard = self.shard(&key);
+                inputs[shard].send((seq, key, item)).unwrap();
+                for out in out_rx.try_iter() {
+                    merge.push(out, &mut callback);
+                }
+            }
+            drop(inputs);
+            for out in out_rx {
+                merge.push(out, &mut callback);
+            }
+        });
+    }
+
+    pub fn run<It>(&self, items: It) -> Vec<KeyedOutput<K, O>>
+    where
+        It: IntoIterator<Item = D>,
+    {
+        let mut result = Vec::new();
+        self.run_with(items, |out| result.push(out));
+        result
+    }
+}
+
+impl<I, D, O, M>
+    KeyedRuntime<I, D, O, Option<D::Key>, M, fn(&D) -> Option<D::Key>>
+where
+    I: Clone + Send,
+    D: DataItem + Send,
+    D::Key: Clone + Eq + Hash + Send,
+    O: Send,
+    M: Transducer<I, D, O> + Clone + Send,
+{
+    pub fn by_key(m: M, init: I) -> Self {
+        Self::new(m, init, D::key)
+    }
+}
+
+// Merging the outputs of the workers
+struct Merge<K, O> {
+    order: MergeOrder,
+    // Next output to report, and the outputs after it (for Input order)
+    next: usize,
+    pending: BTreeMap<usize, KeyedOutput<K, O>>,
+}
+impl<K, O> Merge<K, O> {
+    fn new(order: MergeOrder) -> Self {
+        Self { order, next: 0, pending: BTreeMap::new() }
+    }
+    fn push<G>(&mut self, out: KeyedOutput<K, O>, callback: &mut G)
+    where
+        G: FnMut(KeyedOutput<K, O>),
+    {
+        if self.order == MergeOrder::Arrival {
+            callback(out);
+            return;
+        }
+        self.pending.insert(out.seq, out);
+        while let Some(out) = self.pending.remove(&self.next) {
+            callback(out);
+            self.next += 1;
+        }
+    }
+}
+
+/*
+    Unit Tests
+*/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::sum_by;
+    use crate::qre::{atom, iterate};
+
+    // Items are (sensor, reading); running sum of the readings per sensor
+    fn items() -> Vec<(u32, i64)> {
+        (0..1000).map(|i| ((i * 7) % 13, i as i64)).collect()
+    }
+    fn sum() -> impl Transducer<i64, (u32, i64), i64> + Clone + Send {
+        sum_by(|&(_, x)| x)
+    }
+    fn sequential() -> Vec<KeyedOutput<u32, i64>> {
+        let mut sums = HashMap::new();
+        let items = items().into_iter().enumerate();
+        items
+            .map(|(seq, (key, x))| {
+                let s = sums.entry(key).or_insert(0);
+                *s += x;
+                KeyedOutput { seq, key, output: Ext::One(*s) }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_input_order() {
+        let rt = KeyedRuntime::new(sum(), 0, |&(key, _)| key).workers(4);
+        assert_eq!(rt.run(items()), sequential());
+        let rt = rt.workers(1);
+        assert_eq!(rt.run(items()), sequential());
+    }
+
+    #[test]
+    fn test_arrival_order() {
+        let rt = KeyedRuntime::new(sum(), 0, |&(key, _)| key)
+            .workers(3)
+            .order(MergeOrder::Arrival);
+        let mut out = Vec::new();
+        rt.run_with(items(), |o| out.push(o));
+        // In input order per key
+        let mut last = HashMap::new();
+        for o in &out {
+            let prev = last.insert(o.key, o.seq);
+            assert!(prev.is_none_or(|prev| prev < o.seq));
+        }
+        out.sort_by_key(|o| o.seq);
+        assert_eq!(out, sequential());
+    }
+
+    #[test]
+    fn test_by_key() {
+        struct Reading {
+            sensor: Option<u32>,
+            value: i64,
+        }
+        impl DataItem for Reading {
+            type Key = u32;
+            fn key(&self) -> Option<u32> {
+                self.sensor
+            }
+        }
+        let readings = [(Some(1), 10), (None, 20), (Some(1), 30), (None, 1)]
+            .iter()
+            .map(|&(sensor, value)| Reading { sensor, value });
+        let sum = iterate(atom(|_| true, |s, r: &Reading| s + r.value));
+        let rt = KeyedRuntime::by_key(sum, 0).workers(2);
+        let outs: Vec<(Option<u32>, Ext<i64>)> =
+            rt.run(readings).into_iter().map(|o| (o.key, o.output)).collect();
+        assert_eq!(
+            outs,
+            [
+                (Some(1), Ext::One(10)),
+                (None, Ext::One(20)),
+                (Some(1), Ext::One(40)),
+                (None, Ext::One(21)),
+            ]
+        );
+    }
+}
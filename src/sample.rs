@@ -0,0 +1,534 @@
+/*
+    Sampling and rate limiting.
+
+    High-rate sources often need decimation before an expensive transducer
+    runs on them, or after it, to limit the rate of its outputs. These
+    wrappers do both:
+    - sample_every(m, n) feeds m only every n-th item (the first, the
+      (n+1)-th, ...);
+    - sample_prob(m, p, rng) feeds m each item with probability p;
+    - throttle(m, dur) keeps an output of m only if at least dur time has
+      passed since the last output kept (on a timed stream);
+    - emit_every(m, schedule) goes the other way: it outputs the current
+      value of m on a regular schedule, whether or not m has output.
+    Items which are not fed to m, and outputs which are dropped, give
+    Ext::None.
+
+    The items are counted (and the times measured) from the start of the
+    stream, not from the last .init(); so restarts see the same sample.
+    But the wrappers aren't restartable (except in trivial cases), as a
+    copy started on a restart would sample differently.
+*/
+
+use super::ext_value::Ext;
+use super::interface::Transducer;
+use super::random::Rng;
+use super::timed::{Time, Timed};
+use std::marker::PhantomData;
+use std::mem;
+
+/*
+    Every n-th item
+*/
+
+pub struct SampleEvery<I, D, O, M>
+where
+    M: Transducer<I, D, O>,
+{
+    m: M,
+    n: usize,
+    // Number of items seen, mod n
+    count: usize,
+    ph_i: PhantomData<I>,
+    ph_d: PhantomData<D>,
+    ph_o: PhantomData<O>,
+}
+pub fn sample_every<I, D, O, M>(m: M, n: usize) -> SampleEvery<I, D, O, M>
+where
+    M: Transducer<I, D, O>,
+{
+    assert!(n > 0, "sampling rate must be positive");
+    SampleEvery {
+        m,
+        n,
+        count: 0,
+        ph_i: PhantomData,
+        ph_d: PhantomData,
+        ph_o: PhantomData,
+    }
+}
+
+impl<I, D, O, M> Clone for SampleEvery<I, D, O, M>
+where
+    M: Transducer<I, D, O> + Clone,
+{
+    fn clone(&self) -> Self {
+        let mut result = sample_every(self.m.clone(), self.n);
+        result.count = self.count;
+        result
+    }
+}
+impl<I, D, O, M> Transducer<I, D, O> for SampleEvery<I, D, O, M>
+where
+    M: Transducer<I, D, O>,
+{
+    fn init(&mut self, i: Ext<I>) -> Ext<O> {
+        self.m.init(i)
+    }
+    fn update(&mut self, item: &D) -> Ext<O> {
+        let sampled = self.count == 0;
+        self.count = (self.count + 1) % self.n;
+        if sampled {
+            self.m.update(item)
+        } else {
+            Ext::None
+        }
+    }
+    fn reset(&mut self) {
+        self.m.reset();
+        self.count = 0;
+    }
+
+    fn is_epsilon(&self) -> bool {
+        self.m.is_epsilon()
+    }
+    fn is_restartable(&self) -> bool {
+        self.n == 1 && self.m.is_restartable()
+    }
+    fn is_nullable(&self) -> bool {
+        self.m.is_nullable()
+    }
+    fn n_states(&self) -> usize {
+        self.m.n_states() + 1
+    }
+    fn n_transs(&self) -> usize {
+        self.m.n_transs()
+    }
+    fn n_bytes(&self) -> usize {
+        mem::size_of::<Self>() - mem::size_of::<M>() + self.m.n_bytes()
+    }
+}
+
+/*
+    Random sample
+
+    The rng is seeded by the caller, so the sample is reproducible; .reset()
+    does not rewind it.
+*/
+
+pub struct SampleProb<I, D, O, M>
+where
+    M: Transducer<I, D, O>,
+{
+    m: M,
+    p: f64,
+    rng: Rng,
+    ph_i: PhantomData<I>,
+    ph_d: PhantomData<D>,
+    ph_o: PhantomData<O>,
+}
+pub fn sample_prob<I, D, O, M>(m: M, p: f64, rng: Rng) -> SampleProb<I, D, O, M>
+where
+    M: Transducer<I, D, O>,
+{
+    assert!((0.0..=1.0).contains(&p), "probability must be in [0, 1]");
+    SampleProb {
+        m,
+        p,
+        rng,
+        ph_i: PhantomData,
+        ph_d: PhantomData,
+        ph_o: PhantomData,
+    }
+}
+
+impl<I, D, O, M> Clone for SampleProb<I, D, O, M>
+where
+    M: Transducer<I, D, O> + Clone,
+{
+    fn clone(&self) -> Self {
+        sample_prob(self.m.clone(), self.p, self.rng.clone())
+    }
+}
+impl<I, D, O, M> Transducer<I, D, O> for SampleProb<I, D, O, M>
+where
+    M: Transducer<I, D, O>,
+{
+    fn init(&mut self, i: Ext<I>) -> Ext<O> {
+        self.m.init(i)
+    }
+    fn update(&mut self, item: &D) -> Ext<O> {
+        // Top 53 bits, as a uniform float in [0, 1)
+        let x = (self.rng.next_u64() >> 11) as f64 / (1u64 << 53) as f64;
+        if x < self.p {
+            self.m.update(item)
+        } else {
+            Ext::None
+        }
+    }
+    fn reset(&mut self) {
+        self.m.reset();
+    }
+
+    fn is_epsilon(&self) -> bool {
+        self.m.is_epsilon()
+    }
+    fn is_restartable(&self) -> bool {
+        self.p >= 1.0 && self.m.is_restartable()
+    }
+    fn is_nullable(&self) -> bool {
+        self.m.is_nullable()
+    }
+    fn n_states(&self) -> usize {
+        self.m.n_states()
+    }
+    fn n_transs(&self) -> usize {
+        self.m.n_transs()
+    }
+    fn n_bytes(&self) -> usize {
+        mem::size_of::<Self>() - mem::size_of::<M>() + self.m.n_bytes()
+    }
+}
+
+/*
+    Throttling the outputs
+
+    An output of m at time t is kept if no output was kept in (t - dur, t].
+    Outputs on .init() have no time, and are always kept.
+*/
+
+pub struct Throttle<I, D, O, M>
+where
+    M: Transducer<I, Timed<D>, O>,
+{
+    m: M,
+    dur: Time,
+    // Time of the last output kept
+    last: Option<Time>,
+    ph_i: PhantomData<I>,
+    ph_d: PhantomData<D>,
+    ph_o: PhantomData<O>,
+}
+pub fn throttle<I, D, O, M>(m: M, dur: Time) -> Throttle<I, D, O, M>
+where
+    M: Transducer<I, Timed<D>, O>,
+{
+    Throttle {
+        m,
+        dur,
+        last: None,
+        ph_i: PhantomData,
+        ph_d: PhantomData,
+        ph_o: PhantomData,
+    }
+}
+
+impl<I, D, O, M> Clone for Throttle<I, D, O, M>
+where
+    M: Transducer<I, Timed<D>, O> + Clone,
+{
+    fn clone(&self) -> Self {
+        let mut result = throttle(self.m.clone(), self.dur);
+        result.last = self.last;
+        result
+    }
+}
+impl<I, D, O, M> Transducer<I, Timed<D>, O> for Throttle<I, D, O, M>
+where
+    M: Transducer<I, Timed<D>, O>,
+{
+    fn init(&mut self, i: Ext<I>) -> Ext<O> {
+        self.m.init(i)
+    }
+    fn update(&mut self, item: &Timed<D>) -> Ext<O> {
+        let out = self.m.update(item);
+        if out.is_none()
+            || self
+                .last
+                .is_some_and(|last| item.time.saturating_sub(last) < self.dur)
+        {
+            return Ext::None;
+        }
+        self.last = Some(item.time);
+        out
+    }
+    fn reset(&mut self) {
+        self.m.reset();
+        self.last = None;
+    }
+
+    fn is_epsilon(&self) -> bool {
+        self.m.is_epsilon()
+    }
+    fn is_restartable(&self) -> bool {
+        self.dur == 0 && self.m.is_restartable()
+    }
+    fn is_nullable(&self) -> bool {
+        self.m.is_nullable()
+    }
+    fn n_states(&self) -> usize {
+        self.m.n_states() + 1
+    }
+    fn n_transs(&self) -> usize {
+        self.m.n_transs()
+    }
+    fn n_bytes(&self) -> usize {
+        mem::size_of::<Self>() - mem::size_of::<M>() + self.m.n_bytes()
+    }
+}
+
+/*
+    Periodic emission
+
+    Dashboards need regular readings of an aggregate, rather than only the
+    outputs triggered by matches. emit_every(m, schedule) keeps the current
+    value of m (its latest output, including on .init()), and outputs it
+    exactly on the steps where the schedule is due:
+    - EveryN::new(n): on every n-th item;
+    - Period::new(dur): on the first item at or after each multiple of dur (on
+      a timed stream), i.e. at most once per period.
+    The value output is the one after the item is processed.
+*/
+
+pub trait Schedule<D> {
+    // Whether an output is due on the item
+    fn due(&mut self, item: &D) -> bool;
+    fn reset(&mut self);
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct EveryN {
+    n: usize,
+    // Number of items seen, mod n
+    count: usize,
+}
+impl EveryN {
+    pub fn new(n: usize) -> Self {
+        assert!(n > 0, "emission rate must be positive");
+        EveryN { n, count: 0 }
+    }
+}
+impl<D> Schedule<D> for EveryN {
+    fn due(&mut self, _item: &D) -> bool {
+        self.count = (self.count + 1) % self.n;
+        self.count == 0
+    }
+    fn reset(&mut self) {
+        self.count = 0;
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Period {
+    dur: Time,
+    // Start of the next period (None before the first item)
+    next: Option<Time>,
+}
+impl Period {
+    pub fn new(dur: Time) -> Self {
+        assert!(dur > 0, "emission period must be positive");
+        Period { dur, next: None }
+    }
+}
+impl<D> Schedule<Timed<D>> for Period {
+    fn due(&mut self, item: &Timed<D>) -> bool {
+        let due = self.next.is_some_and(|next| item.time >= next);
+        if due || self.next.is_none() {
+            let period = item.time / self.dur;
+            self.next = Some(period.saturating_add(1).saturating_mul(self.dur));
+        }
+        due
+    }
+    fn reset(&mut self) {
+        self.next = None;
+    }
+}
+
+pub struct EmitEvery<I, D, O, M, S>
+where
+    M: Transducer<I, D, O>,
+    S: Schedule<D>,
+{
+    m: M,
+    schedule: S,
+    current: Ext<O>,
+    ph_i: PhantomData<I>,
+    ph_d: PhantomData<D>,
+}
+pub fn emit_every<I, D, O, M, S>(m: M, schedule: S) -> EmitEvery<I, D, O, M, S>
+where
+    M: Transducer<I, D, O>,
+    S: Schedule<D>,
+{
+    EmitEvery {
+        m,
+        schedule,
+        current: Ext::None,
+        ph_i: PhantomData,
+        ph_d: PhantomData,
+    }
+}
+
+impl<I, D, O, M, S> Clone for EmitEvery<I, D, O, M, S>
+where
+    O: Clone,
+    M: Transducer<I, D, O> + Clone,
+    S: Schedule<D> + Clone,
+{
+    fn clone(&self) -> Self {
+        let mut result = emit_every(self.m.clone(), self.schedule.clone());
+        result.current = self.current.clone();
+        result
+    }
+}
+impl<I, D, O, M, S> Transducer<I, D, O> for EmitEvery<I, D, O, M, S>
+where
+    O: Clone,
+    M: Transducer<I, D, O>,
+    S: Schedule<D>,
+{
+    fn init(&mut self, i: Ext<I>) -> Ext<O> {
+        let out = self.m.init(i);
+        if !out.is_none() {
+            self.current = out;
+        }
+        Ext::None
+    }
+    fn update(&mut self, item: &D) -> Ext<O> {
+        let out = self.m.update(item);
+        if !out.is_none() {
+            self.current = out;
+        }
+        if self.schedule.due(item) {
+            self.current.clone()
+        } else {
+            Ext::None
+        }
+    }
+    fn reset(&mut self) {
+        self.m.reset();
+        self.schedule.reset();
+        self.current = Ext::None;
+    }
+
+    fn is_epsilon(&self) -> bool {
+        false
+    }
+    fn is_restartable(&self) -> bool {
+        false
+    }
+    fn is_nullable(&self) -> bool {
+        false
+    }
+    fn n_states(&self) -> usize {
+        self.m.n_states() + 2
+    }
+    fn n_transs(&self) -> usize {
+        self.m.n_transs()
+    }
+    fn n_bytes(&self) -> usize {
+        mem::size_of::<Self>() - mem::size_of::<M>() + self.m.n_bytes()
+    }
+}
+
+/*
+    Unit Tests
+*/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::sum;
+    use crate::qre::{atom, iterate, union};
+    use crate::timed::untimed;
+
+    #[test]
+    fn test_sample_every() {
+        let mut m = sample_every(sum(), 3);
+        assert!(!m.is_restartable());
+        assert_eq!(m.init_one(0), Ext::One(0));
+        let out = m.update_batch(&[1, 2, 3, 4, 5, 6, 7]);
+        let expected =
+            vec![1, 0, 0, 5, 0, 0, 12].into_iter().map(|x| match x {
+                0 => Ext::None,
+                x => Ext::One(x),
+            });
+        assert_eq!(out, expected.collect::<Vec<_>>());
+        m.reset();
+        m.init_one(0);
+        assert_eq!(m.update(&10), Ext::One(10));
+        assert!(sample_every(sum(), 1).is_restartable());
+    }
+
+    #[test]
+    fn test_sample_prob() {
+        let items: Vec<i64> = vec![1; 1000];
+        let mut m = sample_prob(sum(), 0.25, Rng::new(5));
+        m.init_one(0);
+        let out = m.update_batch(&items);
+        let n = out.iter().filter(|o| o.is_one()).count();
+        assert!((200..300).contains(&n), "sampled {} of 1000", n);
+        // Reproducible given the seed
+        let mut m2 = sample_prob(sum(), 0.25, Rng::new(5));
+        m2.init_one(0);
+        assert_eq!(m2.update_batch(&items), out);
+        // Edge cases
+        let mut m = sample_prob(sum(), 0.0, Rng::new(1));
+        m.init_one(0);
+        assert!(m.update_batch(&items).iter().all(|o| o.is_none()));
+        let mut m = sample_prob(sum(), 1.0, Rng::new(1));
+        m.init_one(0);
+        assert_eq!(m.update_batch(&items)[999], Ext::One(1000));
+    }
+
+    #[test]
+    fn test_throttle() {
+        let mut m = throttle(untimed(sum()), 10);
+        assert_eq!(m.init_one(0), Ext::One(0));
+        let items: Vec<Timed<i64>> = [0, 3, 9, 10, 12, 25, 34]
+            .iter()
+            .map(|&t| Timed::new(t, 1))
+            .collect();
+        let out = m.update_batch(&items);
+        let kept: Vec<Time> = items
+            .iter()
+            .zip(out)
+            .filter(|(_, o)| o.is_one())
+            .map(|(item, _)| item.time)
+            .collect();
+        assert_eq!(kept, vec![0, 10, 25]);
+    }
+
+    #[test]
+    fn test_emit_every() {
+        // Running max of the even items, read every 3 items
+        let max = iterate(union(
+            atom(|&x: &i64| x % 2 == 0, |m: i64, &x| m.max(x)),
+            atom(|&x: &i64| x % 2 != 0, |m, _| m),
+        ));
+        let mut m = emit_every(max, EveryN::new(3));
+        assert_eq!(m.init_one(0), Ext::None);
+        let out = m.update_batch(&[4, 1, 3, 8, 5, 2, 7]);
+        let expected = vec![None, None, Some(4), None, None, Some(8), None];
+        let expected: Vec<Ext<i64>> =
+            expected.into_iter().map(Ext::from).collect();
+        assert_eq!(out, expected);
+
+        // Item count, read once per 10 time units
+        let count = untimed(iterate(atom(|_| true, |n: i64, _: &i64| n + 1)));
+        let mut m = emit_every(count, Period::new(10));
+        m.init_one(0);
+        let items: Vec<Timed<i64>> = [3, 5, 12, 15, 38, 39, 40]
+            .iter()
+            .map(|&t| Timed::new(t, 0))
+            .collect();
+        let out = m.update_batch(&items);
+        let expected = vec![None, None, Some(3), None, Some(5), None, Some(7)];
+        let expected: Vec<Ext<i64>> =
+            expected.into_iter().map(Ext::from).collect();
+        assert_eq!(out, expected);
+        m.reset();
+        m.init_one(0);
+        assert_eq!(m.update(&Timed::new(50, 0)), Ext::None);
+    }
+}
@@ -0,0 +1,91 @@
+/*
+    Scanning text for the hand-written parsers of the text formats: a
+    position in the text, skipping whitespace, and consuming tokens and
+    words.
+
+    Each parser builds on a Scanner with its own error type E, which only
+    has to implement SyntaxError; the parser's own methods (for its
+    keywords, names, numbers, etc.) go in an impl of Scanner<'t, E> for
+    that E, or of a struct holding the Scanner.
+*/
+
+use std::marker::PhantomData;
+use std::ops::Range;
+use std::str::FromStr;
+
+pub(crate) trait SyntaxError {
+    // The error at the span of the text (in bytes): the next character,
+    // or empty at the end of the text
+    fn at(span: Range<usize>, msg: String) -> Self;
+}
+
+pub(crate) struct Scanner<'t, E> {
+    pub text: &'t str,
+    // Offset of the next character (in bytes)
+    pub pos: usize,
+    ph_e: PhantomData<E>,
+}
+impl<'t, E: SyntaxError> Scanner<'t, E> {
+    pub fn new(text: &'t str) -> Self {
+        Self { text, pos: 0, ph_e: PhantomData }
+    }
+    // An error at the next character
+    pub fn error(&self, msg: &str) -> E {
+        let len =
+            self.text[self.pos..].chars().next().map_or(0, char::len_utf8);
+        E::at(self.pos..self.pos + len, msg.to_string())
+    }
+    pub fn skip_space(&mut self) {
+        let rest = &self.text[self.pos..];
+        self.pos += rest.len() - rest.trim_start().len();
+    }
+    // Consume the token if it comes next
+    pub fn eat(&mut self, token: &str) -> bool {
+        self.skip_space();
+        if self.text[self.pos..].starts_with(token) {
+            self.pos += token.len();
+            true
+        } else {
+            false
+        }
+    }
+    pub fn expect(&mut self, token: &str) -> Result<(), E> {
+        if self.eat(token) {
+            Ok(())
+        } else {
+            Err(self.error(&format!("expected {:?}", token)))
+        }
+    }
+    // Check that only whitespace is left, e.g. "expected end of formula"
+    pub fn expect_end(&mut self, what: &str) -> Result<(), E> {
+        self.skip_space();
+        if self.pos < self.text.len() {
+            return Err(self.error(&format!("expected end of {}", what)));
+        }
+        Ok(())
+    }
+    // A run of ASCII letters, digits, and underscores
+    pub fn word(&mut self) -> Option<&'t str> {
+        self.skip_space();
+        let rest = &self.text[self.pos..];
+        let len = rest
+            .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+            .unwrap_or(rest.len());
+        if len == 0 {
+            return None;
+        }
+        self.pos += len;
+        Some(&rest[..len])
+    }
+    // The next word parsed as a T; if it isn't one, nothing is consumed
+    pub fn parse_word<T: FromStr>(&mut self) -> Option<T> {
+        let start = self.pos;
+        match self.word().map(str::parse) {
+            Some(Ok(x)) => Some(x),
+            _ => {
+                self.pos = start;
+                None
+            }
+        }
+    }
+}
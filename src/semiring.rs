@@ -0,0 +1,252 @@
+/*
+    Semiring-valued QREs.
+
+    Many optimization-style queries over streams ("cheapest match",
+    "longest run", "most likely parse") compute outputs in a semiring other
+    than the usual numbers: the min-plus (tropical) semiring, where
+    "+" is min and "*" is +, or the max-plus semiring, where "+" is max and
+    "*" is +.
+
+    This module provides a Semiring trait, the min-plus and max-plus
+    semirings over i64, the boolean and counting semirings, and helpers for
+    building QREs whose outputs live in a semiring (for weighted regexes,
+    which sum over all the ways of matching, see weighted.rs):
+    - sr_plus: choice between two QREs, resolved with the semiring "+"
+      (e.g. the cheaper of two matches). Like union_op, this is not
+      restartable, so it can't be used e.g. as the second operand of concat.
+    - sr_times: sequential combination of two QREs' outputs with the
+      semiring "*" (e.g. total cost of two matches)
+    - sr_sum: running semiring sum over all matches of a QRE so far
+      (e.g. the cheapest match seen so far)
+*/
+
+use super::interface::Transducer;
+use super::qre::{aggregate, apply_op, concat, epsilon, union_op};
+
+pub trait Semiring: Clone {
+    // Identity for plus (and annihilator for times)
+    fn zero() -> Self;
+    // Identity for times
+    fn one() -> Self;
+    fn plus(&self, other: &Self) -> Self;
+    fn times(&self, other: &Self) -> Self;
+}
+
+/*
+    Min-plus (tropical) semiring: plus is min, times is addition.
+    Zero is +infinity, represented by i64::MAX; addition saturates, so
+    infinity is absorbing.
+*/
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub struct MinPlus(pub i64);
+
+impl MinPlus {
+    pub fn is_infinite(&self) -> bool {
+        self.0 == i64::MAX
+    }
+}
+impl Semiring for MinPlus {
+    fn zero() -> Self {
+        MinPlus(i64::MAX)
+    }
+    fn one() -> Self {
+        MinPlus(0)
+    }
+    fn plus(&self, other: &Self) -> Self {
+        MinPlus(self.0.min(other.0))
+    }
+    fn times(&self, other: &Self) -> Self {
+        if self.is_infinite() || other.is_infinite() {
+            Self::zero()
+        } else {
+            MinPlus(self.0.saturating_add(other.0))
+        }
+    }
+}
+
+/*
+    Max-plus semiring: plus is max, times is addition.
+    Zero is -infinity, represented by i64::MIN.
+*/
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub struct MaxPlus(pub i64);
+
+impl MaxPlus {
+    pub fn is_infinite(&self) -> bool {
+        self.0 == i64::MIN
+    }
+}
+impl Semiring for MaxPlus {
+    fn zero() -> Self {
+        MaxPlus(i64::MIN)
+    }
+    fn one() -> Self {
+        MaxPlus(0)
+    }
+    fn plus(&self, other: &Self) -> Self {
+        MaxPlus(self.0.max(other.0))
+    }
+    fn times(&self, other: &Self) -> Self {
+        if self.is_infinite() || other.is_infinite() {
+            Self::zero()
+        } else {
+            MaxPlus(self.0.saturating_add(other.0))
+        }
+    }
+}
+
+/*
+    Boolean semiring: plus is or, times is and.
+*/
+impl Semiring for bool {
+    fn zero() -> Self {
+        false
+    }
+    fn one() -> Self {
+        true
+    }
+    fn plus(&self, other: &Self) -> Self {
+        *self || *other
+    }
+    fn times(&self, other: &Self) -> Self {
+        *self && *other
+    }
+}
+
+/*
+    Counting semiring: the natural numbers, with the usual plus and times
+    (saturating at u64::MAX), e.g. for counting the ways a stream matches.
+*/
+impl Semiring for u64 {
+    fn zero() -> Self {
+        0
+    }
+    fn one() -> Self {
+        1
+    }
+    fn plus(&self, other: &Self) -> Self {
+        self.saturating_add(*other)
+    }
+    fn times(&self, other: &Self) -> Self {
+        self.saturating_mul(*other)
+    }
+}
+
+/* QRE helpers */
+
+pub fn sr_plus<I, D, S, M1, M2>(m1: M1, m2: M2) -> impl Transducer<I, D, S>
+where
+    I: Clone,
+    S: Semiring,
+    M1: Transducer<I, D, S>,
+    M2: Transducer<I, D, S>,
+{
+    union_op(m1, m2, |x: S, y: S| x.plus(&y))
+}
+
+pub fn sr_times<I, D, S, M1, M2>(m1: M1, m2: M2) -> impl Transducer<I, D, S>
+where
+    I: Clone,
+    S: Semiring,
+    M1: Transducer<I, D, S>,
+    M2: Transducer<I, D, S>,
+{
+    apply_op(m1, m2, |x: S, y: S| x.times(&y))
+}
+
+pub fn sr_sum<X, D, S, M>(m: M) -> impl Transducer<X, D, S>
+where
+    S: Semiring,
+    M: Transducer<X, D, S>,
+{
+    // Start the aggregate from the semiring zero
+    concat(
+        epsilon(|x| (x, S::zero())),
+        aggregate(m, |acc: S, y: S| acc.plus(&y)),
+    )
+}
+
+/* Unit tests */
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ext_value::Ext;
+    use crate::qre::{atom, stream_iden};
+
+    // Items are (tag, cost)
+    type Item = (char, i64);
+
+    fn cost_atom(tag: char) -> impl Transducer<MinPlus, Item, MinPlus> + Clone {
+        atom(
+            move |&(t, _c): &Item| t == tag,
+            |s: MinPlus, &(_t, c)| s.times(&MinPlus(c)),
+        )
+    }
+
+    #[test]
+    fn test_semiring_laws() {
+        let xs = [MinPlus(3), MinPlus(-2), MinPlus::zero(), MinPlus::one()];
+        for x in &xs {
+            assert_eq!(x.plus(&MinPlus::zero()), *x);
+            assert_eq!(x.times(&MinPlus::one()), *x);
+            assert_eq!(x.times(&MinPlus::zero()), MinPlus::zero());
+            for y in &xs {
+                assert_eq!(x.plus(y), y.plus(x));
+                for z in &xs {
+                    assert_eq!(
+                        x.times(&y.plus(z)),
+                        x.times(y).plus(&x.times(z))
+                    );
+                }
+            }
+        }
+        let ys = [MaxPlus(3), MaxPlus(-2), MaxPlus::zero(), MaxPlus::one()];
+        for y in &ys {
+            assert_eq!(y.plus(&MaxPlus::zero()), *y);
+            assert_eq!(y.times(&MaxPlus::one()), *y);
+            assert_eq!(y.times(&MaxPlus::zero()), MaxPlus::zero());
+        }
+        assert!(true.plus(&false));
+        assert!(!true.times(&false));
+    }
+
+    #[test]
+    fn test_sr_plus() {
+        // Cheaper of two ways of pricing an item ('c' and 'd' are only
+        // priced one way)
+        let m1 = atom(
+            |&(t, _c): &Item| t != 'c',
+            |s: MinPlus, &(_t, c)| s.times(&MinPlus(c)),
+        );
+        let m2 = atom(
+            |&(t, _c): &Item| t != 'd',
+            |s: MinPlus, &(_t, c)| s.times(&MinPlus(2 * c)),
+        );
+        let mut m = sr_plus(m1, m2);
+        assert_eq!(m.init_one(MinPlus::one()), Ext::None);
+        assert_eq!(m.update_val(('a', 5)), Ext::One(MinPlus(5)));
+        assert_eq!(m.init_one(MinPlus::one()), Ext::None);
+        assert_eq!(m.update_val(('a', -5)), Ext::One(MinPlus(-10)));
+        assert_eq!(m.init_one(MinPlus::one()), Ext::None);
+        assert_eq!(m.update_val(('c', 3)), Ext::One(MinPlus(6)));
+    }
+
+    #[test]
+    fn test_sr_times() {
+        let mut m = sr_times(cost_atom('a'), cost_atom('a'));
+        assert_eq!(m.init_one(MinPlus(1)), Ext::None);
+        assert_eq!(m.update_val(('a', 5)), Ext::One(MinPlus(12)));
+    }
+
+    #[test]
+    fn test_sr_sum() {
+        // Cheapest 'a' item seen so far
+        let mut m = sr_sum(concat(stream_iden(), cost_atom('a')));
+        assert_eq!(m.init_one(MinPlus::one()), Ext::None);
+        assert_eq!(m.update_val(('a', 5)), Ext::One(MinPlus(5)));
+        assert_eq!(m.update_val(('b', 1)), Ext::None);
+        assert_eq!(m.update_val(('a', 7)), Ext::One(MinPlus(5)));
+        assert_eq!(m.update_val(('a', 2)), Ext::One(MinPlus(2)));
+    }
+}
@@ -0,0 +1,297 @@
+/*
+    Thread-safe shared transducers.
+
+    The Transducer API takes &mut self, so a transducer has one owner at a
+    time. SharedTransducer is a handle to one logical transducer which can
+    be cloned and sent to other threads: producers feed it items (and
+    initial values), and readers poll its latest output and its metrics.
+    The transducer is behind an Arc<Mutex<..>>, so the steps of the
+    producers are serialized, in the order they take the lock; each step
+    returns its output to the producer which made it, and .output() gives
+    the output of the latest step to everyone else. .with() gives exclusive
+    access to the transducer itself (e.g. to take a snapshot).
+
+    If a step panics, the lock is poisoned, as the transducer may be left
+    in an inconsistent state, and later steps on any handle panic too.
+
+    The interleavings of the handles are model-checked with loom (see the
+    loom tests at the bottom), by building with --cfg loom:
+        RUSTFLAGS="--cfg loom" cargo test --release --lib shared::loom
+*/
+
+use super::ext_value::Ext;
+use super::interface::Transducer;
+#[cfg(loom)]
+use loom::sync::{Arc, Mutex, MutexGuard};
+use std::marker::PhantomData;
+#[cfg(not(loom))]
+use std::sync::{Arc, Mutex, MutexGuard};
+
+// Counts of the steps taken so far, with the size of the transducer
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct SharedMetrics {
+    pub n_inits: u64,
+    pub n_items: u64,
+    // Steps which produced a value (other than Ext::None)
+    pub n_outputs: u64,
+    pub n_bytes: usize,
+}
+
+struct Inner<M, O> {
+    m: M,
+    output: Ext<O>,
+    metrics: SharedMetrics,
+}
+impl<M, O> Inner<M, O> {
+    fn record(&mut self, output: &Ext<O>)
+    where
+        O: Clone,
+    {
+        if !output.is_none() {
+            self.metrics.n_outputs += 1;
+        }
+        self.output = output.clone();
+    }
+}
+
+pub struct SharedTransducer<I, D, O, M>
+where
+    M: Transducer<I, D, O>,
+{
+    inner: Arc<Mutex<Inner<M, O>>>,
+    ph_i: PhantomData<fn(I)>,
+    ph_d: PhantomData<fn(&D)>,
+}
+impl<I, D, O, M> SharedTransducer<I, D, O, M>
+where
+    O: Clone,
+    M: Transducer<I, D, O>,
+{
+    pub fn new(m: M) -> Self {
+        let inner = Inner { m, output: Ext::None, metrics: Default::default() };
+        Self {
+            inner: Arc::new(Mutex::new(inner)),
+            ph_i: PhantomData,
+            ph_d: PhantomData,
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Inner<M, O>> {
+        self.inner.lock().expect("a step on the shared transducer panicked")
+    }
+
+    pub fn init(&self, i: Ext<I>) -> Ext<O> {
+        let mut inner = self.lock();
+        let output = inner.m.init(i);
+        inner.metrics.n_inits += 1;
+        inner.record(&output);
+        output
+    }
+    pub fn init_one(&self, i: I) -> Ext<O> {
+        self.init(Ext::One(i))
+    }
+    pub fn update(&self, item: &D) -> Ext<O> {
+        let mut inner = self.lock();
+        let output = inner.m.update(item);
+        inner.metrics.n_items += 1;
+        inner.record(&output);
+        output
+    }
+    // Process the items as one step of the lock, so that the steps of other
+    // producers are not interleaved with them
+    pub fn update_batch(&self, items: &[D]) -> Vec<Ext<O>> {
+        let mut inner = self.lock();
+        let outputs: Vec<Ext<O>> =
+            items.iter().map(|item| inner.m.update(item)).collect();
+        inner.metrics.n_items += items.len() as u64;
+        for output in &outputs {
+            inner.record(output);
+        }
+        outputs
+    }
+    pub fn reset(&self) {
+        let mut inner = self.lock();
+        inner.m.reset();
+        inner.output = Ext::None;
+    }
+
+    // The output of the latest step
+    pub fn output(&self) -> Ext<O> {
+        self.lock().output.clone()
+    }
+    pub fn metrics(&self) -> SharedMetrics {
+        let inner = self.lock();
+        SharedMetrics { n_bytes: inner.m.n_bytes(), ..inner.metrics }
+    }
+    pub fn with<R, F: FnOnce(&mut M) -> R>(&self, f: F) -> R {
+        f(&mut self.lock().m)
+    }
+    // The number of handles to the transducer
+    pub fn n_handles(&self) -> usize {
+        Arc::strong_count(&self.inner)
+    }
+}
+impl<I, D, O, M> Clone for SharedTransducer<I, D, O, M>
+where
+    M: Transducer<I, D, O>,
+{
+    fn clone(&self) -> Self {
+        Self {
+            inner: Arc::clone(&self.inner),
+            ph_i: PhantomData,
+            ph_d: PhantomData,
+        }
+    }
+}
+
+/*
+    Unit Tests
+*/
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+    use crate::fixtures::sum;
+    use std::thread;
+
+    #[test]
+    fn test_producers() {
+        let shared = SharedTransducer::new(sum());
+        assert_eq!(shared.init_one(0), Ext::One(0));
+        let handles: Vec<_> = (0..4)
+            .map(|t| {
+                let shared = shared.clone();
+                thread::spawn(move || {
+                    for x in 0..100 {
+                        shared.update(&(t * 100 + x));
+                    }
+                    shared.update_batch(&[1, 2, 3]);
+                })
+            })
+            .collect();
+        assert!(shared.n_handles() > 1);
+        for h in handles {
+            h.join().unwrap();
+        }
+        assert_eq!(shared.n_handles(), 1);
+        assert_eq!(shared.output(), Ext::One((0..400).sum::<i64>() + 24));
+        let metrics = shared.metrics();
+        assert_eq!(metrics.n_inits, 1);
+        assert_eq!(metrics.n_items, 412);
+        assert_eq!(metrics.n_outputs, 413);
+        assert!(metrics.n_bytes > 0);
+    }
+
+    #[test]
+    fn test_readers() {
+        let shared = SharedTransducer::new(sum());
+        shared.init_one(0);
+        let reader = {
+            let shared = shared.clone();
+            thread::spawn(move || {
+                // The running sum only grows
+                let mut last = 0;
+                while last < 5050 {
+                    if let Ext::One(s) = shared.output() {
+                        assert!(s >= last);
+                        last = s;
+                    }
+                }
+            })
+        };
+        for x in 1..=100 {
+            shared.update(&x);
+        }
+        reader.join().unwrap();
+        assert_eq!(shared.with(|m| m.update(&1)), Ext::One(5051));
+        // (Steps through .with() are not recorded)
+        assert_eq!(shared.output(), Ext::One(5050));
+        shared.reset();
+        assert_eq!(shared.output(), Ext::None);
+        assert_eq!(shared.update(&1), Ext::None);
+    }
+}
+
+/*
+    Loom Tests
+
+    These check every interleaving of a few handles, so each model is kept
+    to two or three threads and a handful of steps. The stages of
+    pipeline.rs communicate over bounded std channels, which loom doesn't
+    model, and a Checkpointer (checkpoint.rs) is owned by a single thread,
+    so neither is checked here.
+*/
+
+#[cfg(all(test, loom))]
+mod loom_tests {
+    use super::*;
+    use crate::fixtures::sum;
+    use loom::thread;
+
+    #[test]
+    fn loom_producers() {
+        loom::model(|| {
+            let shared = SharedTransducer::new(sum());
+            shared.init_one(0);
+            let handles: Vec<_> = [1, 10]
+                .iter()
+                .map(|&x| {
+                    let shared = shared.clone();
+                    thread::spawn(move || shared.update(&x))
+                })
+                .collect();
+            let outs: Vec<Ext<i64>> =
+                handles.into_iter().map(|h| h.join().unwrap()).collect();
+            // The steps are serialized: one producer sees its own item
+            // alone, the other sees the total
+            assert!(
+                outs == [Ext::One(1), Ext::One(11)]
+                    || outs == [Ext::One(11), Ext::One(10)]
+            );
+            assert_eq!(shared.output(), Ext::One(11));
+            let metrics = shared.metrics();
+            assert_eq!((metrics.n_items, metrics.n_outputs), (2, 3));
+        });
+    }
+
+    #[test]
+    fn loom_batch_atomic() {
+        loom::model(|| {
+            let shared = SharedTransducer::new(sum());
+            shared.init_one(0);
+            let producer = {
+                let shared = shared.clone();
+                thread::spawn(move || shared.update_batch(&[1, 2, 3]))
+            };
+            // A reader never sees the middle of a batch, and the metrics
+            // agree with the output
+            let out = shared.output();
+            let metrics = shared.metrics();
+            assert!(out == Ext::One(0) || out == Ext::One(6));
+            assert!(metrics.n_items == 0 || metrics.n_items == 3);
+            assert_eq!(metrics.n_outputs, metrics.n_items + 1);
+            let outs = producer.join().unwrap();
+            assert_eq!(outs, [Ext::One(1), Ext::One(3), Ext::One(6)]);
+            assert_eq!(shared.output(), Ext::One(6));
+        });
+    }
+
+    #[test]
+    fn loom_reset() {
+        loom::model(|| {
+            let shared = SharedTransducer::new(sum());
+            shared.init_one(0);
+            let producer = {
+                let shared = shared.clone();
+                thread::spawn(move || shared.update(&5))
+            };
+            shared.reset();
+            let out = producer.join().unwrap();
+            // Either the update came first, or it was after the reset and
+            // so had no initial value
+            assert!(out == Ext::One(5) || out == Ext::None);
+            assert_eq!(shared.output(), Ext::None);
+            assert_eq!(shared.n_handles(), 1);
+        });
+    }
+}
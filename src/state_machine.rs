@@ -4,6 +4,7 @@
     Generally the QRE constructs should be more convenient and high-level,
     but this can be used if you want to manually write
     the states and transitions yourself.
+    (The state_machine! macro declares them as a table; see below.)
 
     For simplicity and safety of the implementation, states in
     the machine are limited to be of all the same type Q. In order to
@@ -11,6 +12,9 @@
     to an enum, or an unsafe Union:
     https://doc.rust-lang.org/reference/items/unions.html
     or even an unsafe pointer.
+    The enum approach is supported by the state_enum! macro, which declares
+    the enum, together with typed versions of the methods adding
+    transitions (see StateType below).
     I originally wanted to support multiple state types,
     but there is no easy way to deal with the complexity of types.
     Either the implementation would itself be inherently unsafe, or it
@@ -19,13 +23,30 @@
     the transitions, but then it is challenging because the Transitions need
     to also keep reference-counted pointers into the states to get/update
     their values). Overall, fixing Q is cleaner design.
+    (An early prototype fixed Q to be i32 instead; its design, with buffers
+    for the previous and current state values, is the one used here. For
+    a machine specialized to particular transitions, without dynamic calls,
+    see .add_static_transition().)
 */
 
+use super::errors::BuildError;
 use super::ext_value::{self, Ext};
-use super::interface::Transducer;
+use super::guard::Guard;
+use super::interface::{RInput, Transducer};
+use super::rate::{Rate, RateNfa};
+#[cfg(feature = "arena")]
+use bumpalo::Bump;
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+use smallvec::SmallVec;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
 use std::fmt::{self, Debug};
 use std::marker::PhantomData;
+use std::mem;
 use std::ops::{Deref, DerefMut, Index, IndexMut};
+use std::rc::Rc;
 
 /*
     States are represented by an Id (index into the state vector of the
@@ -87,23 +108,88 @@ fn test_stateid_index() {
     // assert_eq!(v[1], 2);
 }
 
+/*
+    In the public API, states are referred to either by index (usize) or by
+    a label registered with .add_state_named() or .name_state().
+    The StateRef trait covers both.
+*/
+
+pub trait StateRef {
+    // Look up the state index, given the label (if any) of each state
+    fn to_state_id(
+        &self,
+        names: &[Option<String>],
+    ) -> Result<usize, BuildError>;
+}
+impl StateRef for usize {
+    fn to_state_id(
+        &self,
+        _names: &[Option<String>],
+    ) -> Result<usize, BuildError> {
+        Ok(*self)
+    }
+}
+impl StateRef for &str {
+    fn to_state_id(
+        &self,
+        names: &[Option<String>],
+    ) -> Result<usize, BuildError> {
+        names
+            .iter()
+            .position(|name| name.as_deref() == Some(*self))
+            .ok_or_else(|| BuildError::NoSuchName { name: self.to_string() })
+    }
+}
+impl StateRef for String {
+    fn to_state_id(
+        &self,
+        names: &[Option<String>],
+    ) -> Result<usize, BuildError> {
+        self.as_str().to_state_id(names)
+    }
+}
+
 /*
     Transitions are defined by a guard which says when they are active, and
     an action which says the function applied to the source states to
     give a new result for the target state.
     Transitions implement the Transition trait, providing an interface of
     their functionality, and will be stored in the data transducer as
-    dynamic Box<dyn Transition> objects.
+    dynamic Rc<dyn Transition> objects.
     This is because they are functions so do not share a common type.
+    (Rc rather than Box so that transformations on the machine, such as
+    epsilon elimination, can reuse a transition in several places.)
+
+    The Transition itself only knows how many source states it has; which
+    states these are (and the target state) is stored alongside it in an
+    Edge. This way the same Transition can be used between different states.
+
+    Transitions with no source states (Trans0) set the target state to a
+    value computed only from the item (or, for epsilons, a constant).
+
+    The Transition trait is public so that update transitions can
+    alternatively be given statically: e.g. as a user-declared enum with one
+    variant per kind of transition, used as the type parameter U of
+    DataTransducer (see .add_static_transition()). This avoids a dynamic
+    call (and a closure call) per transition per item, and allows inlining.
 */
 
+struct Trans0<D, Q, G, F>
+where
+    G: Fn(&D) -> bool,
+    F: Fn(&D) -> Q,
+{
+    guard: G,
+    action: F,
+    ph_q: PhantomData<Q>,
+    ph_d: PhantomData<D>,
+}
+
 struct Trans1<D, Q, G, F>
 where
     G: Fn(&D) -> bool,
     F: Fn(&D, &Q) -> Q,
 {
-    source: StateId,
-    target: StateId,
     guard: G,
     action: F,
     ph_q: PhantomData<Q>,
@@ -115,68 +201,123 @@ where
     G: Fn(&D) -> bool,
     F: Fn(&D, &Q, &Q) -> Q,
 {
-    source1: StateId,
-    source2: StateId,
-    target: StateId,
     guard: G,
     action: F,
     ph_q: PhantomData<Q>,
     ph_d: PhantomData<D>,
 }
 
-trait Transition<D, Q> {
-    fn source_ids(&self) -> Vec<StateId>;
-    fn target_id(&self) -> StateId;
+pub trait Transition<D, Q> {
+    fn arity(&self) -> usize;
     fn is_active(&self, item: &D) -> bool;
-    fn eval(&self, item: &D, states: &StateList<Ext<Q>>) -> Ext<Q>;
-
-    /* Derived functionality */
-    fn eval_precond(&self, states: &StateList<Ext<Q>>) -> bool {
-        self.source_ids().iter().all(|&id| states.in_range(id))
+    // PRECONDITION: args.len() == self.arity()
+    // Should be None if all the args are None (as transitions only fire
+    // out of states with a value; see .update_chunk())
+    fn apply(&self, item: &D, args: &[Ext<&Q>]) -> Ext<Q>;
+    // Whether the action is known to be the identity (copying its one
+    // source state). Used by minimization.
+    fn is_iden(&self) -> bool {
+        false
     }
-    fn all_ids(&self) -> Vec<StateId> {
-        let mut result = self.source_ids();
-        result.push(self.target_id());
-        result
+    // Values emitted when the transition fires (as in a Mealy machine),
+    // given the value it produced for the target state; see .add_output()
+    fn emit(&self, _item: &D, _value: &Ext<Q>, _out: &mut Vec<Ext<Q>>) {}
+    // Whether .emit() may produce anything
+    fn has_output(&self) -> bool {
+        false
+    }
+    // A further condition for the transition to fire, which may read the
+    // values of all the states (indexed by state) before the item; checked
+    // after .is_active(). See .add_state_guard().
+    fn is_active_in(&self, _item: &D, _states: &[Ext<Q>]) -> bool {
+        true
+    }
+    // The guard of the transition as a symbolic guard, if it was given as
+    // one (see .add_sym_transition1())
+    fn symbolic_guard(&self) -> Option<Guard<'_, D>> {
+        None
+    }
+    // A name for the action, if it was given one (see .set_action_name())
+    fn action_name(&self) -> Option<&str> {
+        None
     }
 }
 
-// Lightweight Debug implementation
-// This format string is rather incomplete, since function closures
-// do not implement Debug.
-// Note: the + '_ is important because otherwise trait objects default to
-// 'static lifetime.
-// https://stackoverflow.com/questions/63986183/format-requires-static-lifetime
-impl<D, Q> Debug for dyn Transition<D, Q> + '_ {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        f.write_str("[")?;
-        for &id in &self.source_ids() {
-            f.write_fmt(format_args!("{} ", id.0))?;
-        }
-        f.write_fmt(format_args!("-> {}]", self.target_id().0))
+// A reference to a transition is a transition (e.g. for transitions
+// allocated in an arena, see ArenaTransducer)
+impl<D, Q, T> Transition<D, Q> for &T
+where
+    T: ?Sized + Transition<D, Q>,
+{
+    fn arity(&self) -> usize {
+        (**self).arity()
+    }
+    fn is_active(&self, item: &D) -> bool {
+        (**self).is_active(item)
+    }
+    fn apply(&self, item: &D, args: &[Ext<&Q>]) -> Ext<Q> {
+        (**self).apply(item, args)
+    }
+    fn is_iden(&self) -> bool {
+        (**self).is_iden()
+    }
+    fn emit(&self, item: &D, value: &Ext<Q>, out: &mut Vec<Ext<Q>>) {
+        (**self).emit(item, value, out)
+    }
+    fn has_output(&self) -> bool {
+        (**self).has_output()
     }
+    fn is_active_in(&self, item: &D, states: &[Ext<Q>]) -> bool {
+        (**self).is_active_in(item, states)
+    }
+    fn symbolic_guard(&self) -> Option<Guard<'_, D>> {
+        (**self).symbolic_guard()
+    }
+    fn action_name(&self) -> Option<&str> {
+        (**self).action_name()
+    }
+}
+
+// Identity transitions (see .add_iden()) are a separate type so that they
+// can be recognized as such
+struct Iden<D, Q, G>
+where
+    G: Fn(&D) -> bool,
+{
+    guard: G,
+    ph_q: PhantomData<Q>,
+    ph_d: PhantomData<D>,
 }
 
+impl<D, Q, G, F> Transition<D, Q> for Trans0<D, Q, G, F>
+where
+    G: Fn(&D) -> bool,
+    F: Fn(&D) -> Q,
+{
+    fn arity(&self) -> usize {
+        0
+    }
+    fn is_active(&self, item: &D) -> bool {
+        (self.guard)(item)
+    }
+    fn apply(&self, item: &D, _args: &[Ext<&Q>]) -> Ext<Q> {
+        Ext::One((self.action)(item))
+    }
+}
 impl<D, Q, G, F> Transition<D, Q> for Trans1<D, Q, G, F>
 where
     G: Fn(&D) -> bool,
     F: Fn(&D, &Q) -> Q,
 {
-    fn source_ids(&self) -> Vec<StateId> {
-        vec![self.source]
-    }
-    fn target_id(&self) -> StateId {
-        self.target
+    fn arity(&self) -> usize {
+        1
     }
     fn is_active(&self, item: &D) -> bool {
         (self.guard)(item)
     }
-    fn eval(&self, item: &D, states: &StateList<Ext<Q>>) -> Ext<Q> {
-        debug_assert!(self.eval_precond(states));
-        ext_value::apply1(
-            |q| (self.action)(item, q),
-            states[self.source].as_ref(),
-        )
+    fn apply(&self, item: &D, args: &[Ext<&Q>]) -> Ext<Q> {
+        debug_assert_eq!(args.len(), 1);
+        ext_value::apply1(|q| (self.action)(item, q), args[0])
     }
 }
 impl<D, Q, G, F> Transition<D, Q> for Trans2<D, Q, G, F>
@@ -184,465 +325,6255 @@ where
     G: Fn(&D) -> bool,
     F: Fn(&D, &Q, &Q) -> Q,
 {
-    fn source_ids(&self) -> Vec<StateId> {
-        vec![self.source1, self.source2]
-    }
-    fn target_id(&self) -> StateId {
-        self.target
+    fn arity(&self) -> usize {
+        2
     }
     fn is_active(&self, item: &D) -> bool {
         (self.guard)(item)
     }
-    fn eval(&self, item: &D, states: &StateList<Ext<Q>>) -> Ext<Q> {
-        debug_assert!(self.eval_precond(states));
+    fn apply(&self, item: &D, args: &[Ext<&Q>]) -> Ext<Q> {
+        debug_assert_eq!(args.len(), 2);
         ext_value::apply2(
             |q1, q2| (self.action)(item, q1, q2),
-            states[self.source1].as_ref(),
-            states[self.source2].as_ref(),
+            args[0],
+            args[1],
         )
     }
 }
 
-/*
-    More transition functionality.
-
-    Exactly the same as StateId and StateList, TransId and TransList are type
-    wrappers over usize and Vec<T> where the latter can be indexed by the
-    former. The most important thing is that TransList can't be indexed by
-    StateId and StateList can't be indexed by TransId. In fact, I already caught
-    a bug due to such a mistake as I was introducing this discipline.
-*/
-
-#[derive(Copy, Clone, Debug)]
-struct TransId(usize);
-
-#[derive(Clone, Debug)]
-struct TransList<T>(Vec<T>);
-impl<T> Deref for TransList<T> {
-    type Target = Vec<T>;
-    fn deref(&self) -> &Vec<T> {
-        &self.0
+impl<D, Q, G> Transition<D, Q> for Iden<D, Q, G>
+where
+    Q: Clone,
+    G: Fn(&D) -> bool,
+{
+    fn arity(&self) -> usize {
+        1
     }
-}
-impl<T> DerefMut for TransList<T> {
-    fn deref_mut(&mut self) -> &mut Vec<T> {
-        &mut self.0
+    fn is_active(&self, item: &D) -> bool {
+        (self.guard)(item)
     }
-}
-impl<T> Index<TransId> for TransList<T> {
-    type Output = T;
-    fn index(&self, id: TransId) -> &Self::Output {
-        self.0.index(id.0)
+    fn apply(&self, _item: &D, args: &[Ext<&Q>]) -> Ext<Q> {
+        debug_assert_eq!(args.len(), 1);
+        ext_value::apply1(|q: &Q| q.clone(), args[0])
     }
-}
-impl<T> IndexMut<TransId> for TransList<T> {
-    fn index_mut(&mut self, id: TransId) -> &mut Self::Output {
-        self.0.index_mut(id.0)
+    fn is_iden(&self) -> bool {
+        true
     }
 }
 
-// Guard function for epsilon transitions -- should never be called, so panics
-fn epsilon_guard<D>(_item: &D) -> bool {
-    panic!("Called guard for epsilon transition!");
-}
-
 /*
-    The main DataTransducer state machine.
-    Implements the Transducer interface.
-
-    For now, DataTransducer does not implement Clone, due to the transitions
-    being dynamic Trait objects.
+    The first transition of a timer (see .add_timer()). Its sources are the
+    state being timed and the same state delayed by one item; it fires when
+    the first has a value but the second does not, i.e. when the state has
+    just become set.
 */
 
-const ISTATE_ID: StateId = StateId(0);
-const FSTATE_ID: StateId = StateId(1);
-
-pub struct DataTransducer<'a, D, Q>
+struct Arm<D, Q, F>
 where
-    Q: 'a + Clone,
-    D: 'a,
+    F: Fn(&D, &Q) -> Q,
 {
-    // Initial state: states[0]
-    // Final state: states[1]
-    states: StateList<Ext<Q>>,
-    // Transitions, divided into those executed on update from old to new states
-    // and "epsilon transitions" which define a least fixed point on init and
-    // after every update
-    updates: TransList<Box<dyn Transition<D, Q> + 'a>>,
-    epsilons: TransList<Box<dyn Transition<(), Q> + 'a>>,
-    // Store for each state which epsilon-transitions go out from this state
-    // (needed for the least fixed point calculation)
-    eps_out: StateList<Vec<TransId>>,
-    // Dummy marker for D
+    action: F,
+    ph_q: PhantomData<Q>,
     ph_d: PhantomData<D>,
 }
 
-impl<D, Q> Default for DataTransducer<'_, D, Q>
+impl<D, Q, F> Transition<D, Q> for Arm<D, Q, F>
 where
-    Q: Clone,
+    F: Fn(&D, &Q) -> Q,
 {
-    fn default() -> Self {
-        let states = StateList(vec![Ext::None, Ext::None]);
-        let updates = TransList(vec![]);
-        let epsilons = TransList(vec![]);
-        let eps_out = StateList(vec![vec![], vec![]]);
-        let ph_d = PhantomData;
-        let result = Self { states, updates, epsilons, eps_out, ph_d };
-        debug_assert!(result.invariant());
-        result
+    fn arity(&self) -> usize {
+        2
+    }
+    fn is_active(&self, _item: &D) -> bool {
+        true
+    }
+    fn apply(&self, item: &D, args: &[Ext<&Q>]) -> Ext<Q> {
+        debug_assert_eq!(args.len(), 2);
+        if !args[1].is_none() {
+            return Ext::None;
+        }
+        ext_value::apply1(|q| (self.action)(item, q), args[0])
     }
 }
 
-impl<D, Q> Debug for DataTransducer<'_, D, Q>
-where
-    Q: Clone + Debug,
-    D: Debug,
-{
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        f.debug_struct("DataTransducer")
-            .field("states", &self.states)
-            .field("updates", &self.updates)
-            .field("epsilons", &self.epsilons)
-            .field("eps_out", &self.eps_out)
-            .finish()
-    }
+/*
+    Composition of a transition with a one-source epsilon transition
+    (used for epsilon elimination)
+*/
+
+struct Compose<'a, D, Q> {
+    first: Rc<dyn Transition<D, Q> + 'a>,
+    then: Rc<dyn Transition<(), Q> + 'a>,
 }
 
-impl<'a, D, Q> DataTransducer<'a, D, Q>
-where
-    Q: Clone,
-{
-    /* Initialization (forming the states and transitions) */
-    pub fn new() -> Self {
-        Default::default()
+impl<D, Q> Transition<D, Q> for Compose<'_, D, Q> {
+    fn arity(&self) -> usize {
+        self.first.arity()
     }
-    pub fn add_state(&mut self) {
-        debug_assert!(self.states.len() >= 2);
-        self.states.push(Ext::None);
-        self.eps_out.push(Vec::new());
-        debug_assert!(self.invariant());
+    fn is_active(&self, item: &D) -> bool {
+        self.first.is_active(item)
     }
-    // Set the number of states directly
-    // (instead of repeatedly calling .add_state())
-    pub fn set_nstates(&mut self, n: usize) {
-        assert!(self.states.len() <= n);
-        while self.states.len() < n {
-            self.add_state();
-        }
+    fn is_active_in(&self, item: &D, states: &[Ext<Q>]) -> bool {
+        self.first.is_active_in(item, states)
     }
-    // Add an update transition with one source state
-    pub fn add_transition1<G, F>(
-        &mut self,
-        source: usize,
-        target: usize,
-        guard: G,
-        action: F,
-    ) where
-        G: 'a + Fn(&D) -> bool,
-        F: 'a + Fn(&D, &Q) -> Q,
-    {
-        self.add_transition_core(Trans1 {
-            source: StateId(source),
-            target: StateId(target),
-            guard,
-            action,
-            ph_d: PhantomData,
-            ph_q: PhantomData,
-        });
+    fn symbolic_guard(&self) -> Option<Guard<'_, D>> {
+        self.first.symbolic_guard()
     }
-    // Add an update transition with two source states
-    pub fn add_transition2<G, F>(
-        &mut self,
-        source1: usize,
-        source2: usize,
-        target: usize,
-        guard: G,
-        action: F,
-    ) where
-        G: 'a + Fn(&D) -> bool,
-        F: 'a + Fn(&D, &Q, &Q) -> Q,
-    {
-        self.add_transition_core(Trans2 {
-            source1: StateId(source1),
-            source2: StateId(source2),
-            target: StateId(target),
-            guard,
-            action,
-            ph_d: PhantomData,
-            ph_q: PhantomData,
-        });
+    fn apply(&self, item: &D, args: &[Ext<&Q>]) -> Ext<Q> {
+        debug_assert_eq!(self.then.arity(), 1);
+        let mid = self.first.apply(item, args);
+        self.then.apply(&(), &[mid.as_ref()])
     }
-    // Add an "identity transition" which preserves a particular state from one
-    // timestep to the next. (This is common enough that it's worth exposing
-    // specifically in the API.)
-    pub fn add_iden<G>(&mut self, source: usize, target: usize, guard: G)
-    where
-        G: 'a + Fn(&D) -> bool,
-    {
-        self.add_transition1(source, target, guard, |_, q| q.clone())
+    // No outputs: the first transition still fires on its own
+}
+
+/*
+    An update transition with an output action (see .add_output()).
+
+    The output is computed from the item and the value the transition
+    produced. The wrapped transition may itself have outputs; these are
+    emitted first.
+*/
+
+type OutputAction<'a, D, Q> = Box<dyn Fn(&D, &Q) -> Q + 'a>;
+
+struct Output<'a, D, Q> {
+    inner: Rc<dyn Transition<D, Q> + 'a>,
+    out: OutputAction<'a, D, Q>,
+}
+
+impl<D, Q> Transition<D, Q> for Output<'_, D, Q> {
+    fn arity(&self) -> usize {
+        self.inner.arity()
     }
-    // Add an epsilon transition with one source state
-    pub fn add_epsilon1<F>(&mut self, source: usize, target: usize, action: F)
-    where
-        F: 'a + Fn(&Q) -> Q,
-    {
-        self.add_epsilon_core(Trans1 {
-            source: StateId(source),
-            target: StateId(target),
-            guard: epsilon_guard,
-            action: move |_, q| action(q),
-            ph_d: PhantomData,
-            ph_q: PhantomData,
-        });
+    fn is_active(&self, item: &D) -> bool {
+        self.inner.is_active(item)
     }
-    // Add an update transition with two source states
-    pub fn add_epsilon2<F>(
-        &mut self,
-        source1: usize,
-        source2: usize,
-        target: usize,
-        action: F,
-    ) where
-        F: 'a + Fn(&Q, &Q) -> Q,
-    {
-        self.add_epsilon_core(Trans2 {
-            source1: StateId(source1),
-            source2: StateId(source2),
-            target: StateId(target),
-            guard: epsilon_guard,
-            action: move |_, q1, q2| action(q1, q2),
-            ph_d: PhantomData,
-            ph_q: PhantomData,
-        });
+    fn apply(&self, item: &D, args: &[Ext<&Q>]) -> Ext<Q> {
+        self.inner.apply(item, args)
     }
-
-    /* Utility / conveniences */
-    fn add_to_istate(&mut self, i: Ext<Q>) {
-        self.states[ISTATE_ID] += i
+    fn emit(&self, item: &D, value: &Ext<Q>, out: &mut Vec<Ext<Q>>) {
+        self.inner.emit(item, value, out);
+        out.push(ext_value::apply1(|q| (self.out)(item, q), value.as_ref()));
     }
-    fn get_fstate(&self) -> Ext<Q> {
-        self.states[FSTATE_ID].clone()
+    fn has_output(&self) -> bool {
+        true
     }
-    fn eval_epsilon(&self, tid: TransId) -> Ext<Q> {
-        self.epsilons[tid].eval(&(), &self.states)
+    fn is_active_in(&self, item: &D, states: &[Ext<Q>]) -> bool {
+        self.inner.is_active_in(item, states)
     }
-    fn add_transition_core<Tr>(&mut self, tr: Tr)
-    where
-        Tr: 'a + Transition<D, Q>,
-    {
-        assert!(self.trans_precond(&tr));
-        self.updates.push(Box::new(tr));
-        debug_assert!(self.invariant());
+    fn symbolic_guard(&self) -> Option<Guard<'_, D>> {
+        self.inner.symbolic_guard()
     }
-    fn add_epsilon_core<Tr>(&mut self, tr: Tr)
-    where
-        Tr: 'a + Transition<(), Q>,
-    {
-        debug_assert!(self.trans_precond(&tr));
-        let new_tr_id = TransId(self.epsilons.len());
-        for source_id in tr.source_ids() {
-            self.eps_out[source_id].push(new_tr_id);
-        }
-        self.epsilons.push(Box::new(tr));
-        debug_assert!(self.invariant());
+    fn action_name(&self) -> Option<&str> {
+        self.inner.action_name()
     }
+}
 
-    /* Invariant checks and preconditions */
-    fn invariant(&self) -> bool {
-        // Returns true for convenience of debug_assert!(self.invariant())
-        debug_assert!(self.states.len() >= 2);
-        debug_assert_eq!(self.states.len(), self.eps_out.len());
-        debug_assert_eq!(
-            self.eps_out.iter().map(|ids| ids.len()).sum::<usize>(),
-            self.epsilons.iter().map(|eps| eps.source_ids().len()).sum(),
-        );
-        for (state_id, eps_ids) in self.eps_out.enumerate() {
-            for &id in eps_ids {
-                debug_assert!(self.epsilons[id]
-                    .source_ids()
-                    .iter()
-                    .any(|&s| { s == state_id }));
-            }
+/*
+    An update transition with a guard on the state values (see
+    .add_state_guard()).
+*/
+
+type StateGuardFn<'a, D, Q> = Box<dyn Fn(&D, &[Ext<Q>]) -> bool + 'a>;
+
+struct StateGuard<'a, D, Q> {
+    inner: Rc<dyn Transition<D, Q> + 'a>,
+    guard: StateGuardFn<'a, D, Q>,
+}
+
+impl<D, Q> Transition<D, Q> for StateGuard<'_, D, Q> {
+    fn arity(&self) -> usize {
+        self.inner.arity()
+    }
+    fn is_active(&self, item: &D) -> bool {
+        self.inner.is_active(item)
+    }
+    fn apply(&self, item: &D, args: &[Ext<&Q>]) -> Ext<Q> {
+        self.inner.apply(item, args)
+    }
+    fn emit(&self, item: &D, value: &Ext<Q>, out: &mut Vec<Ext<Q>>) {
+        self.inner.emit(item, value, out)
+    }
+    fn has_output(&self) -> bool {
+        self.inner.has_output()
+    }
+    fn is_active_in(&self, item: &D, states: &[Ext<Q>]) -> bool {
+        self.inner.is_active_in(item, states) && (self.guard)(item, states)
+    }
+    fn symbolic_guard(&self) -> Option<Guard<'_, D>> {
+        self.inner.symbolic_guard()
+    }
+    fn action_name(&self) -> Option<&str> {
+        self.inner.action_name()
+    }
+}
+
+/*
+    An update transition whose guard was given symbolically (see
+    .add_sym_transition1()). The wrapped transition evaluates the same
+    guard; this just records it for analysis.
+*/
+
+struct Symbolic<'a, D, Q> {
+    inner: Rc<dyn Transition<D, Q> + 'a>,
+    guard: Guard<'a, D>,
+}
+
+impl<D, Q> Transition<D, Q> for Symbolic<'_, D, Q> {
+    fn arity(&self) -> usize {
+        self.inner.arity()
+    }
+    fn is_active(&self, item: &D) -> bool {
+        self.inner.is_active(item)
+    }
+    fn apply(&self, item: &D, args: &[Ext<&Q>]) -> Ext<Q> {
+        self.inner.apply(item, args)
+    }
+    fn is_iden(&self) -> bool {
+        self.inner.is_iden()
+    }
+    fn emit(&self, item: &D, value: &Ext<Q>, out: &mut Vec<Ext<Q>>) {
+        self.inner.emit(item, value, out)
+    }
+    fn has_output(&self) -> bool {
+        self.inner.has_output()
+    }
+    fn is_active_in(&self, item: &D, states: &[Ext<Q>]) -> bool {
+        self.inner.is_active_in(item, states)
+    }
+    fn symbolic_guard(&self) -> Option<Guard<'_, D>> {
+        Some(self.guard.clone())
+    }
+    fn action_name(&self) -> Option<&str> {
+        self.inner.action_name()
+    }
+}
+
+/*
+    A transition (update or epsilon) whose action was given a name (see
+    .set_action_name()), e.g. the name it was registered under in a
+    text_format::FnTable.
+*/
+
+struct Named<'a, D, Q> {
+    inner: Rc<dyn Transition<D, Q> + 'a>,
+    name: String,
+}
+
+impl<D, Q> Transition<D, Q> for Named<'_, D, Q> {
+    fn arity(&self) -> usize {
+        self.inner.arity()
+    }
+    fn is_active(&self, item: &D) -> bool {
+        self.inner.is_active(item)
+    }
+    fn apply(&self, item: &D, args: &[Ext<&Q>]) -> Ext<Q> {
+        self.inner.apply(item, args)
+    }
+    fn is_iden(&self) -> bool {
+        self.inner.is_iden()
+    }
+    fn emit(&self, item: &D, value: &Ext<Q>, out: &mut Vec<Ext<Q>>) {
+        self.inner.emit(item, value, out)
+    }
+    fn has_output(&self) -> bool {
+        self.inner.has_output()
+    }
+    fn is_active_in(&self, item: &D, states: &[Ext<Q>]) -> bool {
+        self.inner.is_active_in(item, states)
+    }
+    fn symbolic_guard(&self) -> Option<Guard<'_, D>> {
+        self.inner.symbolic_guard()
+    }
+    fn action_name(&self) -> Option<&str> {
+        Some(&self.name)
+    }
+}
+
+/*
+    Edges: a transition together with its source and target states
+
+    Transitions have at most two sources, so these are stored inline
+    (Sources) rather than in a separately allocated Vec.
+*/
+
+pub(crate) const MAX_ARITY: usize = 2;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+struct Sources {
+    ids: [StateId; MAX_ARITY],
+    len: usize,
+}
+impl Sources {
+    fn new(ids: &[StateId]) -> Self {
+        assert!(ids.len() <= MAX_ARITY, "too many source states");
+        let mut result = Self { ids: [StateId(0); MAX_ARITY], len: ids.len() };
+        result.ids[..ids.len()].copy_from_slice(ids);
+        result
+    }
+}
+impl Deref for Sources {
+    type Target = [StateId];
+    fn deref(&self) -> &[StateId] {
+        &self.ids[..self.len]
+    }
+}
+impl DerefMut for Sources {
+    fn deref_mut(&mut self) -> &mut [StateId] {
+        &mut self.ids[..self.len]
+    }
+}
+
+#[test]
+fn test_sources() {
+    let s = Sources::new(&[StateId(3), StateId(1)]);
+    assert_eq!(*s, [StateId(3), StateId(1)]);
+    assert!(Sources::new(&[]).is_empty());
+    assert_eq!(Sources::new(&[StateId(2)]), Sources::new(&[StateId(2)]));
+}
+
+struct Edge<Tr: ?Sized> {
+    sources: Sources,
+    target: StateId,
+    tr: Rc<Tr>,
+    // Priority of an update transition (see .set_priority()); always 0 for
+    // epsilon transitions
+    priority: i32,
+}
+
+impl<Tr: ?Sized> Clone for Edge<Tr> {
+    fn clone(&self) -> Self {
+        Self {
+            sources: self.sources,
+            target: self.target,
+            tr: self.tr.clone(),
+            priority: self.priority,
         }
-        true
     }
-    fn trans_precond<I, Tr>(&self, tr: &Tr) -> bool
+}
+
+impl<Tr: ?Sized> Edge<Tr> {
+    fn source_ids(&self) -> &[StateId] {
+        &self.sources
+    }
+    fn target_id(&self) -> StateId {
+        self.target
+    }
+    fn is_active<I, Q>(&self, item: &I) -> bool
     where
         Tr: Transition<I, Q>,
     {
-        // PRECONDITION for add_transition() and add_epsilon():
-        // transition sources and targets must
-        // already have been added to the machine.
-        tr.all_ids().iter().all(|&id| self.states.in_range(id))
+        self.tr.is_active(item)
     }
-
-    /* Streaming Algorithm */
-    fn eval_epsilons(&mut self) {
-        // The main streaming algorithm for updating the data transducer
-        // following least-fixed-point semantics, and implemented using
-        // a transition worklist.
-        // Note on efficiency: it is slightly more efficient to also
-        // keep a count of how many input states are Ext::None for each
-        // transition, and only add a transition to the worklist when this
-        // number increases. But this only really matters for transitions with
-        // more than one or two source states.
-        let n_epsilons = self.epsilons.len();
-        let mut trans_wklist: Vec<TransId> =
-            (0..n_epsilons).map(TransId).collect();
-        let mut trans_vals: TransList<Ext<()>> =
-            TransList(vec![Ext::None; n_epsilons]);
-        while let Some(tr_id) = trans_wklist.pop() {
-            let cur = trans_vals[tr_id];
-            let tgt_id = self.epsilons[tr_id].target_id();
-            // Only evaluate the transition if its value may cause a change
-            if cur.is_many() || self.states[tgt_id].is_many() {
-                continue;
-            }
-            let new = self.eval_epsilon(tr_id);
-            if new.is_none() || new.is_one() && cur.is_one() {
-                continue;
-            }
-            // Here we know: the value of the transition has increased
-            // (from None to One(x), None to Many, or One(x) to Many)
-            // AND the target state is either None or One(x), so should
-            // be increased by One(x), Many, or Many respectively
-            trans_vals[tr_id] = new.to_unit();
-            self.states[tgt_id] += new;
-            for &eps_id in &self.eps_out[tgt_id] {
-                trans_wklist.push(eps_id);
-            }
-        }
+    fn is_active_in<I, Q>(&self, item: &I, states: &StateList<Ext<Q>>) -> bool
+    where
+        Tr: Transition<I, Q>,
+    {
+        self.tr.is_active_in(item, states)
     }
-    fn eval_updates(&mut self, item: &D) {
-        // The update logic prior to evaluating epsilons -- not as complex
-        // as eval_epsilons() as here we assume updates only take old states
-        // and return new states.
-        let mut new_states = StateList(vec![Ext::None; self.states.len()]);
-        for tr in self.updates.iter() {
-            if tr.is_active(item) {
-                new_states[tr.target_id()] += tr.eval(item, &self.states);
-            }
+    fn eval<I, Q>(&self, item: &I, states: &StateList<Ext<Q>>) -> Ext<Q>
+    where
+        Tr: Transition<I, Q>,
+    {
+        debug_assert!(self.eval_precond(states));
+        apply_to(&*self.tr, &self.sources, item, states)
+    }
+    // Rename the source and target states (used when states are removed,
+    // or when a machine is moved into another one)
+    fn map_ids(&mut self, f: &dyn Fn(StateId) -> StateId) {
+        for s in self.sources.iter_mut() {
+            *s = f(*s);
         }
-        self.states = new_states;
+        self.target = f(self.target);
+    }
+
+    /* Derived functionality */
+    fn eval_precond<I, Q>(&self, states: &StateList<Ext<Q>>) -> bool
+    where
+        Tr: Transition<I, Q>,
+    {
+        self.tr.arity() == self.sources.len()
+            && self.sources.iter().all(|&id| states.in_range(id))
+    }
+    fn heap_bytes(&self) -> usize {
+        // The transition and its reference counts (shared transitions are
+        // counted once per edge)
+        2 * mem::size_of::<usize>() + mem::size_of_val(&*self.tr)
+    }
+    fn all_ids(&self) -> impl Iterator<Item = StateId> + '_ {
+        self.sources.iter().copied().chain(std::iter::once(self.target))
     }
 }
 
-impl<D, Q> Transducer<Q, D, Q> for DataTransducer<'_, D, Q>
+// Apply a transition to the values of its source states
+fn apply_to<I, Q, Tr>(
+    tr: &Tr,
+    sources: &[StateId],
+    item: &I,
+    states: &StateList<Ext<Q>>,
+) -> Ext<Q>
 where
-    Q: Clone,
+    Tr: ?Sized + Transition<I, Q>,
 {
-    fn init(&mut self, i: Ext<Q>) -> Ext<Q> {
-        self.add_to_istate(i);
-        self.eval_epsilons();
-        debug_assert!(self.invariant());
-        self.get_fstate()
+    // Avoid allocating for the arguments
+    match *sources {
+        [] => tr.apply(item, &[]),
+        [s] => tr.apply(item, &[states[s].as_ref()]),
+        [s1, s2] => tr.apply(item, &[states[s1].as_ref(), states[s2].as_ref()]),
+        _ => unreachable!(),
     }
-    fn update(&mut self, item: &D) -> Ext<Q> {
-        self.eval_updates(item);
-        self.eval_epsilons();
-        debug_assert!(self.invariant());
-        self.get_fstate()
+}
+
+// Lightweight Debug implementation
+// This format string is rather incomplete, since function closures
+// do not implement Debug.
+impl<Tr: ?Sized> Debug for Edge<Tr> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("[")?;
+        for &id in self.source_ids() {
+            f.write_fmt(format_args!("{} ", id.0))?;
+        }
+        f.write_fmt(format_args!("-> {}]", self.target_id().0))
     }
-    fn reset(&mut self) {
-        for state in self.states.iter_mut() {
-            *state = Ext::None;
+}
+
+/*
+    More transition functionality.
+
+    Exactly the same as StateId and StateList, TransId and TransList are type
+    wrappers over usize and Vec<T> where the latter can be indexed by the
+    former. The most important thing is that TransList can't be indexed by
+    StateId and StateList can't be indexed by TransId. In fact, I already caught
+    a bug due to such a mistake as I was introducing this discipline.
+*/
+
+#[derive(Copy, Clone, Debug)]
+struct TransId(usize);
+
+#[derive(Clone, Debug)]
+struct TransList<T>(Vec<T>);
+impl<T> Deref for TransList<T> {
+    type Target = Vec<T>;
+    fn deref(&self) -> &Vec<T> {
+        &self.0
+    }
+}
+impl<T> DerefMut for TransList<T> {
+    fn deref_mut(&mut self) -> &mut Vec<T> {
+        &mut self.0
+    }
+}
+impl<T> Index<TransId> for TransList<T> {
+    type Output = T;
+    fn index(&self, id: TransId) -> &Self::Output {
+        self.0.index(id.0)
+    }
+}
+impl<T> IndexMut<TransId> for TransList<T> {
+    fn index_mut(&mut self, id: TransId) -> &mut Self::Output {
+        self.0.index_mut(id.0)
+    }
+}
+
+/*
+    Public handle to a transition, returned when it is added.
+    Update and epsilon transitions are numbered separately, in the order
+    they were added. Like Vec::remove, removing a transition shifts down the
+    indices of the transitions added after it (of the same kind).
+*/
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TransRef {
+    Update(usize),
+    Epsilon(usize),
+}
+impl TransRef {
+    pub fn is_epsilon(&self) -> bool {
+        matches!(self, TransRef::Epsilon(_))
+    }
+}
+
+/*
+    Read-only descriptors of states and transitions, returned by
+    DataTransducer::states() and DataTransducer::transitions().
+    These allow tools (exporters, debuggers, optimizers) to inspect the
+    structure of a machine; the guards and actions themselves are opaque.
+*/
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct StateInfo<'b, Q> {
+    pub id: usize,
+    pub name: Option<&'b str>,
+    pub value: &'b Ext<Q>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TransInfo {
+    pub trans: TransRef,
+    // Source states in order (between 0 and 2 of them)
+    pub sources: Vec<usize>,
+    pub target: usize,
+}
+impl TransInfo {
+    pub fn is_epsilon(&self) -> bool {
+        self.trans.is_epsilon()
+    }
+    pub fn arity(&self) -> usize {
+        self.sources.len()
+    }
+}
+
+// Counts of how often a transition was considered, when profiling is enabled
+// (see .enable_stats()). For epsilon transitions (which have no guard),
+// guard_evals is always 0 and active counts how often it was evaluated.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct FiringStats {
+    pub guard_evals: u64,
+    pub active: u64,
+    // Times the transition produced a value (other than Ext::None)
+    pub produced: u64,
+}
+
+// One step of a recorded execution (see .start_recording()): the input
+// (an initial value or an item), the transitions which contributed a value,
+// the states whose value may have changed with their new values, and the
+// output.
+#[derive(Clone, Debug, PartialEq)]
+pub struct StepRecord<D, Q> {
+    pub input: RInput<Ext<Q>, D>,
+    pub fired: Vec<TransRef>,
+    pub changed: Vec<(usize, Ext<Q>)>,
+    pub output: Ext<Q>,
+}
+impl<D: Debug, Q: Debug> fmt::Display for StepRecord<D, Q> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.input {
+            RInput::Restart(i) => write!(f, "init {:?}", i)?,
+            RInput::Item(d) => write!(f, "update {:?}", d)?,
+        }
+        write!(f, ": fired {:?}, changed {{", self.fired)?;
+        for (k, (id, value)) in self.changed.iter().enumerate() {
+            let sep = if k == 0 { "" } else { ", " };
+            write!(f, "{}{}: {:?}", sep, id, value)?;
+        }
+        write!(f, "}}, output {:?}", self.output)
+    }
+}
+
+// Guard function for epsilon transitions -- should never be called, so panics
+fn epsilon_guard<D>(_item: &D) -> bool {
+    panic!("Called guard for epsilon transition!");
+}
+
+// Problems with a machine found by static analysis (see .analyze()).
+// All lists are sorted.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Report {
+    // States which can never get a value, given the alphabet
+    pub unreachable: Vec<usize>,
+    // States whose value can't affect the output (see .coreachable())
+    pub dead: Vec<usize>,
+    // Groups of states which are on a cycle of epsilon transitions (any
+    // value which gets onto such a cycle becomes Many)
+    pub epsilon_cycles: Vec<Vec<usize>>,
+    // Update transitions whose guard holds on no item of the alphabet
+    pub never_active: Vec<TransRef>,
+}
+impl Report {
+    pub fn is_clean(&self) -> bool {
+        self == &Self::default()
+    }
+}
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_clean() {
+            return writeln!(f, "no problems found");
+        }
+        if !self.unreachable.is_empty() {
+            writeln!(f, "unreachable states: {:?}", self.unreachable)?;
+        }
+        if !self.dead.is_empty() {
+            writeln!(f, "states not affecting the output: {:?}", self.dead)?;
+        }
+        for cycle in &self.epsilon_cycles {
+            writeln!(f, "epsilon cycle through states: {:?}", cycle)?;
+        }
+        if !self.never_active.is_empty() {
+            writeln!(f, "transitions never active: {:?}", self.never_active)?;
+        }
+        Ok(())
+    }
+}
+
+// How the values written to the same target state by several update
+// transitions on the same step are resolved (see .set_conflict_policy()).
+// Union: all the values are combined (so two values give Many).
+// HighestPriority: only the transitions of highest priority among those
+// which produce a value count (and are combined as for Union if several
+// have that priority). This allows "first rule that applies" machines.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum ConflictPolicy {
+    #[default]
+    Union,
+    HighestPriority,
+}
+
+/*
+    Errors when constructing a data transducer (see errors.rs).
+
+    The methods on DataTransducer panic with these; DataTransducerBuilder
+    (below) returns them instead.
+*/
+
+fn build_panic<T>(err: BuildError) -> T {
+    panic!("{}", err)
+}
+
+/*
+    Violations of the internal invariants of a data transducer (see
+    .validate()).
+
+    These indicate a bug (in this module, or in a custom Transition whose
+    arity changes), rather than a problem with the machine as specified.
+    By default they are only checked in debug builds, after each operation,
+    and cause a panic; see Validation for the alternatives.
+*/
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum InvariantViolation {
+    // There must be at least the initial and final states
+    TooFewStates { n_states: usize },
+    // A table indexed by state or transition has the wrong length
+    TableLength { table: &'static str, expected: usize, found: usize },
+    // A state has a value pending outside of a step
+    PendingValue { state: usize },
+    // A transition refers to a state that does not exist
+    TransState { trans: TransRef, state: usize, n_states: usize },
+    // A final state does not exist
+    FinalState { state: usize, n_states: usize },
+    // A subscription is to a state that does not exist
+    SubscribedState { state: usize, n_states: usize },
+    // The index of epsilon transitions by source lists a transition which
+    // does not have that source
+    EpsilonIndex { state: usize, trans: TransRef },
+}
+impl fmt::Display for InvariantViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InvariantViolation::TooFewStates { n_states } => {
+                write!(f, "only {} states (need at least 2)", n_states)
+            }
+            InvariantViolation::TableLength { table, expected, found } => {
+                write!(
+                    f,
+                    "{} has length {}, expected {}",
+                    table, found, expected
+                )
+            }
+            InvariantViolation::PendingValue { state } => {
+                write!(f, "state {} has a pending value between steps", state)
+            }
+            InvariantViolation::TransState { trans, state, n_states } => {
+                write!(
+                    f,
+                    "{:?} refers to state {}, but there are only {} states",
+                    trans, state, n_states
+                )
+            }
+            InvariantViolation::FinalState { state, n_states } => write!(
+                f,
+                "final state {} does not exist (there are {} states)",
+                state, n_states
+            ),
+            InvariantViolation::SubscribedState { state, n_states } => write!(
+                f,
+                "subscription to state {}, but there are only {} states",
+                state, n_states
+            ),
+            InvariantViolation::EpsilonIndex { state, trans } => write!(
+                f,
+                "{:?} is indexed under state {}, which is not its source",
+                trans, state
+            ),
         }
-        debug_assert!(self.invariant());
+    }
+}
+impl Error for InvariantViolation {}
+
+// When the invariants are checked (see .set_validation())
+// Off: never (except by calling .validate() directly).
+// Debug: after each operation in debug builds, panicking on a violation.
+// Panic: after each operation, also in release builds, panicking on a
+// violation.
+// Record: after each operation, recording violations instead of panicking
+// (see .diagnostics()). Useful when the machine is built from an untrusted
+// specification, at the cost of a check on every step.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Validation {
+    Off,
+    #[default]
+    Debug,
+    Panic,
+    Record,
+}
+
+fn invariant_panic(violations: &[InvariantViolation]) -> ! {
+    let mut msg = "data transducer invariant violated:".to_string();
+    for v in violations {
+        msg.push_str(&format!("\n  {}", v));
+    }
+    panic!("{}", msg)
+}
+
+/*
+    The main DataTransducer state machine.
+    Implements the Transducer interface.
+
+    Cloning a DataTransducer is cheap-ish: the transitions are shared
+    (reference counted) between the clones, and only the state values and
+    bookkeeping are copied.
+*/
+
+type EpsOut = SmallVec<[TransId; 2]>;
+type Combiner<'a, Q> = Rc<dyn Fn(&[Ext<&Q>]) -> Ext<Q> + 'a>;
+
+const ISTATE_ID: StateId = StateId(0);
+const FSTATE_ID: StateId = StateId(1);
+
+// Number of items per chunk in .update_batch() (see .update_chunk())
+pub const CHUNK_SIZE: usize = 256;
+
+// How to evaluate the epsilon-transitions on each step: if they form no
+// cycles, a single pass in topological order suffices; otherwise fall back
+// to the generic worklist.
+#[derive(Clone, Debug)]
+enum EpsSchedule {
+    Stale,
+    Cyclic,
+    Ordered(Vec<TransId>),
+}
+
+// Update transitions indexed by a (small) integer key of the item: for each
+// key, the transitions which are active on items with that key, recorded the
+// first time such an item is seen
+struct Dispatch<'a, D> {
+    key: Rc<dyn Fn(&D) -> usize + 'a>,
+    classes: Vec<Option<Vec<TransId>>>,
+}
+impl<D> Clone for Dispatch<'_, D> {
+    fn clone(&self) -> Self {
+        Self { key: self.key.clone(), classes: self.classes.clone() }
+    }
+}
+
+// A callback on a state (see .subscribe()), and whether the state had a
+// value after the last step
+type Callback<'a, Q> = Rc<dyn Fn(&Ext<Q>) + 'a>;
+
+struct Subscription<'a, Q> {
+    state: StateId,
+    callback: Callback<'a, Q>,
+    was_some: bool,
+}
+impl<Q> Clone for Subscription<'_, Q> {
+    fn clone(&self) -> Self {
+        Self {
+            state: self.state,
+            callback: self.callback.clone(),
+            was_some: self.was_some,
+        }
+    }
+}
+
+// The log kept while recording
+struct Recorder<D, Q> {
+    // (D is not required to be Clone in general)
+    clone_item: fn(&D) -> D,
+    steps: Vec<StepRecord<D, Q>>,
+    // Transitions fired so far in the current step
+    fired: Vec<TransRef>,
+}
+impl<D, Q: Clone> Clone for Recorder<D, Q> {
+    fn clone(&self) -> Self {
+        let clone_step = |step: &StepRecord<D, Q>| StepRecord {
+            input: match &step.input {
+                RInput::Restart(i) => RInput::Restart(i.clone()),
+                RInput::Item(d) => RInput::Item((self.clone_item)(d)),
+            },
+            fired: step.fired.clone(),
+            changed: step.changed.clone(),
+            output: step.output.clone(),
+        };
+        Self {
+            clone_item: self.clone_item,
+            steps: self.steps.iter().map(clone_step).collect(),
+            fired: self.fired.clone(),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Stats {
+    updates: TransList<FiringStats>,
+    epsilons: TransList<FiringStats>,
+}
+impl Stats {
+    fn new(n_updates: usize, n_epsilons: usize) -> Self {
+        Self {
+            updates: TransList(vec![Default::default(); n_updates]),
+            epsilons: TransList(vec![Default::default(); n_epsilons]),
+        }
+    }
+}
+
+pub struct DataTransducer<'a, D, Q, U = dyn Transition<D, Q> + 'a>
+where
+    Q: 'a + Clone,
+    D: 'a,
+    U: 'a + ?Sized + Transition<D, Q>,
+{
+    // Initial state: states[0]
+    // Final state: states[1]
+    states: StateList<Ext<Q>>,
+    // Scratch buffer for the new states computed on update (swapped with
+    // states, so as not to allocate on every item); all None between steps
+    next_states: StateList<Ext<Q>>,
+    // Scratch buffers for the epsilons evaluated on each step (see
+    // .eval_epsilons()): the states which changed, which states are dirty,
+    // and the worklist of epsilon-transitions; kept between steps so as not
+    // to allocate on every item
+    changed: Vec<StateId>,
+    dirty: Vec<bool>,
+    eps_wklist: Vec<TransId>,
+    // Scratch buffers for the guards evaluated over a chunk (see
+    // .update_chunk())
+    guards: Vec<bool>,
+    guards_from: Vec<usize>,
+    // Transitions, divided into those executed on update from old to new states
+    // and "epsilon transitions" which define a least fixed point on init and
+    // after every update
+    // (Update transitions have type U: by default these are given by
+    // closures, but see .add_static_transition().)
+    updates: TransList<Edge<U>>,
+    epsilons: TransList<Edge<dyn Transition<(), Q> + 'a>>,
+    // Store for each state which epsilon-transitions go out from this state
+    // (needed for the least fixed point calculation), and separately those
+    // with no source states at all (these fire on every step).
+    // Most states have at most a couple of outgoing epsilons, which are
+    // stored inline.
+    eps_out: StateList<EpsOut>,
+    eps_nullary: Vec<TransId>,
+    // Store for each epsilon-transition the value it has contributed to its
+    // target so far in the current step. This persists across several calls
+    // to .init() within the same step, so that contributions which were
+    // already propagated are not counted twice; it is cleared on .update().
+    eps_vals: TransList<Ext<()>>,
+    // Cached evaluation order for the epsilon-transitions (see EpsSchedule);
+    // recomputed lazily after the epsilon-transitions change
+    eps_schedule: EpsSchedule,
+    // Optional index of the update transitions by a discriminant of the item
+    // (see .set_dispatch())
+    dispatch: Option<Dispatch<'a, D>>,
+    // Firing statistics for each transition, if profiling is enabled
+    stats: Option<Stats>,
+    // Log of the execution so far, if recording
+    recorder: Option<Recorder<D, Q>>,
+    // Values emitted by update transitions on the last step (see
+    // .add_output()); None if no transition has an output
+    emitted: Option<Vec<Ext<Q>>>,
+    // The final states, whose values give the output on each step (by
+    // default just state 1), and how their values are combined (by default
+    // their union; see .set_final_states())
+    finals: Vec<StateId>,
+    combine: Option<Combiner<'a, Q>>,
+    // How conflicting update transitions are resolved, and (with
+    // ConflictPolicy::HighestPriority) a scratch buffer holding, for each
+    // state, the priority of the values written to it so far on this step
+    policy: ConflictPolicy,
+    written: Vec<Option<i32>>,
+    // Whether any update transition may have a guard on the state values
+    // (see .add_state_guard())
+    state_guards: bool,
+    // Callbacks when states get a value (see .subscribe())
+    subscriptions: Vec<Subscription<'a, Q>>,
+    // Optional label for each state (used in the API and for Debug output)
+    names: StateList<Option<String>>,
+    // When to check the invariants, and the violations found so far with
+    // Validation::Record
+    validation: Validation,
+    diagnostics: Vec<InvariantViolation>,
+    // Dummy marker for D
+    ph_d: PhantomData<D>,
+}
+
+impl<'a, D, Q, U> Clone for DataTransducer<'a, D, Q, U>
+where
+    Q: Clone,
+    U: 'a + ?Sized + Transition<D, Q>,
+{
+    fn clone(&self) -> Self {
+        Self {
+            states: self.states.clone(),
+            next_states: self.next_states.clone(),
+            changed: vec![],
+            dirty: vec![],
+            eps_wklist: vec![],
+            guards: vec![],
+            guards_from: vec![],
+            updates: TransList(self.updates.iter().map(Edge::clone).collect()),
+            epsilons: TransList(
+                self.epsilons.iter().map(Edge::clone).collect(),
+            ),
+            eps_out: self.eps_out.clone(),
+            eps_nullary: self.eps_nullary.clone(),
+            eps_vals: self.eps_vals.clone(),
+            eps_schedule: self.eps_schedule.clone(),
+            dispatch: self.dispatch.clone(),
+            stats: self.stats.clone(),
+            recorder: self.recorder.clone(),
+            emitted: self.emitted.clone(),
+            finals: self.finals.clone(),
+            combine: self.combine.clone(),
+            policy: self.policy,
+            written: self.written.clone(),
+            state_guards: self.state_guards,
+            subscriptions: self.subscriptions.clone(),
+            names: self.names.clone(),
+            validation: self.validation,
+            diagnostics: self.diagnostics.clone(),
+            ph_d: PhantomData,
+        }
+    }
+}
+
+impl<'a, D, Q, U> Default for DataTransducer<'a, D, Q, U>
+where
+    Q: Clone,
+    U: 'a + ?Sized + Transition<D, Q>,
+{
+    fn default() -> Self {
+        let states = StateList(vec![Ext::None, Ext::None]);
+        let next_states = StateList(vec![Ext::None, Ext::None]);
+        let changed = vec![];
+        let dirty = vec![];
+        let eps_wklist = vec![];
+        let guards = vec![];
+        let guards_from = vec![];
+        let updates = TransList(vec![]);
+        let epsilons = TransList(vec![]);
+        let eps_out = StateList(vec![EpsOut::new(), EpsOut::new()]);
+        let eps_nullary = vec![];
+        let eps_vals = TransList(vec![]);
+        let eps_schedule = EpsSchedule::Stale;
+        let dispatch = None;
+        let stats = None;
+        let recorder = None;
+        let emitted = None;
+        let finals = vec![FSTATE_ID];
+        let combine = None;
+        let policy = Default::default();
+        let written = vec![];
+        let state_guards = false;
+        let subscriptions = vec![];
+        let names = StateList(vec![None, None]);
+        let validation = Default::default();
+        let diagnostics = vec![];
+        let ph_d = PhantomData;
+        let mut result = Self {
+            states,
+            next_states,
+            changed,
+            dirty,
+            eps_wklist,
+            guards,
+            guards_from,
+            updates,
+            epsilons,
+            eps_out,
+            eps_nullary,
+            eps_vals,
+            eps_schedule,
+            dispatch,
+            stats,
+            recorder,
+            emitted,
+            finals,
+            combine,
+            policy,
+            written,
+            state_guards,
+            subscriptions,
+            names,
+            validation,
+            diagnostics,
+            ph_d,
+        };
+        result.check_invariant();
+        result
+    }
+}
+
+impl<'a, D, Q, U> Debug for DataTransducer<'a, D, Q, U>
+where
+    Q: Clone + Debug,
+    D: Debug,
+    U: 'a + ?Sized + Transition<D, Q>,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // States and transitions are shown using state labels where
+        // available, e.g. [window_sum window_count -> 1]
+        let states = (0..self.states.len())
+            .map(StateId)
+            .map(|id| (Label(self.state_label(id)), &self.states[id]));
+        let updates: Vec<Label> =
+            self.updates.iter().map(|tr| Label(self.trans_label(tr))).collect();
+        let epsilons: Vec<Label> = self
+            .epsilons
+            .iter()
+            .map(|tr| Label(self.trans_label(tr)))
+            .collect();
+        f.debug_struct("DataTransducer")
+            .field("states", &DebugMap(states))
+            .field("updates", &updates)
+            .field("epsilons", &epsilons)
+            .field("eps_out", &self.eps_out)
+            .finish()
+    }
+}
+
+// Helpers for the Debug implementation above
+struct Label(String);
+impl Debug for Label {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+struct DebugMap<It>(It);
+impl<K: Debug, V: Debug, It: Iterator<Item = (K, V)> + Clone> Debug
+    for DebugMap<It>
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_map().entries(self.0.clone()).finish()
+    }
+}
+
+impl<'a, D, Q, U> DataTransducer<'a, D, Q, U>
+where
+    Q: Clone,
+    U: 'a + ?Sized + Transition<D, Q>,
+{
+    /* Initialization (forming the states and transitions) */
+    // These panic if their preconditions are violated; for recoverable
+    // errors use DataTransducerBuilder instead.
+    // The constructor and the methods adding update transitions given by
+    // closures are further below (they are only for the default U).
+    // Add a new state, returning its index
+    pub fn add_state(&mut self) -> usize {
+        debug_assert!(self.states.len() >= 2);
+        self.states.push(Ext::None);
+        self.next_states.push(Ext::None);
+        self.eps_out.push(EpsOut::new());
+        self.names.push(None);
+        self.check_invariant();
+        self.states.len() - 1
+    }
+    // Add a new state with a label, returning its index
+    // The label can then be used in place of the index, e.g.
+    // m.add_iden("window_sum", "window_sum", |_| true)
+    pub fn add_state_named(&mut self, name: &str) -> usize {
+        self.try_add_state_named(name).unwrap_or_else(build_panic)
+    }
+    // Set the label of an existing state (e.g. the initial or final state)
+    pub fn name_state(&mut self, id: usize, name: &str) {
+        self.try_name_state(id, name).unwrap_or_else(build_panic)
+    }
+    // Look up a state by label
+    pub fn state_id(&self, name: &str) -> Option<usize> {
+        name.to_state_id(&self.names).ok()
+    }
+    // Get the label of a state, if any
+    pub fn state_name(&self, id: usize) -> Option<&str> {
+        self.names.get(id).and_then(|name| name.as_deref())
+    }
+    // Set the number of states directly
+    // (instead of repeatedly calling .add_state())
+    pub fn set_nstates(&mut self, n: usize) {
+        self.try_set_nstates(n).unwrap_or_else(build_panic)
+    }
+    // Set which states are final (by default, only state 1). The output on
+    // each step is the union of their values, i.e. Many if more than one
+    // has a value, unless a combining function is given with
+    // .set_final_combiner(). Final states can't be removed. (State 1 also
+    // can't be removed, but it need not be among the final states.)
+    pub fn set_final_states<S: StateRef>(&mut self, states: &[S]) {
+        self.try_set_final_states(states).unwrap_or_else(build_panic)
+    }
+    // Set the function giving the output from the values of the final
+    // states (in the order given to .set_final_states()).
+    // It should return Ext::None when all its arguments are Ext::None, or
+    // the machine will not satisfy the INIT property (see interface.rs).
+    pub fn set_final_combiner<F>(&mut self, combine: F)
+    where
+        F: 'a + Fn(&[Ext<&Q>]) -> Ext<Q>,
+    {
+        self.combine = Some(Rc::new(combine));
+    }
+    pub fn final_states(&self) -> Vec<usize> {
+        self.finals.iter().map(|id| id.0).collect()
+    }
+    // Set the priority of an update transition (by default 0; higher
+    // numbers take precedence). Only matters with
+    // ConflictPolicy::HighestPriority.
+    pub fn set_priority(&mut self, tr: TransRef, priority: i32) {
+        self.try_set_priority(tr, priority).unwrap_or_else(build_panic)
+    }
+    pub fn priority(&self, tr: TransRef) -> Option<i32> {
+        match tr {
+            TransRef::Update(i) => self.updates.get(i).map(|tr| tr.priority),
+            TransRef::Epsilon(i) => self.epsilons.get(i).map(|_| 0),
+        }
+    }
+    // Set how update transitions writing to the same state are resolved
+    // (see ConflictPolicy). Note that with HighestPriority, output actions
+    // (see .add_output()) of a transition are still emitted if its value is
+    // overridden by one of higher priority added after it.
+    pub fn set_conflict_policy(&mut self, policy: ConflictPolicy) {
+        self.policy = policy;
+        self.written.clear();
+    }
+    // Add an epsilon transition which copies the source state to the target
+    pub fn add_epsilon_iden(
+        &mut self,
+        source: impl StateRef,
+        target: impl StateRef,
+    ) -> TransRef {
+        self.try_add_epsilon_iden(source, target).unwrap_or_else(build_panic)
+    }
+    // Add an epsilon transition with no source states, which sets the target
+    // on every .init() and .update()
+    // Note: this breaks the INIT property (see interface.rs) if the target
+    // can reach the final state, since then .init(Ext::None) produces output.
+    pub fn add_epsilon0<F>(
+        &mut self,
+        target: impl StateRef,
+        action: F,
+    ) -> TransRef
+    where
+        F: 'a + Fn() -> Q,
+    {
+        self.try_add_epsilon0(target, action).unwrap_or_else(build_panic)
+    }
+    // Add an epsilon transition with one source state
+    pub fn add_epsilon1<F>(
+        &mut self,
+        source: impl StateRef,
+        target: impl StateRef,
+        action: F,
+    ) -> TransRef
+    where
+        F: 'a + Fn(&Q) -> Q,
+    {
+        self.try_add_epsilon1(source, target, action)
+            .unwrap_or_else(build_panic)
+    }
+    // Add an update transition with two source states
+    pub fn add_epsilon2<F>(
+        &mut self,
+        source1: impl StateRef,
+        source2: impl StateRef,
+        target: impl StateRef,
+        action: F,
+    ) -> TransRef
+    where
+        F: 'a + Fn(&Q, &Q) -> Q,
+    {
+        self.try_add_epsilon2(source1, source2, target, action)
+            .unwrap_or_else(build_panic)
+    }
+    // Remove a transition (update or epsilon)
+    // Transitions of the same kind added after it are renumbered (see TransRef).
+    pub fn remove_transition(&mut self, tr: TransRef) {
+        self.try_remove_transition(tr).unwrap_or_else(build_panic)
+    }
+    // Remove a state, together with all transitions to or from it
+    // States after it are renumbered (shifted down by one), and so are
+    // the remaining transitions.
+    pub fn remove_state(&mut self, id: impl StateRef) {
+        self.try_remove_state(id).unwrap_or_else(build_panic)
+    }
+    // Add an update transition of the static type U (rather than given by
+    // closures), e.g. a variant of an enum implementing Transition.
+    // Create the machine with DataTransducer::<D, Q, U>::default().
+    pub fn add_static_transition<S: StateRef>(
+        &mut self,
+        sources: &[S],
+        target: impl StateRef,
+        tr: U,
+    ) -> TransRef
+    where
+        U: Sized,
+    {
+        self.try_add_static_transition(sources, target, tr)
+            .unwrap_or_else(build_panic)
+    }
+
+    /* Introspection */
+    // Iterate over the states, with their labels and current values
+    pub fn states(&self) -> impl Iterator<Item = StateInfo<'_, Q>> {
+        self.states.enumerate().map(move |(id, value)| StateInfo {
+            id: id.0,
+            name: self.names[id].as_deref(),
+            value,
+        })
+    }
+    // Iterate over the transitions: first updates, then epsilons, each in
+    // the order they were added
+    pub fn transitions(&self) -> impl Iterator<Item = TransInfo> + '_ {
+        let updates = (0..self.updates.len()).map(TransRef::Update);
+        let epsilons = (0..self.epsilons.len()).map(TransRef::Epsilon);
+        updates.chain(epsilons).map(move |tr| self.transition(tr).unwrap())
+    }
+    // Look up a single transition
+    pub fn transition(&self, tr: TransRef) -> Option<TransInfo> {
+        let (sources, target) = match tr {
+            TransRef::Update(i) => {
+                let tr = self.updates.get(i)?;
+                (tr.source_ids(), tr.target_id())
+            }
+            TransRef::Epsilon(i) => {
+                let tr = self.epsilons.get(i)?;
+                (tr.source_ids(), tr.target_id())
+            }
+        };
+        Some(TransInfo {
+            trans: tr,
+            sources: sources.iter().map(|id| id.0).collect(),
+            target: target.0,
+        })
+    }
+    // The name of the action of a transition, if it was given one
+    pub fn action_name(&self, tr: TransRef) -> Option<&str> {
+        match tr {
+            TransRef::Update(i) => self.updates.get(i)?.tr.action_name(),
+            TransRef::Epsilon(i) => self.epsilons.get(i)?.tr.action_name(),
+        }
+    }
+    pub fn conflict_policy(&self) -> ConflictPolicy {
+        self.policy
+    }
+    // Features in use which are not visible through the transitions
+    // (these are not supported by codegen.rs)
+    pub(crate) fn extensions_used(&self) -> Vec<&'static str> {
+        let mut result = vec![];
+        if self.updates.iter().any(|tr| tr.tr.has_output()) {
+            result.push("output actions");
+        }
+        if self.state_guards {
+            result.push("state guards");
+        }
+        if self.combine.is_some() {
+            result.push("a final combiner");
+        }
+        if !self.subscriptions.is_empty() {
+            result.push("subscriptions");
+        }
+        result
+    }
+    // Firing statistics of a transition, if profiling is enabled
+    pub fn stats(&self, tr: TransRef) -> Option<FiringStats> {
+        let stats = self.stats.as_ref()?;
+        match tr {
+            TransRef::Update(i) => stats.updates.get(i).copied(),
+            TransRef::Epsilon(i) => stats.epsilons.get(i).copied(),
+        }
+    }
+    // Start counting how often each transition fires (see FiringStats),
+    // from zero. The counts are also reset when transitions are removed or
+    // renumbered (e.g. by .remove_state() or .minimize()).
+    pub fn enable_stats(&mut self) {
+        self.stats = Some(Stats::new(self.updates.len(), self.epsilons.len()));
+    }
+    pub fn disable_stats(&mut self) {
+        self.stats = None;
+    }
+
+    /* Outputs of transitions */
+    // The values emitted by update transitions with an output action (see
+    // .add_output()) on the last step, in the order the transitions were
+    // added: one for each output action of each transition which produced
+    // a value.
+    pub fn emitted(&self) -> &[Ext<Q>] {
+        self.emitted.as_deref().unwrap_or(&[])
+    }
+
+    /* Subscriptions */
+    // Call the callback whenever the state gets a value (One or Many) after
+    // having none, with the new value. It is called at the end of the
+    // .init() or .update() in which this happens, so the value is final
+    // for the step. (A channel can be used by sending the value from the
+    // callback.)
+    // States with a subscription can't be removed, and are kept by
+    // optimizations as if they were final.
+    pub fn subscribe<F>(&mut self, state: impl StateRef, callback: F)
+    where
+        F: 'a + Fn(&Ext<Q>),
+    {
+        self.try_subscribe(state, callback).unwrap_or_else(build_panic)
+    }
+    pub fn clear_subscriptions(&mut self) {
+        self.subscriptions.clear();
+    }
+
+    /* Recording and replay */
+    // Start logging each step of the execution (see StepRecord), discarding
+    // any previous log
+    pub fn start_recording(&mut self)
+    where
+        D: Clone,
+    {
+        self.recorder = Some(Recorder {
+            clone_item: D::clone,
+            steps: vec![],
+            fired: vec![],
+        });
+    }
+    // Stop logging, returning the log
+    pub fn stop_recording(&mut self) -> Vec<StepRecord<D, Q>> {
+        self.recorder.take().map_or_else(Vec::new, |rec| rec.steps)
+    }
+    // The log so far, if recording
+    pub fn recording(&self) -> Option<&[StepRecord<D, Q>]> {
+        self.recorder.as_ref().map(|rec| rec.steps.as_slice())
+    }
+    // Reset the machine and run the inputs of a log on it again, returning
+    // the outputs (which should match those in the log, if it was recorded
+    // on this machine)
+    pub fn replay(&mut self, log: &[StepRecord<D, Q>]) -> Vec<Ext<Q>> {
+        self.reset();
+        log.iter()
+            .map(|step| match &step.input {
+                RInput::Restart(i) => self.init(i.clone()),
+                RInput::Item(d) => self.update(d),
+            })
+            .collect()
+    }
+
+    /* Analyses */
+    // States which may get a value: those reachable from the initial state
+    // via transitions all of whose sources are reachable. (This ignores
+    // guards, so it over-approximates.) Indexed by state.
+    pub fn reachable(&self) -> Vec<bool> {
+        self.reachable_via(&vec![true; self.updates.len()])
+    }
+    // Same, but only using the update transitions marked as possibly active
+    fn reachable_via(&self, active: &[bool]) -> Vec<bool> {
+        let mut reached = StateList(vec![false; self.states.len()]);
+        reached[ISTATE_ID] = true;
+        let mut changed = true;
+        while changed {
+            changed = false;
+            let targets = self
+                .updates
+                .iter()
+                .zip(active)
+                .filter(|&(_, &active)| active)
+                .map(|(tr, _)| tr)
+                .filter(|tr| tr.source_ids().iter().all(|&s| reached[s]))
+                .map(|tr| tr.target)
+                .chain(
+                    self.epsilons
+                        .iter()
+                        .filter(|tr| {
+                            tr.source_ids().iter().all(|&s| reached[s])
+                        })
+                        .map(|tr| tr.target),
+                )
+                .collect::<Vec<_>>();
+            for tgt in targets {
+                if !reached[tgt] {
+                    reached[tgt] = true;
+                    changed = true;
+                }
+            }
+        }
+        reached.0
+    }
+    // States whose value may affect the output: those from which a
+    // final state, a state with a subscription, or an update transition
+    // with an output action, can be reached via transitions. Indexed by
+    // state.
+    pub fn coreachable(&self) -> Vec<bool> {
+        let mut reached = StateList(vec![false; self.states.len()]);
+        for &id in &self.finals {
+            reached[id] = true;
+        }
+        for sub in &self.subscriptions {
+            reached[sub.state] = true;
+        }
+        for tr in self.updates.iter().filter(|tr| tr.tr.has_output()) {
+            reached[tr.target] = true;
+        }
+        let mut changed = true;
+        while changed {
+            changed = false;
+            let sources = self
+                .updates
+                .iter()
+                .filter(|tr| reached[tr.target])
+                .flat_map(|tr| tr.source_ids().to_vec())
+                .chain(
+                    self.epsilons
+                        .iter()
+                        .filter(|tr| reached[tr.target])
+                        .flat_map(|tr| tr.source_ids().to_vec()),
+                )
+                .collect::<Vec<_>>();
+            for src in sources {
+                if !reached[src] {
+                    reached[src] = true;
+                    changed = true;
+                }
+            }
+        }
+        reached.0
+    }
+
+    // The guard of an update transition, if it was given symbolically
+    // (see .add_sym_transition1())
+    pub fn symbolic_guard(&self, tr: TransRef) -> Option<Guard<'_, D>> {
+        match tr {
+            TransRef::Update(i) => self.updates.get(i)?.tr.symbolic_guard(),
+            TransRef::Epsilon(_) => None,
+        }
+    }
+    // Pairs of update transitions into the same state whose guards may
+    // both hold on the same item, so that which value the state gets
+    // depends on the conflict policy (see ConflictPolicy). Only
+    // transitions with symbolic guards are considered.
+    pub fn overlapping_guards(&self) -> Vec<(TransRef, TransRef)> {
+        let guards: Vec<Option<Guard<'_, D>>> =
+            self.updates.iter().map(|tr| tr.tr.symbolic_guard()).collect();
+        let mut result = vec![];
+        for (i, g1) in guards.iter().enumerate() {
+            for (j, g2) in guards.iter().enumerate().skip(i + 1) {
+                let same_target = self.updates[TransId(i)].target
+                    == self.updates[TransId(j)].target;
+                if let (true, Some(g1), Some(g2)) = (same_target, g1, g2) {
+                    if g1.overlaps(g2) {
+                        result.push((TransRef::Update(i), TransRef::Update(j)));
+                    }
+                }
+            }
+        }
+        result
+    }
+    // Report problems with the machine which can be found statically (see
+    // Report), where the items it will be run on are among the alphabet.
+    // Transitions with unsatisfiable symbolic guards are never active.
+    pub fn analyze(&self, alphabet: &[D]) -> Report {
+        let active: Vec<bool> = self
+            .updates
+            .iter()
+            .map(|tr| {
+                tr.tr.symbolic_guard().is_none_or(|g| g.is_satisfiable())
+                    && alphabet.iter().any(|d| tr.is_active(d))
+            })
+            .collect();
+        let reach = self.reachable_via(&active);
+        let coreach = self.coreachable();
+        let n = self.states.len();
+        let never_active = (0..self.updates.len())
+            .filter(|&i| !active[i])
+            .map(TransRef::Update)
+            .collect();
+        // States on epsilon cycles, grouped by strongly connected component
+        // (s and t are in the same one if each can reach the other)
+        let eps_reach: Vec<Vec<bool>> = (0..n)
+            .map(|i| {
+                let mut reached = StateList(vec![false; n]);
+                let mut stack = vec![StateId(i)];
+                while let Some(st) = stack.pop() {
+                    for &tid in self.eps_out[st].iter() {
+                        let tgt = self.epsilons[tid].target;
+                        if !reached[tgt] {
+                            reached[tgt] = true;
+                            stack.push(tgt);
+                        }
+                    }
+                }
+                reached.0
+            })
+            .collect();
+        let mut epsilon_cycles: Vec<Vec<usize>> = vec![];
+        for i in (0..n).filter(|&i| eps_reach[i][i]) {
+            match epsilon_cycles.iter_mut().find(|c| eps_reach[c[0]][i]) {
+                Some(cycle) => cycle.push(i),
+                None => epsilon_cycles.push(vec![i]),
+            }
+        }
+        Report {
+            unreachable: (0..n).filter(|&i| !reach[i]).collect(),
+            dead: (0..n).filter(|&i| !coreach[i]).collect(),
+            epsilon_cycles,
+            never_active,
+        }
+    }
+
+    /* Optimization passes */
+    // Index the update transitions by a key of the item (e.g. an event tag),
+    // so that on each item only the transitions relevant to its key are
+    // considered. Each guard must give the same result on all items with
+    // the same key; keys index a table, so should be small.
+    pub fn set_dispatch<F>(&mut self, key: F)
+    where
+        F: 'a + Fn(&D) -> usize,
+    {
+        self.dispatch = Some(Dispatch { key: Rc::new(key), classes: vec![] });
+    }
+    pub fn clear_dispatch(&mut self) {
+        self.dispatch = None;
+    }
+    // Remove states which are unreachable or can't reach a final state,
+    // and the transitions using them (other than the initial and final
+    // states, which are always kept). This does not change the output.
+    // Returns the number of states removed.
+    pub fn remove_dead_states(&mut self) -> usize {
+        let n_before = self.states.len();
+        loop {
+            let reach = self.reachable();
+            let coreach = self.coreachable();
+            let useful = |s: StateId| reach[s.0] && coreach[s.0];
+            // A transition is useless if any of its states is
+            let n_transs = self.n_transs();
+            self.updates.retain(|tr| tr.all_ids().all(useful));
+            let keep: Vec<bool> = self
+                .epsilons
+                .iter()
+                .map(|tr| tr.all_ids().all(useful))
+                .collect();
+            let mut keep_iter = keep.iter();
+            self.epsilons.retain(|_| *keep_iter.next().unwrap());
+            let mut keep_iter = keep.iter();
+            self.eps_vals.retain(|_| *keep_iter.next().unwrap());
+            self.rebuild_indices();
+            // Remove states from the end, so that indices don't shift
+            let mut removed = false;
+            for id in (2..self.states.len()).rev() {
+                if !useful(StateId(id)) && !self.is_observed(StateId(id)) {
+                    self.remove_state(id);
+                    removed = true;
+                }
+            }
+            // Removing transitions may make other states useless
+            if !removed && self.n_transs() == n_transs {
+                break;
+            }
+        }
+        self.check_invariant();
+        n_before - self.states.len()
+    }
+    // Merge states which always hold the same value, returning the number
+    // of states removed.
+    // Two states hold the same value if their incoming transitions match up
+    // one-to-one, where matching transitions have the same kind, matching
+    // sources, and the same guard and action. Since guards and actions are
+    // closures, they are compared as follows:
+    // - guards are compared extensionally on the given alphabet, which
+    //   should contain every item that the machine will be run on;
+    // - actions are only known to be equal if they are identities (see
+    //   .add_iden() and .add_epsilon_iden()) or the same transition object.
+    // The initial and final states are never merged with other states.
+    // Resets the machine (any current values are discarded).
+    pub fn minimize(&mut self, alphabet: &[D]) -> usize {
+        self.reset();
+        let n = self.states.len();
+        // Transitions which can't fire on the alphabet are useless
+        self.updates.retain(|tr| alphabet.iter().any(|d| tr.is_active(d)));
+        // Key for comparing transitions (without their source states)
+        #[derive(Clone, Eq, Ord, PartialEq, PartialOrd)]
+        enum Key {
+            Iden(i32, Vec<bool>),
+            Other(usize, i32, Vec<bool>),
+        }
+        let guard_sig = |tr: &Edge<U>| -> Vec<bool> {
+            alphabet.iter().map(|d| tr.is_active(d)).collect()
+        };
+        // (target, is_epsilon, key, sources) for each transition
+        let mut incoming: Vec<(StateId, bool, Key, Sources)> = vec![];
+        for tr in self.updates.iter() {
+            let key = if tr.tr.is_iden() {
+                Key::Iden(tr.priority, guard_sig(tr))
+            } else {
+                let ptr = Rc::as_ptr(&tr.tr) as *const () as usize;
+                Key::Other(ptr, tr.priority, guard_sig(tr))
+            };
+            incoming.push((tr.target, false, key, tr.sources));
+        }
+        for tr in self.epsilons.iter() {
+            let key = if tr.tr.is_iden() {
+                Key::Iden(0, vec![])
+            } else {
+                let ptr = Rc::as_ptr(&tr.tr) as *const () as usize;
+                Key::Other(ptr, 0, vec![])
+            };
+            incoming.push((tr.target, true, key, tr.sources));
+        }
+        // Partition refinement: start with the initial state and state 1,
+        // each final or subscribed state, and all other states, and split classes until
+        // each state's incoming transitions (with sources replaced by their
+        // classes) agree within its class
+        let mut class: Vec<usize> = (0..n)
+            .map(|i| if i < 2 || self.is_observed(StateId(i)) { i } else { n })
+            .collect();
+        let mut n_classes = class.iter().max().map_or(0, |&c| c + 1);
+        loop {
+            let mut sigs: Vec<Vec<(bool, Key, Vec<usize>)>> = vec![vec![]; n];
+            for (tgt, is_eps, key, sources) in incoming.iter() {
+                let srcs = sources.iter().map(|s| class[s.0]).collect();
+                sigs[tgt.0].push((*is_eps, key.clone(), srcs));
+            }
+            let mut new_ids = BTreeMap::new();
+            let new_class: Vec<usize> = (0..n)
+                .map(|i| {
+                    sigs[i].sort();
+                    let next = new_ids.len();
+                    *new_ids
+                        .entry((class[i], std::mem::take(&mut sigs[i])))
+                        .or_insert(next)
+                })
+                .collect();
+            let n_new = new_ids.len();
+            class = new_class;
+            if n_new == n_classes {
+                break;
+            }
+            n_classes = n_new;
+        }
+        // Merge each class into its first state: redirect sources, and drop
+        // the (duplicate) transitions into the other states
+        let mut rep = vec![usize::MAX; n_classes];
+        for (i, &c) in class.iter().enumerate() {
+            if rep[c] == usize::MAX {
+                rep[c] = i;
+            }
+        }
+        let to_rep = |s: StateId| StateId(rep[class[s.0]]);
+        self.updates.retain(|tr| to_rep(tr.target) == tr.target);
+        let keep: Vec<bool> = self
+            .epsilons
+            .iter()
+            .map(|tr| to_rep(tr.target) == tr.target)
+            .collect();
+        let mut keep_iter = keep.iter();
+        self.epsilons.retain(|_| *keep_iter.next().unwrap());
+        let mut keep_iter = keep.iter();
+        self.eps_vals.retain(|_| *keep_iter.next().unwrap());
+        for tr in self.updates.iter_mut() {
+            tr.map_ids(&to_rep);
+        }
+        for tr in self.epsilons.iter_mut() {
+            tr.map_ids(&to_rep);
+        }
+        self.rebuild_indices();
+        let mut removed = 0;
+        for id in (2..n).rev() {
+            if to_rep(StateId(id)).0 != id {
+                self.remove_state(id);
+                removed += 1;
+            }
+        }
+        self.check_invariant();
+        removed
+    }
+
+    /* Snapshot and restore of the current state values */
+    // Useful for checkpointing, debugging, and speculative evaluation.
+    pub fn get_states(&self) -> Vec<Ext<Q>> {
+        self.states.to_vec()
+    }
+    // The values should come from .get_states() on a machine with the
+    // same states and transitions (in particular, they are assumed to be
+    // closed under the epsilon transitions, as they are between calls to
+    // .init() and .update()).
+    pub fn set_states(
+        &mut self,
+        states: Vec<Ext<Q>>,
+    ) -> Result<(), BuildError> {
+        if states.len() != self.states.len() {
+            return Err(BuildError::StateCount {
+                expected: self.states.len(),
+                found: states.len(),
+            });
+        }
+        self.states = StateList(states);
+        // Epsilons have already contributed their current values, so record
+        // this to avoid counting them again on the next .init()
+        for i in 0..self.epsilons.len() {
+            let tid = TransId(i);
+            self.eps_vals[tid] = self.eval_epsilon(tid).to_unit();
+        }
+        // (Restoring values does not trigger subscriptions)
+        for sub in self.subscriptions.iter_mut() {
+            sub.was_some = !self.states[sub.state].is_none();
+        }
+        self.check_invariant();
+        Ok(())
+    }
+
+    /* Chunked (columnar) evaluation */
+    // Same as .update() on each item, but evaluates the guard of each
+    // update transition over the rest of the chunk (one transition at a
+    // time) on the first item where it may fire, i.e. where one of its
+    // source states has a value; transitions out of states which have no
+    // value during the whole chunk are skipped. This gives better
+    // throughput on large chunks, as each guard runs in a tight loop over
+    // contiguous items, and gives the same results as long as the guards
+    // are pure: a guard may be evaluated on items where .update() would
+    // not evaluate it (after its source states lose their value), or not
+    // evaluated where .update() would (before they have one).
+    // .update_batch() uses this for chunks of CHUNK_SIZE items; it falls
+    // back to item-at-a-time evaluation if dispatch, profiling, or
+    // recording is enabled.
+    pub fn update_chunk(&mut self, items: &[D]) -> Vec<Ext<Q>> {
+        let mut result = Vec::with_capacity(items.len());
+        self.update_chunk_into(items, &mut result);
+        result
+    }
+    // The same, pushing the outputs onto result
+    fn update_chunk_into(&mut self, items: &[D], result: &mut Vec<Ext<Q>>) {
+        if self.dispatch.is_some()
+            || self.stats.is_some()
+            || self.recorder.is_some()
+        {
+            for item in items {
+                self.step_update(item);
+                result.push(self.get_fstate());
+            }
+            return;
+        }
+        // guards[i * n + j]: whether update transition i is active on item
+        // j, computed for the items from guards_from[i] on (n if none)
+        let n = items.len();
+        let mut guards = mem::take(&mut self.guards);
+        let mut guards_from = mem::take(&mut self.guards_from);
+        guards.clear();
+        guards.resize(self.updates.len() * n, false);
+        guards_from.clear();
+        guards_from.resize(self.updates.len(), n);
+        for (j, item) in items.iter().enumerate() {
+            self.eval_updates_guarded(item, |i, tr| {
+                if guards_from[i] > j {
+                    let row = &mut guards[i * n + j..(i + 1) * n];
+                    for (active, item) in row.iter_mut().zip(&items[j..]) {
+                        *active = tr.is_active(item);
+                    }
+                    guards_from[i] = j;
+                }
+                guards[i * n + j]
+            });
+            self.eval_epsilons();
+            self.notify();
+            result.push(self.get_fstate());
+        }
+        self.guards = guards;
+        self.guards_from = guards_from;
+        self.check_invariant();
+    }
+
+    /* Borrowed outputs */
+    // The output after the last step, borrowed from the final state when
+    // it is the only one with a value; it is only computed (and owned) if
+    // several final states have values or a combining function is set.
+    // .init_ref() and .update_ref() are versions of .init() and .update()
+    // which return it, avoiding a clone of large outputs on every step.
+    pub fn output(&self) -> Cow<'_, Ext<Q>> {
+        if let Some(combine) = &self.combine {
+            let args: SmallVec<[Ext<&Q>; 4]> = self
+                .finals
+                .iter()
+                .map(|&id| self.states[id].as_ref())
+                .collect();
+            return Cow::Owned(combine(&args));
+        }
+        let mut nonempty =
+            self.finals.iter().filter(|&&id| !self.states[id].is_none());
+        match (nonempty.next(), nonempty.next()) {
+            (None, _) => Cow::Owned(Ext::None),
+            (Some(&id), None) => Cow::Borrowed(&self.states[id]),
+            // The sum of two or more values
+            (Some(_), Some(_)) => Cow::Owned(Ext::Many),
+        }
+    }
+    pub fn init_ref(&mut self, i: Ext<Q>) -> Cow<'_, Ext<Q>> {
+        self.step_init(i);
+        self.output()
+    }
+    pub fn update_ref(&mut self, item: &D) -> Cow<'_, Ext<Q>> {
+        self.step_update(item);
+        self.output()
+    }
+
+    /* Fallible versions of the above (used by DataTransducerBuilder) */
+    fn try_add_state_named(&mut self, name: &str) -> Result<usize, BuildError> {
+        if self.state_id(name).is_some() {
+            return Err(BuildError::DuplicateName { name: name.to_string() });
+        }
+        let id = self.add_state();
+        self.names[StateId(id)] = Some(name.to_string());
+        Ok(id)
+    }
+    fn try_name_state(
+        &mut self,
+        id: usize,
+        name: &str,
+    ) -> Result<(), BuildError> {
+        if id >= self.states.len() {
+            return Err(BuildError::NoSuchState {
+                id,
+                n_states: self.states.len(),
+            });
+        }
+        if self.state_id(name).is_some_and(|other| other != id) {
+            return Err(BuildError::DuplicateName { name: name.to_string() });
+        }
+        self.names[StateId(id)] = Some(name.to_string());
+        Ok(())
+    }
+    fn try_set_nstates(&mut self, n: usize) -> Result<(), BuildError> {
+        if n < self.states.len() {
+            return Err(BuildError::ShrinkStates {
+                current: self.states.len(),
+                requested: n,
+            });
+        }
+        while self.states.len() < n {
+            self.add_state();
+        }
+        Ok(())
+    }
+    fn try_set_priority(
+        &mut self,
+        tr: TransRef,
+        priority: i32,
+    ) -> Result<(), BuildError> {
+        match tr {
+            TransRef::Update(i) if i < self.updates.len() => {
+                self.updates[TransId(i)].priority = priority;
+                Ok(())
+            }
+            TransRef::Update(_) => Err(BuildError::NoSuchTransition {
+                trans: tr,
+                n_transs: self.updates.len(),
+            }),
+            TransRef::Epsilon(_) => {
+                Err(BuildError::EpsilonPriority { trans: tr })
+            }
+        }
+    }
+    fn try_subscribe<F>(
+        &mut self,
+        state: impl StateRef,
+        callback: F,
+    ) -> Result<(), BuildError>
+    where
+        F: 'a + Fn(&Ext<Q>),
+    {
+        let id = state.to_state_id(&self.names)?;
+        if !self.states.in_range(StateId(id)) {
+            return Err(BuildError::NoSuchState {
+                id,
+                n_states: self.states.len(),
+            });
+        }
+        let state = StateId(id);
+        self.subscriptions.push(Subscription {
+            state,
+            callback: Rc::new(callback),
+            was_some: !self.states[state].is_none(),
+        });
+        Ok(())
+    }
+    fn try_set_final_states<S: StateRef>(
+        &mut self,
+        states: &[S],
+    ) -> Result<(), BuildError> {
+        let mut finals = vec![];
+        for s in states {
+            let id = s.to_state_id(&self.names)?;
+            if !self.states.in_range(StateId(id)) {
+                return Err(BuildError::NoSuchState {
+                    id,
+                    n_states: self.states.len(),
+                });
+            }
+            finals.push(StateId(id));
+        }
+        self.finals = finals;
+        Ok(())
+    }
+    fn try_add_static_transition<S: StateRef>(
+        &mut self,
+        sources: &[S],
+        target: impl StateRef,
+        tr: U,
+    ) -> Result<TransRef, BuildError>
+    where
+        U: Sized,
+    {
+        if sources.len() != tr.arity() || sources.len() > MAX_ARITY {
+            return Err(BuildError::Arity {
+                arity: tr.arity(),
+                n_sources: sources.len(),
+            });
+        }
+        let ids = sources
+            .iter()
+            .map(|s| s.to_state_id(&self.names).map(StateId))
+            .collect::<Result<Vec<_>, _>>()?;
+        let target = self.resolve(target)?;
+        // U may define .is_active_in() (this is cheap to check, as the call
+        // is static)
+        self.state_guards = true;
+        self.push_update(Edge {
+            sources: Sources::new(&ids),
+            target,
+            tr: Rc::new(tr),
+            priority: 0,
+        })
+    }
+    fn try_add_epsilon_iden(
+        &mut self,
+        source: impl StateRef,
+        target: impl StateRef,
+    ) -> Result<TransRef, BuildError> {
+        let sources = Sources::new(&[self.resolve(source)?]);
+        let target = self.resolve(target)?;
+        self.add_epsilon_core(
+            sources,
+            target,
+            Iden { guard: epsilon_guard, ph_d: PhantomData, ph_q: PhantomData },
+        )
+    }
+    fn try_add_epsilon0<F>(
+        &mut self,
+        target: impl StateRef,
+        action: F,
+    ) -> Result<TransRef, BuildError>
+    where
+        F: 'a + Fn() -> Q,
+    {
+        let sources = Sources::new(&[]);
+        let target = self.resolve(target)?;
+        self.add_epsilon_core(
+            sources,
+            target,
+            Trans0 {
+                guard: epsilon_guard,
+                action: move |_| action(),
+                ph_d: PhantomData,
+                ph_q: PhantomData,
+            },
+        )
+    }
+    fn try_add_epsilon1<F>(
+        &mut self,
+        source: impl StateRef,
+        target: impl StateRef,
+        action: F,
+    ) -> Result<TransRef, BuildError>
+    where
+        F: 'a + Fn(&Q) -> Q,
+    {
+        let sources = Sources::new(&[self.resolve(source)?]);
+        let target = self.resolve(target)?;
+        self.add_epsilon_core(
+            sources,
+            target,
+            Trans1 {
+                guard: epsilon_guard,
+                action: move |_, q| action(q),
+                ph_d: PhantomData,
+                ph_q: PhantomData,
+            },
+        )
+    }
+    fn try_add_epsilon2<F>(
+        &mut self,
+        source1: impl StateRef,
+        source2: impl StateRef,
+        target: impl StateRef,
+        action: F,
+    ) -> Result<TransRef, BuildError>
+    where
+        F: 'a + Fn(&Q, &Q) -> Q,
+    {
+        let sources =
+            Sources::new(&[self.resolve(source1)?, self.resolve(source2)?]);
+        let target = self.resolve(target)?;
+        self.add_epsilon_core(
+            sources,
+            target,
+            Trans2 {
+                guard: epsilon_guard,
+                action: move |_, q1, q2| action(q1, q2),
+                ph_d: PhantomData,
+                ph_q: PhantomData,
+            },
+        )
+    }
+    fn try_remove_transition(
+        &mut self,
+        tr: TransRef,
+    ) -> Result<(), BuildError> {
+        match tr {
+            TransRef::Update(i) if i < self.updates.len() => {
+                self.updates.remove(i);
+                self.rebuild_indices();
+            }
+            TransRef::Epsilon(i) if i < self.epsilons.len() => {
+                self.epsilons.remove(i);
+                self.eps_vals.remove(i);
+                self.rebuild_indices();
+            }
+            TransRef::Update(_) => {
+                return Err(BuildError::NoSuchTransition {
+                    trans: tr,
+                    n_transs: self.updates.len(),
+                })
+            }
+            TransRef::Epsilon(_) => {
+                return Err(BuildError::NoSuchTransition {
+                    trans: tr,
+                    n_transs: self.epsilons.len(),
+                })
+            }
+        }
+        self.check_invariant();
+        Ok(())
+    }
+    fn try_remove_state(
+        &mut self,
+        id: impl StateRef,
+    ) -> Result<(), BuildError> {
+        let id = id.to_state_id(&self.names)?;
+        let sid = StateId(id);
+        if sid == ISTATE_ID || sid == FSTATE_ID || self.is_observed(sid) {
+            return Err(BuildError::RemoveReserved { id });
+        }
+        if !self.states.in_range(sid) {
+            return Err(BuildError::NoSuchState {
+                id,
+                n_states: self.states.len(),
+            });
+        }
+        // Remove all transitions which use the state
+        self.updates.retain(|tr| !tr.all_ids().any(|s| s == sid));
+        let keep: Vec<bool> = self
+            .epsilons
+            .iter()
+            .map(|tr| !tr.all_ids().any(|s| s == sid))
+            .collect();
+        let mut keep_iter = keep.iter();
+        self.epsilons.retain(|_| *keep_iter.next().unwrap());
+        let mut keep_iter = keep.iter();
+        self.eps_vals.retain(|_| *keep_iter.next().unwrap());
+        // Remove the state and renumber the ones after it
+        self.states.remove(id);
+        self.next_states.remove(id);
+        self.eps_out.remove(id);
+        self.names.remove(id);
+        let shift = |s: StateId| if s.0 > id { StateId(s.0 - 1) } else { s };
+        for tr in self.updates.iter_mut() {
+            tr.map_ids(&shift);
+        }
+        for tr in self.epsilons.iter_mut() {
+            tr.map_ids(&shift);
+        }
+        for f in self.finals.iter_mut() {
+            *f = shift(*f);
+        }
+        for sub in self.subscriptions.iter_mut() {
+            sub.state = shift(sub.state);
+        }
+        self.rebuild_indices();
+        self.check_invariant();
+        Ok(())
+    }
+
+    /* Utility / conveniences */
+    fn add_to_istate(&mut self, i: Ext<Q>) {
+        self.states[ISTATE_ID] += i
+    }
+    // The steps of .init() and .update(), without computing the output
+    fn step_init(&mut self, i: Ext<Q>) {
+        let input = self.recorder.as_ref().map(|_| i.clone());
+        if !i.is_none() {
+            self.changed.push(ISTATE_ID);
+        }
+        self.add_to_istate(i);
+        self.eval_epsilons();
+        self.notify();
+        self.check_invariant();
+        if let Some(i) = input {
+            let changed = if i.is_none() { vec![] } else { vec![ISTATE_ID] };
+            self.record_step(RInput::Restart(i), changed);
+        }
+    }
+    fn step_update(&mut self, item: &D) {
+        let before = self.recorder.as_ref().map(|rec| {
+            let input = RInput::Item((rec.clone_item)(item));
+            let nonempty =
+                self.states.enumerate().filter(|(_, q)| !q.is_none());
+            (input, nonempty.map(|(id, _)| id).collect::<Vec<_>>())
+        });
+        self.eval_updates(item);
+        self.eval_epsilons();
+        self.notify();
+        self.check_invariant();
+        if let Some((input, mut changed)) = before {
+            // States which had a value before may have lost it
+            for (id, q) in self.states.enumerate() {
+                if !q.is_none() {
+                    changed.push(id);
+                }
+            }
+            self.record_step(input, changed);
+        }
+    }
+    fn get_fstate(&self) -> Ext<Q> {
+        self.output().into_owned()
+    }
+    fn is_final(&self, id: StateId) -> bool {
+        self.finals.contains(&id)
+    }
+    // Final states and those with subscriptions
+    fn is_observed(&self, id: StateId) -> bool {
+        self.is_final(id) || self.subscriptions.iter().any(|s| s.state == id)
+    }
+    fn notify(&mut self) {
+        for sub in self.subscriptions.iter_mut() {
+            let value = &self.states[sub.state];
+            if !value.is_none() && !sub.was_some {
+                (sub.callback)(value);
+            }
+            sub.was_some = !value.is_none();
+        }
+    }
+    fn record_step(
+        &mut self,
+        input: RInput<Ext<Q>, D>,
+        mut changed: Vec<StateId>,
+    ) {
+        // Log a step which changed the given states, as well as the targets
+        // of the transitions fired
+        let output = self.get_fstate();
+        let rec = self.recorder.as_mut().unwrap();
+        let fired = mem::take(&mut rec.fired);
+        for &tr in &fired {
+            changed.push(StateId(match tr {
+                TransRef::Update(i) => self.updates[TransId(i)].target.0,
+                TransRef::Epsilon(i) => self.epsilons[TransId(i)].target.0,
+            }));
+        }
+        let mut changed: Vec<usize> = changed.iter().map(|id| id.0).collect();
+        changed.sort_unstable();
+        changed.dedup();
+        let states = &self.states;
+        let changed =
+            changed.into_iter().map(|id| (id, states[StateId(id)].clone()));
+        rec.steps.push(StepRecord {
+            input,
+            fired,
+            changed: changed.collect(),
+            output,
+        });
+    }
+    fn rebuild_indices(&mut self) {
+        // Recompute eps_out from scratch, and invalidate the cached epsilon
+        // schedule and update dispatch (after transitions are removed or
+        // renumbered)
+        for ids in self.eps_out.iter_mut() {
+            ids.clear();
+        }
+        self.eps_nullary.clear();
+        for (i, tr) in self.epsilons.iter().enumerate() {
+            for &source_id in tr.source_ids() {
+                self.eps_out[source_id].push(TransId(i));
+            }
+            if tr.source_ids().is_empty() {
+                self.eps_nullary.push(TransId(i));
+            }
+        }
+        self.eps_schedule = EpsSchedule::Stale;
+        if let Some(dispatch) = &mut self.dispatch {
+            dispatch.classes.clear();
+        }
+        if self.stats.is_some() {
+            self.enable_stats();
+        }
+    }
+    fn resolve(&self, s: impl StateRef) -> Result<StateId, BuildError> {
+        s.to_state_id(&self.names).map(StateId)
+    }
+    fn state_label(&self, id: StateId) -> String {
+        match &self.names[id] {
+            Some(name) => name.clone(),
+            None => id.0.to_string(),
+        }
+    }
+    fn trans_label<Tr: ?Sized>(&self, tr: &Edge<Tr>) -> String {
+        let mut result = "[".to_string();
+        for &id in tr.source_ids() {
+            result.push_str(&self.state_label(id));
+            result.push(' ');
+        }
+        result.push_str("-> ");
+        result.push_str(&self.state_label(tr.target_id()));
+        result.push(']');
+        result
+    }
+    fn epsilon_cycle(&self) -> Option<StateId> {
+        // Find a state on a cycle of epsilon transitions, if any
+        // (depth-first search; 0 = unvisited, 1 = on stack, 2 = done)
+        let n = self.states.len();
+        let mut color = StateList(vec![0u8; n]);
+        for root in (0..n).map(StateId) {
+            if color[root] != 0 {
+                continue;
+            }
+            let mut stack: Vec<(StateId, usize)> = vec![(root, 0)];
+            color[root] = 1;
+            while let Some(&mut (st, ref mut next)) = stack.last_mut() {
+                match self.eps_out[st].get(*next) {
+                    Some(&tid) => {
+                        *next += 1;
+                        let tgt = self.epsilons[tid].target;
+                        if color[tgt] == 1 {
+                            return Some(tgt);
+                        } else if color[tgt] == 0 {
+                            color[tgt] = 1;
+                            stack.push((tgt, 0));
+                        }
+                    }
+                    None => {
+                        color[st] = 2;
+                        stack.pop();
+                    }
+                }
+            }
+        }
+        None
+    }
+    fn compute_eps_schedule(&self) -> EpsSchedule {
+        // Order the states topologically w.r.t. epsilon-transitions (Kahn's
+        // algorithm), then order each epsilon-transition by the latest of its
+        // sources. Any transition whose target feeds into another transition
+        // then comes before it.
+        let n = self.states.len();
+        let mut in_degree = StateList(vec![0usize; n]);
+        for tr in self.epsilons.iter() {
+            in_degree[tr.target_id()] += tr.source_ids().len();
+        }
+        let mut ready: Vec<StateId> =
+            (0..n).map(StateId).filter(|&id| in_degree[id] == 0).collect();
+        let mut position = StateList(vec![0usize; n]);
+        let mut n_sorted = 0;
+        while let Some(id) = ready.pop() {
+            position[id] = n_sorted;
+            n_sorted += 1;
+            for &tid in &self.eps_out[id] {
+                let tgt = self.epsilons[tid].target_id();
+                in_degree[tgt] -= 1;
+                if in_degree[tgt] == 0 {
+                    ready.push(tgt);
+                }
+            }
+        }
+        if n_sorted < n {
+            return EpsSchedule::Cyclic;
+        }
+        let mut order: Vec<TransId> =
+            (0..self.epsilons.len()).map(TransId).collect();
+        order.sort_by_key(|&tid| {
+            let sources = self.epsilons[tid].source_ids();
+            sources.iter().map(|&s| position[s] + 1).max().unwrap_or(0)
+        });
+        EpsSchedule::Ordered(order)
+    }
+    fn eval_epsilon(&self, tid: TransId) -> Ext<Q> {
+        self.epsilons[tid].eval(&(), &self.states)
+    }
+    fn add_epsilon_core<Tr>(
+        &mut self,
+        sources: Sources,
+        target: StateId,
+        tr: Tr,
+    ) -> Result<TransRef, BuildError>
+    where
+        Tr: 'a + Transition<(), Q>,
+    {
+        self.push_epsilon(Edge {
+            sources,
+            target,
+            tr: Rc::new(tr),
+            priority: 0,
+        })
+    }
+    fn push_update(&mut self, edge: Edge<U>) -> Result<TransRef, BuildError> {
+        self.trans_precond(&edge)?;
+        self.updates.push(edge);
+        if let Some(dispatch) = &mut self.dispatch {
+            dispatch.classes.clear();
+        }
+        if let Some(stats) = &mut self.stats {
+            stats.updates.push(Default::default());
+        }
+        self.check_invariant();
+        Ok(TransRef::Update(self.updates.len() - 1))
+    }
+    fn push_epsilon(
+        &mut self,
+        edge: Edge<dyn Transition<(), Q> + 'a>,
+    ) -> Result<TransRef, BuildError> {
+        self.trans_precond(&edge)?;
+        let new_tr_id = TransId(self.epsilons.len());
+        for &source_id in edge.source_ids() {
+            self.eps_out[source_id].push(new_tr_id);
+        }
+        if edge.source_ids().is_empty() {
+            self.eps_nullary.push(new_tr_id);
+        }
+        self.epsilons.push(edge);
+        self.eps_vals.push(Ext::None);
+        self.eps_schedule = EpsSchedule::Stale;
+        if let Some(stats) = &mut self.stats {
+            stats.epsilons.push(Default::default());
+        }
+        self.check_invariant();
+        Ok(TransRef::Epsilon(new_tr_id.0))
+    }
+
+    /* Invariant checks and preconditions */
+    // Check the internal invariants (see InvariantViolation), returning all
+    // the violations found
+    pub fn validate(&self) -> Result<(), Vec<InvariantViolation>> {
+        use InvariantViolation::*;
+        let mut errs = vec![];
+        let n_states = self.states.len();
+        if n_states < 2 {
+            errs.push(TooFewStates { n_states });
+        }
+        let mut table = |table, expected, found| {
+            if expected != found {
+                errs.push(TableLength { table, expected, found });
+            }
+        };
+        table("next_states", n_states, self.next_states.len());
+        table("eps_out", n_states, self.eps_out.len());
+        table("names", n_states, self.names.len());
+        table("eps_vals", self.epsilons.len(), self.eps_vals.len());
+        if let Some(stats) = &self.stats {
+            table("stats.updates", self.updates.len(), stats.updates.len());
+            table("stats.epsilons", self.epsilons.len(), stats.epsilons.len());
+        }
+        table(
+            "eps_nullary",
+            self.epsilons
+                .iter()
+                .filter(|eps| eps.source_ids().is_empty())
+                .count(),
+            self.eps_nullary.len(),
+        );
+        table(
+            "eps_out entries",
+            self.epsilons.iter().map(|eps| eps.source_ids().len()).sum(),
+            self.eps_out.iter().map(|ids| ids.len()).sum::<usize>(),
+        );
+        for (i, q) in self.next_states.iter().enumerate() {
+            if !q.is_none() {
+                errs.push(PendingValue { state: i });
+            }
+        }
+        // (Without collecting the ids, as this runs on every step in debug
+        // builds, which should not allocate)
+        let mut trans_ids = |trans, ids: &mut dyn Iterator<Item = StateId>| {
+            for id in ids.filter(|&id| id.0 >= n_states) {
+                errs.push(TransState { trans, state: id.0, n_states });
+            }
+        };
+        for (i, tr) in self.updates.iter().enumerate() {
+            trans_ids(TransRef::Update(i), &mut tr.all_ids());
+        }
+        for (i, tr) in self.epsilons.iter().enumerate() {
+            trans_ids(TransRef::Epsilon(i), &mut tr.all_ids());
+        }
+        for id in self.finals.iter().filter(|&&id| id.0 >= n_states) {
+            errs.push(FinalState { state: id.0, n_states });
+        }
+        for sub in self.subscriptions.iter().filter(|s| s.state.0 >= n_states) {
+            errs.push(SubscribedState { state: sub.state.0, n_states });
+        }
+        for (state_id, eps_ids) in self.eps_out.enumerate() {
+            for &id in eps_ids {
+                let ok =
+                    self.epsilons.0.get(id.0).is_some_and(|eps| {
+                        eps.source_ids().contains(&state_id)
+                    });
+                if !ok {
+                    let trans = TransRef::Epsilon(id.0);
+                    errs.push(EpsilonIndex { state: state_id.0, trans });
+                }
+            }
+        }
+        if errs.is_empty() {
+            Ok(())
+        } else {
+            Err(errs)
+        }
+    }
+    // Set when the invariants are checked (see Validation)
+    pub fn set_validation(&mut self, mode: Validation) {
+        self.validation = mode;
+    }
+    // The invariant violations found so far, with Validation::Record
+    pub fn diagnostics(&self) -> &[InvariantViolation] {
+        &self.diagnostics
+    }
+    pub fn clear_diagnostics(&mut self) {
+        self.diagnostics.clear();
+    }
+    fn check_invariant(&mut self) {
+        let check = match self.validation {
+            Validation::Off => false,
+            Validation::Debug => cfg!(debug_assertions),
+            Validation::Panic | Validation::Record => true,
+        };
+        if !check {
+            return;
+        }
+        if let Err(errs) = self.validate() {
+            if self.validation == Validation::Record {
+                self.diagnostics.extend(errs);
+            } else {
+                invariant_panic(&errs);
+            }
+        }
+    }
+    fn trans_precond<Tr: ?Sized>(
+        &self,
+        tr: &Edge<Tr>,
+    ) -> Result<(), BuildError> {
+        // PRECONDITION for add_transition() and add_epsilon():
+        // transition sources and targets must
+        // already have been added to the machine.
+        match tr.all_ids().find(|&id| !self.states.in_range(id)) {
+            Some(id) => Err(BuildError::NoSuchState {
+                id: id.0,
+                n_states: self.states.len(),
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /* Streaming Algorithm */
+    fn eval_epsilons(&mut self) {
+        // The main streaming algorithm for updating the data transducer
+        // following least-fixed-point semantics. When there are no epsilon
+        // cycles, a single pass in topological order computes the fixed
+        // point; otherwise it is computed using a transition worklist.
+        // Only epsilons downstream of the changed states (plus those with
+        // no sources) can contribute anything new, so only those are
+        // evaluated. The changed states are taken from self.changed, which
+        // is left empty.
+        if let EpsSchedule::Stale = self.eps_schedule {
+            self.eps_schedule = self.compute_eps_schedule();
+        }
+        match mem::replace(&mut self.eps_schedule, EpsSchedule::Stale) {
+            EpsSchedule::Ordered(order) => {
+                let mut dirty = mem::take(&mut self.dirty);
+                dirty.clear();
+                dirty.resize(self.states.len(), false);
+                for id in self.changed.drain(..) {
+                    dirty[id.0] = true;
+                }
+                for &tr_id in &order {
+                    let sources = self.epsilons[tr_id].source_ids();
+                    let active = sources.is_empty()
+                        || sources.iter().any(|&s| dirty[s.0]);
+                    if active && self.eval_epsilon_step(tr_id) {
+                        dirty[self.epsilons[tr_id].target_id().0] = true;
+                    }
+                }
+                self.dirty = dirty;
+                self.eps_schedule = EpsSchedule::Ordered(order);
+            }
+            schedule => {
+                self.eps_schedule = schedule;
+                self.eval_epsilons_worklist();
+            }
+        }
+    }
+    fn eval_epsilon_step(&mut self, tr_id: TransId) -> bool {
+        // Evaluate a single epsilon-transition, adding its value to the
+        // target; returns whether the target changed
+        let cur = self.eps_vals[tr_id];
+        let tgt_id = self.epsilons[tr_id].target_id();
+        // Only evaluate the transition if its value may cause a change
+        if cur.is_many() || self.states[tgt_id].is_many() {
+            return false;
+        }
+        let new = self.eval_epsilon(tr_id);
+        if let Some(stats) = &mut self.stats {
+            stats.epsilons[tr_id].active += 1;
+            stats.epsilons[tr_id].produced += !new.is_none() as u64;
+        }
+        if new.is_none() || new.is_one() && cur.is_one() {
+            return false;
+        }
+        if let Some(rec) = &mut self.recorder {
+            rec.fired.push(TransRef::Epsilon(tr_id.0));
+        }
+        // Here we know: the value of the transition has increased
+        // (from None to One(x), None to Many, or One(x) to Many)
+        // AND the target state is either None or One(x), so should
+        // be increased by One(x), Many, or Many respectively
+        self.eps_vals[tr_id] = new.to_unit();
+        self.states[tgt_id] += new;
+        true
+    }
+    fn eval_epsilons_worklist(&mut self) {
+        // Note on efficiency: it is slightly more efficient to also
+        // keep a count of how many input states are Ext::None for each
+        // transition, and only add a transition to the worklist when this
+        // number increases. But this only really matters for transitions with
+        // more than one or two source states.
+        let mut trans_wklist = mem::take(&mut self.eps_wklist);
+        trans_wklist.clear();
+        trans_wklist.extend_from_slice(&self.eps_nullary);
+        for id in self.changed.drain(..) {
+            trans_wklist.extend_from_slice(&self.eps_out[id]);
+        }
+        while let Some(tr_id) = trans_wklist.pop() {
+            if self.eval_epsilon_step(tr_id) {
+                let tgt_id = self.epsilons[tr_id].target_id();
+                trans_wklist.extend_from_slice(&self.eps_out[tgt_id]);
+            }
+        }
+        self.eps_wklist = trans_wklist;
+    }
+    fn eval_updates(&mut self, item: &D) {
+        // The update logic prior to evaluating epsilons -- not as complex
+        // as eval_epsilons() as here we assume updates only take old states
+        // and return new states.
+        // Records in self.changed the states which are not None afterwards
+        // (all others are None, so epsilons out of them cannot fire).
+        // (The updates are independent, so they can also be evaluated in
+        // parallel; see .update_par().)
+        self.start_updates();
+        let mut written = match self.policy {
+            ConflictPolicy::Union => None,
+            ConflictPolicy::HighestPriority => {
+                Some(self.written.as_mut_slice())
+            }
+        };
+        match self.dispatch.take() {
+            Some(mut dispatch) => {
+                let k = (dispatch.key)(item);
+                if k >= dispatch.classes.len() {
+                    dispatch.classes.resize(k + 1, None);
+                }
+                let updates = &self.updates;
+                let stats = &mut self.stats;
+                let active = dispatch.classes[k].get_or_insert_with(|| {
+                    (0..updates.len())
+                        .map(TransId)
+                        .filter(|&tid| {
+                            if let Some(stats) = stats {
+                                stats.updates[tid].guard_evals += 1;
+                            }
+                            updates[tid].is_active(item)
+                        })
+                        .collect()
+                });
+                for &tid in active.iter() {
+                    if self.state_guards
+                        && !self.updates[tid].is_active_in(item, &self.states)
+                    {
+                        continue;
+                    }
+                    let produced = Self::fire_update(
+                        &self.updates[tid],
+                        item,
+                        &self.states,
+                        &mut self.next_states,
+                        &mut self.changed,
+                        &mut self.emitted,
+                        written.as_deref_mut(),
+                    );
+                    if let Some(stats) = &mut self.stats {
+                        stats.updates[tid].active += 1;
+                        stats.updates[tid].produced += produced as u64;
+                    }
+                    if let (Some(rec), true) = (&mut self.recorder, produced) {
+                        rec.fired.push(TransRef::Update(tid.0));
+                    }
+                }
+                self.dispatch = Some(dispatch);
+            }
+            None => {
+                for (i, tr) in self.updates.iter().enumerate() {
+                    let active = tr.is_active(item)
+                        && (!self.state_guards
+                            || tr.is_active_in(item, &self.states));
+                    let produced = active
+                        && Self::fire_update(
+                            tr,
+                            item,
+                            &self.states,
+                            &mut self.next_states,
+                            &mut self.changed,
+                            &mut self.emitted,
+                            written.as_deref_mut(),
+                        );
+                    if let Some(stats) = &mut self.stats {
+                        let stats = &mut stats.updates[TransId(i)];
+                        stats.guard_evals += 1;
+                        stats.active += active as u64;
+                        stats.produced += produced as u64;
+                    }
+                    if let (Some(rec), true) = (&mut self.recorder, produced) {
+                        rec.fired.push(TransRef::Update(i));
+                    }
+                }
+            }
+        }
+        self.finish_updates();
+    }
+    fn fire_update(
+        tr: &Edge<U>,
+        item: &D,
+        states: &StateList<Ext<Q>>,
+        next_states: &mut StateList<Ext<Q>>,
+        changed: &mut Vec<StateId>,
+        emitted: &mut Option<Vec<Ext<Q>>>,
+        written: Option<&mut [Option<i32>]>,
+    ) -> bool {
+        // Returns whether the transition produced a value (which was not
+        // discarded due to its priority)
+        if Self::is_overridden(tr, written.as_deref()) {
+            // (Checked first, to save evaluating the transition)
+            return false;
+        }
+        let new = tr.eval(item, states);
+        Self::merge_update(
+            tr,
+            item,
+            new,
+            next_states,
+            changed,
+            emitted,
+            written,
+        )
+    }
+    // Whether a transition of higher priority already wrote the target
+    fn is_overridden(tr: &Edge<U>, written: Option<&[Option<i32>]>) -> bool {
+        written.is_some_and(|written| {
+            written[tr.target_id().0].is_some_and(|p| p > tr.priority)
+        })
+    }
+    fn merge_update(
+        tr: &Edge<U>,
+        item: &D,
+        new: Ext<Q>,
+        next_states: &mut StateList<Ext<Q>>,
+        changed: &mut Vec<StateId>,
+        emitted: &mut Option<Vec<Ext<Q>>>,
+        written: Option<&mut [Option<i32>]>,
+    ) -> bool {
+        // Add the value produced by a transition to its target (see
+        // .fire_update())
+        if Self::is_overridden(tr, written.as_deref()) {
+            return false;
+        }
+        let tgt_id = tr.target_id();
+        let produced = !new.is_none();
+        if let (Some(emitted), true) = (emitted, produced) {
+            tr.tr.emit(item, &new, emitted);
+        }
+        if next_states[tgt_id].is_none() && produced {
+            changed.push(tgt_id);
+        }
+        if let (Some(written), true) = (written, produced) {
+            // Override the values of lower priority
+            if written[tgt_id.0].is_some_and(|p| p < tr.priority) {
+                next_states[tgt_id] = Ext::None;
+            }
+            written[tgt_id.0] = Some(tr.priority);
+        }
+        next_states[tgt_id] += new;
+        produced
+    }
+    fn eval_updates_guarded<G>(&mut self, item: &D, mut guard: G)
+    where
+        G: FnMut(usize, &Edge<U>) -> bool,
+    {
+        // As .eval_updates() without dispatch, profiling, or recording,
+        // where guard(i, tr) is whether update transition i is active on the
+        // item; it is not called for transitions whose source states all
+        // have no value (these can't fire)
+        self.start_updates();
+        let mut written = match self.policy {
+            ConflictPolicy::Union => None,
+            ConflictPolicy::HighestPriority => {
+                Some(self.written.as_mut_slice())
+            }
+        };
+        let states = &self.states;
+        for (i, tr) in self.updates.iter().enumerate() {
+            let sources = tr.source_ids();
+            if !sources.is_empty()
+                && sources.iter().all(|&s| states[s].is_none())
+            {
+                continue;
+            }
+            if guard(i, tr)
+                && (!self.state_guards || tr.is_active_in(item, &self.states))
+            {
+                Self::fire_update(
+                    tr,
+                    item,
+                    &self.states,
+                    &mut self.next_states,
+                    &mut self.changed,
+                    &mut self.emitted,
+                    written.as_deref_mut(),
+                );
+            }
+        }
+        self.finish_updates();
+    }
+    // Before and after evaluating the update transitions on an item: clear
+    // the scratch buffers, then move the new states in
+    fn start_updates(&mut self) {
+        self.changed.clear();
+        if let Some(emitted) = &mut self.emitted {
+            emitted.clear();
+        }
+        if self.policy == ConflictPolicy::HighestPriority {
+            self.written.clear();
+            self.written.resize(self.states.len(), None);
+        }
+    }
+    fn finish_updates(&mut self) {
+        mem::swap(&mut self.states, &mut self.next_states);
+        for state in self.next_states.iter_mut() {
+            *state = Ext::None;
+        }
+        self.clear_eps_vals();
+    }
+    fn clear_eps_vals(&mut self) {
+        for val in self.eps_vals.iter_mut() {
+            *val = Ext::None;
+        }
+    }
+}
+
+/*
+    Parallel evaluation of the update transitions (with the "parallel"
+    feature).
+
+    The update transitions on an item only read the old states, and their
+    values are added to their targets with the commutative +=, so they can
+    be evaluated independently. .update_par() is the same as .update(), but
+    evaluates the guards and actions of the update transitions in parallel
+    on rayon's thread pool; the values produced are then added to their
+    targets in the order of the transitions (under the conflict policy,
+    and with their outputs emitted), so that the results are the same as
+    with .update() (as long as the guards and actions are pure).
+
+    Closures in the default transitions are neither Send nor Sync, so this
+    is for machines whose update transitions are given statically (see
+    .add_static_transition()), by a type U which is Sync. Splitting each
+    item across threads has a cost, so this only pays off for machines
+    with many update transitions (e.g. thousands, compiled from large
+    patterns), or expensive ones. Dispatch is not used (all the guards are
+    evaluated); with profiling or recording enabled, it falls back to
+    .update().
+*/
+
+#[cfg(feature = "parallel")]
+impl<'a, D, Q, U> DataTransducer<'a, D, Q, U>
+where
+    D: Sync,
+    Q: Clone + Send + Sync,
+    U: 'a + ?Sized + Transition<D, Q> + Sync,
+{
+    pub fn update_par(&mut self, item: &D) -> Ext<Q> {
+        if self.stats.is_some() || self.recorder.is_some() {
+            return self.update(item);
+        }
+        self.eval_updates_par(item);
+        self.eval_epsilons();
+        self.notify();
+        self.check_invariant();
+        self.get_fstate()
+    }
+    fn eval_updates_par(&mut self, item: &D) {
+        // The edges hold their transitions in an Rc, which can't be shared
+        // between threads, but the transitions themselves can
+        let edges: Vec<(&U, &[StateId])> =
+            self.updates.iter().map(|tr| (&*tr.tr, &*tr.sources)).collect();
+        let states = &self.states;
+        let state_guards = self.state_guards;
+        let produced: Vec<(usize, Ext<Q>)> = edges
+            .par_iter()
+            .enumerate()
+            .filter_map(|(i, &(tr, sources))| {
+                let active = tr.is_active(item)
+                    && (!state_guards || tr.is_active_in(item, states));
+                let new = if active {
+                    apply_to(tr, sources, item, states)
+                } else {
+                    Ext::None
+                };
+                Some((i, new)).filter(|(_, new)| !new.is_none())
+            })
+            .collect();
+        // Add the values to the targets, in order
+        self.start_updates();
+        let mut written = match self.policy {
+            ConflictPolicy::Union => None,
+            ConflictPolicy::HighestPriority => {
+                Some(self.written.as_mut_slice())
+            }
+        };
+        for (i, new) in produced {
+            Self::merge_update(
+                &self.updates[TransId(i)],
+                item,
+                new,
+                &mut self.next_states,
+                &mut self.changed,
+                &mut self.emitted,
+                written.as_deref_mut(),
+            );
+        }
+        self.finish_updates();
+    }
+}
+
+impl<'a, D, Q, U> Transducer<Q, D, Q> for DataTransducer<'a, D, Q, U>
+where
+    Q: Clone,
+    U: 'a + ?Sized + Transition<D, Q>,
+{
+    fn init(&mut self, i: Ext<Q>) -> Ext<Q> {
+        self.step_init(i);
+        self.get_fstate()
+    }
+    fn update(&mut self, item: &D) -> Ext<Q> {
+        self.step_update(item);
+        self.get_fstate()
+    }
+    fn update_batch(&mut self, items: &[D]) -> Vec<Ext<Q>> {
+        // Same as .update() on each item (including the notifications of
+        // subscribers), in chunks (see .update_chunk()), with the outputs
+        // pushed onto a single vector
+        let mut result = Vec::with_capacity(items.len());
+        for chunk in items.chunks(CHUNK_SIZE) {
+            self.update_chunk_into(chunk, &mut result);
+        }
+        result
+    }
+    fn reset(&mut self) {
+        for state in self.states.iter_mut() {
+            *state = Ext::None;
+        }
+        self.clear_eps_vals();
+        if let Some(emitted) = &mut self.emitted {
+            emitted.clear();
+        }
+        for sub in self.subscriptions.iter_mut() {
+            sub.was_some = false;
+        }
+        self.check_invariant();
+    }
+
+    fn is_epsilon(&self) -> bool {
+        // The transducer is an epsilon if it only has epsilon transitions
+        self.updates.is_empty()
+    }
+    fn is_restartable(&self) -> bool {
+        // TODO: we could implement the decision procedure for this, but it is
+        // rather complex (PSPACE-complete).
+        // For a bounded approximation, see .is_restartable_bounded().
+        unimplemented!()
+    }
+    fn is_nullable(&self) -> bool {
+        // Nullable if a final state is reachable from the initial state
+        // using only epsilon transitions (all of whose sources are reached)
+        let mut reached = StateList(vec![false; self.states.len()]);
+        reached[ISTATE_ID] = true;
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for tr in self.epsilons.iter() {
+                let tgt = tr.target_id();
+                if !reached[tgt] && tr.source_ids().iter().all(|&s| reached[s])
+                {
+                    reached[tgt] = true;
+                    changed = true;
+                }
+            }
+        }
+        self.finals.iter().any(|&id| reached[id])
+    }
+    fn n_states(&self) -> usize {
+        debug_assert!(self.states.len() >= 2);
+        self.states.len()
+    }
+    fn n_transs(&self) -> usize {
+        self.updates.len() + self.epsilons.len()
+    }
+    fn n_bytes(&self) -> usize {
+        // Sum the allocated capacity of each list, plus the transition objects
+        // themselves (which are boxed separately)
+        let states = (self.states.capacity() + self.next_states.capacity())
+            * mem::size_of::<Ext<Q>>();
+        let updates = self.updates.capacity() * mem::size_of::<Edge<U>>()
+            + self.updates.iter().map(|tr| tr.heap_bytes()).sum::<usize>();
+        let epsilons = self.epsilons.capacity()
+            * mem::size_of::<Edge<dyn Transition<(), Q>>>()
+            + self.epsilons.iter().map(|tr| tr.heap_bytes()).sum::<usize>();
+        let eps_out = self.eps_out.capacity() * mem::size_of::<EpsOut>()
+            + self
+                .eps_out
+                .iter()
+                .filter(|ids| ids.spilled())
+                .map(|ids| ids.capacity() * mem::size_of::<TransId>())
+                .sum::<usize>()
+            + self.eps_nullary.capacity() * mem::size_of::<TransId>();
+        let eps_vals = self.eps_vals.capacity() * mem::size_of::<Ext<()>>();
+        let eps_schedule = match &self.eps_schedule {
+            EpsSchedule::Ordered(order) => {
+                order.capacity() * mem::size_of::<TransId>()
+            }
+            _ => 0,
+        };
+        let dispatch = self.dispatch.as_ref().map_or(0, |dispatch| {
+            dispatch.classes.capacity() * mem::size_of::<Option<Vec<TransId>>>()
+                + dispatch
+                    .classes
+                    .iter()
+                    .flatten()
+                    .map(|ids| ids.capacity() * mem::size_of::<TransId>())
+                    .sum::<usize>()
+        });
+        let stats = self.stats.as_ref().map_or(0, |stats| {
+            (stats.updates.capacity() + stats.epsilons.capacity())
+                * mem::size_of::<FiringStats>()
+        });
+        let names = self.names.capacity() * mem::size_of::<Option<String>>()
+            + self
+                .names
+                .iter()
+                .map(|name| name.as_ref().map_or(0, |name| name.capacity()))
+                .sum::<usize>();
+        mem::size_of::<Self>()
+            + states
+            + updates
+            + epsilons
+            + eps_out
+            + eps_vals
+            + eps_schedule
+            + dispatch
+            + stats
+            + names
+    }
+}
+
+/*
+    Rate NFA (see rate.rs)
+*/
+
+impl<'a, D, Q, U> Rate<D> for DataTransducer<'a, D, Q, U>
+where
+    Q: Clone,
+    U: 'a + ?Sized + Transition<D, Q>,
+{
+    // The DFA on the sets of states with a value, from {initial state}
+    fn rate_nfa(&self, alphabet: &[D]) -> RateNfa {
+        let n = self.states.len();
+        // Add the targets of the epsilons whose sources are all in the set
+        let closure = |mut set: StateList<bool>| {
+            let mut changed = true;
+            while changed {
+                changed = false;
+                for tr in self.epsilons.iter() {
+                    if !set[tr.target]
+                        && tr.source_ids().iter().all(|&s| set[s])
+                    {
+                        set[tr.target] = true;
+                        changed = true;
+                    }
+                }
+            }
+            set
+        };
+        let mut start = StateList(vec![false; n]);
+        start[ISTATE_ID] = true;
+        let mut nfa = RateNfa::new(alphabet.len());
+        let mut ids = HashMap::new();
+        let mut sets = vec![closure(start)];
+        ids.insert(sets[0].0.clone(), nfa.add_state());
+        nfa.set_initial(0);
+        while let Some(set) = sets.pop() {
+            let id = ids[&set.0];
+            if self.finals.iter().any(|&f| set[f]) {
+                nfa.set_final(id);
+            }
+            for (a, d) in alphabet.iter().enumerate() {
+                let mut next = StateList(vec![false; n]);
+                for tr in self.updates.iter() {
+                    if tr.tr.is_active(d)
+                        && tr.source_ids().iter().all(|&s| set[s])
+                    {
+                        next[tr.target] = true;
+                    }
+                }
+                let next = closure(next);
+                let next_id = match ids.get(&next.0) {
+                    Some(&next_id) => next_id,
+                    None => {
+                        let next_id = nfa.add_state();
+                        ids.insert(next.0.clone(), next_id);
+                        sets.push(next);
+                        next_id
+                    }
+                };
+                nfa.add_transition(id, a, next_id);
+            }
+        }
+        nfa
+    }
+}
+
+/*
+    Update transitions given by closures (the default).
+*/
+
+impl<'a, D, Q> DataTransducer<'a, D, Q>
+where
+    Q: Clone,
+{
+    pub fn new() -> Self {
+        Default::default()
+    }
+    // Add an update transition with no source states, which sets the target
+    // whenever the guard holds (independently of the current state)
+    pub fn add_transition0<G, F>(
+        &mut self,
+        target: impl StateRef,
+        guard: G,
+        action: F,
+    ) -> TransRef
+    where
+        G: 'a + Fn(&D) -> bool,
+        F: 'a + Fn(&D) -> Q,
+    {
+        self.try_add_transition0(target, guard, action)
+            .unwrap_or_else(build_panic)
+    }
+    // Add an update transition with one source state
+    pub fn add_transition1<G, F>(
+        &mut self,
+        source: impl StateRef,
+        target: impl StateRef,
+        guard: G,
+        action: F,
+    ) -> TransRef
+    where
+        G: 'a + Fn(&D) -> bool,
+        F: 'a + Fn(&D, &Q) -> Q,
+    {
+        self.try_add_transition1(source, target, guard, action)
+            .unwrap_or_else(build_panic)
+    }
+    // Add an update transition with two source states
+    pub fn add_transition2<G, F>(
+        &mut self,
+        source1: impl StateRef,
+        source2: impl StateRef,
+        target: impl StateRef,
+        guard: G,
+        action: F,
+    ) -> TransRef
+    where
+        G: 'a + Fn(&D) -> bool,
+        F: 'a + Fn(&D, &Q, &Q) -> Q,
+    {
+        self.try_add_transition2(source1, source2, target, guard, action)
+            .unwrap_or_else(build_panic)
+    }
+    // Add an "identity transition" which preserves a particular state from one
+    // timestep to the next. (This is common enough that it's worth exposing
+    // specifically in the API.)
+    pub fn add_iden<G>(
+        &mut self,
+        source: impl StateRef,
+        target: impl StateRef,
+        guard: G,
+    ) -> TransRef
+    where
+        G: 'a + Fn(&D) -> bool,
+    {
+        self.try_add_iden(source, target, guard).unwrap_or_else(build_panic)
+    }
+    // Add a timer: n items after the source state becomes set (gets a
+    // value, after a step where it had none), the target is set to the
+    // action applied to the item and the value the source had then.
+    // Timeouts can then be written without keeping a counter in Q.
+    // The countdown is kept in n new states, which are added to the
+    // machine (one holding the source delayed by an item, and one for each
+    // further item to wait); if the source becomes set again while a timer
+    // is running, both fire. Returns the transition that fires the timer.
+    pub fn add_timer<F>(
+        &mut self,
+        source: impl StateRef,
+        target: impl StateRef,
+        n: usize,
+        action: F,
+    ) -> TransRef
+    where
+        F: 'a + Fn(&D, &Q) -> Q,
+    {
+        self.try_add_timer(source, target, n, action)
+            .unwrap_or_else(build_panic)
+    }
+    // Add a guard on the current state values to an update transition: it
+    // then only fires if both its guard on the item and this guard hold.
+    // The guard is given the item and the values of all states, indexed
+    // by state. As for the actions of update transitions, these are the
+    // values before the item, so the result does not depend on the order
+    // the transitions are evaluated in; the values computed on this item
+    // are not visible. (This also means state guards do not benefit from
+    // .set_dispatch(); they are checked on every item.)
+    pub fn add_state_guard<G>(&mut self, tr: TransRef, guard: G)
+    where
+        G: 'a + Fn(&D, &[Ext<Q>]) -> bool,
+    {
+        self.try_add_state_guard(tr, guard).unwrap_or_else(build_panic)
+    }
+    // Attach an output action to an update transition: whenever it fires,
+    // the action is applied to the item and the value the transition
+    // produced, and the result is emitted (see .emitted()). This is in
+    // addition to the value of the final state, and allows Mealy-style
+    // machines. A transition may be given several output actions.
+    pub fn add_output<F>(&mut self, tr: TransRef, out: F)
+    where
+        F: 'a + Fn(&D, &Q) -> Q,
+    {
+        self.try_add_output(tr, out).unwrap_or_else(build_panic)
+    }
+    // Give the action of a transition a name. This has no effect on
+    // evaluation, but allows the machine to be exported (see
+    // json_format.rs).
+    pub fn set_action_name(&mut self, tr: TransRef, name: &str) {
+        self.try_set_action_name(tr, name).unwrap_or_else(build_panic)
+    }
+
+    // Versions of the above with symbolic guards (see guard.rs) instead of
+    // closures. These behave the same, but the guard can then be inspected,
+    // e.g. by .overlapping_guards() and .analyze().
+    pub fn add_sym_transition0<F>(
+        &mut self,
+        target: impl StateRef,
+        guard: Guard<'a, D>,
+        action: F,
+    ) -> TransRef
+    where
+        F: 'a + Fn(&D) -> Q,
+    {
+        self.try_add_sym_transition0(target, guard, action)
+            .unwrap_or_else(build_panic)
+    }
+    pub fn add_sym_transition1<F>(
+        &mut self,
+        source: impl StateRef,
+        target: impl StateRef,
+        guard: Guard<'a, D>,
+        action: F,
+    ) -> TransRef
+    where
+        F: 'a + Fn(&D, &Q) -> Q,
+    {
+        self.try_add_sym_transition1(source, target, guard, action)
+            .unwrap_or_else(build_panic)
+    }
+    pub fn add_sym_transition2<F>(
+        &mut self,
+        source1: impl StateRef,
+        source2: impl StateRef,
+        target: impl StateRef,
+        guard: Guard<'a, D>,
+        action: F,
+    ) -> TransRef
+    where
+        F: 'a + Fn(&D, &Q, &Q) -> Q,
+    {
+        self.try_add_sym_transition2(source1, source2, target, guard, action)
+            .unwrap_or_else(build_panic)
+    }
+    pub fn add_sym_iden(
+        &mut self,
+        source: impl StateRef,
+        target: impl StateRef,
+        guard: Guard<'a, D>,
+    ) -> TransRef {
+        self.try_add_sym_iden(source, target, guard).unwrap_or_else(build_panic)
+    }
+
+    /* Optimization passes which compose transitions */
+    // Remove epsilon transitions where possible, by composing them with the
+    // transitions into their source state. The epsilons which remain are
+    // those out of the initial state (which are needed on .init()), and
+    // those with zero or two sources.
+    // Returns an error, leaving the machine unchanged, if there is a cycle
+    // of epsilon transitions (in which case values may circulate and
+    // produce Many, so composing is not possible).
+    // Resets the machine (any current values are discarded).
+    pub fn eliminate_epsilons(&mut self) -> Result<(), BuildError> {
+        if let Some(state) = self.epsilon_cycle() {
+            return Err(BuildError::EpsilonCycle { state: state.0 });
+        }
+        if self.policy == ConflictPolicy::HighestPriority {
+            return Err(BuildError::PriorityEpsilons);
+        }
+        self.reset();
+        let eliminable = |e: &Edge<dyn Transition<(), Q> + 'a>| {
+            e.sources.len() == 1 && e.sources[0] != ISTATE_ID
+        };
+        // Eliminate in topological order: only pick an epsilon if no other
+        // eliminable epsilon leads into its source. Then all the epsilons
+        // created by composition are not eliminable, so this terminates.
+        loop {
+            let next = self.epsilons.iter().position(|e| {
+                eliminable(e)
+                    && !self
+                        .epsilons
+                        .iter()
+                        .any(|e2| eliminable(e2) && e2.target == e.sources[0])
+            });
+            let e = match next {
+                Some(i) => {
+                    self.eps_vals.remove(i);
+                    self.epsilons.remove(i)
+                }
+                None => break,
+            };
+            let (source, target) = (e.sources[0], e.target);
+            let new_updates: Vec<_> = self
+                .updates
+                .iter()
+                .filter(|u| u.target == source)
+                .map(|u| Edge {
+                    sources: u.sources,
+                    target,
+                    tr: Rc::new(Compose {
+                        first: u.tr.clone(),
+                        then: e.tr.clone(),
+                    }) as Rc<dyn Transition<D, Q> + 'a>,
+                    priority: u.priority,
+                })
+                .collect();
+            let new_epsilons: Vec<_> = self
+                .epsilons
+                .iter()
+                .filter(|e2| e2.target == source)
+                .map(|e2| Edge {
+                    sources: e2.sources,
+                    target,
+                    tr: Rc::new(Compose {
+                        first: e2.tr.clone(),
+                        then: e.tr.clone(),
+                    })
+                        as Rc<dyn Transition<(), Q> + 'a>,
+                    priority: 0,
+                })
+                .collect();
+            self.updates.extend(new_updates);
+            for e2 in new_epsilons {
+                self.epsilons.push(e2);
+                self.eps_vals.push(Ext::None);
+            }
+            self.rebuild_indices();
+        }
+        debug_assert!(!self.epsilons.iter().any(eliminable));
+        self.check_invariant();
+        Ok(())
+    }
+
+    /* Fallible versions of the above (used by DataTransducerBuilder) */
+    fn try_add_transition0<G, F>(
+        &mut self,
+        target: impl StateRef,
+        guard: G,
+        action: F,
+    ) -> Result<TransRef, BuildError>
+    where
+        G: 'a + Fn(&D) -> bool,
+        F: 'a + Fn(&D) -> Q,
+    {
+        let sources = Sources::new(&[]);
+        let target = self.resolve(target)?;
+        self.add_transition_core(
+            sources,
+            target,
+            Trans0 { guard, action, ph_d: PhantomData, ph_q: PhantomData },
+        )
+    }
+    fn try_add_transition1<G, F>(
+        &mut self,
+        source: impl StateRef,
+        target: impl StateRef,
+        guard: G,
+        action: F,
+    ) -> Result<TransRef, BuildError>
+    where
+        G: 'a + Fn(&D) -> bool,
+        F: 'a + Fn(&D, &Q) -> Q,
+    {
+        let sources = Sources::new(&[self.resolve(source)?]);
+        let target = self.resolve(target)?;
+        self.add_transition_core(
+            sources,
+            target,
+            Trans1 { guard, action, ph_d: PhantomData, ph_q: PhantomData },
+        )
+    }
+    fn try_add_transition2<G, F>(
+        &mut self,
+        source1: impl StateRef,
+        source2: impl StateRef,
+        target: impl StateRef,
+        guard: G,
+        action: F,
+    ) -> Result<TransRef, BuildError>
+    where
+        G: 'a + Fn(&D) -> bool,
+        F: 'a + Fn(&D, &Q, &Q) -> Q,
+    {
+        let sources =
+            Sources::new(&[self.resolve(source1)?, self.resolve(source2)?]);
+        let target = self.resolve(target)?;
+        self.add_transition_core(
+            sources,
+            target,
+            Trans2 { guard, action, ph_d: PhantomData, ph_q: PhantomData },
+        )
+    }
+    fn try_add_iden<G>(
+        &mut self,
+        source: impl StateRef,
+        target: impl StateRef,
+        guard: G,
+    ) -> Result<TransRef, BuildError>
+    where
+        G: 'a + Fn(&D) -> bool,
+    {
+        let sources = Sources::new(&[self.resolve(source)?]);
+        let target = self.resolve(target)?;
+        self.add_transition_core(
+            sources,
+            target,
+            Iden { guard, ph_d: PhantomData, ph_q: PhantomData },
+        )
+    }
+    fn try_add_timer<F>(
+        &mut self,
+        source: impl StateRef,
+        target: impl StateRef,
+        n: usize,
+        action: F,
+    ) -> Result<TransRef, BuildError>
+    where
+        F: 'a + Fn(&D, &Q) -> Q,
+    {
+        let source = self.resolve(source)?;
+        let target = self.resolve(target)?;
+        if n == 0 {
+            return Err(BuildError::ZeroTimer);
+        }
+        let delayed = StateId(self.add_state());
+        self.try_add_iden(source.0, delayed.0, |_| true)?;
+        let sources = Sources::new(&[source, delayed]);
+        if n == 1 {
+            let arm = Arm { action, ph_d: PhantomData, ph_q: PhantomData };
+            return self.add_transition_core(sources, target, arm);
+        }
+        // Count down the remaining items in a chain of states
+        let mut last = StateId(self.add_state());
+        let arm = Arm {
+            action: |_: &D, q: &Q| q.clone(),
+            ph_d: PhantomData,
+            ph_q: PhantomData,
+        };
+        self.add_transition_core(sources, last, arm)?;
+        for _ in 2..n {
+            let next = StateId(self.add_state());
+            self.try_add_iden(last.0, next.0, |_| true)?;
+            last = next;
+        }
+        self.try_add_transition1(last.0, target.0, |_| true, action)
+    }
+    fn try_add_state_guard<G>(
+        &mut self,
+        tr: TransRef,
+        guard: G,
+    ) -> Result<(), BuildError>
+    where
+        G: 'a + Fn(&D, &[Ext<Q>]) -> bool,
+    {
+        let edge = match tr {
+            TransRef::Update(i) if i < self.updates.len() => {
+                &mut self.updates[TransId(i)]
+            }
+            TransRef::Update(_) => {
+                return Err(BuildError::NoSuchTransition {
+                    trans: tr,
+                    n_transs: self.updates.len(),
+                })
+            }
+            TransRef::Epsilon(_) => {
+                return Err(BuildError::EpsilonStateGuard { trans: tr })
+            }
+        };
+        let inner = edge.tr.clone();
+        edge.tr = Rc::new(StateGuard { inner, guard: Box::new(guard) });
+        self.state_guards = true;
+        Ok(())
+    }
+    fn try_add_output<F>(
+        &mut self,
+        tr: TransRef,
+        out: F,
+    ) -> Result<(), BuildError>
+    where
+        F: 'a + Fn(&D, &Q) -> Q,
+    {
+        let edge = match tr {
+            TransRef::Update(i) if i < self.updates.len() => {
+                &mut self.updates[TransId(i)]
+            }
+            TransRef::Update(_) => {
+                return Err(BuildError::NoSuchTransition {
+                    trans: tr,
+                    n_transs: self.updates.len(),
+                })
+            }
+            TransRef::Epsilon(_) => {
+                return Err(BuildError::EpsilonOutput { trans: tr })
+            }
+        };
+        let inner = edge.tr.clone();
+        edge.tr = Rc::new(Output { inner, out: Box::new(out) });
+        self.emitted.get_or_insert_with(Vec::new);
+        Ok(())
+    }
+    fn try_set_action_name(
+        &mut self,
+        tr: TransRef,
+        name: &str,
+    ) -> Result<(), BuildError> {
+        let name = name.to_string();
+        match tr {
+            TransRef::Update(i) if i < self.updates.len() => {
+                let edge = &mut self.updates[TransId(i)];
+                let inner = edge.tr.clone();
+                edge.tr = Rc::new(Named { inner, name });
+            }
+            TransRef::Epsilon(i) if i < self.epsilons.len() => {
+                let edge = &mut self.epsilons[TransId(i)];
+                let inner = edge.tr.clone();
+                edge.tr = Rc::new(Named { inner, name });
+            }
+            TransRef::Update(_) => {
+                return Err(BuildError::NoSuchTransition {
+                    trans: tr,
+                    n_transs: self.updates.len(),
+                })
+            }
+            TransRef::Epsilon(_) => {
+                return Err(BuildError::NoSuchTransition {
+                    trans: tr,
+                    n_transs: self.epsilons.len(),
+                })
+            }
+        }
+        Ok(())
+    }
+    fn try_add_sym_transition0<F>(
+        &mut self,
+        target: impl StateRef,
+        guard: Guard<'a, D>,
+        action: F,
+    ) -> Result<TransRef, BuildError>
+    where
+        F: 'a + Fn(&D) -> Q,
+    {
+        let tr =
+            self.try_add_transition0(target, guard.clone().into_fn(), action);
+        self.record_guard(tr, guard)
+    }
+    fn try_add_sym_transition1<F>(
+        &mut self,
+        source: impl StateRef,
+        target: impl StateRef,
+        guard: Guard<'a, D>,
+        action: F,
+    ) -> Result<TransRef, BuildError>
+    where
+        F: 'a + Fn(&D, &Q) -> Q,
+    {
+        let g = guard.clone().into_fn();
+        let tr = self.try_add_transition1(source, target, g, action);
+        self.record_guard(tr, guard)
+    }
+    fn try_add_sym_transition2<F>(
+        &mut self,
+        source1: impl StateRef,
+        source2: impl StateRef,
+        target: impl StateRef,
+        guard: Guard<'a, D>,
+        action: F,
+    ) -> Result<TransRef, BuildError>
+    where
+        F: 'a + Fn(&D, &Q, &Q) -> Q,
+    {
+        let g = guard.clone().into_fn();
+        let tr = self.try_add_transition2(source1, source2, target, g, action);
+        self.record_guard(tr, guard)
+    }
+    fn try_add_sym_iden(
+        &mut self,
+        source: impl StateRef,
+        target: impl StateRef,
+        guard: Guard<'a, D>,
+    ) -> Result<TransRef, BuildError> {
+        let tr = self.try_add_iden(source, target, guard.clone().into_fn());
+        self.record_guard(tr, guard)
+    }
+    // Wrap a newly added update transition to record its symbolic guard
+    fn record_guard(
+        &mut self,
+        tr: Result<TransRef, BuildError>,
+        guard: Guard<'a, D>,
+    ) -> Result<TransRef, BuildError> {
+        let tr = tr?;
+        if let TransRef::Update(i) = tr {
+            let edge = &mut self.updates[TransId(i)];
+            let inner = edge.tr.clone();
+            edge.tr = Rc::new(Symbolic { inner, guard });
+        }
+        Ok(tr)
+    }
+    fn add_transition_core<Tr>(
+        &mut self,
+        sources: Sources,
+        target: StateId,
+        tr: Tr,
+    ) -> Result<TransRef, BuildError>
+    where
+        Tr: 'a + Transition<D, Q>,
+    {
+        self.push_update(Edge { sources, target, tr: Rc::new(tr), priority: 0 })
+    }
+}
+
+/*
+    States of several types.
+
+    Q is an enum with one variant per type, and the transitions convert
+    between the enum and the variants. The state_enum! macro declares such
+    an enum, implementing StateType for each of its variant types, e.g.
+
+        state_enum! {
+            #[derive(Clone, Debug)]
+            enum St { Count(usize), Avg(f64) }
+        }
+
+    and then the .add_typed_*() methods below take actions on the variant
+    types, e.g. |d, n: &usize| *n as f64 / d.total. The variant types must be
+    distinct. If a state holds a value of a different type than an action
+    expects, the action panics.
+*/
+
+pub trait StateType<Q>: Sized {
+    fn into_state(self) -> Q;
+    fn from_state(q: &Q) -> Option<&Self>;
+}
+
+#[macro_export]
+macro_rules! state_enum {
+    (
+        $(#[$meta:meta])*
+        $vis:vis enum $name:ident { $($variant:ident($ty:ty)),* $(,)? }
+    ) => {
+        $(#[$meta])*
+        $vis enum $name {
+            $($variant($ty)),*
+        }
+        $(
+            impl $crate::state_machine::StateType<$name> for $ty {
+                fn into_state(self) -> $name {
+                    $name::$variant(self)
+                }
+                #[allow(unreachable_patterns)]
+                fn from_state(q: &$name) -> Option<&Self> {
+                    match q {
+                        $name::$variant(x) => Some(x),
+                        _ => None,
+                    }
+                }
+            }
+        )*
+    };
+}
+
+fn typed_state<Q, A: StateType<Q>>(q: &Q) -> &A {
+    A::from_state(q).unwrap_or_else(|| {
+        panic!("state value is not of type {}", std::any::type_name::<A>())
+    })
+}
+
+impl<'a, D, Q> DataTransducer<'a, D, Q>
+where
+    Q: Clone,
+{
+    pub fn add_typed_transition0<B, G, F>(
+        &mut self,
+        target: impl StateRef,
+        guard: G,
+        action: F,
+    ) -> TransRef
+    where
+        B: StateType<Q>,
+        G: 'a + Fn(&D) -> bool,
+        F: 'a + Fn(&D) -> B,
+    {
+        self.add_transition0(target, guard, move |d| action(d).into_state())
+    }
+    pub fn add_typed_transition1<A, B, G, F>(
+        &mut self,
+        source: impl StateRef,
+        target: impl StateRef,
+        guard: G,
+        action: F,
+    ) -> TransRef
+    where
+        A: StateType<Q>,
+        B: StateType<Q>,
+        G: 'a + Fn(&D) -> bool,
+        F: 'a + Fn(&D, &A) -> B,
+    {
+        self.add_transition1(source, target, guard, move |d, q| {
+            action(d, typed_state(q)).into_state()
+        })
+    }
+    pub fn add_typed_transition2<A1, A2, B, G, F>(
+        &mut self,
+        source1: impl StateRef,
+        source2: impl StateRef,
+        target: impl StateRef,
+        guard: G,
+        action: F,
+    ) -> TransRef
+    where
+        A1: StateType<Q>,
+        A2: StateType<Q>,
+        B: StateType<Q>,
+        G: 'a + Fn(&D) -> bool,
+        F: 'a + Fn(&D, &A1, &A2) -> B,
+    {
+        self.add_transition2(
+            source1,
+            source2,
+            target,
+            guard,
+            move |d, q1, q2| {
+                action(d, typed_state(q1), typed_state(q2)).into_state()
+            },
+        )
+    }
+    pub fn add_typed_epsilon1<A, B, F>(
+        &mut self,
+        source: impl StateRef,
+        target: impl StateRef,
+        action: F,
+    ) -> TransRef
+    where
+        A: StateType<Q>,
+        B: StateType<Q>,
+        F: 'a + Fn(&A) -> B,
+    {
+        self.add_epsilon1(source, target, move |q| {
+            action(typed_state(q)).into_state()
+        })
+    }
+    pub fn add_typed_epsilon2<A1, A2, B, F>(
+        &mut self,
+        source1: impl StateRef,
+        source2: impl StateRef,
+        target: impl StateRef,
+        action: F,
+    ) -> TransRef
+    where
+        A1: StateType<Q>,
+        A2: StateType<Q>,
+        B: StateType<Q>,
+        F: 'a + Fn(&A1, &A2) -> B,
+    {
+        self.add_epsilon2(source1, source2, target, move |q1, q2| {
+            action(typed_state(q1), typed_state(q2)).into_state()
+        })
+    }
+}
+
+/*
+    Declaring a machine with the state_machine! macro.
+
+    Instead of a sequence of calls adding states and transitions by index,
+    the machine is declared as a table, e.g. (the max-difference example
+    from the POPL paper)
+
+        let m = state_machine! {
+            <(char, isize), isize>
+            states { start = 0, result = 1, no_a, max_a, no_b, max_b }
+            // Initialize the maxima (and restart on '#')
+            eps start -> no_a => |_q| 0;
+            eps start -> no_b => |_q| 0;
+            no_a -> max_a if |&d| d.0 == 'a' => |&d, _q| d.1;
+            max_a -> max_a if |&d| d.0 == 'a' => |&d, &q| q.max(d.1);
+            max_a -> max_a if |&d| d.0 != 'a' => iden;
+            ...
+            max_a, max_b -> result if |&d| d.0 == '#' => |_d, &a, &b| a - b;
+        };
+
+    The entries are
+        <D, Q>
+            the item and state types (optional, but closures on the items
+            usually need them to typecheck)
+        states n;
+            ensure there are n states (numbered 0 to n - 1)
+        states { name, name = index, ... }
+            label the states: a new state for each name, or the given
+            existing one (e.g. the initial state 0 and the final state 1)
+        sources -> target if guard => action;
+            an update transition from 0 to 2 sources (.add_transition0/1/2),
+            or with the action iden and one source, .add_iden()
+        eps sources -> target => action;
+            an epsilon transition (.add_epsilon0/1/2 or .add_epsilon_iden)
+    in that order, where each state is an index or a label. The expansion
+    is the calls above, so errors (e.g. an unknown label) panic as they do.
+*/
+
+#[macro_export]
+macro_rules! state_machine {
+    (@ref $s:ident) => { stringify!($s) };
+    (@ref $s:literal) => { $s };
+
+    (@state $m:ident; $name:ident = $id:literal) => {
+        $m.name_state($id, stringify!($name));
+    };
+    (@state $m:ident; $name:ident) => {
+        $m.add_state_named(stringify!($name));
+    };
+
+    (@go $m:ident;) => {};
+    (@go $m:ident; states $n:literal; $($rest:tt)*) => {
+        $m.set_nstates($n);
+        $crate::state_machine!(@go $m; $($rest)*);
+    };
+    (
+        @go $m:ident;
+        states { $($name:ident $(= $id:literal)?),* $(,)? }
+        $($rest:tt)*
+    ) => {
+        $($crate::state_machine!(@state $m; $name $(= $id)?);)*
+        $crate::state_machine!(@go $m; $($rest)*);
+    };
+    (@go $m:ident; eps -> $t:tt => $f:expr; $($rest:tt)*) => {
+        $m.add_epsilon0($crate::state_machine!(@ref $t), $f);
+        $crate::state_machine!(@go $m; $($rest)*);
+    };
+    (@go $m:ident; eps $s:tt -> $t:tt => iden; $($rest:tt)*) => {
+        $m.add_epsilon_iden(
+            $crate::state_machine!(@ref $s),
+            $crate::state_machine!(@ref $t),
+        );
+        $crate::state_machine!(@go $m; $($rest)*);
+    };
+    (@go $m:ident; eps $s:tt -> $t:tt => $f:expr; $($rest:tt)*) => {
+        $m.add_epsilon1(
+            $crate::state_machine!(@ref $s),
+            $crate::state_machine!(@ref $t),
+            $f,
+        );
+        $crate::state_machine!(@go $m; $($rest)*);
+    };
+    (
+        @go $m:ident;
+        eps $s1:tt, $s2:tt -> $t:tt => $f:expr;
+        $($rest:tt)*
+    ) => {
+        $m.add_epsilon2(
+            $crate::state_machine!(@ref $s1),
+            $crate::state_machine!(@ref $s2),
+            $crate::state_machine!(@ref $t),
+            $f,
+        );
+        $crate::state_machine!(@go $m; $($rest)*);
+    };
+    (@go $m:ident; -> $t:tt if $g:expr => $f:expr; $($rest:tt)*) => {
+        $m.add_transition0($crate::state_machine!(@ref $t), $g, $f);
+        $crate::state_machine!(@go $m; $($rest)*);
+    };
+    (@go $m:ident; $s:tt -> $t:tt if $g:expr => iden; $($rest:tt)*) => {
+        $m.add_iden(
+            $crate::state_machine!(@ref $s),
+            $crate::state_machine!(@ref $t),
+            $g,
+        );
+        $crate::state_machine!(@go $m; $($rest)*);
+    };
+    (@go $m:ident; $s:tt -> $t:tt if $g:expr => $f:expr; $($rest:tt)*) => {
+        $m.add_transition1(
+            $crate::state_machine!(@ref $s),
+            $crate::state_machine!(@ref $t),
+            $g,
+            $f,
+        );
+        $crate::state_machine!(@go $m; $($rest)*);
+    };
+    (
+        @go $m:ident;
+        $s1:tt, $s2:tt -> $t:tt if $g:expr => $f:expr;
+        $($rest:tt)*
+    ) => {
+        $m.add_transition2(
+            $crate::state_machine!(@ref $s1),
+            $crate::state_machine!(@ref $s2),
+            $crate::state_machine!(@ref $t),
+            $g,
+            $f,
+        );
+        $crate::state_machine!(@go $m; $($rest)*);
+    };
+
+    (<$d:ty, $q:ty> $($body:tt)*) => {{
+        let mut m = $crate::state_machine::DataTransducer::<$d, $q>::new();
+        $crate::state_machine!(@go m; $($body)*);
+        m
+    }};
+    ($($body:tt)*) => {{
+        let mut m = $crate::state_machine::DataTransducer::new();
+        $crate::state_machine!(@go m; $($body)*);
+        m
+    }};
+}
+
+/*
+    Update transitions allocated in a bump arena (with feature "arena").
+
+    The guards and actions are moved into the arena rather than boxed
+    individually, so a machine which is built and torn down frequently (e.g.
+    one per key) makes far fewer allocations, and its transitions are
+    adjacent in memory. The arena must outlive the machine.
+    Note that the arena does not run destructors: closures should not
+    capture values which own heap data (or that data is only freed when
+    the arena is).
+*/
+
+#[cfg(feature = "arena")]
+pub type ArenaTransducer<'a, D, Q> =
+    DataTransducer<'a, D, Q, &'a (dyn Transition<D, Q> + 'a)>;
+
+#[cfg(feature = "arena")]
+impl<'a, D, Q> ArenaTransducer<'a, D, Q>
+where
+    Q: Clone,
+{
+    // Create the machine with ArenaTransducer::default(), and add update
+    // transitions with the following (epsilons are added as usual)
+    pub fn add_transition0_in<G, F>(
+        &mut self,
+        arena: &'a Bump,
+        target: impl StateRef,
+        guard: G,
+        action: F,
+    ) -> TransRef
+    where
+        G: 'a + Fn(&D) -> bool,
+        F: 'a + Fn(&D) -> Q,
+    {
+        let tr = Trans0 { guard, action, ph_d: PhantomData, ph_q: PhantomData };
+        self.add_static_transition::<usize>(&[], target, arena.alloc(tr))
+    }
+    pub fn add_transition1_in<G, F>(
+        &mut self,
+        arena: &'a Bump,
+        source: impl StateRef,
+        target: impl StateRef,
+        guard: G,
+        action: F,
+    ) -> TransRef
+    where
+        G: 'a + Fn(&D) -> bool,
+        F: 'a + Fn(&D, &Q) -> Q,
+    {
+        let tr = Trans1 { guard, action, ph_d: PhantomData, ph_q: PhantomData };
+        self.add_static_transition(&[source], target, arena.alloc(tr))
+    }
+    pub fn add_transition2_in<G, F>(
+        &mut self,
+        arena: &'a Bump,
+        source1: impl StateRef,
+        source2: impl StateRef,
+        target: impl StateRef,
+        guard: G,
+        action: F,
+    ) -> TransRef
+    where
+        G: 'a + Fn(&D) -> bool,
+        F: 'a + Fn(&D, &Q, &Q) -> Q,
+    {
+        let tr = Trans2 { guard, action, ph_d: PhantomData, ph_q: PhantomData };
+        let source1 = self.resolve(source1).unwrap_or_else(build_panic);
+        let source2 = self.resolve(source2).unwrap_or_else(build_panic);
+        let sources = [source1.0, source2.0];
+        self.add_static_transition(&sources, target, arena.alloc(tr))
+    }
+    pub fn add_iden_in<G>(
+        &mut self,
+        arena: &'a Bump,
+        source: impl StateRef,
+        target: impl StateRef,
+        guard: G,
+    ) -> TransRef
+    where
+        G: 'a + Fn(&D) -> bool,
+    {
+        let tr = Iden { guard, ph_d: PhantomData, ph_q: PhantomData };
+        self.add_static_transition(&[source], target, arena.alloc(tr))
+    }
+}
+
+/*
+    Constructions combining several machines into one.
+
+    These take ownership of the machines and move their states and
+    transitions into the result (renumbering the states). The result is in
+    its initial state, i.e. any current values of the inputs are discarded.
+    State labels are kept, except where they would clash with a label
+    already in the result (in which case the later one is dropped).
+    Machines with several final states are first given a single final state
+    holding their union; they may not have a custom combiner
+    (see .set_final_combiner()).
+    The other settings of the machines which affect their results are
+    carried over: their conflict policies and priorities (see .append()),
+    and the outputs of their transitions (see .add_output()). Machines with
+    state guards (see .add_state_guard()), which read the states by index,
+    or with dispatch (see .set_dispatch()), whose key only applies to their
+    own transitions, can't be combined.
+*/
+
+impl<'a, D, Q> DataTransducer<'a, D, Q>
+where
+    Q: Clone,
+{
+    // Move all states and transitions of other into self, returning the
+    // index of other's initial state (other's state i becomes offset + i)
+    // The transitions of the two machines have different targets, so they
+    // never conflict: the result uses ConflictPolicy::HighestPriority if
+    // either machine does, and the priorities of a machine which doesn't
+    // (which have no effect) are reset to 0.
+    fn append(&mut self, mut other: DataTransducer<'a, D, Q>) -> usize {
+        assert!(
+            !other.state_guards,
+            "can't combine machines with state guards"
+        );
+        assert!(
+            other.dispatch.is_none(),
+            "can't combine machines with dispatch (see .clear_dispatch())"
+        );
+        other.reset();
+        other.funnel_finals();
+        match other.policy {
+            ConflictPolicy::Union => other.clear_priorities(),
+            ConflictPolicy::HighestPriority => {
+                if self.policy == ConflictPolicy::Union {
+                    self.clear_priorities();
+                    self.policy = ConflictPolicy::HighestPriority;
+                }
+            }
+        }
+        if other.emitted.is_some() {
+            self.emitted.get_or_insert_with(Vec::new);
+        }
+        let offset = self.states.len();
+        for name in other.names.iter() {
+            let id = self.add_state();
+            if let Some(name) = name {
+                if self.state_id(name).is_none() {
+                    self.names[StateId(id)] = Some(name.clone());
+                }
+            }
+        }
+        let shift = |s: StateId| StateId(s.0 + offset);
+        for mut tr in other.updates.0.drain(..) {
+            tr.map_ids(&shift);
+            self.updates.push(tr);
+        }
+        for mut tr in other.epsilons.0.drain(..) {
+            tr.map_ids(&shift);
+            self.epsilons.push(tr);
+            self.eps_vals.push(Ext::None);
+        }
+        for mut sub in other.subscriptions.drain(..) {
+            sub.state = shift(sub.state);
+            self.subscriptions.push(sub);
+        }
+        self.rebuild_indices();
+        self.check_invariant();
+        offset
+    }
+    fn clear_priorities(&mut self) {
+        for tr in self.updates.iter_mut() {
+            tr.priority = 0;
+        }
+    }
+    // Make state 1 the only final state, by adding a state with epsilon
+    // transitions from each final state and swapping it with state 1
+    fn funnel_finals(&mut self) {
+        if self.finals == [FSTATE_ID] && self.combine.is_none() {
+            return;
+        }
+        assert!(
+            self.combine.is_none(),
+            "can't combine machines with a custom final combiner"
+        );
+        let out = StateId(self.add_state());
+        for id in self.finals.clone() {
+            self.add_epsilon_iden(id.0, out.0);
+        }
+        let swap = |s: StateId| match s {
+            FSTATE_ID => out,
+            s if s == out => FSTATE_ID,
+            s => s,
+        };
+        for tr in self.updates.iter_mut() {
+            tr.map_ids(&swap);
+        }
+        for tr in self.epsilons.iter_mut() {
+            tr.map_ids(&swap);
+        }
+        self.names.0.swap(FSTATE_ID.0, out.0);
+        self.finals = vec![FSTATE_ID];
+        self.rebuild_indices();
+        self.check_invariant();
+    }
+}
+
+// Product of two machines: on each initial value, both run in parallel,
+// and the output is the combination of their outputs (when both produce
+// one). Equivalent to qre::parcomp followed by combine.
+pub fn product<'a, D, Q, F>(
+    m1: DataTransducer<'a, D, Q>,
+    m2: DataTransducer<'a, D, Q>,
+    combine: F,
+) -> DataTransducer<'a, D, Q>
+where
+    Q: Clone,
+    F: 'a + Fn(&Q, &Q) -> Q,
+{
+    let mut m = DataTransducer::new();
+    let i1 = m.append(m1);
+    let i2 = m.append(m2);
+    m.add_epsilon_iden(0, i1);
+    m.add_epsilon_iden(0, i2);
+    m.add_epsilon2(i1 + 1, i2 + 1, 1, combine);
+    m
+}
+
+// Union of two machines: on each initial value, both run in parallel, and
+// the output is the union of their outputs (i.e. Many if both produce one).
+// Equivalent to qre::union.
+pub fn union_machines<'a, D, Q>(
+    m1: DataTransducer<'a, D, Q>,
+    m2: DataTransducer<'a, D, Q>,
+) -> DataTransducer<'a, D, Q>
+where
+    Q: Clone,
+{
+    let mut m = DataTransducer::new();
+    let i1 = m.append(m1);
+    let i2 = m.append(m2);
+    m.add_epsilon_iden(0, i1);
+    m.add_epsilon_iden(0, i2);
+    m.add_epsilon_iden(i1 + 1, 1);
+    m.add_epsilon_iden(i2 + 1, 1);
+    m
+}
+
+// Concatenation of two machines: the output of m1 is fed as an initial
+// value to m2 (on every step it produces one). Equivalent to qre::concat;
+// as there, the result is only correct if m2 is restartable.
+pub fn concat_machines<'a, D, Q>(
+    m1: DataTransducer<'a, D, Q>,
+    m2: DataTransducer<'a, D, Q>,
+) -> DataTransducer<'a, D, Q>
+where
+    Q: Clone,
+{
+    let mut m = DataTransducer::new();
+    let i1 = m.append(m1);
+    let i2 = m.append(m2);
+    m.add_epsilon_iden(0, i1);
+    m.add_epsilon_iden(i1 + 1, i2);
+    m.add_epsilon_iden(i2 + 1, 1);
+    m
+}
+
+// Kleene iteration of a machine: the initial value and each output of m
+// are fed back into m, and are also the output. Equivalent to qre::iterate
+// (so also only correct if m is restartable). If m is nullable, this
+// creates an epsilon cycle and all outputs are Many.
+pub fn iterate_machine<D, Q>(
+    m: DataTransducer<'_, D, Q>,
+) -> DataTransducer<'_, D, Q>
+where
+    Q: Clone,
+{
+    let mut result = DataTransducer::new();
+    let loop_st = result.add_state();
+    let i = result.append(m);
+    result.add_epsilon_iden(0, loop_st);
+    result.add_epsilon_iden(loop_st, i);
+    result.add_epsilon_iden(i + 1, loop_st);
+    result.add_epsilon_iden(loop_st, 1);
+    result
+}
+
+/*
+    Adapter for the input and output types.
+
+    A DataTransducer<D, Q> is a Transducer<Q, D, Q>; to use it where a
+    transducer with other input and output types is expected (e.g. in the
+    QRE combinators), map the initial value into Q and the output out of Q.
+*/
+
+pub struct MachineIo<I, D, O, Q, M, F, G>
+where
+    M: Transducer<Q, D, Q>,
+    F: Fn(I) -> Q,
+    G: Fn(Q) -> O,
+{
+    m: M,
+    in_map: F,
+    out_map: G,
+    ph_i: PhantomData<I>,
+    ph_d: PhantomData<D>,
+    ph_o: PhantomData<O>,
+    ph_q: PhantomData<Q>,
+}
+pub fn machine_io<I, D, O, Q, M, F, G>(
+    m: M,
+    in_map: F,
+    out_map: G,
+) -> MachineIo<I, D, O, Q, M, F, G>
+where
+    M: Transducer<Q, D, Q>,
+    F: Fn(I) -> Q,
+    G: Fn(Q) -> O,
+{
+    MachineIo {
+        m,
+        in_map,
+        out_map,
+        ph_i: PhantomData,
+        ph_d: PhantomData,
+        ph_o: PhantomData,
+        ph_q: PhantomData,
+    }
+}
+
+impl<I, D, O, Q, M, F, G> Clone for MachineIo<I, D, O, Q, M, F, G>
+where
+    M: Transducer<Q, D, Q> + Clone,
+    F: Fn(I) -> Q + Clone,
+    G: Fn(Q) -> O + Clone,
+{
+    fn clone(&self) -> Self {
+        machine_io(self.m.clone(), self.in_map.clone(), self.out_map.clone())
+    }
+}
+impl<I, D, O, Q, M, F, G> Transducer<I, D, O> for MachineIo<I, D, O, Q, M, F, G>
+where
+    M: Transducer<Q, D, Q>,
+    F: Fn(I) -> Q,
+    G: Fn(Q) -> O,
+{
+    fn init(&mut self, i: Ext<I>) -> Ext<O> {
+        let out = self.m.init(ext_value::apply1(&self.in_map, i));
+        ext_value::apply1(&self.out_map, out)
+    }
+    fn update(&mut self, item: &D) -> Ext<O> {
+        ext_value::apply1(&self.out_map, self.m.update(item))
+    }
+    fn reset(&mut self) {
+        self.m.reset();
+    }
+
+    fn is_epsilon(&self) -> bool {
+        self.m.is_epsilon()
+    }
+    fn is_restartable(&self) -> bool {
+        self.m.is_restartable()
+    }
+    fn is_nullable(&self) -> bool {
+        self.m.is_nullable()
+    }
+    fn n_states(&self) -> usize {
+        self.m.n_states()
+    }
+    fn n_transs(&self) -> usize {
+        self.m.n_transs()
+    }
+    fn n_bytes(&self) -> usize {
+        mem::size_of::<Self>() - mem::size_of::<M>() + self.m.n_bytes()
+    }
+}
+
+/*
+    Pipelines of machines.
+
+    pipe_machines(m1, m2) runs m1 on the input, and feeds each output of m1
+    to m2 as a data item, in the same step; the output is that of m2. Both
+    machines get the same initial values. (To feed the outputs of m1 to m2
+    as initial values instead, use concat_machines, which gives a single
+    machine.)
+
+    This can't be a single DataTransducer: the states of m2 must keep their
+    values on steps where m1 produces no output, which would need a
+    transition firing on the *absence* of a value.
+*/
+
+pub struct PipeMachines<'a, D, Q>
+where
+    Q: 'a + Clone,
+    D: 'a,
+{
+    m1: DataTransducer<'a, D, Q>,
+    m2: DataTransducer<'a, Q, Q>,
+    // Output of m1 so far in the current step (see .init())
+    step1: Ext<Q>,
+}
+pub fn pipe_machines<'a, D, Q>(
+    m1: DataTransducer<'a, D, Q>,
+    m2: DataTransducer<'a, Q, Q>,
+) -> PipeMachines<'a, D, Q>
+where
+    Q: Clone,
+{
+    PipeMachines { m1, m2, step1: Ext::None }
+}
+
+impl<D, Q: Clone> Clone for PipeMachines<'_, D, Q> {
+    fn clone(&self) -> Self {
+        PipeMachines {
+            m1: self.m1.clone(),
+            m2: self.m2.clone(),
+            step1: self.step1.clone(),
+        }
+    }
+}
+impl<D, Q: Clone> PipeMachines<'_, D, Q> {
+    // Feed an output of m1 to m2; out2 is the output of m2 if there is
+    // none. A Many output can't be fed as an item, so it is passed on.
+    fn feed(&mut self, out1: Ext<Q>, out2: Ext<Q>) -> Ext<Q> {
+        match out1 {
+            Ext::None => out2,
+            Ext::One(q) => self.m2.update(&q),
+            Ext::Many => Ext::Many,
+        }
+    }
+}
+impl<D, Q: Clone> Transducer<Q, D, Q> for PipeMachines<'_, D, Q> {
+    fn init(&mut self, i: Ext<Q>) -> Ext<Q> {
+        let out2 = self.m2.init(i.clone());
+        // m1 reports its output accumulated over the step, which may
+        // include the output already fed on the update
+        let out1 = self.m1.init(i);
+        let new1 = match (&self.step1, &out1) {
+            (Ext::None, _) => out1.clone(),
+            (Ext::One(_), Ext::Many) => Ext::Many,
+            _ => Ext::None,
+        };
+        self.step1 = out1;
+        self.feed(new1, out2)
+    }
+    fn update(&mut self, item: &D) -> Ext<Q> {
+        let out1 = self.m1.update(item);
+        self.step1 = out1.clone();
+        self.feed(out1, Ext::None)
+    }
+    fn reset(&mut self) {
+        self.m1.reset();
+        self.m2.reset();
+        self.step1 = Ext::None;
+    }
+
+    fn is_epsilon(&self) -> bool {
+        false
+    }
+    fn is_restartable(&self) -> bool {
+        // The restarts share m2
+        false
+    }
+    fn is_nullable(&self) -> bool {
+        self.m1.is_nullable() || self.m2.is_nullable()
+    }
+    fn n_states(&self) -> usize {
+        self.m1.n_states() + self.m2.n_states()
+    }
+    fn n_transs(&self) -> usize {
+        self.m1.n_transs() + self.m2.n_transs()
+    }
+    fn n_bytes(&self) -> usize {
+        self.m1.n_bytes() + self.m2.n_bytes()
+    }
+}
+
+/*
+    Builder for DataTransducer which reports invalid states and transitions
+    as a BuildError instead of panicking.
+    This is useful when constructing machines programmatically, e.g. from
+    a parsed specification.
+*/
+
+pub struct DataTransducerBuilder<'a, D, Q, U = dyn Transition<D, Q> + 'a>
+where
+    Q: 'a + Clone,
+    D: 'a,
+    U: 'a + ?Sized + Transition<D, Q>,
+{
+    m: DataTransducer<'a, D, Q, U>,
+}
+
+impl<'a, D, Q, U> Default for DataTransducerBuilder<'a, D, Q, U>
+where
+    Q: Clone,
+    U: 'a + ?Sized + Transition<D, Q>,
+{
+    fn default() -> Self {
+        Self { m: DataTransducer::default() }
+    }
+}
+
+impl<'a, D, Q, U> DataTransducerBuilder<'a, D, Q, U>
+where
+    Q: Clone,
+    U: 'a + ?Sized + Transition<D, Q>,
+{
+    pub fn build(self) -> DataTransducer<'a, D, Q, U> {
+        self.m
+    }
+    pub fn n_states(&self) -> usize {
+        self.m.n_states()
+    }
+    pub fn add_state(&mut self) -> usize {
+        self.m.add_state()
+    }
+    pub fn add_state_named(&mut self, name: &str) -> Result<usize, BuildError> {
+        self.m.try_add_state_named(name)
+    }
+    pub fn name_state(
+        &mut self,
+        id: usize,
+        name: &str,
+    ) -> Result<(), BuildError> {
+        self.m.try_name_state(id, name)
+    }
+    pub fn set_nstates(&mut self, n: usize) -> Result<(), BuildError> {
+        self.m.try_set_nstates(n)
+    }
+    pub fn set_final_states<S: StateRef>(
+        &mut self,
+        states: &[S],
+    ) -> Result<(), BuildError> {
+        self.m.try_set_final_states(states)
+    }
+    pub fn set_final_combiner<F>(&mut self, combine: F)
+    where
+        F: 'a + Fn(&[Ext<&Q>]) -> Ext<Q>,
+    {
+        self.m.set_final_combiner(combine)
+    }
+    pub fn set_priority(
+        &mut self,
+        tr: TransRef,
+        priority: i32,
+    ) -> Result<(), BuildError> {
+        self.m.try_set_priority(tr, priority)
+    }
+    pub fn set_conflict_policy(&mut self, policy: ConflictPolicy) {
+        self.m.set_conflict_policy(policy)
+    }
+    pub fn set_dispatch<F>(&mut self, key: F)
+    where
+        F: 'a + Fn(&D) -> usize,
+    {
+        self.m.set_dispatch(key)
+    }
+    pub fn add_static_transition<S: StateRef>(
+        &mut self,
+        sources: &[S],
+        target: impl StateRef,
+        tr: U,
+    ) -> Result<TransRef, BuildError>
+    where
+        U: Sized,
+    {
+        self.m.try_add_static_transition(sources, target, tr)
+    }
+    pub fn add_epsilon_iden(
+        &mut self,
+        source: impl StateRef,
+        target: impl StateRef,
+    ) -> Result<TransRef, BuildError> {
+        self.m.try_add_epsilon_iden(source, target)
+    }
+    pub fn add_epsilon0<F>(
+        &mut self,
+        target: impl StateRef,
+        action: F,
+    ) -> Result<TransRef, BuildError>
+    where
+        F: 'a + Fn() -> Q,
+    {
+        self.m.try_add_epsilon0(target, action)
+    }
+    pub fn add_epsilon1<F>(
+        &mut self,
+        source: impl StateRef,
+        target: impl StateRef,
+        action: F,
+    ) -> Result<TransRef, BuildError>
+    where
+        F: 'a + Fn(&Q) -> Q,
+    {
+        self.m.try_add_epsilon1(source, target, action)
+    }
+    pub fn add_epsilon2<F>(
+        &mut self,
+        source1: impl StateRef,
+        source2: impl StateRef,
+        target: impl StateRef,
+        action: F,
+    ) -> Result<TransRef, BuildError>
+    where
+        F: 'a + Fn(&Q, &Q) -> Q,
+    {
+        self.m.try_add_epsilon2(source1, source2, target, action)
+    }
+    pub fn remove_transition(
+        &mut self,
+        tr: TransRef,
+    ) -> Result<(), BuildError> {
+        self.m.try_remove_transition(tr)
+    }
+    pub fn remove_state(
+        &mut self,
+        id: impl StateRef,
+    ) -> Result<(), BuildError> {
+        self.m.try_remove_state(id)
+    }
+}
+
+impl<'a, D, Q> DataTransducerBuilder<'a, D, Q>
+where
+    Q: Clone,
+{
+    pub fn new() -> Self {
+        Default::default()
+    }
+    pub fn add_transition0<G, F>(
+        &mut self,
+        target: impl StateRef,
+        guard: G,
+        action: F,
+    ) -> Result<TransRef, BuildError>
+    where
+        G: 'a + Fn(&D) -> bool,
+        F: 'a + Fn(&D) -> Q,
+    {
+        self.m.try_add_transition0(target, guard, action)
+    }
+    pub fn add_transition1<G, F>(
+        &mut self,
+        source: impl StateRef,
+        target: impl StateRef,
+        guard: G,
+        action: F,
+    ) -> Result<TransRef, BuildError>
+    where
+        G: 'a + Fn(&D) -> bool,
+        F: 'a + Fn(&D, &Q) -> Q,
+    {
+        self.m.try_add_transition1(source, target, guard, action)
+    }
+    pub fn add_transition2<G, F>(
+        &mut self,
+        source1: impl StateRef,
+        source2: impl StateRef,
+        target: impl StateRef,
+        guard: G,
+        action: F,
+    ) -> Result<TransRef, BuildError>
+    where
+        G: 'a + Fn(&D) -> bool,
+        F: 'a + Fn(&D, &Q, &Q) -> Q,
+    {
+        self.m.try_add_transition2(source1, source2, target, guard, action)
+    }
+    pub fn add_iden<G>(
+        &mut self,
+        source: impl StateRef,
+        target: impl StateRef,
+        guard: G,
+    ) -> Result<TransRef, BuildError>
+    where
+        G: 'a + Fn(&D) -> bool,
+    {
+        self.m.try_add_iden(source, target, guard)
+    }
+    pub fn add_timer<F>(
+        &mut self,
+        source: impl StateRef,
+        target: impl StateRef,
+        n: usize,
+        action: F,
+    ) -> Result<TransRef, BuildError>
+    where
+        F: 'a + Fn(&D, &Q) -> Q,
+    {
+        self.m.try_add_timer(source, target, n, action)
+    }
+    pub fn add_state_guard<G>(
+        &mut self,
+        tr: TransRef,
+        guard: G,
+    ) -> Result<(), BuildError>
+    where
+        G: 'a + Fn(&D, &[Ext<Q>]) -> bool,
+    {
+        self.m.try_add_state_guard(tr, guard)
+    }
+    pub fn add_output<F>(
+        &mut self,
+        tr: TransRef,
+        out: F,
+    ) -> Result<(), BuildError>
+    where
+        F: 'a + Fn(&D, &Q) -> Q,
+    {
+        self.m.try_add_output(tr, out)
+    }
+    pub fn set_action_name(
+        &mut self,
+        tr: TransRef,
+        name: &str,
+    ) -> Result<(), BuildError> {
+        self.m.try_set_action_name(tr, name)
+    }
+    pub fn add_sym_transition0<F>(
+        &mut self,
+        target: impl StateRef,
+        guard: Guard<'a, D>,
+        action: F,
+    ) -> Result<TransRef, BuildError>
+    where
+        F: 'a + Fn(&D) -> Q,
+    {
+        self.m.try_add_sym_transition0(target, guard, action)
+    }
+    pub fn add_sym_transition1<F>(
+        &mut self,
+        source: impl StateRef,
+        target: impl StateRef,
+        guard: Guard<'a, D>,
+        action: F,
+    ) -> Result<TransRef, BuildError>
+    where
+        F: 'a + Fn(&D, &Q) -> Q,
+    {
+        self.m.try_add_sym_transition1(source, target, guard, action)
+    }
+    pub fn add_sym_transition2<F>(
+        &mut self,
+        source1: impl StateRef,
+        source2: impl StateRef,
+        target: impl StateRef,
+        guard: Guard<'a, D>,
+        action: F,
+    ) -> Result<TransRef, BuildError>
+    where
+        F: 'a + Fn(&D, &Q, &Q) -> Q,
+    {
+        self.m.try_add_sym_transition2(source1, source2, target, guard, action)
+    }
+    pub fn add_sym_iden(
+        &mut self,
+        source: impl StateRef,
+        target: impl StateRef,
+        guard: Guard<'a, D>,
+    ) -> Result<TransRef, BuildError> {
+        self.m.try_add_sym_iden(source, target, guard)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interface::RInput;
+
+    type ExD = (char, isize);
+    type ExQ = isize;
+
+    /* Additional methods for testing */
+    impl<'a, D, Q, U> DataTransducer<'a, D, Q, U>
+    where
+        D: Debug,
+        Q: Clone + Debug + Eq,
+        U: 'a + ?Sized + Transition<D, Q>,
+    {
+        fn init_expect(&mut self, i: Q, o: Ext<Q>) {
+            println!("State: {:?}", self);
+            println!("===== init: {:?} =====", i);
+            println!("Expected output: {:?}", o);
+            assert_eq!(self.init_one(i), o);
+            println!("Output is correct");
+        }
+        fn update_expect(&mut self, d: D, o: Ext<Q>) {
+            println!("State: {:?}", self);
+            println!("===== update: {:?} =====", d);
+            println!("Expected output: {:?}", o);
+            assert_eq!(self.update_val(d), o);
+            println!("Output is correct");
+        }
+    }
+
+    #[test]
+    fn test_popl19_ex1() {
+        // Initialize
+        let mut m = DataTransducer::<ExD, ExQ>::new();
+        m.set_nstates(4);
+        assert_eq!(m.n_states(), 4);
+        assert_eq!(m.n_transs(), 0);
+        assert!(m.is_epsilon());
+        // 0: Initial, always set to Ext::One
+        // 1: Final, sum of last three 'a' events
+        // 2: Sum of last two 'a' events
+        // 3: Sum of last 'a' event
+        m.add_iden(0, 0, |_d| true);
+        m.add_iden(2, 2, |&d| d.0 == 'b');
+        m.add_iden(3, 3, |&d| d.0 == 'b');
+        m.add_transition1(0, 3, |&d| d.0 == 'a', |&d, _q| d.1);
+        m.add_transition1(3, 2, |&d| d.0 == 'a', |&d, &q| q + d.1);
+        m.add_transition1(2, 1, |&d| d.0 == 'a', |&d, &q| q + d.1);
+        assert_eq!(m.n_states(), 4);
+        assert_eq!(m.n_transs(), 6);
+        assert!(!m.is_epsilon());
+        // Test
+        m.init_expect(0, Ext::None);
+        m.update_expect(('a', 6), Ext::None);
+        m.update_expect(('b', 2), Ext::None);
+        m.update_expect(('a', 5), Ext::None);
+        m.update_expect(('a', 7), Ext::One(18));
+        m.update_expect(('b', 2), Ext::None);
+        m.update_expect(('a', 8), Ext::One(20));
+        m.update_expect(('#', 0), Ext::None);
+        m.update_expect(('b', 2), Ext::None);
+        m.update_expect(('a', 2), Ext::None);
+        m.update_expect(('a', 2), Ext::None);
+        m.update_expect(('a', 2), Ext::One(6));
+        m.update_expect(('a', 0), Ext::One(4));
+    }
+
+    #[test]
+    fn test_stats() {
+        let mut m = DataTransducer::<ExD, ExQ>::new();
+        m.set_nstates(3);
+        let t0 = m.add_transition1(0, 2, |&d| d.0 == 'a', |&d, &q| q + d.1);
+        let t1 = m.add_iden(2, 2, |&d| d.0 == 'b');
+        let e0 = m.add_epsilon1(2, 1, |&q| q);
+        assert_eq!(m.stats(t0), None);
+        m.enable_stats();
+        m.init_one(1);
+        m.update_val(('a', 1));
+        m.update_val(('b', 1));
+        m.update_val(('a', 1));
+        let stats = |guard_evals, active, produced| FiringStats {
+            guard_evals,
+            active,
+            produced,
+        };
+        assert_eq!(m.stats(t0), Some(stats(3, 2, 1)));
+        assert_eq!(m.stats(t1), Some(stats(3, 1, 1)));
+        assert_eq!(m.stats(e0), Some(stats(0, 2, 2)));
+        assert_eq!(m.stats(TransRef::Epsilon(1)), None);
+        // New transitions start at zero; removing one resets the counts
+        let e1 = m.add_epsilon1(2, 1, |&q| q);
+        assert_eq!(m.stats(e1), Some(stats(0, 0, 0)));
+        m.remove_transition(e1);
+        assert_eq!(m.stats(t0), Some(stats(0, 0, 0)));
+        // With dispatch, guards are only evaluated once per key
+        m.set_dispatch(|&d| d.0 as usize);
+        m.init_one(1);
+        m.update_val(('a', 1));
+        m.update_val(('a', 1));
+        assert_eq!(m.stats(t0), Some(stats(1, 2, 1)));
+        m.disable_stats();
+        assert_eq!(m.stats(t0), None);
+    }
+
+    #[test]
+    fn test_recording() {
+        let mut m = DataTransducer::<ExD, ExQ>::new();
+        m.set_nstates(3);
+        let t0 = m.add_transition1(0, 2, |&d| d.0 == 'a', |&d, &q| q + d.1);
+        let t1 = m.add_iden(2, 2, |&d| d.0 == 'b');
+        let e0 = m.add_epsilon1(2, 1, |&q| q);
+        assert_eq!(m.recording(), None);
+        m.start_recording();
+        m.init_one(1);
+        m.update_val(('a', 2));
+        m.update_val(('b', 0));
+        m.update_val(('c', 0));
+        let log = m.recording().unwrap().to_vec();
+        assert_eq!(
+            log,
+            vec![
+                StepRecord {
+                    input: RInput::Restart(Ext::One(1)),
+                    fired: vec![],
+                    changed: vec![(0, Ext::One(1))],
+                    output: Ext::None,
+                },
+                StepRecord {
+                    input: RInput::Item(('a', 2)),
+                    fired: vec![t0, e0],
+                    changed: vec![
+                        (0, Ext::None),
+                        (1, Ext::One(3)),
+                        (2, Ext::One(3))
+                    ],
+                    output: Ext::One(3),
+                },
+                StepRecord {
+                    input: RInput::Item(('b', 0)),
+                    fired: vec![t1, e0],
+                    changed: vec![(1, Ext::One(3)), (2, Ext::One(3))],
+                    output: Ext::One(3),
+                },
+                StepRecord {
+                    input: RInput::Item(('c', 0)),
+                    fired: vec![],
+                    changed: vec![(1, Ext::None), (2, Ext::None)],
+                    output: Ext::None,
+                },
+            ]
+        );
+        assert_eq!(
+            log[1].to_string(),
+            "update ('a', 2): fired [Update(0), Epsilon(0)], \
+             changed {0: None, 1: One(3), 2: One(3)}, output One(3)"
+        );
+        assert_eq!(m.stop_recording(), log);
+        assert_eq!(m.recording(), None);
+        // Replaying gives the same outputs
+        let outputs: Vec<_> = log.iter().map(|step| step.output).collect();
+        assert_eq!(m.replay(&log), outputs);
+    }
+
+    #[test]
+    fn test_outputs() {
+        // Running sum of the 'a' items, with no final state: the sum is
+        // emitted on each 'a', and twice the sum on each 'b'
+        let mut m = DataTransducer::<ExD, ExQ>::new();
+        m.set_nstates(3);
+        m.add_epsilon1(0, 2, |&q| q);
+        let t1 = m.add_transition1(2, 2, |&d| d.0 == 'a', |&d, &q| q + d.1);
+        let t2 = m.add_iden(2, 2, |&d| d.0 != 'a');
+        m.add_output(t1, |_, &q| q);
+        m.add_output(t2, |&d, &q| if d.0 == 'b' { 2 * q } else { q });
+        m.add_output(t2, |_, &q| -q);
+        // State 2 is not removed, although the final state is unreachable
+        assert_eq!(m.remove_dead_states(), 0);
+        assert_eq!(m.emitted(), &[]);
+        m.init_expect(0, Ext::None);
+        m.update_expect(('a', 3), Ext::None);
+        assert_eq!(m.emitted(), &[Ext::One(3)]);
+        m.update_expect(('b', 0), Ext::None);
+        assert_eq!(m.emitted(), &[Ext::One(6), Ext::One(-3)]);
+        m.init_expect(2, Ext::None);
+        m.update_expect(('a', 1), Ext::None);
+        assert_eq!(m.emitted(), &[Ext::Many]);
+        m.reset();
+        assert_eq!(m.emitted(), &[]);
+        m.update_expect(('a', 1), Ext::None);
+        assert_eq!(m.emitted(), &[]);
+        // Errors
+        let mut b = DataTransducerBuilder::<ExD, ExQ>::new();
+        let e = b.add_epsilon_iden(0, 1).unwrap();
+        assert_eq!(
+            b.add_output(e, |_, &q| q),
+            Err(BuildError::EpsilonOutput { trans: e })
+        );
+        assert_eq!(
+            b.add_output(TransRef::Update(0), |_, &q| q),
+            Err(BuildError::NoSuchTransition {
+                trans: TransRef::Update(0),
+                n_transs: 0
+            })
+        );
+    }
+
+    #[test]
+    fn test_final_states() {
+        // Two final states: the value of 'a' items, and of positive items
+        let mut m = DataTransducer::<ExD, ExQ>::new();
+        m.set_nstates(4);
+        m.add_transition0(2, |&d| d.0 == 'a', |&d| d.1);
+        m.add_transition0(3, |&d| d.1 > 0, |&d| d.1);
+        assert_eq!(m.final_states(), vec![1]);
+        m.set_final_states(&[2, 3]);
+        assert_eq!(m.final_states(), vec![2, 3]);
+        assert_eq!(m.remove_dead_states(), 0);
+        assert!(!m.is_nullable());
+        m.update_expect(('a', 0), Ext::One(0));
+        m.update_expect(('b', 2), Ext::One(2));
+        m.update_expect(('a', 2), Ext::Many);
+        m.update_expect(('b', 0), Ext::None);
+        // With a combiner
+        m.set_final_combiner(|qs| match (qs[0], qs[1]) {
+            (Ext::None, Ext::None) => Ext::None,
+            (Ext::One(&x), Ext::One(&y)) => Ext::One(x * y),
+            _ => Ext::One(-1),
+        });
+        m.update_expect(('a', 0), Ext::One(-1));
+        m.update_expect(('a', 2), Ext::One(4));
+        m.update_expect(('b', 0), Ext::None);
+        assert_eq!(
+            m.clone().try_remove_state(3),
+            Err(BuildError::RemoveReserved { id: 3 })
+        );
+        // Final states are not merged by minimization
+        let alphabet = [('a', 1), ('b', 1), ('a', 0), ('b', 0)];
+        let mut m2 = m.clone();
+        m2.add_state();
+        m2.add_transition0(4, |&d| d.0 == 'a', |&d| d.1);
+        m2.set_final_states(&[2, 3, 4]);
+        assert_eq!(m2.minimize(&alphabet), 0);
+        assert_eq!(
+            DataTransducerBuilder::<ExD, ExQ>::new().set_final_states(&[2]),
+            Err(BuildError::NoSuchState { id: 2, n_states: 2 })
+        );
+
+        // Combining machines uses the union of the final states
+        let mut m = DataTransducer::<ExD, ExQ>::new();
+        m.set_nstates(4);
+        m.name_state(2, "a");
+        m.name_state(3, "b");
+        m.add_transition1(0, 2, |&d| d.0 == 'a', |&d, &q| q + d.1);
+        m.add_transition1(0, 3, |&d| d.0 == 'b', |&d, &q| q - d.1);
+        m.add_iden(1, 1, |_| true);
+        m.set_final_states(&["a", "b"]);
+        let mut m = concat_machines(m, add_a_machine());
+        m.init_expect(10, Ext::None);
+        m.update_expect(('a', 1), Ext::None);
+        m.update_expect(('a', 2), Ext::One(13));
+        m.init_expect(10, Ext::One(13));
+        m.update_expect(('b', 1), Ext::None);
+        m.update_expect(('a', 2), Ext::One(11));
+        assert_eq!(m.state_id("a"), Some(4));
+    }
+
+    #[test]
+    #[should_panic(expected = "custom final combiner")]
+    fn test_final_combiner_concat() {
+        let mut m = add_a_machine();
+        m.set_final_combiner(|qs| ext_value::apply1(|&q| q, qs[0]));
+        concat_machines(m, add_a_machine());
+    }
+
+    #[test]
+    fn test_combine_priorities() {
+        // "First rule that applies": add 'a' items; otherwise multiply by
+        // positive items; otherwise keep the value
+        let first_rule = || {
+            let mut m = DataTransducer::<ExD, ExQ>::new();
+            m.set_nstates(3);
+            m.add_epsilon1(0, 2, |&q| q);
+            m.add_epsilon1(2, 1, |&q| q);
+            m.add_iden(2, 2, |_| true);
+            let mul = m.add_transition1(2, 2, |&d| d.1 > 0, |&d, &q| q * d.1);
+            let add =
+                m.add_transition1(2, 2, |&d| d.0 == 'a', |&d, &q| q + d.1);
+            m.set_priority(add, 2);
+            m.set_priority(mul, 1);
+            m.set_conflict_policy(ConflictPolicy::HighestPriority);
+            m
+        };
+        let mut m = union_machines(first_rule(), DataTransducer::new());
+        assert_eq!(m.conflict_policy(), ConflictPolicy::HighestPriority);
+        m.init_expect(1, Ext::One(1));
+        m.update_expect(('b', 3), Ext::One(3));
+        m.update_expect(('a', 3), Ext::One(6));
+        // The priorities of a machine without the policy have no effect
+        // (adding and subtracting 'a' items gives two values)
+        let mut both = add_a_machine();
+        let sub = both.add_transition1(0, 1, |&d| d.0 == 'a', |&d, &q| q - d.1);
+        both.set_priority(sub, 1);
+        let mut m = concat_machines(first_rule(), both);
+        m.init_expect(1, Ext::None);
+        m.update_expect(('b', 3), Ext::None);
+        m.update_expect(('a', 2), Ext::Many);
+        assert_eq!(m.priority(TransRef::Update(4)), Some(0));
+    }
+
+    #[test]
+    #[should_panic(expected = "can't combine machines with state guards")]
+    fn test_state_guard_union() {
+        let mut m = add_a_machine();
+        m.add_state_guard(TransRef::Update(0), |_, _| true);
+        union_machines(add_a_machine(), m);
+    }
+
+    #[test]
+    #[should_panic(expected = "can't combine machines with dispatch")]
+    fn test_dispatch_union() {
+        let mut m = add_a_machine();
+        m.set_dispatch(|d| d.0 as usize);
+        union_machines(m, add_a_machine());
+    }
+
+    #[test]
+    fn test_priorities() {
+        // "First rule that applies": add 'a' items; otherwise multiply by
+        // positive items; otherwise keep the value
+        let mut m = DataTransducer::<ExD, ExQ>::new();
+        m.set_nstates(3);
+        m.add_epsilon1(0, 2, |&q| q);
+        m.add_epsilon1(2, 1, |&q| q);
+        let keep = m.add_iden(2, 2, |_| true);
+        let mul = m.add_transition1(2, 2, |&d| d.1 > 0, |&d, &q| q * d.1);
+        let add = m.add_transition1(2, 2, |&d| d.0 == 'a', |&d, &q| q + d.1);
+        m.set_priority(add, 2);
+        m.set_priority(mul, 1);
+        assert_eq!(m.priority(add), Some(2));
+        assert_eq!(m.priority(keep), Some(0));
+        assert_eq!(m.priority(TransRef::Update(3)), None);
+        // By default, all values are combined
+        let mut m2 = m.clone();
+        m2.init_expect(1, Ext::One(1));
+        m2.update_expect(('b', 0), Ext::One(1));
+        m2.update_expect(('b', 3), Ext::Many);
+        m.set_conflict_policy(ConflictPolicy::HighestPriority);
+        m.init_expect(1, Ext::One(1));
+        m.update_expect(('b', 0), Ext::One(1));
+        m.update_expect(('b', 3), Ext::One(3));
+        m.update_expect(('a', 3), Ext::One(6));
+        m.update_expect(('a', -3), Ext::One(3));
+        // Transitions of equal priority are combined
+        m.set_priority(mul, 0);
+        m.update_expect(('b', 3), Ext::Many);
+        m.set_priority(mul, 1);
+        m.reset();
+        // Only values count: a transition of higher priority which is
+        // active but produces no value does not override others
+        m.init_expect(2, Ext::One(2));
+        let restart = m.add_transition1(0, 2, |_| true, |&d, &q| q + d.1);
+        m.set_priority(restart, 5);
+        m.update_expect(('b', 2), Ext::One(4));
+        m.update_expect(('b', 2), Ext::One(8));
+        // Errors
+        assert_eq!(m.eliminate_epsilons(), Err(BuildError::PriorityEpsilons));
+        let mut b = DataTransducerBuilder::<ExD, ExQ>::new();
+        let e = b.add_epsilon_iden(0, 1).unwrap();
+        assert_eq!(
+            b.set_priority(e, 1),
+            Err(BuildError::EpsilonPriority { trans: e })
+        );
+    }
+
+    #[test]
+    fn test_state_guards() {
+        // Count 'a' items, outputting the count on 'b' items once it is at
+        // least 2
+        let mut m = DataTransducer::<ExD, ExQ>::new();
+        m.set_nstates(3);
+        m.add_epsilon1(0, 2, |_| 0);
+        m.add_transition1(2, 2, |&d| d.0 == 'a', |_, &q| q + 1);
+        m.add_iden(2, 2, |&d| d.0 != 'a');
+        let out = m.add_iden(2, 1, |&d| d.0 == 'b');
+        m.add_state_guard(out, |_, qs| match qs[2] {
+            Ext::One(n) => n >= 2,
+            _ => false,
+        });
+        let mut m2 = m.clone();
+        m.init_expect(0, Ext::None);
+        m.update_expect(('a', 0), Ext::None);
+        m.update_expect(('b', 0), Ext::None);
+        m.update_expect(('a', 0), Ext::None);
+        m.update_expect(('b', 0), Ext::One(2));
+        // The guard sees the values before the item
+        m.update_expect(('a', 0), Ext::None);
+        m.update_expect(('c', 0), Ext::None);
+        m.update_expect(('b', 0), Ext::One(3));
+        // Same with dispatch and with epsilons eliminated
+        m2.set_dispatch(|&d| (d.0 == 'b') as usize);
+        m2.eliminate_epsilons().unwrap();
+        m2.init_expect(0, Ext::None);
+        m2.update_expect(('b', 0), Ext::None);
+        m2.update_expect(('a', 0), Ext::None);
+        m2.update_expect(('a', 0), Ext::None);
+        m2.update_expect(('b', 0), Ext::One(2));
+        let e = m2.add_epsilon_iden(0, 1);
+        assert_eq!(
+            m2.try_add_state_guard(e, |_, _| true),
+            Err(BuildError::EpsilonStateGuard { trans: e })
+        );
+    }
+
+    #[test]
+    fn test_timers() {
+        // A session opens on 'a' and stays open until 'c'; three items
+        // after it opens, output its value times 10
+        let mut m = DataTransducer::<ExD, ExQ>::new();
+        m.set_nstates(3);
+        m.add_iden(0, 0, |_| true);
+        m.add_transition1(0, 2, |&d| d.0 == 'a', |&d, _| d.1);
+        m.add_iden(2, 2, |&d| d.0 != 'c');
+        m.add_timer(2, 1, 3, |_, &q| q * 10);
+        assert_eq!(m.n_states(), 6);
+        let mut m2 = m.clone();
+        m.init_expect(0, Ext::None);
+        m.update_expect(('a', 4), Ext::None);
+        m.update_expect(('b', 0), Ext::None);
+        m.update_expect(('b', 0), Ext::None);
+        m.update_expect(('b', 0), Ext::One(40));
+        // Only when the state becomes set, not while it stays set
+        m.update_expect(('b', 0), Ext::None);
+        m.update_expect(('b', 0), Ext::None);
+        m.update_expect(('b', 0), Ext::None);
+        // The timer runs even if the session closes
+        m.update_expect(('c', 0), Ext::None);
+        m.update_expect(('a', 5), Ext::None);
+        m.update_expect(('c', 0), Ext::None);
+        m.update_expect(('a', 6), Ext::None);
+        m.update_expect(('b', 0), Ext::One(50));
+        m.update_expect(('b', 0), Ext::None);
+        m.update_expect(('b', 0), Ext::One(60));
+        // Same with dead states removed and minimized
+        assert_eq!(m2.remove_dead_states(), 0);
+        m2.minimize(&[('a', 1), ('b', 0), ('c', 0)]);
+        m2.init_expect(0, Ext::None);
+        m2.update_expect(('a', 7), Ext::None);
+        m2.update_expect(('c', 0), Ext::None);
+        m2.update_expect(('b', 0), Ext::None);
+        m2.update_expect(('b', 0), Ext::One(70));
+
+        // A timer of one item
+        let mut m = DataTransducer::<ExD, ExQ>::new();
+        m.add_timer(0, 1, 1, |&d, &q| q + d.1);
+        assert_eq!(m.n_states(), 3);
+        m.init_expect(1, Ext::None);
+        m.update_expect(('a', 2), Ext::One(3));
+        m.update_expect(('a', 2), Ext::None);
+        let mut b = DataTransducerBuilder::<ExD, ExQ>::new();
+        assert_eq!(
+            b.add_timer(0, 1, 0, |_, &q| q).unwrap_err(),
+            BuildError::ZeroTimer
+        );
+        assert_eq!(b.n_states(), 2);
+    }
+
+    state_enum! {
+        #[derive(Clone, Debug, PartialEq)]
+        enum AvgState {
+            Pair((isize, usize)),
+            Avg(f64),
+        }
+    }
+
+    #[test]
+    fn test_typed_states() {
+        // Running average of the 'a' items: state 2 holds the sum and
+        // count, and the output is their quotient
+        let mut m = DataTransducer::<ExD, AvgState>::new();
+        m.set_nstates(3);
+        m.add_typed_epsilon1(0, 2, |&x: &f64| (x as isize, 0));
+        m.add_typed_transition1(
+            2,
+            2,
+            |&d| d.0 == 'a',
+            |&d, &(sum, count): &(isize, usize)| (sum + d.1, count + 1),
+        );
+        m.add_iden(2, 2, |&d| d.0 != 'a');
+        m.add_typed_transition1(
+            2,
+            1,
+            |&d| d.0 == 'a',
+            |&d, &(sum, count): &(isize, usize)| {
+                (sum + d.1) as f64 / (count + 1) as f64
+            },
+        );
+        // (AvgState is not Eq, so init_expect etc. can't be used)
+        assert_eq!(m.init_one(AvgState::Avg(0.0)), Ext::None);
+        assert_eq!(m.update_val(('a', 3)), Ext::One(AvgState::Avg(3.0)));
+        assert_eq!(m.update_val(('b', 0)), Ext::None);
+        assert_eq!(m.update_val(('a', 4)), Ext::One(AvgState::Avg(3.5)));
+        assert_eq!(
+            <(isize, usize)>::from_state(&AvgState::Pair((7, 2))),
+            Some(&(7, 2))
+        );
+        assert_eq!(f64::from_state(&AvgState::Pair((7, 2))), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "state value is not of type f64")]
+    fn test_typed_states_mismatch() {
+        let mut m = DataTransducer::<ExD, AvgState>::new();
+        m.add_typed_transition1(0, 1, |_| true, |_, &x: &f64| x);
+        m.init_one(AvgState::Pair((0, 0)));
+        m.update_val(('a', 0));
+    }
+
+    #[test]
+    fn test_subscriptions() {
+        use std::cell::RefCell;
+        use std::sync::mpsc;
+        // sum_machine, notifying when the sum is first computed and when
+        // the output first appears
+        let log = Rc::new(RefCell::new(vec![]));
+        let (tx, rx) = mpsc::channel();
+        let mut m = sum_machine();
+        let log2 = log.clone();
+        m.subscribe("sum", move |q| log2.borrow_mut().push(*q));
+        m.subscribe(1, move |q| tx.send(*q).unwrap());
+        // The subscribed state is kept
+        m.add_state();
+        m.remove_state(3);
+        assert_eq!(
+            m.try_remove_state("sum"),
+            Err(BuildError::RemoveReserved { id: 2 })
+        );
+        m.init_one(1);
+        assert_eq!(*log.borrow(), vec![Ext::One(1)]);
+        m.update_val(('b', 0));
+        m.update_val(('a', 2));
+        m.init_one(5);
+        assert_eq!(*log.borrow(), vec![Ext::One(1)]);
+        assert_eq!(rx.try_iter().collect::<Vec<_>>(), vec![Ext::One(3)]);
+        m.update_val(('a', 2));
+        m.update_val(('a', 2));
+        assert_eq!(rx.try_iter().collect::<Vec<_>>(), vec![]);
+        m.reset();
+        m.update_batch(&[('a', 2)]);
+        m.init_one(0);
+        m.update_batch(&[('a', 2), ('c', 0)]);
+        assert_eq!(*log.borrow(), vec![Ext::One(1), Ext::One(0)]);
+        assert_eq!(rx.try_iter().collect::<Vec<_>>(), vec![Ext::One(2)]);
+        // Subscriptions are kept when combining machines
+        let mut m = union_machines(add_a_machine(), m);
+        m.init_one(1);
+        m.update_val(('a', 1));
+        assert_eq!(*log.borrow(), vec![Ext::One(1), Ext::One(0), Ext::One(1)]);
+        assert_eq!(rx.try_iter().collect::<Vec<_>>(), vec![Ext::One(2)]);
+        m.clear_subscriptions();
+        m.init_one(1);
+        assert_eq!(log.borrow().len(), 3);
+    }
+
+    #[test]
+    fn test_analyze() {
+        let alphabet = [('a', 1), ('b', 1)];
+        assert!(sum_machine().analyze(&alphabet).is_clean());
+        assert_eq!(
+            sum_machine().analyze(&alphabet).to_string(),
+            "no problems found\n"
+        );
+        let mut m = sum_machine();
+        m.set_nstates(7);
+        // 3: only reachable on 'c' items; 4: can't reach the final state;
+        // 5 and 6: on an epsilon cycle (and unreachable)
+        let t = m.add_transition1(2, 3, |&d| d.0 == 'c', |_, &q| q);
+        m.add_iden(3, 1, |_| true);
+        m.add_iden(2, 4, |_| true);
+        m.add_epsilon_iden(5, 6);
+        m.add_epsilon1(6, 5, |&q| q + 1);
+        m.add_epsilon_iden(6, 1);
+        let report = m.analyze(&alphabet);
+        assert_eq!(
+            report,
+            Report {
+                unreachable: vec![3, 5, 6],
+                dead: vec![4],
+                epsilon_cycles: vec![vec![5, 6]],
+                never_active: vec![t],
+            }
+        );
+        assert_eq!(
+            report.to_string(),
+            "unreachable states: [3, 5, 6]\n\
+             states not affecting the output: [4]\n\
+             epsilon cycle through states: [5, 6]\n\
+             transitions never active: [Update(3)]\n"
+        );
+        assert_eq!(m.analyze(&[('c', 0)]).unreachable, vec![5, 6]);
+    }
+
+    #[test]
+    fn test_sym_guards() {
+        use crate::guard::{field, pred};
+        let is_a = pred("is_a", |d: &ExD| d.0 == 'a');
+        let val = field("val", |d: &ExD| d.1 as i64);
+        // Sum of the nonnegative values of 'a' items, but small ones
+        // (0..=9) count double
+        let mut m = DataTransducer::new();
+        m.add_epsilon_iden(0, 1);
+        let big = is_a.clone() & val.at_least(10);
+        let small = is_a.clone() & val.in_range(0, 9);
+        let t1 = m.add_sym_transition1(1, 1, big, |d, &q| q + d.1);
+        let t2 = m.add_sym_transition1(1, 1, small, |d, &q| q + 2 * d.1);
+        let t3 = m.add_sym_iden(1, 1, !(is_a.clone() & val.at_least(0)));
+        m.init_expect(0, Ext::One(0));
+        m.update_expect(('a', 12), Ext::One(12));
+        m.update_expect(('a', 3), Ext::One(18));
+        m.update_expect(('a', -3), Ext::One(18));
+        m.update_expect(('b', 5), Ext::One(18));
+        // Inspecting the guards
+        assert_eq!(
+            m.symbolic_guard(t1).unwrap().to_string(),
+            "(is_a & val >= 10)"
+        );
+        assert!(m.symbolic_guard(TransRef::Epsilon(0)).is_none());
+        assert!(m.overlapping_guards().is_empty());
+        // An overlapping and an unsatisfiable guard
+        let t4 = m.add_sym_transition1(1, 1, val.equals(5), |_, &q| q);
+        let t5 =
+            m.add_sym_transition0(1, val.at_least(3) & val.at_most(2), |_| 0);
+        assert_eq!(m.overlapping_guards(), vec![(t2, t4), (t3, t4)]);
+        assert_eq!(
+            m.analyze(&[('a', 5), ('a', 20), ('b', 0)]).never_active,
+            vec![t5]
+        );
+        // Preserved by the optimization passes
+        m.eliminate_epsilons().unwrap();
+        assert!(m.symbolic_guard(t1).is_some());
+        let mut b = DataTransducerBuilder::<_, ExQ>::new();
+        let t = b.add_sym_iden(0, 1, is_a).unwrap();
+        assert!(b.build().symbolic_guard(t).is_some());
+    }
+
+    #[test]
+    fn test_dispatch() {
+        // Same as test_popl19_ex1, but guards are only checked on the first
+        // item with each tag
+        use std::cell::Cell;
+        let guard_calls = Cell::new(0);
+        let is_a = |d: &ExD| {
+            guard_calls.set(guard_calls.get() + 1);
+            d.0 == 'a'
+        };
+        let mut m = DataTransducer::<ExD, ExQ>::new();
+        m.set_nstates(4);
+        m.set_dispatch(|&d| d.0 as usize);
+        m.add_iden(0, 0, |_d| true);
+        m.add_iden(2, 2, |&d| d.0 == 'b');
+        m.add_iden(3, 3, |&d| d.0 == 'b');
+        m.add_transition1(0, 3, is_a, |&d, _q| d.1);
+        m.add_transition1(3, 2, is_a, |&d, &q| q + d.1);
+        m.add_transition1(2, 1, is_a, |&d, &q| q + d.1);
+        m.init_expect(0, Ext::None);
+        m.update_expect(('a', 6), Ext::None);
+        m.update_expect(('b', 2), Ext::None);
+        m.update_expect(('a', 5), Ext::None);
+        m.update_expect(('a', 7), Ext::One(18));
+        m.update_expect(('b', 2), Ext::None);
+        m.update_expect(('a', 8), Ext::One(20));
+        m.update_expect(('#', 0), Ext::None);
+        assert_eq!(guard_calls.get(), 9);
+        // Adding a transition invalidates the index
+        m.add_iden(1, 1, |&d| d.0 == 'b');
+        m.update_expect(('b', 2), Ext::None);
+        m.update_expect(('a', 2), Ext::None);
+        m.update_expect(('b', 2), Ext::None);
+        assert_eq!(guard_calls.get(), 15);
+        m.clear_dispatch();
+        m.update_expect(('a', 2), Ext::None);
+        assert_eq!(guard_calls.get(), 18);
+    }
+
+    #[test]
+    fn test_update_batch() {
+        let mut m1 = DataTransducer::<ExD, ExQ>::new();
+        m1.set_nstates(3);
+        m1.add_iden(0, 2, |_d| true);
+        m1.add_transition1(2, 2, |&d| d.0 == 'a', |&d, &q| q + d.1);
+        m1.add_iden(2, 2, |&d| d.0 == 'b');
+        m1.add_epsilon1(2, 1, |&q| q);
+        let mut m2 = m1.clone();
+        let items = [('a', 1), ('b', 5), ('a', 2), ('c', 0), ('a', 3)];
+        m1.init_one(10);
+        m2.init_one(10);
+        let expected: Vec<_> = items.iter().map(|d| m1.update(d)).collect();
+        assert_eq!(
+            expected,
+            vec![
+                Ext::One(10),
+                Ext::One(10),
+                Ext::One(12),
+                Ext::None,
+                Ext::None
+            ]
+        );
+        assert_eq!(m2.update_batch(&items), expected);
+        assert_eq!(m2.update_batch(&[]), vec![]);
+
+        // Subscribers are notified as with .update(), also when falling
+        // back to one item at a time (here when recording)
+        use std::cell::RefCell;
+        for recording in [false, true] {
+            let logs = [(); 2].map(|_| Rc::new(RefCell::new(Vec::new())));
+            let mut ms = [m1.clone(), m1.clone()];
+            for (m, log) in ms.iter_mut().zip(&logs) {
+                let log = log.clone();
+                m.reset();
+                m.subscribe(1, move |q| log.borrow_mut().push(*q));
+                if recording {
+                    m.start_recording();
+                }
+            }
+            for i in [10, 1] {
+                ms[0].init_one(i);
+                ms[1].init_one(i);
+                for d in &items {
+                    ms[0].update(d);
+                }
+                ms[1].update_batch(&items);
+            }
+            assert_eq!(*logs[0].borrow(), vec![Ext::One(10), Ext::One(1)]);
+            assert_eq!(*logs[1].borrow(), *logs[0].borrow());
+        }
+    }
+
+    #[test]
+    fn test_update_chunk() {
+        use std::cell::RefCell;
+        // Guards log their evaluations, to check they run a column at a time
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let (log1, log2) = (log.clone(), log.clone());
+        let mut m1 = DataTransducer::<ExD, ExQ>::new();
+        m1.set_nstates(3);
+        m1.add_iden(0, 2, |_d| true);
+        m1.add_transition1(
+            2,
+            2,
+            move |&d| {
+                log1.borrow_mut().push(('a', d.1));
+                d.0 == 'a'
+            },
+            |&d, &q| q + d.1,
+        );
+        let tr = m1.add_transition1(
+            2,
+            2,
+            move |&d| {
+                log2.borrow_mut().push(('b', d.1));
+                d.0 == 'b'
+            },
+            |&d, &q| q * d.1,
+        );
+        m1.add_epsilon1(2, 1, |&q| q);
+        m1.set_priority(tr, 1);
+        let mut m2 = m1.clone();
+        let items = [('a', 1), ('b', 5), ('a', 2), ('c', 0)];
+        m1.init_one(0);
+        m2.init_one(0);
+        let expected: Vec<_> = items.iter().map(|d| m1.update(d)).collect();
+        log.borrow_mut().clear();
+        assert_eq!(m2.update_chunk(&items), expected);
+        let guards: Vec<char> = log.borrow().iter().map(|e| e.0).collect();
+        // (not on the first item, where state 2 has no value yet)
+        assert_eq!(guards, vec!['a', 'a', 'a', 'b', 'b', 'b']);
+
+        // The guard of a transition is only evaluated from the first item
+        // where its source state has a value (here after the first 'c')
+        let log3 = log.clone();
+        let mut m3 = DataTransducer::<ExD, ExQ>::new();
+        m3.set_nstates(4);
+        m3.add_epsilon_iden(0, 3);
+        m3.add_iden(3, 3, |&d| d.0 != 'c');
+        m3.add_iden(3, 2, |&d| d.0 == 'c');
+        m3.add_transition1(
+            2,
+            2,
+            move |&d| {
+                log3.borrow_mut().push(('x', d.1));
+                true
+            },
+            |&d, &q| q + d.1,
+        );
+        m3.add_epsilon_iden(2, 1);
+        let mut m4 = m3.clone();
+        let items = [('a', 1), ('c', 0), ('a', 2), ('b', 3)];
+        m3.init_one(0);
+        m4.init_one(0);
+        let expected: Vec<_> = items.iter().map(|d| m3.update(d)).collect();
+        assert_eq!(expected[3], Ext::One(5));
+        log.borrow_mut().clear();
+        assert_eq!(m4.update_chunk(&items), expected);
+        assert_eq!(*log.borrow(), vec![('x', 2), ('x', 3)]);
+
+        // Under a conflict policy, and across chunks in .update_batch()
+        m1.set_conflict_policy(ConflictPolicy::HighestPriority);
+        m2.set_conflict_policy(ConflictPolicy::HighestPriority);
+        m1.add_iden(2, 2, |&d| d.0 == 'b');
+        m2.add_iden(2, 2, |&d| d.0 == 'b');
+        let items: Vec<ExD> = (0..3 * CHUNK_SIZE as isize)
+            .map(|x| (['a', 'b', 'c', 'a'][x as usize % 4], x % 3 - 1))
+            .collect();
+        let expected: Vec<_> = items.iter().map(|d| m1.update(d)).collect();
+        assert_eq!(m2.update_batch(&items), expected);
+        // With dispatch enabled, falls back to one item at a time
+        m1.set_dispatch(|d| d.0 as usize);
+        m2.set_dispatch(|d| d.0 as usize);
+        let expected: Vec<_> = items.iter().map(|d| m1.update(d)).collect();
+        assert_eq!(m2.update_chunk(&items), expected);
+    }
+
+    #[test]
+    fn test_output_ref() {
+        // Two final states: the input so far, and the last 'a' item
+        let mut m = DataTransducer::<ExD, ExQ>::new();
+        m.set_nstates(3);
+        m.add_epsilon1(0, 1, |&q| q);
+        m.add_iden(1, 1, |&d| d.0 != 'a');
+        m.add_transition1(1, 2, |&d| d.0 == 'a', |&d, _| d.1);
+        m.set_final_states(&[1, 2]);
+        assert!(matches!(m.init_ref(Ext::One(7)), Cow::Borrowed(&Ext::One(7))));
+        assert!(matches!(m.update_ref(&('b', 0)), Cow::Borrowed(_)));
+        assert!(matches!(m.update_ref(&('a', 3)), Cow::Borrowed(&Ext::One(3))));
+        assert!(matches!(m.update_ref(&('b', 0)), Cow::Owned(Ext::None)));
+        m.init_one(1);
+        m.update_val(('a', 2));
+        m.init_one(1);
+        assert_eq!(*m.output(), Ext::Many);
+        assert!(matches!(m.output(), Cow::Owned(Ext::Many)));
+        assert_eq!(m.output().into_owned(), m.init(Ext::None));
+        // With a combiner, the output is computed
+        m.set_final_combiner(|args| match (args[0], args[1]) {
+            (Ext::One(&q1), Ext::One(&q2)) => Ext::One(q1 + q2),
+            _ => Ext::None,
+        });
+        assert!(matches!(m.output(), Cow::Owned(Ext::One(3))));
+    }
+
+    // Transitions of test_popl19_ex1, as a static type
+    enum Ex1Trans {
+        Keep,
+        KeepOnB,
+        FirstA,
+        AddA,
+    }
+    impl Transition<ExD, ExQ> for Ex1Trans {
+        fn arity(&self) -> usize {
+            1
+        }
+        fn is_active(&self, item: &ExD) -> bool {
+            match self {
+                Ex1Trans::Keep => true,
+                Ex1Trans::KeepOnB => item.0 == 'b',
+                Ex1Trans::FirstA | Ex1Trans::AddA => item.0 == 'a',
+            }
+        }
+        fn apply(&self, item: &ExD, args: &[Ext<&ExQ>]) -> Ext<ExQ> {
+            let f = |&q: &ExQ| match self {
+                Ex1Trans::Keep | Ex1Trans::KeepOnB => q,
+                Ex1Trans::FirstA => item.1,
+                Ex1Trans::AddA => q + item.1,
+            };
+            ext_value::apply1(f, args[0])
+        }
+        fn is_iden(&self) -> bool {
+            matches!(self, Ex1Trans::Keep | Ex1Trans::KeepOnB)
+        }
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn test_update_par() {
+        use crate::random::Rng;
+        // A machine of static transitions, with random sources, targets
+        // and priorities (and few transitions to the final state)
+        let mut rng = Rng::new(2873);
+        let mut m1 = DataTransducer::<ExD, ExQ, Ex1Trans>::default();
+        m1.set_nstates(30);
+        for _ in 0..60 {
+            let source = rng.below(30) as usize;
+            let target = match rng.below(20) {
+                0 => 1,
+                _ => 2 + rng.below(28) as usize,
+            };
+            let tr = match rng.below(4) {
+                0 => Ex1Trans::Keep,
+                1 => Ex1Trans::KeepOnB,
+                2 => Ex1Trans::FirstA,
+                _ => Ex1Trans::AddA,
+            };
+            let tr = m1.add_static_transition(&[source], target, tr);
+            m1.set_priority(tr, rng.below(3) as i32);
+        }
+        for policy in [ConflictPolicy::Union, ConflictPolicy::HighestPriority] {
+            m1.set_conflict_policy(policy);
+            m1.reset();
+            let mut m2 = m1.clone();
+            let mut n_one = 0;
+            for step in 0..200 {
+                if step % 40 == 0 {
+                    assert_eq!(m2.init_one(step), m1.init_one(step));
+                }
+                let ch = ['a', 'b', '#'][rng.below(3) as usize];
+                let item = (ch, rng.below(10) as isize);
+                let out = m1.update(&item);
+                n_one += out.is_one() as usize;
+                assert_eq!(m2.update_par(&item), out);
+                assert_eq!(m2.get_states(), m1.get_states());
+            }
+            assert!(n_one > 0);
+        }
+    }
+
+    #[test]
+    fn test_popl19_ex1_static() {
+        let mut m = DataTransducer::<ExD, ExQ, Ex1Trans>::default();
+        m.set_nstates(4);
+        m.add_static_transition(&[0], 0, Ex1Trans::Keep);
+        m.add_static_transition(&[2], 2, Ex1Trans::KeepOnB);
+        m.add_static_transition(&[3], 3, Ex1Trans::KeepOnB);
+        m.add_static_transition(&[0], 3, Ex1Trans::FirstA);
+        m.add_static_transition(&[3], 2, Ex1Trans::AddA);
+        m.add_static_transition(&[2], 1, Ex1Trans::AddA);
+        assert_eq!(m.n_transs(), 6);
+        m.init_expect(0, Ext::None);
+        m.update_expect(('a', 6), Ext::None);
+        m.update_expect(('b', 2), Ext::None);
+        m.update_expect(('a', 5), Ext::None);
+        m.update_expect(('a', 7), Ext::One(18));
+        m.update_expect(('b', 2), Ext::None);
+        m.update_expect(('a', 8), Ext::One(20));
+        m.update_expect(('#', 0), Ext::None);
+        m.update_expect(('a', 2), Ext::None);
+        // Analyses and passes work as for closures
+        assert_eq!(m.minimize(&[('a', 0), ('b', 0), ('#', 0)]), 0);
+        assert_eq!(m.n_transs(), 6);
+    }
+
+    #[cfg(feature = "arena")]
+    #[test]
+    fn test_arena() {
+        let arena = Bump::new();
+        let tag = 'a';
+        let mut m = ArenaTransducer::<ExD, ExQ>::default();
+        m.set_nstates(4);
+        m.add_iden_in(&arena, 0, 0, |_d| true);
+        m.add_iden_in(&arena, 2, 2, |&d| d.0 == 'b');
+        m.add_iden_in(&arena, 3, 3, |&d| d.0 == 'b');
+        m.add_transition1_in(&arena, 0, 3, move |&d| d.0 == tag, |&d, _q| d.1);
+        m.add_transition1_in(
+            &arena,
+            3,
+            2,
+            move |&d| d.0 == tag,
+            |&d, &q| q + d.1,
+        );
+        m.add_transition1_in(
+            &arena,
+            2,
+            1,
+            move |&d| d.0 == tag,
+            |&d, &q| q + d.1,
+        );
+        m.add_transition0_in(&arena, 2, |&d| d.0 == '!', |&d| d.1);
+        m.add_transition2_in(
+            &arena,
+            0,
+            2,
+            1,
+            |&d| d.0 == '!',
+            |_, &x, &y| x * y,
+        );
+        m.init_expect(0, Ext::None);
+        m.update_expect(('a', 6), Ext::None);
+        m.update_expect(('b', 2), Ext::None);
+        m.update_expect(('a', 5), Ext::None);
+        m.update_expect(('a', 7), Ext::One(18));
+        m.update_expect(('!', 3), Ext::One(0));
+        m.update_expect(('a', 8), Ext::One(11));
+        // The guards capturing tag were allocated in the arena
+        assert!(arena.allocated_bytes() >= 3 * mem::size_of::<char>());
+    }
+
+    #[test]
+    fn test_static_errors() {
+        let mut b = DataTransducerBuilder::<ExD, ExQ, Ex1Trans>::default();
+        b.set_nstates(3).unwrap();
+        assert_eq!(
+            b.add_static_transition::<usize>(&[], 2, Ex1Trans::Keep),
+            Err(BuildError::Arity { arity: 1, n_sources: 0 })
+        );
+        assert_eq!(
+            b.add_static_transition(&[3], 2, Ex1Trans::AddA),
+            Err(BuildError::NoSuchState { id: 3, n_states: 3 })
+        );
+        assert_eq!(
+            b.add_static_transition(&[2], 1, Ex1Trans::AddA),
+            Ok(TransRef::Update(0))
+        );
+    }
+
+    #[test]
+    fn test_popl19_ex2() {
+        // Initialize
+        let mut m = DataTransducer::<ExD, ExQ>::new();
+        m.set_nstates(4);
+        // 0: Initial, previous window average
+        // 1: Final, end of window average
+        // 2: Sum of 'a' events in current window
+        // 3: Count of 'a' events in current window
+        m.add_iden(0, 0, |&d| d.0 == 'b');
+        m.add_iden(2, 2, |&d| d.0 == 'b');
+        m.add_iden(3, 3, |&d| d.0 == 'b');
+        m.add_transition1(0, 2, |&d| d.0 == 'a', |&d, _q| d.1);
+        m.add_transition1(0, 3, |&d| d.0 == 'a', |_d, _q| 1);
+        m.add_transition1(2, 2, |&d| d.0 == 'a', |&d, &q| q + d.1);
+        m.add_transition1(3, 3, |&d| d.0 == 'a', |_d, &q| q + 1);
+        m.add_transition2(2, 3, 1, |&d| d.0 == '#', |_d, &q2, &q3| q2 / q3);
+        m.add_iden(0, 1, |&d| d.0 == '#');
+        m.add_epsilon1(1, 0, |&q| q);
+        assert_eq!(m.n_states(), 4);
+        assert_eq!(m.n_transs(), 10);
+        // Test
+        m.init_expect(0, Ext::None);
+        m.update_expect(('b', 2), Ext::None);
+        m.update_expect(('a', 6), Ext::None);
+        m.update_expect(('b', 2), Ext::None);
+        m.update_expect(('a', 8), Ext::None);
+        m.update_expect(('a', 9), Ext::None);
+        // (6 + 8 + 9) / 3 = 7
+        m.update_expect(('#', 0), Ext::One(7));
+        m.update_expect(('b', 2), Ext::None);
+        m.update_expect(('#', 2), Ext::One(7));
+        m.update_expect(('a', 2), Ext::None);
+        m.update_expect(('#', 0), Ext::One(2));
+    }
+
+    #[test]
+    fn test_popl19_ex3() {
+        // Initialize
+        let mut m = DataTransducer::<ExD, ExQ>::new();
+        m.set_nstates(7);
+        // 0: Initial, Ext::One initially and after each '#'
+        // 1: Final, stores the final answer after each '#'
+        // 2: Ext::One if we haven't yet seen an 'a'
+        // 3: Ext::One if we haven't yet seen a 'b'
+        // 4: Max 'a' if we have seen at least one
+        // 5: Max 'b' if we have seen at least one
+        // 6: Ext::One always (used to set state 0 on '#')
+        m.add_epsilon1(0, 2, |_q| 0);
+        m.add_epsilon1(0, 3, |_q| 0);
+        m.add_epsilon1(0, 6, |_q| 0);
+        assert!(m.is_epsilon());
+        m.add_iden(2, 2, |&d| d.0 == 'b');
+        m.add_iden(4, 4, |&d| d.0 == 'b');
+        m.add_iden(3, 3, |&d| d.0 == 'a');
+        m.add_iden(5, 5, |&d| d.0 == 'a');
+        m.add_iden(6, 6, |&d| d.0 != '#');
+        m.add_transition1(2, 4, |&d| d.0 == 'a', |&d, _q| d.1);
+        m.add_transition1(4, 4, |&d| d.0 == 'a', |&d, &q| q.max(d.1));
+        m.add_transition1(3, 5, |&d| d.0 == 'b', |&d, _q| d.1);
+        m.add_transition1(5, 5, |&d| d.0 == 'b', |&d, &q| q.max(d.1));
+        m.add_transition2(4, 5, 1, |&d| d.0 == '#', |_d, &q4, &q5| q4 - q5);
+        m.add_transition1(6, 0, |&d| d.0 == '#', |_d, _q| 0);
+        assert_eq!(m.n_transs(), 14);
+        // Test
+        m.init_expect(0, Ext::None);
+        m.update_expect(('b', 2), Ext::None);
+        m.update_expect(('a', 6), Ext::None);
+        m.update_expect(('b', 3), Ext::None);
+        m.update_expect(('b', 1), Ext::None);
+        m.update_expect(('a', 8), Ext::None);
+        m.update_expect(('#', 0), Ext::One(5));
+        m.update_expect(('b', 2), Ext::None);
+        m.update_expect(('#', 2), Ext::None);
+        m.update_expect(('a', 1), Ext::None);
+        m.update_expect(('a', 3), Ext::None);
+        m.update_expect(('#', 2), Ext::None);
+        m.update_expect(('a', 7), Ext::None);
+        m.update_expect(('b', 1), Ext::None);
+        m.update_expect(('#', 0), Ext::One(6));
+    }
+
+    #[test]
+    fn test_popl19_ex3_trans0() {
+        // Same as test_popl19_ex3, but using a constant transition to set
+        // state 0 on '#', instead of an extra state which is always set
+        let mut m = DataTransducer::<ExD, ExQ>::new();
+        m.set_nstates(6);
+        m.add_epsilon1(0, 2, |_q| 0);
+        m.add_epsilon1(0, 3, |_q| 0);
+        m.add_iden(2, 2, |&d| d.0 == 'b');
+        m.add_iden(4, 4, |&d| d.0 == 'b');
+        m.add_iden(3, 3, |&d| d.0 == 'a');
+        m.add_iden(5, 5, |&d| d.0 == 'a');
+        m.add_transition1(2, 4, |&d| d.0 == 'a', |&d, _q| d.1);
+        m.add_transition1(4, 4, |&d| d.0 == 'a', |&d, &q| q.max(d.1));
+        m.add_transition1(3, 5, |&d| d.0 == 'b', |&d, _q| d.1);
+        m.add_transition1(5, 5, |&d| d.0 == 'b', |&d, &q| q.max(d.1));
+        m.add_transition2(4, 5, 1, |&d| d.0 == '#', |_d, &q4, &q5| q4 - q5);
+        m.add_transition0(0, |&d| d.0 == '#', |_d| 0);
+        assert_eq!(m.n_states(), 6);
+        assert_eq!(m.n_transs(), 12);
+        m.init_expect(0, Ext::None);
+        m.update_expect(('b', 2), Ext::None);
+        m.update_expect(('a', 6), Ext::None);
+        m.update_expect(('b', 3), Ext::None);
+        m.update_expect(('b', 1), Ext::None);
+        m.update_expect(('a', 8), Ext::None);
+        m.update_expect(('#', 0), Ext::One(5));
+        m.update_expect(('b', 2), Ext::None);
+        m.update_expect(('#', 2), Ext::None);
+        m.update_expect(('a', 1), Ext::None);
+        m.update_expect(('a', 3), Ext::None);
+        m.update_expect(('#', 2), Ext::None);
+        m.update_expect(('a', 7), Ext::None);
+        m.update_expect(('b', 1), Ext::None);
+        m.update_expect(('#', 0), Ext::One(6));
+    }
+
+    #[test]
+    fn test_trans0() {
+        let mut m = DataTransducer::<ExD, ExQ>::new();
+        m.set_nstates(3);
+        // 2: Ext::One always, with the last item value
+        // 1: Final, the last item value plus the initial value
+        m.add_transition0(2, |_d| true, |&d| d.1);
+        m.add_epsilon2(0, 2, 1, |&q0, &q2| q0 + q2);
+        m.add_epsilon0(0, || 100);
+        assert!(!m.is_epsilon());
+        assert!(!m.is_nullable());
+        m.update_expect(('a', 1), Ext::One(101));
+        m.update_expect(('a', 2), Ext::One(102));
+        m.init_expect(3, Ext::Many);
+        m.update_expect(('a', 4), Ext::One(104));
+        m.reset();
+        m.init_expect(3, Ext::None);
+    }
+
+    #[test]
+    fn test_builder() -> Result<(), BuildError> {
+        // Same as test_popl19_ex1, using the builder
+        let mut b = DataTransducerBuilder::<ExD, ExQ>::new();
+        b.set_nstates(4)?;
+        b.add_iden(0, 0, |_d| true)?;
+        b.add_iden(2, 2, |&d| d.0 == 'b')?;
+        b.add_iden(3, 3, |&d| d.0 == 'b')?;
+        b.add_transition1(0, 3, |&d| d.0 == 'a', |&d, _q| d.1)?;
+        b.add_transition1(3, 2, |&d| d.0 == 'a', |&d, &q| q + d.1)?;
+        b.add_transition1(2, 1, |&d| d.0 == 'a', |&d, &q| q + d.1)?;
+        let mut m = b.build();
+        assert_eq!(m.n_states(), 4);
+        assert_eq!(m.n_transs(), 6);
+        m.init_expect(0, Ext::None);
+        m.update_expect(('a', 6), Ext::None);
+        m.update_expect(('b', 2), Ext::None);
+        m.update_expect(('a', 5), Ext::None);
+        m.update_expect(('a', 7), Ext::One(18));
+        Ok(())
+    }
+
+    #[test]
+    fn test_builder_errors() {
+        let mut b = DataTransducerBuilder::<ExD, ExQ>::new();
+        assert_eq!(b.set_nstates(5), Ok(()));
+        assert_eq!(
+            b.set_nstates(4),
+            Err(BuildError::ShrinkStates { current: 5, requested: 4 })
+        );
+        assert_eq!(
+            b.add_iden(3, 5, |_| false),
+            Err(BuildError::NoSuchState { id: 5, n_states: 5 })
+        );
+        assert_eq!(
+            b.add_transition2(7, 1, 3, |_| false, |_, _, _| 0),
+            Err(BuildError::NoSuchState { id: 7, n_states: 5 })
+        );
+        assert_eq!(
+            b.add_epsilon0(6, || 0),
+            Err(BuildError::NoSuchState { id: 6, n_states: 5 })
+        );
+        assert_eq!(b.add_state(), 5);
+        assert_eq!(b.add_epsilon0(5, || 0), Ok(TransRef::Epsilon(0)));
+        // Failed additions have no effect
+        let m = b.build();
+        assert_eq!(m.n_states(), 6);
+        assert_eq!(m.n_transs(), 1);
+    }
+
+    #[test]
+    fn test_popl19_ex3_named() {
+        // Same as test_popl19_ex3, using state labels
+        let mut m = DataTransducer::<ExD, ExQ>::new();
+        m.name_state(0, "start");
+        m.name_state(1, "result");
+        m.add_state_named("no_a");
+        m.add_state_named("no_b");
+        m.add_state_named("max_a");
+        m.add_state_named("max_b");
+        m.add_state_named("always");
+        assert_eq!(m.n_states(), 7);
+        assert_eq!(m.state_id("max_a"), Some(4));
+        assert_eq!(m.state_name(6), Some("always"));
+        m.add_epsilon1("start", "no_a", |_q| 0);
+        m.add_epsilon1("start", "no_b", |_q| 0);
+        m.add_epsilon1("start", "always", |_q| 0);
+        m.add_iden("no_a", "no_a", |&d| d.0 == 'b');
+        m.add_iden("max_a", "max_a", |&d| d.0 == 'b');
+        m.add_iden("no_b", "no_b", |&d| d.0 == 'a');
+        m.add_iden("max_b", "max_b", |&d| d.0 == 'a');
+        m.add_iden("always", "always", |&d| d.0 != '#');
+        m.add_transition1("no_a", "max_a", |&d| d.0 == 'a', |&d, _q| d.1);
+        m.add_transition1(
+            "max_a",
+            "max_a",
+            |&d| d.0 == 'a',
+            |&d, &q| q.max(d.1),
+        );
+        m.add_transition1("no_b", "max_b", |&d| d.0 == 'b', |&d, _q| d.1);
+        m.add_transition1(
+            "max_b",
+            "max_b",
+            |&d| d.0 == 'b',
+            |&d, &q| q.max(d.1),
+        );
+        m.add_transition2(
+            "max_a",
+            "max_b",
+            "result",
+            |&d| d.0 == '#',
+            |_d, &qa, &qb| qa - qb,
+        );
+        m.add_transition1("always", 0, |&d| d.0 == '#', |_d, _q| 0);
+        assert_eq!(m.n_transs(), 14);
+        m.init_expect(0, Ext::None);
+        m.update_expect(('b', 2), Ext::None);
+        m.update_expect(('a', 6), Ext::None);
+        m.update_expect(('b', 3), Ext::None);
+        m.update_expect(('a', 8), Ext::None);
+        m.update_expect(('#', 0), Ext::One(5));
+        // Debug output uses the labels
+        let debug = format!("{:?}", m);
+        assert!(debug.contains("[max_a max_b -> result]"));
+        assert!(debug.contains("[always -> start]"));
+        assert!(debug.contains("no_a: One(0)"));
+    }
+
+    #[test]
+    fn test_state_machine_macro() {
+        // Same as test_popl19_ex3_named
+        let mut m = state_machine! {
+            <ExD, ExQ>
+            states {
+                start = 0,
+                result = 1,
+                no_a,
+                no_b,
+                max_a,
+                max_b,
+                always,
+            }
+            eps start -> no_a => |_q| 0;
+            eps start -> no_b => |_q| 0;
+            eps start -> always => |_q| 0;
+            no_a -> no_a if |&d| d.0 == 'b' => iden;
+            max_a -> max_a if |&d| d.0 == 'b' => iden;
+            no_b -> no_b if |&d| d.0 == 'a' => iden;
+            max_b -> max_b if |&d| d.0 == 'a' => iden;
+            always -> always if |&d| d.0 != '#' => iden;
+            no_a -> max_a if |&d| d.0 == 'a' => |&d, _q| d.1;
+            max_a -> max_a if |&d| d.0 == 'a' => |&d, &q| q.max(d.1);
+            no_b -> max_b if |&d| d.0 == 'b' => |&d, _q| d.1;
+            max_b -> max_b if |&d| d.0 == 'b' => |&d, &q| q.max(d.1);
+            max_a, max_b -> result if |&d| d.0 == '#' => |_d, &a, &b| a - b;
+            always -> 0 if |&d| d.0 == '#' => |_d, _q| 0;
+        };
+        assert_eq!(m.n_states(), 7);
+        assert_eq!(m.n_transs(), 14);
+        assert_eq!(m.state_id("max_b"), Some(5));
+        m.init_expect(0, Ext::None);
+        m.update_expect(('b', 2), Ext::None);
+        m.update_expect(('a', 6), Ext::None);
+        m.update_expect(('b', 3), Ext::None);
+        m.update_expect(('a', 8), Ext::None);
+        m.update_expect(('#', 0), Ext::One(5));
+        let debug = format!("{:?}", m);
+        assert!(debug.contains("[max_a max_b -> result]"));
+
+        // Numbered states, and the other kinds of transitions
+        let mut m: DataTransducer<ExD, ExQ> = state_machine! {
+            states 5;
+            -> 2 if |_| true => |d: &ExD| d.1;
+            eps 0 -> 3 => iden;
+            3 -> 3 if |_| true => iden;
+            eps 2, 3 -> 1 => |&x, &y| x + y;
+            eps -> 4 => || 0;
+        };
+        assert_eq!(m.n_transs(), 5);
+        assert_eq!(m.transitions().filter(|t| t.is_epsilon()).count(), 3);
+        m.init_expect(10, Ext::None);
+        m.update_expect(('a', 3), Ext::One(13));
+        m.update_expect(('a', 4), Ext::One(14));
+    }
+
+    #[test]
+    fn test_named_errors() {
+        let mut b = DataTransducerBuilder::<ExD, ExQ>::new();
+        assert_eq!(b.add_state_named("x"), Ok(2));
+        assert_eq!(
+            b.add_state_named("x"),
+            Err(BuildError::DuplicateName { name: "x".to_string() })
+        );
+        assert_eq!(b.n_states(), 3);
+        assert_eq!(b.name_state(2, "x"), Ok(()));
+        assert_eq!(
+            b.name_state(3, "y"),
+            Err(BuildError::NoSuchState { id: 3, n_states: 3 })
+        );
+        assert_eq!(
+            b.add_iden("x", "y", |_| true),
+            Err(BuildError::NoSuchName { name: "y".to_string() })
+        );
+        assert_eq!(b.add_iden("x", 1, |_| true), Ok(TransRef::Update(0)));
+        assert_eq!(b.remove_state("x"), Ok(()));
+        let m = b.build();
+        assert_eq!(m.state_id("x"), None);
+        assert_eq!(m.n_transs(), 0);
+    }
+
+    #[test]
+    fn test_introspection() {
+        let mut m = DataTransducer::<ExD, ExQ>::new();
+        m.add_state_named("sum");
+        m.add_transition0(2, |&d| d.0 == 'a', |&d| d.1);
+        m.add_transition2(0, 2, 2, |&d| d.0 == 'b', |&d, _, &q| q + d.1);
+        m.add_epsilon1("sum", 1, |&q| q);
+        m.init_one(0);
+        m.update_val(('a', 3));
+        let states: Vec<_> = m.states().collect();
+        assert_eq!(states.len(), 3);
+        assert_eq!(states[0].value, &Ext::None);
+        assert_eq!(states[1].value, &Ext::One(3));
+        assert_eq!(states[2].name, Some("sum"));
+        assert_eq!(states[2].value, &Ext::One(3));
+        let transs: Vec<_> = m.transitions().collect();
+        assert_eq!(
+            transs,
+            vec![
+                TransInfo {
+                    trans: TransRef::Update(0),
+                    sources: vec![],
+                    target: 2
+                },
+                TransInfo {
+                    trans: TransRef::Update(1),
+                    sources: vec![0, 2],
+                    target: 2
+                },
+                TransInfo {
+                    trans: TransRef::Epsilon(0),
+                    sources: vec![2],
+                    target: 1
+                },
+            ]
+        );
+        assert!(transs[2].is_epsilon());
+        assert_eq!(transs[1].arity(), 2);
+        assert_eq!(m.transition(TransRef::Epsilon(1)), None);
+    }
+
+    #[test]
+    fn test_get_set_states() {
+        // Sum of all items, with one copy restarting on each init
+        let mut m = DataTransducer::<ExD, ExQ>::new();
+        m.set_nstates(3);
+        m.add_epsilon1(0, 2, |&q| q);
+        m.add_transition1(2, 2, |_| true, |&d, &q| q + d.1);
+        m.add_epsilon1(2, 1, |&q| q);
+        m.init_expect(1, Ext::One(1));
+        m.update_expect(('a', 2), Ext::One(3));
+        let snapshot = m.get_states();
+        assert_eq!(snapshot, vec![Ext::None, Ext::One(3), Ext::One(3)]);
+        m.update_expect(('a', 4), Ext::One(7));
+        m.init_expect(0, Ext::Many);
+        // Restore and take a different path
+        m.set_states(snapshot.clone()).unwrap();
+        assert_eq!(m.get_states(), snapshot);
+        m.init_expect(0, Ext::Many);
+        m.set_states(snapshot).unwrap();
+        m.update_expect(('a', 10), Ext::One(13));
+        // Restoring is idempotent with respect to epsilons
+        let snapshot = m.get_states();
+        m.set_states(snapshot).unwrap();
+        assert_eq!(m.init(Ext::None), Ext::One(13));
+        assert_eq!(
+            m.set_states(vec![Ext::None]),
+            Err(BuildError::StateCount { expected: 3, found: 1 })
+        );
+    }
+
+    // Running sum and count of 'a' items (since init), without a final state
+    // value until the first 'a'
+    fn sum_machine<'a>() -> DataTransducer<'a, ExD, ExQ> {
+        let mut m = DataTransducer::<ExD, ExQ>::new();
+        m.add_state_named("sum");
+        m.add_epsilon1(0, "sum", |&q| q);
+        m.add_transition1("sum", "sum", |&d| d.0 == 'a', |&d, &q| q + d.1);
+        m.add_iden("sum", "sum", |&d| d.0 != 'a');
+        m.add_transition1("sum", 1, |&d| d.0 == 'a', |&d, &q| q + d.1);
+        m
+    }
+    fn count_machine<'a>() -> DataTransducer<'a, ExD, ExQ> {
+        let mut m = DataTransducer::<ExD, ExQ>::new();
+        m.add_state_named("count");
+        m.add_epsilon1(0, "count", |_| 0);
+        m.add_transition1("count", "count", |&d| d.0 == 'a', |_, &q| q + 1);
+        m.add_iden("count", "count", |&d| d.0 != 'a');
+        m.add_transition1("count", 1, |&d| d.0 == 'a', |_, &q| q + 1);
+        m
     }
 
-    fn is_epsilon(&self) -> bool {
-        // The transducer is an epsilon if it only has epsilon transitions
-        self.updates.is_empty()
+    #[test]
+    fn test_product() {
+        let mut m1 = sum_machine();
+        m1.init_one(100);
+        let mut m = product(m1, count_machine(), |&sum, &count| sum / count);
+        assert_eq!(m.n_states(), 2 + 3 + 3);
+        assert_eq!(m.n_transs(), 4 + 4 + 3);
+        assert_eq!(m.state_id("count"), Some(7));
+        m.init_expect(0, Ext::None);
+        m.update_expect(('a', 4), Ext::One(4));
+        m.update_expect(('b', 4), Ext::None);
+        m.update_expect(('a', 8), Ext::One(6));
+        // (init reports the output so far in the current step)
+        m.init_expect(0, Ext::One(6));
+        m.update_expect(('a', 3), Ext::Many);
+        m.reset();
+        m.update_expect(('a', 3), Ext::None);
+        m.init_expect(6, Ext::None);
+        m.update_expect(('a', 3), Ext::One(9));
     }
-    fn is_restartable(&self) -> bool {
-        // TODO: we could implement the decision procedure for this, but it is
-        // rather complex (PSPACE-complete).
-        unimplemented!()
+
+    #[test]
+    fn test_union_machines() {
+        // Sum and count of 'a' items as alternatives
+        let mut m = union_machines(sum_machine(), count_machine());
+        assert_eq!(m.n_states(), 2 + 3 + 3);
+        assert_eq!(m.n_transs(), 4 + 4 + 4);
+        m.init_expect(0, Ext::None);
+        m.update_expect(('a', 4), Ext::Many);
+        m.update_expect(('b', 4), Ext::None);
+        // Only one side produces output
+        let mut m = union_machines(sum_machine(), DataTransducer::new());
+        m.init_expect(1, Ext::None);
+        m.update_expect(('a', 4), Ext::One(5));
+        let mut m = union_machines(DataTransducer::new(), sum_machine());
+        m.init_expect(1, Ext::None);
+        m.update_expect(('a', 4), Ext::One(5));
+        // The empty machine is an identity for union; the epsilon machine
+        // (initial state wired to final) produces output immediately
+        let mut eps = DataTransducer::<ExD, ExQ>::new();
+        eps.add_epsilon1(0, 1, |&q| q);
+        let mut m = union_machines(eps, sum_machine());
+        m.init_expect(1, Ext::One(1));
+        m.update_expect(('a', 4), Ext::One(5));
     }
-    fn n_states(&self) -> usize {
-        debug_assert!(self.states.len() >= 2);
-        self.states.len()
+
+    // Adds the value of an 'a' item to the initial value
+    fn add_a_machine<'a>() -> DataTransducer<'a, ExD, ExQ> {
+        let mut m = DataTransducer::<ExD, ExQ>::new();
+        m.add_transition1(0, 1, |&d| d.0 == 'a', |&d, &q| q + d.1);
+        m
     }
-    fn n_transs(&self) -> usize {
-        self.updates.len() + self.epsilons.len()
+
+    #[test]
+    fn test_machine_io() {
+        use crate::qre;
+        // Sum of the 'a' items, from a u8 initial value, as a string
+        let m =
+            machine_io(sum_machine(), |i: u8| i as isize, |q| q.to_string());
+        let mut m = qre::concat(m, qre::epsilon(|s: String| s + "!"));
+        assert_eq!(m.init_one(1), Ext::None);
+        assert_eq!(m.update_val(('a', 3)), Ext::One("4!".to_string()));
+        assert_eq!(m.update_val(('b', 0)), Ext::None);
+        assert_eq!(m.update_val(('a', 2)), Ext::One("6!".to_string()));
+        // (init reports the output so far in the current step)
+        assert_eq!(m.init(Ext::Many), Ext::One("6!".to_string()));
+        assert_eq!(m.update_val(('a', 2)), Ext::Many);
+        assert_eq!(m.n_states(), 3);
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    #[test]
+    fn test_validation() {
+        let mut m = sum_machine();
+        assert_eq!(m.validate(), Ok(()));
+        // Corrupt the machine
+        m.finals.push(StateId(5));
+        m.eps_out[StateId(1)].push(TransId(0));
+        assert_eq!(
+            m.validate(),
+            Err(vec![
+                InvariantViolation::TableLength {
+                    table: "eps_out entries",
+                    expected: 1,
+                    found: 2
+                },
+                InvariantViolation::FinalState { state: 5, n_states: 3 },
+                InvariantViolation::EpsilonIndex {
+                    state: 1,
+                    trans: TransRef::Epsilon(0)
+                },
+            ])
+        );
+        assert_eq!(
+            m.validate().unwrap_err()[1].to_string(),
+            "final state 5 does not exist (there are 3 states)"
+        );
+        // Recording the violations instead of panicking
+        let mut m = sum_machine();
+        m.names.push(None);
+        m.set_validation(Validation::Record);
+        m.init_one(0);
+        m.update_val(('a', 1));
+        assert_eq!(m.diagnostics().len(), 2);
+        assert_eq!(
+            m.diagnostics()[0].to_string(),
+            "names has length 4, expected 3"
+        );
+        m.clear_diagnostics();
+        m.set_validation(Validation::Off);
+        m.init_one(0);
+        assert!(m.diagnostics().is_empty());
+    }
 
-    type ExD = (char, isize);
-    type ExQ = isize;
+    #[test]
+    #[should_panic(expected = "data transducer invariant violated:\n  \
+                               state 2 has a pending value between steps")]
+    fn test_validation_panic() {
+        let mut m = sum_machine();
+        m.set_validation(Validation::Panic);
+        m.next_states[StateId(2)] = Ext::One(0);
+        m.reset();
+    }
 
-    /* Additional methods for testing */
-    impl<D, Q> DataTransducer<'_, D, Q>
-    where
-        D: Debug,
-        Q: Clone + Debug + Eq,
-    {
-        fn init_expect(&mut self, i: Q, o: Ext<Q>) {
-            println!("State: {:?}", self);
-            println!("===== init: {:?} =====", i);
-            println!("Expected output: {:?}", o);
-            assert_eq!(self.init_one(i), o);
-            println!("Output is correct");
-        }
-        fn update_expect(&mut self, d: D, o: Ext<Q>) {
-            println!("State: {:?}", self);
-            println!("===== update: {:?} =====", d);
-            println!("Expected output: {:?}", o);
-            assert_eq!(self.update_val(d), o);
-            println!("Output is correct");
-        }
+    #[test]
+    fn test_pipe_machines() {
+        // Running sum of the 'a' items, piped into a count of the even sums
+        // (starting from the initial value)
+        let mut evens = DataTransducer::<ExQ, ExQ>::new();
+        evens.add_state_named("n");
+        evens.add_epsilon_iden(0, "n");
+        let even = |&q: &ExQ| (q % 2 == 0) as ExQ;
+        evens.add_transition1("n", "n", |_| true, move |d, &n| n + even(d));
+        evens.add_transition1("n", 1, |_| true, move |d, &n| n + even(d));
+        let mut m = pipe_machines(sum_machine(), evens);
+        assert_eq!(m.n_states(), 6);
+        assert_eq!(m.init_one(0), Ext::None);
+        assert_eq!(m.update_val(('a', 2)), Ext::One(1));
+        assert_eq!(m.update_val(('b', 5)), Ext::None);
+        assert_eq!(m.update_val(('a', 3)), Ext::One(1));
+        assert_eq!(m.update_val(('a', 1)), Ext::One(2));
+        let mut m2 = m.spawn_empty();
+        assert_eq!(m2.init_one(10), Ext::None);
+        assert_eq!(m2.update_val(('a', 2)), Ext::One(11));
+        // Outputs already fed in the step are not fed again on a restart,
+        // and Many outputs of the first machine are passed on
+        assert_eq!(m.init_one(0), Ext::One(2));
+        assert_eq!(m.update_val(('a', 2)), Ext::Many);
+        // Outputs of the first machine on a restart are fed too
+        let mut id = DataTransducer::<ExD, ExQ>::new();
+        id.add_epsilon_iden(0, 1);
+        let mut m = pipe_machines(id, m.m2.clone());
+        m.reset();
+        assert!(m.is_nullable());
+        assert_eq!(m.init_one(4), Ext::One(5));
+        assert_eq!(m.update_val(('a', 2)), Ext::None);
     }
 
     #[test]
-    fn test_popl19_ex1() {
-        // Initialize
-        let mut m = DataTransducer::<ExD, ExQ>::new();
-        m.set_nstates(4);
-        assert_eq!(m.n_states(), 4);
-        assert_eq!(m.n_transs(), 0);
-        assert!(m.is_epsilon());
-        // 0: Initial, always set to Ext::One
-        // 1: Final, sum of last three 'a' events
-        // 2: Sum of last two 'a' events
-        // 3: Sum of last 'a' event
-        m.add_iden(0, 0, |_d| true);
-        m.add_iden(2, 2, |&d| d.0 == 'b');
-        m.add_iden(3, 3, |&d| d.0 == 'b');
-        m.add_transition1(0, 3, |&d| d.0 == 'a', |&d, _q| d.1);
-        m.add_transition1(3, 2, |&d| d.0 == 'a', |&d, &q| q + d.1);
-        m.add_transition1(2, 1, |&d| d.0 == 'a', |&d, &q| q + d.1);
-        assert_eq!(m.n_states(), 4);
-        assert_eq!(m.n_transs(), 6);
-        assert!(!m.is_epsilon());
-        // Test
+    fn test_concat_machines() {
+        // First 'a' item, followed by the running sum of the remaining 'a's
+        let mut m = concat_machines(add_a_machine(), sum_machine());
+        assert_eq!(m.n_states(), 2 + 2 + 3);
         m.init_expect(0, Ext::None);
-        m.update_expect(('a', 6), Ext::None);
-        m.update_expect(('b', 2), Ext::None);
-        m.update_expect(('a', 5), Ext::None);
-        m.update_expect(('a', 7), Ext::One(18));
-        m.update_expect(('b', 2), Ext::None);
-        m.update_expect(('a', 8), Ext::One(20));
-        m.update_expect(('#', 0), Ext::None);
-        m.update_expect(('b', 2), Ext::None);
-        m.update_expect(('a', 2), Ext::None);
-        m.update_expect(('a', 2), Ext::None);
-        m.update_expect(('a', 2), Ext::One(6));
-        m.update_expect(('a', 0), Ext::One(4));
+        m.update_expect(('a', 3), Ext::None);
+        m.update_expect(('a', 4), Ext::One(7));
+        m.update_expect(('b', 4), Ext::None);
+        m.update_expect(('a', 1), Ext::One(8));
+        // Concatenating with the epsilon machine is the identity
+        let mut eps = DataTransducer::<ExD, ExQ>::new();
+        eps.add_epsilon1(0, 1, |&q| q);
+        let mut m = concat_machines(eps, add_a_machine());
+        m.init_expect(1, Ext::None);
+        m.update_expect(('a', 4), Ext::One(5));
+        m.update_expect(('a', 4), Ext::None);
     }
 
     #[test]
-    fn test_popl19_ex2() {
-        // Initialize
+    fn test_iterate_machine() {
+        // Sum of a prefix consisting only of 'a's
+        let mut m = iterate_machine(add_a_machine());
+        assert_eq!(m.n_states(), 2 + 1 + 2);
+        assert!(m.is_nullable());
+        m.init_expect(0, Ext::One(0));
+        m.update_expect(('a', 2), Ext::One(2));
+        m.update_expect(('a', 3), Ext::One(5));
+        m.update_expect(('b', 3), Ext::None);
+        m.update_expect(('a', 3), Ext::None);
+        // Iterating a nullable machine
+        let mut eps = DataTransducer::<ExD, ExQ>::new();
+        eps.add_epsilon1(0, 1, |&q| q);
+        let mut m = iterate_machine(eps);
+        m.init_expect(0, Ext::Many);
+    }
+
+    #[test]
+    fn test_eliminate_epsilons() {
+        // test_popl19_ex2, whose epsilon restarts from the final state
         let mut m = DataTransducer::<ExD, ExQ>::new();
         m.set_nstates(4);
-        // 0: Initial, previous window average
-        // 1: Final, end of window average
-        // 2: Sum of 'a' events in current window
-        // 3: Count of 'a' events in current window
         m.add_iden(0, 0, |&d| d.0 == 'b');
         m.add_iden(2, 2, |&d| d.0 == 'b');
         m.add_iden(3, 3, |&d| d.0 == 'b');
@@ -653,16 +6584,14 @@ mod tests {
         m.add_transition2(2, 3, 1, |&d| d.0 == '#', |_d, &q2, &q3| q2 / q3);
         m.add_iden(0, 1, |&d| d.0 == '#');
         m.add_epsilon1(1, 0, |&q| q);
-        assert_eq!(m.n_states(), 4);
-        assert_eq!(m.n_transs(), 10);
-        // Test
+        m.eliminate_epsilons().unwrap();
+        assert_eq!(m.n_transs(), 11);
+        assert!(m.transitions().all(|tr| !tr.is_epsilon()));
         m.init_expect(0, Ext::None);
         m.update_expect(('b', 2), Ext::None);
         m.update_expect(('a', 6), Ext::None);
-        m.update_expect(('b', 2), Ext::None);
         m.update_expect(('a', 8), Ext::None);
         m.update_expect(('a', 9), Ext::None);
-        // (6 + 8 + 9) / 3 = 7
         m.update_expect(('#', 0), Ext::One(7));
         m.update_expect(('b', 2), Ext::None);
         m.update_expect(('#', 2), Ext::One(7));
@@ -671,49 +6600,256 @@ mod tests {
     }
 
     #[test]
-    fn test_popl19_ex3() {
-        // Initialize
+    fn test_eliminate_epsilon_chain() {
+        // Epsilons in a chain and out of the initial state
+        let mut m = DataTransducer::<ExD, ExQ>::new();
+        m.set_nstates(5);
+        m.add_epsilon1(0, 2, |&q| q * 10);
+        m.add_transition1(2, 3, |_| true, |&d, &q| q + d.1);
+        m.add_epsilon1(3, 4, |&q| q + 1);
+        m.add_epsilon1(4, 1, |&q| q * 2);
+        m.add_epsilon2(2, 2, 1, |&q1, &q2| q1 + q2);
+        m.eliminate_epsilons().unwrap();
+        // The epsilon out of 0 and the 2-source epsilon remain
+        assert_eq!(m.transitions().filter(|tr| tr.is_epsilon()).count(), 2);
+        m.init_expect(1, Ext::One(20));
+        m.update_expect(('a', 3), Ext::One(28));
+        m.init_expect(2, Ext::Many);
+        m.update_expect(('a', 3), Ext::One(48));
+    }
+
+    #[test]
+    fn test_eliminate_epsilons_cycle() {
+        let mut m = DataTransducer::<ExD, ExQ>::new();
+        m.set_nstates(4);
+        m.add_epsilon1(0, 2, |_| 0);
+        m.add_epsilon1(2, 3, |_| 0);
+        m.add_epsilon1(3, 2, |_| 0);
+        assert_eq!(
+            m.eliminate_epsilons(),
+            Err(BuildError::EpsilonCycle { state: 2 })
+        );
+        assert_eq!(m.n_transs(), 3);
+    }
+
+    #[test]
+    fn test_reachability() {
         let mut m = DataTransducer::<ExD, ExQ>::new();
         m.set_nstates(7);
-        // 0: Initial, Ext::One initially and after each '#'
-        // 1: Final, stores the final answer after each '#'
-        // 2: Ext::One if we haven't yet seen an 'a'
-        // 3: Ext::One if we haven't yet seen a 'b'
-        // 4: Max 'a' if we have seen at least one
-        // 5: Max 'b' if we have seen at least one
-        // 6: Ext::One always (used to set state 0 on '#')
-        m.add_epsilon1(0, 2, |_q| 0);
-        m.add_epsilon1(0, 3, |_q| 0);
-        m.add_epsilon1(0, 6, |_q| 0);
-        assert!(m.is_epsilon());
+        // 2, 6: useful; 3: unreachable; 4: doesn't reach final;
+        // 5: only reachable together with 3
+        m.add_iden(0, 2, |_| true);
+        m.add_iden(2, 1, |_| true);
+        m.add_iden(3, 1, |_| true);
+        m.add_epsilon1(2, 4, |&q| q);
+        m.add_transition2(2, 3, 5, |_| true, |_, &q1, &q2| q1 + q2);
+        m.add_iden(5, 1, |_| true);
+        m.add_transition0(6, |&d| d.0 == 'b', |&d| d.1);
+        m.add_epsilon1(6, 1, |&q| q);
+        assert_eq!(
+            m.reachable(),
+            vec![true, true, true, false, true, false, true]
+        );
+        assert_eq!(
+            m.coreachable(),
+            vec![true, true, true, true, false, true, true]
+        );
+        assert_eq!(m.remove_dead_states(), 3);
+        assert_eq!(m.n_states(), 4);
+        assert_eq!(m.n_transs(), 4);
+        m.init_expect(1, Ext::None);
+        m.update_expect(('a', 0), Ext::None);
+        m.update_expect(('b', 5), Ext::Many);
+        m.update_expect(('b', 5), Ext::One(5));
+        // Nothing more to remove
+        assert_eq!(m.remove_dead_states(), 0);
+        assert_eq!(m.n_transs(), 4);
+    }
+
+    #[test]
+    fn test_remove_dead_states_cascade() {
+        let mut m = DataTransducer::<ExD, ExQ>::new();
+        m.set_nstates(5);
+        // 3 is unreachable, so the transition into 4 never fires, and then
+        // 2 (only used by that transition) can't reach the final state
+        m.add_iden(0, 2, |_| true);
+        m.add_transition2(2, 3, 4, |_| true, |_, &q1, &q2| q1 + q2);
+        m.add_iden(4, 1, |_| true);
+        assert_eq!(m.remove_dead_states(), 3);
+        assert_eq!(m.n_states(), 2);
+        assert_eq!(m.n_transs(), 0);
+    }
+
+    #[test]
+    fn test_minimize() {
+        let mut m = DataTransducer::<ExD, ExQ>::new();
+        m.set_nstates(6);
+        // 2 and 3 both copy the initial value on 'a' items (guards differ
+        // but agree on the alphabet)
+        m.add_iden(0, 2, |&d| d.0 == 'a');
+        m.add_iden(0, 3, |&d| d.0 != 'b');
+        // 4 and 5 keep copies of them
+        m.add_iden(2, 4, |_| true);
+        m.add_iden(4, 4, |_| true);
+        m.add_iden(3, 5, |_| true);
+        m.add_iden(5, 5, |_| true);
+        m.add_epsilon_iden(4, 1);
+        m.add_epsilon_iden(5, 1);
+        assert_eq!(m.minimize(&[('a', 0), ('b', 0)]), 2);
+        assert_eq!(m.n_states(), 4);
+        assert_eq!(m.n_transs(), 5);
+        m.init_expect(3, Ext::None);
+        m.update_expect(('a', 0), Ext::None);
+        m.update_expect(('b', 0), Ext::Many);
+        m.update_expect(('b', 0), Ext::Many);
+        // Already minimal
+        assert_eq!(m.minimize(&[('a', 0), ('b', 0)]), 0);
+        // With a larger alphabet the guards differ
+        let mut m = DataTransducer::<ExD, ExQ>::new();
+        m.set_nstates(4);
+        m.add_iden(0, 2, |&d| d.0 == 'a');
+        m.add_iden(0, 3, |&d| d.0 != 'b');
+        assert_eq!(m.minimize(&[('a', 0), ('b', 0), ('c', 0)]), 0);
+    }
+
+    #[test]
+    fn test_minimize_actions() {
+        // Distinct non-identity actions are never merged, even if they
+        // happen to compute the same function
+        let mut m = DataTransducer::<ExD, ExQ>::new();
+        m.set_nstates(4);
+        m.add_transition1(0, 2, |_| true, |_, &q| q + 1);
+        m.add_transition1(0, 3, |_| true, |_, &q| q + 1);
+        m.add_transition2(2, 3, 1, |_| true, |_, &q1, &q2| q1 * q2);
+        assert_eq!(m.minimize(&[('a', 0)]), 0);
+        // Transitions which can't fire on the alphabet are removed
+        m.add_state();
+        m.add_transition1(0, 4, |&d| d.0 == 'z', |_, &q| q);
+        m.add_iden(4, 2, |_| true);
+        assert_eq!(m.minimize(&[('a', 0)]), 0);
+        assert_eq!(m.n_transs(), 4);
+    }
+
+    #[test]
+    fn test_clone() {
+        let mut m1 = sum_machine();
+        m1.init_one(1);
+        let mut m2 = m1.clone();
+        m1.update_expect(('a', 2), Ext::One(3));
+        m2.update_expect(('a', 5), Ext::One(6));
+        assert_eq!(m2.n_transs(), m1.n_transs());
+        m2.add_state();
+        assert_eq!(m2.n_states(), m1.n_states() + 1);
+    }
+
+    #[test]
+    fn test_restartable_bounded() {
+        let alphabet = [
+            RInput::Restart(1),
+            RInput::Restart(2),
+            RInput::Item(('a', 1)),
+            RInput::Item(('b', 1)),
+        ];
+        // Keeps the initial value until the first 'b', then outputs it
+        let mut m = DataTransducer::<ExD, ExQ>::new();
+        m.set_nstates(3);
+        m.add_epsilon_iden(0, 2);
+        m.add_iden(2, 2, |&d| d.0 != 'b');
+        m.add_transition1(2, 1, |&d| d.0 == 'b', |&d, &q| q + d.1);
+        assert_eq!(m.is_restartable_bounded(&alphabet[..3], 4), Ok(()));
+        // Note: .init() reports all output of the current step, including
+        // output already reported by .update(), so restarting right after
+        // an output is a counterexample
+        assert_eq!(
+            m.is_restartable_bounded(&alphabet, 3),
+            Err(vec![
+                RInput::Restart(1),
+                RInput::Item(('b', 1)),
+                RInput::Restart(1),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_remove_transition() {
+        // test_popl19_ex2, but with the window average reported only once
+        // (remove the transition restarting from the previous average)
+        let mut m = DataTransducer::<ExD, ExQ>::new();
+        m.set_nstates(4);
+        let t0 = m.add_iden(0, 0, |&d| d.0 == 'b');
         m.add_iden(2, 2, |&d| d.0 == 'b');
-        m.add_iden(4, 4, |&d| d.0 == 'b');
-        m.add_iden(3, 3, |&d| d.0 == 'a');
-        m.add_iden(5, 5, |&d| d.0 == 'a');
-        m.add_iden(6, 6, |&d| d.0 != '#');
-        m.add_transition1(2, 4, |&d| d.0 == 'a', |&d, _q| d.1);
-        m.add_transition1(4, 4, |&d| d.0 == 'a', |&d, &q| q.max(d.1));
-        m.add_transition1(3, 5, |&d| d.0 == 'b', |&d, _q| d.1);
-        m.add_transition1(5, 5, |&d| d.0 == 'b', |&d, &q| q.max(d.1));
-        m.add_transition2(4, 5, 1, |&d| d.0 == '#', |_d, &q4, &q5| q4 - q5);
-        m.add_transition1(6, 0, |&d| d.0 == '#', |_d, _q| 0);
-        assert_eq!(m.n_transs(), 14);
-        // Test
+        m.add_iden(3, 3, |&d| d.0 == 'b');
+        m.add_transition1(0, 2, |&d| d.0 == 'a', |&d, _q| d.1);
+        m.add_transition1(0, 3, |&d| d.0 == 'a', |_d, _q| 1);
+        m.add_transition1(2, 2, |&d| d.0 == 'a', |&d, &q| q + d.1);
+        m.add_transition1(3, 3, |&d| d.0 == 'a', |_d, &q| q + 1);
+        m.add_transition2(2, 3, 1, |&d| d.0 == '#', |_d, &q2, &q3| q2 / q3);
+        let t8 = m.add_iden(0, 1, |&d| d.0 == '#');
+        let e0 = m.add_epsilon1(1, 0, |&q| q);
+        assert_eq!(t0, TransRef::Update(0));
+        assert_eq!(t8, TransRef::Update(8));
+        assert_eq!(e0, TransRef::Epsilon(0));
+        m.remove_transition(t8);
+        m.remove_transition(e0);
+        m.remove_transition(t0);
+        assert_eq!(m.n_transs(), 7);
         m.init_expect(0, Ext::None);
-        m.update_expect(('b', 2), Ext::None);
         m.update_expect(('a', 6), Ext::None);
-        m.update_expect(('b', 3), Ext::None);
-        m.update_expect(('b', 1), Ext::None);
         m.update_expect(('a', 8), Ext::None);
-        m.update_expect(('#', 0), Ext::One(5));
-        m.update_expect(('b', 2), Ext::None);
-        m.update_expect(('#', 2), Ext::None);
-        m.update_expect(('a', 1), Ext::None);
-        m.update_expect(('a', 3), Ext::None);
-        m.update_expect(('#', 2), Ext::None);
-        m.update_expect(('a', 7), Ext::None);
-        m.update_expect(('b', 1), Ext::None);
-        m.update_expect(('#', 0), Ext::One(6));
+        m.update_expect(('#', 0), Ext::One(7));
+        m.update_expect(('a', 2), Ext::None);
+        m.update_expect(('#', 0), Ext::None);
+    }
+
+    #[test]
+    fn test_remove_state() {
+        let mut m = DataTransducer::<ExD, ExQ>::new();
+        m.set_nstates(5);
+        // State 2 is a dead end; state 3 and 4 count 'a's
+        m.add_iden(0, 2, |_| true);
+        m.add_epsilon1(2, 1, |&q| q);
+        m.add_transition1(0, 3, |&d| d.0 == 'a', |_d, _q| 1);
+        m.add_transition1(3, 3, |&d| d.0 == 'a', |_d, &q| q + 1);
+        m.add_iden(3, 4, |&d| d.0 == '#');
+        m.add_epsilon1(4, 1, |&q| q);
+        assert_eq!(m.n_transs(), 6);
+        m.remove_state(2);
+        assert_eq!(m.n_states(), 4);
+        assert_eq!(m.n_transs(), 4);
+        m.init_expect(0, Ext::None);
+        m.update_expect(('a', 0), Ext::None);
+        m.update_expect(('a', 0), Ext::None);
+        m.update_expect(('#', 0), Ext::One(2));
+        m.update_expect(('a', 0), Ext::None);
+    }
+
+    #[test]
+    fn test_remove_errors() {
+        let mut b = DataTransducerBuilder::<ExD, ExQ>::new();
+        b.set_nstates(3).unwrap();
+        let e = b.add_epsilon1(2, 1, |&q| q).unwrap();
+        assert_eq!(
+            b.remove_transition(TransRef::Update(0)),
+            Err(BuildError::NoSuchTransition {
+                trans: TransRef::Update(0),
+                n_transs: 0
+            })
+        );
+        assert_eq!(
+            b.remove_state(1),
+            Err(BuildError::RemoveReserved { id: 1 })
+        );
+        assert_eq!(
+            b.remove_state(3),
+            Err(BuildError::NoSuchState { id: 3, n_states: 3 })
+        );
+        assert_eq!(b.remove_transition(e), Ok(()));
+        assert_eq!(
+            b.remove_transition(e),
+            Err(BuildError::NoSuchTransition { trans: e, n_transs: 0 })
+        );
+        assert_eq!(b.remove_state(2), Ok(()));
+        assert_eq!(b.n_states(), 2);
     }
 
     #[test]
@@ -773,6 +6909,112 @@ mod tests {
         m.init_expect(0, Ext::Many);
     }
 
+    #[test]
+    fn test_nullable() {
+        let mut m = DataTransducer::<ExD, ExQ>::new();
+        m.set_nstates(4);
+        m.add_iden(0, 1, |_d| true);
+        assert!(!m.is_nullable());
+        m.add_epsilon1(0, 2, |&q| q);
+        m.add_epsilon2(2, 3, 1, |&q2, &q3| q2 + q3);
+        assert!(!m.is_nullable());
+        m.add_epsilon1(2, 3, |&q| q);
+        assert!(m.is_nullable());
+    }
+
+    #[test]
+    fn test_init_twice_in_step() {
+        // Contributions already propagated in the current step should not be
+        // counted again on a second .init()
+        let mut m = DataTransducer::<ExD, ExQ>::new();
+        m.set_nstates(3);
+        m.add_transition1(0, 2, |_d| true, |&d, &q| q + d.1);
+        m.add_epsilon1(2, 1, |&q| q);
+        m.init_expect(1, Ext::None);
+        m.update_expect(('a', 2), Ext::One(3));
+        m.init_expect(5, Ext::One(3));
+        m.init_expect(6, Ext::One(3));
+        m.update_expect(('a', 2), Ext::Many);
+    }
+
+    #[test]
+    fn test_eps_schedule() {
+        // Epsilons added in reverse order: a single pass in index order
+        // would not reach the final state
+        let mut m = DataTransducer::<ExD, ExQ>::new();
+        m.set_nstates(5);
+        m.add_epsilon2(3, 4, 1, |&q3, &q4| q3 * q4);
+        m.add_epsilon1(2, 4, |&q| q + 1);
+        m.add_epsilon1(2, 3, |&q| q + 2);
+        m.add_epsilon1(0, 2, |&q| q);
+        m.add_iden(2, 2, |&d| d.0 == 'a');
+        m.init_expect(1, Ext::One(6));
+        assert!(matches!(m.eps_schedule, EpsSchedule::Ordered(_)));
+        m.update_expect(('a', 0), Ext::One(6));
+        m.init_expect(2, Ext::Many);
+        m.update_expect(('b', 0), Ext::None);
+        // Adding a cycle falls back to the worklist
+        m.add_epsilon1(4, 2, |&q| q);
+        assert!(matches!(m.eps_schedule, EpsSchedule::Stale));
+        m.init_expect(1, Ext::Many);
+        assert!(matches!(m.eps_schedule, EpsSchedule::Cyclic));
+        m.remove_transition(TransRef::Epsilon(4));
+        m.reset();
+        m.init_expect(1, Ext::One(6));
+        assert!(matches!(m.eps_schedule, EpsSchedule::Ordered(_)));
+    }
+
+    #[test]
+    fn test_dirty_tracking() {
+        // Epsilons out of states which are None are not evaluated at all
+        // (with or without epsilon cycles)
+        use std::cell::Cell;
+        let evals = Cell::new(0);
+        let mut m = DataTransducer::<ExD, ExQ>::new();
+        m.set_nstates(5);
+        m.add_transition1(0, 2, |&d| d.0 == 'a', |&d, &q| q + d.1);
+        m.add_iden(2, 2, |&d| d.0 == 'a');
+        m.add_transition1(0, 3, |&d| d.0 == 'b', |&d, &q| q + d.1);
+        m.add_epsilon1(2, 1, |&q| {
+            evals.set(evals.get() + 1);
+            q
+        });
+        m.add_epsilon0(4, || 10);
+        m.add_epsilon2(3, 4, 1, |&q3, &q4| q3 + q4);
+        m.init_expect(1, Ext::None);
+        m.update_expect(('b', 1), Ext::One(12));
+        m.update_expect(('a', 1), Ext::None);
+        m.init_expect(1, Ext::None);
+        assert_eq!(evals.get(), 0);
+        m.update_expect(('a', 1), Ext::One(2));
+        assert_eq!(evals.get(), 1);
+        // Same with an epsilon cycle (worklist evaluation)
+        m.add_epsilon1(3, 3, |&q| q);
+        m.init_expect(1, Ext::One(2));
+        m.update_expect(('b', 1), Ext::Many);
+        assert_eq!(evals.get(), 1);
+    }
+
+    #[test]
+    fn test_n_bytes() {
+        let mut m = DataTransducer::<ExD, ExQ>::new();
+        let base = m.n_bytes();
+        assert!(base >= mem::size_of_val(&m) + 2 * mem::size_of::<Ext<ExQ>>());
+        m.set_nstates(4);
+        let with_states = m.n_bytes();
+        assert!(with_states > base);
+        m.add_iden(2, 3, |_d| true);
+        m.add_epsilon1(3, 1, |&q| q);
+        assert!(m.n_bytes() > with_states);
+        // Processing items doesn't change the footprint (once the epsilon
+        // schedule has been computed on the first step)
+        m.init_one(0);
+        let before = m.n_bytes();
+        m.update_val(('a', 1));
+        m.reset();
+        assert_eq!(m.n_bytes(), before);
+    }
+
     #[test]
     fn test_reset() {
         let mut m = DataTransducer::<ExD, ExQ>::new();
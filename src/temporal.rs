@@ -0,0 +1,654 @@
+/*
+    Runtime verification: monitors for past-time LTL and MTL formulas.
+
+    A Formula is built from atomic propositions, which are symbolic guards
+    on the items (see guard.rs), with the boolean connectives and the
+    past-time temporal operators:
+        prev(f)             Y f     f held at the previous step
+        since(f, g)         f S g   g held at some step, and f at every
+                                    step after it
+        once(f)             O f     f held at some step
+        historically(f)     H f     f held at every step
+    and their metric versions, which only look at the steps whose time is
+    between lo and hi (inclusive) before the current one:
+        once_in(lo, hi, f)          O[lo,hi] f
+        historically_in(lo, hi, f)  H[lo,hi] f
+    By default the time of an item is its position in the stream (so the
+    bounds count items); Monitor::timed() reads it from the items instead,
+    e.g. a timestamp, which must not decrease.
+
+    monitor(f) compiles a formula into a Monitor, a transducer producing
+    a verdict for each item: whether the formula holds on the stream so
+    far. Verdicts are three-valued: a formula which looks a fixed distance
+    into the past (prev, or a metric operator whose window starts before
+    the first item) is Unknown where that part of the past was not
+    observed, and Unknown propagates through the connectives as in Kleene
+    logic (so "Y p | true" is still True). The unbounded operators look at
+    the whole stream so far, so are never Unknown by themselves. .init()
+    starts monitoring a new stream.
+
+    The monitor keeps one verdict per subformula, so each step takes time
+    linear in the size of the formula; the metric operators also keep the
+    verdicts of their subformula inside their window.
+
+    Formulas can also be parsed (see parse_formula()), with the syntax in
+    the second column above, "!", "&", "|", "->" for the connectives (from
+    highest to lowest precedence, after the temporal operators), "true",
+    "false", and parentheses. Atomic propositions are names, looked up by
+    a function given to the parser.
+*/
+
+use super::ext_value::Ext;
+use super::guard::Guard;
+use super::interface::Transducer;
+use super::scanner::{Scanner, SyntaxError};
+use super::timed::Time;
+use std::collections::VecDeque;
+use std::error::Error;
+use std::fmt::{self, Display};
+use std::ops;
+
+/*
+    Verdicts
+*/
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Verdict {
+    True,
+    False,
+    Unknown,
+}
+impl Verdict {
+    pub fn is_known(self) -> bool {
+        self != Verdict::Unknown
+    }
+}
+impl From<bool> for Verdict {
+    fn from(b: bool) -> Self {
+        if b {
+            Verdict::True
+        } else {
+            Verdict::False
+        }
+    }
+}
+impl ops::Not for Verdict {
+    type Output = Verdict;
+    fn not(self) -> Verdict {
+        match self {
+            Verdict::True => Verdict::False,
+            Verdict::False => Verdict::True,
+            Verdict::Unknown => Verdict::Unknown,
+        }
+    }
+}
+impl ops::BitAnd for Verdict {
+    type Output = Verdict;
+    fn bitand(self, other: Verdict) -> Verdict {
+        match (self, other) {
+            (Verdict::False, _) | (_, Verdict::False) => Verdict::False,
+            (Verdict::True, Verdict::True) => Verdict::True,
+            _ => Verdict::Unknown,
+        }
+    }
+}
+impl ops::BitOr for Verdict {
+    type Output = Verdict;
+    fn bitor(self, other: Verdict) -> Verdict {
+        !(!self & !other)
+    }
+}
+impl Display for Verdict {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Verdict::True => write!(f, "true"),
+            Verdict::False => write!(f, "false"),
+            Verdict::Unknown => write!(f, "unknown"),
+        }
+    }
+}
+
+/*
+    Formulas
+*/
+
+pub enum Formula<'a, D> {
+    Const(bool),
+    Prop(Guard<'a, D>),
+    Not(Box<Formula<'a, D>>),
+    And(Box<Formula<'a, D>>, Box<Formula<'a, D>>),
+    Or(Box<Formula<'a, D>>, Box<Formula<'a, D>>),
+    Prev(Box<Formula<'a, D>>),
+    Since(Box<Formula<'a, D>>, Box<Formula<'a, D>>),
+    Once(Box<Formula<'a, D>>),
+    Historically(Box<Formula<'a, D>>),
+    OnceIn(Time, Time, Box<Formula<'a, D>>),
+    HistoricallyIn(Time, Time, Box<Formula<'a, D>>),
+}
+impl<D> Clone for Formula<'_, D> {
+    fn clone(&self) -> Self {
+        match self {
+            Formula::Const(b) => Formula::Const(*b),
+            Formula::Prop(g) => Formula::Prop(g.clone()),
+            Formula::Not(f) => Formula::Not(f.clone()),
+            Formula::And(f, g) => Formula::And(f.clone(), g.clone()),
+            Formula::Or(f, g) => Formula::Or(f.clone(), g.clone()),
+            Formula::Prev(f) => Formula::Prev(f.clone()),
+            Formula::Since(f, g) => Formula::Since(f.clone(), g.clone()),
+            Formula::Once(f) => Formula::Once(f.clone()),
+            Formula::Historically(f) => Formula::Historically(f.clone()),
+            Formula::OnceIn(lo, hi, f) => Formula::OnceIn(*lo, *hi, f.clone()),
+            Formula::HistoricallyIn(lo, hi, f) => {
+                Formula::HistoricallyIn(*lo, *hi, f.clone())
+            }
+        }
+    }
+}
+
+pub fn prop<D>(g: Guard<'_, D>) -> Formula<'_, D> {
+    Formula::Prop(g)
+}
+pub fn implies<'a, D>(f: Formula<'a, D>, g: Formula<'a, D>) -> Formula<'a, D> {
+    !f | g
+}
+pub fn prev<D>(f: Formula<'_, D>) -> Formula<'_, D> {
+    Formula::Prev(Box::new(f))
+}
+pub fn since<'a, D>(f: Formula<'a, D>, g: Formula<'a, D>) -> Formula<'a, D> {
+    Formula::Since(Box::new(f), Box::new(g))
+}
+pub fn once<D>(f: Formula<'_, D>) -> Formula<'_, D> {
+    Formula::Once(Box::new(f))
+}
+pub fn historically<D>(f: Formula<'_, D>) -> Formula<'_, D> {
+    Formula::Historically(Box::new(f))
+}
+pub fn once_in<D>(lo: Time, hi: Time, f: Formula<'_, D>) -> Formula<'_, D> {
+    assert!(lo <= hi, "empty interval [{}, {}]", lo, hi);
+    Formula::OnceIn(lo, hi, Box::new(f))
+}
+pub fn historically_in<D>(
+    lo: Time,
+    hi: Time,
+    f: Formula<'_, D>,
+) -> Formula<'_, D> {
+    assert!(lo <= hi, "empty interval [{}, {}]", lo, hi);
+    Formula::HistoricallyIn(lo, hi, Box::new(f))
+}
+
+impl<'a, D> ops::BitAnd for Formula<'a, D> {
+    type Output = Formula<'a, D>;
+    fn bitand(self, other: Formula<'a, D>) -> Formula<'a, D> {
+        Formula::And(Box::new(self), Box::new(other))
+    }
+}
+impl<'a, D> ops::BitOr for Formula<'a, D> {
+    type Output = Formula<'a, D>;
+    fn bitor(self, other: Formula<'a, D>) -> Formula<'a, D> {
+        Formula::Or(Box::new(self), Box::new(other))
+    }
+}
+impl<'a, D> ops::Not for Formula<'a, D> {
+    type Output = Formula<'a, D>;
+    fn not(self) -> Formula<'a, D> {
+        Formula::Not(Box::new(self))
+    }
+}
+
+impl<D> Display for Formula<'_, D> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Formula::Const(b) => write!(f, "{}", b),
+            Formula::Prop(g) => write!(f, "{}", g),
+            Formula::Not(g) => write!(f, "!{}", g),
+            Formula::And(g, h) => write!(f, "({} & {})", g, h),
+            Formula::Or(g, h) => write!(f, "({} | {})", g, h),
+            Formula::Prev(g) => write!(f, "Y {}", g),
+            Formula::Since(g, h) => write!(f, "({} S {})", g, h),
+            Formula::Once(g) => write!(f, "O {}", g),
+            Formula::Historically(g) => write!(f, "H {}", g),
+            Formula::OnceIn(lo, hi, g) => write!(f, "O[{},{}] {}", lo, hi, g),
+            Formula::HistoricallyIn(lo, hi, g) => {
+                write!(f, "H[{},{}] {}", lo, hi, g)
+            }
+        }
+    }
+}
+
+/*
+    Monitors
+*/
+
+// A subformula, with its subformulas given by their index in the
+// monitor's list (which is in post-order, so they come before it)
+enum Node<'a, D> {
+    Const(bool),
+    Prop(Guard<'a, D>),
+    Not(usize),
+    And(usize, usize),
+    Or(usize, usize),
+    Prev(usize),
+    Since(usize, usize),
+    Once(usize),
+    Historically(usize),
+    // The times and verdicts of the subformula within the window
+    OnceIn(Time, Time, usize, VecDeque<(Time, Verdict)>),
+    HistoricallyIn(Time, Time, usize, VecDeque<(Time, Verdict)>),
+}
+
+type TimeFn<'a, D> = Box<dyn Fn(&D) -> Time + 'a>;
+
+pub struct Monitor<'a, D> {
+    nodes: Vec<Node<'a, D>>,
+    // Verdict of each subformula at the current step, and the previous one
+    verdicts: Vec<Verdict>,
+    prev: Vec<Verdict>,
+    time_of: Option<TimeFn<'a, D>>,
+    // Whether .init() was called, and the time of the first item
+    started: bool,
+    first: Option<Time>,
+    n_steps: Time,
+}
+
+pub fn monitor<D>(f: Formula<'_, D>) -> Monitor<'_, D> {
+    let mut nodes = Vec::new();
+    compile(f, &mut nodes);
+    let n = nodes.len();
+    Monitor {
+        nodes,
+        verdicts: vec![Verdict::Unknown; n],
+        prev: vec![Verdict::Unknown; n],
+        time_of: None,
+        started: false,
+        first: None,
+        n_steps: 0,
+    }
+}
+fn compile<'a, D>(f: Formula<'a, D>, nodes: &mut Vec<Node<'a, D>>) -> usize {
+    let node = match f {
+        Formula::Const(b) => Node::Const(b),
+        Formula::Prop(g) => Node::Prop(g),
+        Formula::Not(f) => Node::Not(compile(*f, nodes)),
+        Formula::And(f, g) => Node::And(compile(*f, nodes), compile(*g, nodes)),
+        Formula::Or(f, g) => Node::Or(compile(*f, nodes), compile(*g, nodes)),
+        Formula::Prev(f) => Node::Prev(compile(*f, nodes)),
+        Formula::Since(f, g) => {
+            Node::Since(compile(*f, nodes), compile(*g, nodes))
+        }
+        Formula::Once(f) => Node::Once(compile(*f, nodes)),
+        Formula::Historically(f) => Node::Historically(compile(*f, nodes)),
+        Formula::OnceIn(lo, hi, f) => {
+            Node::OnceIn(lo, hi, compile(*f, nodes), VecDeque::new())
+        }
+        Formula::HistoricallyIn(lo, hi, f) => {
+            Node::HistoricallyIn(lo, hi, compile(*f, nodes), VecDeque::new())
+        }
+    };
+    nodes.push(node);
+    nodes.len() - 1
+}
+
+impl<'a, D> Monitor<'a, D> {
+    pub fn timed<F>(mut self, time_of: F) -> Self
+    where
+        F: Fn(&D) -> Time + 'a,
+    {
+        self.time_of = Some(Box::new(time_of));
+        self
+    }
+    pub fn n_subformulas(&self) -> usize {
+        self.nodes.len()
+    }
+
+    fn step(&mut self, item: &D) -> Verdict {
+        let t = match &self.time_of {
+            Some(time_of) => time_of(item),
+            None => self.n_steps,
+        };
+        let first = *self.first.get_or_insert(t);
+        debug_assert!(t >= first, "time went backwards");
+        let is_first = self.n_steps == 0;
+        self.n_steps += 1;
+        std::mem::swap(&mut self.verdicts, &mut self.prev);
+        let (v, prev) = (&mut self.verdicts, &self.prev);
+        // The verdict of subformula j at the previous step, or start before
+        // the first item
+        let before = |j: usize, start: Verdict| {
+            if is_first {
+                start
+            } else {
+                prev[j]
+            }
+        };
+        for (i, node) in self.nodes.iter_mut().enumerate() {
+            let verdict = match node {
+                Node::Const(b) => Verdict::from(*b),
+                Node::Prop(g) => Verdict::from(g.eval(item)),
+                Node::Not(f) => !v[*f],
+                Node::And(f, g) => v[*f] & v[*g],
+                Node::Or(f, g) => v[*f] | v[*g],
+                Node::Prev(f) => before(*f, Verdict::Unknown),
+                Node::Since(f, g) => {
+                    v[*g] | (v[*f] & before(i, Verdict::False))
+                }
+                Node::Once(f) => v[*f] | before(i, Verdict::False),
+                Node::Historically(f) => v[*f] & before(i, Verdict::True),
+                Node::OnceIn(lo, hi, f, window) => {
+                    let (lo, hi) = (*lo, *hi);
+                    window.push_back((t, v[*f]));
+                    let found = in_window(window, t, lo, hi)
+                        .fold(Verdict::False, |acc, v| acc | v);
+                    // Unseen steps before the first item may be in the window
+                    if t.checked_sub(hi).is_none_or(|start| start < first) {
+                        found | Verdict::Unknown
+                    } else {
+                        found
+                    }
+                }
+                Node::HistoricallyIn(lo, hi, f, window) => {
+                    let (lo, hi) = (*lo, *hi);
+                    window.push_back((t, v[*f]));
+                    let all = in_window(window, t, lo, hi)
+                        .fold(Verdict::True, |acc, v| acc & v);
+                    if t.checked_sub(hi).is_none_or(|start| start < first) {
+                        all & Verdict::Unknown
+                    } else {
+                        all
+                    }
+                }
+            };
+            v[i] = verdict;
+        }
+        *self.verdicts.last().unwrap()
+    }
+}
+
+// Drop the entries of the window which are too old for any later step, and
+// return the verdicts of those between lo and hi before t
+fn in_window(
+    window: &mut VecDeque<(Time, Verdict)>,
+    t: Time,
+    lo: Time,
+    hi: Time,
+) -> impl Iterator<Item = Verdict> + '_ {
+    while window.front().is_some_and(|&(s, _)| t - s > hi) {
+        window.pop_front();
+    }
+    window.iter().filter(move |&&(s, _)| t - s >= lo).map(|&(_, v)| v)
+}
+
+impl<D> Transducer<(), D, Verdict> for Monitor<'_, D> {
+    fn init(&mut self, i: Ext<()>) -> Ext<Verdict> {
+        if !i.is_none() {
+            self.reset();
+            self.started = true;
+        }
+        Ext::None
+    }
+    fn update(&mut self, item: &D) -> Ext<Verdict> {
+        if self.started {
+            Ext::One(self.step(item))
+        } else {
+            Ext::None
+        }
+    }
+    fn reset(&mut self) {
+        for node in self.nodes.iter_mut() {
+            if let Node::OnceIn(_, _, _, window)
+            | Node::HistoricallyIn(_, _, _, window) = node
+            {
+                window.clear();
+            }
+        }
+        self.started = false;
+        self.first = None;
+        self.n_steps = 0;
+    }
+
+    fn is_epsilon(&self) -> bool {
+        false
+    }
+    fn is_restartable(&self) -> bool {
+        false
+    }
+    fn is_nullable(&self) -> bool {
+        false
+    }
+    fn n_states(&self) -> usize {
+        self.nodes.len()
+    }
+    fn n_transs(&self) -> usize {
+        self.nodes.len()
+    }
+}
+
+/*
+    Parsing
+*/
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum FormulaError {
+    // At the given byte offset in the text
+    Syntax { pos: usize, msg: String },
+    UnknownProp(String),
+}
+impl Display for FormulaError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FormulaError::Syntax { pos, msg } => {
+                write!(f, "at offset {}: {}", pos, msg)
+            }
+            FormulaError::UnknownProp(name) => {
+                write!(f, "unknown proposition {:?}", name)
+            }
+        }
+    }
+}
+impl Error for FormulaError {}
+impl SyntaxError for FormulaError {
+    fn at(span: ops::Range<usize>, msg: String) -> Self {
+        FormulaError::Syntax { pos: span.start, msg }
+    }
+}
+
+// Parse a formula, looking up the atomic propositions with props
+pub fn parse_formula<'a, D, F>(
+    text: &str,
+    props: F,
+) -> Result<Formula<'a, D>, FormulaError>
+where
+    F: Fn(&str) -> Option<Guard<'a, D>>,
+{
+    let mut parser = Parser { scan: Scanner::new(text), props };
+    let f = parser.implies()?;
+    parser.scan.expect_end("formula")?;
+    Ok(f)
+}
+
+struct Parser<'t, F> {
+    scan: Scanner<'t, FormulaError>,
+    props: F,
+}
+impl<'t, F> Parser<'t, F> {
+    fn number(&mut self) -> Result<Time, FormulaError> {
+        self.scan
+            .parse_word()
+            .ok_or_else(|| self.scan.error("expected a number"))
+    }
+    fn interval(&mut self) -> Result<Option<(Time, Time)>, FormulaError> {
+        if !self.scan.eat("[") {
+            return Ok(None);
+        }
+        let lo = self.number()?;
+        self.scan.expect(",")?;
+        let hi = self.number()?;
+        self.scan.expect("]")?;
+        if lo > hi {
+            return Err(self.scan.error("empty interval"));
+        }
+        Ok(Some((lo, hi)))
+    }
+}
+impl<'a, 't, D, F> Parser<'t, F>
+where
+    F: Fn(&str) -> Option<Guard<'a, D>>,
+{
+    fn implies(&mut self) -> Result<Formula<'a, D>, FormulaError> {
+        let f = self.or()?;
+        if self.scan.eat("->") {
+            Ok(implies(f, self.implies()?))
+        } else {
+            Ok(f)
+        }
+    }
+    fn or(&mut self) -> Result<Formula<'a, D>, FormulaError> {
+        let mut f = self.and()?;
+        while self.scan.eat("|") {
+            f = f | self.and()?;
+        }
+        Ok(f)
+    }
+    fn and(&mut self) -> Result<Formula<'a, D>, FormulaError> {
+        let mut f = self.since()?;
+        while self.scan.eat("&") {
+            f = f & self.since()?;
+        }
+        Ok(f)
+    }
+    fn since(&mut self) -> Result<Formula<'a, D>, FormulaError> {
+        let mut f = self.unary()?;
+        loop {
+            let start = self.scan.pos;
+            if self.scan.word() != Some("S") {
+                self.scan.pos = start;
+                return Ok(f);
+            }
+            f = since(f, self.unary()?);
+        }
+    }
+    fn unary(&mut self) -> Result<Formula<'a, D>, FormulaError> {
+        if self.scan.eat("!") {
+            return Ok(!self.unary()?);
+        }
+        if self.scan.eat("(") {
+            let f = self.implies()?;
+            self.scan.expect(")")?;
+            return Ok(f);
+        }
+        let start = self.scan.pos;
+        let word = match self.scan.word() {
+            Some(word) => word,
+            None => return Err(self.scan.error("expected a formula")),
+        };
+        Ok(match word {
+            "true" => Formula::Const(true),
+            "false" => Formula::Const(false),
+            "Y" => prev(self.unary()?),
+            "O" => match self.interval()? {
+                Some((lo, hi)) => once_in(lo, hi, self.unary()?),
+                None => once(self.unary()?),
+            },
+            "H" => match self.interval()? {
+                Some((lo, hi)) => historically_in(lo, hi, self.unary()?),
+                None => historically(self.unary()?),
+            },
+            "S" => {
+                self.scan.pos = start;
+                return Err(self.scan.error("expected a formula"));
+            }
+            name => match (self.props)(name) {
+                Some(g) => prop(g),
+                None => {
+                    return Err(FormulaError::UnknownProp(name.to_string()))
+                }
+            },
+        })
+    }
+}
+
+/*
+    Unit Tests
+*/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::guard::pred;
+    use Verdict::{False as F, True as T, Unknown as U};
+
+    fn props<'a>(name: &str) -> Option<Guard<'a, char>> {
+        match name {
+            "a" | "b" | "c" => {
+                let ch = name.chars().next().unwrap();
+                Some(pred(name, move |&c: &char| c == ch))
+            }
+            _ => None,
+        }
+    }
+    fn verdicts(text: &str, items: &str) -> Vec<Verdict> {
+        let mut m = monitor(parse_formula(text, props).unwrap());
+        m.init_one(());
+        items.chars().map(|c| m.update(&c).unwrap()).collect()
+    }
+
+    #[test]
+    fn test_verdict() {
+        assert_eq!(T & U, U);
+        assert_eq!(F & U, F);
+        assert_eq!(T | U, T);
+        assert_eq!(!U, U);
+        assert_eq!(U.to_string(), "unknown");
+    }
+
+    #[test]
+    fn test_ltl() {
+        assert_eq!(verdicts("a", "abca"), vec![T, F, F, T]);
+        assert_eq!(verdicts("!a & !b", "abca"), vec![F, F, T, F]);
+        assert_eq!(verdicts("Y a", "abca"), vec![U, T, F, F]);
+        assert_eq!(verdicts("Y a | true", "ab"), vec![T, T]);
+        assert_eq!(verdicts("O b", "abca"), vec![F, T, T, T]);
+        assert_eq!(verdicts("H !c", "abca"), vec![T, T, F, F]);
+        // Every c is preceded by an a, with no b in between
+        let f = "c -> Y (!b S a)";
+        assert_eq!(verdicts(f, "acbcaac"), vec![T, T, T, F, T, T, T]);
+        assert_eq!(verdicts("a -> b", "ab"), verdicts("!a | b", "ab"));
+    }
+
+    #[test]
+    fn test_mtl() {
+        // A b within the last 2 steps (including this one)
+        assert_eq!(verdicts("O[0,2] b", "aabaaa"), vec![U, U, T, T, T, F]);
+        // No c between 1 and 2 steps ago
+        assert_eq!(verdicts("H[1,2] !c", "acaaa"), vec![U, U, F, F, T]);
+
+        // With timestamps
+        let is = |ch| prop(pred("", move |&(c, _): &(char, Time)| c == ch));
+        let f = implies(is('b'), once_in(1, 10, is('a')));
+        let mut m = monitor(f).timed(|&(_, t): &(char, Time)| t);
+        m.init_one(());
+        let items = [('a', 100), ('b', 105), ('b', 111), ('a', 120)];
+        let out: Vec<_> =
+            items.iter().map(|(c, t)| m.update(&(*c, *t))).collect();
+        assert_eq!(
+            out,
+            vec![Ext::One(T), Ext::One(T), Ext::One(F), Ext::One(T)]
+        );
+        // .init() restarts
+        m.init_one(());
+        assert_eq!(m.update(&('b', 200)), Ext::One(U));
+    }
+
+    #[test]
+    fn test_parse() {
+        let f = parse_formula("a S b & !c -> Y O[0,3] a", props).unwrap();
+        assert_eq!(f.to_string(), "(!((a S b) & !c) | Y O[0,3] a)");
+        assert_eq!(monitor(f).n_subformulas(), 11);
+        let err = parse_formula::<char, _>("a & d", props).err();
+        assert_eq!(err, Some(FormulaError::UnknownProp("d".to_string())));
+        let err = parse_formula::<char, _>("O[3,1] a", props).err().unwrap();
+        assert_eq!(err.to_string(), "at offset 6: empty interval");
+        assert!(parse_formula::<char, _>("(a", props).is_err());
+        assert!(parse_formula::<char, _>("a b", props).is_err());
+        assert!(parse_formula::<char, _>("S a", props).is_err());
+    }
+}
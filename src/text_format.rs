@@ -0,0 +1,573 @@
+/*
+    Textual description format for DataTransducer state machines.
+
+    Guards and actions are closures, so they can't be written down in a
+    file directly. Instead, the user registers them by name in a FnTable,
+    and the text refers to them by name.
+
+    The format is line-based. Blank lines and lines starting with # are
+    ignored. Each other line is one of:
+        states <n>
+            ensure there are at least n states (numbered 0 to n - 1)
+        state <label>
+            add a new state with the given label
+        label <index> <label>
+            label an existing state (e.g. the initial or final state)
+        update <sources> -> <target> when <guard> do <action>
+            add an update transition
+        epsilon <sources> -> <target> do <action>
+            add an epsilon transition
+    where <sources> is a list of 0 to 2 states, and each state is either
+    an index or a label. The action must be registered with the same number
+    of arguments as there are sources.
+    Built in to every table are the guard "true" and the update/epsilon
+    action "iden" (with one source), which copies the state.
+
+    The guards of the parsed machine are symbolic (see guard.rs), and the
+    actions are named (see DataTransducer::set_action_name()), so it can
+    be exported again (see json_format.rs).
+
+    Example (the windowed average example from the POPL paper):
+        states 4
+        label 2 sum
+        label 3 count
+        update 0 -> 0 when is_b do iden
+        update sum -> sum when is_b do iden
+        update count -> count when is_b do iden
+        update 0 -> sum when is_a do value
+        update 0 -> count when is_a do one
+        update sum -> sum when is_a do add_value
+        update count -> count when is_a do add_one
+        update sum count -> 1 when is_hash do divide
+        update 0 -> 1 when is_hash do iden
+        epsilon 1 -> 0 do iden
+*/
+
+use super::errors::BuildError;
+use super::guard;
+use super::state_machine::{DataTransducer, DataTransducerBuilder, TransRef};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::rc::Rc;
+
+/*
+    Table of named guards and actions
+*/
+
+type Guard<'a, D> = Rc<dyn Fn(&D) -> bool + 'a>;
+type Field<'a, D> = Rc<dyn Fn(&D) -> i64 + 'a>;
+type Action0<'a, D, Q> = Rc<dyn Fn(&D) -> Q + 'a>;
+type Action1<'a, D, Q> = Rc<dyn Fn(&D, &Q) -> Q + 'a>;
+type Action2<'a, D, Q> = Rc<dyn Fn(&D, &Q, &Q) -> Q + 'a>;
+type EpsAction0<'a, Q> = Rc<dyn Fn() -> Q + 'a>;
+type EpsAction1<'a, Q> = Rc<dyn Fn(&Q) -> Q + 'a>;
+type EpsAction2<'a, Q> = Rc<dyn Fn(&Q, &Q) -> Q + 'a>;
+
+pub struct FnTable<'a, D, Q> {
+    pub(crate) guards: HashMap<String, Guard<'a, D>>,
+    // Integer fields of the items, for interval constraints in symbolic
+    // guards (only used by json_format.rs)
+    pub(crate) fields: HashMap<String, Field<'a, D>>,
+    pub(crate) actions0: HashMap<String, Action0<'a, D, Q>>,
+    pub(crate) actions1: HashMap<String, Action1<'a, D, Q>>,
+    pub(crate) actions2: HashMap<String, Action2<'a, D, Q>>,
+    pub(crate) eps_actions0: HashMap<String, EpsAction0<'a, Q>>,
+    pub(crate) eps_actions1: HashMap<String, EpsAction1<'a, Q>>,
+    pub(crate) eps_actions2: HashMap<String, EpsAction2<'a, Q>>,
+}
+
+impl<'a, D, Q> Default for FnTable<'a, D, Q>
+where
+    D: 'a,
+    Q: 'a + Clone,
+{
+    fn default() -> Self {
+        let mut result = Self {
+            guards: HashMap::new(),
+            fields: HashMap::new(),
+            actions0: HashMap::new(),
+            actions1: HashMap::new(),
+            actions2: HashMap::new(),
+            eps_actions0: HashMap::new(),
+            eps_actions1: HashMap::new(),
+            eps_actions2: HashMap::new(),
+        };
+        result.guard("true", |_| true);
+        result.action1("iden", |_, q| q.clone());
+        result.eps_action1("iden", |q| q.clone());
+        result
+    }
+}
+
+impl<'a, D, Q> FnTable<'a, D, Q>
+where
+    D: 'a,
+    Q: 'a + Clone,
+{
+    pub fn new() -> Self {
+        Default::default()
+    }
+    // Register functions under a name (replacing any previous function of
+    // the same kind with that name)
+    pub fn guard<G>(&mut self, name: &str, g: G) -> &mut Self
+    where
+        G: 'a + Fn(&D) -> bool,
+    {
+        self.guards.insert(name.to_string(), Rc::new(g));
+        self
+    }
+    pub fn field<F>(&mut self, name: &str, f: F) -> &mut Self
+    where
+        F: 'a + Fn(&D) -> i64,
+    {
+        self.fields.insert(name.to_string(), Rc::new(f));
+        self
+    }
+    pub fn action0<F>(&mut self, name: &str, f: F) -> &mut Self
+    where
+        F: 'a + Fn(&D) -> Q,
+    {
+        self.actions0.insert(name.to_string(), Rc::new(f));
+        self
+    }
+    pub fn action1<F>(&mut self, name: &str, f: F) -> &mut Self
+    where
+        F: 'a + Fn(&D, &Q) -> Q,
+    {
+        self.actions1.insert(name.to_string(), Rc::new(f));
+        self
+    }
+    pub fn action2<F>(&mut self, name: &str, f: F) -> &mut Self
+    where
+        F: 'a + Fn(&D, &Q, &Q) -> Q,
+    {
+        self.actions2.insert(name.to_string(), Rc::new(f));
+        self
+    }
+    pub fn eps_action0<F>(&mut self, name: &str, f: F) -> &mut Self
+    where
+        F: 'a + Fn() -> Q,
+    {
+        self.eps_actions0.insert(name.to_string(), Rc::new(f));
+        self
+    }
+    pub fn eps_action1<F>(&mut self, name: &str, f: F) -> &mut Self
+    where
+        F: 'a + Fn(&Q) -> Q,
+    {
+        self.eps_actions1.insert(name.to_string(), Rc::new(f));
+        self
+    }
+    pub fn eps_action2<F>(&mut self, name: &str, f: F) -> &mut Self
+    where
+        F: 'a + Fn(&Q, &Q) -> Q,
+    {
+        self.eps_actions2.insert(name.to_string(), Rc::new(f));
+        self
+    }
+}
+
+/*
+    Parse errors
+*/
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum TextErrorKind {
+    // The line is not of any of the forms above
+    Syntax(String),
+    // Guard or action not registered in the table
+    // (for actions, with the given number of arguments)
+    UnknownGuard(String),
+    UnknownAction { name: String, arity: usize },
+    // The line is well-formed but can't be added to the machine
+    Build(BuildError),
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TextError {
+    // Line number, starting from 1
+    pub line: usize,
+    pub kind: TextErrorKind,
+}
+impl fmt::Display for TextError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: ", self.line)?;
+        match &self.kind {
+            TextErrorKind::Syntax(msg) => write!(f, "{}", msg),
+            TextErrorKind::UnknownGuard(name) => {
+                write!(f, "unknown guard {:?}", name)
+            }
+            TextErrorKind::UnknownAction { name, arity } => write!(
+                f,
+                "unknown action {:?} with {} source state(s)",
+                name, arity
+            ),
+            TextErrorKind::Build(err) => write!(f, "{}", err),
+        }
+    }
+}
+impl Error for TextError {}
+
+/*
+    The parser
+*/
+
+// A state reference: either an index or a label
+enum StRef<'t> {
+    Index(usize),
+    Label(&'t str),
+}
+
+// Parsed form of a transition line
+struct TransLine<'t> {
+    sources: Vec<StRef<'t>>,
+    target: StRef<'t>,
+    guard: Option<&'t str>,
+    action: &'t str,
+}
+
+fn syntax_err<T>(msg: &str) -> Result<T, TextErrorKind> {
+    Err(TextErrorKind::Syntax(msg.to_string()))
+}
+
+fn parse_stref(word: &str) -> StRef<'_> {
+    match word.parse() {
+        Ok(i) => StRef::Index(i),
+        Err(_) => StRef::Label(word),
+    }
+}
+
+fn parse_index(word: &str) -> Result<usize, TextErrorKind> {
+    word.parse()
+        .or_else(|_| syntax_err(&format!("expected a number: {}", word)))
+}
+
+// Parse the part of a transition line after the keyword
+// (with guard iff has_guard)
+fn parse_trans<'t>(
+    words: &[&'t str],
+    has_guard: bool,
+) -> Result<TransLine<'t>, TextErrorKind> {
+    let arrow = match words.iter().position(|&w| w == "->") {
+        Some(i) => i,
+        None => return syntax_err("expected ->"),
+    };
+    if arrow > 2 {
+        return syntax_err("at most 2 source states are allowed");
+    }
+    let sources = words[..arrow].iter().map(|w| parse_stref(w)).collect();
+    let rest = &words[arrow + 1..];
+    let (target, guard, action) = match (has_guard, rest) {
+        (true, [target, "when", guard, "do", action]) => {
+            (target, Some(*guard), action)
+        }
+        (false, [target, "do", action]) => (target, None, action),
+        (true, _) => {
+            return syntax_err("expected -> <target> when <guard> do <action>")
+        }
+        (false, _) => return syntax_err("expected -> <target> do <action>"),
+    };
+    Ok(TransLine { sources, target: parse_stref(target), guard, action })
+}
+
+// Add one line to the machine
+fn parse_line<'a, D, Q>(
+    line: &str,
+    table: &FnTable<'a, D, Q>,
+    b: &mut DataTransducerBuilder<'a, D, Q>,
+) -> Result<(), TextErrorKind>
+where
+    D: 'a,
+    Q: 'a + Clone,
+{
+    let words: Vec<&str> = line.split_whitespace().collect();
+    match words.as_slice() {
+        [] => Ok(()),
+        ["states", n] => {
+            let n = parse_index(n)?;
+            if n > b.n_states() {
+                b.set_nstates(n).map_err(TextErrorKind::Build)?;
+            }
+            Ok(())
+        }
+        ["state", label] => {
+            b.add_state_named(label).map_err(TextErrorKind::Build)?;
+            Ok(())
+        }
+        ["label", id, label] => {
+            let id = parse_index(id)?;
+            b.name_state(id, label).map_err(TextErrorKind::Build)
+        }
+        ["update", rest @ ..] => {
+            let tr = parse_trans(rest, true)?;
+            add_update(&tr, table, b)
+        }
+        ["epsilon", rest @ ..] => {
+            let tr = parse_trans(rest, false)?;
+            add_epsilon(&tr, table, b)
+        }
+        [keyword, ..] => syntax_err(&format!("unknown keyword: {}", keyword)),
+    }
+}
+
+// Resolve state references (labels are resolved by the builder)
+macro_rules! with_stref {
+    ($r:expr, $x:ident => $body:expr) => {
+        match $r {
+            StRef::Index($x) => $body,
+            StRef::Label($x) => $body,
+        }
+    };
+}
+
+fn add_update<'a, D, Q>(
+    tr: &TransLine<'_>,
+    table: &FnTable<'a, D, Q>,
+    b: &mut DataTransducerBuilder<'a, D, Q>,
+) -> Result<(), TextErrorKind>
+where
+    D: 'a,
+    Q: 'a + Clone,
+{
+    let guard_name = tr.guard.unwrap();
+    let g = table
+        .guards
+        .get(guard_name)
+        .ok_or_else(|| TextErrorKind::UnknownGuard(guard_name.to_string()))?
+        .clone();
+    let guard = guard::pred(guard_name, move |d: &D| g(d));
+    let unknown = || TextErrorKind::UnknownAction {
+        name: tr.action.to_string(),
+        arity: tr.sources.len(),
+    };
+    let result = match tr.sources.as_slice() {
+        [] => {
+            let f = table.actions0.get(tr.action).ok_or_else(unknown)?.clone();
+            with_stref!(&tr.target, t => {
+                b.add_sym_transition0(*t, guard, move |d| f(d))
+            })
+        }
+        [s] => {
+            let f = table.actions1.get(tr.action).ok_or_else(unknown)?.clone();
+            with_stref!(s, s => with_stref!(&tr.target, t => {
+                b.add_sym_transition1(*s, *t, guard, move |d, q| f(d, q))
+            }))
+        }
+        [s1, s2] => {
+            let f = table.actions2.get(tr.action).ok_or_else(unknown)?.clone();
+            with_stref!(s1, s1 => with_stref!(s2, s2 =>
+                with_stref!(&tr.target, t => b.add_sym_transition2(
+                    *s1,
+                    *s2,
+                    *t,
+                    guard,
+                    move |d, q1, q2| f(d, q1, q2),
+                ))
+            ))
+        }
+        _ => unreachable!(),
+    };
+    name_action(result, tr.action, b)
+}
+
+fn name_action<'a, D, Q>(
+    tr: Result<TransRef, BuildError>,
+    name: &str,
+    b: &mut DataTransducerBuilder<'a, D, Q>,
+) -> Result<(), TextErrorKind>
+where
+    D: 'a,
+    Q: 'a + Clone,
+{
+    tr.and_then(|tr| b.set_action_name(tr, name)).map_err(TextErrorKind::Build)
+}
+
+fn add_epsilon<'a, D, Q>(
+    tr: &TransLine<'_>,
+    table: &FnTable<'a, D, Q>,
+    b: &mut DataTransducerBuilder<'a, D, Q>,
+) -> Result<(), TextErrorKind>
+where
+    D: 'a,
+    Q: 'a + Clone,
+{
+    let unknown = || TextErrorKind::UnknownAction {
+        name: tr.action.to_string(),
+        arity: tr.sources.len(),
+    };
+    let result = match tr.sources.as_slice() {
+        [] => {
+            let f =
+                table.eps_actions0.get(tr.action).ok_or_else(unknown)?.clone();
+            with_stref!(&tr.target, t => b.add_epsilon0(*t, move || f()))
+        }
+        [s] => {
+            let f =
+                table.eps_actions1.get(tr.action).ok_or_else(unknown)?.clone();
+            with_stref!(s, s => with_stref!(&tr.target, t => {
+                b.add_epsilon1(*s, *t, move |q| f(q))
+            }))
+        }
+        [s1, s2] => {
+            let f =
+                table.eps_actions2.get(tr.action).ok_or_else(unknown)?.clone();
+            with_stref!(s1, s1 => with_stref!(s2, s2 =>
+                with_stref!(&tr.target, t => {
+                    b.add_epsilon2(*s1, *s2, *t, move |q1, q2| f(q1, q2))
+                })
+            ))
+        }
+        _ => unreachable!(),
+    };
+    name_action(result, tr.action, b)
+}
+
+// Parse a machine description, using the guards and actions in table
+pub fn parse_machine<'a, D, Q>(
+    text: &str,
+    table: &FnTable<'a, D, Q>,
+) -> Result<DataTransducer<'a, D, Q>, TextError>
+where
+    D: 'a,
+    Q: 'a + Clone,
+{
+    let mut b = DataTransducerBuilder::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.starts_with('#') {
+            continue;
+        }
+        parse_line(line, table, &mut b)
+            .map_err(|kind| TextError { line: i + 1, kind })?;
+    }
+    Ok(b.build())
+}
+
+/* Unit tests */
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ext_value::Ext;
+    use crate::interface::Transducer;
+
+    type ExD = (char, isize);
+    type ExQ = isize;
+
+    fn example_table() -> FnTable<'static, ExD, ExQ> {
+        let mut table = FnTable::new();
+        table
+            .guard("is_a", |&d: &ExD| d.0 == 'a')
+            .guard("is_b", |&d: &ExD| d.0 == 'b')
+            .guard("is_hash", |&d: &ExD| d.0 == '#')
+            .action1("value", |&d: &ExD, _| d.1)
+            .action1("one", |_, _| 1)
+            .action1("add_value", |&d: &ExD, &q| q + d.1)
+            .action1("add_one", |_, &q| q + 1)
+            .action2("divide", |_, &q1, &q2| q1 / q2);
+        table
+    }
+
+    const EXAMPLE: &str = "
+        # Average of 'a' values in each window, separated by '#'
+        states 4
+        label 2 sum
+        label 3 count
+        update 0 -> 0 when is_b do iden
+        update sum -> sum when is_b do iden
+        update count -> count when is_b do iden
+        update 0 -> sum when is_a do value
+        update 0 -> count when is_a do one
+        update sum -> sum when is_a do add_value
+        update count -> count when is_a do add_one
+        update sum count -> 1 when is_hash do divide
+        update 0 -> 1 when is_hash do iden
+        epsilon 1 -> 0 do iden
+    ";
+
+    #[test]
+    fn test_parse_example() {
+        let table = example_table();
+        let mut m = parse_machine(EXAMPLE, &table).unwrap();
+        assert_eq!(m.n_states(), 4);
+        assert_eq!(m.n_transs(), 10);
+        assert_eq!(m.state_id("count"), Some(3));
+        let tr = TransRef::Update(7);
+        assert_eq!(m.action_name(tr), Some("divide"));
+        assert_eq!(m.symbolic_guard(tr).unwrap().to_string(), "is_hash");
+        assert_eq!(m.action_name(TransRef::Epsilon(0)), Some("iden"));
+        assert_eq!(m.init_one(0), Ext::None);
+        assert_eq!(m.update_val(('a', 6)), Ext::None);
+        assert_eq!(m.update_val(('b', 2)), Ext::None);
+        assert_eq!(m.update_val(('a', 8)), Ext::None);
+        assert_eq!(m.update_val(('a', 9)), Ext::None);
+        assert_eq!(m.update_val(('#', 0)), Ext::One(7));
+        assert_eq!(m.update_val(('#', 0)), Ext::One(7));
+        assert_eq!(m.update_val(('a', 2)), Ext::None);
+        assert_eq!(m.update_val(('#', 0)), Ext::One(2));
+    }
+
+    #[test]
+    fn test_parse_state_and_epsilons() {
+        let mut table = example_table();
+        table
+            .action0("get_value", |&d: &ExD| d.1)
+            .eps_action0("zero", || 0)
+            .eps_action2("sum", |&x, &y| x + y);
+        let text = "
+            state zero
+            epsilon -> zero do zero
+            epsilon zero 0 -> 1 do sum
+            update -> 1 when is_b do get_value
+        ";
+        let mut m = parse_machine(text, &table).unwrap();
+        assert_eq!(m.n_states(), 3);
+        assert_eq!(m.init_one(10), Ext::One(10));
+        assert_eq!(m.update_val(('a', 0)), Ext::None);
+        assert_eq!(m.init_one(5), Ext::One(5));
+        assert_eq!(m.update_val(('b', 3)), Ext::One(3));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let table = example_table();
+        let err = |text: &str| parse_machine(text, &table).unwrap_err();
+        assert_eq!(
+            err("states 2\nfoo bar"),
+            TextError {
+                line: 2,
+                kind: TextErrorKind::Syntax("unknown keyword: foo".to_string())
+            }
+        );
+        assert_eq!(
+            err("update 0 -> 1 when is_c do iden").kind,
+            TextErrorKind::UnknownGuard("is_c".to_string())
+        );
+        assert_eq!(
+            err("update 0 -> 1 when true do divide").kind,
+            TextErrorKind::UnknownAction {
+                name: "divide".to_string(),
+                arity: 1
+            }
+        );
+        assert_eq!(
+            err("epsilon 0 -> x do iden").kind,
+            TextErrorKind::Build(BuildError::NoSuchName {
+                name: "x".to_string()
+            })
+        );
+        assert_eq!(
+            err("\n\nupdate 0 -> 5 when true do iden").to_string(),
+            "line 3: transition refers to state 5, but there are only 2 states"
+        );
+        assert!(matches!(
+            err("epsilon 0 1 2 -> 1 do iden").kind,
+            TextErrorKind::Syntax(_)
+        ));
+        assert!(matches!(
+            err("update 0 -> 1 do iden").kind,
+            TextErrorKind::Syntax(_)
+        ));
+    }
+}
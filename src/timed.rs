@@ -0,0 +1,1212 @@
+/*
+    Timed streams: items which carry a timestamp.
+
+    Items of a timed stream are Timed<D>, with timestamps in arbitrary
+    units (e.g. milliseconds) which are nondecreasing along the stream
+    (or have been put in order by a ReorderBuffer). within(), after() and
+    tumbling_window() also take any other item type which carries its
+    timestamp (see interface::DataItem); an item without one is taken to
+    be at the time of the item before it.
+    Transducers over timed streams are ordinary transducers with item type
+    Timed<D>, so the QRE constructs apply unchanged (e.g. an atom over
+    Timed<D> can guard on the time as well as the item). In addition, this
+    module provides the constructs which depend on time:
+    - untimed(m) runs a transducer over D on the items, ignoring the times;
+    - within(m, dur) and after(m, dur) restrict the matches of m to those
+      which span at most (resp. at least) dur, from the first item of the
+      match to the last (see also qre::within, which bounds the number of
+      items instead);
+    - during(lo, hi) is a guard on the time of an item;
+    - tumbling_window(dur, m) restarts m on each window of length dur;
+    - event_time(m, f) and processing_time(m, clock) run m on plain items,
+      stamped with a field of the item or with the time they are processed;
+    - reordered(m) runs m on a stream whose items may arrive out of order,
+      with watermarks (see "Out-of-order streams" below), and
+      watermark_windows(m, dur) emits the result of each window once the
+      watermark passes its end.
+
+    The durations are measured from the first item after .init(): so
+    within() and after() can't be restartable (the matches of m started
+    at different times can't be told apart), and similarly windows. As for
+    Aggregate in qre.rs, .init() should be called once at the start of
+    the stream; calling it again restarts m, discarding any match in
+    progress.
+*/
+
+use super::ext_value::Ext;
+use super::interface::{DataItem, Transducer};
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::marker::PhantomData;
+use std::mem;
+use std::rc::Rc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub type Time = u64;
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Timed<D> {
+    pub time: Time,
+    pub item: D,
+}
+impl<D> Timed<D> {
+    pub fn new(time: Time, item: D) -> Self {
+        Self { time, item }
+    }
+}
+impl<D> DataItem for Timed<D> {
+    type Key = ();
+    fn timestamp(&self) -> Option<Time> {
+        Some(self.time)
+    }
+}
+
+// Guard for items with time in the interval [lo, hi)
+pub fn during<D>(lo: Time, hi: Time) -> impl Fn(&Timed<D>) -> bool + Clone {
+    move |d| lo <= d.time && d.time < hi
+}
+
+/*
+    Ignoring the times
+*/
+
+pub struct Untimed<I, D, O, M>
+where
+    M: Transducer<I, D, O>,
+{
+    m: M,
+    ph_i: PhantomData<I>,
+    ph_d: PhantomData<D>,
+    ph_o: PhantomData<O>,
+}
+pub fn untimed<I, D, O, M>(m: M) -> Untimed<I, D, O, M>
+where
+    M: Transducer<I, D, O>,
+{
+    Untimed { m, ph_i: PhantomData, ph_d: PhantomData, ph_o: PhantomData }
+}
+
+impl<I, D, O, M> Clone for Untimed<I, D, O, M>
+where
+    M: Transducer<I, D, O> + Clone,
+{
+    fn clone(&self) -> Self {
+        untimed(self.m.clone())
+    }
+}
+impl<I, D, O, M> Transducer<I, Timed<D>, O> for Untimed<I, D, O, M>
+where
+    M: Transducer<I, D, O>,
+{
+    fn init(&mut self, i: Ext<I>) -> Ext<O> {
+        self.m.init(i)
+    }
+    fn update(&mut self, item: &Timed<D>) -> Ext<O> {
+        self.m.update(&item.item)
+    }
+    fn reset(&mut self) {
+        self.m.reset();
+    }
+
+    fn is_epsilon(&self) -> bool {
+        self.m.is_epsilon()
+    }
+    fn is_restartable(&self) -> bool {
+        self.m.is_restartable()
+    }
+    fn is_nullable(&self) -> bool {
+        self.m.is_nullable()
+    }
+    fn n_states(&self) -> usize {
+        self.m.n_states()
+    }
+    fn n_transs(&self) -> usize {
+        self.m.n_transs()
+    }
+    fn n_bytes(&self) -> usize {
+        mem::size_of::<Self>() - mem::size_of::<M>() + self.m.n_bytes()
+    }
+}
+
+/*
+    Bounds on the duration of a match
+
+    The output of m is kept only if the time from the first item after
+    .init() to the current item is in [min, max]. (An output on .init()
+    itself has duration 0.) Once the time exceeds max, m is reset, as no
+    later match could be kept.
+*/
+
+pub struct TimeBound<I, D, O, M>
+where
+    M: Transducer<I, D, O>,
+{
+    m: M,
+    min: Time,
+    max: Time,
+    // Time of the first item of the current match, and of the last item
+    start: Option<Time>,
+    last: Option<Time>,
+    ph_i: PhantomData<I>,
+    ph_d: PhantomData<D>,
+    ph_o: PhantomData<O>,
+}
+fn time_bound<I, D, O, M>(min: Time, max: Time, m: M) -> TimeBound<I, D, O, M>
+where
+    M: Transducer<I, D, O>,
+{
+    TimeBound {
+        m,
+        min,
+        max,
+        start: None,
+        last: None,
+        ph_i: PhantomData,
+        ph_d: PhantomData,
+        ph_o: PhantomData,
+    }
+}
+pub fn within<I, D, O, M>(m: M, dur: Time) -> TimeBound<I, D, O, M>
+where
+    D: DataItem,
+    M: Transducer<I, D, O>,
+{
+    time_bound(0, dur, m)
+}
+pub fn after<I, D, O, M>(m: M, dur: Time) -> TimeBound<I, D, O, M>
+where
+    D: DataItem,
+    M: Transducer<I, D, O>,
+{
+    time_bound(dur, Time::MAX, m)
+}
+
+impl<I, D, O, M> TimeBound<I, D, O, M>
+where
+    M: Transducer<I, D, O>,
+{
+    fn in_bounds(&self, elapsed: Time, out: Ext<O>) -> Ext<O> {
+        if self.min <= elapsed && elapsed <= self.max {
+            out
+        } else {
+            Ext::None
+        }
+    }
+}
+impl<I, D, O, M> Clone for TimeBound<I, D, O, M>
+where
+    M: Transducer<I, D, O> + Clone,
+{
+    fn clone(&self) -> Self {
+        let mut result = time_bound(self.min, self.max, self.m.clone());
+        result.start = self.start;
+        result.last = self.last;
+        result
+    }
+}
+impl<I, D, O, M> Transducer<I, D, O> for TimeBound<I, D, O, M>
+where
+    D: DataItem,
+    M: Transducer<I, D, O>,
+{
+    fn init(&mut self, i: Ext<I>) -> Ext<O> {
+        if i.is_none() {
+            return Ext::None;
+        }
+        self.m.reset();
+        self.start = None;
+        self.last = None;
+        let out = self.m.init(i);
+        self.in_bounds(0, out)
+    }
+    fn update(&mut self, item: &D) -> Ext<O> {
+        let time = item.timestamp().or(self.last).unwrap_or(0);
+        self.last = Some(time);
+        let start = *self.start.get_or_insert(time);
+        let elapsed = time.saturating_sub(start);
+        if elapsed > self.max {
+            // Timed out: discard the partial matches
+            self.m.reset();
+            return Ext::None;
+        }
+        let out = self.m.update(item);
+        self.in_bounds(elapsed, out)
+    }
+    fn reset(&mut self) {
+        self.m.reset();
+        self.start = None;
+        self.last = None;
+    }
+
+    fn is_epsilon(&self) -> bool {
+        self.m.is_epsilon()
+    }
+    fn is_restartable(&self) -> bool {
+        false
+    }
+    fn is_nullable(&self) -> bool {
+        self.min == 0 && self.m.is_nullable()
+    }
+    fn n_states(&self) -> usize {
+        self.m.n_states() + 1
+    }
+    fn n_transs(&self) -> usize {
+        self.m.n_transs()
+    }
+    fn n_bytes(&self) -> usize {
+        mem::size_of::<Self>() - mem::size_of::<M>() + self.m.n_bytes()
+    }
+}
+
+/*
+    Tumbling windows
+
+    Time is divided into windows [0, dur), [dur, 2 dur), and so on. The
+    sub-transducer is initialized (with the initial value) at the start of
+    each window which has items, and its outputs are those of the window.
+*/
+
+pub struct TumblingWindow<I, D, O, M>
+where
+    M: Transducer<I, D, O>,
+{
+    m: M,
+    dur: Time,
+    init: Ext<I>,
+    // Index of the current window (None before the first item)
+    window: Option<Time>,
+    ph_d: PhantomData<D>,
+    ph_o: PhantomData<O>,
+}
+pub fn tumbling_window<I, D, O, M>(
+    dur: Time,
+    m: M,
+) -> TumblingWindow<I, D, O, M>
+where
+    D: DataItem,
+    M: Transducer<I, D, O>,
+{
+    assert!(dur > 0, "window duration must be positive");
+    TumblingWindow {
+        m,
+        dur,
+        init: Ext::None,
+        window: None,
+        ph_d: PhantomData,
+        ph_o: PhantomData,
+    }
+}
+
+impl<I, D, O, M> Clone for TumblingWindow<I, D, O, M>
+where
+    I: Clone,
+    D: DataItem,
+    M: Transducer<I, D, O> + Clone,
+{
+    fn clone(&self) -> Self {
+        let mut result = tumbling_window(self.dur, self.m.clone());
+        result.init = self.init.clone();
+        result.window = self.window;
+        result
+    }
+}
+impl<I, D, O, M> Transducer<I, D, O> for TumblingWindow<I, D, O, M>
+where
+    I: Clone,
+    D: DataItem,
+    M: Transducer<I, D, O>,
+{
+    fn init(&mut self, i: Ext<I>) -> Ext<O> {
+        self.init += i.clone();
+        self.m.init(i)
+    }
+    fn update(&mut self, item: &D) -> Ext<O> {
+        let window = match item.timestamp() {
+            Some(time) => time / self.dur,
+            None => self.window.unwrap_or(0),
+        };
+        if self.window.is_some_and(|w| w != window) {
+            self.m.reset();
+            self.m.init(self.init.clone());
+        }
+        self.window = Some(window);
+        self.m.update(item)
+    }
+    fn reset(&mut self) {
+        self.m.reset();
+        self.init = Ext::None;
+        self.window = None;
+    }
+
+    fn is_epsilon(&self) -> bool {
+        self.m.is_epsilon()
+    }
+    fn is_restartable(&self) -> bool {
+        false
+    }
+    fn is_nullable(&self) -> bool {
+        self.m.is_nullable()
+    }
+    fn n_states(&self) -> usize {
+        self.m.n_states() + 2
+    }
+    fn n_transs(&self) -> usize {
+        self.m.n_transs()
+    }
+    fn n_bytes(&self) -> usize {
+        mem::size_of::<Self>() - mem::size_of::<M>() + self.m.n_bytes()
+    }
+}
+
+/*
+    Event time and processing time
+
+    The operators above work on item-carried timestamps ("event time"). To
+    run them on a stream of plain items instead, the items can be stamped:
+    - event_time(m, time_of) takes the timestamp from the item itself;
+    - processing_time(m, clock) takes it from a clock supplied by the
+      driver ("processing time"), at the time the item is processed.
+    Clocks are injectable: SystemClock reads the wall clock, and
+    ManualClock is set by hand, to test time-based operators
+    deterministically.
+*/
+
+pub trait Clock {
+    fn now(&self) -> Time;
+}
+
+// Wall clock, in milliseconds since the Unix epoch
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+impl Clock for SystemClock {
+    fn now(&self) -> Time {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis() as Time)
+    }
+}
+
+// Clock which only moves when told to; clones share the same time
+#[derive(Clone, Debug, Default)]
+pub struct ManualClock(Rc<Cell<Time>>);
+impl ManualClock {
+    pub fn new(time: Time) -> Self {
+        Self(Rc::new(Cell::new(time)))
+    }
+    pub fn set(&self, time: Time) {
+        self.0.set(time);
+    }
+    pub fn advance(&self, dur: Time) {
+        self.0.set(self.0.get() + dur);
+    }
+}
+impl Clock for ManualClock {
+    fn now(&self) -> Time {
+        self.0.get()
+    }
+}
+
+pub struct Stamped<I, D, O, M, F>
+where
+    M: Transducer<I, Timed<D>, O>,
+    F: Fn(&D) -> Time,
+{
+    m: M,
+    time_of: F,
+    ph_i: PhantomData<I>,
+    ph_d: PhantomData<D>,
+    ph_o: PhantomData<O>,
+}
+pub fn event_time<I, D, O, M, F>(m: M, time_of: F) -> Stamped<I, D, O, M, F>
+where
+    M: Transducer<I, Timed<D>, O>,
+    F: Fn(&D) -> Time,
+{
+    Stamped {
+        m,
+        time_of,
+        ph_i: PhantomData,
+        ph_d: PhantomData,
+        ph_o: PhantomData,
+    }
+}
+pub fn processing_time<I, D, O, M, C>(
+    m: M,
+    clock: C,
+) -> Stamped<I, D, O, M, impl Fn(&D) -> Time + Clone>
+where
+    M: Transducer<I, Timed<D>, O>,
+    C: Clock + Clone,
+{
+    event_time(m, move |_| clock.now())
+}
+
+impl<I, D, O, M, F> Clone for Stamped<I, D, O, M, F>
+where
+    M: Transducer<I, Timed<D>, O> + Clone,
+    F: Fn(&D) -> Time + Clone,
+{
+    fn clone(&self) -> Self {
+        event_time(self.m.clone(), self.time_of.clone())
+    }
+}
+impl<I, D, O, M, F> Transducer<I, D, O> for Stamped<I, D, O, M, F>
+where
+    D: Clone,
+    M: Transducer<I, Timed<D>, O>,
+    F: Fn(&D) -> Time,
+{
+    fn init(&mut self, i: Ext<I>) -> Ext<O> {
+        self.m.init(i)
+    }
+    fn update(&mut self, item: &D) -> Ext<O> {
+        let time = (self.time_of)(item);
+        self.m.update(&Timed::new(time, item.clone()))
+    }
+    fn reset(&mut self) {
+        self.m.reset();
+    }
+
+    fn is_epsilon(&self) -> bool {
+        self.m.is_epsilon()
+    }
+    fn is_restartable(&self) -> bool {
+        self.m.is_restartable()
+    }
+    fn is_nullable(&self) -> bool {
+        self.m.is_nullable()
+    }
+    fn n_states(&self) -> usize {
+        self.m.n_states()
+    }
+    fn n_transs(&self) -> usize {
+        self.m.n_transs()
+    }
+    fn n_bytes(&self) -> usize {
+        mem::size_of::<Self>() - mem::size_of::<M>() + self.m.n_bytes()
+    }
+}
+
+/*
+    Out-of-order streams
+
+    On real feeds, items may arrive slightly out of timestamp order. Such a
+    stream is given as a sequence of Events: items, in arrival order,
+    interleaved with watermarks. A watermark t promises that no item with
+    time <= t arrives afterwards; items which break the promise are "late".
+
+    - ReorderBuffer holds the items until a watermark passes them, then
+      releases them in timestamp order (items with equal times are released
+      in arrival order). Late items are rejected.
+    - BoundedDelay generates watermarks for feeds where items are at most
+      `delay` behind the latest time seen, and with_watermarks() applies it
+      to a stream of timed items.
+    - Reordered drives a transducer over Timed<D> from a stream of Events,
+      so the transducer sees items in order; its outputs are produced only
+      once a watermark passes the corresponding items.
+*/
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Event<D> {
+    Item(Timed<D>),
+    Watermark(Time),
+}
+
+#[derive(Clone, Debug)]
+pub struct ReorderBuffer<D> {
+    // Keyed by (time, arrival number)
+    pending: BTreeMap<(Time, u64), D>,
+    watermark: Option<Time>,
+    arrivals: u64,
+}
+impl<D> Default for ReorderBuffer<D> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl<D> ReorderBuffer<D> {
+    pub fn new() -> Self {
+        Self { pending: BTreeMap::new(), watermark: None, arrivals: 0 }
+    }
+    pub fn watermark(&self) -> Option<Time> {
+        self.watermark
+    }
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+    pub fn is_late(&self, time: Time) -> bool {
+        self.watermark.is_some_and(|wm| time <= wm)
+    }
+
+    // Buffer an item; late items are given back
+    pub fn insert(&mut self, item: Timed<D>) -> Result<(), Timed<D>> {
+        if self.is_late(item.time) {
+            return Err(item);
+        }
+        self.pending.insert((item.time, self.arrivals), item.item);
+        self.arrivals += 1;
+        Ok(())
+    }
+
+    // Advance the watermark, releasing the items it passes in order
+    // (watermarks which don't advance are ignored)
+    pub fn advance(&mut self, watermark: Time) -> Vec<Timed<D>> {
+        if self.is_late(watermark) {
+            return Vec::new();
+        }
+        self.watermark = Some(watermark);
+        let later = self.pending.split_off(&(watermark, u64::MAX));
+        let released = mem::replace(&mut self.pending, later);
+        released
+            .into_iter()
+            .map(|((time, _), item)| Timed::new(time, item))
+            .collect()
+    }
+
+    // Release all remaining items, as at the end of the stream
+    pub fn flush(&mut self) -> Vec<Timed<D>> {
+        self.advance(Time::MAX)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct BoundedDelay {
+    delay: Time,
+    latest: Option<Time>,
+}
+impl BoundedDelay {
+    pub fn new(delay: Time) -> Self {
+        Self { delay, latest: None }
+    }
+    // Observe the time of an arriving item; return the new watermark, if
+    // it advances
+    pub fn observe(&mut self, time: Time) -> Option<Time> {
+        if self.latest.is_some_and(|latest| time <= latest) {
+            return None;
+        }
+        self.latest = Some(time);
+        time.checked_sub(self.delay + 1)
+    }
+}
+
+pub struct WithWatermarks<D, It> {
+    items: It,
+    delay: BoundedDelay,
+    // Watermark to emit after the current item
+    next: Option<Time>,
+    done: bool,
+    ph_d: PhantomData<D>,
+}
+// Interleave the items with watermarks, assuming a bounded delay; a final
+// watermark at the end of the stream releases everything
+pub fn with_watermarks<D, It>(items: It, delay: Time) -> WithWatermarks<D, It>
+where
+    It: Iterator<Item = Timed<D>>,
+{
+    WithWatermarks {
+        items,
+        delay: BoundedDelay::new(delay),
+        next: None,
+        done: false,
+        ph_d: PhantomData,
+    }
+}
+impl<D, It> Iterator for WithWatermarks<D, It>
+where
+    It: Iterator<Item = Timed<D>>,
+{
+    type Item = Event<D>;
+    fn next(&mut self) -> Option<Event<D>> {
+        if let Some(wm) = self.next.take() {
+            return Some(Event::Watermark(wm));
+        }
+        if self.done {
+            return None;
+        }
+        match self.items.next() {
+            Some(item) => {
+                self.next = self.delay.observe(item.time);
+                Some(Event::Item(item))
+            }
+            None => {
+                self.done = true;
+                Some(Event::Watermark(Time::MAX))
+            }
+        }
+    }
+}
+
+pub struct Reordered<I, D, O, M>
+where
+    M: Transducer<I, Timed<D>, O>,
+{
+    m: M,
+    buffer: ReorderBuffer<D>,
+    late: usize,
+    ph_i: PhantomData<I>,
+    ph_o: PhantomData<O>,
+}
+pub fn reordered<I, D, O, M>(m: M) -> Reordered<I, D, O, M>
+where
+    M: Transducer<I, Timed<D>, O>,
+{
+    Reordered {
+        m,
+        buffer: ReorderBuffer::new(),
+        late: 0,
+        ph_i: PhantomData,
+        ph_o: PhantomData,
+    }
+}
+impl<I, D, O, M> Reordered<I, D, O, M>
+where
+    M: Transducer<I, Timed<D>, O>,
+{
+    pub fn init(&mut self, i: Ext<I>) -> Ext<O> {
+        self.m.init(i)
+    }
+    pub fn init_one(&mut self, i: I) -> Ext<O> {
+        self.m.init(Ext::One(i))
+    }
+
+    // Process an event, returning the outputs on the items released by it
+    // (each timestamped with its item); late items are dropped
+    pub fn process(&mut self, event: Event<D>) -> Vec<Timed<Ext<O>>> {
+        match event {
+            Event::Item(item) => {
+                if self.buffer.insert(item).is_err() {
+                    self.late += 1;
+                }
+                Vec::new()
+            }
+            Event::Watermark(wm) => {
+                let released = self.buffer.advance(wm);
+                self.run(released)
+            }
+        }
+    }
+    pub fn finish(&mut self) -> Vec<Timed<Ext<O>>> {
+        let released = self.buffer.flush();
+        self.run(released)
+    }
+    fn run(&mut self, items: Vec<Timed<D>>) -> Vec<Timed<Ext<O>>> {
+        let m = &mut self.m;
+        items.iter().map(|item| Timed::new(item.time, m.update(item))).collect()
+    }
+
+    pub fn watermark(&self) -> Option<Time> {
+        self.buffer.watermark()
+    }
+    pub fn n_pending(&self) -> usize {
+        self.buffer.len()
+    }
+    pub fn n_late(&self) -> usize {
+        self.late
+    }
+    pub fn reset(&mut self) {
+        self.m.reset();
+        self.buffer = ReorderBuffer::new();
+        self.late = 0;
+    }
+}
+
+/*
+    Watermark-aware windows
+
+    tumbling_window above emits the output of each window eagerly, on every
+    item, which assumes items in order. On an out-of-order stream, a window
+    [start, end) is only complete once the watermark reaches end - 1. So
+    watermark_windows(m, dur) buffers the items of each window, and emits
+    its result exactly when the watermark passes the window end: m is run
+    (from the initial value, on the items in timestamp order) and its output
+    after the last item is the result.
+
+    Items for a window whose result was emitted are late. The window is
+    kept for allowed_lateness(t) time after its end, and late items in that
+    time are handled by the LatePolicy:
+    - Drop: the item is dropped;
+    - SideOutput: the item is set aside, for the caller to .take_late();
+    - Update (the default): the result is recomputed, and emitted as a
+      retraction of the previous result followed by the correction (with
+      the next revision number).
+    Items arriving after that can't update the window, so they are dropped
+    (or set aside, with SideOutput). All late items which don't update a
+    window are counted, so that late data is never silently lost.
+*/
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum LatePolicy {
+    Drop,
+    SideOutput,
+    #[default]
+    Update,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct WindowOutput<O> {
+    pub start: Time,
+    pub end: Time,
+    pub output: Ext<O>,
+    // 0 for the result on the watermark, then 1, 2, ... for updates
+    pub revision: usize,
+    // Whether this retracts the result with this revision
+    pub retraction: bool,
+}
+
+struct Pane<D, O> {
+    items: Vec<Timed<D>>,
+    // Last result emitted
+    last: Option<WindowOutput<O>>,
+}
+
+pub struct WatermarkWindows<I, D, O, M>
+where
+    M: Transducer<I, Timed<D>, O>,
+{
+    m: M,
+    dur: Time,
+    lateness: Time,
+    policy: LatePolicy,
+    init: Ext<I>,
+    // Keyed by window index (start / dur)
+    panes: BTreeMap<Time, Pane<D, O>>,
+    watermark: Option<Time>,
+    // Late items set aside, and the number of late items not applied
+    side: Vec<Timed<D>>,
+    late: usize,
+}
+pub fn watermark_windows<I, D, O, M>(
+    m: M,
+    dur: Time,
+) -> WatermarkWindows<I, D, O, M>
+where
+    M: Transducer<I, Timed<D>, O>,
+{
+    assert!(dur > 0, "window duration must be positive");
+    WatermarkWindows {
+        m,
+        dur,
+        lateness: 0,
+        policy: LatePolicy::default(),
+        init: Ext::None,
+        panes: BTreeMap::new(),
+        watermark: None,
+        side: Vec::new(),
+        late: 0,
+    }
+}
+impl<I, D, O, M> WatermarkWindows<I, D, O, M>
+where
+    I: Clone,
+    O: Clone,
+    M: Transducer<I, Timed<D>, O> + Clone,
+{
+    pub fn allowed_lateness(mut self, lateness: Time) -> Self {
+        self.lateness = lateness;
+        self
+    }
+    pub fn late_policy(mut self, policy: LatePolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    pub fn init(&mut self, i: Ext<I>) {
+        self.init += i;
+    }
+    pub fn init_one(&mut self, i: I) {
+        self.init(Ext::One(i));
+    }
+
+    // Process an event, returning the window results it causes
+    pub fn process(&mut self, event: Event<D>) -> Vec<WindowOutput<O>> {
+        match event {
+            Event::Item(item) => self.insert(item),
+            Event::Watermark(wm) => self.advance(wm),
+        }
+    }
+    pub fn finish(&mut self) -> Vec<WindowOutput<O>> {
+        self.advance(Time::MAX)
+    }
+
+    pub fn watermark(&self) -> Option<Time> {
+        self.watermark
+    }
+    pub fn n_open(&self) -> usize {
+        self.panes.len()
+    }
+    pub fn n_late(&self) -> usize {
+        self.late
+    }
+    pub fn take_late(&mut self) -> Vec<Timed<D>> {
+        mem::take(&mut self.side)
+    }
+
+    fn end(&self, index: Time) -> Time {
+        index.saturating_mul(self.dur).saturating_add(self.dur)
+    }
+    fn is_complete(&self, index: Time) -> bool {
+        self.watermark.is_some_and(|wm| self.end(index) - 1 <= wm)
+    }
+    fn is_expired(&self, index: Time) -> bool {
+        let last = (self.end(index) - 1).saturating_add(self.lateness);
+        self.watermark.is_some_and(|wm| last <= wm)
+    }
+
+    fn insert(&mut self, item: Timed<D>) -> Vec<WindowOutput<O>> {
+        let index = item.time / self.dur;
+        let complete = self.is_complete(index);
+        if self.is_expired(index)
+            || (complete && self.policy != LatePolicy::Update)
+        {
+            self.late += 1;
+            if self.policy == LatePolicy::SideOutput {
+                self.side.push(item);
+            }
+            return Vec::new();
+        }
+        let pane = self
+            .panes
+            .entry(index)
+            .or_insert_with(|| Pane { items: Vec::new(), last: None });
+        pane.items.push(item);
+        if complete {
+            self.fire(index)
+        } else {
+            Vec::new()
+        }
+    }
+    fn advance(&mut self, wm: Time) -> Vec<WindowOutput<O>> {
+        if self.watermark.is_some_and(|old| wm <= old) {
+            return Vec::new();
+        }
+        self.watermark = Some(wm);
+        let ready: Vec<Time> = self
+            .panes
+            .iter()
+            .filter(|&(&i, pane)| pane.last.is_none() && self.is_complete(i))
+            .map(|(&index, _)| index)
+            .collect();
+        let result =
+            ready.into_iter().flat_map(|index| self.fire(index)).collect();
+        let expired: Vec<Time> = self
+            .panes
+            .keys()
+            .copied()
+            .filter(|&i| self.is_expired(i))
+            .collect();
+        for index in expired {
+            self.panes.remove(&index);
+        }
+        result
+    }
+    // Emit the (new) result of a window, retracting the previous one
+    fn fire(&mut self, index: Time) -> Vec<WindowOutput<O>> {
+        let end = self.end(index);
+        let mut m = self.m.spawn_empty();
+        let pane = self.panes.get_mut(&index).unwrap();
+        pane.items.sort_by_key(|item| item.time);
+        let mut output = m.init(self.init.clone());
+        for item in &pane.items {
+            output = m.update(item);
+        }
+        let mut result = Vec::new();
+        let mut revision = 0;
+        if let Some(mut last) = pane.last.take() {
+            revision = last.revision + 1;
+            last.retraction = true;
+            result.push(last);
+        }
+        let new = WindowOutput {
+            start: end - self.dur,
+            end,
+            output,
+            revision,
+            retraction: false,
+        };
+        pane.last = Some(new.clone());
+        result.push(new);
+        result
+    }
+}
+
+/*
+    Unit Tests
+*/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::qre::{atom, atom_univ, concat, iterate, union};
+
+    type Ev = Timed<char>;
+
+    fn stream(items: &[(Time, char)]) -> Vec<Ev> {
+        items.iter().map(|&(t, ch)| Timed::new(t, ch)).collect()
+    }
+
+    fn is(ch: char) -> impl Fn(&Ev) -> bool + Clone {
+        move |e| e.item == ch
+    }
+
+    // An 'a' followed by a 'b', outputting the time of the 'b'
+    fn a_then_b() -> impl Transducer<(), Ev, Time> + Clone {
+        concat(atom(is('a'), |(), _| ()), atom(is('b'), |(), e: &Ev| e.time))
+    }
+
+    #[test]
+    fn test_within_after() {
+        let items = stream(&[(0, 'a'), (5, 'b')]);
+        let mut m = within(a_then_b(), 10);
+        m.init_one(());
+        assert_eq!(m.update_batch(&items), vec![Ext::None, Ext::One(5)]);
+        let mut m = within(a_then_b(), 4);
+        m.init_one(());
+        assert_eq!(m.update_batch(&items), vec![Ext::None, Ext::None]);
+        let mut m = after(a_then_b(), 4);
+        assert!(!m.is_nullable() && !m.is_restartable());
+        m.init_one(());
+        assert_eq!(m.update_batch(&items), vec![Ext::None, Ext::One(5)]);
+        // Measured from the first item after the restart
+        let items = stream(&[(0, 'c'), (20, 'a'), (25, 'b')]);
+        let mut m = within(a_then_b(), 10);
+        assert_eq!(m.init_one(()), Ext::None);
+        assert_eq!(m.update(&items[0]), Ext::None);
+        m.init_one(());
+        assert_eq!(m.update_batch(&items[1..]), vec![Ext::None, Ext::One(25)]);
+    }
+
+    #[test]
+    fn test_untimed_during() {
+        let items = stream(&[(10, 'a'), (15, 'b'), (20, 'c'), (25, 'd')]);
+        // Count the items between times 10 and 20
+        let count = atom(during(10, 20), |n: usize, _: &Ev| n + 1);
+        let mut m = iterate(union(count, atom(during(20, 30), |n, _| n)));
+        m.init_one(0);
+        let out = m.update_batch(&items);
+        assert_eq!(
+            out,
+            vec![Ext::One(1), Ext::One(2), Ext::One(2), Ext::One(2)]
+        );
+        // Count all items
+        let mut m = untimed(iterate(atom_univ(|n: usize, _: &char| n + 1)));
+        m.init_one(0);
+        let out = m.update_batch(&items);
+        assert_eq!(
+            out,
+            vec![Ext::One(1), Ext::One(2), Ext::One(3), Ext::One(4)]
+        );
+    }
+
+    #[test]
+    fn test_tumbling_window() {
+        // Sum of the items (as digits) in windows of 10
+        let sum = iterate(atom(
+            |_: &Timed<char>| true,
+            |s: u32, e: &Timed<char>| s + e.item.to_digit(10).unwrap(),
+        ));
+        let mut m = tumbling_window(10, sum);
+        m.init_one(0);
+        let items =
+            stream(&[(1, '1'), (9, '2'), (10, '3'), (35, '4'), (39, '5')]);
+        assert_eq!(
+            m.update_batch(&items),
+            vec![
+                Ext::One(1),
+                Ext::One(3),
+                Ext::One(3),
+                Ext::One(4),
+                Ext::One(9)
+            ]
+        );
+        let mut m2 = m.clone();
+        assert_eq!(m2.update(&Timed::new(41, '1')), Ext::One(1));
+        m.reset();
+        assert_eq!(m.update(&Timed::new(41, '1')), Ext::None);
+    }
+
+    #[test]
+    fn test_data_items() {
+        // Log lines, some of which (continuations) have no timestamp
+        struct Line(Option<Time>, &'static str);
+        impl DataItem for Line {
+            type Key = ();
+            fn timestamp(&self) -> Option<Time> {
+                self.0
+            }
+        }
+        let lines = [
+            Line(Some(3), "start"),
+            Line(None, "..."),
+            Line(Some(12), "start"),
+            Line(None, "..."),
+            Line(Some(14), "end"),
+        ];
+        let count = iterate(atom(|_: &Line| true, |n: usize, _| n + 1));
+        let mut m = tumbling_window(10, count);
+        m.init_one(0);
+        let out: Vec<usize> =
+            m.update_batch(&lines).into_iter().map(Ext::unwrap).collect();
+        assert_eq!(out, [1, 2, 1, 2, 3]);
+
+        // A start followed by an end, at most 5 apart
+        let start = atom(|l: &Line| l.1 == "start", |(), _| ());
+        let rest = iterate(atom(|l: &Line| l.1 == "...", |(), _| ()));
+        let end = atom(|l: &Line| l.1 == "end", |(), l: &Line| l.0);
+        let mut m = within(concat(concat(start, rest), end), 5);
+        m.init_one(());
+        assert_eq!(m.update_batch(&lines[2..])[2], Ext::One(Some(14)));
+        m.init_one(());
+        assert_eq!(m.update_batch(&lines)[4], Ext::None);
+    }
+
+    #[test]
+    fn test_reorder_buffer() {
+        let mut buf = ReorderBuffer::new();
+        for &(t, ch) in &[(3, 'a'), (1, 'b'), (3, 'c'), (2, 'd')] {
+            buf.insert(Timed::new(t, ch)).unwrap();
+        }
+        assert_eq!(buf.advance(2), stream(&[(1, 'b'), (2, 'd')]));
+        assert_eq!(buf.watermark(), Some(2));
+        assert_eq!(buf.insert(Timed::new(2, 'e')), Err(Timed::new(2, 'e')));
+        assert!(buf.advance(1).is_empty());
+        assert_eq!(buf.len(), 2);
+        assert_eq!(buf.flush(), stream(&[(3, 'a'), (3, 'c')]));
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_watermarks() {
+        let items = stream(&[(5, 'a'), (3, 'b'), (8, 'c'), (6, 'd')]);
+        let events: Vec<Event<char>> =
+            with_watermarks(items.into_iter(), 2).collect();
+        assert_eq!(
+            events,
+            vec![
+                Event::Item(Timed::new(5, 'a')),
+                Event::Watermark(2),
+                Event::Item(Timed::new(3, 'b')),
+                Event::Item(Timed::new(8, 'c')),
+                Event::Watermark(5),
+                Event::Item(Timed::new(6, 'd')),
+                Event::Watermark(Time::MAX),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_reordered() {
+        // 'a' followed by 'b' in timestamp order, even if they arrive
+        // the other way around
+        let mut m = reordered(a_then_b());
+        m.init_one(());
+        let items = stream(&[(2, 'b'), (1, 'a'), (0, 'c'), (7, 'a')]);
+        let mut events = with_watermarks(items.into_iter(), 1);
+        let mut out = Vec::new();
+        for event in &mut events {
+            out.extend(m.process(event));
+        }
+        assert_eq!(m.n_late(), 1);
+        assert_eq!(m.n_pending(), 0);
+        assert_eq!(
+            out,
+            vec![
+                Timed::new(1, Ext::None),
+                Timed::new(2, Ext::One(2)),
+                Timed::new(7, Ext::None),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_event_processing_time() {
+        // The same query, within 10 time units, in both modes
+        let items = ['a', 'b', 'a', 'c', 'b'];
+        let mut m = event_time(within(a_then_b(), 10), |_: &char| 5);
+        m.init_one(());
+        assert_eq!(m.update_batch(&items)[1], Ext::One(5));
+        let clock = ManualClock::new(100);
+        let mut m = processing_time(within(a_then_b(), 10), clock.clone());
+        m.init_one(());
+        let mut out = Vec::new();
+        for item in &items {
+            out.push(m.update(item));
+            clock.advance(7);
+        }
+        assert_eq!(
+            out,
+            vec![Ext::None, Ext::One(107), Ext::None, Ext::None, Ext::None]
+        );
+        assert!(SystemClock.now() > 0);
+    }
+
+    #[test]
+    fn test_watermark_windows() {
+        // Sum of the items (as digits) in windows of 10, reporting the
+        // window sum only once it is complete
+        let sum = iterate(atom(
+            |_: &Timed<char>| true,
+            |s: u32, e: &Timed<char>| s + e.item.to_digit(10).unwrap(),
+        ));
+        let mut m = watermark_windows(sum.clone(), 10).allowed_lateness(5);
+        m.init_one(0);
+        let window = |start, output, revision| WindowOutput {
+            start,
+            end: start + 10,
+            output: Ext::One(output),
+            revision,
+            retraction: false,
+        };
+        let retract = |start, output, revision| WindowOutput {
+            retraction: true,
+            ..window(start, output, revision)
+        };
+        let items = stream(&[(3, '1'), (12, '2'), (1, '3'), (10, '4')]);
+        for item in items.iter().cloned() {
+            assert!(m.process(Event::Item(item)).is_empty());
+        }
+        assert!(m.process(Event::Watermark(8)).is_empty());
+        assert_eq!(m.process(Event::Watermark(12)), vec![window(0, 4, 0)]);
+        // Late, but within the allowed lateness
+        let late = Event::Item(Timed::new(5, '5'));
+        assert_eq!(m.process(late), vec![retract(0, 4, 0), window(0, 9, 1)]);
+        assert_eq!(m.n_open(), 2);
+        assert!(m.process(Event::Watermark(14)).is_empty());
+        assert_eq!(m.n_open(), 1);
+        // Too late
+        assert!(m.process(Event::Item(Timed::new(9, '1'))).is_empty());
+        assert_eq!(m.n_late(), 1);
+        assert_eq!(m.process(Event::Item(Timed::new(15, '3'))), vec![]);
+        assert_eq!(m.finish(), vec![window(10, 9, 0)]);
+        assert_eq!(m.n_open(), 0);
+        assert!(m.take_late().is_empty());
+
+        // Other policies
+        let events = || {
+            let mut events: Vec<Event<char>> =
+                items.iter().cloned().map(Event::Item).collect();
+            events.push(Event::Watermark(12));
+            events.push(Event::Item(Timed::new(5, '5')));
+            events
+        };
+        let mut m = watermark_windows(sum.clone(), 10)
+            .allowed_lateness(5)
+            .late_policy(LatePolicy::Drop);
+        m.init_one(0);
+        let out: Vec<_> =
+            events().into_iter().flat_map(|e| m.process(e)).collect();
+        assert_eq!(out, vec![window(0, 4, 0)]);
+        assert_eq!(m.n_late(), 1);
+        assert!(m.take_late().is_empty());
+        let mut m =
+            watermark_windows(sum, 10).late_policy(LatePolicy::SideOutput);
+        m.init_one(0);
+        let out: Vec<_> =
+            events().into_iter().flat_map(|e| m.process(e)).collect();
+        assert_eq!(out, vec![window(0, 4, 0)]);
+        assert_eq!(m.take_late(), stream(&[(5, '5')]));
+        assert!(m.take_late().is_empty());
+    }
+}
@@ -0,0 +1,454 @@
+/*
+    Weighted regexes: regexes whose symbols carry weights in a semiring
+    (see semiring.rs), evaluated to the sum over all the ways of matching
+    the stream of the product of the weights along each.
+
+    In the QRE constructs and in DataTransducer, a stream matched in two
+    ways gives Ext::Many; here the two weights are added with the semiring
+    plus instead. So with the counting semiring (u64) the output is the
+    number of parses, with min-plus the cost of the cheapest parse, and
+    with the boolean semiring whether the stream matches at all.
+
+    A symbol sym(g, w) matches an item on which the guard g holds, with
+    weight w(item) (or a constant, with sym_const). Concatenation
+    multiplies the weights (in order, so the semiring need not be
+    commutative), union adds them, and star is the sum over all numbers
+    of iterations. Iterations which match the empty stream are not
+    counted (otherwise, the sum would be infinite when the body of a star
+    is nullable); so e.g. (a*)* matches "aa" in two ways, not infinitely
+    many.
+
+    The regex compiles (by the Glushkov construction) to a machine with a
+    state for each symbol of the regex, and weighted transitions between
+    them: Weighted, a transducer which holds a weight for each state, and
+    after each item outputs the weight of the stream since .init() times
+    the initial value (Ext::None if the stream doesn't match). Several
+    .init()s add up: the output is the sum over the initial values.
+*/
+
+use super::ext_value::Ext;
+use super::guard::Guard;
+use super::interface::Transducer;
+use super::semiring::Semiring;
+use std::ops;
+use std::rc::Rc;
+
+/*
+    Weighted regexes
+*/
+
+pub type Weight<'a, D, S> = Rc<dyn Fn(&D) -> S + 'a>;
+
+pub enum WRegex<'a, D, S> {
+    Empty,
+    Eps,
+    Sym(Guard<'a, D>, Weight<'a, D, S>),
+    Cat(Box<WRegex<'a, D, S>>, Box<WRegex<'a, D, S>>),
+    Alt(Box<WRegex<'a, D, S>>, Box<WRegex<'a, D, S>>),
+    Star(Box<WRegex<'a, D, S>>),
+}
+impl<D, S> Clone for WRegex<'_, D, S> {
+    fn clone(&self) -> Self {
+        match self {
+            WRegex::Empty => WRegex::Empty,
+            WRegex::Eps => WRegex::Eps,
+            WRegex::Sym(g, w) => WRegex::Sym(g.clone(), w.clone()),
+            WRegex::Cat(r1, r2) => WRegex::Cat(r1.clone(), r2.clone()),
+            WRegex::Alt(r1, r2) => WRegex::Alt(r1.clone(), r2.clone()),
+            WRegex::Star(r) => WRegex::Star(r.clone()),
+        }
+    }
+}
+
+pub fn sym<'a, D, S, F>(g: Guard<'a, D>, weight: F) -> WRegex<'a, D, S>
+where
+    F: Fn(&D) -> S + 'a,
+{
+    WRegex::Sym(g, Rc::new(weight))
+}
+pub fn sym_const<'a, D, S>(g: Guard<'a, D>, weight: S) -> WRegex<'a, D, S>
+where
+    S: Clone + 'a,
+{
+    sym(g, move |_| weight.clone())
+}
+pub fn cat<'a, D, S>(
+    r1: WRegex<'a, D, S>,
+    r2: WRegex<'a, D, S>,
+) -> WRegex<'a, D, S> {
+    WRegex::Cat(Box::new(r1), Box::new(r2))
+}
+pub fn star<D, S>(r: WRegex<'_, D, S>) -> WRegex<'_, D, S> {
+    WRegex::Star(Box::new(r))
+}
+pub fn plus<D, S>(r: WRegex<'_, D, S>) -> WRegex<'_, D, S> {
+    cat(r.clone(), star(r))
+}
+pub fn opt<D, S>(r: WRegex<'_, D, S>) -> WRegex<'_, D, S> {
+    WRegex::Eps | r
+}
+// Concatenation of any number of regexes
+pub fn seq<D, S>(rs: Vec<WRegex<'_, D, S>>) -> WRegex<'_, D, S> {
+    rs.into_iter().rev().fold(WRegex::Eps, |acc, r| cat(r, acc))
+}
+
+impl<'a, D, S> ops::BitOr for WRegex<'a, D, S> {
+    type Output = WRegex<'a, D, S>;
+    fn bitor(self, other: Self) -> Self {
+        WRegex::Alt(Box::new(self), Box::new(other))
+    }
+}
+
+/*
+    Glushkov construction
+
+    For each subexpression: the weight of the empty stream (if nullable),
+    the symbols which can come first and last, with the weights before
+    the first and after the last, and (added to the machine) the weights
+    between consecutive symbols.
+*/
+
+type Positions<S> = Vec<(usize, S)>;
+
+struct Glushkov<S> {
+    empty: Option<S>,
+    first: Positions<S>,
+    last: Positions<S>,
+}
+
+fn add_to<S: Semiring>(ps: &mut Positions<S>, p: usize, w: S) {
+    match ps.iter_mut().find(|(q, _)| *q == p) {
+        Some((_, v)) => *v = v.plus(&w),
+        None => ps.push((p, w)),
+    }
+}
+fn opt_plus<S: Semiring>(x: Option<S>, y: Option<S>) -> Option<S> {
+    match (x, y) {
+        (Some(x), Some(y)) => Some(x.plus(&y)),
+        (x, None) => x,
+        (None, y) => y,
+    }
+}
+
+/*
+    The compiled machine
+*/
+
+pub struct Weighted<'a, D, S> {
+    guards: Vec<Guard<'a, D>>,
+    weights: Vec<Weight<'a, D, S>>,
+    // The weight of the empty stream, before the first symbol, between
+    // symbols, and after the last symbol
+    empty: Option<S>,
+    first: Positions<S>,
+    follow: Vec<Positions<S>>,
+    last: Positions<S>,
+    // Sum of the initial values since the last item
+    start: Ext<S>,
+    // Weight of the runs ending at each symbol
+    state: Vec<Ext<S>>,
+}
+
+impl<'a, D, S: Semiring> WRegex<'a, D, S> {
+    pub fn compile(&self) -> Weighted<'a, D, S> {
+        let mut m = Weighted {
+            guards: Vec::new(),
+            weights: Vec::new(),
+            empty: None,
+            first: Vec::new(),
+            follow: Vec::new(),
+            last: Vec::new(),
+            start: Ext::None,
+            state: Vec::new(),
+        };
+        let g = m.glushkov(self);
+        m.empty = g.empty;
+        m.first = g.first;
+        m.last = g.last;
+        m.state = vec![Ext::None; m.guards.len()];
+        m
+    }
+}
+
+impl<'a, D, S: Semiring> Weighted<'a, D, S> {
+    fn glushkov(&mut self, r: &WRegex<'a, D, S>) -> Glushkov<S> {
+        match r {
+            WRegex::Empty => {
+                Glushkov { empty: None, first: vec![], last: vec![] }
+            }
+            WRegex::Eps => {
+                Glushkov { empty: Some(S::one()), first: vec![], last: vec![] }
+            }
+            WRegex::Sym(g, w) => {
+                let p = self.guards.len();
+                self.guards.push(g.clone());
+                self.weights.push(w.clone());
+                self.follow.push(vec![]);
+                Glushkov {
+                    empty: None,
+                    first: vec![(p, S::one())],
+                    last: vec![(p, S::one())],
+                }
+            }
+            WRegex::Alt(r1, r2) => {
+                let (g1, g2) = (self.glushkov(r1), self.glushkov(r2));
+                let mut first = g1.first;
+                for (p, w) in g2.first {
+                    add_to(&mut first, p, w);
+                }
+                let mut last = g1.last;
+                for (p, w) in g2.last {
+                    add_to(&mut last, p, w);
+                }
+                let empty = opt_plus(g1.empty, g2.empty);
+                Glushkov { empty, first, last }
+            }
+            WRegex::Cat(r1, r2) => {
+                let (g1, g2) = (self.glushkov(r1), self.glushkov(r2));
+                self.link(&g1.last, &g2.first);
+                let mut first = g1.first;
+                if let Some(e1) = &g1.empty {
+                    for (p, w) in g2.first {
+                        add_to(&mut first, p, e1.times(&w));
+                    }
+                }
+                let mut last = g2.last;
+                if let Some(e2) = &g2.empty {
+                    for (p, w) in g1.last {
+                        add_to(&mut last, p, w.times(e2));
+                    }
+                }
+                let empty = match (g1.empty, g2.empty) {
+                    (Some(e1), Some(e2)) => Some(e1.times(&e2)),
+                    _ => None,
+                };
+                Glushkov { empty, first, last }
+            }
+            WRegex::Star(r) => {
+                let g = self.glushkov(r);
+                self.link(&g.last, &g.first);
+                Glushkov { empty: Some(S::one()), first: g.first, last: g.last }
+            }
+        }
+    }
+    // Add the transitions from each of the last symbols to each of the
+    // first symbols
+    fn link(&mut self, last: &Positions<S>, first: &Positions<S>) {
+        for (p, wp) in last {
+            for (q, wq) in first {
+                add_to(&mut self.follow[*p], *q, wp.times(wq));
+            }
+        }
+    }
+
+    // Number of symbols of the regex (states of the machine)
+    pub fn n_symbols(&self) -> usize {
+        self.guards.len()
+    }
+
+    // The weight of the items (Ext::None if they don't match)
+    pub fn weight_of(&mut self, items: &[D]) -> Ext<S> {
+        self.reset();
+        let mut out = self.init_one(S::one());
+        for item in items {
+            out = self.update(item);
+        }
+        out
+    }
+
+    fn output(&self) -> Ext<S> {
+        let mut result = Ext::None;
+        for (p, w) in &self.last {
+            add_ext(&mut result, times_ext(&self.state[*p], w));
+        }
+        result
+    }
+}
+
+// Semiring operations on Ext, where Ext::None is no run (rather than the
+// semiring zero) and Ext::Many is absorbing
+fn add_ext<S: Semiring>(x: &mut Ext<S>, y: Ext<S>) {
+    *x = match (std::mem::replace(x, Ext::None), y) {
+        (Ext::One(a), Ext::One(b)) => Ext::One(a.plus(&b)),
+        (Ext::None, y) => y,
+        (x, Ext::None) => x,
+        _ => Ext::Many,
+    }
+}
+fn times_ext<S: Semiring>(x: &Ext<S>, w: &S) -> Ext<S> {
+    match x {
+        Ext::One(a) => Ext::One(a.times(w)),
+        Ext::None => Ext::None,
+        Ext::Many => Ext::Many,
+    }
+}
+
+impl<D, S: Semiring> Transducer<S, D, S> for Weighted<'_, D, S> {
+    fn init(&mut self, i: Ext<S>) -> Ext<S> {
+        let out = match &self.empty {
+            Some(e) => times_ext(&i, e),
+            None => Ext::None,
+        };
+        add_ext(&mut self.start, i);
+        out
+    }
+    fn update(&mut self, item: &D) -> Ext<S> {
+        let mut next = vec![Ext::None; self.guards.len()];
+        for (p, w) in &self.first {
+            add_ext(&mut next[*p], times_ext(&self.start, w));
+        }
+        for (p, x) in self.state.iter().enumerate() {
+            if !x.is_none() {
+                for (q, w) in &self.follow[p] {
+                    add_ext(&mut next[*q], times_ext(x, w));
+                }
+            }
+        }
+        for (q, x) in next.iter_mut().enumerate() {
+            if x.is_none() {
+                continue;
+            }
+            if self.guards[q].eval(item) {
+                let w = (self.weights[q])(item);
+                *x = times_ext(x, &w);
+            } else {
+                *x = Ext::None;
+            }
+        }
+        self.state = next;
+        self.start = Ext::None;
+        self.output()
+    }
+    fn reset(&mut self) {
+        self.start = Ext::None;
+        for x in &mut self.state {
+            *x = Ext::None;
+        }
+    }
+
+    fn is_epsilon(&self) -> bool {
+        self.guards.is_empty()
+    }
+    fn is_restartable(&self) -> bool {
+        // Initial values are added with the semiring plus, not with Ext
+        false
+    }
+    fn is_nullable(&self) -> bool {
+        self.empty.is_some()
+    }
+    fn n_states(&self) -> usize {
+        self.guards.len()
+    }
+    fn n_transs(&self) -> usize {
+        self.first.len() + self.follow.iter().map(Vec::len).sum::<usize>()
+    }
+}
+
+/*
+    Unit Tests
+*/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::derivative::{self, Derivatives};
+    use crate::guard;
+    use crate::semiring::{MaxPlus, MinPlus};
+
+    fn is<'a>(c: char) -> Guard<'a, char> {
+        guard::field("char", |&c: &char| c as i64).equals(c as i64)
+    }
+    fn ch<S: Semiring + 'static>(c: char) -> WRegex<'static, char, S> {
+        sym_const(is(c), S::one())
+    }
+
+    #[test]
+    fn test_counting() {
+        let chars = |s: &str| s.chars().collect::<Vec<_>>();
+        // (a | aa)*: the number of parses of a^n is Fibonacci
+        let r: WRegex<char, u64> = star(ch('a') | seq(vec![ch('a'), ch('a')]));
+        let mut m = r.compile();
+        assert_eq!(m.n_symbols(), 3);
+        assert_eq!(m.weight_of(&[]), Ext::One(1));
+        let counts: Vec<Ext<u64>> =
+            (1..=6).map(|n| m.weight_of(&vec!['a'; n])).collect();
+        let fib = [1, 2, 3, 5, 8, 13];
+        assert_eq!(
+            counts,
+            fib.iter().map(|&n| Ext::One(n)).collect::<Vec<_>>()
+        );
+        assert_eq!(m.weight_of(&chars("aab")), Ext::None);
+        // (a*)* doesn't count the empty iterations
+        let mut m = star(star(ch::<u64>('a'))).compile();
+        assert_eq!(m.weight_of(&chars("aa")), Ext::One(2));
+        // (a|a)(a|a)b?
+        let a2 = || ch::<u64>('a') | ch('a');
+        let mut m = seq(vec![a2(), a2(), opt(ch('b'))]).compile();
+        assert_eq!(m.weight_of(&chars("aa")), Ext::One(4));
+        assert_eq!(m.weight_of(&chars("aab")), Ext::One(4));
+        // Several .init()s add up
+        m.reset();
+        m.init_one(1);
+        m.update(&'a');
+        m.init_one(2);
+        assert_eq!(m.update(&'a'), Ext::One(4));
+        assert_eq!(m.update(&'a'), Ext::One(8));
+        assert_eq!(m.init(Ext::Many), Ext::None);
+        m.update(&'a');
+        assert_eq!(m.update(&'a'), Ext::Many);
+    }
+
+    #[test]
+    fn test_costs() {
+        // Items are (char, cost): the cheapest way to read a stream as
+        // words, where a word is a single item or two items at a discount
+        let cost = |&(_, c): &(char, i64)| c;
+        let any = || guard::Guard::True;
+        let one = sym(any(), move |d| MinPlus(cost(d)));
+        let two = cat(
+            sym(any(), move |d| MinPlus(cost(d))),
+            sym(any(), move |d| MinPlus(cost(d) - 1)),
+        );
+        let mut m = star(one | two).compile();
+        let items = [('a', 1), ('b', 5), ('c', 1), ('d', 1)];
+        assert_eq!(m.weight_of(&items), Ext::One(MinPlus(6)));
+        // Max-plus: the most expensive way
+        let one = sym(any(), move |d| MaxPlus(cost(d)));
+        let two = cat(
+            sym(any(), move |d| MaxPlus(cost(d))),
+            sym(any(), move |d| MaxPlus(cost(d) - 1)),
+        );
+        let mut m = star(one | two).compile();
+        assert_eq!(m.weight_of(&items), Ext::One(MaxPlus(8)));
+    }
+
+    #[test]
+    fn test_boolean() {
+        // With the boolean semiring, the same language as the regex
+        let (a, b) = (ch::<bool>('a'), ch::<bool>('b'));
+        let r = cat(star(a.clone() | b.clone()), seq(vec![a, b.clone(), b]));
+        let mut m = r.compile();
+        let r = derivative::seq(vec![
+            derivative::star(
+                derivative::sym(is('a')) | derivative::sym(is('b')),
+            ),
+            derivative::sym(is('a')),
+            derivative::sym(is('b')),
+            derivative::sym(is('b')),
+        ]);
+        let mut dfa = Derivatives::new(&r).lazy();
+        for n in 0..7 {
+            for bits in 0..(1u32 << n) {
+                let w: Vec<char> = (0..n)
+                    .map(|i| if bits >> i & 1 == 1 { 'b' } else { 'a' })
+                    .collect();
+                dfa.reset();
+                let mut out = dfa.init_one(());
+                for c in &w {
+                    out = dfa.update(c);
+                }
+                assert_eq!(m.weight_of(&w).is_one(), out.is_one());
+            }
+        }
+    }
+}